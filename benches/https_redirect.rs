@@ -0,0 +1,83 @@
+use std::hint::black_box;
+
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, Criterion};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    uri::{Authority, Scheme},
+    HeaderValue, StatusCode, Uri,
+};
+use once_cell::sync::Lazy;
+use pingora::http::ResponseHeader;
+
+const TEXT_PLAIN: HeaderValue = HeaderValue::from_static("text/plain");
+
+static REDIRECT_HEADERS: Lazy<ResponseHeader> = Lazy::new(|| {
+    let mut headers =
+        ResponseHeader::build_no_case(StatusCode::PERMANENT_REDIRECT, Some(3)).unwrap();
+    headers.append_header(CONTENT_TYPE, TEXT_PLAIN).unwrap();
+    headers.append_header(CONTENT_LENGTH, 0).unwrap();
+    headers
+});
+
+/// The redirect of `HttpLB::request_filter` before: the URI is cloned into a new `Uri`,
+/// and the headers are built for each request
+fn redirect_with_uri_builder(host: &str, uri: &Uri) -> ResponseHeader {
+    let new_uri = Uri::builder()
+        .scheme(Scheme::HTTPS)
+        .authority(host)
+        .path_and_query(uri.path_and_query().unwrap().to_owned())
+        .build()
+        .unwrap();
+
+    let mut res_headers =
+        ResponseHeader::build_no_case(StatusCode::PERMANENT_REDIRECT, Some(1)).unwrap();
+    res_headers
+        .append_header(LOCATION, new_uri.to_string())
+        .unwrap();
+    res_headers
+        .append_header(CONTENT_TYPE, "text/plain")
+        .unwrap();
+    res_headers.append_header(CONTENT_LENGTH, 0).unwrap();
+    res_headers
+}
+
+/// The redirect now: the host is validated as an authority, the location is written
+/// once and the other headers are cloned
+fn redirect_with_preformatted_headers(host: &str, uri: &Uri) -> ResponseHeader {
+    let authority = Authority::try_from(host).unwrap();
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    let mut location =
+        BytesMut::with_capacity("https://".len() + host.len() + path_and_query.len());
+    location.put_slice(b"https://");
+    location.put_slice(authority.as_str().as_bytes());
+    location.put_slice(path_and_query.as_bytes());
+
+    let mut res_headers = REDIRECT_HEADERS.clone();
+    res_headers
+        .insert_header(
+            LOCATION,
+            HeaderValue::from_maybe_shared(location.freeze()).unwrap(),
+        )
+        .unwrap();
+    res_headers
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("HTTPS redirect");
+    let host = "www.example.com";
+    let uri: Uri = "/products/42?utm_source=newsletter&utm_medium=email"
+        .parse()
+        .unwrap();
+
+    group.bench_function("uri_builder", |b| {
+        b.iter(|| redirect_with_uri_builder(black_box(host), black_box(&uri)))
+    });
+
+    group.bench_function("preformatted_headers", |b| {
+        b.iter(|| redirect_with_preformatted_headers(black_box(host), black_box(&uri)))
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,300 @@
+use std::fmt::Write as _;
+
+use anyhow::bail;
+use http::header;
+use pingora::{protocols::http::ServerSession, protocols::tls::SslDigest};
+
+use crate::{
+    config::{Admin, AdminScope},
+    stores,
+};
+
+/// Who sent a request to the admin API, and what it's allowed to do
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub scope: AdminScope,
+}
+
+struct Token {
+    name: String,
+    token: String,
+    scope: AdminScope,
+}
+
+struct Client {
+    name: String,
+    /// Lowercase hexadecimal, without colons
+    fingerprint: Option<String>,
+    organization: Option<String>,
+    scope: AdminScope,
+}
+
+/// Credentials accepted by the admin API: bearer tokens and client certificates
+pub struct Credentials {
+    tokens: Vec<Token>,
+    clients: Vec<Client>,
+    /// Whether the client certificates are verified against `client_ca`
+    verify_clients: bool,
+}
+
+impl Credentials {
+    pub fn new(admin: &Admin) -> anyhow::Result<Self> {
+        let mut tokens = vec![];
+        if let Some(token) = admin.token.as_deref() {
+            if token.is_empty() {
+                bail!("admin.token cannot be empty");
+            }
+            tokens.push(Token {
+                name: "admin".to_string(),
+                token: token.to_string(),
+                scope: AdminScope::Write,
+            });
+        }
+
+        for (index, token) in admin.tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                bail!("admin.tokens[{index}].token cannot be empty");
+            }
+            tokens.push(Token {
+                name: token.name.to_string(),
+                token: token.token.to_string(),
+                scope: token.scope,
+            });
+        }
+
+        let mut clients = vec![];
+        let verify_clients = admin
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca.is_some());
+        if let Some(tls) = admin.tls.as_ref() {
+            if !tls.clients.is_empty() && tls.client_ca.is_none() {
+                bail!("admin.tls.client_ca is required to authenticate the clients");
+            }
+
+            for (index, client) in tls.clients.iter().enumerate() {
+                if client.fingerprint.is_none() && client.organization.is_none() {
+                    bail!("admin.tls.clients[{index}] requires a fingerprint or an organization");
+                }
+                clients.push(Client {
+                    name: client.name.to_string(),
+                    fingerprint: client
+                        .fingerprint
+                        .as_deref()
+                        .map(|f| f.replace(':', "").to_lowercase()),
+                    organization: client.organization.clone(),
+                    scope: client.scope,
+                });
+            }
+        }
+
+        if tokens.is_empty() && clients.is_empty() {
+            bail!(
+                "admin.token, admin.tokens or admin.tls.clients is required to expose the admin API"
+            );
+        }
+
+        Ok(Self {
+            tokens,
+            clients,
+            verify_clients,
+        })
+    }
+
+    /// Identifies the sender of the request, by its bearer token or else by its certificate
+    pub fn authenticate(&self, session: &ServerSession) -> Option<Identity> {
+        let token = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let certificate = session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_deref());
+
+        self.identify(token, certificate)
+    }
+
+    fn identify(&self, token: Option<&str>, certificate: Option<&SslDigest>) -> Option<Identity> {
+        if let Some(token) = token {
+            // Every token is compared, in constant time. The secret references are read on
+            // each request, so that their rotations apply right away.
+            return self
+                .tokens
+                .iter()
+                .filter(|t| {
+                    stores::secrets::resolve(&t.token).is_some_and(|expected| {
+                        expected.len() == token.len()
+                            && openssl::memcmp::eq(expected.as_bytes(), token.as_bytes())
+                    })
+                })
+                .map(|t| Identity {
+                    name: t.name.clone(),
+                    scope: t.scope,
+                })
+                .max_by_key(|identity| identity.scope);
+        }
+
+        // Without a token, the client must present a certificate. The handshake verifies the
+        // certificates against `client_ca` but lets the clients without one connect, they
+        // may use a token instead.
+        if !self.verify_clients {
+            return None;
+        }
+        let certificate = certificate.filter(|c| !c.cert_digest.is_empty())?;
+        let fingerprint = certificate
+            .cert_digest
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+
+        self.clients
+            .iter()
+            .filter(|client| {
+                client
+                    .fingerprint
+                    .as_ref()
+                    .is_none_or(|f| *f == fingerprint)
+                    && client
+                        .organization
+                        .as_ref()
+                        .is_none_or(|o| certificate.organization.as_ref() == Some(o))
+            })
+            .map(|client| Identity {
+                name: client.name.clone(),
+                scope: client.scope,
+            })
+            .max_by_key(|identity| identity.scope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::config::{AdminClient, AdminTls, AdminToken};
+
+    use super::*;
+
+    fn certificate(digest: Vec<u8>, organization: Option<&str>) -> SslDigest {
+        SslDigest {
+            cipher: "TLS_AES_128_GCM_SHA256",
+            version: "TLSv1.3",
+            organization: organization.map(ToString::to_string),
+            serial_number: None,
+            cert_digest: digest,
+        }
+    }
+
+    #[test]
+    fn test_identify() {
+        let admin = Admin {
+            token: Some(Cow::Borrowed("root")),
+            tokens: vec![AdminToken {
+                name: Cow::Borrowed("grafana"),
+                token: Cow::Borrowed("viewer"),
+                scope: AdminScope::Read,
+            }],
+            tls: Some(AdminTls {
+                key: "admin.key".into(),
+                pem: "admin.pem".into(),
+                client_ca: Some("ca.pem".into()),
+                clients: vec![
+                    AdminClient {
+                        name: Cow::Borrowed("deploy"),
+                        fingerprint: Some("AB:CD".to_string()),
+                        organization: None,
+                        scope: AdminScope::Write,
+                    },
+                    AdminClient {
+                        name: Cow::Borrowed("ops"),
+                        fingerprint: None,
+                        organization: Some("ops".to_string()),
+                        scope: AdminScope::Read,
+                    },
+                ],
+            }),
+            ..Admin::default()
+        };
+        let credentials = Credentials::new(&admin).unwrap();
+
+        let identity = credentials.identify(Some("root"), None).unwrap();
+        assert_eq!(identity.scope, AdminScope::Write);
+        let identity = credentials.identify(Some("viewer"), None).unwrap();
+        assert_eq!(
+            (identity.name.as_str(), identity.scope),
+            ("grafana", AdminScope::Read)
+        );
+        assert!(credentials.identify(Some("roo"), None).is_none());
+
+        let deploy = certificate(vec![0xab, 0xcd], None);
+        assert_eq!(
+            credentials.identify(None, Some(&deploy)).unwrap().name,
+            "deploy"
+        );
+        let ops = certificate(vec![0x01], Some("ops"));
+        assert_eq!(credentials.identify(None, Some(&ops)).unwrap().name, "ops");
+        let unknown = certificate(vec![0x01], Some("dev"));
+        assert!(credentials.identify(None, Some(&unknown)).is_none());
+
+        // A wrong token is not rescued by a valid certificate
+        assert!(credentials.identify(Some("wrong"), Some(&deploy)).is_none());
+        // Without a token, a certificate is required
+        assert!(credentials.identify(None, None).is_none());
+        let empty = certificate(vec![], Some("ops"));
+        assert!(credentials.identify(None, Some(&empty)).is_none());
+    }
+
+    #[test]
+    fn test_unverified_certificates() {
+        // Without `client_ca`, the certificates are not verified and never identify anyone
+        let credentials = Credentials {
+            tokens: vec![],
+            clients: vec![Client {
+                name: "ops".to_string(),
+                fingerprint: None,
+                organization: Some("ops".to_string()),
+                scope: AdminScope::Write,
+            }],
+            verify_clients: false,
+        };
+        let ops = certificate(vec![0x01], Some("ops"));
+        assert!(credentials.identify(None, Some(&ops)).is_none());
+    }
+
+    #[test]
+    fn test_invalid_credentials() {
+        assert!(Credentials::new(&Admin::default()).is_err());
+
+        let admin = Admin {
+            tokens: vec![AdminToken {
+                name: Cow::Borrowed("grafana"),
+                token: Cow::Borrowed(""),
+                scope: AdminScope::Read,
+            }],
+            ..Admin::default()
+        };
+        let err = Credentials::new(&admin).err().unwrap();
+        assert_eq!(err.to_string(), "admin.tokens[0].token cannot be empty");
+
+        let admin = Admin {
+            tls: Some(AdminTls {
+                key: "admin.key".into(),
+                pem: "admin.pem".into(),
+                client_ca: None,
+                clients: vec![AdminClient {
+                    name: Cow::Borrowed("deploy"),
+                    fingerprint: Some("abcd".to_string()),
+                    organization: None,
+                    scope: AdminScope::Write,
+                }],
+            }),
+            ..Admin::default()
+        };
+        assert!(Credentials::new(&admin).is_err());
+    }
+}
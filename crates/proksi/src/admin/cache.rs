@@ -0,0 +1,197 @@
+use http::StatusCode;
+use pingora_cache::key::CacheHashKey;
+use serde_json::json;
+
+use crate::proxy_server::https_proxy::{cache_key, STORAGE_CACHE};
+use crate::stores;
+
+use super::{error, Reply};
+
+/// Objects listed by default, and at most
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+fn query_pairs(query: &str) -> Vec<(String, String)> {
+    reqwest::Url::parse(&format!("http://localhost/?{query}"))
+        .map(|url| url.query_pairs().into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// The host is the directory of the cache, it can't point outside of it
+fn valid_host(host: Option<&str>) -> Option<&str> {
+    host.filter(|h| !h.is_empty() && !h.contains(['/', '\\']) && !h.starts_with('.'))
+}
+
+/// `GET /cache/{host}?path_prefix=...&limit=...`: the objects of the disk cache of a host,
+/// sorted by path, only the ones whose path starts with `path_prefix` when given
+pub async fn list(host: &str, query: &str) -> Reply {
+    let Some(host) = valid_host(Some(host)) else {
+        return error(StatusCode::BAD_REQUEST, "a valid host is required");
+    };
+    let (mut path_prefix, mut limit) = (None, DEFAULT_LIMIT);
+    for (name, value) in query_pairs(query) {
+        match name.as_str() {
+            "path_prefix" => path_prefix = Some(value),
+            "limit" => match value.parse::<usize>() {
+                Ok(value) if (1..=MAX_LIMIT).contains(&value) => limit = value,
+                _ => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        format!("limit must be from 1 to {MAX_LIMIT}"),
+                    )
+                }
+            },
+            _ => {}
+        }
+    }
+    if path_prefix.as_deref().is_some_and(|p| !p.starts_with('/')) {
+        return error(StatusCode::BAD_REQUEST, "path_prefix must start with /");
+    }
+
+    match STORAGE_CACHE
+        .list_namespace(host, path_prefix.as_deref(), limit)
+        .await
+    {
+        Ok((objects, truncated)) => (
+            StatusCode::OK,
+            json!({
+                "host": host,
+                "path_prefix": path_prefix,
+                "objects": objects,
+                "truncated": truncated,
+            }),
+        ),
+        Err(err) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to list the cache: {err}"),
+        ),
+    }
+}
+
+/// `GET /cache/{host}/object?path=...` (or `?key=...`): whether the response of the path (and
+/// query) is in the disk cache of the host, with its headers, and the cache settings of the
+/// route
+pub async fn object(host: &str, query: &str) -> Reply {
+    let Some(host) = valid_host(Some(host)) else {
+        return error(StatusCode::BAD_REQUEST, "a valid host is required");
+    };
+    let (mut path, mut key) = (None, None);
+    for (name, value) in query_pairs(query) {
+        match name.as_str() {
+            "path" => path = Some(value),
+            "key" => key = Some(value),
+            _ => {}
+        }
+    }
+
+    let key = match (path.as_deref(), key) {
+        (Some(path), _) if !path.starts_with('/') => {
+            return error(StatusCode::BAD_REQUEST, "path must start with /")
+        }
+        (Some(path), _) => cache_key(host, path).primary(),
+        // The key is the name of the files of the object
+        (None, Some(key)) if !key.is_empty() && key.bytes().all(|b| b.is_ascii_hexdigit()) => key,
+        _ => return error(StatusCode::BAD_REQUEST, "a path or a valid key is required"),
+    };
+
+    let route_cache = stores::get_route_by_key(host).and_then(|route| route.cache);
+    let object = STORAGE_CACHE.object(host, &key).await;
+    (
+        StatusCode::OK,
+        json!({
+            "host": host,
+            "key": key,
+            "cached": object.is_some(),
+            "object": object,
+            "route_cache": route_cache,
+        }),
+    )
+}
+
+/// `DELETE /cache?host=...&path_prefix=...`: purges the disk cache of a host, only the objects
+/// whose path starts with `path_prefix` when given
+pub async fn purge(query: &str) -> Reply {
+    let (mut host, mut path_prefix) = (None, None);
+    for (name, value) in query_pairs(query) {
+        match name.as_str() {
+            "host" => host = Some(value),
+            "path_prefix" => path_prefix = Some(value),
+            _ => {}
+        }
+    }
+
+    let Some(host) = valid_host(host.as_deref()) else {
+        return error(StatusCode::BAD_REQUEST, "a valid host is required");
+    };
+
+    if path_prefix.as_deref().is_some_and(|p| !p.starts_with('/')) {
+        return error(StatusCode::BAD_REQUEST, "path_prefix must start with /");
+    }
+
+    match STORAGE_CACHE
+        .purge_namespace(host, path_prefix.as_deref())
+        .await
+    {
+        Ok(purged) => (
+            StatusCode::OK,
+            json!({
+                "host": host,
+                "path_prefix": path_prefix,
+                "purged": purged,
+            }),
+        ),
+        Err(err) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to purge the cache: {err}"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_object() {
+        let (status, body) = object("object.localhost", "path=%2Fassets%2Fapp.js%3Fv%3D1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["cached"], false);
+        assert_eq!(
+            body["key"],
+            cache_key("object.localhost", "/assets/app.js?v=1").primary()
+        );
+
+        assert_eq!(object("..", "path=%2F").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            object("object.localhost", "path=assets").await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            object("object.localhost", "key=..%2Fconfig").await.0,
+            StatusCode::BAD_REQUEST
+        );
+
+        let (status, body) = list("object.localhost", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["objects"], json!([]));
+        assert_eq!(
+            list("object.localhost", "limit=0").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let (status, body) = purge("host=purge.localhost&path_prefix=%2Fassets").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["path_prefix"], "/assets");
+        assert_eq!(body["purged"]["disk"], 0);
+
+        assert_eq!(purge("").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(purge("host=..").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            purge("host=a.localhost&path_prefix=assets").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
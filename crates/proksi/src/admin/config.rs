@@ -0,0 +1,98 @@
+use http::StatusCode;
+use serde_json::{json, Value};
+
+use crate::{
+    config::export::{self, ExportFormat},
+    stores,
+};
+
+use super::{error, Reply};
+
+/// `GET /config?format=yaml|json`: the configuration proksi started with (JSON by default),
+/// with the routes of the router instead of the routes of the configuration file
+pub fn export(config: &Value, query: &str) -> Result<(ExportFormat, String), Reply> {
+    let format = match query_format(query) {
+        None | Some("json") => ExportFormat::Json,
+        Some("yaml" | "yml") => ExportFormat::Yaml,
+        Some(format) => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                format!("unknown format {format}, expected yaml or json"),
+            ))
+        }
+    };
+
+    let mut value = config.clone();
+    if let (Some(object), Value::Object(routes)) = (value.as_object_mut(), runtime_routes()) {
+        object.extend(routes);
+    }
+
+    export::render(&value, format)
+        .map(|body| (format, body))
+        .map_err(|err| {
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to export the configuration: {err}"),
+            )
+        })
+}
+
+fn query_format(query: &str) -> Option<&str> {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("format="))
+}
+
+/// Routes of the configuration, the admin API and the store (`routes`), and the routes
+/// discovered from docker, which have no definition (`discovered_routes`)
+fn runtime_routes() -> Value {
+    let definitions = stores::get_route_definitions();
+    let mut routes = json!({ "routes": definitions });
+
+    let discovered = stores::get_routes();
+    let mut discovered = discovered
+        .iter()
+        .filter(|(host, _)| definitions.iter().all(|route| route.host != host.as_str()))
+        .map(|(host, route)| {
+            json!({
+                "host": host,
+                "upstreams": route.upstreams,
+                "plugins": route.plugins.values().collect::<Vec<_>>(),
+                "self_signed_certificate": route.self_signed_certificate,
+            })
+        })
+        .collect::<Vec<_>>();
+    discovered.sort_by(|a, b| a["host"].as_str().cmp(&b["host"].as_str()));
+    routes["discovered_routes"] = json!(discovered);
+
+    // The routes hold secrets too (e.g. in the settings of their plugins)
+    export::redact(&mut routes);
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let config = export::to_value(&Config::default()).unwrap();
+
+        let (format, body) = export(&config, "").unwrap();
+        assert_eq!(format, ExportFormat::Json);
+        let value = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(value["service_name"], "proksi");
+        assert!(value["routes"].is_array());
+
+        let (format, body) = export(&config, "format=yaml").unwrap();
+        assert_eq!(format, ExportFormat::Yaml);
+        assert!(body.contains("service_name: proksi"));
+
+        assert_eq!(
+            export(&config, "format=toml").unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use http::StatusCode;
+use serde_json::{json, Value};
+
+use crate::proxy_server::connections;
+
+use super::{error, Reply};
+
+/// `GET /connections`: in-flight requests, with their downstream and upstream connections
+pub fn list() -> Value {
+    json!(connections::list())
+}
+
+/// `DELETE /connections/{id}`: closes the connection of an in-flight request
+pub fn close(id: &str) -> Reply {
+    let Ok(id) = id.parse::<u64>() else {
+        return error(StatusCode::BAD_REQUEST, "invalid connection id");
+    };
+
+    if !connections::close(id) {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("connection {id} not found, the request may have finished"),
+        );
+    }
+
+    (StatusCode::ACCEPTED, json!({ "closing": id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close() {
+        assert_eq!(close("abc").0, StatusCode::BAD_REQUEST);
+        assert_eq!(close("0").0, StatusCode::NOT_FOUND);
+    }
+}
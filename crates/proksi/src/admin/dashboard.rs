@@ -0,0 +1,43 @@
+use http::{header, Response, StatusCode};
+
+/// The dashboard is a static page, its data is fetched from the admin API with the
+/// credentials of the user (it holds none of its own)
+const INDEX: &str = include_str!("dashboard/index.html");
+const APP: &str = include_str!("dashboard/app.js");
+
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; \
+    style-src 'unsafe-inline'; connect-src 'self'; frame-ancestors 'none'";
+
+/// `GET /dashboard` and its script, `None` for the other paths
+pub fn asset(path: &str) -> Option<Response<Vec<u8>>> {
+    let (content_type, body) = match path {
+        "/" | "/dashboard" | "/dashboard/" => ("text/html; charset=utf-8", INDEX),
+        "/dashboard/app.js" => ("text/javascript; charset=utf-8", APP),
+        _ => return None,
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(body.as_bytes().to_vec())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assets() {
+        let page = asset("/dashboard").unwrap();
+        assert!(String::from_utf8_lossy(page.body()).contains("/dashboard/app.js"));
+        assert!(page.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+
+        assert!(asset("/dashboard/app.js").is_some());
+        assert!(asset("/dashboard/other.js").is_none());
+    }
+}
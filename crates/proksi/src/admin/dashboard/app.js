@@ -0,0 +1,213 @@
+"use strict";
+
+// Polls the admin API and renders its state. The token is kept in the session storage
+// of the tab; without a token, the requests rely on a client certificate (mutual TLS).
+const REFRESH_MS = 5000;
+const SPARK_SAMPLES = 60;
+const TOKEN_KEY = "proksi-admin-token";
+
+let previous = null;
+const rates = [];
+
+const $ = (id) => document.getElementById(id);
+
+function cell(text, className) {
+  const td = document.createElement("td");
+  td.textContent = text === undefined || text === null ? "-" : String(text);
+  if (className) td.className = className;
+  return td;
+}
+
+function fill(tbody, rows, empty) {
+  tbody.replaceChildren();
+  if (rows.length === 0) {
+    const tr = document.createElement("tr");
+    const td = cell(empty, "muted");
+    td.colSpan = tbody.closest("table").querySelectorAll("th").length;
+    tr.append(td);
+    tbody.append(tr);
+    return;
+  }
+  for (const cells of rows) {
+    const tr = document.createElement("tr");
+    tr.append(...cells);
+    tbody.append(tr);
+  }
+}
+
+async function get(path) {
+  const headers = {};
+  const token = sessionStorage.getItem(TOKEN_KEY);
+  if (token) headers.Authorization = `Bearer ${token}`;
+
+  const response = await fetch(path, { headers, cache: "no-store" });
+  if (response.status === 401) throw new Error("unauthorized");
+  if (!response.ok) throw new Error(`${path}: ${response.status}`);
+  return response.json();
+}
+
+function sumOf(counts) {
+  return Object.values(counts || {}).reduce((sum, count) => sum + count, 0);
+}
+
+function renderTraffic(traffic, now) {
+  const hosts = traffic.hosts || {};
+  const perHost = {};
+  let rate = null;
+  let errorRate = null;
+
+  if (previous) {
+    const seconds = (now - previous.at) / 1000;
+    rate = Math.max(0, traffic.total - previous.traffic.total) / seconds;
+    let errors = 0;
+    for (const [host, counts] of Object.entries(hosts)) {
+      const before = previous.traffic.hosts[host] || {};
+      perHost[host] = Math.max(0, sumOf(counts) - sumOf(before)) / seconds;
+      errors += Math.max(0, (counts["5xx"] || 0) - (before["5xx"] || 0));
+    }
+    errorRate = errors / seconds;
+    rates.push(rate);
+    if (rates.length > SPARK_SAMPLES) rates.shift();
+  }
+  previous = { at: now, traffic };
+
+  $("rate").textContent = rate === null ? "-" : rate.toFixed(1);
+  $("error-rate").textContent = errorRate === null ? "-" : errorRate.toFixed(1);
+  $("error-rate").className = errorRate > 0 ? "big bad" : "big";
+  $("total").textContent = traffic.total;
+
+  const max = Math.max(1, ...rates);
+  const step = 120 / (SPARK_SAMPLES - 1);
+  const points = rates.map((value, i) => `${(i * step).toFixed(1)},${(46 - (value / max) * 44).toFixed(1)}`);
+  $("spark").querySelector("polyline").setAttribute("points", points.join(" "));
+
+  const rows = Object.entries(hosts)
+    .sort((a, b) => sumOf(b[1]) - sumOf(a[1]))
+    .map(([host, counts]) => [
+      cell(host),
+      cell(perHost[host] === undefined ? null : perHost[host].toFixed(1), "num"),
+      cell(counts["2xx"] || 0, "num"),
+      cell(counts["3xx"] || 0, "num"),
+      cell(counts["4xx"] || 0, "num"),
+      cell(counts["5xx"] || 0, counts["5xx"] ? "num bad" : "num"),
+    ]);
+  fill($("traffic"), rows, "No requests yet");
+}
+
+function renderUpstreams(routes) {
+  const rows = routes.map((route) => {
+    const total = route.upstreams.length;
+    const state = route.healthy === 0 ? "bad" : route.healthy < total ? "warn" : "ok";
+    const list = document.createElement("td");
+    for (const upstream of route.upstreams) {
+      const span = document.createElement("span");
+      span.className = upstream.healthy ? "ok" : "bad";
+      span.textContent = `● ${upstream.address} `;
+      list.append(span);
+    }
+    return [cell(route.host), cell(`${route.healthy}/${total}`, `num ${state}`), list];
+  });
+  fill($("upstreams"), rows, "No routes");
+}
+
+function renderCache(cache) {
+  const rows = Object.entries(cache.hosts || {}).map(([host, results]) => {
+    const hits = results.hit || 0;
+    const misses = (results.miss || 0) + (results.expired || 0);
+    const ratio = hits + misses === 0 ? null : `${((hits / (hits + misses)) * 100).toFixed(1)}%`;
+    return [cell(host), cell(ratio, "num"), cell(hits, "num"), cell(misses, "num")];
+  });
+  fill($("cache"), rows, "No cached routes");
+
+  const memory = cache.disk_memory_tier || {};
+  $("cache-memory").textContent =
+    `Memory tier of the disk cache: ${memory.objects || 0} objects, ${((memory.bytes || 0) / 1048576).toFixed(1)} MiB`;
+}
+
+function renderCertificates(certificates) {
+  const rows = certificates
+    .sort((a, b) => (a.expires_in_days ?? Infinity) - (b.expires_in_days ?? Infinity))
+    .map((certificate) => {
+      const days = certificate.expires_in_days;
+      const state = days === null ? "" : days < 7 ? "bad" : days < 21 ? "warn" : "ok";
+      const renewal = certificate.renewal;
+      const renewalText = renewal
+        ? `${renewal.state}${renewal.last_error ? `: ${renewal.last_error}` : ""}`
+        : null;
+      return [
+        cell(certificate.host),
+        cell(days === null ? null : `${days} days`, `num ${state}`),
+        cell(renewalText, renewal && renewal.state === "failed" ? "bad" : ""),
+      ];
+    });
+  fill($("certificates"), rows, "No certificates");
+}
+
+function renderErrors(errors) {
+  const rows = errors.slice(0, 20).map((error) => [
+    cell(new Date(error.at * 1000).toLocaleTimeString()),
+    cell(error.host),
+    cell(`${error.method} ${error.path}`),
+    cell(error.status || null, "num bad"),
+    cell(error.upstream),
+    cell(error.error, "muted"),
+  ]);
+  fill($("errors"), rows, "No errors");
+}
+
+function showLogin(message) {
+  $("dashboard").hidden = true;
+  $("logout").hidden = true;
+  $("login").hidden = false;
+  if (message) $("login-message").textContent = message;
+}
+
+async function refresh() {
+  try {
+    const [version, traffic, upstreams, cache, certificates, errors] = await Promise.all([
+      get("/version"),
+      get("/traffic"),
+      get("/upstreams"),
+      get("/cache"),
+      get("/certificates"),
+      get("/errors"),
+    ]);
+
+    $("login").hidden = true;
+    $("dashboard").hidden = false;
+    $("logout").hidden = !sessionStorage.getItem(TOKEN_KEY);
+    $("version").textContent =
+      `${version.service_name} · v${version.version} · up ${Math.floor(version.uptime_secs / 60)} min`;
+
+    renderTraffic(traffic, Date.now());
+    renderUpstreams(upstreams);
+    renderCache(cache);
+    renderCertificates(certificates);
+    renderErrors(errors);
+    $("updated").textContent = `updated ${new Date().toLocaleTimeString()}`;
+  } catch (err) {
+    if (err.message === "unauthorized") {
+      showLogin(sessionStorage.getItem(TOKEN_KEY) ? "The token was refused." : null);
+      return;
+    }
+    $("updated").textContent = `refresh failed: ${err.message}`;
+  }
+}
+
+$("login-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  sessionStorage.setItem(TOKEN_KEY, $("token").value);
+  $("token").value = "";
+  previous = null;
+  refresh();
+});
+
+$("logout").addEventListener("click", () => {
+  sessionStorage.removeItem(TOKEN_KEY);
+  showLogin("Enter a token of the admin API, it's kept in this tab only.");
+});
+
+refresh();
+setInterval(() => {
+  if ($("login").hidden) refresh();
+}, REFRESH_MS);
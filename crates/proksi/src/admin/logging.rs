@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::services::logger::filter;
+
+use super::{error, Reply};
+
+/// Changes are reverted after 10 minutes by default, and at most after a day
+const DEFAULT_DURATION_SECS: u64 = 600;
+const MAX_DURATION_SECS: u64 = 86_400;
+
+#[derive(Deserialize)]
+struct FilterChange {
+    /// Directives of the filter, e.g. `debug` or `proksi=debug,pingora=info`
+    filter: String,
+    duration_secs: Option<u64>,
+}
+
+/// `GET /logging`: active filter of the logs
+pub fn current() -> Value {
+    json!(filter::current())
+}
+
+/// `PUT /logging`: replaces the filter of the logs until the change expires
+pub fn set(body: &[u8]) -> Reply {
+    let change = match serde_json::from_slice::<FilterChange>(body) {
+        Ok(change) => change,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid filter: {err}")),
+    };
+
+    let duration_secs = change.duration_secs.unwrap_or(DEFAULT_DURATION_SECS);
+    if duration_secs == 0 || duration_secs > MAX_DURATION_SECS {
+        return error(
+            StatusCode::BAD_REQUEST,
+            format!("duration_secs must be between 1 and {MAX_DURATION_SECS}"),
+        );
+    }
+
+    match filter::set(&change.filter, Duration::from_secs(duration_secs)) {
+        Ok(state) => {
+            tracing::warn!(
+                "log filter changed to {} for {duration_secs} seconds",
+                state.filter
+            );
+            (StatusCode::OK, json!(state))
+        }
+        Err(err) => error(StatusCode::BAD_REQUEST, err),
+    }
+}
+
+/// `DELETE /logging`: restores the filter of the configuration
+pub fn reset() -> Reply {
+    match filter::reset() {
+        Ok(state) => (StatusCode::OK, json!(state)),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_changes() {
+        assert_eq!(set(b"debug").0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            set(br#"{"filter": "debug", "duration_secs": 0}"#).0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            set(br#"{"filter": "debug", "duration_secs": 100000}"#).0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::time::Instant;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use http::{header, Method, Response, StatusCode};
+use pingora::{
+    apps::http_app::{HttpServer, ServeHttp},
+    listeners::tls::TlsSettings,
+    protocols::http::ServerSession,
+    services::listening::Service,
+    tls::ssl::SslVerifyMode,
+};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::config::{AdminScope, Config, StoreType};
+
+mod auth;
+mod cache;
+mod config;
+mod connections;
+mod dashboard;
+mod logging;
+mod profiling;
+mod routes;
+mod status;
+mod stores;
+mod tap;
+
+/// Maximum size of the request bodies (routes, upstreams)
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Status and body of the response of the admin API
+pub type Reply = (StatusCode, Value);
+
+pub fn error(status: StatusCode, message: impl std::fmt::Display) -> Reply {
+    (status, json!({ "error": message.to_string() }))
+}
+
+/// Serves the admin API: the runtime state of proksi, as JSON
+pub struct AdminApp {
+    credentials: auth::Credentials,
+    service_name: String,
+    started_at: Instant,
+    persist_routes: bool,
+    store_type: StoreType,
+    /// Configuration proksi started with, without its secrets (see `GET /config`)
+    config: Value,
+    /// Changes are applied one at a time, so that concurrent requests can't interleave
+    changes: Mutex<()>,
+}
+
+impl AdminApp {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            credentials: auth::Credentials::new(&config.admin)?,
+            service_name: config.service_name.to_string(),
+            started_at: Instant::now(),
+            persist_routes: config.admin.persist_routes,
+            store_type: config.store.store_type.clone(),
+            config: crate::config::export::to_value(config)?,
+            changes: Mutex::new(()),
+        })
+    }
+
+    /// Applies the changes of the `POST`, `PUT` and `DELETE` requests
+    async fn change(&self, session: &mut ServerSession, identity: &auth::Identity) -> Reply {
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_string();
+        if identity.scope < AdminScope::Write {
+            return error(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{} is not allowed to change the state (read scope)",
+                    identity.name
+                ),
+            );
+        }
+        let query = session
+            .req_header()
+            .uri
+            .query()
+            .unwrap_or_default()
+            .to_string();
+
+        let body = match read_body(session).await {
+            Ok(body) => body,
+            Err(reply) => return reply,
+        };
+
+        let _guard = self.changes.lock().await;
+        tracing::info!("admin API: {method} {path} by {}", identity.name);
+        match (&method, path.as_str()) {
+            (&Method::DELETE, "/cache") => cache::purge(&query).await,
+            (&Method::PUT, "/logging") => logging::set(&body),
+            (&Method::DELETE, "/logging") => logging::reset(),
+            (&Method::DELETE, path) if path.starts_with("/connections/") => {
+                connections::close(path.trim_start_matches("/connections/"))
+            }
+            _ => self.change_route(&method, &path, &body).await,
+        }
+    }
+
+    /// Creates, changes or deletes a route
+    async fn change_route(&self, method: &Method, path: &str, body: &[u8]) -> Reply {
+        let persist = self.persist_routes;
+        let host = path.strip_prefix("/routes/").unwrap_or_default();
+
+        match (method, host.split_once('/')) {
+            (&Method::POST, _) if path == "/routes" => routes::create(body, persist).await,
+            (&Method::PUT, Some((host, "upstreams"))) => {
+                routes::replace_upstreams(host, body, persist).await
+            }
+            (&Method::PUT, Some((host, "active"))) => {
+                routes::switch_pool(host, body, persist).await
+            }
+            (&Method::PUT, Some((host, "canary"))) => {
+                routes::set_canary_weight(host, body, persist).await
+            }
+            (&Method::PUT, Some((host, "faults"))) => routes::set_faults(host, body, persist).await,
+            (&Method::DELETE, Some((host, "faults"))) => routes::remove_faults(host, persist).await,
+            (&Method::PUT, None) if !host.is_empty() => routes::replace(host, body, persist).await,
+            (&Method::DELETE, None) if !host.is_empty() => routes::delete(host, persist).await,
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+async fn read_body(session: &mut ServerSession) -> Result<BytesMut, Reply> {
+    let mut body = BytesMut::new();
+    loop {
+        match session.read_request_body().await {
+            Ok(Some(chunk)) if body.len() + chunk.len() <= MAX_BODY_SIZE => {
+                body.extend_from_slice(&chunk);
+            }
+            Ok(Some(_)) => {
+                return Err(error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "request body is too large",
+                ))
+            }
+            Ok(None) => return Ok(body),
+            Err(err) => return Err(error(StatusCode::BAD_REQUEST, err)),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        // The page of the dashboard is public, its requests are authenticated
+        if session.req_header().method == Method::GET {
+            if let Some(page) = dashboard::asset(session.req_header().uri.path()) {
+                return page;
+            }
+        }
+
+        let Some(identity) = self.credentials.authenticate(session) else {
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                &json!({ "error": "unauthorized" }),
+            );
+        };
+
+        if session.req_header().method != Method::GET {
+            let (status, body) = self.change(session, &identity).await;
+            return json_response(status, &body);
+        }
+
+        if session.req_header().uri.path() == "/config" {
+            let query = session.req_header().uri.query().unwrap_or_default();
+            return match config::export(&self.config, query) {
+                Ok((format, body)) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, format.content_type())
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(body.into_bytes())
+                    .unwrap_or_default(),
+                Err((status, body)) => json_response(status, &body),
+            };
+        }
+
+        // Profiling slows the proxy down while it runs, and its profiles show its internals
+        if let Some(profile) = session
+            .req_header()
+            .uri
+            .path()
+            .strip_prefix("/debug/pprof/")
+        {
+            if identity.scope < AdminScope::Write {
+                return json_response(
+                    StatusCode::FORBIDDEN,
+                    &json!({ "error": "profiling requires the write scope" }),
+                );
+            }
+            let query = session.req_header().uri.query().unwrap_or_default();
+            return profiling::serve(profile, query).await;
+        }
+
+        // Streams the requests until the client goes away
+        if session.req_header().uri.path() == "/tap" {
+            let query = session
+                .req_header()
+                .uri
+                .query()
+                .unwrap_or_default()
+                .to_string();
+            return tap::stream(session, &query).await;
+        }
+
+        let body = match session.req_header().uri.path() {
+            "/version" => status::version(&self.service_name, self.started_at),
+            "/routes" => status::routes(),
+            "/upstreams" => status::upstreams(),
+            "/certificates" => status::certificates().await,
+            path if path.starts_with("/certificates/") => {
+                let host = path.trim_start_matches("/certificates/");
+                match status::certificate(host).await {
+                    Some(certificate) => certificate,
+                    None => {
+                        let (status, body) =
+                            error(StatusCode::NOT_FOUND, format!("no certificate for {host}"));
+                        return json_response(status, &body);
+                    }
+                }
+            }
+            "/cache" => status::cache(),
+            path if path.starts_with("/cache/") => {
+                let query = session.req_header().uri.query().unwrap_or_default();
+                let host = path.trim_start_matches("/cache/");
+                let (status, body) = match host.strip_suffix("/object") {
+                    Some(host) => cache::object(host, query).await,
+                    None => cache::list(host, query).await,
+                };
+                return json_response(status, &body);
+            }
+            "/logging" => logging::current(),
+            "/connections" => connections::list(),
+            "/traffic" => status::traffic(),
+            "/errors" => status::errors(),
+            "/stores" => {
+                let (status, body) = stores::overview(&self.store_type).await;
+                return json_response(status, &body);
+            }
+            "/stores/challenges" => {
+                let (status, body) = stores::challenges().await;
+                return json_response(status, &body);
+            }
+            "/stores/cache_routing" => stores::cache_routing(),
+            "/stores/health" => stores::health(),
+            path if path.starts_with("/stores/routes/") => {
+                let host = path.trim_start_matches("/stores/routes/");
+                let (status, body) = stores::host(host).await;
+                return json_response(status, &body);
+            }
+            _ => return json_response(StatusCode::NOT_FOUND, &json!({ "error": "not found" })),
+        };
+
+        json_response(StatusCode::OK, &body)
+    }
+}
+
+pub fn json_response(status: StatusCode, body: &Value) -> Response<Vec<u8>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap_or_default()
+}
+
+/// Creates the service exposing the admin API, when an address is configured
+pub fn admin_service(config: &Config) -> anyhow::Result<Option<Service<HttpServer<AdminApp>>>> {
+    let Some(address) = config.admin.address.as_deref() else {
+        return Ok(None);
+    };
+
+    let mut service = Service::new(
+        "admin".to_string(),
+        HttpServer::new_app(AdminApp::new(config)?),
+    );
+
+    match config.admin.tls.as_ref() {
+        Some(tls) => {
+            let mut settings =
+                TlsSettings::intermediate(&tls.pem.to_string_lossy(), &tls.key.to_string_lossy())
+                    .map_err(|err| anyhow!("admin.tls: {err}"))?;
+
+            if let Some(client_ca) = tls.client_ca.as_ref() {
+                settings
+                    .set_ca_file(client_ca)
+                    .map_err(|err| anyhow!("admin.tls.client_ca: {err}"))?;
+                // The clients without a certificate can still connect with a token, the requests
+                // without a token are rejected without a certificate (see `Credentials`)
+                settings.set_verify(SslVerifyMode::PEER);
+            }
+
+            service.add_tls_with_settings(address, None, settings);
+        }
+        None => service.add_tcp(address),
+    }
+    Ok(Some(service))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn test_token_is_required() {
+        let mut config = Config::default();
+        config.admin.address = Some(Cow::Borrowed("127.0.0.1:9091"));
+        assert!(admin_service(&config).is_err());
+
+        config.admin.token = Some(Cow::Borrowed("secret"));
+        assert!(admin_service(&config).unwrap().is_some());
+
+        config.admin.address = None;
+        assert!(admin_service(&config).unwrap().is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! CPU and heap profiles of the process in the pprof format (`go tool pprof`), built with the
+//! `profiling` feature. Without it, the endpoints respond with `501 Not Implemented`.
+
+#[cfg(feature = "profiling")]
+use http::header;
+use http::{Response, StatusCode};
+use serde_json::json;
+
+use super::json_response;
+
+/// Duration of the CPU profiles by default, and at most, in seconds
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+
+/// Samples of the CPU profiles per second
+#[cfg(feature = "profiling")]
+const FREQUENCY: i32 = 99;
+
+fn seconds(query: &str) -> Result<u64, String> {
+    let seconds = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("seconds="))
+        .map_or(Ok(DEFAULT_SECONDS), str::parse::<u64>);
+    match seconds {
+        Ok(seconds) if (1..=MAX_SECONDS).contains(&seconds) => Ok(seconds),
+        _ => Err(format!("seconds must be from 1 to {MAX_SECONDS}")),
+    }
+}
+
+#[cfg(feature = "profiling")]
+fn profile_response(body: Vec<u8>, filename: &str) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        )
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap_or_default()
+}
+
+fn error_response(status: StatusCode, message: impl std::fmt::Display) -> Response<Vec<u8>> {
+    json_response(status, &json!({ "error": message.to_string() }))
+}
+
+/// `GET /debug/pprof/{profile}`: `profile?seconds=30` samples the CPU for the given time,
+/// `heap` dumps the sampled allocations of jemalloc
+pub async fn serve(profile: &str, query: &str) -> Response<Vec<u8>> {
+    match profile {
+        "profile" => match seconds(query) {
+            Ok(seconds) => cpu_profile(seconds).await,
+            Err(message) => error_response(StatusCode::BAD_REQUEST, message),
+        },
+        "heap" => heap_profile().await,
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+#[cfg(feature = "profiling")]
+async fn cpu_profile(seconds: u64) -> Response<Vec<u8>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use pprof::protos::Message;
+
+    /// The profiler samples the whole process, one profile at a time
+    static PROFILING: AtomicBool = AtomicBool::new(false);
+
+    /// Allows the next profile once this one is done, or its client went away
+    struct Running;
+    impl Drop for Running {
+        fn drop(&mut self) {
+            PROFILING.store(false, Ordering::Release);
+        }
+    }
+
+    if PROFILING.swap(true, Ordering::AcqRel) {
+        return error_response(StatusCode::CONFLICT, "a CPU profile is already running");
+    }
+    let running = Running;
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build();
+    let profile = match guard {
+        Ok(guard) => {
+            tokio::time::sleep(std::time::Duration::from_secs(seconds)).await;
+            guard
+                .report()
+                .build()
+                .map_err(|err| err.to_string())
+                .and_then(|report| report.pprof().map_err(|err| err.to_string()))
+        }
+        Err(err) => Err(err.to_string()),
+    };
+    drop(running);
+
+    match profile {
+        Ok(profile) => {
+            let mut body = Vec::new();
+            match profile.encode(&mut body) {
+                Ok(()) => profile_response(body, "profile.pb"),
+                Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to profile the CPU: {err}"),
+        ),
+    }
+}
+
+#[cfg(feature = "profiling")]
+async fn heap_profile() -> Response<Vec<u8>> {
+    // The dump writes to a temporary file
+    let dump = tokio::task::spawn_blocking(|| {
+        let Some(ctl) = jemalloc_pprof::PROF_CTL.as_ref() else {
+            return Err("jemalloc profiling is not available".to_string());
+        };
+        let mut ctl = ctl.blocking_lock();
+        if !ctl.activated() {
+            return Err("jemalloc profiling is not active".to_string());
+        }
+        ctl.dump_pprof().map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|dump| dump);
+
+    match dump {
+        // Compressed by jemalloc_pprof
+        Ok(body) => profile_response(body, "heap.pb.gz"),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to dump the heap profile: {err}"),
+        ),
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+async fn cpu_profile(_seconds: u64) -> Response<Vec<u8>> {
+    not_built()
+}
+
+#[cfg(not(feature = "profiling"))]
+async fn heap_profile() -> Response<Vec<u8>> {
+    not_built()
+}
+
+#[cfg(not(feature = "profiling"))]
+fn not_built() -> Response<Vec<u8>> {
+    error_response(
+        StatusCode::NOT_IMPLEMENTED,
+        "proksi was built without the profiling feature",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seconds() {
+        assert_eq!(seconds(""), Ok(DEFAULT_SECONDS));
+        assert_eq!(seconds("debug=1&seconds=5"), Ok(5));
+        assert!(seconds("seconds=0").is_err());
+        assert!(seconds("seconds=301").is_err());
+        assert!(seconds("seconds=ten").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        assert_eq!(serve("goroutine", "").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            serve("profile", "seconds=0").await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
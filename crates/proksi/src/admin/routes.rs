@@ -0,0 +1,398 @@
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{validate::check_route, BlueGreenPool, Route, RoutePlugin, RouteUpstream},
+    plugins::fault_injection,
+    services::{blue_green, discovery},
+    stores,
+};
+
+use super::{error, Reply};
+
+/// `POST /routes`: creates a route, which must not exist yet
+pub async fn create(body: &[u8], persist: bool) -> Reply {
+    let route = match serde_json::from_slice::<Route>(body) {
+        Ok(route) => route,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid route: {err}")),
+    };
+
+    if stores::get_route_by_key(&route.host).is_some() {
+        return error(
+            StatusCode::CONFLICT,
+            format!("route {} already exists", route.host),
+        );
+    }
+
+    apply(route, StatusCode::CREATED, persist).await
+}
+
+/// `PUT /routes/{host}`: creates or replaces the route of the host
+pub async fn replace(host: &str, body: &[u8], persist: bool) -> Reply {
+    // The host of the path wins, so it can be omitted from the body
+    let route = serde_json::from_slice::<Value>(body).and_then(|mut route| {
+        if let Some(route) = route.as_object_mut() {
+            route.insert("host".to_string(), json!(host));
+        }
+        serde_json::from_value::<Route>(route)
+    });
+
+    match route {
+        Ok(route) => apply(route, StatusCode::OK, persist).await,
+        Err(err) => error(StatusCode::BAD_REQUEST, format!("invalid route: {err}")),
+    }
+}
+
+/// `PUT /routes/{host}/upstreams`: replaces the upstreams of a route, keeping its settings
+pub async fn replace_upstreams(host: &str, body: &[u8], persist: bool) -> Reply {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return not_found(host);
+    };
+
+    match serde_json::from_slice::<Vec<RouteUpstream>>(body) {
+        Ok(upstreams) => route.upstreams = upstreams,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid upstreams: {err}")),
+    }
+
+    apply(route, StatusCode::OK, persist).await
+}
+
+#[derive(Deserialize)]
+struct ActivePool {
+    active: BlueGreenPool,
+}
+
+/// `PUT /routes/{host}/active`: switches the active pool of a blue/green route, all at once
+pub async fn switch_pool(host: &str, body: &[u8], persist: bool) -> Reply {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return not_found(host);
+    };
+
+    let active = match serde_json::from_slice::<ActivePool>(body) {
+        Ok(pool) => pool.active,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid pool: {err}")),
+    };
+
+    let Some(pools) = route.blue_green.as_mut() else {
+        return error(
+            StatusCode::CONFLICT,
+            format!("route {host} has no blue_green pools"),
+        );
+    };
+    let previous = pools.active;
+    let rollback = pools.rollback.clone();
+    pools.active = active;
+
+    let reply = apply(route, StatusCode::OK, persist).await;
+    if reply.0.is_success() && previous != active {
+        tracing::info!(
+            "switched {host} from the {} pool to the {} pool",
+            previous.as_str(),
+            active.as_str()
+        );
+        blue_green::switched(host, active, rollback);
+    }
+    reply
+}
+
+#[derive(Deserialize)]
+struct CanaryWeight {
+    weight: u8,
+}
+
+/// `PUT /routes/{host}/canary`: changes the share of the requests sent to the canary pool
+pub async fn set_canary_weight(host: &str, body: &[u8], persist: bool) -> Reply {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return not_found(host);
+    };
+
+    let weight = match serde_json::from_slice::<CanaryWeight>(body) {
+        Ok(canary) => canary.weight,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid weight: {err}")),
+    };
+
+    let Some(canary) = route.canary.as_mut() else {
+        return error(
+            StatusCode::CONFLICT,
+            format!("route {host} has no canary pool"),
+        );
+    };
+    let previous = canary.weight;
+    canary.weight = weight;
+
+    let reply = apply(route, StatusCode::OK, persist).await;
+    if reply.0.is_success() {
+        tracing::info!(
+            "sending {weight}% of the requests of {host} to the canary (was {previous}%)"
+        );
+    }
+    reply
+}
+
+/// `PUT /routes/{host}/faults`: changes the faults injected in the requests of a route,
+/// the options of the body are merged into the `fault_injection` plugin (added if needed)
+pub async fn set_faults(host: &str, body: &[u8], persist: bool) -> Reply {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return not_found(host);
+    };
+
+    let options = match serde_json::from_slice::<serde_json::Map<String, Value>>(body) {
+        Ok(options) => options,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("invalid faults: {err}")),
+    };
+
+    let plugins = route.plugins.get_or_insert_with(Vec::new);
+    let index = match plugins.iter().position(|p| p.name == "fault_injection") {
+        Some(index) => index,
+        None => {
+            plugins.push(RoutePlugin {
+                name: "fault_injection".into(),
+                config: None,
+            });
+            plugins.len() - 1
+        }
+    };
+    let config = plugins[index].config.get_or_insert_with(Default::default);
+    for (key, value) in options {
+        config.insert(key.into(), value);
+    }
+    if let Err(err) = fault_injection::check_config(config) {
+        return error(StatusCode::BAD_REQUEST, format!("invalid faults: {err}"));
+    }
+
+    let reply = apply(route, StatusCode::OK, persist).await;
+    if reply.0.is_success() {
+        tracing::warn!("changed the faults injected in the requests of {host}");
+    }
+    reply
+}
+
+/// `DELETE /routes/{host}/faults`: stops injecting faults in the requests of a route
+pub async fn remove_faults(host: &str, persist: bool) -> Reply {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return not_found(host);
+    };
+
+    if let Some(plugins) = route.plugins.as_mut() {
+        plugins.retain(|p| p.name != "fault_injection");
+    }
+
+    let reply = apply(route, StatusCode::OK, persist).await;
+    if reply.0.is_success() {
+        tracing::info!("stopped injecting faults in the requests of {host}");
+    }
+    reply
+}
+
+/// `DELETE /routes/{host}`
+pub async fn delete(host: &str, persist: bool) -> Reply {
+    if stores::get_route_definition(host).is_none() {
+        return not_found(host);
+    }
+
+    discovery::delete_route(host);
+
+    if persist {
+        if let Err(err) = stores::global::get_store().remove_route(host).await {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("route deleted but not persisted: {err}"),
+            );
+        }
+    }
+
+    (StatusCode::OK, json!({ "deleted": host }))
+}
+
+/// Routes discovered from docker are not defined in proksi, they can't be changed
+fn not_found(host: &str) -> Reply {
+    error(
+        StatusCode::NOT_FOUND,
+        format!("route {host} not found in the configuration or the admin API"),
+    )
+}
+
+async fn apply(route: Route, status: StatusCode, persist: bool) -> Reply {
+    if let Err(err) = check_route(&route) {
+        return error(StatusCode::BAD_REQUEST, err);
+    }
+
+    if let Err(err) = discovery::apply_route(&route).await {
+        return error(StatusCode::BAD_REQUEST, err);
+    }
+
+    if persist {
+        let saved = match serde_json::to_string(&route) {
+            Ok(json) => stores::global::get_store()
+                .set_route(&route.host, json)
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+
+        if let Err(err) = saved {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("route applied but not persisted: {err}"),
+            );
+        }
+    }
+
+    (status, json!(route))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::{global::init_store, MemoryStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_lifecycle() {
+        init_store(MemoryStore::new());
+
+        let route =
+            br#"{"host": "api.localhost", "upstreams": [{"ip": "127.0.0.1", "port": 3000}]}"#;
+        assert_eq!(create(route, true).await.0, StatusCode::CREATED);
+        assert_eq!(create(route, true).await.0, StatusCode::CONFLICT);
+        assert!(stores::get_route_by_key("api.localhost").is_some());
+
+        let upstreams =
+            br#"[{"ip": "127.0.0.1", "port": 3001}, {"ip": "127.0.0.1", "port": 3002}]"#;
+        let (status, body) = replace_upstreams("api.localhost", upstreams, true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["upstreams"][1]["port"], 3002);
+        let container = stores::get_route_by_key("api.localhost").unwrap();
+        assert_eq!(container.load_balancer.backends().get_backend().len(), 2);
+
+        let persisted = stores::global::get_store().get_routes().await.unwrap();
+        assert!(persisted[0].contains("3002"));
+
+        // invalid routes are not applied
+        let invalid = br#"{"upstreams": [{"ip": "127.0.0.1", "port": 0}]}"#;
+        let (status, _) = replace("api.localhost", invalid, true).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            stores::get_route_by_key("api.localhost")
+                .unwrap()
+                .upstreams
+                .len(),
+            2
+        );
+
+        assert_eq!(delete("api.localhost", true).await.0, StatusCode::OK);
+        assert!(stores::get_route_by_key("api.localhost").is_none());
+        assert!(stores::global::get_store()
+            .get_routes()
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(delete("api.localhost", true).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_switch_pool() {
+        init_store(MemoryStore::new());
+
+        let route = br#"{
+            "host": "deploy.localhost",
+            "blue_green": {
+                "blue": [{"ip": "127.0.0.1", "port": 4000}],
+                "green": [{"ip": "127.0.0.1", "port": 4001}, {"ip": "127.0.0.1", "port": 4002}]
+            }
+        }"#;
+        assert_eq!(create(route, false).await.0, StatusCode::CREATED);
+        let container = stores::get_route_by_key("deploy.localhost").unwrap();
+        assert_eq!(container.load_balancer.backends().get_backend().len(), 1);
+
+        let (status, body) =
+            switch_pool("deploy.localhost", br#"{"active": "green"}"#, false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["blue_green"]["active"], "green");
+        let container = stores::get_route_by_key("deploy.localhost").unwrap();
+        assert_eq!(container.load_balancer.backends().get_backend().len(), 2);
+
+        let (status, _) = switch_pool("deploy.localhost", br#"{"active": "red"}"#, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let route =
+            br#"{"host": "single.localhost", "upstreams": [{"ip": "127.0.0.1", "port": 4003}]}"#;
+        assert_eq!(create(route, false).await.0, StatusCode::CREATED);
+        let (status, _) = switch_pool("single.localhost", br#"{"active": "green"}"#, false).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        discovery::delete_route("deploy.localhost");
+        discovery::delete_route("single.localhost");
+    }
+
+    #[tokio::test]
+    async fn test_set_canary_weight() {
+        init_store(MemoryStore::new());
+
+        let route = br#"{
+            "host": "canary.localhost",
+            "upstreams": [{"ip": "127.0.0.1", "port": 5000}],
+            "canary": {"upstreams": [{"ip": "127.0.0.1", "port": 5001}], "weight": 5}
+        }"#;
+        assert_eq!(create(route, false).await.0, StatusCode::CREATED);
+        assert!(stores::get_route_by_key("canary.localhost")
+            .unwrap()
+            .canary
+            .is_some());
+
+        let (status, body) =
+            set_canary_weight("canary.localhost", br#"{"weight": 50}"#, false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["canary"]["weight"], 50);
+
+        // The weight is a percentage
+        let (status, _) = set_canary_weight("canary.localhost", br#"{"weight": 101}"#, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let route = stores::get_route_definition("canary.localhost").unwrap();
+        assert_eq!(route.canary.unwrap().weight, 50);
+
+        discovery::delete_route("canary.localhost");
+    }
+
+    #[tokio::test]
+    async fn test_faults() {
+        init_store(MemoryStore::new());
+
+        let route =
+            br#"{"host": "faults.localhost", "upstreams": [{"ip": "127.0.0.1", "port": 6000}]}"#;
+        assert_eq!(create(route, false).await.0, StatusCode::CREATED);
+
+        let (status, body) = set_faults(
+            "faults.localhost",
+            br#"{"abort_status": 503, "abort_percentage": 10}"#,
+            false,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["plugins"][0]["name"], "fault_injection");
+        assert!(stores::get_route_by_key("faults.localhost")
+            .unwrap()
+            .plugins
+            .contains_key("fault_injection"));
+
+        // The options are merged, the faults can be toggled
+        let (status, body) = set_faults("faults.localhost", br#"{"enabled": false}"#, false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["plugins"][0]["config"]["abort_status"], 503);
+        assert_eq!(body["plugins"][0]["config"]["enabled"], false);
+
+        let (status, _) = set_faults("faults.localhost", br#"{"abort_status": 200}"#, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = remove_faults("faults.localhost", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["plugins"], json!([]));
+        assert!(!stores::get_route_by_key("faults.localhost")
+            .unwrap()
+            .plugins
+            .contains_key("fault_injection"));
+
+        discovery::delete_route("faults.localhost");
+    }
+}
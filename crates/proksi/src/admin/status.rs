@@ -0,0 +1,378 @@
+use std::{collections::BTreeMap, net::IpAddr, time::Instant};
+
+use clap::crate_version;
+use openssl::{asn1::Asn1Time, x509::X509NameRef};
+use pingora::lb::{selection::RoundRobin, LoadBalancer};
+use prometheus::{core::Collector, IntCounterVec};
+use serde_json::{json, Value};
+
+use crate::{
+    cache::disk::storage,
+    metrics,
+    proxy_server::recent_errors,
+    services::{certificate_issuers, letsencrypt::http01::DEFAULT_RENEW_INTERVAL_DAYS},
+    stores::{
+        self,
+        certificates::Certificate,
+        health::{self, UpstreamsHealth},
+    },
+};
+
+/// Name and version of the running binary, and the version of its route table
+pub fn version(service_name: &str, started_at: Instant) -> Value {
+    let routes = stores::get_routes();
+    json!({
+        "name": "proksi",
+        "version": crate_version!(),
+        "service_name": service_name,
+        "pid": std::process::id(),
+        "uptime_secs": started_at.elapsed().as_secs(),
+        "routes": {
+            "version": routes.version,
+            "updated_at": routes.updated_at,
+            "count": routes.iter().count(),
+        },
+    })
+}
+
+/// Routes currently in the router, sorted by host
+pub fn routes() -> Value {
+    let routes = stores::get_routes();
+    let mut hosts = routes.iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    hosts
+        .into_iter()
+        .map(|(host, route)| {
+            json!({
+                "host": host,
+                "upstreams": route.upstreams,
+                "plugins": route.plugins.keys().collect::<Vec<_>>(),
+                "cache": route.cache,
+                "compression": route.compression,
+                "limits": route.limits,
+                "websocket": route.websocket,
+                "grpc": route.grpc,
+                "streaming": route.streaming,
+                "sticky_sessions": route.sticky_sessions,
+                "static_files": route.static_files,
+                "maintenance": route.error_pages.as_ref().is_some_and(|pages| pages.maintenance),
+                "ip_filter": route.ip_filter,
+                "methods": route.methods.as_ref().map(|m| m.allow.to_str().unwrap_or_default()),
+                "self_signed_certificate": route.self_signed_certificate,
+            })
+        })
+        .collect()
+}
+
+/// Upstream pools of the routes, with their health (active and passive checks)
+pub fn upstreams() -> Value {
+    let routes = stores::get_routes();
+    let mut hosts = routes.iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+    let state = health::snapshot();
+
+    hosts
+        .into_iter()
+        .map(|(host, route)| {
+            let upstreams = pool(host, &route.load_balancer, &state);
+            let mut body = json!({
+                "host": host,
+                "healthy": upstreams.iter().filter(|u| u["healthy"] == true).count(),
+                "upstreams": upstreams,
+            });
+            if let Some(canary) = route.canary.as_ref() {
+                body["canary"] = json!(pool(host, &canary.load_balancer, &state));
+            }
+            body
+        })
+        .collect()
+}
+
+/// The upstreams of a pool of a route, with their health
+fn pool(
+    host: &str,
+    load_balancer: &LoadBalancer<RoundRobin>,
+    state: &UpstreamsHealth,
+) -> Vec<Value> {
+    let backends = load_balancer.backends();
+    backends
+        .get_backend()
+        .iter()
+        .map(|backend| {
+            let address = backend.addr.to_string();
+            let health = state
+                .get(host)
+                .and_then(|upstreams| upstreams.get(&address));
+            json!({
+                "address": &address,
+                "healthy": health.map_or(backends.ready(backend), |h| h.healthy),
+                "source": health.map(|h| h.source),
+                "failures": health.map_or(0, |h| h.failures),
+                "changed_at": health.map(|h| h.changed_at),
+                "last_error": health.and_then(|h| h.last_error.as_deref()),
+            })
+        })
+        .collect()
+}
+
+/// Certificates of the store, with the number of days before they expire
+pub async fn certificates() -> Value {
+    let store = stores::global::get_store();
+    let certificates = store.get_certificates().await;
+
+    let mut hosts = certificates.iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    hosts
+        .into_iter()
+        .map(|(host, certificate)| certificate_status(host, certificate))
+        .collect()
+}
+
+/// Certificate of a single host, `None` when the store has none
+pub async fn certificate(host: &str) -> Option<Value> {
+    let store = stores::global::get_store();
+    let certificate = store.get_certificate(host).await?;
+
+    Some(certificate_status(host, &certificate))
+}
+
+fn certificate_status(host: &str, certificate: &Certificate) -> Value {
+    let leaf = &certificate.leaf;
+    let not_after = leaf.not_after();
+    let expires_in_days = Asn1Time::days_from_now(0)
+        .ok()
+        .and_then(|now| now.diff(not_after).ok())
+        .map(|diff| diff.days);
+
+    let subject_alt_names = leaf
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    name.dnsname()
+                        .map(ToString::to_string)
+                        .or_else(|| name.ipaddress().and_then(ip_address))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    // Certificates of Let's Encrypt are renewed when they expire in less than 30 days, the
+    // ones of the other issuers after two thirds of their lifetime
+    let has_issuer = stores::get_route_by_key(host).is_some_and(|r| r.certificate_issuer.is_some());
+    let renewal = stores::get_renewal_status(host).map(|status| {
+        let renews_in_days = match has_issuer {
+            true => certificate_issuers::renews_in_secs(leaf).map(|secs| (secs / 86_400).max(0)),
+            false => {
+                expires_in_days.map(|days| (i64::from(days) - DEFAULT_RENEW_INTERVAL_DAYS).max(0))
+            }
+        };
+
+        json!({
+            "state": status.state,
+            "renews_in_days": renews_in_days,
+            "last_attempt_at": status.last_attempt_at,
+            "last_success_at": status.last_success_at,
+            "last_error": status.last_error,
+        })
+    });
+
+    let serial_number = leaf
+        .serial_number()
+        .to_bn()
+        .and_then(|serial| serial.to_hex_str().map(|hex| hex.to_string()))
+        .ok();
+
+    json!({
+        "host": host,
+        "subject": name_to_string(leaf.subject_name()),
+        "issuer": name_to_string(leaf.issuer_name()),
+        "subject_alt_names": subject_alt_names,
+        "serial_number": serial_number,
+        "not_before": leaf.not_before().to_string(),
+        "not_after": not_after.to_string(),
+        "expires_in_days": expires_in_days,
+        "chain": certificate.chain.is_some(),
+        "renewal": renewal,
+    })
+}
+
+/// Formats a name as `CN=example.com, O=Example`
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// IP addresses of the subject alternative names are raw bytes (4 for IPv4, 16 for IPv6)
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?).to_string()),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+/// Values of a counter labeled by `host`, by host and by the value of its other `label`
+fn counts_by_host(
+    counter: &IntCounterVec,
+    label_name: &str,
+) -> BTreeMap<String, BTreeMap<String, u64>> {
+    let mut hosts: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for family in counter.collect() {
+        for metric in family.get_metric() {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|l| l.name() == name)
+                    .map(|l| l.value().to_string())
+                    .unwrap_or_default()
+            };
+
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let count = metric.get_counter().value() as u64;
+            hosts
+                .entry(label("host"))
+                .or_default()
+                .insert(label(label_name), count);
+        }
+    }
+    hosts
+}
+
+/// Objects kept in memory by the disk cache and the cache results of each host
+pub fn cache() -> Value {
+    let hosts = counts_by_host(&metrics::CACHE_REQUESTS, "result");
+
+    let (objects, bytes) = storage::memory_usage();
+    json!({
+        "disk_memory_tier": {
+            "objects": objects,
+            "bytes": bytes,
+        },
+        "hosts": hosts,
+    })
+}
+
+/// Requests of each host since the start, by status class (the rates are computed by
+/// the clients, from two successive values)
+pub fn traffic() -> Value {
+    let hosts = counts_by_host(&metrics::HTTP_REQUESTS, "status");
+    let total = hosts.values().flat_map(BTreeMap::values).sum::<u64>();
+
+    json!({
+        "total": total,
+        "hosts": hosts,
+    })
+}
+
+/// Recent requests answered with a 5xx status or that failed to be proxied, the newest first
+pub fn errors() -> Value {
+    json!(recent_errors::list())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        metrics::CACHE_REQUESTS
+            .with_label_values(&["cache.localhost", "hit"])
+            .inc_by(3);
+        metrics::CACHE_REQUESTS
+            .with_label_values(&["cache.localhost", "miss"])
+            .inc();
+
+        let cache = cache();
+        assert_eq!(cache["hosts"]["cache.localhost"]["hit"], 3);
+        assert_eq!(cache["hosts"]["cache.localhost"]["miss"], 1);
+        assert!(cache["disk_memory_tier"]["objects"].is_number());
+    }
+
+    #[test]
+    fn test_traffic() {
+        metrics::HTTP_REQUESTS
+            .with_label_values(&["traffic.localhost", "2xx"])
+            .inc_by(5);
+        metrics::HTTP_REQUESTS
+            .with_label_values(&["traffic.localhost", "5xx"])
+            .inc();
+
+        let traffic = traffic();
+        assert_eq!(traffic["hosts"]["traffic.localhost"]["2xx"], 5);
+        assert_eq!(traffic["hosts"]["traffic.localhost"]["5xx"], 1);
+        assert!(traffic["total"].as_u64().is_some_and(|total| total >= 6));
+    }
+
+    #[test]
+    fn test_certificate_status() {
+        use openssl::{
+            hash::MessageDigest,
+            pkey::PKey,
+            rsa::Rsa,
+            x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+        };
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "cert.localhost").unwrap();
+        name.append_entry_by_text("O", "Proksi").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("cert.localhost")
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let certificate = Certificate {
+            key,
+            leaf: builder.build(),
+            chain: None,
+        };
+
+        let status = certificate_status("cert.localhost", &certificate);
+        assert_eq!(status["issuer"], "CN=cert.localhost, O=Proksi");
+        assert_eq!(
+            status["subject_alt_names"],
+            json!(["cert.localhost", "127.0.0.1"])
+        );
+        assert!(status["renewal"].is_null());
+
+        stores::update_renewal_status("cert.localhost", |status| {
+            status.state = stores::certificates::RenewalState::Failed;
+            status.last_error = Some("challenge failed".to_string());
+        });
+        let status = certificate_status("cert.localhost", &certificate);
+        assert_eq!(status["renewal"]["state"], "failed");
+        assert_eq!(status["renewal"]["last_error"], "challenge failed");
+        assert!(status["renewal"]["renews_in_days"].as_i64().unwrap() >= 59);
+    }
+}
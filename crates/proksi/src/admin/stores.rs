@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use http::StatusCode;
+use serde_json::{json, Value};
+
+use crate::{
+    config::StoreType,
+    stores::{self, health},
+};
+
+use super::{error, Reply};
+
+/// `GET /stores`: number and size of the keys of the store (memory, Redis or a file), and
+/// of the state kept in memory by this instance
+pub async fn overview(store_type: &StoreType) -> Reply {
+    let usage = match stores::global::get_store().usage().await {
+        Ok(usage) => usage.into_iter().collect::<BTreeMap<_, _>>(),
+        Err(err) => {
+            return error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("failed to read the store: {err}"),
+            )
+        }
+    };
+
+    let routes = stores::get_routes();
+    let cache_routing = stores::get_cache_routings();
+    let health = health::snapshot();
+    let upstreams = health.values().flat_map(|upstreams| upstreams.values());
+
+    (
+        StatusCode::OK,
+        json!({
+            "store": {
+                "store_type": store_type_name(store_type),
+                "keys": usage,
+            },
+            "route_table": {
+                "version": routes.version,
+                "updated_at": routes.updated_at,
+                "changed_hosts": routes.changed_hosts,
+                "count": routes.iter().count(),
+            },
+            "route_definitions": stores::get_route_definitions().len(),
+            "cache_routing": {
+                "keys": cache_routing.len(),
+                "bytes": cache_routing.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>(),
+            },
+            "health": {
+                "hosts": health.len(),
+                "upstreams": upstreams.clone().count(),
+                "unhealthy": upstreams.filter(|u| !u.healthy).count(),
+            },
+        }),
+    )
+}
+
+/// `GET /stores/cache_routing`: cache directory of each host, set on its first cached request
+pub fn cache_routing() -> Value {
+    json!(stores::get_cache_routings())
+}
+
+/// `GET /stores/challenges`: HTTP-01 challenges waiting for Let's Encrypt, of every instance
+/// sharing the store (their proofs are not returned)
+pub async fn challenges() -> Reply {
+    match stores::global::get_store().get_challenge_tokens().await {
+        Ok(mut tokens) => {
+            tokens.sort();
+            let tokens = tokens
+                .into_iter()
+                .map(|(domain, token)| json!({ "domain": domain, "token": token }))
+                .collect::<Vec<_>>();
+            (StatusCode::OK, json!(tokens))
+        }
+        Err(err) => error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("failed to read the store: {err}"),
+        ),
+    }
+}
+
+/// `GET /stores/health`: health of every upstream, as shared by the active and passive checks
+pub fn health() -> Value {
+    json!(*health::snapshot())
+}
+
+/// `GET /stores/routes/{host}`: everything the stores hold about a host, to find out why its
+/// requests go where they go
+pub async fn host(host: &str) -> Reply {
+    let routes = stores::get_routes();
+    let route = routes.get(host);
+    let definition = stores::get_route_definition(host);
+
+    let store = stores::global::get_store();
+    let saved = match store.get_routes().await {
+        Ok(saved) => saved.iter().any(|route| {
+            serde_json::from_str::<Value>(route).is_ok_and(|route| route["host"] == host)
+        }),
+        Err(err) => {
+            return error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("failed to read the store: {err}"),
+            )
+        }
+    };
+    let challenges = store
+        .get_challenge_tokens()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(domain, _)| domain == host)
+        .map(|(_, token)| token)
+        .collect::<BTreeSet<_>>();
+    let certificate = store.get_certificate(host).await.is_some();
+
+    if route.is_none() && definition.is_none() && !saved && !certificate && challenges.is_empty() {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("nothing is stored for {host}"),
+        );
+    }
+
+    // Routes of Docker are only in the router
+    let source = match (&definition, saved) {
+        (_, true) => "admin_api",
+        (Some(_), false) => "configuration",
+        (None, false) if route.is_some() => "discovery",
+        (None, false) => "none",
+    };
+
+    let state = health::snapshot();
+    let upstreams = route.map(|route| {
+        let backends = route.load_balancer.backends();
+        backends
+            .get_backend()
+            .iter()
+            .map(|backend| {
+                let address = backend.addr.to_string();
+                let health = state
+                    .get(host)
+                    .and_then(|upstreams| upstreams.get(&address));
+                json!({
+                    "address": address,
+                    "ready": backends.ready(backend),
+                    "health": health,
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    (
+        StatusCode::OK,
+        json!({
+            "host": host,
+            "source": source,
+            "route_table_version": routes.version,
+            "in_router": route.is_some(),
+            "saved_in_store": saved,
+            "upstreams": upstreams,
+            "cache_directory": stores::get_cache_routings().remove(host),
+            "certificate": certificate,
+            "renewal": stores::get_renewal_status(host),
+            "challenge_tokens": challenges,
+        }),
+    )
+}
+
+fn store_type_name(store_type: &StoreType) -> &'static str {
+    match store_type {
+        StoreType::Memory => "memory",
+        StoreType::Redis => "redis",
+        StoreType::File => "file",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::{global::init_store, MemoryStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inspect_stores() {
+        init_store(MemoryStore::new());
+        let host = "inspect.localhost";
+        assert_eq!(self::host(host).await.0, StatusCode::NOT_FOUND);
+
+        stores::global::get_store()
+            .set_challenge(host, "token".to_string(), "proof".to_string())
+            .await
+            .unwrap();
+
+        let (status, body) = challenges().await;
+        assert_eq!(status, StatusCode::OK);
+        let challenge = json!({ "domain": host, "token": "token" });
+        assert!(body.as_array().unwrap().contains(&challenge));
+
+        let (status, body) = self::host(host).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["source"], "none");
+        assert_eq!(body["challenge_tokens"], json!(["token"]));
+
+        let (status, body) = overview(&StoreType::Memory).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["store"]["store_type"], "memory");
+        assert!(body["store"]["keys"]["challenges"]["keys"].as_u64() >= Some(2));
+    }
+}
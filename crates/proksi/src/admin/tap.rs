@@ -0,0 +1,268 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, Response, StatusCode};
+use openssl::{base64, sha::sha1};
+use pingora::{http::ResponseHeader, protocols::http::ServerSession};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::proxy_server::{
+    tap::{self, TapFilter},
+    websocket::is_websocket_upgrade,
+};
+
+use super::{error, json_response, Reply};
+
+/// Taps streaming at the same time, each one gets a copy of every request
+const MAX_TAPS: usize = 8;
+/// Sent when no request matched for a while, so that the proxies in between keep the
+/// stream open
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+static TAPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a tap while it streams
+struct TapGuard;
+
+impl TapGuard {
+    fn acquire() -> Option<Self> {
+        TAPS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |taps| {
+            (taps < MAX_TAPS).then_some(taps + 1)
+        })
+        .ok()
+        .map(|_| Self)
+    }
+}
+
+impl Drop for TapGuard {
+    fn drop(&mut self) {
+        TAPS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// How the requests are streamed to the client
+#[derive(Clone, Copy)]
+enum Stream {
+    /// Server-sent events (`text/event-stream`)
+    Events,
+    /// Text frames of a WebSocket, for the clients sending `Upgrade: websocket`
+    WebSocket,
+}
+
+impl Stream {
+    fn message(self, data: &[u8]) -> Bytes {
+        match self {
+            Self::Events => {
+                let mut message = BytesMut::with_capacity(data.len() + 8);
+                message.put_slice(b"data: ");
+                message.put_slice(data);
+                message.put_slice(b"\n\n");
+                message.freeze()
+            }
+            Self::WebSocket => websocket_frame(0x1, data),
+        }
+    }
+
+    fn keepalive(self) -> Bytes {
+        match self {
+            Self::Events => Bytes::from_static(b": keepalive\n\n"),
+            Self::WebSocket => websocket_frame(0x9, b""),
+        }
+    }
+}
+
+/// An unmasked frame, as servers send them (RFC 6455 section 5.2)
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(payload.len() + 10);
+    frame.put_u8(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.put_u8(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.put_u8(126);
+            frame.put_u16(len as u16);
+        }
+        len => {
+            frame.put_u8(127);
+            frame.put_u64(len as u64);
+        }
+    }
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+fn websocket_accept(key: &[u8]) -> String {
+    let mut input = key.to_vec();
+    input.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+    base64::encode_block(&sha1(&input))
+}
+
+/// `GET /tap`: streams the summaries of the requests as they complete, filtered by the
+/// query (`host`, `path_prefix`, `status`, `min_duration_ms`, `percentage`), for
+/// `duration` seconds or until the client goes away.
+///
+/// The stream is written to the session here, the response returned afterwards is not
+/// sent again and the connection is closed.
+pub async fn stream(session: &mut ServerSession, query: &str) -> Response<Vec<u8>> {
+    let filter = match TapFilter::from_query(query) {
+        Ok(filter) => filter,
+        Err(err) => {
+            let (status, body) = error(StatusCode::BAD_REQUEST, err);
+            return json_response(status, &body);
+        }
+    };
+    let duration = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("duration="))
+        .and_then(|duration| duration.parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    let Some(_guard) = TapGuard::acquire() else {
+        let (status, body) = error(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("only {MAX_TAPS} taps can stream at the same time"),
+        );
+        return json_response(status, &body);
+    };
+
+    let (stream, response) = match handshake(session) {
+        Ok(handshake) => handshake,
+        Err((status, body)) => return json_response(status, &body),
+    };
+    session.set_keepalive(None);
+    if let Err(err) = session.write_response_header(Box::new(response)).await {
+        tracing::debug!("failed to start a tap: {err}");
+        return Response::default();
+    }
+
+    let mut requests = tap::subscribe();
+    let deadline = duration.map(|duration| tokio::time::Instant::now() + duration);
+    loop {
+        let next = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, requests.recv())
+                    .await
+                    .ok(),
+                None => Some(requests.recv().await),
+            }
+        };
+        let message = match tokio::time::timeout(KEEPALIVE_INTERVAL, next).await {
+            // The duration of the tap is over
+            Ok(None) => break,
+            Ok(Some(Ok(event))) => {
+                if !filter.matches(&event) {
+                    continue;
+                }
+                let Ok(event) = serde_json::to_vec(&*event) else {
+                    continue;
+                };
+                stream.message(&event)
+            }
+            Ok(Some(Err(RecvError::Lagged(skipped)))) => {
+                stream.message(json!({ "skipped": skipped }).to_string().as_bytes())
+            }
+            Ok(Some(Err(RecvError::Closed))) => break,
+            Err(_) => stream.keepalive(),
+        };
+
+        // The client went away
+        if session.write_response_body(message, false).await.is_err() {
+            return Response::default();
+        }
+    }
+
+    let end = match stream {
+        Stream::Events => Bytes::new(),
+        Stream::WebSocket => websocket_frame(0x8, b""),
+    };
+    session.write_response_body(end, true).await.ok();
+    Response::default()
+}
+
+/// The header of the response starting the stream, a WebSocket one for the upgrade requests
+fn handshake(session: &ServerSession) -> Result<(Stream, ResponseHeader), Reply> {
+    let build = |status: StatusCode, headers: &[(header::HeaderName, String)]| {
+        let mut response = ResponseHeader::build(status, Some(headers.len()))?;
+        for (name, value) in headers {
+            response.insert_header(name.clone(), value.as_str())?;
+        }
+        Ok::<_, Box<pingora::Error>>(response)
+    };
+    let internal_error = |err| error(StatusCode::INTERNAL_SERVER_ERROR, err);
+
+    if !is_websocket_upgrade(session.req_header()) {
+        let response = build(
+            StatusCode::OK,
+            &[
+                (header::CONTENT_TYPE, "text/event-stream".to_string()),
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+            ],
+        )
+        .map_err(internal_error)?;
+        return Ok((Stream::Events, response));
+    }
+
+    let Some(key) = session.req_header().headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            "Sec-WebSocket-Key is required",
+        ));
+    };
+    let response = build(
+        StatusCode::SWITCHING_PROTOCOLS,
+        &[
+            (header::UPGRADE, "websocket".to_string()),
+            (header::CONNECTION, "Upgrade".to_string()),
+            (
+                header::SEC_WEBSOCKET_ACCEPT,
+                websocket_accept(key.as_bytes()),
+            ),
+        ],
+    )
+    .map_err(internal_error)?;
+    Ok((Stream::WebSocket, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket_accept() {
+        // Example of RFC 6455 section 1.3
+        assert_eq!(
+            websocket_accept(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            &Stream::Events.message(b"{\"status\":200}")[..],
+            b"data: {\"status\":200}\n\n"
+        );
+
+        let frame = Stream::WebSocket.message(b"{}");
+        assert_eq!(&frame[..], b"\x81\x02{}");
+
+        let frame = websocket_frame(0x1, &[b'a'; 200]);
+        assert_eq!(&frame[..4], &[0x81, 126, 0, 200]);
+        assert_eq!(frame.len(), 204);
+    }
+
+    #[test]
+    fn test_max_taps() {
+        let guards = (0..MAX_TAPS)
+            .map_while(|_| TapGuard::acquire())
+            .collect::<Vec<_>>();
+        assert_eq!(guards.len(), MAX_TAPS);
+        assert!(TapGuard::acquire().is_none());
+        drop(guards);
+        assert!(TapGuard::acquire().is_some());
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// Buffers of the log lines sent to the logger service
+pub static LOG_BUFFERS: BufferPool = BufferPool::new(1024, 256);
+
+/// Size of the chunks of the bodies written to and served from the disk cache
+pub const CACHE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Buffers of the bodies written to the disk cache
+pub static CACHE_BUFFERS: BufferPool = BufferPool::new(CACHE_CHUNK_SIZE, 64);
+
+/// Buffers that grew larger than this many times their initial size are not reused, so a
+/// few large bodies (or log lines) don't stay allocated
+const MAX_GROWTH: usize = 4;
+
+/// A bounded pool of `BytesMut` buffers, reused instead of allocating a new buffer for each
+/// log line or chunk of a body
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub const fn new(buffer_size: usize, max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            buffer_size,
+            max_buffers,
+        }
+    }
+
+    /// An empty buffer, with room for at least `buffer_size` bytes
+    pub fn get(&self) -> BytesMut {
+        let buffer = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop());
+        buffer.unwrap_or_else(|| BytesMut::with_capacity(self.buffer_size))
+    }
+
+    /// Gives the buffer back to the pool. It's dropped instead when the pool is full or the
+    /// buffer is too small (e.g. part of it is still used by a `Bytes`) or too large.
+    pub fn put(&self, mut buffer: BytesMut) {
+        let capacity = buffer.capacity();
+        if capacity < self.buffer_size || capacity > self.buffer_size * MAX_GROWTH {
+            return;
+        }
+
+        buffer.clear();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.max_buffers {
+                buffers.push(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(16, 2);
+
+        let mut buffer = pool.get();
+        assert!(buffer.capacity() >= 16);
+        buffer.extend_from_slice(b"hello");
+        let ptr = buffer.as_ptr();
+        pool.put(buffer);
+
+        // The same allocation is reused, empty
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        // Buffers that grew too much are dropped, and the pool is bounded
+        let mut large = pool.get();
+        large.extend_from_slice(&[0; 16 * MAX_GROWTH + 1]);
+        pool.put(large);
+        for _ in 0..3 {
+            pool.put(BytesMut::with_capacity(16));
+        }
+        assert_eq!(pool.buffers.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,88 @@
+//! Bloom filter of the keys cached in a directory. A key it doesn't contain is definitely not
+//! cached, a key it contains is cached but for about 1% of the keys.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Bits for each key of the capacity, with `HASHES` bits set by each key the filter has
+/// about 1% of false positives when full
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+/// Filters have room for at least this many keys
+const MIN_CAPACITY: usize = 1024;
+
+pub struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    /// Number of bits (a power of two) minus one
+    mask: u64,
+    hasher: RandomState,
+    len: AtomicUsize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let bits = (capacity * BITS_PER_KEY).next_power_of_two();
+        Self {
+            bits: (0..bits / 64).map(|_| AtomicU64::new(0)).collect(),
+            mask: bits as u64 - 1,
+            hasher: RandomState::new(),
+            len: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+
+    pub fn insert(&self, key: &str) {
+        for bit in self.bits_of(key) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether the key may have been inserted, `false` when it definitely wasn't
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bits_of(key).all(|bit| {
+            self.bits[(bit / 64) as usize].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// Whether more keys than its capacity were inserted, its false positives are more
+    /// frequent from then on
+    pub fn is_full(&self) -> bool {
+        self.len.load(Ordering::Relaxed) > self.capacity
+    }
+
+    /// The bits of the key, from two halves of its hash
+    fn bits_of(&self, key: &str) -> impl Iterator<Item = u64> + '_ {
+        let hash = self.hasher.hash_one(key);
+        let (low, high) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..HASHES).map(move |i| low.wrapping_add(i.wrapping_mul(high)) & self.mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter = BloomFilter::with_capacity(2000);
+        for key in 0..2000 {
+            filter.insert(&format!("key-{key}"));
+        }
+        assert!((0..2000).all(|key| filter.may_contain(&format!("key-{key}"))));
+        assert!(!filter.is_full());
+
+        let false_positives = (0..10_000)
+            .filter(|key| filter.may_contain(&format!("other-{key}")))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+
+        filter.insert("one-more");
+        assert!(filter.is_full());
+    }
+}
@@ -0,0 +1,89 @@
+//! Reads and writes of the cache files, through io_uring when built with the `io-uring`
+//! feature and the kernel supports it, with the blocking thread pool otherwise.
+
+use std::{fs::File, io, path::Path, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::AsyncWriteExt;
+
+#[cfg(feature = "io-uring")]
+use super::uring;
+
+/// Reads up to `len` bytes of the file from the offset, off the runtime threads. The chunk
+/// is shorter at the end of the file.
+pub async fn read_at(file: Arc<File>, offset: u64, len: usize) -> io::Result<Bytes> {
+    #[cfg(feature = "io-uring")]
+    if uring::is_available() {
+        return uring::read_at(file, offset, len).await;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        use std::os::unix::fs::FileExt;
+
+        let mut chunk = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match file.read_at(&mut chunk[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        chunk.truncate(read);
+        Ok(Bytes::from(chunk))
+    })
+    .await?
+}
+
+/// A cache file being written, in append mode
+pub enum CacheFile {
+    Tokio(tokio::fs::File),
+    #[cfg(feature = "io-uring")]
+    Uring(Arc<File>),
+}
+
+impl CacheFile {
+    /// Opens the file, created if it doesn't exist
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+
+        #[cfg(feature = "io-uring")]
+        if uring::is_available() {
+            let (options, path) = (options.clone(), path.to_path_buf());
+            let file = tokio::task::spawn_blocking(move || options.open(path)).await??;
+            return Ok(Self::Uring(Arc::new(file)));
+        }
+
+        let file = tokio::fs::OpenOptions::from(options).open(path).await?;
+        Ok(Self::Tokio(file))
+    }
+
+    /// Appends the data, the buffer is empty once it's written
+    pub async fn append(&mut self, data: &mut BytesMut) -> io::Result<()> {
+        match self {
+            Self::Tokio(file) => {
+                file.write_all(data).await?;
+                data.clear();
+            }
+            #[cfg(feature = "io-uring")]
+            Self::Uring(file) => {
+                let len = data.len();
+                uring::append(file.clone(), data.split().freeze()).await?;
+                // The written part was released by the ring, its memory is reused
+                data.reserve(len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the data appended to be written
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tokio(file) => file.flush().await,
+            #[cfg(feature = "io-uring")]
+            Self::Uring(_) => Ok(()),
+        }
+    }
+}
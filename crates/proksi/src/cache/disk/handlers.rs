@@ -1,12 +1,14 @@
 use std::{
     any::Any,
-    io::Read,
-    path::{Path, PathBuf},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use async_trait::async_trait;
 
-// use bytes::BufMut;
 use pingora_cache::{
     key::CacheHashKey,
     storage::{HandleHit, HandleMiss, MissFinishType},
@@ -16,35 +18,116 @@ use pingora_cache::{
 
 use pingora::Result;
 
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use crate::{
+    buffers::{CACHE_BUFFERS, CACHE_CHUNK_SIZE},
+    cache::disk::storage::MEMORY_TIER,
+};
+
+use super::{
+    files::{self, CacheFile},
+    meta::DiskCacheItemMetadata,
+    mmap::{MappedFile, MMAP_THRESHOLD},
+};
+
+/// Cache files being written, waited for before proksi exits
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of cache files being written
+pub fn writes_in_flight() -> usize {
+    WRITES.load(Ordering::Acquire)
+}
 
-use crate::cache::disk::storage::DISK_MEMORY_CACHE;
+/// A cache file being written, until its miss handler is dropped
+struct PendingWrite;
 
-use super::meta::DiskCacheItemMetadata;
+impl PendingWrite {
+    fn new() -> Self {
+        WRITES.fetch_add(1, Ordering::AcqRel);
+        PendingWrite
+    }
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        WRITES.fetch_sub(1, Ordering::AcqRel);
+    }
+}
 
 pub struct DiskCacheHitHandler {
-    target: std::io::BufReader<std::fs::File>,
+    /// The cache file, opened on the first read of the body: read one chunk at a time, or
+    /// mapped when it's larger than `MMAP_THRESHOLD`
+    target: Option<std::fs::File>,
     path: PathBuf,
 
     meta: DiskCacheItemMetadata,
-    finished_buffer: bytes::BytesMut,
+    /// The file being read, its length and the offset of the next chunk
+    file: Option<(Arc<std::fs::File>, u64, u64)>,
+    /// The chunks read so far, kept in the memory cache when the hit finishes with the
+    /// whole body. Mapped bodies are not kept in memory.
+    body: Option<bytes::BytesMut>,
+    /// The large file being sent, one window at a time
+    mapped: Option<MappedFile>,
+    /// The part of the current window not sent yet
+    remaining: bytes::Bytes,
 }
 
 /// HIT handler for the cache
 impl DiskCacheHitHandler {
-    pub fn new(
-        target: std::io::BufReader<std::fs::File>,
-        path: PathBuf,
-
-        meta: DiskCacheItemMetadata,
-    ) -> Self {
+    pub fn new(target: std::fs::File, path: PathBuf, meta: DiskCacheItemMetadata) -> Self {
         DiskCacheHitHandler {
-            target,
+            target: Some(target),
             path,
             meta,
-            finished_buffer: bytes::BytesMut::new(),
+            file: None,
+            body: None,
+            mapped: None,
+            remaining: bytes::Bytes::new(),
         }
     }
+
+    /// Reads the file one chunk at a time, or maps it when it's large
+    fn open_body(&mut self, target: std::fs::File) -> std::io::Result<()> {
+        let len = target.metadata()?.len();
+        if len >= MMAP_THRESHOLD {
+            tracing::debug!("mapping from cache: {len}");
+            self.mapped = Some(MappedFile::new(target, len));
+            return Ok(());
+        }
+
+        tracing::debug!("reading from cache: {len}");
+        self.body = Some(bytes::BytesMut::with_capacity(
+            usize::try_from(len).unwrap_or_default(),
+        ));
+        self.file = Some((Arc::new(target), len, 0));
+        Ok(())
+    }
+
+    /// Reads the next chunk of the file, `None` at its end
+    async fn next_chunk(&mut self) -> std::io::Result<Option<bytes::Bytes>> {
+        let Some((file, len, offset)) = &mut self.file else {
+            return Ok(None);
+        };
+        let size = usize::try_from(len.saturating_sub(*offset))
+            .unwrap_or(usize::MAX)
+            .min(CACHE_CHUNK_SIZE);
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let chunk = files::read_at(file.clone(), *offset, size).await?;
+        *offset += chunk.len() as u64;
+        if let Some(body) = &mut self.body {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Some(chunk).filter(|chunk| !chunk.is_empty()))
+    }
+
+    /// The whole body, once every chunk of the file was read
+    fn finished_body(&mut self) -> Option<bytes::Bytes> {
+        let (_, len, _) = self.file.as_ref()?;
+        let body = self.body.take()?;
+        (body.len() as u64 == *len).then(|| body.freeze())
+    }
 }
 
 #[async_trait]
@@ -53,45 +136,69 @@ impl HandleHit for DiskCacheHitHandler {
     ///
     /// Return `None` when no more body to read.
     async fn read_body(&mut self) -> Result<Option<bytes::Bytes>> {
-        let mut buffer = vec![0; 32_000];
+        if let Some(target) = self.target.take() {
+            if self.open_body(target).is_err() {
+                tracing::error!("failed to read from cache: {:?}", self.path);
+                return Ok(None);
+            }
+        }
 
-        let Ok(bytes_read) = self.target.read(&mut buffer) else {
-            tracing::error!("failed to read completely from cache: {:?}", self.path);
-            return Ok(None);
-        };
+        if self.file.is_some() {
+            return match self.next_chunk().await {
+                Ok(chunk) => Ok(chunk),
+                Err(err) => {
+                    tracing::error!("failed to read from cache: {:?}: {err}", self.path);
+                    self.body = None;
+                    Ok(None)
+                }
+            };
+        }
 
-        tracing::debug!("read from cache: {bytes_read}");
-        if bytes_read == 0 {
-            return Ok(None);
+        if self.remaining.is_empty() {
+            if let Some(mapped) = &mut self.mapped {
+                match mapped.next_window().await {
+                    Ok(Some(window)) => self.remaining = window,
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::error!("failed to map from cache: {:?}: {err}", self.path);
+                        return Ok(None);
+                    }
+                }
+            }
         }
 
-        let slice = bytes::Bytes::copy_from_slice(&buffer[..bytes_read]);
+        if self.remaining.is_empty() {
+            return Ok(None);
+        }
 
-        self.finished_buffer
-            .extend_from_slice(&buffer[..bytes_read]);
-        Ok(Some(slice))
+        // The chunks are slices of the mapped window, nothing is copied
+        let chunk_size = self.remaining.len().min(CACHE_CHUNK_SIZE);
+        Ok(Some(self.remaining.split_to(chunk_size)))
     }
 
     /// Finish the current cache hit
     async fn finish(
-        self: Box<Self>, // because self is always used as a trait object
+        mut self: Box<Self>, // because self is always used as a trait object
         _storage: &'static (dyn Storage + Sync),
         cache_key: &CacheKey,
         _: &SpanHandle,
     ) -> Result<()> {
+        // The file could not be read completely, or was mapped
+        let Some(finished_body) = self.finished_body() else {
+            return Ok(());
+        };
+
         // Skiping if the data is already in the cache
-        if let Some(existing) = DISK_MEMORY_CACHE.pin().get(&cache_key.primary()) {
-            if existing.1.len() == self.finished_buffer.len() {
-                tracing::debug!("skipping write, cache already contains data for {cache_key:?}");
-                return Ok(());
-            }
+        if MEMORY_TIER.body_len(&cache_key.primary()) == Some(finished_body.len()) {
+            tracing::debug!("skipping write, cache already contains data for {cache_key:?}");
+            return Ok(());
         }
         tracing::debug!("writing to memory cache: {:?}", cache_key.primary());
 
-        DISK_MEMORY_CACHE.pin().insert(
-            cache_key.primary(),
-            (self.meta, self.finished_buffer.freeze()),
-        );
+        if !MEMORY_TIER.insert(cache_key.primary(), self.meta, finished_body) {
+            tracing::debug!("not admitted to memory cache: {:?}", self.path);
+            return Ok(());
+        }
 
         tracing::debug!("wrote to memory cache: {:?}", self.path);
         Ok(())
@@ -117,9 +224,13 @@ impl HandleHit for DiskCacheHitHandler {
 
 /// MISS handler for the cache
 pub struct DiskCacheMissHandler {
-    main_path: PathBuf,
-    key: CacheKey,
+    path: PathBuf,
+    file: Option<CacheFile>,
+    /// Chunks of the body not written yet, written at once when they reach
+    /// `CACHE_CHUNK_SIZE` (a buffer of `CACHE_BUFFERS`)
+    pending: bytes::BytesMut,
     _meta: DiskCacheItemMetadata,
+    _write: PendingWrite,
 }
 
 impl DiskCacheMissHandler {
@@ -129,26 +240,26 @@ impl DiskCacheMissHandler {
         directory: PathBuf,
     ) -> DiskCacheMissHandler {
         DiskCacheMissHandler {
-            key,
+            path: directory.join(format!("{}.cache", key.primary())),
+            file: None,
+            pending: CACHE_BUFFERS.get(),
             _meta: meta,
-            main_path: directory,
+            _write: PendingWrite::new(),
         }
     }
 
-    /// Writes a file to disk and append data on every write
-    async fn write_to_file<P: AsRef<Path>>(
-        path: P,
-        content: &[u8],
-    ) -> std::io::Result<tokio::fs::File> {
-        let mut file = OpenOptions::new()
-            .create(true) // Create the file if it doesn't exist
-            .append(true)
-            .open(path)
-            .await?;
+    /// Appends the pending chunks to the cache file, opened on the first write
+    async fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
 
-        // Writing the content to the file
-        file.write_all(content).await?;
-        Ok(file)
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(CacheFile::open(&self.path).await?),
+        };
+
+        file.append(&mut self.pending).await
     }
 }
 
@@ -156,21 +267,20 @@ impl DiskCacheMissHandler {
 impl HandleMiss for DiskCacheMissHandler {
     /// Write the given body to the storage
     async fn write_body(&mut self, data: bytes::Bytes, end: bool) -> pingora::Result<()> {
-        let primary_key = self.key.primary();
-        let main_path = self.main_path.clone();
-        let cache_file = format!("{primary_key}.cache");
-
-        let Ok(_f) = Self::write_to_file(&main_path.join(&cache_file), &data).await else {
-            tracing::error!(
-                "failed to write to cache file: {:?}",
-                main_path.join(cache_file)
-            );
+        self.pending.extend_from_slice(&data);
+        if self.pending.len() < CACHE_CHUNK_SIZE && !end {
+            return Ok(());
+        }
+
+        if self.write_pending().await.is_err() {
+            tracing::error!("failed to write to cache file: {:?}", self.path);
             return Err(pingora::Error::new_str("failed to write to cache file"));
-        };
+        }
 
         if end {
-            // file.flush().await.ok();
-            return Ok(());
+            if let Some(file) = &mut self.file {
+                file.flush().await.ok();
+            }
         }
 
         Ok(())
@@ -181,20 +291,29 @@ impl HandleMiss for DiskCacheMissHandler {
     /// When `self` is dropped without calling this function, the storage should consider this write
     /// failed.
     async fn finish(
-        self: Box<Self>, // because self is always used as a trait object
+        mut self: Box<Self>, // because self is always used as a trait object
     ) -> Result<MissFinishType> {
+        if self.write_pending().await.is_err() {
+            tracing::error!("failed to write to cache file: {:?}", self.path);
+            return Err(pingora::Error::new_str("failed to write to cache file"));
+        }
+        if let Some(file) = &mut self.file {
+            file.flush().await.ok();
+        }
+
+        CACHE_BUFFERS.put(std::mem::take(&mut self.pending));
         Ok(MissFinishType::Created(0))
     }
 }
 
 pub struct DiskCacheHitHandlerInMemory {
-    target: bytes::buf::Reader<bytes::Bytes>,
-    // path: PathBuf,
+    /// The part of the body not read yet, shared with the memory cache
+    target: bytes::Bytes,
 }
 
 /// HIT handler for the cache
 impl DiskCacheHitHandlerInMemory {
-    pub fn new(target: bytes::buf::Reader<bytes::Bytes>) -> Self {
+    pub fn new(target: bytes::Bytes) -> Self {
         DiskCacheHitHandlerInMemory { target }
     }
 }
@@ -205,20 +324,14 @@ impl HandleHit for DiskCacheHitHandlerInMemory {
     ///
     /// Return `None` when no more body to read.
     async fn read_body(&mut self) -> Result<Option<bytes::Bytes>> {
-        let mut buffer = vec![0; 128_000];
-
-        let Ok(bytes_read) = self.target.read(&mut buffer) else {
-            tracing::error!("failed to read completely from MEMORY cache");
-            return Ok(None);
-        };
-
-        tracing::debug!("read from cache: {bytes_read}");
-        if bytes_read == 0 {
+        if self.target.is_empty() {
             return Ok(None);
         }
 
-        let slice = bytes::Bytes::copy_from_slice(&buffer[..bytes_read]);
-        Ok(Some(slice))
+        // The chunks are slices of the cached body, nothing is copied
+        let bytes_read = self.target.len().min(CACHE_CHUNK_SIZE);
+        tracing::debug!("read from cache: {bytes_read}");
+        Ok(Some(self.target.split_to(bytes_read)))
     }
 
     /// Finish the current cache hit
@@ -248,3 +361,76 @@ impl HandleHit for DiskCacheHitHandlerInMemory {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn meta() -> DiskCacheItemMetadata {
+        DiskCacheItemMetadata {
+            status: 200,
+            created_at: std::time::SystemTime::now(),
+            fresh_until: std::time::SystemTime::now(),
+            stale_while_revalidate_sec: 0,
+            stale_if_error_sec: 0,
+            headers: BTreeMap::new(),
+            namespace: None,
+            path: None,
+        }
+    }
+
+    /// Serves the cache file of the given length, returns its chunks and the handler
+    async fn serve(path: &std::path::Path, len: usize) -> (Vec<bytes::Bytes>, DiskCacheHitHandler) {
+        let body: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(path, &body).unwrap();
+
+        let file = std::fs::File::open(path).unwrap();
+        let mut hit = DiskCacheHitHandler::new(file, path.to_path_buf(), meta());
+        let mut chunks = vec![];
+        while let Some(chunk) = hit.read_body().await.unwrap() {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.concat(), body);
+        (chunks, hit)
+    }
+
+    #[tokio::test]
+    async fn test_serve_large_object() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("body.cache");
+
+        // Read one chunk at a time, and kept whole for the memory tier
+        let len = 3 * CACHE_CHUNK_SIZE + 10;
+        let (chunks, mut hit) = serve(&path, len).await;
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CACHE_CHUNK_SIZE));
+        assert_eq!(hit.finished_body().map(|body| body.len()), Some(len));
+
+        // Mapped one window at a time, not kept in memory
+        let len = usize::try_from(MMAP_THRESHOLD).unwrap() + CACHE_CHUNK_SIZE / 2;
+        let (chunks, mut hit) = serve(&path, len).await;
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CACHE_CHUNK_SIZE));
+        assert!(hit.finished_body().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partial_read_not_kept() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("body.cache");
+        std::fs::write(&path, vec![1; 2 * CACHE_CHUNK_SIZE]).unwrap();
+
+        // The client went away after the first chunk
+        let file = std::fs::File::open(&path).unwrap();
+        let mut hit = DiskCacheHitHandler::new(file, path.clone(), meta());
+        assert!(hit.read_body().await.unwrap().is_some());
+        assert!(hit.finished_body().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
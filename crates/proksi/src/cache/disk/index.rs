@@ -0,0 +1,127 @@
+//! Index of the keys cached in each directory, so the lookups of keys that are not cached
+//! return before opening their files.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+use super::bloom::BloomFilter;
+
+/// The filter of a directory is rebuilt from its files after this long, to forget the
+/// deleted objects and learn the objects written by other instances sharing the directory
+const REBUILD_INTERVAL: Duration = Duration::from_secs(300);
+
+struct DirectoryKeys {
+    keys: BloomFilter,
+    built_at: Instant,
+    rebuilding: AtomicBool,
+}
+
+impl DirectoryKeys {
+    fn needs_rebuild(&self) -> bool {
+        self.keys.is_full() || self.built_at.elapsed() >= REBUILD_INTERVAL
+    }
+}
+
+type Directory = Arc<OnceCell<DirectoryKeys>>;
+
+#[derive(Default)]
+pub struct CacheIndex {
+    directories: papaya::HashMap<PathBuf, Directory>,
+}
+
+impl CacheIndex {
+    /// Whether the key may be cached in the directory, `false` when it definitely isn't. The
+    /// keys of a directory are read from its files by its first lookup.
+    pub async fn may_contain(&'static self, directory: &Path, primary_key: &str) -> bool {
+        let entry = self
+            .directories
+            .pin()
+            .get_or_insert_with(directory.to_path_buf(), Directory::default)
+            .clone();
+        let keys = entry.get_or_init(|| build(directory)).await;
+
+        // The lookups use the current keys while they are rebuilt
+        if keys.needs_rebuild() && !keys.rebuilding.swap(true, Ordering::AcqRel) {
+            let directory = directory.to_path_buf();
+            tokio::spawn(async move {
+                let keys = build(&directory).await;
+                self.directories
+                    .pin()
+                    .insert(directory, Arc::new(OnceCell::new_with(Some(keys))));
+            });
+        }
+
+        keys.keys.may_contain(primary_key)
+    }
+
+    /// Adds a key cached in the directory. A key cached while the directory is rebuilt from
+    /// its files may be missed, its next lookup is a miss and caches it again.
+    pub fn insert(&self, directory: &Path, primary_key: &str) {
+        if let Some(keys) = self
+            .directories
+            .pin()
+            .get(directory)
+            .and_then(|entry| entry.get())
+        {
+            keys.keys.insert(primary_key);
+        }
+    }
+}
+
+/// Reads the keys of the objects of the directory, from the names of their metadata files
+async fn build(directory: &Path) -> DirectoryKeys {
+    let mut primary_keys = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(directory).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name();
+            if let Some(primary_key) = name.to_str().and_then(|n| n.strip_suffix(".metadata")) {
+                primary_keys.push(primary_key.to_string());
+            }
+        }
+    }
+
+    // Room for as many new objects as the directory has
+    let keys = BloomFilter::with_capacity(primary_keys.len() * 2);
+    for primary_key in &primary_keys {
+        keys.insert(primary_key);
+    }
+    tracing::debug!(
+        "indexed {} cached objects in {directory:?}",
+        primary_keys.len()
+    );
+
+    DirectoryKeys {
+        keys,
+        built_at: Instant::now(),
+        rebuilding: AtomicBool::new(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_index() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("cached.metadata"), "{}").unwrap();
+        std::fs::write(directory.join("cached.cache"), "body").unwrap();
+
+        let index: &'static CacheIndex = Box::leak(Box::default());
+        assert!(index.may_contain(&directory, "cached").await);
+        assert!(!index.may_contain(&directory, "missing").await);
+
+        index.insert(&directory, "new");
+        assert!(index.may_contain(&directory, "new").await);
+        std::fs::remove_dir_all(&directory).ok();
+    }
+}
@@ -0,0 +1,275 @@
+//! The memory tier of the disk cache: the objects read from the disk, bounded by their size.
+//! The objects are admitted and evicted by TinyUFO: a new object only replaces an older one
+//! when it was requested more often (TinyLFU), so objects requested once (e.g. by a crawler)
+//! don't evict the popular ones, and the objects of the tier are evicted with S3-FIFO.
+//!
+//! The objects are kept in a lock-free map by key, TinyUFO only keeps the hashes of the
+//! keys to decide which objects stay.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use tinyufo::TinyUfo;
+
+use super::meta::DiskCacheItemMetadata;
+
+/// Default size of the objects kept in memory
+pub const DEFAULT_MEMORY_SIZE: usize = 256 * 1024 * 1024;
+
+/// Expected average size of the objects, to size the frequency sketch of TinyUFO
+const ESTIMATED_OBJECT_SIZE: usize = 16 * 1024;
+
+/// An object in memory, and its size: its body, headers and key
+#[derive(Clone)]
+struct Entry {
+    meta: DiskCacheItemMetadata,
+    body: Bytes,
+    weight: usize,
+}
+
+impl Entry {
+    fn new(key: &str, meta: DiskCacheItemMetadata, body: Bytes) -> Self {
+        let headers: usize = meta.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let weight = key.len() + headers + body.len();
+        Self { meta, body, weight }
+    }
+}
+
+/// Decides which keys stay in memory. TinyUFO weighs the objects with 16 bits, so the
+/// weights are counted in units of 1/65535th of the tier (rounded up).
+struct Policy {
+    cache: TinyUfo<String, String>,
+    max_size: usize,
+    unit: usize,
+}
+
+impl Policy {
+    fn new(max_size: usize) -> Self {
+        let unit = max_size.div_ceil(u16::MAX as usize).max(1);
+        Self {
+            cache: TinyUfo::new(
+                max_size / unit,
+                (max_size / ESTIMATED_OBJECT_SIZE).max(1024),
+            ),
+            max_size,
+            unit,
+        }
+    }
+
+    fn weight(&self, weight: usize) -> u16 {
+        weight.div_ceil(self.unit).clamp(1, u16::MAX as usize) as u16
+    }
+}
+
+pub struct MemoryTier {
+    entries: papaya::HashMap<String, Entry>,
+    policy: ArcSwap<Policy>,
+    /// Size of the objects in memory
+    size: AtomicUsize,
+}
+
+impl MemoryTier {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: papaya::HashMap::new(),
+            policy: ArcSwap::from_pointee(Policy::new(max_size)),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the size of the objects kept in memory, the objects already in memory are
+    /// dropped (it's set once, before the first requests)
+    pub fn set_max_size(&self, max_size: usize) {
+        if self.policy.load().max_size == max_size {
+            return;
+        }
+        self.policy.store(Arc::new(Policy::new(max_size)));
+        self.remove_matching(|_| true);
+    }
+
+    /// The object of the key, a hit counts as a use of the object
+    pub fn get(&self, key: &str) -> Option<(DiskCacheItemMetadata, Bytes)> {
+        let entries = self.entries.pin();
+        let entry = entries.get(key)?;
+        self.policy.load().cache.get(&key.to_string());
+        Some((entry.meta.clone(), entry.body.clone()))
+    }
+
+    /// Size of the body of the object of the key in memory
+    pub fn body_len(&self, key: &str) -> Option<usize> {
+        self.entries.pin().get(key).map(|entry| entry.body.len())
+    }
+
+    /// Keeps the object in memory, unless it's rejected by the admission policy. Returns
+    /// whether it was admitted. Each insertion counts as a request of the key for the
+    /// admission of its next insertions.
+    pub fn insert(&self, key: String, meta: DiskCacheItemMetadata, body: Bytes) -> bool {
+        let policy = self.policy.load();
+        let entry = Entry::new(&key, meta, body);
+        if entry.weight > policy.max_size {
+            return false;
+        }
+
+        // The object is in the map before TinyUFO knows its key, so that the insertions
+        // evicting it concurrently find it. The evicted objects are removed from memory,
+        // the object itself when it was less popular than the object it would replace.
+        let entries = self.entries.pin();
+        let weight = policy.weight(entry.weight);
+        self.size.fetch_add(entry.weight, Ordering::Relaxed);
+        if let Some(previous) = entries.insert(key.clone(), entry) {
+            self.size.fetch_sub(previous.weight, Ordering::Relaxed);
+        }
+
+        let mut admitted = true;
+        for evicted in policy.cache.put(key.clone(), key.clone(), weight) {
+            admitted &= evicted.data != key;
+            if let Some(evicted) = entries.remove(&evicted.data) {
+                self.size.fetch_sub(evicted.weight, Ordering::Relaxed);
+            }
+        }
+        admitted
+    }
+
+    /// Removes the object of the key, returns whether it was in memory
+    pub fn remove(&self, key: &str) -> bool {
+        self.policy.load().cache.remove(&key.to_string());
+        match self.entries.pin().remove(key) {
+            Some(entry) => {
+                self.size.fetch_sub(entry.weight, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the objects whose metadata matches, returns how many were removed
+    pub fn remove_matching(&self, matches: impl Fn(&DiskCacheItemMetadata) -> bool) -> usize {
+        let policy = self.policy.load();
+        let mut removed = 0;
+        self.entries.pin().retain(|key, entry| {
+            if matches(&entry.meta) {
+                policy.cache.remove(key);
+                self.size.fetch_sub(entry.weight, Ordering::Relaxed);
+                removed += 1;
+                return false;
+            }
+            true
+        });
+        removed
+    }
+
+    /// Number of objects in memory, and their size
+    pub fn usage(&self) -> (usize, usize) {
+        (self.entries.len(), self.size.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn meta() -> DiskCacheItemMetadata {
+        DiskCacheItemMetadata {
+            status: 200,
+            created_at: std::time::SystemTime::now(),
+            fresh_until: std::time::SystemTime::now(),
+            stale_while_revalidate_sec: 0,
+            stale_if_error_sec: 0,
+            headers: BTreeMap::new(),
+            namespace: None,
+            path: None,
+        }
+    }
+
+    fn request(tier: &MemoryTier, key: &str, times: usize) {
+        for _ in 0..times {
+            tier.get(key);
+        }
+    }
+
+    #[test]
+    fn test_admission() {
+        // Room for "hot" and "warm", each object weighs its key and its body
+        let tier = MemoryTier::new(17);
+        assert!(tier.insert("hot".to_string(), meta(), Bytes::from("hot..")));
+        request(&tier, "hot", 2);
+        // Read from the disk twice
+        assert!(tier.insert("warm".to_string(), meta(), Bytes::from("warm.")));
+        assert!(tier.insert("warm".to_string(), meta(), Bytes::from("warm.")));
+        assert_eq!(tier.usage(), (2, 17));
+
+        // Requested once, it doesn't evict the objects requested more often
+        assert!(!tier.insert("scan".to_string(), meta(), Bytes::from("scan.")));
+        assert!(tier.body_len("warm").is_some());
+        assert_eq!(tier.usage(), (2, 17));
+
+        // Requested as often as the object it replaces, the object used in memory stays
+        assert!(!tier.insert("new".to_string(), meta(), Bytes::from("new..")));
+        assert!(tier.insert("new".to_string(), meta(), Bytes::from("new..")));
+        assert!(tier.body_len("warm").is_none());
+        assert!(tier.body_len("hot").is_some());
+        assert_eq!(tier.usage(), (2, 16));
+
+        // Larger than the whole tier
+        assert!(!tier.insert("large".to_string(), meta(), Bytes::from("x".repeat(20))));
+    }
+
+    #[test]
+    fn test_weight_units() {
+        let policy = Policy::new(DEFAULT_MEMORY_SIZE);
+        assert_eq!(policy.unit, 4097);
+        assert_eq!(policy.weight(10), 1);
+        assert_eq!(policy.weight(4098), 2);
+        assert_eq!(policy.weight(DEFAULT_MEMORY_SIZE), 65521);
+    }
+
+    #[test]
+    fn test_remove() {
+        let tier = MemoryTier::new(100);
+        tier.insert("a".to_string(), meta(), Bytes::from("aaaa"));
+        tier.insert("a".to_string(), meta(), Bytes::from("aa"));
+        tier.insert("b".to_string(), meta(), Bytes::from("bbb"));
+        assert_eq!(tier.usage(), (2, 7));
+
+        assert!(tier.remove("a"));
+        assert!(!tier.remove("a"));
+        assert_eq!(tier.remove_matching(|_| true), 1);
+        assert_eq!(tier.usage(), (0, 0));
+
+        tier.insert("c".to_string(), meta(), Bytes::from("c"));
+        tier.set_max_size(200);
+        assert_eq!(tier.usage(), (0, 0));
+    }
+
+    #[test]
+    fn test_concurrent_insertions() {
+        let tier = MemoryTier::new(1000);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let tier = &tier;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        let key = format!("{thread}-{i}");
+                        request(tier, &key, i % 4);
+                        tier.insert(key, meta(), Bytes::from(vec![0; 40]));
+                    }
+                });
+            }
+        });
+        // Makes room for the insertions that raced each other
+        tier.insert("last".to_string(), meta(), Bytes::from(vec![0; 40]));
+
+        // The size is the weight of the objects left, within the size of the tier
+        let (objects, size) = tier.usage();
+        let weights: usize = tier.entries.pin().values().map(|entry| entry.weight).sum();
+        assert_eq!(size, weights);
+        assert!(size <= 1000);
+        assert!(objects > 0);
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::BTreeMap, time::SystemTime};
 
 use http::StatusCode;
-use pingora_cache::CacheMeta;
+use pingora_cache::{CacheKey, CacheMeta};
 
 use pingora::http::ResponseHeader;
 use serde::{Deserialize, Serialize};
@@ -17,6 +17,12 @@ pub struct DiskCacheItemMetadata {
 
     /// It's converted later on to a `ResponseHeader`
     pub headers: BTreeMap<String, String>,
+
+    /// Namespace (host) and path of the cached request, used to purge the cache
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
 impl DiskCacheItemMetadata {
@@ -33,6 +39,33 @@ impl DiskCacheItemMetadata {
     }
 }
 
+impl DiskCacheItemMetadata {
+    /// Metadata of the given `CacheKey`, whose primary key is the base64 encoded path
+    pub fn for_key(key: &CacheKey, meta: &CacheMeta) -> Self {
+        let path = openssl::base64::decode_block(key.primary_key())
+            .ok()
+            .and_then(|path| String::from_utf8(path).ok());
+
+        DiskCacheItemMetadata {
+            namespace: Some(key.namespace().to_string()),
+            path,
+            ..DiskCacheItemMetadata::from(meta)
+        }
+    }
+
+    /// Whether the object belongs to the namespace and its path starts with `path_prefix`
+    pub fn matches(&self, namespace: &str, path_prefix: Option<&str>) -> bool {
+        if self.namespace.as_deref().is_some_and(|ns| ns != namespace) {
+            return false;
+        }
+
+        match path_prefix {
+            Some(prefix) => self.path.as_deref().is_some_and(|p| p.starts_with(prefix)),
+            None => true,
+        }
+    }
+}
+
 impl From<&CacheMeta> for DiskCacheItemMetadata {
     /// Converts a `CacheMeta` to a `DiskCacheItemMeta`
     fn from(meta: &CacheMeta) -> Self {
@@ -47,6 +80,8 @@ impl From<&CacheMeta> for DiskCacheItemMetadata {
                 .into_iter()
                 .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
                 .collect(),
+            namespace: None,
+            path: None,
         }
     }
 }
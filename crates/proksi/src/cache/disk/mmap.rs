@@ -0,0 +1,113 @@
+//! Large cache files are served from memory mappings of the file, one window at a time,
+//! instead of being read into a buffer of their size.
+
+use std::{ffi::c_void, fs::File, io, num::NonZeroUsize, ptr::NonNull, sync::Arc};
+
+use bytes::Bytes;
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+
+/// Files from this size are mapped instead of read
+pub const MMAP_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Size of each mapping of a file (a multiple of the page size). A hit only maps the next
+/// window once the chunks of the previous one were sent.
+const MMAP_WINDOW: u64 = 4 * 1024 * 1024;
+
+/// A window of a file mapped in memory, unmapped once every `Bytes` of it is dropped.
+///
+/// Cache files are only appended to or deleted, never truncated, so the mapped pages stay
+/// backed by the file (a deleted file lives until it's unmapped).
+struct Window {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by the window
+unsafe impl Send for Window {}
+unsafe impl Sync for Window {}
+
+impl AsRef<[u8]> for Window {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the mapping has `len` readable bytes until the window is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by `MappedFile::next_window`, and the `Bytes`
+        // slices of it are all dropped
+        unsafe {
+            mman::munmap(self.ptr, self.len).ok();
+        }
+    }
+}
+
+/// A cache file read through mappings of its successive windows
+pub struct MappedFile {
+    file: Arc<File>,
+    len: u64,
+    offset: u64,
+}
+
+impl MappedFile {
+    pub fn new(file: File, len: u64) -> Self {
+        Self {
+            file: Arc::new(file),
+            len,
+            offset: 0,
+        }
+    }
+
+    /// Maps the next window of the file, `None` at its end. On Linux, the pages are loaded by
+    /// the blocking thread pool, sending them doesn't block the runtime on page faults.
+    pub async fn next_window(&mut self) -> io::Result<Option<Bytes>> {
+        let Some(len) = NonZeroUsize::new(
+            usize::try_from(self.len.saturating_sub(self.offset).min(MMAP_WINDOW))
+                .map_err(io::Error::other)?,
+        ) else {
+            return Ok(None);
+        };
+
+        let (file, offset) = (self.file.clone(), self.offset);
+        let window = tokio::task::spawn_blocking(move || {
+            let offset = i64::try_from(offset).map_err(io::Error::other)?;
+            #[cfg(target_os = "linux")]
+            let flags = MapFlags::MAP_SHARED | MapFlags::MAP_POPULATE;
+            #[cfg(not(target_os = "linux"))]
+            let flags = MapFlags::MAP_SHARED;
+            // SAFETY: a new read-only shared mapping of the file, owned by the window
+            let ptr =
+                unsafe { mman::mmap(None, len, ProtFlags::PROT_READ, flags, &*file, offset) }?;
+            Ok::<_, io::Error>(Window {
+                ptr,
+                len: len.get(),
+            })
+        })
+        .await??;
+
+        self.offset += len.get() as u64;
+        Ok(Some(Bytes::from_owner(window)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mapped_windows() {
+        let path = std::env::temp_dir().join(format!("{}.cache", uuid::Uuid::new_v4()));
+        let body: Vec<u8> = (0..MMAP_WINDOW + 1000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &body).unwrap();
+
+        let mut mapped = MappedFile::new(File::open(&path).unwrap(), body.len() as u64);
+        let first = mapped.next_window().await.unwrap().unwrap();
+        let second = mapped.next_window().await.unwrap().unwrap();
+        assert!(mapped.next_window().await.unwrap().is_none());
+
+        assert_eq!(first.len() as u64, MMAP_WINDOW);
+        assert_eq!([first, second].concat(), body);
+        std::fs::remove_file(path).unwrap();
+    }
+}
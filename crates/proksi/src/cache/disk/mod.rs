@@ -1,3 +1,10 @@
+mod bloom;
+mod files;
 pub mod handlers;
+mod index;
+pub mod memory;
 pub mod meta;
+mod mmap;
 pub mod storage;
+#[cfg(feature = "io-uring")]
+mod uring;
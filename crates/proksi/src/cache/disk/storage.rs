@@ -1,8 +1,13 @@
-use std::{any::Any, path::PathBuf};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
 
-use bytes::Buf;
 use once_cell::sync::Lazy;
 use pingora_cache::{
     key::{CacheHashKey, CompactCacheKey},
@@ -12,18 +17,35 @@ use pingora_cache::{
 
 use pingora::Result;
 
-pub(super) static DISK_MEMORY_CACHE: Lazy<
-    papaya::HashMap<String, (DiskCacheItemMetadata, bytes::Bytes)>,
-> = Lazy::new(papaya::HashMap::new);
+pub(super) static MEMORY_TIER: Lazy<MemoryTier> =
+    Lazy::new(|| MemoryTier::new(DEFAULT_MEMORY_SIZE));
+
+/// Keys cached in each directory, the lookups of the other keys don't touch the disk
+static CACHE_INDEX: Lazy<CacheIndex> = Lazy::new(CacheIndex::default);
+
+/// Hits of each object since it was last cached (or since the start), by primary key
+static HITS: Lazy<papaya::HashMap<String, AtomicU64>> = Lazy::new(papaya::HashMap::new);
 
 use crate::{
     cache::disk::{
         handlers::{DiskCacheHitHandler, DiskCacheHitHandlerInMemory, DiskCacheMissHandler},
+        index::CacheIndex,
+        memory::{MemoryTier, DEFAULT_MEMORY_SIZE},
         meta::DiskCacheItemMetadata,
     },
     stores,
 };
 
+/// Number of objects kept in memory by the disk cache, and their size
+pub fn memory_usage() -> (usize, usize) {
+    MEMORY_TIER.usage()
+}
+
+/// Sets the size of the objects kept in memory by the disk cache
+pub fn set_memory_size(max_size: usize) {
+    MEMORY_TIER.set_max_size(max_size);
+}
+
 /// Disk based cache storage using a `BufReader`
 pub struct DiskCache {
     pub directory: PathBuf,
@@ -57,6 +79,241 @@ impl DiskCache {
     fn get_memory_key(key: &CacheKey) -> String {
         key.primary()
     }
+
+    fn count_hit(primary_key: &str) {
+        HITS.pin()
+            .get_or_insert_with(primary_key.to_string(), AtomicU64::default)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The object of the primary key in the namespace, with its headers
+    pub async fn object(&self, namespace: &str, primary_key: &str) -> Option<CachedObject> {
+        let directory = self.get_directory_for(namespace);
+        let body = tokio::fs::read(directory.join(format!("{primary_key}.metadata")))
+            .await
+            .ok()?;
+        let meta = serde_json::from_slice::<DiskCacheItemMetadata>(&body).ok()?;
+        let size = tokio::fs::metadata(directory.join(format!("{primary_key}.cache")))
+            .await
+            .ok()
+            .map(|file| file.len());
+
+        Some(CachedObject::new(
+            primary_key,
+            meta,
+            size,
+            SystemTime::now(),
+        ))
+    }
+
+    /// The objects of the namespace whose path starts with `path_prefix` (all of them without
+    /// a prefix), sorted by path, without their headers. At most `limit` objects are read,
+    /// the second value tells whether there are more.
+    pub async fn list_namespace(
+        &self,
+        namespace: &str,
+        path_prefix: Option<&str>,
+        limit: usize,
+    ) -> std::io::Result<(Vec<CachedObject>, bool)> {
+        let directory = self.get_directory_for(namespace);
+        let mut entries = match tokio::fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok((Vec::new(), false))
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut objects = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(primary_key) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".metadata"))
+                .map(ToString::to_string)
+            else {
+                continue;
+            };
+            let Some(mut object) = self.object(namespace, &primary_key).await else {
+                continue;
+            };
+            if path_prefix.is_some_and(|prefix| {
+                !object
+                    .path
+                    .as_deref()
+                    .is_some_and(|p| p.starts_with(prefix))
+            }) {
+                continue;
+            }
+            if objects.len() == limit {
+                return Ok((sorted(objects), true));
+            }
+
+            object.headers = None;
+            objects.push(object);
+        }
+
+        Ok((sorted(objects), false))
+    }
+
+    /// Deletes the objects of the namespace whose path starts with `path_prefix` (all of them
+    /// without a prefix), from the disk and the memory tier
+    pub async fn purge_namespace(
+        &self,
+        namespace: &str,
+        path_prefix: Option<&str>,
+    ) -> std::io::Result<PurgeResult> {
+        let mut result = PurgeResult::default();
+        let directory = self.get_directory_for(namespace);
+
+        let mut entries = match tokio::fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+            Err(err) => return Err(err),
+        };
+        let mut purged = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(primary_key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".metadata"))
+            else {
+                continue;
+            };
+
+            // Objects cached before their path was recorded only match a purge of the namespace
+            let matches = tokio::fs::read(&path)
+                .await
+                .ok()
+                .and_then(|body| serde_json::from_slice::<DiskCacheItemMetadata>(&body).ok())
+                .map_or(path_prefix.is_none(), |meta| {
+                    meta.matches(namespace, path_prefix)
+                });
+
+            if matches {
+                purged.push(primary_key.to_string());
+            }
+        }
+
+        for primary_key in &purged {
+            tokio::fs::remove_file(directory.join(format!("{primary_key}.metadata"))).await?;
+            // The body is missing while (or when) its write failed
+            tokio::fs::remove_file(directory.join(format!("{primary_key}.cache")))
+                .await
+                .ok();
+            result.disk += 1;
+        }
+
+        for primary_key in &purged {
+            HITS.pin().remove(primary_key);
+            if MEMORY_TIER.remove(primary_key) {
+                result.memory += 1;
+            }
+        }
+
+        // Objects that are only left in memory, e.g. their files were deleted by hand
+        result.memory += MEMORY_TIER.remove_matching(|meta| {
+            meta.namespace.as_deref() == Some(namespace) && meta.matches(namespace, path_prefix)
+        });
+
+        Ok(result)
+    }
+}
+
+fn sorted(mut objects: Vec<CachedObject>) -> Vec<CachedObject> {
+    objects.sort_by(|a, b| a.path.cmp(&b.path));
+    objects
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether a cached object can be served
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    /// Served from the cache
+    Fresh,
+    /// Served while it's revalidated in the background
+    StaleWhileRevalidate,
+    /// Only served when the upstream fails
+    StaleIfError,
+    /// Fetched again by the next request
+    Expired,
+}
+
+/// An object of the disk cache, as shown by the admin API
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CachedObject {
+    /// Primary key of the object, the name of its files
+    pub key: String,
+    /// Path and query of the request, unknown for the objects cached by older versions
+    pub path: Option<String>,
+    pub status: u16,
+    /// Size of the body, in bytes (`None` while it's written)
+    pub size: Option<u64>,
+    /// Unix timestamps, in seconds
+    pub created_at: u64,
+    pub fresh_until: u64,
+    pub age_secs: u64,
+    pub freshness: Freshness,
+    pub stale_while_revalidate_secs: u32,
+    pub stale_if_error_secs: u32,
+    pub in_memory: bool,
+    pub hits: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+impl CachedObject {
+    fn new(key: &str, meta: DiskCacheItemMetadata, size: Option<u64>, now: SystemTime) -> Self {
+        let (created_at, fresh_until, now) = (
+            unix_secs(meta.created_at),
+            unix_secs(meta.fresh_until),
+            unix_secs(now),
+        );
+        let stale_secs = now.saturating_sub(fresh_until);
+        let freshness = if now < fresh_until {
+            Freshness::Fresh
+        } else if stale_secs < u64::from(meta.stale_while_revalidate_sec) {
+            Freshness::StaleWhileRevalidate
+        } else if stale_secs < u64::from(meta.stale_if_error_sec) {
+            Freshness::StaleIfError
+        } else {
+            Freshness::Expired
+        };
+
+        Self {
+            key: key.to_string(),
+            path: meta.path,
+            status: meta.status,
+            size,
+            created_at,
+            fresh_until,
+            age_secs: now.saturating_sub(created_at),
+            freshness,
+            stale_while_revalidate_secs: meta.stale_while_revalidate_sec,
+            stale_if_error_secs: meta.stale_if_error_sec,
+            in_memory: MEMORY_TIER.body_len(key).is_some(),
+            hits: HITS
+                .pin()
+                .get(key)
+                .map_or(0, |hits| hits.load(Ordering::Relaxed)),
+            headers: Some(meta.headers),
+        }
+    }
+}
+
+/// Number of objects deleted by a purge, on disk and in memory
+#[derive(Debug, Default, serde::Serialize)]
+pub struct PurgeResult {
+    pub disk: usize,
+    pub memory: usize,
 }
 
 #[async_trait]
@@ -80,9 +337,9 @@ impl Storage for DiskCache {
         // and return the file contents as the body
         let memcache_key = Self::get_memory_key(key);
 
-        if let Some(data) = DISK_MEMORY_CACHE.pin().get(&memcache_key) {
-            let (meta, body) = data;
+        if let Some((meta, body)) = MEMORY_TIER.get(&memcache_key) {
             tracing::debug!("found cache for {key:?} in memory {}", body.len());
+            Self::count_hit(&memcache_key);
 
             return Ok(Some((
                 CacheMeta::new(
@@ -90,15 +347,19 @@ impl Storage for DiskCache {
                     meta.created_at,
                     meta.stale_while_revalidate_sec,
                     meta.stale_if_error_sec,
-                    DiskCacheItemMetadata::convert_headers(meta),
+                    DiskCacheItemMetadata::convert_headers(&meta),
                 ),
-                Box::new(DiskCacheHitHandlerInMemory::new(body.clone().reader())),
+                Box::new(DiskCacheHitHandlerInMemory::new(body)),
             )));
         }
 
         let namespace = key.namespace();
         let primary_key = key.primary();
         let main_path = self.get_directory_for(namespace);
+        if !CACHE_INDEX.may_contain(&main_path, &primary_key).await {
+            return Ok(None);
+        }
+
         let cache_file = format!("{primary_key}.cache");
         let file_path = main_path.join(cache_file);
 
@@ -112,8 +373,7 @@ impl Storage for DiskCache {
 
         // file_stream.rewind().await.ok();
         tracing::debug!("found cache for {key:?}");
-
-        let buf_reader = std::io::BufReader::new(file_stream);
+        Self::count_hit(&primary_key);
 
         Ok(Some((
             CacheMeta::new(
@@ -123,7 +383,7 @@ impl Storage for DiskCache {
                 meta.stale_if_error_sec,
                 DiskCacheItemMetadata::convert_headers(&meta),
             ),
-            Box::new(DiskCacheHitHandler::new(buf_reader, file_path, meta)),
+            Box::new(DiskCacheHitHandler::new(file_stream, file_path, meta)),
         )))
     }
 
@@ -145,17 +405,19 @@ impl Storage for DiskCache {
         }
 
         let Ok(serialized_metadata) =
-            serde_json::to_vec::<DiskCacheItemMetadata>(&DiskCacheItemMetadata::from(meta))
+            serde_json::to_vec::<DiskCacheItemMetadata>(&DiskCacheItemMetadata::for_key(key, meta))
         else {
             return Err(pingora::Error::new_str("failed to serialize cache meta"));
         };
         tokio::fs::write(main_path.join(metadata_file), serialized_metadata)
             .await
             .ok();
+        CACHE_INDEX.insert(&main_path, &primary_key);
+        HITS.pin().remove(&primary_key);
 
         Ok(Box::new(DiskCacheMissHandler::new(
             key.to_owned(),
-            DiskCacheItemMetadata::from(meta),
+            DiskCacheItemMetadata::for_key(key, meta),
             main_path,
         )))
     }
@@ -186,7 +448,7 @@ impl Storage for DiskCache {
         let metadata_file = format!("{primary_key}.metadata");
 
         let Ok(serialized_metadata) =
-            serde_json::to_vec::<DiskCacheItemMetadata>(&DiskCacheItemMetadata::from(meta))
+            serde_json::to_vec::<DiskCacheItemMetadata>(&DiskCacheItemMetadata::for_key(key, meta))
         else {
             return Err(pingora::Error::new_str("failed to serialize cache meta"));
         };
@@ -203,3 +465,137 @@ impl Storage for DiskCache {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_meta(now: SystemTime) -> DiskCacheItemMetadata {
+        DiskCacheItemMetadata {
+            status: 200,
+            created_at: now,
+            fresh_until: now,
+            stale_while_revalidate_sec: 0,
+            stale_if_error_sec: 0,
+            headers: BTreeMap::new(),
+            namespace: Some("purge.localhost".to_string()),
+            path: None,
+        }
+    }
+
+    fn cache_object(cache: &DiskCache, primary_key: &str, path: &str) -> DiskCacheItemMetadata {
+        let meta = DiskCacheItemMetadata {
+            path: Some(path.to_string()),
+            ..cache_meta(SystemTime::now())
+        };
+
+        let directory = cache.get_directory_for("purge.localhost");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join(format!("{primary_key}.metadata")),
+            serde_json::to_vec(&meta).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(directory.join(format!("{primary_key}.cache")), "body").unwrap();
+        meta
+    }
+
+    #[tokio::test]
+    async fn test_list_namespace() {
+        let cache = DiskCache {
+            directory: std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+        };
+        cache_object(&cache, "list-b1", "/index.html");
+        cache_object(&cache, "list-a1", "/assets/app.js");
+        cache_object(&cache, "list-a2", "/assets/app.css");
+        DiskCache::count_hit("list-a1");
+        DiskCache::count_hit("list-a1");
+
+        let (objects, truncated) = cache
+            .list_namespace("purge.localhost", None, 10)
+            .await
+            .unwrap();
+        assert!(!truncated);
+        let paths = objects
+            .iter()
+            .map(|o| o.path.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                Some("/assets/app.css"),
+                Some("/assets/app.js"),
+                Some("/index.html")
+            ]
+        );
+        assert!(objects
+            .iter()
+            .all(|o| o.headers.is_none() && o.size == Some(4)));
+
+        let (objects, truncated) = cache
+            .list_namespace("purge.localhost", Some("/assets"), 1)
+            .await
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(objects.len(), 1);
+
+        let object = cache.object("purge.localhost", "list-a1").await.unwrap();
+        assert_eq!(object.hits, 2);
+        assert_eq!(object.freshness, Freshness::Expired);
+        assert!(object.headers.is_some());
+        assert!(cache.object("purge.localhost", "missing").await.is_none());
+        std::fs::remove_dir_all(&cache.directory).ok();
+    }
+
+    #[test]
+    fn test_freshness() {
+        let now = SystemTime::now();
+        let mut meta = cache_meta(now);
+        meta.fresh_until = now + std::time::Duration::from_secs(60);
+        let object = CachedObject::new("fresh", meta.clone(), None, now);
+        assert_eq!(object.freshness, Freshness::Fresh);
+
+        meta.stale_while_revalidate_sec = 30;
+        meta.stale_if_error_sec = 120;
+        let later = |secs| now + std::time::Duration::from_secs(secs);
+        let freshness =
+            |secs| CachedObject::new("stale", meta.clone(), None, later(secs)).freshness;
+        assert_eq!(freshness(70), Freshness::StaleWhileRevalidate);
+        assert_eq!(freshness(120), Freshness::StaleIfError);
+        assert_eq!(freshness(200), Freshness::Expired);
+        assert_eq!(
+            CachedObject::new("stale", meta, None, later(70)).age_secs,
+            70
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_namespace() {
+        let cache = DiskCache {
+            directory: std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+        };
+        let meta = cache_object(&cache, "purge-a1", "/assets/app.js");
+        cache_object(&cache, "purge-b1", "/index.html");
+        MEMORY_TIER.insert("purge-a1".to_string(), meta, bytes::Bytes::from("body"));
+
+        let purged = cache
+            .purge_namespace("purge.localhost", Some("/assets"))
+            .await
+            .unwrap();
+        assert_eq!((purged.disk, purged.memory), (1, 1));
+        assert!(MEMORY_TIER.body_len("purge-a1").is_none());
+
+        let purged = cache
+            .purge_namespace("purge.localhost", None)
+            .await
+            .unwrap();
+        assert_eq!(purged.disk, 1);
+
+        let purged = cache
+            .purge_namespace("purge.localhost", None)
+            .await
+            .unwrap();
+        assert_eq!(purged.disk, 0);
+        std::fs::remove_dir_all(&cache.directory).ok();
+    }
+}
@@ -0,0 +1,297 @@
+//! Reads and writes of the cache files through io_uring, when built with the `io-uring`
+//! feature. A single thread owns the ring: it submits the operations of every request at
+//! once and sends each result back when it completes.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc, OnceLock},
+};
+
+use bytes::{Buf, Bytes};
+use io_uring::{opcode, squeue, types::Fd, IoUring};
+use tokio::sync::oneshot;
+
+/// Operations in flight at once, the others wait in the queue of the ring thread
+const RING_ENTRIES: u32 = 128;
+
+/// Largest read or write of a single operation, larger ones are split
+const MAX_OPERATION_LEN: usize = 1 << 30;
+
+/// A read or write to submit to the ring. The operation owns its file and its buffer
+/// until it completes, even when the caller stopped waiting for it.
+enum Operation {
+    /// Reads a chunk of the file from the offset, of the capacity of the buffer
+    Read {
+        file: Arc<File>,
+        offset: u64,
+        body: Vec<u8>,
+        reply: oneshot::Sender<io::Result<Bytes>>,
+    },
+    /// Appends the data to the file
+    Write {
+        file: Arc<File>,
+        data: Bytes,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+impl Operation {
+    fn entry(&mut self, slot: usize) -> squeue::Entry {
+        let entry = match self {
+            Operation::Read {
+                file, offset, body, ..
+            } => {
+                let spare = body.spare_capacity_mut();
+                opcode::Read::new(
+                    Fd(file.as_raw_fd()),
+                    spare.as_mut_ptr().cast(),
+                    operation_len(spare.len()),
+                )
+                .offset(*offset + body.len() as u64)
+                .build()
+            }
+            // The file is opened in append mode, the data goes to its end
+            Operation::Write { file, data, .. } => opcode::Write::new(
+                Fd(file.as_raw_fd()),
+                data.as_ptr(),
+                operation_len(data.len()),
+            )
+            .offset(u64::MAX)
+            .build(),
+        };
+        entry.user_data(slot as u64)
+    }
+
+    /// Handles the result of the operation, returns the operation again when there is
+    /// more to read or write
+    fn complete(self, res: i32) -> Option<Self> {
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            if err.kind() == io::ErrorKind::Interrupted || err.kind() == io::ErrorKind::WouldBlock {
+                return Some(self);
+            }
+            self.fail(err);
+            return None;
+        }
+
+        let done = res.unsigned_abs() as usize;
+        match self {
+            Operation::Read {
+                file,
+                offset,
+                mut body,
+                reply,
+            } => {
+                // SAFETY: the kernel wrote `done` bytes after the length
+                unsafe { body.set_len(body.len() + done) };
+                if done == 0 || body.len() == body.capacity() {
+                    reply.send(Ok(Bytes::from(body))).ok();
+                    return None;
+                }
+                Some(Operation::Read {
+                    file,
+                    offset,
+                    body,
+                    reply,
+                })
+            }
+            Operation::Write {
+                file,
+                mut data,
+                reply,
+            } => {
+                data.advance(done);
+                if data.is_empty() {
+                    // The buffer of the caller is released before it's told, so it can
+                    // be reused
+                    drop(data);
+                    reply.send(Ok(())).ok();
+                    return None;
+                }
+                Some(Operation::Write { file, data, reply })
+            }
+        }
+    }
+
+    fn fail(self, err: io::Error) {
+        match self {
+            Operation::Read { reply, .. } => {
+                reply.send(Err(err)).ok();
+            }
+            Operation::Write { data, reply, .. } => {
+                drop(data);
+                reply.send(Err(err)).ok();
+            }
+        }
+    }
+}
+
+fn operation_len(len: usize) -> u32 {
+    u32::try_from(len.min(MAX_OPERATION_LEN)).unwrap_or(u32::MAX)
+}
+
+/// Submits the operations received by the channel until every sender is dropped
+fn run(mut ring: IoUring, operations: &mpsc::Receiver<Operation>) {
+    let entries = ring.params().sq_entries() as usize;
+    let mut in_flight: Vec<Option<Operation>> = (0..entries).map(|_| None).collect();
+    let mut free_slots: Vec<usize> = (0..entries).rev().collect();
+    let mut queue = VecDeque::new();
+
+    loop {
+        // Waits for an operation when there is nothing else to do
+        if free_slots.len() == in_flight.len() && queue.is_empty() {
+            match operations.recv() {
+                Ok(operation) => queue.push_back(operation),
+                Err(_) => return,
+            }
+        }
+        queue.extend(operations.try_iter());
+
+        {
+            let mut submission = ring.submission();
+            while !queue.is_empty() && !submission.is_full() {
+                let Some(slot) = free_slots.pop() else {
+                    break;
+                };
+                let Some(mut operation) = queue.pop_front() else {
+                    break;
+                };
+                // SAFETY: the operation owns its file and buffer, it's kept in its slot until
+                // the kernel completes it
+                if unsafe { submission.push(&operation.entry(slot)) }.is_err() {
+                    free_slots.push(slot);
+                    queue.push_front(operation);
+                    break;
+                }
+                in_flight[slot] = Some(operation);
+            }
+        }
+
+        if let Err(err) = ring.submit_and_wait(1) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            tracing::error!("io_uring of the disk cache failed: {err}");
+            for operation in in_flight.iter_mut().filter_map(Option::take).chain(queue) {
+                operation.fail(io::Error::new(err.kind(), err.to_string()));
+            }
+            return;
+        }
+
+        for completion in ring.completion() {
+            let slot = usize::try_from(completion.user_data()).unwrap_or(usize::MAX);
+            let Some(operation) = in_flight.get_mut(slot).and_then(Option::take) else {
+                continue;
+            };
+            if let Some(operation) = operation.complete(completion.result()) {
+                queue.push_front(operation);
+            }
+            free_slots.push(slot);
+        }
+    }
+}
+
+/// Sender of the operations to the ring thread, `None` when io_uring is not available
+/// (e.g. an older kernel, or denied by seccomp): the files are read and written with the
+/// blocking thread pool then.
+fn ring() -> Option<&'static mpsc::Sender<Operation>> {
+    static RING: OnceLock<Option<mpsc::Sender<Operation>>> = OnceLock::new();
+
+    RING.get_or_init(|| {
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => ring,
+            Err(err) => {
+                tracing::warn!("io_uring is not available for the disk cache: {err}");
+                return None;
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("proksi-io-uring".to_string())
+            .spawn(move || run(ring, &receiver))
+            .ok()?;
+        Some(sender)
+    })
+    .as_ref()
+}
+
+pub fn is_available() -> bool {
+    ring().is_some()
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the io_uring thread of the disk cache stopped")
+}
+
+/// Reads up to `len` bytes of the file from the offset, less at the end of the file
+pub async fn read_at(file: Arc<File>, offset: u64, len: usize) -> io::Result<Bytes> {
+    let ring = ring().ok_or_else(stopped)?;
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+
+    let (reply, result) = oneshot::channel();
+    ring.send(Operation::Read {
+        file,
+        offset,
+        body: Vec::with_capacity(len),
+        reply,
+    })
+    .map_err(|_| stopped())?;
+    result.await.map_err(|_| stopped())?
+}
+
+/// Appends the data to the file, which is opened in append mode
+pub async fn append(file: Arc<File>, data: Bytes) -> io::Result<()> {
+    let ring = ring().ok_or_else(stopped)?;
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let (reply, result) = oneshot::channel();
+    ring.send(Operation::Write { file, data, reply })
+        .map_err(|_| stopped())?;
+    result.await.map_err(|_| stopped())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_and_append() {
+        if !is_available() {
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("body.cache");
+
+        let file = Arc::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap(),
+        );
+        let chunks: Vec<Bytes> = (0..4u8).map(|i| Bytes::from(vec![i; 100_000])).collect();
+        for chunk in &chunks {
+            append(file.clone(), chunk.clone()).await.unwrap();
+        }
+
+        let file = Arc::new(File::open(&path).unwrap());
+        let chunk = read_at(file.clone(), 150_000, 100_000).await.unwrap();
+        assert_eq!(chunk, [vec![1; 50_000], vec![2; 50_000]].concat());
+        // The last chunk is shorter
+        let chunk = read_at(file.clone(), 350_000, 100_000).await.unwrap();
+        assert_eq!(chunk, vec![3; 50_000]);
+        assert!(read_at(file, 400_000, 100_000).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
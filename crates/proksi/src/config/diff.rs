@@ -0,0 +1,279 @@
+use std::{collections::BTreeMap, fmt::Write as _, path::Path};
+
+use anyhow::anyhow;
+use serde_json::Value;
+
+use super::{export, load_from_path, Config, DiffArgs};
+
+/// A route or a listener of the candidate configuration, compared to the current one
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    /// The changed settings of a route or a listener (e.g. `cache`, `+ upstream ...`)
+    Changed(String, Vec<String>),
+}
+
+/// What applying the candidate configuration would change
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub routes: Vec<Change>,
+    pub listeners: Vec<Change>,
+    /// Sections outside of the routes and the listeners (e.g. `server`, `logging`)
+    pub settings: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.listeners.is_empty() && self.settings.is_empty()
+    }
+
+    pub fn render(&self) -> String {
+        if self.is_empty() {
+            return "no changes\n".to_string();
+        }
+
+        let mut output = String::new();
+        for (title, changes) in [("routes", &self.routes), ("listeners", &self.listeners)] {
+            if changes.is_empty() {
+                continue;
+            }
+            let _ = writeln!(output, "{title}:");
+            for change in changes {
+                let (sign, name, details) = match change {
+                    Change::Added(name) => ('+', name, &[][..]),
+                    Change::Removed(name) => ('-', name, &[][..]),
+                    Change::Changed(name, details) => ('~', name, details.as_slice()),
+                };
+                let _ = writeln!(output, "  {sign} {name}");
+                for detail in details {
+                    let _ = writeln!(output, "      {detail}");
+                }
+            }
+        }
+        if !self.settings.is_empty() {
+            output.push_str("settings:\n");
+            for section in &self.settings {
+                let _ = writeln!(output, "  ~ {section}");
+            }
+        }
+        output
+    }
+}
+
+/// `proksi diff`: prints what the candidate configuration would change, compared to the
+/// configuration of a running instance (with `--admin-url`) or to the current files
+pub fn run(current: &Config, args: &DiffArgs) -> anyhow::Result<()> {
+    if !args.file.exists() {
+        return Err(anyhow!("{:?} does not exist", args.file));
+    }
+    let candidate = load_candidate(&args.file)?;
+
+    let current = match args.admin_url.as_deref() {
+        Some(url) => running_config(url, args.token.as_deref())?,
+        None => export::to_value(current)?,
+    };
+
+    print!("{}", diff(&current, &export::to_value(&candidate)?).render());
+    Ok(())
+}
+
+/// The candidate is validated like the configuration proksi starts with
+fn load_candidate(path: &Path) -> anyhow::Result<Config> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{path:?} is not a valid path"))?;
+    load_from_path(path, &Config::default(), false)
+        .map_err(|err| anyhow!("invalid configuration {path}: {err}"))
+}
+
+/// The configuration of a running instance, from its admin API (`GET /config`)
+fn running_config(admin_url: &str, token: Option<&str>) -> anyhow::Result<Value> {
+    let url = reqwest::Url::parse(admin_url)
+        .and_then(|url| url.join("/config?format=json"))
+        .map_err(|err| anyhow!("admin URL {admin_url} is invalid: {err}"))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "the admin API responded with {}",
+                response.status()
+            ));
+        }
+        Ok(response.json::<Value>().await?)
+    })
+}
+
+/// Compares the exported configurations (see `export::to_value`), their secrets are
+/// redacted in both of them
+pub fn diff(current: &Value, candidate: &Value) -> ConfigDiff {
+    let mut settings = vec![];
+    let empty = serde_json::Map::new();
+    let current_object = current.as_object().unwrap_or(&empty);
+    let candidate_object = candidate.as_object().unwrap_or(&empty);
+    let mut sections = current_object
+        .keys()
+        .chain(candidate_object.keys())
+        .filter(|key| !["routes", "listeners", "discovered_routes"].contains(&key.as_str()))
+        .collect::<Vec<_>>();
+    sections.sort();
+    sections.dedup();
+    for section in sections {
+        if current_object.get(section) != candidate_object.get(section) {
+            settings.push(section.clone());
+        }
+    }
+
+    ConfigDiff {
+        routes: diff_list(current, candidate, "routes", "host"),
+        listeners: diff_list(current, candidate, "listeners", "name"),
+        settings,
+    }
+}
+
+/// Compares the items of a list by their `key` (e.g. the `host` of the routes)
+fn diff_list(current: &Value, candidate: &Value, list: &str, key: &str) -> Vec<Change> {
+    let by_key = |config: &Value| -> BTreeMap<String, Value> {
+        config[list]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| Some((item[key].as_str()?.to_string(), item.clone())))
+            .collect()
+    };
+    let current = by_key(current);
+    let candidate = by_key(candidate);
+
+    let mut changes = vec![];
+    for (name, item) in &candidate {
+        match current.get(name) {
+            None => changes.push(Change::Added(name.clone())),
+            Some(previous) if previous != item => {
+                changes.push(Change::Changed(name.clone(), diff_item(previous, item)));
+            }
+            Some(_) => {}
+        }
+    }
+    for name in current.keys().filter(|name| !candidate.contains_key(*name)) {
+        changes.push(Change::Removed(name.clone()));
+    }
+    changes
+}
+
+/// The changed settings of a route or a listener, with the upstreams added and removed
+fn diff_item(current: &Value, candidate: &Value) -> Vec<String> {
+    let mut details = vec![];
+
+    let addresses = |item: &Value| -> Vec<String> {
+        item["upstreams"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|upstream| match &upstream["ip"] {
+                Value::String(ip) => format!("{ip}:{}", upstream["port"]),
+                _ => upstream.to_string(),
+            })
+            .collect()
+    };
+    let (before, after) = (addresses(current), addresses(candidate));
+    for address in after.iter().filter(|a| !before.contains(a)) {
+        details.push(format!("+ upstream {address}"));
+    }
+    for address in before.iter().filter(|a| !after.contains(a)) {
+        details.push(format!("- upstream {address}"));
+    }
+
+    let empty = serde_json::Map::new();
+    let current = current.as_object().unwrap_or(&empty);
+    let candidate = candidate.as_object().unwrap_or(&empty);
+    let mut keys = current.keys().chain(candidate.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    // The upstreams are only marked as changed when none was added or removed (e.g. a
+    // changed weight)
+    let upstreams_listed = !details.is_empty();
+    for key in keys {
+        if current.get(key) != candidate.get(key) && !(key == "upstreams" && upstreams_listed) {
+            details.push(format!("~ {key}"));
+        }
+    }
+    details
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let current = json!({
+            "server": { "https_address": "0.0.0.0:443" },
+            "logging": { "level": "info" },
+            "routes": [
+                { "host": "a.localhost", "upstreams": [{ "ip": "10.0.0.1", "port": 3000 }] },
+                {
+                    "host": "b.localhost",
+                    "upstreams": [{ "ip": "10.0.0.2", "port": 3000 }],
+                    "cache": null
+                },
+                { "host": "c.localhost", "upstreams": [] }
+            ],
+            "listeners": [{ "name": "postgres", "address": "0.0.0.0:5432" }]
+        });
+        let candidate = json!({
+            "server": { "https_address": "0.0.0.0:443" },
+            "logging": { "level": "debug" },
+            "routes": [
+                { "host": "a.localhost", "upstreams": [{ "ip": "10.0.0.1", "port": 3000 }] },
+                {
+                    "host": "b.localhost",
+                    "upstreams": [{ "ip": "10.0.0.3", "port": 3000 }],
+                    "cache": { "enabled": true }
+                },
+                { "host": "d.localhost", "upstreams": [] }
+            ],
+            "listeners": [{ "name": "postgres", "address": "0.0.0.0:5432" }]
+        });
+
+        let diff = diff(&current, &candidate);
+        assert_eq!(
+            diff.routes,
+            vec![
+                Change::Changed(
+                    "b.localhost".to_string(),
+                    vec![
+                        "+ upstream 10.0.0.3:3000".to_string(),
+                        "- upstream 10.0.0.2:3000".to_string(),
+                        "~ cache".to_string(),
+                    ]
+                ),
+                Change::Added("d.localhost".to_string()),
+                Change::Removed("c.localhost".to_string()),
+            ]
+        );
+        assert!(diff.listeners.is_empty());
+        assert_eq!(diff.settings, vec!["logging".to_string()]);
+
+        let rendered = diff.render();
+        assert!(rendered.contains("  ~ b.localhost\n      + upstream 10.0.0.3:3000\n"));
+        assert!(rendered.contains("settings:\n  ~ logging\n"));
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let config = json!({ "routes": [{ "host": "a.localhost", "upstreams": [] }] });
+        let diff = diff(&config, &config);
+        assert!(diff.is_empty());
+        assert_eq!(diff.render(), "no changes\n");
+    }
+}
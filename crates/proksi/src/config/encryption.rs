@@ -0,0 +1,547 @@
+use std::{fmt::Write, io::Read, path::PathBuf, time::Duration};
+
+use age::armor::ArmoredReader;
+use anyhow::{anyhow, Context};
+use figment::{providers::Serialized, Figment};
+use openssl::{
+    sha::Sha512,
+    symm::{decrypt_aead, Cipher},
+};
+use serde_json::Value;
+
+use crate::services::secrets::aws::AwsSecrets;
+
+use super::SecretsAws;
+
+/// Decrypts the values of the configuration encrypted with sops (`ENC[AES256_GCM,...]`),
+/// with the data key of the `sops` metadata of the file. The figments without the
+/// metadata are left as they are.
+pub(super) fn decrypt(figment: Figment) -> Result<Figment, figment::Error> {
+    let mut value = figment.extract::<Value>()?;
+    let Some(metadata) = value.as_object_mut().and_then(|v| v.remove("sops")) else {
+        return Ok(figment);
+    };
+
+    data_key(&metadata, &value)
+        .and_then(|key| {
+            verify_mac(&sops_document(&figment)?, &metadata, &key)?;
+            decrypt_tree(&mut value, &mut vec![], &key)
+        })
+        .map_err(|err| {
+            figment::Error::from(format!("failed to decrypt the configuration: {err:#}"))
+        })?;
+
+    Ok(Figment::from(Serialized::defaults(value)))
+}
+
+/// The encrypted file, as it was written by sops: the MAC covers its values in their
+/// order in the file, which the merged configuration doesn't keep
+fn sops_document(figment: &Figment) -> anyhow::Result<serde_yaml::Value> {
+    let path = figment
+        .find_metadata("sops")
+        .and_then(|metadata| metadata.source.as_ref()?.file_path())
+        .ok_or_else(|| anyhow!("the sops metadata doesn't come from a file"))?;
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the encrypted file {path:?}"))?;
+    Ok(serde_yaml::from_str(&contents)?)
+}
+
+/// The data key of the file, decrypted with an age identity or a key of AWS KMS
+fn data_key(metadata: &Value, config: &Value) -> anyhow::Result<Vec<u8>> {
+    let mut errors = vec![];
+
+    let age = metadata["age"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if !age.is_empty() {
+        match age_data_key(age) {
+            Ok(key) => return check_data_key(key),
+            Err(err) => errors.push(format!("age: {err:#}")),
+        }
+    }
+
+    let kms = metadata["kms"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if !kms.is_empty() {
+        match kms_data_key(kms, config) {
+            Ok(key) => return check_data_key(key),
+            Err(err) => errors.push(format!("kms: {err:#}")),
+        }
+    }
+
+    if errors.is_empty() {
+        return Err(anyhow!("the sops metadata has no age or kms key"));
+    }
+    Err(anyhow!(
+        "the data key can't be decrypted ({})",
+        errors.join("; ")
+    ))
+}
+
+fn check_data_key(key: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if key.len() != 32 {
+        return Err(anyhow!("the data key is not 32 bytes"));
+    }
+    Ok(key)
+}
+
+fn age_data_key(recipients: &[Value]) -> anyhow::Result<Vec<u8>> {
+    let identities = age_identities()?;
+    recipients
+        .iter()
+        .filter_map(|recipient| recipient["enc"].as_str())
+        .find_map(|file| decrypt_age(file, &identities).ok())
+        .ok_or_else(|| anyhow!("no identity matches the recipients of the file"))
+}
+
+/// The age identities of `SOPS_AGE_KEY`, or of the file of `SOPS_AGE_KEY_FILE`
+/// (default: `~/.config/sops/age/keys.txt`), like sops
+fn age_identities() -> anyhow::Result<Vec<Box<dyn age::Identity>>> {
+    let keys = if let Ok(keys) = std::env::var("SOPS_AGE_KEY") {
+        keys
+    } else {
+        let path = std::env::var_os("SOPS_AGE_KEY_FILE")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| {
+                        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+                    })
+                    .map(|config| config.join("sops/age/keys.txt"))
+            })
+            .ok_or_else(|| anyhow!("SOPS_AGE_KEY or SOPS_AGE_KEY_FILE is required"))?;
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read the age identities {path:?}"))?
+    };
+
+    parse_age_identities(&keys)
+}
+
+/// Parses the `AGE-SECRET-KEY-1...` identities, one per line (`#` starts a comment)
+fn parse_age_identities(keys: &str) -> anyhow::Result<Vec<Box<dyn age::Identity>>> {
+    age::IdentityFile::from_buffer(keys.as_bytes())
+        .context("the age identities are invalid")?
+        .into_identities()
+        .map_err(|err| anyhow!("the age identities are invalid: {err}"))
+}
+
+/// Decrypts an armored age file with one of the identities
+fn decrypt_age(armored: &str, identities: &[Box<dyn age::Identity>]) -> anyhow::Result<Vec<u8>> {
+    let decryptor = age::Decryptor::new_buffered(ArmoredReader::new(armored.trim().as_bytes()))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))?;
+
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Decrypts the data key with the first key of AWS KMS that can, with the credentials of
+/// the environment (like the `aws-kms://` secret references)
+fn kms_data_key(keys: &[Value], config: &Value) -> anyhow::Result<Vec<u8>> {
+    let endpoint = config["secrets"]["aws"]["endpoint"].as_str();
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?;
+
+            let mut errors = vec![];
+            for key in keys {
+                let (Some(arn), Some(ciphertext)) = (key["arn"].as_str(), key["enc"].as_str())
+                else {
+                    continue;
+                };
+                // arn:aws:kms:<region>:<account>:key/<id>
+                let Some(region) = arn.split(':').nth(3) else {
+                    errors.push(format!("{arn} is not the ARN of a key"));
+                    continue;
+                };
+
+                let aws = AwsSecrets::new(&SecretsAws {
+                    region: region.to_string().into(),
+                    endpoint: endpoint.map(|endpoint| endpoint.to_string().into()),
+                })?;
+                let context = key.get("context").filter(|context| context.is_object());
+                match aws.decrypt_bytes(&client, ciphertext, context).await {
+                    Ok(data_key) => return Ok(data_key),
+                    Err(err) => errors.push(format!("{arn}: {err}")),
+                }
+            }
+
+            Err(anyhow!("{}", errors.join("; ")))
+        })
+}
+
+/// Decrypts the encrypted values of the tree. As with sops, the path of a value is
+/// authenticated with it: the keys leading to it, without the indexes of the lists.
+fn decrypt_tree(value: &mut Value, path: &mut Vec<String>, key: &[u8]) -> anyhow::Result<()> {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                path.push(name.clone());
+                decrypt_tree(value, path, key)?;
+                path.pop();
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                decrypt_tree(value, path, key)?;
+            }
+        }
+        Value::String(encrypted) if encrypted.starts_with("ENC[") => {
+            let decrypted = decrypt_value(encrypted, &format!("{}:", path.join(":")), key)
+                .with_context(|| format!("failed to decrypt {}", path.join(".")))?;
+            *value = decrypted;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Decrypts a value of sops, `ENC[AES256_GCM,data:<base64>,iv:<base64>,tag:<base64>,type:<type>]`,
+/// authenticated with its path (or the time of the last change, for the MAC)
+fn decrypt_value(encrypted: &str, additional_data: &str, key: &[u8]) -> anyhow::Result<Value> {
+    let fields = encrypted
+        .strip_prefix("ENC[AES256_GCM,")
+        .and_then(|fields| fields.strip_suffix(']'))
+        .ok_or_else(|| anyhow!("the value is not encrypted with AES256_GCM"))?;
+    let field = |name: &str| {
+        fields
+            .split(',')
+            .find_map(|field| field.strip_prefix(name)?.strip_prefix(':'))
+            .ok_or_else(|| anyhow!("the encrypted value has no {name}"))
+    };
+    let decode = |name: &str| -> anyhow::Result<Vec<u8>> {
+        Ok(openssl::base64::decode_block(field(name)?)?)
+    };
+
+    let plaintext = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&decode("iv")?),
+        additional_data.as_bytes(),
+        &decode("data")?,
+        &decode("tag")?,
+    )
+    .map_err(|_| anyhow!("the value can't be decrypted with the data key"))?;
+    let plaintext = String::from_utf8(plaintext).context("the value is not UTF-8")?;
+
+    match field("type")? {
+        "str" | "bytes" => Ok(Value::String(plaintext)),
+        "int" => Ok(Value::from(plaintext.parse::<i64>()?)),
+        "float" => Ok(Value::from(plaintext.parse::<f64>()?)),
+        // sops writes `True` and `False`
+        "bool" => Ok(Value::Bool(plaintext.eq_ignore_ascii_case("true"))),
+        other => Err(anyhow!("the type {other} is not supported")),
+    }
+}
+
+/// Verifies the MAC of the file (`sops.mac`): the SHA-512 of its values in clear, in their
+/// order in the file, encrypted with the data key and the time of the last change of the
+/// file. With `mac_only_encrypted`, only the encrypted values are covered.
+fn verify_mac(document: &serde_yaml::Value, metadata: &Value, key: &[u8]) -> anyhow::Result<()> {
+    let (Some(mac), Some(last_modified)) =
+        (metadata["mac"].as_str(), metadata["lastmodified"].as_str())
+    else {
+        return Err(anyhow!("the sops metadata has no mac or lastmodified"));
+    };
+    let expected = match decrypt_value(mac, last_modified, key) {
+        Ok(Value::String(mac)) => mac,
+        _ => {
+            return Err(anyhow!(
+                "the MAC of the file can't be decrypted with the data key"
+            ))
+        }
+    };
+
+    let mut digest = Sha512::new();
+    let only_encrypted = metadata["mac_only_encrypted"].as_bool().unwrap_or(false);
+    let values = document
+        .as_mapping()
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| name.as_str() != Some("sops"));
+    for (name, value) in values {
+        let mut path = vec![name.as_str().unwrap_or_default().to_string()];
+        digest_values(value, &mut path, key, only_encrypted, &mut digest)?;
+    }
+
+    let computed = mac_hex(digest);
+    if computed.len() != expected.len()
+        || !openssl::memcmp::eq(computed.as_bytes(), expected.as_bytes())
+    {
+        return Err(anyhow!(
+            "the MAC of the file doesn't match its values, the file was changed after sops encrypted it"
+        ));
+    }
+    Ok(())
+}
+
+/// Adds the values of the tree to the MAC, in clear and formatted like sops
+fn digest_values(
+    value: &serde_yaml::Value,
+    path: &mut Vec<String>,
+    key: &[u8],
+    only_encrypted: bool,
+    digest: &mut Sha512,
+) -> anyhow::Result<()> {
+    use serde_yaml::Value as Yaml;
+
+    let clear = match value {
+        Yaml::Mapping(map) => {
+            for (name, value) in map {
+                path.push(name.as_str().unwrap_or_default().to_string());
+                digest_values(value, path, key, only_encrypted, digest)?;
+                path.pop();
+            }
+            return Ok(());
+        }
+        Yaml::Sequence(values) => {
+            for value in values {
+                digest_values(value, path, key, only_encrypted, digest)?;
+            }
+            return Ok(());
+        }
+        Yaml::Tagged(tagged) => {
+            return digest_values(&tagged.value, path, key, only_encrypted, digest)
+        }
+        Yaml::String(encrypted) if encrypted.starts_with("ENC[") => {
+            match decrypt_value(encrypted, &format!("{}:", path.join(":")), key)
+                .with_context(|| format!("failed to decrypt {}", path.join(".")))?
+            {
+                Value::String(value) => value,
+                Value::Bool(value) => sops_bool(value).to_string(),
+                value => value.to_string(),
+            }
+        }
+        _ if only_encrypted => return Ok(()),
+        Yaml::String(value) => value.clone(),
+        Yaml::Bool(value) => sops_bool(*value).to_string(),
+        // Formatted like Go, without an exponent or a trailing `.0`
+        Yaml::Number(number) => match number.as_f64().filter(|_| number.is_f64()) {
+            Some(float) => float.to_string(),
+            None => number.to_string(),
+        },
+        Yaml::Null => String::new(),
+    };
+
+    digest.update(clear.as_bytes());
+    Ok(())
+}
+
+/// The MAC as sops writes it, in uppercase hexadecimal
+fn mac_hex(digest: Sha512) -> String {
+    digest.finish().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02X}");
+        hex
+    })
+}
+
+/// sops writes the booleans `True` and `False`
+fn sops_bool(value: bool) -> &'static str {
+    if value {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::providers::{Format, Yaml};
+
+    use super::*;
+
+    const IDENTITY: &str =
+        "AGE-SECRET-KEY-15ZE08S6VT30WCJX8XSDQQ09VY4TQUHPFH5TY3NPH2NMUDSHJDECS4LQCSC";
+
+    /// The data key encrypted by age for the recipient of `IDENTITY`
+    const DATA_KEY: &str = "-----BEGIN AGE ENCRYPTED FILE-----
+YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSBYTWQvU2oreTNTRkpORC9a
+SFhzM3Y3RmtPd3JOeEwxTitvT295b0J4RkVJCkFrbE01Um1rTm50K1BSR3FmUUNY
+eXk4MHRCSE1OK0VmWVhudXc2WEgxRUkKLS0tIEdxU3Vtd3poUVdlUDdZc1pnNmxF
+WDNVeC8rVVZza3ZDRkdESTJkMUZ1d0EKPcRjsplBYMBdaoDd69f8Lp5w23zaWwma
+PF770LgwmtOPsI6yJ2YApbxb8qVCxFLWE0Am4pDDsLRz3jLzFQcGFg==
+-----END AGE ENCRYPTED FILE-----
+";
+
+    fn data_key() -> Vec<u8> {
+        decrypt_age(DATA_KEY, &parse_age_identities(IDENTITY).unwrap()).unwrap()
+    }
+
+    /// The MAC of sops for the values in clear, encrypted with the data key
+    fn encrypt_mac(values: &[&str], last_modified: &str) -> String {
+        let mut digest = Sha512::new();
+        for value in values {
+            digest.update(value.as_bytes());
+        }
+        let mac = mac_hex(digest);
+
+        let iv = [7; 32];
+        let mut tag = [0; 16];
+        let data = openssl::symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &data_key(),
+            Some(&iv),
+            last_modified.as_bytes(),
+            mac.as_bytes(),
+            &mut tag,
+        )
+        .unwrap();
+        let encode = openssl::base64::encode_block;
+        format!(
+            "ENC[AES256_GCM,data:{},iv:{},tag:{},type:str]",
+            encode(&data),
+            encode(&iv),
+            encode(&tag)
+        )
+    }
+
+    #[test]
+    fn test_age_identity() {
+        assert_eq!(parse_age_identities(IDENTITY).unwrap().len(), 1);
+        assert_eq!(
+            parse_age_identities(&format!("# created: 2026-10-14\n{IDENTITY}\n"))
+                .unwrap()
+                .len(),
+            1
+        );
+        // A character changed: the checksum is invalid
+        assert!(parse_age_identities(&IDENTITY.replace("15ZE", "15ZF")).is_err());
+        assert!(parse_age_identities(
+            "age1c0x7qxquzpd47ujf4sqn9a669v4lu636na2j2f6936ncmndcvv0st4qfhm"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_decrypt_age() {
+        assert_eq!(data_key()[..4], [0xff, 0xc3, 0x09, 0xf0],);
+        assert_eq!(data_key().len(), 32);
+
+        // Another identity
+        let other = age::x25519::Identity::generate();
+        assert!(decrypt_age(DATA_KEY, &[Box::new(other)]).is_err());
+    }
+
+    #[test]
+    fn test_verify_mac() {
+        let last_modified = "2026-10-14T09:30:00Z";
+        let document: serde_yaml::Value = serde_yaml::from_str(
+            "admin:
+  address: 127.0.0.1:9091
+  token: ENC[AES256_GCM,data:7aUFaca0,iv:6Y9U1cDd5sfKtWuWpK4rX7qAWDToZ+8A+FF3+Fbzg+Q=,tag:s4+9IsXdDxXOQTfzoJ5O9g==,type:str]
+server:
+  workers: 4
+  upgrade: true
+  ratio: 1.0
+sops:
+  version: 3.9.0
+",
+        )
+        .unwrap();
+        let metadata = |values: &[&str], only_encrypted: bool| {
+            serde_json::json!({
+                "lastmodified": last_modified,
+                "mac": encrypt_mac(values, last_modified),
+                "mac_only_encrypted": only_encrypted,
+            })
+        };
+        let key = data_key();
+
+        // Every value in clear, in the order of the file
+        let values = ["127.0.0.1:9091", "s3cr3t", "4", "True", "1"];
+        assert!(verify_mac(&document, &metadata(&values, false), &key).is_ok());
+        assert!(verify_mac(&document, &metadata(&["s3cr3t"], true), &key).is_ok());
+
+        // A value changed, removed or reordered after the encryption
+        let changed = ["127.0.0.1:9092", "s3cr3t", "4", "True", "1"];
+        assert!(verify_mac(&document, &metadata(&changed, false), &key).is_err());
+        assert!(verify_mac(&document, &metadata(&values[1..], false), &key).is_err());
+        let reordered = ["s3cr3t", "127.0.0.1:9091", "4", "True", "1"];
+        assert!(verify_mac(&document, &metadata(&reordered, false), &key).is_err());
+
+        // The time of the last change is authenticated with the MAC
+        let mut moved = metadata(&values, false);
+        moved["lastmodified"] = Value::from("2026-10-15T09:30:00Z");
+        assert!(verify_mac(&document, &moved, &key).is_err());
+        assert!(verify_mac(&document, &serde_json::json!({}), &key).is_err());
+    }
+
+    #[test]
+    fn test_sops_document() {
+        let path = std::env::temp_dir().join(format!("proksi-sops-{}.yaml", std::process::id()));
+        std::fs::write(&path, "admin:\n  token: a\nsops:\n  version: 3.9.0\n").unwrap();
+
+        let figment = Figment::new()
+            .merge(Serialized::defaults(
+                serde_json::json!({ "admin": { "address": "127.0.0.1:9091" } }),
+            ))
+            .merge(Yaml::file(&path));
+        let document = sops_document(&figment).unwrap();
+        assert_eq!(document["admin"]["token"].as_str(), Some("a"));
+        assert!(document["admin"].get("address").is_none());
+
+        // The metadata doesn't come from a file, the MAC can't be verified
+        let figment = Figment::from(Serialized::defaults(serde_json::json!({ "sops": {} })));
+        assert!(sops_document(&figment).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_value() {
+        let key = data_key();
+        assert_eq!(
+            decrypt_value(
+                "ENC[AES256_GCM,data:7aUFaca0,iv:6Y9U1cDd5sfKtWuWpK4rX7qAWDToZ+8A+FF3+Fbzg+Q=,tag:s4+9IsXdDxXOQTfzoJ5O9g==,type:str]",
+                "admin:token:",
+                &key
+            )
+            .unwrap(),
+            Value::from("s3cr3t")
+        );
+        assert_eq!(
+            decrypt_value(
+                "ENC[AES256_GCM,data:uGM=,iv:CYFpypUCxB+0hq4CjwfirAhShuDTxhK/1ejsj/PCj6g=,tag:LMTmUEcRGAwyyKlfCfAN0Q==,type:int]",
+                "server:workers:",
+                &key
+            )
+            .unwrap(),
+            Value::from(42)
+        );
+
+        // The value was encrypted for another key
+        assert!(decrypt_value(
+            "ENC[AES256_GCM,data:7aUFaca0,iv:6Y9U1cDd5sfKtWuWpK4rX7qAWDToZ+8A+FF3+Fbzg+Q=,tag:s4+9IsXdDxXOQTfzoJ5O9g==,type:str]",
+            "routes:header:",
+            &key
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_decrypt_tree() {
+        let mut config = serde_json::json!({
+            "admin": {
+                "token": "ENC[AES256_GCM,data:7aUFaca0,iv:6Y9U1cDd5sfKtWuWpK4rX7qAWDToZ+8A+FF3+Fbzg+Q=,tag:s4+9IsXdDxXOQTfzoJ5O9g==,type:str]",
+                "address": "127.0.0.1:9091",
+            },
+            "routes": [{
+                "ssl": { "enabled": "ENC[AES256_GCM,data:Vg57FQ==,iv:l+JJtoCfa6q6/EDoSXgbgr9QQscGXg06lx320qbWhSQ=,tag:t0/LJevTvn3ntp1Te9Bd5Q==,type:bool]" },
+            }],
+        });
+        decrypt_tree(&mut config, &mut vec![], &data_key()).unwrap();
+
+        assert_eq!(config["admin"]["token"], "s3cr3t");
+        assert_eq!(config["admin"]["address"], "127.0.0.1:9091");
+        assert_eq!(config["routes"][0]["ssl"]["enabled"], true);
+    }
+}
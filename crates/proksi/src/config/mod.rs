@@ -1,22 +1,28 @@
-use std::{borrow::Cow, collections::HashMap, path::PathBuf};
+use std::{borrow::Cow, collections::HashMap, net::IpAddr, path::PathBuf};
 
-use clap::{Args, Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use figment::{
     providers::{Env, Format, Serialized, Yaml},
     Figment, Provider,
 };
 use hcl::Hcl;
+use ipnet::IpNet;
 
 use serde::{Deserialize, Deserializer, Serialize};
 use tracing::level_filters::LevelFilter;
 
+pub mod diff;
+mod encryption;
+pub mod export;
 mod hcl;
-mod validate;
+pub mod validate;
 
 #[derive(Debug, Serialize, Deserialize, Clone, ValueEnum)]
 pub enum StoreType {
     Memory,
     Redis,
+    /// A file on disk (see `StoreConfig::path`), for a single instance
+    File,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,6 +30,14 @@ pub struct StoreConfig {
     #[serde(deserialize_with = "store_type_deser")]
     pub store_type: StoreType,
     pub redis_url: Option<String>,
+
+    /// Path to the file of the `file` store (e.g. `/var/lib/proksi/store.redb`)
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// State of this instance kept across restarts, whatever the store type
+    #[serde(default)]
+    pub warm_restart: Option<WarmRestart>,
 }
 
 impl Default for StoreConfig {
@@ -31,10 +45,36 @@ impl Default for StoreConfig {
         Self {
             store_type: StoreType::Memory,
             redis_url: None,
+            path: None,
+            warm_restart: None,
         }
     }
 }
 
+/// The health of the upstreams, the renewal status of the certificates and the windows of
+/// the rate limiters are saved in a file, and restored on startup
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WarmRestart {
+    /// File of the state (e.g. `/var/lib/proksi/state.redb`)
+    pub path: PathBuf,
+
+    /// Interval (in seconds) between two saves of the state, it's saved on shutdown too
+    #[serde(default = "default_warm_restart_save_interval")]
+    pub save_interval_secs: u64,
+
+    /// Age (in seconds) after which a saved state is too old to be restored
+    #[serde(default = "default_warm_restart_max_age")]
+    pub max_age_secs: u64,
+}
+
+fn default_warm_restart_save_interval() -> u64 {
+    30
+}
+
+fn default_warm_restart_max_age() -> u64 {
+    600
+}
+
 /// Default fn for boolean values
 fn bool_true() -> bool {
     true
@@ -64,6 +104,60 @@ fn default_cache_path() -> PathBuf {
     PathBuf::from("/tmp")
 }
 
+fn default_upstream_pool_size() -> usize {
+    128
+}
+
+fn default_upstream_pool_idle_timeout() -> u64 {
+    360
+}
+
+fn default_shutdown_drain_secs() -> u64 {
+    300
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    5
+}
+
+fn default_cache_memory_size() -> usize {
+    crate::cache::disk::memory::DEFAULT_MEMORY_SIZE
+}
+
+fn default_compression_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Brotli,
+        CompressionAlgorithm::Gzip,
+    ]
+}
+
+fn default_compression_content_types() -> Vec<Cow<'static, str>> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "application/wasm",
+        "image/svg+xml",
+    ]
+    .into_iter()
+    .map(Cow::Borrowed)
+    .collect()
+}
+
+fn default_compression_min_size() -> u64 {
+    1024
+}
+
+fn default_forwarded_headers_enabled() -> bool {
+    true
+}
+
+fn default_min_body_rate_grace() -> u64 {
+    5
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, ValueEnum)]
 pub(crate) enum DockerServiceMode {
     Swarm,
@@ -139,6 +233,10 @@ pub struct LetsEncrypt {
 
     /// Renewal check interval in seconds (default: 84600 - a day)
     pub renew_interval_secs: Option<u64>,
+
+    /// Request the HTTP-01 challenges before asking the CA to validate them (default: true),
+    /// disable it when the proxy can't reach its own public address (e.g. no hairpin NAT)
+    pub self_check: Option<bool>,
 }
 
 impl Default for LetsEncrypt {
@@ -148,10 +246,172 @@ impl Default for LetsEncrypt {
             enabled: Some(false),
             staging: Some(true),
             renew_interval_secs: Some(84_600),
+            self_check: Some(true),
+        }
+    }
+}
+
+/// Vault server of the `vault://<path>#<field>` secret references
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SecretsVault {
+    /// Address of Vault (e.g. `https://vault.internal:8200`)
+    pub url: Cow<'static, str>,
+
+    /// Token of Vault, defaults to the `VAULT_TOKEN` environment variable
+    pub token: Option<Cow<'static, str>>,
+
+    /// Namespace of the secrets (Vault Enterprise)
+    pub namespace: Option<Cow<'static, str>>,
+
+    /// PEM file of the root certificate of the CA, trusted for the connections to Vault
+    pub ca_certificate: Option<PathBuf>,
+}
+
+/// AWS region of the `aws-sm://<secret id>#<field>` (Secrets Manager) and
+/// `aws-kms://<ciphertext>` (KMS) secret references. The credentials come from the
+/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SecretsAws {
+    pub region: Cow<'static, str>,
+
+    /// Replaces the endpoints of the region (e.g. `http://localhost:4566` for `LocalStack`)
+    pub endpoint: Option<Cow<'static, str>>,
+}
+
+/// Providers of the secret references, the values of the configuration that are fetched
+/// at startup and refreshed at runtime instead of being written in the files
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Secrets {
+    /// Interval of the refreshes of the secrets, in seconds (default: 300)
+    #[serde(default = "default_secrets_refresh_interval")]
+    pub refresh_interval_secs: u64,
+
+    pub vault: Option<SecretsVault>,
+
+    pub aws: Option<SecretsAws>,
+}
+
+fn default_secrets_refresh_interval() -> u64 {
+    300
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Self {
+            refresh_interval_secs: default_secrets_refresh_interval(),
+            vault: None,
+            aws: None,
+        }
+    }
+}
+
+/// Rules evaluated by proksi on the requests and the upstreams of the routes, notifying
+/// webhooks when they are breached (for the setups without Prometheus and Alertmanager)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Alerts {
+    /// Interval of the evaluations of the rules, in seconds (default: 30)
+    #[serde(default = "default_alerts_interval")]
+    pub interval_secs: u64,
+
+    #[serde(default)]
+    pub webhooks: Vec<AlertWebhook>,
+
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+}
+
+fn default_alerts_interval() -> u64 {
+    30
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_alerts_interval(),
+            webhooks: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+/// An endpoint receiving the notifications of the alerts as JSON (`POST`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlertWebhook {
+    pub name: String,
+
+    pub url: String,
+
+    /// Headers of the requests (e.g. `Authorization`)
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// The value of a route a rule compares with its threshold
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// Share of the requests of the window answered with a 5xx, in %
+    ErrorRate,
+    /// 95th percentile of the duration of the requests of the window, in milliseconds
+    LatencyP95,
+    /// Upstreams of the route that are unhealthy now
+    UnhealthyUpstreams,
+}
+
+impl AlertCondition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ErrorRate => "error_rate",
+            Self::LatencyP95 => "latency_p95",
+            Self::UnhealthyUpstreams => "unhealthy_upstreams",
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlertRule {
+    pub name: String,
+
+    pub condition: AlertCondition,
+
+    /// The rule fires for a route once the value of its condition is above it
+    pub threshold: f64,
+
+    /// Hosts of the routes the rule applies to, every route when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Rolling window of the error rate and the latency, in seconds (default: 300)
+    #[serde(default = "default_alert_window")]
+    pub window_secs: u64,
+
+    /// Requests a route needs in the window for its error rate and latency to be
+    /// evaluated (default: 20)
+    #[serde(default = "default_alert_min_requests")]
+    pub min_requests: u64,
+
+    /// Names of the webhooks notified, every webhook when empty
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+
+    /// Time before the webhooks are notified again of the rule firing for a route, while
+    /// it keeps firing or when it fires again, in seconds (default: 600)
+    #[serde(default = "default_alert_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+fn default_alert_window() -> u64 {
+    300
+}
+
+fn default_alert_min_requests() -> u64 {
+    20
+}
+
+fn default_alert_cooldown() -> u64 {
+    600
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Path {
     // TLS
@@ -182,6 +442,23 @@ pub struct RouteHeaderRemove {
     pub name: Cow<'static, str>,
 }
 
+/// Header rules evaluated for each request. Values can contain variables
+/// (ex: `$remote_addr`, `$host`, `$upstream_addr`, `$request_id`)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteHeaderRules {
+    /// Headers to add, keeping the existing ones with the same name
+    #[serde(default)]
+    pub add: Vec<RouteHeaderAdd>,
+
+    /// Headers to add, replacing the existing ones with the same name
+    #[serde(default)]
+    pub set: Vec<RouteHeaderAdd>,
+
+    /// Headers to remove
+    #[serde(default)]
+    pub remove: Vec<RouteHeaderRemove>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteHeader {
     /// The name of the header
@@ -189,6 +466,12 @@ pub struct RouteHeader {
 
     /// The value of the header
     pub remove: Option<Vec<RouteHeaderRemove>>,
+
+    /// Rules applied to the request sent to the upstream
+    pub request: Option<RouteHeaderRules>,
+
+    /// Rules applied to the response received from the upstream
+    pub response: Option<RouteHeaderRules>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -208,9 +491,36 @@ pub struct RouteUpstream {
 
     pub sni: Option<String>,
 
+    /// The HTTP version used with the upstream, negotiated with ALPN for TLS
+    /// upstreams (default: `auto`)
+    pub protocol: Option<UpstreamProtocol>,
+
     pub headers: Option<RouteHeader>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum UpstreamProtocol {
+    /// HTTP/2 when the TLS upstream supports it, HTTP/1.1 otherwise (and for plaintext upstreams)
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "http1")]
+    Http1,
+    /// HTTP/2 only, with prior knowledge (h2c) for plaintext upstreams
+    #[serde(rename = "h2")]
+    H2,
+}
+
+/// Transforms the `UpstreamProtocol` into the ALPN preference of the pingora peers
+impl From<UpstreamProtocol> for pingora::protocols::ALPN {
+    fn from(val: UpstreamProtocol) -> Self {
+        match val {
+            UpstreamProtocol::Auto => pingora::protocols::ALPN::H2H1,
+            UpstreamProtocol::Http1 => pingora::protocols::ALPN::H1,
+            UpstreamProtocol::H2 => pingora::protocols::ALPN::H2,
+        }
+    }
+}
+
 impl Default for RouteUpstream {
     fn default() -> Self {
         RouteUpstream {
@@ -219,17 +529,68 @@ impl Default for RouteUpstream {
             network: None,
             weight: None,
             sni: None,
+            protocol: None,
             headers: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSslCertificate {
     /// Whether to use a self-signed certificate if the certificate can't be
     /// retrieved from the path or object storage (or generated from letsencrypt)
     /// (defaults to true)
     pub self_signed_on_failure: Option<bool>,
+
+    /// Name of the certificate issuer (see `certificate_issuers`) ordering the certificate,
+    /// instead of Let's Encrypt
+    pub issuer: Option<Cow<'static, str>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CertificateIssuerType {
+    /// An ACME server (e.g. step-ca), validating the domains with HTTP-01 challenges
+    #[serde(rename = "acme")]
+    Acme,
+    /// The PKI secrets engine of HashiCorp Vault
+    #[serde(rename = "vault")]
+    Vault,
+}
+
+/// A private CA issuing the certificates of the routes that select it, e.g. for internal
+/// hostnames that public CAs can't validate
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CertificateIssuer {
+    /// Name of the issuer, selected by the `ssl_certificate.issuer` of the routes
+    pub name: Cow<'static, str>,
+
+    #[serde(rename = "type")]
+    pub issuer_type: CertificateIssuerType,
+
+    /// URL of the ACME directory (`acme`) or address of Vault (`vault`)
+    pub url: Cow<'static, str>,
+
+    /// PEM file of the root certificate of the CA, trusted for the connections to the issuer
+    pub ca_certificate: Option<PathBuf>,
+
+    /// Contact of the ACME account (`acme`)
+    pub email: Option<Cow<'static, str>>,
+
+    /// Token of Vault, defaults to the `VAULT_TOKEN` environment variable (`vault`)
+    pub token: Option<Cow<'static, str>>,
+
+    /// Path of the PKI secrets engine, defaults to `pki` (`vault`)
+    pub mount: Option<Cow<'static, str>>,
+
+    /// Role issuing the certificates (`vault`)
+    pub role: Option<Cow<'static, str>>,
+
+    /// Lifetime of the certificates (e.g. `720h`), defaults to the TTL of the role (`vault`)
+    pub ttl: Option<Cow<'static, str>>,
+
+    /// Request the HTTP-01 challenges before asking the CA to validate them (`acme`,
+    /// default: true)
+    pub self_check: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -256,19 +617,25 @@ pub struct RoutePlugin {
     pub config: Option<HashMap<Cow<'static, str>, serde_json::Value>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSslPath {
     /// Path to the certificate .key file (e.g. `/etc/proksi/certs/my-host.key`)
     pub key: PathBuf,
 
     /// Path to the certificate .pem file (e.g. `/etc/proksi/certs/my-host.pem`)
     pub pem: PathBuf,
+
+    /// Passphrase of the .key file, when it's encrypted
+    pub passphrase: Option<Cow<'static, str>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ProtoVersion {
+    #[serde(rename = "v1.1")]
     V1_1,
+    #[serde(rename = "v1.2")]
     V1_2,
+    #[serde(rename = "v1.3")]
     V1_3,
 }
 
@@ -283,7 +650,7 @@ impl From<pingora::tls::ssl::SslVersion> for ProtoVersion {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSsl {
     /// If provided, will be used instead of generating certificates from
     /// Let's Encrypt or self-signed certificates.
@@ -314,6 +681,101 @@ pub struct RouteSsl {
     /// The default value is <true>.
     #[serde(default = "bool_true")]
     pub self_signed_fallback: bool,
+
+    /// Verification of the certificates of the clients (mutual TLS)
+    pub client_auth: Option<RouteSslClientAuth>,
+}
+
+/// Certificates the clients of a route authenticate with, verified during the handshake
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteSslClientAuth {
+    /// PEM file of the CA certificates issuing the client certificates
+    pub ca: PathBuf,
+
+    /// Whether the clients must send a certificate (default: true). Otherwise the clients
+    /// without one are proxied too, and those sending one must send a valid one.
+    #[serde(default = "bool_true")]
+    pub required: bool,
+
+    /// Details of the client certificate sent to the upstreams
+    pub forward: Option<RouteClientCertificateForward>,
+
+    /// Checks that the client certificates aren't revoked, with CRLs or OCSP
+    pub revocation: Option<RouteClientCertificateRevocation>,
+}
+
+/// Revocation checks of the client certificates: the CRLs are checked during the
+/// handshake, the OCSP responders for the requests
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteClientCertificateRevocation {
+    /// CRL files (PEM or DER) signed by the CA certificates, read again when they change
+    #[serde(default)]
+    pub crl: Vec<PathBuf>,
+
+    /// Whether the OCSP responders of the certificates are asked for their status
+    /// (default: false)
+    #[serde(default)]
+    pub ocsp: bool,
+
+    /// Timeout of the OCSP requests, in seconds (default: 5)
+    #[serde(default = "default_ocsp_timeout")]
+    pub ocsp_timeout_secs: u64,
+
+    /// How long the statuses given by the OCSP responders are kept, in seconds
+    /// (default: 3600)
+    #[serde(default = "default_ocsp_cache")]
+    pub ocsp_cache_secs: u64,
+
+    /// What to do with the certificates whose status is unknown (no valid CRL of their
+    /// issuer, no answer of their OCSP responder)
+    #[serde(default)]
+    pub policy: RevocationPolicy,
+}
+
+fn default_ocsp_timeout() -> u64 {
+    5
+}
+
+fn default_ocsp_cache() -> u64 {
+    3600
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum RevocationPolicy {
+    /// The certificates whose status is unknown are rejected
+    #[default]
+    #[serde(rename = "fail_closed")]
+    FailClosed,
+    /// The certificates whose status is unknown are accepted, only the revoked ones are
+    /// rejected
+    #[serde(rename = "fail_open")]
+    FailOpen,
+}
+
+/// The headers carrying the verified certificate of the client to the upstreams. The
+/// headers sent by the clients themselves are always removed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteClientCertificateForward {
+    #[serde(default)]
+    pub format: ClientCertificateFormat,
+
+    /// Whether the certificate itself is sent, as a URL-encoded PEM (default: false)
+    #[serde(default)]
+    pub include_pem: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum ClientCertificateFormat {
+    /// A single `X-Forwarded-Client-Cert` header, as sent by Envoy
+    /// (`Hash=...;Subject="...";URI=...;DNS=...`)
+    #[default]
+    #[serde(rename = "xfcc")]
+    Xfcc,
+    /// A header per detail: `X-Client-Cert-Fingerprint`, `X-Client-Cert-Subject`,
+    /// `X-Client-Cert-Issuer`, `X-Client-Cert-Serial`, `X-Client-Cert-DNS`,
+    /// `X-Client-Cert-URI` and `X-Client-Cert`
+    #[serde(rename = "headers")]
+    Headers,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -343,79 +805,516 @@ pub struct RouteCache {
     pub path: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Route {
-    /// The hostname that the proxy will accept
-    /// requests for the upstreams in the route.
-    /// (ex: 'example.com', 'www.example.com', etc.)
-    ///
-    /// This is the host header that the proxy will match and will
-    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
-    pub host: Cow<'static, str>,
+/// IP filtering rules evaluated against the client IP.
+/// Denied networks take precedence over allowed ones and, when `allow` is not empty,
+/// any IP outside of the allowed networks is denied.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct IpFilter {
+    /// Networks (CIDR) or single IPs allowed to access the routes
+    #[serde(default, deserialize_with = "deserialize_ip_networks")]
+    pub allow: Vec<IpNet>,
+
+    /// Networks (CIDR) or single IPs denied from accessing the routes
+    #[serde(default, deserialize_with = "deserialize_ip_networks")]
+    pub deny: Vec<IpNet>,
+}
 
-    pub cache: Option<RouteCache>,
+impl IpFilter {
+    /// Returns `true` if the given IP can access the routes protected by this filter
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
 
-    /// Plugins that will be applied to the route/host
-    /// (ex: rate limiting, oauth2, etc.)
-    pub plugins: Option<Vec<RoutePlugin>>,
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
 
-    /// SSL certificate configurations for the given host
-    /// (ex: self-signed, path/object storage, etc.)
-    pub ssl_certificate: Option<RouteSslCertificate>,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CompressionAlgorithm {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
 
-    /// SSL configuration for the route
-    pub ssl: Option<RouteSsl>,
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteCompression {
+    pub enabled: Option<bool>,
 
-    /// Header modifications for the given route (remove, add, etc. )
-    pub headers: Option<RouteHeader>,
+    /// Supported algorithms, by order of preference when the client
+    /// accepts more than one with the same quality
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<CompressionAlgorithm>,
 
-    /// The upstreams to which the request will be proxied,
-    pub upstreams: Vec<RouteUpstream>,
+    /// Compression level used by every algorithm. When not set, a level
+    /// balancing speed and size is used for each algorithm.
+    pub level: Option<u32>,
 
-    /// The matcher for the route
-    /// (ex: path, query, etc.)
-    pub match_with: Option<RouteMatcher>,
-}
+    /// Content types that will be compressed (`text/*` matches every text type)
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<Cow<'static, str>>,
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogLevel {
-    Debug,
-    Info,
-    Warn,
-    Error,
-    Trace,
+    /// Responses smaller than this (in bytes, based on `Content-Length`) are not compressed
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: u64,
 }
 
-/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
-/// enum used by the `tracing` crate.
-impl From<&LogLevel> for tracing::level_filters::LevelFilter {
-    fn from(val: &LogLevel) -> Self {
-        match val {
-            LogLevel::Debug => LevelFilter::DEBUG,
-            LogLevel::Info => LevelFilter::INFO,
-            LogLevel::Warn => LevelFilter::WARN,
-            LogLevel::Error => LevelFilter::ERROR,
-            LogLevel::Trace => LevelFilter::TRACE,
-        }
-    }
+/// WebSocket connections of a route (`Upgrade: websocket` requests)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteWebSocket {
+    /// Whether upgrades are allowed. Defaults to `true` when the section is present.
+    pub enabled: Option<bool>,
+
+    /// Time without receiving anything from one of the sides after which
+    /// the connection is closed, in seconds
+    pub idle_timeout: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
-pub enum LogRotation {
-    #[default]
-    Never,
-    Daily,
-    Hourly,
-    Minutely,
+/// gRPC calls of a route (`Content-Type: application/grpc` requests),
+/// always sent to the upstream over HTTP/2
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteGrpc {
+    /// Whether gRPC calls are detected. Defaults to `true` when the section is present,
+    /// when disabled they are proxied like any other request.
+    pub enabled: Option<bool>,
+
+    /// Time without receiving anything from the upstream after which the call is
+    /// aborted, in seconds (long-lived streams need more than the default of 360)
+    pub timeout: Option<u64>,
+
+    /// Maximum number of concurrent calls multiplexed on a single upstream connection
+    pub max_streams: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogFormat {
-    Json,
-    Pretty,
+/// Server-Sent Events and long-polling responses of a route, sent to the client
+/// as soon as they are received from the upstream
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteStreaming {
+    /// Defaults to `true` when the section is present. The responses are never cached,
+    /// compressed or rewritten.
+    pub enabled: Option<bool>,
+
+    /// Time without receiving anything from the upstream after which the response is
+    /// aborted, in seconds (defaults to 3600)
+    pub idle_timeout: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+/// Files of a directory served by a route instead of its upstreams, e.g. the build of a
+/// website or of a single-page app
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteStaticFiles {
+    /// Defaults to `true` when the section is present
+    pub enabled: Option<bool>,
+
+    /// Directory of the files, a request path is a path relative to it
+    pub root: PathBuf,
+
+    /// File served for the paths of the directories (defaults to `index.html`)
+    pub index: Option<String>,
+
+    /// File (relative to `root`) served for the paths that match no file, except the paths
+    /// of assets (with an extension, e.g. `/app.js`) that stay a 404. `index.html` serves
+    /// the entrypoint of a single-page app for the paths routed on the client.
+    pub fallback: Option<PathBuf>,
+
+    /// Algorithms of the files compressed at build time, by order of preference. A file is
+    /// sent compressed when the client accepts one of them and its sibling exists
+    /// (`app.js.br`, `app.js.gz` or `app.js.zst`).
+    #[serde(default)]
+    pub precompressed: Vec<CompressionAlgorithm>,
+}
+
+/// Pages of the responses generated by the proxy (errors of the upstreams, requests denied
+/// or not found) and maintenance mode of a route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteErrorPages {
+    /// Defaults to `true` when the section is present
+    pub enabled: Option<bool>,
+
+    /// Directory of the HTML templates: `<status>.html` (e.g. `502.html`), then
+    /// `<class>xx.html` (e.g. `5xx.html`), then `error.html`, and `maintenance.html`.
+    /// Without a template the responses have no body, except for the JSON clients.
+    pub directory: Option<PathBuf>,
+
+    /// Every request gets a `503` with the maintenance page, instead of being proxied
+    #[serde(default)]
+    pub maintenance: bool,
+
+    /// Seconds after which the clients can retry during the maintenance (`Retry-After`)
+    pub retry_after: Option<u64>,
+}
+
+/// Requests of a client sent to the same upstream, for as long as its session is active.
+/// The upstream is kept in a cookie, signed with a key of the store (shared with Redis).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteStickySessions {
+    /// Defaults to `true` when the section is present
+    pub enabled: Option<bool>,
+
+    /// Name of the cookie holding the session (defaults to `proksi_session`)
+    pub cookie: Option<String>,
+
+    /// Time without requests after which the session is forgotten, in seconds
+    /// (defaults to 3600)
+    pub idle_timeout: Option<u64>,
+}
+
+/// Two pools of upstreams of a route, the requests going to the `active` one. Deploy
+/// tooling flips the active pool with the admin API (`PUT /routes/{host}/active`).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteBlueGreen {
+    pub blue: Vec<RouteUpstream>,
+
+    pub green: Vec<RouteUpstream>,
+
+    /// The pool receiving the requests (default: `blue`)
+    #[serde(default)]
+    pub active: BlueGreenPool,
+
+    /// Switches back to the previous pool when the new one fails too many requests
+    pub rollback: Option<RouteBlueGreenRollback>,
+}
+
+impl RouteBlueGreen {
+    /// The upstreams of the active pool
+    pub fn active_upstreams(&self) -> &[RouteUpstream] {
+        match self.active {
+            BlueGreenPool::Blue => &self.blue,
+            BlueGreenPool::Green => &self.green,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlueGreenPool {
+    #[default]
+    #[serde(rename = "blue")]
+    Blue,
+    #[serde(rename = "green")]
+    Green,
+}
+
+impl BlueGreenPool {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BlueGreenPool::Blue => "blue",
+            BlueGreenPool::Green => "green",
+        }
+    }
+
+    /// The inactive pool, when this one is active
+    pub fn other(self) -> Self {
+        match self {
+            BlueGreenPool::Blue => BlueGreenPool::Green,
+            BlueGreenPool::Green => BlueGreenPool::Blue,
+        }
+    }
+}
+
+/// The errors watched after a switch of the active pool
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteBlueGreenRollback {
+    /// Share of the requests answered with a 5xx or failing to be proxied, from 0 to 1,
+    /// above which the previous pool is active again (default: 0.05)
+    #[serde(default = "default_rollback_max_error_rate")]
+    pub max_error_rate: f64,
+
+    /// Requests received by the new pool before its error rate is checked (default: 20)
+    #[serde(default = "default_rollback_min_requests")]
+    pub min_requests: u32,
+
+    /// Time after a switch during which its errors are watched, in seconds (default: 300)
+    #[serde(default = "default_rollback_window")]
+    pub window_secs: u64,
+}
+
+fn default_rollback_max_error_rate() -> f64 {
+    0.05
+}
+
+fn default_rollback_min_requests() -> u32 {
+    20
+}
+
+fn default_rollback_window() -> u64 {
+    300
+}
+
+/// A share of the requests of a route sent to a canary pool of upstreams, the others going
+/// to its `upstreams` (or to the active pool of `blue_green`). The share is changed at
+/// runtime with the admin API (`PUT /routes/{host}/canary`).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteCanary {
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// Percentage of the requests sent to the canary, from 0 to 100 (default: 0)
+    #[serde(default)]
+    pub weight: u8,
+
+    /// Clients keep the pool they were sent to, instead of a pool picked per request
+    pub sticky: Option<RouteCanarySticky>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteCanarySticky {
+    /// What identifies a client (default: `cookie`)
+    #[serde(default)]
+    pub by: CanaryStickyKey,
+
+    /// Name of the cookie (defaults to `proksi_canary`) or of the header identifying the
+    /// client (required with `header`)
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CanaryStickyKey {
+    /// A cookie set by proksi on the first response to the client
+    #[default]
+    #[serde(rename = "cookie")]
+    Cookie,
+    /// A header of the requests, e.g. the ID of the user set by an authentication layer
+    #[serde(rename = "header")]
+    Header,
+    /// The IP address of the client
+    #[serde(rename = "client_ip")]
+    ClientIp,
+}
+
+/// A copy of a share of the requests of a route sent to a shadow upstream, e.g. to try a
+/// new backend with production traffic. The responses of the shadow are discarded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteMirror {
+    /// URL of the shadow upstream (e.g. `http://10.0.4.1:3000`), receiving the requests
+    /// with their path and query
+    pub url: String,
+
+    /// Percentage of the requests copied, from 0 to 100 (default: 100)
+    #[serde(default = "default_mirror_percentage")]
+    pub percentage: u8,
+
+    /// Copies the bodies of the requests too (default: false)
+    #[serde(default)]
+    pub include_body: bool,
+
+    /// Requests with a larger body are not copied, in bytes (default: 1 MiB)
+    pub max_body_size: Option<usize>,
+
+    /// Time given to the shadow upstream to respond, in seconds (default: 5)
+    pub timeout_secs: Option<u64>,
+
+    /// Copies waiting for the shadow upstream, the next ones are dropped (default: 100)
+    pub max_in_flight: Option<usize>,
+}
+
+fn default_mirror_percentage() -> u8 {
+    100
+}
+
+/// A sample of the requests of a route written to a file, with their headers and body, to
+/// be sent again with `proksi replay`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteCapture {
+    /// File the requests are appended to, one JSON object per line
+    pub path: PathBuf,
+
+    /// Percentage of the requests captured, from 0 to 100 (default: 1)
+    #[serde(default = "default_capture_percentage")]
+    pub percentage: u8,
+
+    /// Bodies are cut to this size, in bytes (default: 64 KiB)
+    pub max_body_size: Option<usize>,
+
+    /// Headers whose values are replaced by `<redacted>`, added to `authorization`,
+    /// `proxy-authorization` and `cookie`
+    #[serde(default)]
+    pub redact_headers: Vec<String>,
+
+    /// Regular expressions of the parts of the bodies replaced by `<redacted>`
+    /// (e.g. `"password":\s*"[^"]*"`)
+    #[serde(default)]
+    pub redact_body: Vec<String>,
+}
+
+fn default_capture_percentage() -> u8 {
+    1
+}
+
+/// A sample of the request and response bodies of a route written to the logs, to debug
+/// integrations with its clients
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteBodyLogging {
+    /// Percentage of the requests logged, from 0 to 100 (default: 1)
+    #[serde(default = "default_capture_percentage")]
+    pub percentage: u8,
+
+    /// Bodies are cut to this size, in bytes (default: 4 KiB)
+    pub max_body_size: Option<usize>,
+
+    /// Logs the request bodies (default: true)
+    #[serde(default = "bool_true")]
+    pub request: bool,
+
+    /// Logs the response bodies (default: true)
+    #[serde(default = "bool_true")]
+    pub response: bool,
+
+    /// Regular expressions of the parts of the bodies replaced by `<redacted>`
+    /// (e.g. `\d{16}`)
+    #[serde(default)]
+    pub redact_body: Vec<String>,
+
+    /// Paths of the fields of the JSON bodies replaced by `<redacted>` (e.g. `$.password`,
+    /// `$.cards[*].number` or `$..token`)
+    #[serde(default)]
+    pub redact_json: Vec<String>,
+}
+
+/// Size limits of the requests of a route, and buffering of their body
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteLimits {
+    /// Maximum size of the request line and headers, in bytes (`431` when exceeded)
+    pub max_header_size: Option<u64>,
+
+    /// Maximum size of the body as sent by the client, in bytes (`413` when exceeded)
+    pub max_body_size: Option<u64>,
+
+    /// Maximum size of the headers and the body together, in bytes (`413` when exceeded)
+    pub max_request_size: Option<u64>,
+
+    /// The body is fully received before being sent to the upstream, instead of being
+    /// streamed as it arrives
+    #[serde(default)]
+    pub buffer_body: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Route {
+    /// The hostname that the proxy will accept
+    /// requests for the upstreams in the route.
+    /// (ex: 'example.com', 'www.example.com', etc.)
+    ///
+    /// This is the host header that the proxy will match and will
+    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
+    pub host: Cow<'static, str>,
+
+    pub cache: Option<RouteCache>,
+
+    /// Response compression based on the `Accept-Encoding` of the request
+    pub compression: Option<RouteCompression>,
+
+    /// Size limits of the requests (headers, body) and buffering of their body
+    pub limits: Option<RouteLimits>,
+
+    /// WebSocket upgrades and idle timeout of the tunneled connections
+    pub websocket: Option<RouteWebSocket>,
+
+    /// Upstream connection and timeout of the gRPC calls
+    pub grpc: Option<RouteGrpc>,
+
+    /// Responses sent without delay (Server-Sent Events, long-polling)
+    pub streaming: Option<RouteStreaming>,
+
+    /// Requests of a session sent to the same upstream
+    pub sticky_sessions: Option<RouteStickySessions>,
+
+    /// Files served from a directory, instead of the upstreams
+    pub static_files: Option<RouteStaticFiles>,
+
+    /// Content types of file extensions (e.g. `wasm: application/wasm`). They replace the
+    /// default types of the static files and the types of the successful upstream responses.
+    pub mime_types: Option<HashMap<String, String>>,
+
+    /// Templates of the error responses generated by the proxy, and maintenance mode
+    pub error_pages: Option<RouteErrorPages>,
+
+    /// Plugins that will be applied to the route/host
+    /// (ex: rate limiting, oauth2, etc.)
+    pub plugins: Option<Vec<RoutePlugin>>,
+
+    /// SSL certificate configurations for the given host
+    /// (ex: self-signed, path/object storage, etc.)
+    pub ssl_certificate: Option<RouteSslCertificate>,
+
+    /// SSL configuration for the route
+    pub ssl: Option<RouteSsl>,
+
+    /// Header modifications for the given route (remove, add, etc. )
+    pub headers: Option<RouteHeader>,
+
+    /// The upstreams to which the request will be proxied (none for the routes serving
+    /// `static_files`)
+    #[serde(default)]
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// Blue and green pools of upstreams, used instead of `upstreams`
+    pub blue_green: Option<RouteBlueGreen>,
+
+    /// A pool of upstreams receiving a share of the requests
+    pub canary: Option<RouteCanary>,
+
+    /// A shadow upstream receiving a copy of a share of the requests
+    pub mirror: Option<RouteMirror>,
+
+    /// A sample of the requests written to a file, to be replayed
+    pub capture: Option<RouteCapture>,
+
+    /// A sample of the request and response bodies written to the logs
+    pub body_logging: Option<RouteBodyLogging>,
+
+    /// The matcher for the route
+    /// (ex: path, query, etc.)
+    pub match_with: Option<RouteMatcher>,
+
+    /// IP allow/deny lists for the route, evaluated after the global ones
+    pub ip_filter: Option<IpFilter>,
+
+    /// Methods allowed by the route (e.g. only `GET` for static files), the requests with
+    /// another method get a `405`. `HEAD` is allowed along with `GET`.
+    pub methods: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Trace,
+}
+
+/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
+/// enum used by the `tracing` crate.
+impl From<&LogLevel> for tracing::level_filters::LevelFilter {
+    fn from(val: &LogLevel) -> Self {
+        match val {
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogFormat {
+    Json,
+    Pretty,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
 #[group(id = "logging", requires = "level")]
 pub struct Logging {
     /// If logging is enabled at all. Setting this to `false` will disable all logging output.
@@ -447,60 +1346,645 @@ pub struct Logging {
     )]
     pub access_logs_enabled: bool,
 
-    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
-    #[arg(
-        long = "log.error_logs_enabled",
-        required = false,
-        value_parser,
-        default_value = "true"
-    )]
-    pub error_logs_enabled: bool,
+    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
+    #[arg(
+        long = "log.error_logs_enabled",
+        required = false,
+        value_parser,
+        default_value = "true"
+    )]
+    pub error_logs_enabled: bool,
+
+    /// The format of the log output
+    #[serde(deserialize_with = "log_format_deser")]
+    #[arg(
+        long = "log.format",
+        required = false,
+        value_enum,
+        default_value = "json"
+    )]
+    pub format: LogFormat,
+
+    /// If set, logs will be written to the specified file
+    #[arg(long = "log.path", required = false, value_parser)]
+    pub path: Option<PathBuf>,
+
+    #[clap(skip)]
+    #[serde(deserialize_with = "log_rotation_deser", default)]
+    pub rotation: LogRotation,
+
+    /// Ships the access logs in batches to ClickHouse or to an HTTP bulk endpoint
+    #[clap(skip)]
+    #[serde(default)]
+    pub export: Option<LogExport>,
+}
+
+/// Where the batches of access logs are sent
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogExportSink {
+    /// Inserted in a ClickHouse table through its HTTP interface (`FORMAT JSONEachRow`)
+    #[serde(rename = "clickhouse")]
+    ClickHouse,
+    /// Posted to an HTTP endpoint, one JSON object per line (`application/x-ndjson`)
+    #[serde(rename = "http")]
+    Http,
+}
+
+/// A sink the access logs are exported to, in batches, besides the logs written by proksi
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogExport {
+    pub sink: LogExportSink,
+
+    /// URL of the ClickHouse HTTP interface (e.g. `http://clickhouse:8123`) or of the
+    /// bulk endpoint
+    pub url: String,
+
+    /// Table the logs are inserted in (required with `clickhouse`)
+    pub table: Option<String>,
+
+    /// Headers sent with the batches, e.g. the credentials of the sink
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// Logs sent in a batch (default: 1000)
+    #[serde(default = "default_log_export_batch_size")]
+    pub batch_size: usize,
+
+    /// Longest time a log waits for its batch to fill up, in seconds (default: 5)
+    #[serde(default = "default_log_export_flush_interval")]
+    pub flush_interval_secs: u64,
+
+    /// Whether the batches are compressed with gzip (default: true)
+    #[serde(default = "bool_true")]
+    pub compression: bool,
+
+    /// Attempts after the first one before a batch is spilled or dropped (default: 3)
+    #[serde(default = "default_log_export_max_retries")]
+    pub max_retries: u32,
+
+    /// Directory the batches are written to while the sink is down, they are sent again
+    /// once it's back. Without it, the batches are dropped.
+    pub spill_path: Option<PathBuf>,
+
+    /// Size of the spilled batches after which the next ones are dropped, in bytes
+    /// (default: 1 GiB)
+    #[serde(default = "default_log_export_max_spill_size")]
+    pub max_spill_size: u64,
+
+    /// Logs waiting to be batched, the next ones are dropped (default: 100000)
+    #[serde(default = "default_log_export_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_log_export_batch_size() -> usize {
+    1000
+}
+
+fn default_log_export_flush_interval() -> u64 {
+    5
+}
+
+fn default_log_export_max_retries() -> u32 {
+    3
+}
+
+fn default_log_export_max_spill_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_log_export_queue_size() -> usize {
+    100_000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+#[group(id = "auto_reload")]
+pub struct AutoReload {
+    /// Enables the auto-reload service
+    #[arg(long = "auto_reload.enabled", default_value = "false")]
+    pub enabled: Option<bool>,
+
+    /// The interval (in seconds) to check for changes in the configuration file
+    #[arg(
+        long = "auto_reload.interval_secs",
+        default_value = "30",
+        group = "auto_reload",
+        id = "auto_reload.interval_secs"
+    )]
+    pub interval_secs: Option<u64>,
+
+    #[clap(skip)]
+    pub paths: Vec<PathBuf>,
+}
+
+impl Default for AutoReload {
+    fn default() -> Self {
+        Self {
+            enabled: Some(false),
+            interval_secs: Some(30),
+            paths: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum ListenerProtocol {
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    #[serde(rename = "udp")]
+    Udp,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    /// Human readable header
+    #[serde(rename = "v1")]
+    V1,
+    /// Binary header
+    #[serde(rename = "v2")]
+    V2,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ListenerUpstream {
+    /// The address of the upstream (an IP address or a hostname)
+    pub ip: Cow<'static, str>,
+
+    pub port: u16,
+
+    /// When provided, the address of the client is sent to the upstream with
+    /// the PROXY protocol (TCP only)
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// TLS used to connect to the upstreams of a listener
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ListenerUpstreamTls {
+    /// The server name sent during the handshake (and verified, when `verify` is enabled)
+    pub sni: Option<String>,
+
+    /// Whether the certificate of the upstreams is verified
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// Admin API, served on its own address (it should not be reachable from the internet)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Admin {
+    /// Address of the admin API (e.g. `127.0.0.1:9091`), disabled when not provided
+    pub address: Option<Cow<'static, str>>,
+
+    /// Token expected in the `Authorization: Bearer <token>` header, with the `write` scope
+    pub token: Option<Cow<'static, str>>,
+
+    /// Named tokens, each with its own scope (e.g. read-only tokens for the dashboards)
+    #[serde(default)]
+    pub tokens: Vec<AdminToken>,
+
+    /// Serves the admin API over TLS, and authenticates the clients with their certificate
+    pub tls: Option<AdminTls>,
+
+    /// Routes created or changed with the admin API are saved in the store (see `store`),
+    /// and restored on startup
+    #[serde(default)]
+    pub persist_routes: bool,
+}
+
+/// What the credentials of the admin API allow
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdminScope {
+    /// `GET` requests only
+    #[default]
+    #[serde(rename = "read")]
+    Read,
+    /// Every request, including the changes of routes, cache and logging
+    #[serde(rename = "write")]
+    Write,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminToken {
+    /// Name of the token, used in the logs of the changes
+    pub name: Cow<'static, str>,
+
+    pub token: Cow<'static, str>,
+
+    #[serde(default)]
+    pub scope: AdminScope,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminTls {
+    /// Path to the certificate .key file of the admin API
+    pub key: PathBuf,
+
+    /// Path to the certificate .pem file of the admin API
+    pub pem: PathBuf,
+
+    /// Certificate authority the client certificates must be signed by, the clients
+    /// can't connect without a certificate when provided
+    pub client_ca: Option<PathBuf>,
+
+    /// Clients authenticated by their certificate, when no token is sent
+    #[serde(default)]
+    pub clients: Vec<AdminClient>,
+}
+
+/// A client certificate, identified by its SHA-256 fingerprint or its organization (`O`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminClient {
+    /// Name of the client, used in the logs of the changes
+    pub name: Cow<'static, str>,
+
+    /// SHA-256 fingerprint of the certificate, in hexadecimal (colons are ignored)
+    pub fingerprint: Option<String>,
+
+    /// Organization of the subject of the certificate
+    pub organization: Option<String>,
+
+    #[serde(default)]
+    pub scope: AdminScope,
+}
+
+/// A listener proxying the connections it accepts to its upstreams, as they are
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigListener {
+    /// Name of the listener, used in the logs (ex: 'postgres')
+    pub name: Cow<'static, str>,
+
+    /// The address to bind the listener to (ex: '0.0.0.0:5432')
+    pub address: Cow<'static, str>,
+
+    #[serde(default)]
+    pub protocol: ListenerProtocol,
+
+    /// The upstreams the connections are load balanced (round robin) to
+    pub upstreams: Vec<ListenerUpstream>,
+
+    /// Interval between the health checks of the upstreams, in seconds (TCP only)
+    #[serde(default = "default_listener_health_check_interval")]
+    pub health_check_interval: u64,
+
+    /// Maximum time to connect to an upstream, in seconds
+    pub connect_timeout: Option<u64>,
+
+    /// Time without datagrams after which the session of a client is closed,
+    /// in seconds (UDP only, defaults to 30)
+    pub idle_timeout: Option<u64>,
+
+    /// Whether the connections start with a PROXY protocol header (v1 or v2), sent by
+    /// the load balancer in front of proksi with the address of the client (TCP only)
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+
+    /// When provided, TLS is terminated by the listener with this certificate (TCP only)
+    pub tls: Option<RouteSslPath>,
+
+    /// When provided, the connections to the upstreams use TLS (TCP only)
+    pub upstream_tls: Option<ListenerUpstreamTls>,
+}
+
+fn default_listener_health_check_interval() -> u64 {
+    15
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum ForwardedMode {
+    /// Headers set by trusted proxies are kept (the IP of the trusted proxy
+    /// is appended to `X-Forwarded-For`), and replaced for any other peer
+    #[default]
+    #[serde(rename = "append")]
+    Append,
+    /// Headers are always replaced, `X-Forwarded-For` only contains the client IP
+    #[serde(rename = "overwrite")]
+    Overwrite,
+}
+
+/// The `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Real-IP`
+/// headers sent to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardedHeaders {
+    #[serde(default = "default_forwarded_headers_enabled")]
+    pub enabled: bool,
+
+    #[serde(default)]
+    pub mode: ForwardedMode,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> Self {
+        Self {
+            enabled: default_forwarded_headers_enabled(),
+            mode: ForwardedMode::default(),
+        }
+    }
+}
+
+/// Protections against clients sending their requests slowly (e.g. Slowloris attacks)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlowClients {
+    /// Maximum idle time between two reads of the request body, in seconds
+    /// (HTTP/1.1 only, 60 seconds when not set)
+    pub read_timeout: Option<u64>,
+
+    /// Maximum time to receive the request body, in seconds
+    pub body_timeout: Option<u64>,
+
+    /// Minimum average transfer rate of the request body, in bytes per second
+    pub min_body_rate: Option<u64>,
+
+    /// Time before the minimum transfer rate is enforced, in seconds
+    #[serde(default = "default_min_body_rate_grace")]
+    pub min_body_rate_grace: u64,
+}
+
+impl Default for SlowClients {
+    fn default() -> Self {
+        Self {
+            read_timeout: None,
+            body_timeout: None,
+            min_body_rate: None,
+            min_body_rate_grace: default_min_body_rate_grace(),
+        }
+    }
+}
+
+/// Limits of the connections of each client IP, against floods of connections. They are
+/// checked at the first request of a connection, independently of the rate limits of the
+/// requests.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectionLimits {
+    /// Most connections open at once by a client IP (no limit when not set)
+    pub max_connections: Option<usize>,
+
+    /// Most new connections of a client IP per `rate_window_secs` (no limit when not set)
+    pub max_new_connections: Option<u32>,
+
+    /// Window of `max_new_connections`, in seconds (default: 1)
+    #[serde(default = "default_connection_rate_window")]
+    pub rate_window_secs: u64,
+
+    /// Time the rejected connections are held before they get their response, in seconds,
+    /// slowing down the clients flooding proksi (closed right away when 0)
+    #[serde(default)]
+    pub tarpit_secs: u64,
+
+    /// Networks (CIDR) or IPs that are never limited (e.g. the load balancers in front of proksi)
+    #[serde(default, deserialize_with = "deserialize_ip_networks")]
+    pub exempt: Vec<IpNet>,
+}
+
+fn default_connection_rate_window() -> u64 {
+    1
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_new_connections: None,
+            rate_window_secs: default_connection_rate_window(),
+            tarpit_secs: 0,
+            exempt: vec![],
+        }
+    }
+}
+
+/// Pool of the idle connections to the upstreams, reused by the next requests
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpstreamPool {
+    /// Idle connections kept by each thread of the HTTPS service, shared by every upstream
+    /// (the least recently used are closed first)
+    #[serde(default = "default_upstream_pool_size")]
+    pub size: usize,
+
+    /// Time an idle connection is kept in the pool, in seconds
+    #[serde(default = "default_upstream_pool_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Default for UpstreamPool {
+    fn default() -> Self {
+        Self {
+            size: default_upstream_pool_size(),
+            idle_timeout: default_upstream_pool_idle_timeout(),
+        }
+    }
+}
+
+/// Graceful shutdown (`SIGTERM`) and upgrade (`SIGQUIT`): the listeners stop accepting
+/// connections and the in-flight requests are drained, then proksi exits
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GracefulShutdown {
+    /// Most time given to the in-flight requests and connections to finish, in seconds
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub drain_secs: u64,
+
+    /// Time given to the last logs and cache writes after the drain period, then to the
+    /// services to stop, in seconds
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for GracefulShutdown {
+    fn default() -> Self {
+        Self {
+            drain_secs: default_shutdown_drain_secs(),
+            timeout_secs: default_shutdown_timeout_secs(),
+        }
+    }
+}
+
+/// Keys encrypting the TLS session tickets of the HTTPS service, which let the clients
+/// resume their sessions without a full handshake
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionTickets {
+    /// Whether the clients get session tickets (default: true)
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+
+    /// Time a key encrypts the new tickets, in seconds (default: 3600). The tickets of the
+    /// previous key are still accepted (renewed) for as long, and rejected after.
+    #[serde(default = "default_session_ticket_rotation")]
+    pub rotation_interval_secs: u64,
+
+    /// Whether the keys are kept in the store, so that the instances sharing it (and the
+    /// next runs, with the `redis` and `file` stores) resume the sessions of each other
+    #[serde(default)]
+    pub shared: bool,
+}
+
+fn default_session_ticket_rotation() -> u64 {
+    3600
+}
+
+impl Default for SessionTickets {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            rotation_interval_secs: default_session_ticket_rotation(),
+            shared: false,
+        }
+    }
+}
+
+/// Checks of the HTTP requests of the HTTPS and HTTP listeners, against request smuggling
+/// and headers that proxies and upstreams could read differently
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StrictParsing {
+    #[serde(default)]
+    pub https: StrictParsingRules,
+
+    #[serde(default)]
+    pub http: StrictParsingRules,
+}
+
+/// The requests failing a check get a `400 Bad Request` response (`431 Request Header Fields
+/// Too Large` past `max_headers`), and their connection is closed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StrictParsingRules {
+    /// Whether the requests of the listener are checked (default: true)
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+
+    /// Rejects the HTTP/1 requests with both `Content-Length` and `Transfer-Encoding`, or a
+    /// `Transfer-Encoding` not ending with `chunked` (default: true)
+    #[serde(default = "bool_true")]
+    pub reject_conflicting_length: bool,
+
+    /// Rejects the HTTP/1 requests with a header continued on the next line (obs-fold)
+    /// (default: true)
+    #[serde(default = "bool_true")]
+    pub reject_obs_fold: bool,
+
+    /// What to do with the requests with several `Host` headers
+    #[serde(default)]
+    pub duplicate_host: DuplicateHost,
+
+    /// Most headers of a request (default: 100)
+    #[serde(default = "default_max_request_headers")]
+    pub max_headers: usize,
+}
+
+fn default_max_request_headers() -> usize {
+    100
+}
+
+impl Default for StrictParsingRules {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            reject_conflicting_length: true,
+            reject_obs_fold: true,
+            duplicate_host: DuplicateHost::default(),
+            max_headers: default_max_request_headers(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum DuplicateHost {
+    /// The identical `Host` headers are merged into one, the requests with different ones
+    /// are rejected
+    #[default]
+    #[serde(rename = "normalize")]
+    Normalize,
+    /// Every request with several `Host` headers is rejected
+    #[serde(rename = "reject")]
+    Reject,
+    /// The requests are proxied as they are, the first `Host` header selects the route
+    #[serde(rename = "allow")]
+    Allow,
+}
+
+/// Liveness (`/healthz`) and readiness (`/readyz`) probes, e.g. for Kubernetes
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Probes {
+    /// Address of the probes (e.g. `0.0.0.0:9092`), they are not exposed without it
+    pub address: Option<Cow<'static, str>>,
+
+    /// Healthy upstreams every route needs for proksi to be ready (0 to skip the check)
+    #[serde(default)]
+    pub min_healthy_upstreams: usize,
+}
+
+/// Public pages summarizing the availability of the upstreams of groups of routes (e.g. the
+/// routes of a tenant), from their health checks
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StatusPages {
+    /// Address of the pages (e.g. `0.0.0.0:9093`), they are not exposed without it
+    pub address: Option<Cow<'static, str>>,
+
+    /// Pages served on `/<name>` (HTML) and `/<name>.json`
+    #[serde(default)]
+    pub pages: Vec<StatusPage>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StatusPage {
+    /// Name of the page in its path (letters, digits, `-` and `_`)
+    pub name: String,
 
-    /// The format of the log output
-    #[serde(deserialize_with = "log_format_deser")]
-    #[arg(
-        long = "log.format",
-        required = false,
-        value_enum,
-        default_value = "json"
-    )]
-    pub format: LogFormat,
+    /// Title of the page, its name by default
+    pub title: Option<String>,
 
-    /// If set, logs will be written to the specified file
-    #[arg(long = "log.path", required = false, value_parser)]
-    pub path: Option<PathBuf>,
+    /// Hosts of the routes shown on the page
+    pub hosts: Vec<String>,
+}
 
-    #[clap(skip)]
-    #[serde(deserialize_with = "log_rotation_deser", default)]
-    pub rotation: LogRotation,
+/// Threads of a service and the CPUs they run on, each service has its own runtime
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ServiceRuntime {
+    /// Worker threads of the service, its default when not provided
+    pub threads: Option<usize>,
+
+    /// CPUs the threads of the service are pinned to (e.g. `[0, 1]`), any CPU when empty
+    #[serde(default)]
+    pub cpus: Vec<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
-#[group(id = "auto_reload")]
-pub struct AutoReload {
-    /// Enables the auto-reload service
-    #[arg(long = "auto_reload.enabled", default_value = "false")]
-    pub enabled: Option<bool>,
+/// Threads and CPUs of each service
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Services {
+    /// The HTTPS proxy, with `worker_threads` threads by default
+    #[serde(default)]
+    pub https: ServiceRuntime,
 
-    /// The interval (in seconds) to check for changes in the configuration file
-    #[arg(
-        long = "auto_reload.interval_secs",
-        default_value = "30",
-        group = "auto_reload",
-        id = "auto_reload.interval_secs"
-    )]
-    pub interval_secs: Option<u64>,
+    /// The HTTP service answering the ACME challenges (1 thread by default)
+    #[serde(default)]
+    pub http: ServiceRuntime,
 
-    #[clap(skip)]
-    pub paths: Vec<PathBuf>,
+    /// Each layer 4 listener and the health checks of its upstreams (1 thread by default)
+    #[serde(default)]
+    pub listeners: ServiceRuntime,
+
+    /// The logger, writing the logs to stdout or to the log files (1 thread by default)
+    #[serde(default)]
+    pub logger: ServiceRuntime,
+
+    /// Let's Encrypt, the health checks, the discovery of the routes and the warm restarts
+    /// (1 thread by default)
+    #[serde(default)]
+    pub background: ServiceRuntime,
+
+    /// The admin API, the metrics, the probes and the status pages (1 thread by default)
+    #[serde(default)]
+    pub admin: ServiceRuntime,
 }
 
-impl Default for AutoReload {
+/// Settings of the caches shared by every route
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cache {
+    /// Size of the objects the disk cache keeps in memory, in bytes. New objects only
+    /// replace older ones in memory when they are requested more often.
+    #[serde(default = "default_cache_memory_size")]
+    pub memory_size: usize,
+}
+
+impl Default for Cache {
     fn default() -> Self {
         Self {
-            enabled: Some(false),
-            interval_secs: Some(30),
-            paths: vec![],
+            memory_size: default_cache_memory_size(),
         }
     }
 }
@@ -524,6 +2008,62 @@ pub struct ServerCfg {
         default_value = "0.0.0.0:80"
     )]
     pub http_address: Option<Cow<'static, str>>,
+
+    /// The address used to expose prometheus metrics (`/metrics`).
+    /// Metrics are not exposed if no address is provided.
+    #[arg(long = "server.metrics_address", required = false, value_parser)]
+    pub metrics_address: Option<Cow<'static, str>>,
+
+    /// Networks (CIDR) or IPs of the proxies/load balancers in front of proksi.
+    /// The client IP is only read from `X-Forwarded-For` when the request comes from them.
+    #[clap(skip)]
+    #[serde(default, deserialize_with = "deserialize_ip_networks")]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// How the `X-Forwarded-*` and `X-Real-IP` headers are sent to the upstreams
+    #[clap(skip)]
+    #[serde(default)]
+    pub forwarded_headers: ForwardedHeaders,
+
+    /// Timeouts and minimum transfer rate of the request bodies
+    #[clap(skip)]
+    #[serde(default)]
+    pub slow_clients: SlowClients,
+
+    /// Concurrent connections and connection rate of each client IP
+    #[clap(skip)]
+    #[serde(default)]
+    pub connection_limits: ConnectionLimits,
+
+    /// Size and idle timeout of the pool of connections to the upstreams
+    #[clap(skip)]
+    #[serde(default)]
+    pub upstream_pool: UpstreamPool,
+
+    /// Liveness and readiness probes, on their own address
+    #[clap(skip)]
+    #[serde(default)]
+    pub probes: Probes,
+
+    /// Public status pages of groups of routes, on their own address
+    #[clap(skip)]
+    #[serde(default)]
+    pub status_pages: StatusPages,
+
+    /// Checks of the requests against request smuggling, for each listener
+    #[clap(skip)]
+    #[serde(default)]
+    pub strict_parsing: StrictParsing,
+
+    /// Keys and rotation of the TLS session tickets
+    #[clap(skip)]
+    #[serde(default)]
+    pub session_tickets: SessionTickets,
+
+    /// Drain period and timeout of the graceful shutdowns
+    #[clap(skip)]
+    #[serde(default)]
+    pub shutdown: GracefulShutdown,
 }
 
 /// The main configuration struct.
@@ -568,6 +2108,54 @@ pub struct ServerCfg {
 ///         network: "shared"
 /// ```
 ///
+/// Commands run instead of the proxy
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sends the requests captured by the routes (see the route `capture`) to an upstream
+    Replay(ReplayArgs),
+    /// Prints the routes, listeners and settings a configuration would add, change or
+    /// remove, without applying it
+    Diff(DiffArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// The candidate configuration (a `proksi.hcl` or `proksi.yaml` file, or their directory)
+    pub file: PathBuf,
+
+    /// Compares with the configuration of a running instance, read from its admin API
+    /// (e.g. `http://127.0.0.1:9091`), instead of the current configuration files
+    #[arg(long)]
+    pub admin_url: Option<String>,
+
+    /// Token of the admin API
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// File of the captured requests
+    pub file: PathBuf,
+
+    /// URL of the upstream receiving the requests (e.g. `http://127.0.0.1:3000`)
+    #[arg(long)]
+    pub upstream: String,
+
+    /// Only sends the requests of this host
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Header added to the requests, replacing a redacted one (e.g. `authorization: Bearer
+    /// <token>`), can be repeated
+    #[arg(short = 'H', long = "header")]
+    pub headers: Vec<String>,
+
+    /// Time between two requests, in milliseconds
+    #[arg(long, default_value = "0")]
+    pub delay_ms: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Parser)]
 #[command(name = "Proksi")]
 #[command(version, about, long_about = None)]
@@ -592,9 +2180,19 @@ pub(crate) struct Config {
     #[clap(short, long, default_value = "false")]
     pub upgrade: bool,
 
+    /// Prints the configuration, once the files, environment variables and arguments are
+    /// merged, and exits (the secrets are redacted)
+    #[clap(long, value_enum)]
+    #[serde(skip)]
+    pub print_config: Option<export::ExportFormat>,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
     /// The number of worker threads to be used by the HTTPS proxy service.
     ///
-    /// For background services the default is always (1) and cannot be changed.
+    /// The threads of the other services are set in `services`.
     #[clap(short, long, required = false, default_value = "2")]
     pub worker_threads: Option<usize>,
 
@@ -621,16 +2219,54 @@ pub(crate) struct Config {
     #[clap(skip)]
     pub lets_encrypt: LetsEncrypt,
 
+    /// Private CAs issuing the certificates of the routes that select them
+    #[clap(skip)]
+    #[serde(default)]
+    pub certificate_issuers: Vec<CertificateIssuer>,
+
+    /// Providers of the secret references (`vault://`, `aws-sm://` and `aws-kms://`)
+    #[clap(skip)]
+    #[serde(default)]
+    pub secrets: Secrets,
+
+    /// Alerts notified to webhooks when the routes break a threshold
+    #[clap(skip)]
+    #[serde(default)]
+    pub alerts: Alerts,
+
     /// Configuration for paths (TLS, config file, etc.)
     #[clap(skip)]
     pub paths: Path,
 
+    /// Global IP allow/deny lists, applied to every route
+    #[clap(skip)]
+    #[serde(default)]
+    pub ip_filter: IpFilter,
+
     /// The routes to be proxied to.
     #[clap(skip)]
     pub routes: Vec<Route>,
-    // Listeners -- a list of specific listeners and upstrems
-    // that don't necessarily need to be HTTP/HTTPS related
-    // pub listeners: Vec<ConfigListener>,
+
+    /// Listeners proxying connections that are not HTTP/HTTPS related
+    /// (e.g. databases, SMTP)
+    #[clap(skip)]
+    #[serde(default)]
+    pub listeners: Vec<ConfigListener>,
+
+    /// Admin API exposing the runtime state (routes, upstreams, certificates, cache)
+    #[clap(skip)]
+    #[serde(default)]
+    pub admin: Admin,
+
+    /// Threads and CPUs of each service
+    #[clap(skip)]
+    #[serde(default)]
+    pub services: Services,
+
+    /// Caches shared by every route
+    #[clap(skip)]
+    #[serde(default)]
+    pub cache: Cache,
 }
 
 impl Default for Config {
@@ -641,13 +2277,34 @@ impl Default for Config {
             server: ServerCfg {
                 https_address: Some(Cow::Borrowed("0.0.0.0:443")),
                 http_address: Some(Cow::Borrowed("0.0.0.0:80")),
+                metrics_address: None,
+                trusted_proxies: vec![],
+                forwarded_headers: ForwardedHeaders::default(),
+                slow_clients: SlowClients::default(),
+                connection_limits: ConnectionLimits::default(),
+                upstream_pool: UpstreamPool::default(),
+                probes: Probes::default(),
+                status_pages: StatusPages::default(),
+                strict_parsing: StrictParsing::default(),
+                session_tickets: SessionTickets::default(),
+                shutdown: GracefulShutdown::default(),
             },
             worker_threads: Some(2),
             upgrade: false,
             daemon: false,
+            print_config: None,
+            command: None,
             docker: Docker::default(),
             lets_encrypt: LetsEncrypt::default(),
+            certificate_issuers: vec![],
+            secrets: Secrets::default(),
+            alerts: Alerts::default(),
+            ip_filter: IpFilter::default(),
             routes: vec![],
+            listeners: vec![],
+            admin: Admin::default(),
+            services: Services::default(),
+            cache: Cache::default(),
             auto_reload: AutoReload::default(),
             store: StoreConfig::default(),
             logging: Logging {
@@ -658,6 +2315,7 @@ impl Default for Config {
                 format: LogFormat::Json,
                 path: None,
                 rotation: LogRotation::Never,
+                export: None,
             },
             paths: Path::default(),
         }
@@ -710,7 +2368,10 @@ pub fn load(fallback: &str) -> Result<Config, figment::Error> {
     // Check if no explicit config path was provided by the user
     let use_minimal_default = parsed_commands.config_path.is_none();
 
-    load_from_path(path_with_fallback, &parsed_commands, use_minimal_default)
+    let mut config = load_from_path(path_with_fallback, &parsed_commands, use_minimal_default)?;
+    config.print_config = parsed_commands.print_config;
+    config.command = parsed_commands.command;
+    Ok(config)
 }
 
 /// Test-friendly version of load that doesn't parse command line arguments
@@ -798,7 +2459,9 @@ pub(crate) fn load_from_path(
         }
     }
 
-    let config: Config = figment
+    // The values encrypted with sops are decrypted before the environment variables
+    // override them
+    let config: Config = encryption::decrypt(figment)?
         .merge(Env::prefixed("PROKSI_").split("__"))
         .extract()?;
 
@@ -898,6 +2561,23 @@ where
     }
 }
 
+/// Parses a list of networks in the CIDR notation, single IPs are treated
+/// as a network containing only that IP (e.g. `10.0.0.1` -> `10.0.0.1/32`)
+fn deserialize_ip_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| {
+            value
+                .parse::<IpNet>()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| serde::de::Error::custom(format!("invalid IP or CIDR: {value}")))
+        })
+        .collect()
+}
+
 fn store_type_deser<'de, D>(deserializer: D) -> Result<StoreType, D::Error>
 where
     D: Deserializer<'de>,
@@ -906,7 +2586,10 @@ where
     match s.to_lowercase().as_str() {
         "memory" => Ok(StoreType::Memory),
         "redis" => Ok(StoreType::Redis),
-        _ => Err(serde::de::Error::custom("expected one of: memory, redis")),
+        "file" => Ok(StoreType::File),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: memory, redis, file",
+        )),
     }
 }
 
@@ -992,6 +2675,10 @@ mod tests {
                   value: "1.0"
               remove:
                 - name: "Server"
+              request:
+                set:
+                  - name: "X-Real-IP"
+                    value: "$remote_addr"
             upstreams:
               - ip: "10.0.1.3/25"
                 port: 3000
@@ -1010,6 +2697,12 @@ mod tests {
             let proxy_config = config.unwrap();
             assert_eq!(proxy_config.service_name, "proksi");
 
+            let headers = proxy_config.routes[0].headers.as_ref().unwrap();
+            let request_headers = headers.request.as_ref().unwrap();
+            assert_eq!(request_headers.set[0].value, "$remote_addr");
+            assert!(request_headers.add.is_empty());
+            assert!(headers.response.is_none());
+
             Ok(())
         });
     }
@@ -1074,6 +2767,9 @@ mod tests {
                     upstreams:
                       - ip: "localhost"
                         port: 3001
+                        protocol: h2
+                    streaming:
+                      idle_timeout: 600
                 "#,
             )?;
 
@@ -1085,12 +2781,137 @@ mod tests {
             assert_eq!(proxy_config.routes.len(), 1);
             assert_eq!(proxy_config.routes[0].host, "yaml.localhost");
             assert_eq!(proxy_config.routes[0].upstreams[0].port, 3001);
+            assert_eq!(
+                proxy_config.routes[0].upstreams[0].protocol,
+                Some(UpstreamProtocol::H2)
+            );
+            let streaming = proxy_config.routes[0].streaming.as_ref().unwrap();
+            assert_eq!(streaming.enabled, None);
+            assert_eq!(streaming.idle_timeout, Some(600));
             assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
 
             Ok(())
         });
     }
 
+    #[test]
+    fn test_load_config_with_ip_filter() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_file_path = format!("{}/custom_config.yml", tmp_dir);
+
+            jail.create_file(
+                &config_file_path,
+                r#"
+                server:
+                  trusted_proxies: ["10.0.0.0/8"]
+                  forwarded_headers:
+                    mode: overwrite
+                ip_filter:
+                  deny: ["192.168.1.10", "2001:db8::/32"]
+                routes:
+                  - host: "yaml.localhost"
+                    ip_filter:
+                      allow: ["192.168.0.0/16"]
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3001
+                "#,
+            )?;
+
+            let default_config = Config::default();
+            let proxy_config = load_from_path(&config_file_path, &default_config, false).unwrap();
+
+            assert_eq!(proxy_config.server.trusted_proxies.len(), 1);
+            assert!(proxy_config.server.forwarded_headers.enabled);
+            assert_eq!(
+                proxy_config.server.forwarded_headers.mode,
+                ForwardedMode::Overwrite
+            );
+            assert_eq!(proxy_config.ip_filter.deny.len(), 2);
+            assert!(proxy_config.ip_filter.allow.is_empty());
+
+            let route_filter = proxy_config.routes[0].ip_filter.as_ref().unwrap();
+            let ip = "192.168.1.10".parse().unwrap();
+            assert!(route_filter.is_allowed(&ip));
+            assert!(!proxy_config.ip_filter.is_allowed(&ip));
+            assert!(!route_filter.is_allowed(&"172.16.0.1".parse().unwrap()));
+            assert!(!proxy_config
+                .ip_filter
+                .is_allowed(&"2001:db8::1".parse().unwrap()));
+            assert!(proxy_config
+                .ip_filter
+                .is_allowed(&"172.16.0.1".parse().unwrap()));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_invalid_ip_filter() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_file_path = format!("{}/custom_config.yml", tmp_dir);
+
+            jail.create_file(
+                &config_file_path,
+                r#"
+                ip_filter:
+                  allow: ["not-an-ip"]
+                "#,
+            )?;
+
+            let default_config = Config::default();
+            assert!(load_from_path(&config_file_path, &default_config, false).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_upstream_pool() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let default_config = Config::default();
+            let path = format!("{tmp_dir}/pool.yml");
+
+            jail.create_file(&path, "server:\n  upstream_pool:\n    idle_timeout: 60\n")?;
+            let config = load_from_path(&path, &default_config, false).unwrap();
+            assert_eq!(config.server.upstream_pool.size, 128);
+            assert_eq!(config.server.upstream_pool.idle_timeout, 60);
+
+            // An empty pool would keep the connections it should close
+            jail.create_file(&path, "server:\n  upstream_pool:\n    size: 0\n")?;
+            let err = load_from_path(&path, &default_config, false).unwrap_err();
+            assert!(
+                err.to_string().contains("server.upstream_pool.size"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_services_validation() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let default_config = Config::default();
+
+            for (services, error) in [
+                ("logger: { threads: 0 }", "services.logger.threads"),
+                ("https: { cpus: [0, 1024] }", "services.https.cpus"),
+            ] {
+                let path = format!("{tmp_dir}/services.yml");
+                jail.create_file(&path, &format!("services:\n  {services}\n"))?;
+                let err = load_from_path(&path, &default_config, false).unwrap_err();
+                assert!(err.to_string().contains(error), "{err}");
+            }
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_lets_encrypt_validation_when_disabled() {
         figment::Jail::expect_with(|jail| {
@@ -1149,6 +2970,97 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_secret_references_validation() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            let routes = r#"
+                routes:
+                  - host: "test.localhost"
+                    plugins:
+                      - name: basic_auth
+                        config:
+                          user: admin
+                          pass: "vault://secret/data/proksi#pass"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#;
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), routes)?;
+            // The reference needs the configuration of Vault
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &format!(
+                    r#"
+                secrets:
+                  vault:
+                    url: "https://vault.internal:8200"
+                {routes}"#
+                ),
+            )?;
+            let config = load_for_test(&tmp_dir).unwrap();
+            assert_eq!(config.secrets.refresh_interval_secs, 300);
+
+            // The references of the other values would never be resolved
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                secrets:
+                  vault:
+                    url: "https://vault.internal:8200"
+                lets_encrypt:
+                  email: "vault://secret/data/proksi#email"
+                "#,
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_client_auth_validation() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config = |ca: &str| {
+                format!(
+                    r#"
+                routes:
+                  - host: "test.localhost"
+                    ssl:
+                      client_auth:
+                        ca: "{ca}"
+                        forward:
+                          format: headers
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(&format!("{tmp_dir}/missing.pem")),
+            )?;
+            let err = load_for_test(&tmp_dir).unwrap_err();
+            assert!(err.to_string().contains("ssl.client_auth"));
+
+            // The file must contain certificates
+            jail.create_file(format!("{}/ca.pem", tmp_dir), "not a certificate")?;
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                &config(&format!("{tmp_dir}/ca.pem")),
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_load_config_from_yaml_and_env_vars() {
         figment::Jail::expect_with(|jail| {
@@ -1325,6 +3237,15 @@ mod tests {
                 }
                 paths {
                     lets_encrypt = "/etc/proksi/letsencrypt"
+                }
+                services {
+                    https {
+                        threads = 4
+                        cpus = [0, 1]
+                    }
+                    logger {
+                        cpus = [2]
+                    }
                 }
                     "#,
             )?;
@@ -1333,6 +3254,15 @@ mod tests {
             let proxy_config = config.unwrap();
 
             assert_eq!(proxy_config.service_name, "hcl-service");
+            assert_eq!(
+                proxy_config.services.https,
+                ServiceRuntime {
+                    threads: Some(4),
+                    cpus: vec![0, 1],
+                }
+            );
+            assert_eq!(proxy_config.services.logger.cpus, vec![2]);
+            assert_eq!(proxy_config.services.background, ServiceRuntime::default());
 
             assert_eq!(
                 proxy_config.server.https_address,
@@ -1,6 +1,24 @@
+use std::path::Component;
+
 use anyhow::anyhow;
 
-use super::Config;
+use crate::proxy_server::body_logging::BodyLogging;
+use crate::proxy_server::canary::Canary;
+use crate::proxy_server::capture::Capture;
+use crate::proxy_server::client_certificates::ClientAuth;
+use crate::proxy_server::error_pages::ErrorPages;
+use crate::proxy_server::header_rules::HeaderRules;
+use crate::proxy_server::methods::AllowedMethods;
+use crate::proxy_server::mime_types::MimeTypes;
+use crate::proxy_server::mirror::Mirror;
+use crate::services::{alerts, log_export};
+use crate::status_pages;
+use crate::stores::secrets::{references_in, SecretReference};
+
+use super::{CertificateIssuer, CertificateIssuerType, Config, Route, RouteUpstream, StoreType};
+
+/// CPUs the threads of a service can be pinned to (the size of `cpu_set_t`)
+const MAX_CPUS: usize = 1024;
 
 /// given a Config struct, validate the values to ensure
 /// That we program won't panic when we try to use them
@@ -10,13 +28,74 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         return Err(anyhow!("Worker threads must be greater than 0"));
     }
 
+    let services = &config.services;
+    for (name, runtime) in [
+        ("https", &services.https),
+        ("http", &services.http),
+        ("listeners", &services.listeners),
+        ("logger", &services.logger),
+        ("background", &services.background),
+        ("admin", &services.admin),
+    ] {
+        if runtime.threads == Some(0) {
+            return Err(anyhow!("services.{name}.threads must be greater than 0"));
+        }
+        if let Some(cpu) = runtime.cpus.iter().find(|cpu| **cpu >= MAX_CPUS) {
+            return Err(anyhow!(
+                "services.{name}.cpus: {cpu} is not a CPU (must be lower than {MAX_CPUS})"
+            ));
+        }
+    }
+
+    if config.server.session_tickets.rotation_interval_secs < 60 {
+        return Err(anyhow!(
+            "server.session_tickets.rotation_interval_secs must be at least 60"
+        ));
+    }
+
+    let connection_limits = &config.server.connection_limits;
+    if connection_limits.max_connections == Some(0) {
+        return Err(anyhow!(
+            "server.connection_limits.max_connections must be greater than 0"
+        ));
+    }
+    if connection_limits.max_new_connections == Some(0) {
+        return Err(anyhow!(
+            "server.connection_limits.max_new_connections must be greater than 0"
+        ));
+    }
+    if connection_limits.rate_window_secs == 0 {
+        return Err(anyhow!(
+            "server.connection_limits.rate_window_secs must be greater than 0"
+        ));
+    }
+
+    let strict_parsing = &config.server.strict_parsing;
+    for (listener, rules) in [
+        ("https", &strict_parsing.https),
+        ("http", &strict_parsing.http),
+    ] {
+        if rules.max_headers == 0 {
+            return Err(anyhow!(
+                "server.strict_parsing.{listener}.max_headers must be greater than 0"
+            ));
+        }
+    }
+
+    // Pingora keeps the connections evicted right away by an empty pool
+    if config.server.upstream_pool.size == 0 {
+        return Err(anyhow!("server.upstream_pool.size must be greater than 0"));
+    }
+
     // Validate that the docker interval secs is greater than 0
     if config.docker.interval_secs.unwrap() == 0 {
         return Err(anyhow!("docker.interval_secs must be greater than 0"));
     }
 
     // validate that the lets encrypt email does not contain @example or is empty, but only if lets_encrypt is enabled
-    if config.lets_encrypt.enabled.unwrap_or(false) && (config.lets_encrypt.email.contains("@example") || config.lets_encrypt.email.is_empty()) {
+    if config.lets_encrypt.enabled.unwrap_or(false)
+        && (config.lets_encrypt.email.contains("@example") || config.lets_encrypt.email.is_empty())
+    {
         return Err(anyhow!(
             "lets_encrypt.email cannot be empty or an email from @example.com (the default value)"
         ));
@@ -27,26 +106,278 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         return Err(anyhow!("paths.lets_encrypt cannot be empty"));
     }
 
+    // The file store needs a path, and the redis store an URL
+    match config.store.store_type {
+        StoreType::File if config.store.path.is_none() => {
+            return Err(anyhow!(
+                "store.path is required when store.store_type is 'file'"
+            ));
+        }
+        StoreType::Redis if config.store.redis_url.is_none() => {
+            return Err(anyhow!(
+                "store.redis_url is required when store.store_type is 'redis'"
+            ));
+        }
+        _ => {}
+    }
+
+    // A database file can only be opened once
+    if let (StoreType::File, Some(path), Some(warm_restart)) = (
+        &config.store.store_type,
+        config.store.path.as_ref(),
+        config.store.warm_restart.as_ref(),
+    ) {
+        if *path == warm_restart.path {
+            return Err(anyhow!(
+                "store.warm_restart.path cannot be the file of the store (store.path)"
+            ));
+        }
+    }
+
+    if let Some(export) = config.logging.export.as_ref() {
+        log_export::check_config(export)?;
+    }
+
+    status_pages::check_config(&config.server.status_pages)?;
+    alerts::check_config(&config.alerts)?;
+
+    check_secret_references(config)?;
+
+    for (index, issuer) in config.certificate_issuers.iter().enumerate() {
+        check_certificate_issuer(issuer)
+            .map_err(|err| anyhow!("certificate_issuers[{index}].{err}"))?;
+
+        if config.certificate_issuers[..index]
+            .iter()
+            .any(|other| other.name == issuer.name)
+        {
+            return Err(anyhow!(
+                "certificate_issuers[{index}].name {} is already used",
+                issuer.name
+            ));
+        }
+    }
+
     // Validate the routes
     for (route_index, route) in config.routes.iter().enumerate() {
-        // Validate the route's upstreams
-        for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
-            // Validate the upstream's address
-            if upstream.ip.is_empty() {
+        check_route(route).map_err(|err| anyhow!("routes{}.{}", route_index, err))?;
+
+        let issuer = route
+            .ssl_certificate
+            .as_ref()
+            .and_then(|s| s.issuer.as_ref());
+        if let Some(issuer) = issuer {
+            if !config.certificate_issuers.iter().any(|i| &i.name == issuer) {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.id cannot be empty",
-                    route_index,
-                    upstream_index
+                    "routes{route_index}.ssl_certificate.issuer {issuer} is not in certificate_issuers"
                 ));
             }
+        }
+    }
+
+    Ok(())
+}
+
+/// The secret references are only resolved in the routes, the admin tokens and the
+/// tokens of the certificate issuers, and need the configuration of their provider
+fn check_secret_references(config: &Config) -> Result<(), anyhow::Error> {
+    let value = serde_json::to_value(config)?;
+    if config.secrets.refresh_interval_secs == 0 {
+        return Err(anyhow!(
+            "secrets.refresh_interval_secs must be greater than 0"
+        ));
+    }
+
+    let mut resolved = references_in(&value["routes"]);
+    resolved.extend(references_in(&value["admin"]["token"]));
+    resolved.extend(references_in(&value["admin"]["tokens"]));
+    for issuer in value["certificate_issuers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        resolved.extend(references_in(&issuer["token"]));
+    }
+
+    for reference in references_in(&value) {
+        if !resolved.contains(&reference) {
+            return Err(anyhow!(
+                "{reference}: secret references are only supported in the routes, admin tokens and certificate_issuers tokens"
+            ));
+        }
+
+        let provider = match SecretReference::parse(&reference) {
+            Some(Ok(SecretReference::Vault { .. })) => config.secrets.vault.is_some(),
+            Some(Ok(_)) => config.secrets.aws.is_some(),
+            Some(Err(err)) => return Err(err),
+            None => true,
+        };
+        if !provider {
+            return Err(anyhow!(
+                "{reference}: secrets.vault or secrets.aws is required by the secret reference"
+            ));
+        }
+    }
+
+    Ok(())
+}
 
-            if upstream.port == 0 {
+fn check_certificate_issuer(issuer: &CertificateIssuer) -> Result<(), anyhow::Error> {
+    if issuer.name.is_empty() {
+        return Err(anyhow!("name cannot be empty"));
+    }
+
+    if reqwest::Url::parse(&issuer.url).is_err() {
+        return Err(anyhow!("url must be an absolute URL"));
+    }
+
+    if issuer.issuer_type == CertificateIssuerType::Vault && issuer.role.is_none() {
+        return Err(anyhow!("role is required for the vault issuers"));
+    }
+
+    Ok(())
+}
+
+/// Validates a route of the configuration or of the admin API, the errors
+/// are relative to the route (e.g. `upstreams0.port must be greater than 0`)
+pub fn check_route(route: &Route) -> Result<(), anyhow::Error> {
+    if route.host.is_empty() {
+        return Err(anyhow!("host cannot be empty"));
+    }
+
+    // Validate the header rules (names and variables)
+    if let Some(headers) = route.headers.as_ref() {
+        for (name, rules) in [
+            ("request", &headers.request),
+            ("response", &headers.response),
+        ] {
+            if let Some(Err(err)) = rules.as_ref().map(HeaderRules::from_config) {
+                return Err(anyhow!("headers.{}: {}", name, err));
+            }
+        }
+    }
+
+    // Validate the session cookie
+    if let Some(sticky) = route.sticky_sessions.as_ref() {
+        let valid_cookie = sticky.cookie.as_deref().is_none_or(|cookie| {
+            !cookie.is_empty()
+                && cookie
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        });
+        if !valid_cookie {
+            return Err(anyhow!(
+                "sticky_sessions.cookie must only contain letters, digits, '-', '_' or '.'"
+            ));
+        }
+
+        if sticky.idle_timeout == Some(0) {
+            return Err(anyhow!(
+                "sticky_sessions.idle_timeout must be greater than 0"
+            ));
+        }
+    }
+
+    // The files are looked up under the root only
+    if let Some(Err(err)) = route.mime_types.as_ref().map(MimeTypes::from_config) {
+        return Err(anyhow!("mime_types: {err}"));
+    }
+
+    if let Some(Err(err)) = route.error_pages.as_ref().map(ErrorPages::from_config) {
+        return Err(anyhow!("error_pages: {err}"));
+    }
+
+    if let Some(Err(err)) = route.methods.as_deref().map(AllowedMethods::from_config) {
+        return Err(anyhow!("methods: {err}"));
+    }
+
+    let client_auth = route.ssl.as_ref().and_then(|ssl| ssl.client_auth.as_ref());
+    if let Some(Err(err)) = client_auth.map(ClientAuth::from_config) {
+        return Err(anyhow!("ssl.client_auth: {err}"));
+    }
+
+    if let Some(files) = route.static_files.as_ref() {
+        if files.root.as_os_str().is_empty() {
+            return Err(anyhow!("static_files.root cannot be empty"));
+        }
+
+        let leaves_root = files.fallback.as_ref().is_some_and(|fallback| {
+            fallback
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        });
+        if leaves_root {
+            return Err(anyhow!(
+                "static_files.fallback must be a path relative to static_files.root"
+            ));
+        }
+    }
+
+    // Validate the route's upstreams
+    check_upstreams("upstreams", &route.upstreams)?;
+
+    if let Some(pools) = route.blue_green.as_ref() {
+        if !route.upstreams.is_empty() {
+            return Err(anyhow!(
+                "upstreams must be empty when blue_green is set, they are replaced by its pools"
+            ));
+        }
+
+        for (name, upstreams) in [("blue", &pools.blue), ("green", &pools.green)] {
+            if upstreams.is_empty() {
+                return Err(anyhow!("blue_green.{name} must have at least one upstream"));
+            }
+            check_upstreams(&format!("blue_green.{name}"), upstreams)?;
+        }
+
+        if let Some(rollback) = pools.rollback.as_ref() {
+            if !(rollback.max_error_rate > 0.0 && rollback.max_error_rate <= 1.0) {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.port must be greater than 0",
-                    route_index,
-                    upstream_index
+                    "blue_green.rollback.max_error_rate must be between 0 and 1"
                 ));
             }
+
+            if rollback.window_secs == 0 {
+                return Err(anyhow!(
+                    "blue_green.rollback.window_secs must be greater than 0"
+                ));
+            }
+        }
+    }
+
+    if let Some(canary) = route.canary.as_ref() {
+        check_upstreams("canary.upstreams", &canary.upstreams)?;
+        if let Err(err) = Canary::from_config(&route.host, canary) {
+            return Err(anyhow!("canary: {err}"));
+        }
+    }
+
+    if let Some(Err(err)) = route.mirror.as_ref().map(Mirror::from_config) {
+        return Err(anyhow!("mirror: {err}"));
+    }
+
+    if let Some(Err(err)) = route.capture.as_ref().map(Capture::from_config) {
+        return Err(anyhow!("capture: {err}"));
+    }
+
+    if let Some(Err(err)) = route.body_logging.as_ref().map(BodyLogging::from_config) {
+        return Err(anyhow!("body_logging: {err}"));
+    }
+
+    Ok(())
+}
+
+fn check_upstreams(name: &str, upstreams: &[RouteUpstream]) -> Result<(), anyhow::Error> {
+    for (upstream_index, upstream) in upstreams.iter().enumerate() {
+        // Validate the upstream's address
+        if upstream.ip.is_empty() {
+            return Err(anyhow!("{name}{upstream_index}.id cannot be empty"));
+        }
+
+        if upstream.port == 0 {
+            return Err(anyhow!(
+                "{name}{upstream_index}.port must be greater than 0"
+            ));
         }
     }
 
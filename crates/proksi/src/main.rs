@@ -1,29 +1,45 @@
-use ::pingora::server::Server;
+use ::pingora::server::{RunArgs, Server};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use clap::crate_version;
-use config::{load, LogFormat, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
-use stores::{MemoryStore, global::init_store};
-use tracing_subscriber::EnvFilter;
+use config::{load, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
 
 use std::{borrow::Cow, sync::Arc};
 
 use pingora::{listeners::tls::TlsSettings, proxy::http_proxy_service, server::configuration::Opt};
 
 use proxy_server::cert_store::CertStore;
+use server::with_runtime;
 use services::{logger::ProxyLoggerReceiver, BackgroundFunctionService};
 
+mod admin;
+mod buffers;
 mod cache;
 mod channel;
 mod config;
+mod metrics;
 mod plugins;
+mod probes;
 mod proxy_server;
 mod server;
 mod services;
+mod status_pages;
 mod stores;
 mod tools;
 mod wasm;
 
+/// jemalloc samples the allocations for the heap profiles of the admin API
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Options of jemalloc: heap profiling is active from the start, with an allocation sampled
+/// every 512 KiB on average (2^19 bytes), cheap enough for production
+#[cfg(feature = "profiling")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
+
 #[derive(Clone, Default)]
 pub struct MsgRoute {
     host: Cow<'static, str>,
@@ -62,6 +78,14 @@ fn main() -> Result<(), anyhow::Error> {
     // Configuration can be refreshed on file change
     // Loads configuration from command-line, YAML or TOML sources
     let proxy_config = Arc::new(load("/etc/proksi/configs").expect("Failed to load configuration"));
+    if let Some(format) = proxy_config.print_config {
+        return config::export::print(&proxy_config, format);
+    }
+    match &proxy_config.command {
+        Some(config::Command::Replay(args)) => return tools::replay::run(args),
+        Some(config::Command::Diff(args)) => return config::diff::run(&proxy_config, args),
+        None => {}
+    }
 
     let https_address = proxy_config
         .server
@@ -71,7 +95,7 @@ fn main() -> Result<(), anyhow::Error> {
     let le_address = proxy_config.server.http_address.clone().unwrap_or_default();
 
     // Logging channel
-    let (log_sender, log_receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+    let (log_sender, log_receiver) = tokio::sync::mpsc::unbounded_channel::<BytesMut>();
 
     // Receiver channel for Routes/Certificates/etc
     let (sender, mut _receiver) = tokio::sync::broadcast::channel::<MsgProxy>(10);
@@ -83,76 +107,151 @@ fn main() -> Result<(), anyhow::Error> {
     );
 
     // Creates a tracing/logging subscriber based on the configuration provided
-    if proxy_config.logging.format == LogFormat::Json {
-        tracing_subscriber::fmt()
-            .json()
-            .with_env_filter(EnvFilter::from_default_env())
-            .with_max_level(&proxy_config.logging.level)
-            .with_writer(appender)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .with_max_level(&proxy_config.logging.level)
-            .with_ansi(proxy_config.logging.path.is_none())
-            .with_writer(appender)
-            .init();
-    };
+    services::logger::init_subscriber(&proxy_config, appender);
 
     // Initialize global store based on configuration
-    match proxy_config.store.store_type {
-        config::StoreType::Memory => {
-            tracing::info!("using Memory store for certificates");
-            init_store(MemoryStore::new());
-        }
-        config::StoreType::Redis => {
-            let redis_url =
-                proxy_config.store.redis_url.as_deref().expect(
-                    "Failed to get redis_url from configuration when store type is 'redis'",
-                );
-            let redis_store = stores::RedisStore::new(redis_url)
-                .expect("Failed to initialize Redis store");
-            tracing::info!("using Redis store for certificates");
-            init_store(redis_store);
-        }
-    };
+    stores::global::init_store_from_config(&proxy_config.store)?;
 
-    // Pingora load balancer server
-    let pingora_opts = Opt {
-        daemon: proxy_config.daemon,
-        upgrade: proxy_config.upgrade,
-        conf: None,
-        nocapture: false,
-        test: false,
-    };
+    // Secret references of the configuration (Vault, AWS), refreshed by the secrets service
+    services::secrets::init(&proxy_config)?;
 
-    let mut pingora_server = Server::new(Some(pingora_opts))?;
-    pingora_server.bootstrap();
+    // Objects the disk cache keeps in memory
+    cache::disk::storage::set_memory_size(proxy_config.cache.memory_size);
+
+    // Pingora load balancer server
+    let mut pingora_server = pingora_server(&proxy_config)?;
 
     // Service: HTTP Load Balancer (only used by acme-challenges)
     // As we don't necessarily need an upstream to handle the acme-challenges,
     // we can use a simple mock LoadBalancer
     let mut http_public_service = http_proxy_service(
         &pingora_server.configuration,
-        proxy_server::http_proxy::HttpLB {},
+        proxy_server::http_proxy::HttpLB {
+            connection_limits: proxy_config.server.connection_limits.clone(),
+            strict_parsing: proxy_config.server.strict_parsing.http.clone(),
+        },
     );
 
     // Service: HTTPS Load Balancer (main service)
     // The router will also handle health checks and failover in case of upstream failure
-    let router = proxy_server::https_proxy::Router {};
+    let router = proxy_server::https_proxy::Router {
+        ip_filter: proxy_config.ip_filter.clone(),
+        trusted_proxies: proxy_config.server.trusted_proxies.clone(),
+        forwarded_headers: proxy_config.server.forwarded_headers.clone(),
+        slow_clients: proxy_config.server.slow_clients.clone(),
+        connection_limits: proxy_config.server.connection_limits.clone(),
+        upstream_pool: proxy_config.server.upstream_pool.clone(),
+        strict_parsing: proxy_config.server.strict_parsing.https.clone(),
+    };
     let mut https_secure_service = http_proxy_service(&pingora_server.configuration, router);
     http_public_service.add_tcp(&le_address);
 
     // Worker threads per configuration
-    https_secure_service.threads = proxy_config.worker_threads;
+    let https_threads = proxy_config
+        .services
+        .https
+        .threads
+        .or(proxy_config.worker_threads);
+    https_secure_service.threads = https_threads;
+
+    // Add TLS settings to the HTTPS service
+    https_secure_service.add_tls_with_settings(&https_address, None, tls_settings(&proxy_config)?);
+
+    // Services: layer 4 listeners, metrics, admin API, probes and status pages
+    // Each service runs with the threads and CPUs of `services`
+    add_optional_services(&mut pingora_server, &proxy_config)?;
+    let services = &proxy_config.services;
+
+    // Non-dedicated background services
+    pingora_server.add_service(with_runtime(
+        Box::new(BackgroundFunctionService::new(proxy_config.clone(), sender)),
+        None,
+        &services.background,
+    ));
+
+    // Dedicated logger service
+    pingora_server.add_service(with_runtime(
+        Box::new(ProxyLoggerReceiver::new(log_receiver, &proxy_config)),
+        None,
+        &services.logger,
+    ));
+
+    // Listen on HTTP and HTTPS ports. Both are named `Pingora HTTP Proxy Service`, they
+    // get their own names so that their threads can be told apart.
+    pingora_server.add_service(with_runtime(
+        Box::new(http_public_service),
+        Some("http_service"),
+        &services.http,
+    ));
+    pingora_server.add_service(with_runtime(
+        Box::new(https_secure_service),
+        Some("https_service"),
+        &services.https,
+    ));
+
+    let server_info = format!(
+        "running HTTPS service on {} and HTTP service on {}",
+        &https_address, &le_address
+    );
+    tracing::info!(
+        version = crate_version!(),
+        workers = https_threads,
+        server_info,
+    );
+
+    pingora_server.run(RunArgs::default());
+
+    // Pingora shut its services down, after their grace period on graceful shutdowns
+    if services::drain::timed_out() {
+        anyhow::bail!("requests, logs or cache writes were still in flight at shutdown");
+    }
+    Ok(())
+}
+
+/// The Pingora server, bootstrapped
+fn pingora_server(proxy_config: &config::Config) -> anyhow::Result<Server> {
+    let pingora_opts = Opt {
+        daemon: proxy_config.daemon,
+        upgrade: proxy_config.upgrade,
+        conf: None,
+        nocapture: false,
+        test: false,
+    };
+
+    let mut pingora_server = Server::new(Some(pingora_opts))?;
+    pingora_server.bootstrap();
+
+    // Idle connections to the upstreams, the connectors of the services are created with
+    // it, and the periods of the graceful shutdowns. The grace period covers the drain and
+    // the flush of the logs and cache writes after it (with a second to spare), so that both
+    // finish before the runtimes are shut down.
+    let shutdown = &proxy_config.server.shutdown;
+    if let Some(configuration) = Arc::get_mut(&mut pingora_server.configuration) {
+        configuration.upstream_keepalive_pool_size = proxy_config.server.upstream_pool.size;
+        configuration.grace_period_seconds =
+            Some(shutdown.drain_secs + shutdown.timeout_secs + 1);
+        configuration.graceful_shutdown_timeout_seconds = Some(shutdown.timeout_secs);
+    } else {
+        tracing::warn!("the upstream connection pool and the shutdown periods could not be set");
+    }
 
-    // Setup tls settings and Enable HTTP/2
+    Ok(pingora_server)
+}
+
+/// TLS settings of the HTTPS service, with HTTP/2 enabled
+fn tls_settings(proxy_config: &config::Config) -> anyhow::Result<TlsSettings> {
     let cert_store = CertStore::new();
     let mut tls_settings = TlsSettings::with_callbacks(Box::new(cert_store)).unwrap();
     tls_settings.enable_h2();
 
     // tls_settings.set_session_cache_mode(SslSessionCacheMode::SERVER);
     tls_settings.set_servername_callback(move |ssl_ref, _| CertStore::sni_callback(ssl_ref));
+    tls_settings.set_client_hello_callback(proxy_server::tls_fingerprint::client_hello_callback);
+    proxy_server::connection_metrics::set_info_callback(&mut tls_settings);
+    proxy_server::session_tickets::configure(
+        &mut tls_settings,
+        &proxy_config.server.session_tickets,
+    )?;
 
     // For now this is a hardcoded recommendation based on
     // https://developers.cloudflare.com/ssl/reference/protocols/
@@ -160,33 +259,62 @@ fn main() -> Result<(), anyhow::Error> {
     tls_settings.set_min_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_2))?;
     tls_settings.set_max_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_3))?;
 
-    // Add TLS settings to the HTTPS service
-    https_secure_service.add_tls_with_settings(&https_address, None, tls_settings);
+    Ok(tls_settings)
+}
 
-    // Add Prometheus service
-    // let mut prometheus_service_http = Service::prometheus_http_service();
-    // prometheus_service_http.add_tcp("0.0.0.0:9090");
-    // pingora_server.add_service(prometheus_service_http);
+/// Adds the services that only run when they are configured
+fn add_optional_services(
+    pingora_server: &mut Server,
+    proxy_config: &config::Config,
+) -> anyhow::Result<()> {
+    let services = &proxy_config.services;
 
-    // Non-dedicated background services
-    pingora_server.add_service(BackgroundFunctionService::new(proxy_config.clone(), sender));
+    // Layer 4 listeners (and the health checks of their upstreams)
+    for listener in &proxy_config.listeners {
+        for service in proxy_server::listener_services(listener)? {
+            pingora_server.add_service(with_runtime(service, None, &services.listeners));
+        }
+    }
 
-    // Dedicated logger service
-    pingora_server.add_service(ProxyLoggerReceiver::new(log_receiver, &proxy_config));
+    // Prometheus metrics service
+    if let Some(metrics_address) = &proxy_config.server.metrics_address {
+        let service = Box::new(metrics::metrics_service(metrics_address));
+        pingora_server.add_service(with_runtime(service, None, &services.admin));
+    }
 
-    // Listen on HTTP and HTTPS ports
-    pingora_server.add_service(http_public_service);
-    pingora_server.add_service(https_secure_service);
+    // Admin API service
+    if let Some(admin_service) = admin::admin_service(proxy_config)? {
+        pingora_server.add_service(with_runtime(Box::new(admin_service), None, &services.admin));
+    }
 
-    let server_info = format!(
-        "running HTTPS service on {} and HTTP service on {}",
-        &https_address, &le_address
-    );
-    tracing::info!(
-        version = crate_version!(),
-        workers = proxy_config.worker_threads,
-        server_info,
-    );
+    // Export of the access logs to a log sink
+    if let Some(export) = &proxy_config.logging.export {
+        let service = Box::new(services::log_export::LogExportService::new(export)?);
+        pingora_server.add_service(with_runtime(service, None, &services.logger));
+    }
+
+    // Liveness and readiness probes service
+    if let Some(probes_service) = probes::probes_service(proxy_config) {
+        pingora_server.add_service(with_runtime(
+            Box::new(probes_service),
+            None,
+            &services.admin,
+        ));
+    }
+
+    // Public status pages, and the availability history of their hosts
+    if let Some(status_pages_service) = status_pages::status_pages_service(proxy_config) {
+        pingora_server.add_service(with_runtime(
+            Box::new(status_pages_service),
+            None,
+            &services.admin,
+        ));
+        pingora_server.add_service(with_runtime(
+            Box::new(status_pages::availability::AvailabilityService),
+            None,
+            &services.admin,
+        ));
+    }
 
-    pingora_server.run_forever();
+    Ok(())
 }
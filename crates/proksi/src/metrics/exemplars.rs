@@ -0,0 +1,296 @@
+use std::{
+    fmt::Write as _,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use prometheus::{core::Collector, proto::MetricFamily, register_histogram_vec, HistogramVec};
+
+/// Length of the values of the exemplars, OpenMetrics limits their labels to 128 characters
+const MAX_EXEMPLAR_VALUE: usize = 64;
+
+/// Last exemplar of each bucket of a series, by upper bound of the bucket
+type Buckets = Mutex<Vec<(f64, Option<Exemplar>)>>;
+
+/// Exemplars of the series of the traced histograms (see `series_key`)
+static EXEMPLARS: Lazy<papaya::HashMap<String, Buckets>> = Lazy::new(papaya::HashMap::new);
+
+/// An observation of a histogram, with the ID of the trace (or of the request) it was made
+/// for. Grafana links the exemplars to the traces.
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    /// `trace_id` or `request_id`
+    label: &'static str,
+    id: String,
+    value: f64,
+    /// Unix timestamp, in seconds
+    timestamp: f64,
+}
+
+/// A histogram keeping the last observation of each of its buckets as an exemplar, exposed
+/// in the OpenMetrics format of `/metrics`
+pub struct TracedHistogram {
+    name: &'static str,
+    label_names: &'static [&'static str],
+    buckets: Vec<f64>,
+    histogram: HistogramVec,
+}
+
+impl TracedHistogram {
+    pub fn register(
+        name: &'static str,
+        help: &str,
+        label_names: &'static [&'static str],
+        buckets: Vec<f64>,
+    ) -> Self {
+        Self {
+            name,
+            label_names,
+            histogram: register_histogram_vec!(name, help, label_names, buckets.clone()).unwrap(),
+            buckets,
+        }
+    }
+
+    /// The current values of the series of the histogram
+    pub fn collect(&self) -> Vec<MetricFamily> {
+        self.histogram.collect()
+    }
+
+    /// Observes the value, with the trace (`trace_id`) or the request (`request_id`) of the
+    /// observation when it has one
+    pub fn observe(
+        &self,
+        label_values: &[&str],
+        value: f64,
+        exemplar: Option<(&'static str, &str)>,
+    ) {
+        self.histogram
+            .with_label_values(label_values)
+            .observe(value);
+
+        let Some((label, id)) = exemplar else {
+            return;
+        };
+        let labels = self
+            .label_names
+            .iter()
+            .copied()
+            .zip(label_values.iter().copied())
+            .collect();
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+
+        let exemplars = EXEMPLARS.pin();
+        let series = exemplars.get_or_insert_with(series_key(self.name, labels), || {
+            let bounds = self.buckets.iter().copied().chain([f64::INFINITY]);
+            Mutex::new(bounds.map(|bound| (bound, None)).collect())
+        });
+        if let Ok(mut series) = series.lock() {
+            series[bucket].1 = Some(Exemplar {
+                label,
+                id: id.chars().take(MAX_EXEMPLAR_VALUE).collect(),
+                value,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default(),
+            });
+        };
+    }
+}
+
+/// Identifies a series by the name of its histogram and its labels, in any order
+fn series_key(name: &str, mut labels: Vec<(&str, &str)>) -> String {
+    labels.sort_unstable();
+    let mut key = name.to_string();
+    for (label, value) in labels {
+        let _ = write!(key, ",{label}={value:?}");
+    }
+    key
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The labels of a sample of the text format (`{host="a",le="0.1"}`), unescaped
+fn parse_labels(labels: &str) -> Option<Vec<(String, String)>> {
+    let mut parsed = vec![];
+    let mut rest = labels.strip_prefix('{')?.strip_suffix('}')?;
+    while !rest.is_empty() {
+        let (name, value) = rest.split_once("=\"")?;
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => unescaped.push('\n'),
+                    escaped => unescaped.push(escaped),
+                },
+                (end, '"') => break end,
+                (_, c) => unescaped.push(c),
+            }
+        };
+        parsed.push((name.trim_start_matches(',').to_string(), unescaped));
+        rest = &value[end + 1..];
+    }
+    Some(parsed)
+}
+
+/// The exemplar of the bucket of a histogram sample (`<histogram>_bucket{...,le="0.1"}`)
+fn bucket_exemplar(histogram: &str, labels: &str) -> Option<Exemplar> {
+    let labels = parse_labels(labels)?;
+    let le = labels
+        .iter()
+        .find(|(label, _)| label == "le")
+        .and_then(|(_, le)| match le.as_str() {
+            "+Inf" => Some(f64::INFINITY),
+            le => le.parse::<f64>().ok(),
+        })?;
+    let key = series_key(
+        histogram,
+        labels
+            .iter()
+            .filter(|(label, _)| label != "le")
+            .map(|(label, value)| (label.as_str(), value.as_str()))
+            .collect(),
+    );
+
+    let exemplars = EXEMPLARS.pin();
+    let series = exemplars.get(&key)?.lock().ok()?;
+    series
+        .iter()
+        .find(|(bound, _)| *bound == le)
+        .and_then(|(_, exemplar)| exemplar.clone())
+}
+
+/// Converts the metrics of the Prometheus text format to the OpenMetrics one, with the
+/// exemplars of the traced histograms on their buckets
+pub fn to_openmetrics(text: &str) -> String {
+    let mut output = String::with_capacity(text.len() + text.len() / 8);
+    let mut help = None;
+    let mut histogram = None;
+
+    for line in text.lines() {
+        if let Some(line) = line.strip_prefix("# HELP ") {
+            help = Some(line);
+            continue;
+        }
+        if let Some(line) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = line.split_once(' ').unwrap_or((line, "untyped"));
+            // The counters are named without their `_total` suffix, the ones without it are
+            // left as they are
+            let (name, kind) = match (kind, name.strip_suffix("_total")) {
+                ("counter", Some(family)) => (family, "counter"),
+                ("counter" | "untyped", _) => (name, "unknown"),
+                (kind, _) => (name, kind),
+            };
+            histogram = (kind == "histogram").then_some(name);
+
+            if let Some(help) = help.take() {
+                let text = help.split_once(' ').map_or("", |(_, text)| text);
+                let _ = writeln!(output, "# HELP {name} {text}");
+            }
+            let _ = writeln!(output, "# TYPE {name} {kind}");
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        output.push_str(line);
+        let exemplar = histogram.and_then(|histogram| {
+            let sample = line.strip_prefix(histogram)?.strip_prefix("_bucket")?;
+            let (labels, _) = sample.rsplit_once(' ')?;
+            bucket_exemplar(histogram, labels)
+        });
+        if let Some(exemplar) = exemplar {
+            let _ = write!(
+                output,
+                " # {{{}=\"{}\"}} {} {}",
+                exemplar.label,
+                escape(&exemplar.id),
+                exemplar.value,
+                exemplar.timestamp
+            );
+        }
+        output.push('\n');
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_labels() {
+        assert_eq!(
+            parse_labels(r#"{host="a.localhost",path="say \"hi\"\\",le="0.1"}"#).unwrap(),
+            vec![
+                ("host".to_string(), "a.localhost".to_string()),
+                ("path".to_string(), "say \"hi\"\\".to_string()),
+                ("le".to_string(), "0.1".to_string()),
+            ]
+        );
+        assert_eq!(parse_labels("{}").unwrap(), vec![]);
+        assert!(parse_labels(r#"{host="a"#).is_none());
+    }
+
+    #[test]
+    fn test_to_openmetrics() {
+        let histogram = TracedHistogram::register(
+            "test_traced_duration_seconds",
+            "Test histogram",
+            &["host"],
+            vec![0.1, 1.0],
+        );
+        histogram.observe(
+            &["traced.localhost"],
+            0.05,
+            Some(("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736")),
+        );
+        histogram.observe(&["traced.localhost"], 0.5, None);
+        histogram.observe(&["traced.localhost"], 5.0, Some(("request_id", "slow")));
+
+        let text = "# HELP test_requests_total Requests\n\
+                    # TYPE test_requests_total counter\n\
+                    test_requests_total{host=\"traced.localhost\"} 3\n\
+                    # HELP test_traced_duration_seconds Test histogram\n\
+                    # TYPE test_traced_duration_seconds histogram\n\
+                    test_traced_duration_seconds_bucket{host=\"traced.localhost\",le=\"0.1\"} 1\n\
+                    test_traced_duration_seconds_bucket{host=\"traced.localhost\",le=\"1\"} 2\n\
+                    test_traced_duration_seconds_bucket{host=\"traced.localhost\",le=\"+Inf\"} 3\n\
+                    test_traced_duration_seconds_sum{host=\"traced.localhost\"} 5.55\n\
+                    test_traced_duration_seconds_count{host=\"traced.localhost\"} 3\n";
+        let output = to_openmetrics(text);
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "# HELP test_requests Requests");
+        assert_eq!(lines[1], "# TYPE test_requests counter");
+        assert_eq!(lines[2], "test_requests_total{host=\"traced.localhost\"} 3");
+        assert!(lines[5].starts_with(
+            "test_traced_duration_seconds_bucket{host=\"traced.localhost\",le=\"0.1\"} 1 \
+             # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.05 "
+        ));
+        assert_eq!(
+            lines[6], "test_traced_duration_seconds_bucket{host=\"traced.localhost\",le=\"1\"} 2",
+            "the observation of the bucket had no trace"
+        );
+        assert!(lines[7].contains("# {request_id=\"slow\"} 5 "));
+        assert_eq!(
+            lines[8],
+            "test_traced_duration_seconds_sum{host=\"traced.localhost\"} 5.55"
+        );
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
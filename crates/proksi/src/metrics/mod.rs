@@ -0,0 +1,508 @@
+use async_trait::async_trait;
+use http::{header, Response, StatusCode};
+use once_cell::sync::Lazy;
+use pingora::{
+    apps::http_app::{HttpServer, ServeHttp},
+    protocols::http::ServerSession,
+    services::listening::Service,
+};
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+pub use exemplars::TracedHistogram;
+
+mod exemplars;
+
+/// Content type of the OpenMetrics format, the only one with exemplars
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Notifications of the alerts sent to the webhooks, by rule, status (firing, resolved)
+/// and result (sent, failed)
+pub static ALERT_NOTIFICATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_alert_notifications_total",
+        "Notifications of the alerts sent to the webhooks",
+        &["rule", "status", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests authenticated with an API key, by key name and result (allowed, rate_limited)
+pub static API_KEY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_api_key_requests_total",
+        "Requests authenticated with an API key",
+        &["host", "key", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests rejected because the API key was missing or invalid
+pub static API_KEY_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_api_key_rejections_total",
+        "Requests rejected because of a missing or invalid API key",
+        &["host", "reason"]
+    )
+    .unwrap()
+});
+
+/// Switches of the active pool of the blue/green routes, by reason (api, rollback)
+pub static BLUE_GREEN_SWITCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_blue_green_switches_total",
+        "Switches of the active pool of the blue/green routes",
+        &["host", "active", "reason"]
+    )
+    .unwrap()
+});
+
+/// Requests of classified bots, by class (crawler, scraper) and result (allowed, blocked, rate_limited)
+pub static BOT_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_bot_requests_total",
+        "Requests checked by the bot filter plugin",
+        &["host", "class", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests of routes with caching enabled, by result (hit, miss, expired)
+pub static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_cache_requests_total",
+        "Requests of routes with caching enabled",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests of the routes with a canary pool, by pool (stable, canary)
+pub static CANARY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_canary_requests_total",
+        "Requests of the routes with a canary pool, by pool",
+        &["host", "pool"]
+    )
+    .unwrap()
+});
+
+/// Requests of the challenge plugin, by result (issued, passed, failed)
+pub static CHALLENGE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_challenge_requests_total",
+        "Challenges issued to clients and their results",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// Revocation checks of the client certificates, by method (crl, ocsp) and status
+/// (good, revoked, unknown)
+pub static CLIENT_CERTIFICATE_REVOCATION_CHECKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_client_certificate_revocation_checks_total",
+        "Revocation checks of the client certificates",
+        &["method", "status"]
+    )
+    .unwrap()
+});
+
+/// Time between the TCP accept of the connections of the HTTPS service and the end of their
+/// TLS handshake, by protocol (h2, http/1.1)
+pub static CONNECTION_ACCEPT_TO_TLS_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proksi_connection_accept_to_tls_duration_seconds",
+        "Time between the TCP accept of the connections and the end of their TLS handshake",
+        &["protocol"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap()
+});
+
+/// Lifetime of the closed client connections, by listener (https, http) and protocol
+pub static CONNECTION_DURATION_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proksi_connection_duration_seconds",
+        "Lifetime of the closed client connections",
+        &["listener", "protocol"],
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0]
+    )
+    .unwrap()
+});
+
+/// Connections rejected by the connection limits of a listener (https, http), by limit
+/// (concurrent, rate)
+pub static CONNECTION_LIMIT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_connection_limit_rejections_total",
+        "Connections rejected by the connection limits of a listener",
+        &["listener", "limit"]
+    )
+    .unwrap()
+});
+
+/// Requests served by the client connections once they are closed, by listener (https,
+/// http) and protocol
+pub static CONNECTION_REQUESTS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proksi_connection_requests",
+        "Requests served by the client connections once they are closed",
+        &["listener", "protocol"],
+        vec![1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 5000.0]
+    )
+    .unwrap()
+});
+
+/// Fragments of the pages processed by the ESI plugin, by result (hit, miss, error)
+pub static ESI_FRAGMENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_esi_fragments_total",
+        "Fragments included in the pages by the ESI plugin",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests of the A/B experiments, by variant and status class of the response
+pub static EXPERIMENT_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_experiment_requests_total",
+        "Requests of the A/B experiments, by variant and status class of the response",
+        &["host", "experiment", "variant", "status"]
+    )
+    .unwrap()
+});
+
+/// Faults injected in the requests by the fault injection plugin, by fault (delay, abort,
+/// disconnect)
+pub static FAULT_INJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_fault_injections_total",
+        "Faults injected in the requests by the fault injection plugin",
+        &["host", "fault"]
+    )
+    .unwrap()
+});
+
+/// Requests checked by the GeoIP plugin, by country and result (allowed, denied)
+pub static GEOIP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_geoip_requests_total",
+        "Requests checked by the GeoIP plugin",
+        &["host", "country", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests of the HTTPS service, by status class of the response (2xx, 3xx, 4xx, 5xx)
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_http_requests_total",
+        "Requests of the HTTPS service, by status class of the response",
+        &["host", "status"]
+    )
+    .unwrap()
+});
+
+/// Access logs exported to the log sink, by result: `sent`, `spilled` (written to disk
+/// while the sink is down) or `dropped`
+pub static LOG_EXPORT_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_log_export_events_total",
+        "Access logs exported to the log sink",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Time to serve the requests of the HTTPS service, from their headers to the end of their
+/// response, with the traces of the requests as exemplars
+pub static HTTP_REQUEST_SECONDS: Lazy<TracedHistogram> = Lazy::new(|| {
+    TracedHistogram::register(
+        "proksi_http_request_duration_seconds",
+        "Time to serve the requests of the HTTPS service",
+        &["host"],
+        vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+        ],
+    )
+});
+
+/// Copies of the requests sent to the shadow upstreams, by result (the status class of the
+/// response of the shadow, error, dropped or too_large)
+pub static MIRROR_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_mirror_requests_total",
+        "Copies of the requests sent to the shadow upstreams, by result",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// Requests redirected by the redirects plugin, by status of the redirect
+pub static REDIRECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_redirects_total",
+        "Requests redirected by the map of the redirects plugin",
+        &["host", "status"]
+    )
+    .unwrap()
+});
+
+/// Requests rejected because of a missing, expired or invalid URL signature
+pub static SIGNED_URL_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_signed_url_rejections_total",
+        "Requests rejected because of a missing, expired or invalid URL signature",
+        &["host", "reason"]
+    )
+    .unwrap()
+});
+
+/// Clients disconnected for sending their request too slowly, by reason
+/// (read_timeout, body_timeout, min_rate)
+pub static SLOW_CLIENT_DISCONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_slow_client_disconnections_total",
+        "Clients disconnected for sending their request too slowly",
+        &["host", "reason"]
+    )
+    .unwrap()
+});
+
+/// Requests rejected by the strict parsing of a listener (https, http), by reason
+/// (conflicting_length, obs_fold, duplicate_host, too_many_headers)
+pub static STRICT_PARSING_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_strict_parsing_rejections_total",
+        "Requests rejected by the strict parsing of a listener",
+        &["listener", "reason"]
+    )
+    .unwrap()
+});
+
+/// Connections (handshake) and requests rejected because of their TLS fingerprint
+pub static TLS_FINGERPRINT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_tls_fingerprint_rejections_total",
+        "Connections and requests rejected because of their TLS fingerprint",
+        &["host", "stage"]
+    )
+    .unwrap()
+});
+
+/// TLS handshakes of the HTTPS service, by version, protocol negotiated with ALPN (h2,
+/// http/1.1, other, none) and whether the session was resumed
+pub static TLS_HANDSHAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_tls_handshakes_total",
+        "TLS handshakes of the HTTPS service, by version, ALPN protocol and resumption",
+        &["version", "alpn", "resumed"]
+    )
+    .unwrap()
+});
+
+/// Time between the ClientHello and the end of the TLS handshakes of the HTTPS service
+pub static TLS_HANDSHAKE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "proksi_tls_handshake_duration_seconds",
+        "Time between the ClientHello and the end of the TLS handshakes",
+        &["version", "resumed"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap()
+});
+
+/// Connections to the upstreams used by the requests, by whether they were reused from the
+/// pool (true, false)
+pub static UPSTREAM_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_upstream_connections_total",
+        "Connections to the upstreams used by the requests, new or reused from the pool",
+        &["host", "upstream", "reused"]
+    )
+    .unwrap()
+});
+
+/// Time to establish the new connections to the upstreams (TCP and TLS handshakes), with
+/// the traces of the requests as exemplars
+pub static UPSTREAM_CONNECT_SECONDS: Lazy<TracedHistogram> = Lazy::new(|| {
+    TracedHistogram::register(
+        "proksi_upstream_connect_duration_seconds",
+        "Time to establish the new connections to the upstreams",
+        &["host", "upstream"],
+        vec![
+            0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+        ],
+    )
+});
+
+/// Upstreams taken out of their pool by the health checks, by the check that took them out
+/// (active, passive)
+pub static UPSTREAM_EJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_upstream_ejections_total",
+        "Upstreams taken out of their pool by the active and passive health checks",
+        &["host", "upstream", "source"]
+    )
+    .unwrap()
+});
+
+/// Whether each upstream can receive requests (1) or not (0), see `stores::health`
+pub static UPSTREAM_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_healthy",
+        "Whether the upstream can receive requests, from the active and passive checks",
+        &["host", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Duration of the last active health check of each upstream
+pub static UPSTREAM_HEALTH_CHECK_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "proksi_upstream_health_check_duration_seconds",
+        "Duration of the last active health check of the upstream",
+        &["host", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Requests waiting for the response of each upstream
+pub static UPSTREAM_IN_FLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_in_flight_requests",
+        "Requests sent to the upstream whose response is not complete yet",
+        &["host", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Requests of the pools (stable, canary) that had no upstream able to receive them
+pub static UPSTREAM_POOL_EXHAUSTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_upstream_pool_exhausted_total",
+        "Requests answered with 503 because no upstream of the pool was healthy",
+        &["host", "pool"]
+    )
+    .unwrap()
+});
+
+/// Upstreams of each host, by health (healthy, unhealthy)
+pub static UPSTREAM_POOL_UPSTREAMS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_pool_upstreams",
+        "Upstreams of the host, by health",
+        &["host", "state"]
+    )
+    .unwrap()
+});
+
+/// Requests that matched WAF rules, by result (blocked, detected)
+pub static WAF_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_waf_requests_total",
+        "Requests that matched WAF rules",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// WebSocket connections currently open
+pub static WEBSOCKET_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_websocket_connections",
+        "WebSocket connections currently open",
+        &["host"]
+    )
+    .unwrap()
+});
+
+/// WebSocket connections established since the start
+pub static WEBSOCKET_CONNECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_websocket_connections_total",
+        "WebSocket connections established",
+        &["host"]
+    )
+    .unwrap()
+});
+
+/// Counts a request in `proksi_upstream_in_flight_requests` until it's dropped
+pub struct UpstreamInFlight {
+    host: String,
+    upstream: String,
+}
+
+impl UpstreamInFlight {
+    pub fn new(host: &str, upstream: &str) -> Self {
+        UPSTREAM_IN_FLIGHT
+            .with_label_values(&[host, upstream])
+            .inc();
+        Self {
+            host: host.to_string(),
+            upstream: upstream.to_string(),
+        }
+    }
+}
+
+impl Drop for UpstreamInFlight {
+    fn drop(&mut self) {
+        UPSTREAM_IN_FLIGHT
+            .with_label_values(&[self.host.as_str(), self.upstream.as_str()])
+            .dec();
+    }
+}
+
+/// Serves the metrics of the default prometheus registry in the text format
+pub struct MetricsApp;
+
+#[async_trait]
+impl ServeHttp for MetricsApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        if session.req_header().uri.path() != "/metrics" {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(header::CONTENT_LENGTH, 0)
+                .body(vec![])
+                .unwrap_or_default();
+        }
+
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+            tracing::error!("failed to encode metrics: {err}");
+        }
+
+        // Prometheus asks for OpenMetrics when it scrapes the exemplars
+        let openmetrics = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+        let content_type = if openmetrics {
+            buffer = exemplars::to_openmetrics(&String::from_utf8_lossy(&buffer)).into_bytes();
+            OPENMETRICS_CONTENT_TYPE
+        } else {
+            encoder.format_type()
+        };
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, buffer.len())
+            .body(buffer)
+            .unwrap_or_default()
+    }
+}
+
+/// Creates the service exposing `/metrics` on the given address
+pub fn metrics_service(address: &str) -> Service<HttpServer<MetricsApp>> {
+    let mut service = Service::new("metrics".to_string(), HttpServer::new_app(MetricsApp));
+    service.add_tcp(address);
+    service
+}
@@ -0,0 +1,368 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{StatusCode, Uri};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{
+    config::RoutePlugin,
+    metrics,
+    proxy_server::https_proxy::RouterContext,
+    stores::{
+        api_keys::{hash_api_key, ApiKey},
+        global::get_store,
+    },
+};
+
+use super::{
+    rate_limit::RateLimiter, respond_with_retry_after, respond_with_status, route_settings,
+    settings_cache::SettingsCache, MiddlewarePlugin,
+};
+
+/// Default header used to send the API key
+const DEFAULT_HEADER: &str = "x-api-key";
+
+/// Default rate limit window (in seconds)
+const DEFAULT_RATE_LIMIT_WINDOW: u64 = 60;
+
+/// Per-route settings of the API key plugin
+struct ApiKeySettings {
+    header: String,
+    query_param: Option<String>,
+    /// Keys from the configuration, by their hash
+    keys: HashMap<String, ApiKey>,
+    use_store: bool,
+    rate_limit: Option<u64>,
+    rate_limit_window: Duration,
+    hide_credentials: bool,
+}
+
+impl ApiKeySettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let mut keys = HashMap::new();
+
+        if let Some(values) = config.get("keys") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid keys"))?;
+
+            for value in values {
+                let key = value
+                    .get("key")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Missing or invalid key"))?;
+                let name = value
+                    .get("name")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Missing or invalid name for key"))?;
+
+                keys.insert(
+                    hash_api_key(key),
+                    ApiKey {
+                        name: name.to_string(),
+                        rate_limit: value.get("rate_limit").and_then(serde_json::Value::as_u64),
+                    },
+                );
+            }
+        }
+
+        Ok(Self {
+            header: config
+                .get("header")
+                .and_then(|v| v.as_str())
+                .unwrap_or(DEFAULT_HEADER)
+                .to_ascii_lowercase(),
+            query_param: config
+                .get("query_param")
+                .and_then(|v| v.as_str())
+                .map(ToString::to_string),
+            keys,
+            use_store: config
+                .get("use_store")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(false),
+            rate_limit: config.get("rate_limit").and_then(serde_json::Value::as_u64),
+            rate_limit_window: Duration::from_secs(
+                config
+                    .get("rate_limit_window")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_RATE_LIMIT_WINDOW),
+            ),
+            hide_credentials: config
+                .get("hide_credentials")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+
+    /// Extracts the API key from the configured header or query parameter
+    fn extract_key(&self, req: &RequestHeader) -> Option<String> {
+        if let Some(value) = req.headers.get(&self.header).and_then(|v| v.to_str().ok()) {
+            return Some(value.trim().to_string());
+        }
+
+        let param = self.query_param.as_deref()?;
+        let query = req.uri.query()?;
+
+        form_urlencoded_pairs(query)
+            .find(|(name, _)| name == param)
+            .map(|(_, value)| value)
+    }
+
+    /// Finds the API key in the configuration first, then in the global store (if enabled)
+    async fn find_key(&self, key: &str) -> Option<ApiKey> {
+        let key_hash = hash_api_key(key);
+
+        if let Some(api_key) = self.keys.get(&key_hash) {
+            return Some(api_key.clone());
+        }
+
+        if self.use_store {
+            return get_store().get_api_key(&key_hash).await;
+        }
+
+        None
+    }
+}
+
+/// Decodes the pairs of a query string
+fn form_urlencoded_pairs(query: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    reqwest::Url::parse(&format!("http://localhost/?{query}"))
+        .map(|url| url.query_pairs().into_owned().collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+}
+
+/// Removes a query parameter from the URI, keeping the rest of the query untouched
+fn strip_query_param(uri: &Uri, param: &str) -> Option<Uri> {
+    let query = uri.query()?;
+
+    let remaining = query
+        .split('&')
+        .filter(|pair| form_urlencoded_pairs(pair).all(|(name, _)| name != param))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let path_and_query = if remaining.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{remaining}", uri.path())
+    };
+
+    path_and_query.parse().ok()
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Authenticates requests with API keys (from a header or query parameter),
+/// applying per-key rate limits
+pub struct ApiKeyAuth {
+    pub(super) rate_limiter: RateLimiter,
+    settings: SettingsCache<ApiKeySettings>,
+}
+
+impl ApiKeyAuth {
+    pub fn new() -> Self {
+        Self {
+            rate_limiter: RateLimiter::default(),
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for ApiKeyAuth {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Nothing to do if the plugin configuration is not present
+        let Some(config) = plugin.config.as_ref() else {
+            return Ok(false);
+        };
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            ApiKeySettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let Some(key) = settings.extract_key(session.req_header()) else {
+            metrics::API_KEY_REJECTIONS
+                .with_label_values(&[ctx.host.as_str(), "missing"])
+                .inc();
+            return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+        };
+
+        let Some(api_key) = settings.find_key(&key).await else {
+            metrics::API_KEY_REJECTIONS
+                .with_label_values(&[ctx.host.as_str(), "invalid"])
+                .inc();
+            return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+        };
+
+        if let Some(limit) = api_key.rate_limit.or(settings.rate_limit) {
+            let id = format!("{}:{}", ctx.host, api_key.name);
+            if let Err(retry_after) =
+                self.rate_limiter
+                    .check(&id, limit, settings.rate_limit_window, current_timestamp())
+            {
+                metrics::API_KEY_REQUESTS
+                    .with_label_values(&[ctx.host.as_str(), api_key.name.as_str(), "rate_limited"])
+                    .inc();
+                return respond_with_retry_after(session, retry_after).await;
+            }
+        }
+
+        metrics::API_KEY_REQUESTS
+            .with_label_values(&[ctx.host.as_str(), api_key.name.as_str(), "allowed"])
+            .inc();
+
+        if settings.hide_credentials {
+            ctx.extensions
+                .insert(Cow::Borrowed("api_key_header"), settings.header.clone());
+            if let Some(param) = &settings.query_param {
+                ctx.extensions.insert(Cow::Borrowed("api_key_query"), param.clone());
+            }
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        if let Some(header) = ctx.extensions.get("api_key_header") {
+            upstream_request.remove_header(header.as_str());
+        }
+
+        if let Some(param) = ctx.extensions.get("api_key_query") {
+            if let Some(uri) = strip_query_param(&upstream_request.uri, param) {
+                upstream_request.set_uri(uri);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings() -> ApiKeySettings {
+        let config = HashMap::from([
+            (
+                Cow::Borrowed("keys"),
+                json!([
+                    { "name": "client-a", "key": "key-a", "rate_limit": 5 },
+                    { "name": "client-b", "key": "key-b" }
+                ]),
+            ),
+            (Cow::Borrowed("query_param"), json!("api_key")),
+            (Cow::Borrowed("rate_limit"), json!(100)),
+        ]);
+        ApiKeySettings::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let settings = settings();
+        assert_eq!(settings.header, "x-api-key");
+        assert_eq!(settings.keys.len(), 2);
+        assert_eq!(
+            settings.keys.get(&hash_api_key("key-a")),
+            Some(&ApiKey {
+                name: "client-a".to_string(),
+                rate_limit: Some(5)
+            })
+        );
+        assert_eq!(settings.rate_limit, Some(100));
+
+        let invalid = HashMap::from([(Cow::Borrowed("keys"), json!([{ "name": "no-key" }]))]);
+        assert!(ApiKeySettings::from_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_extract_key() {
+        let settings = settings();
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-api-key", "key-a").unwrap();
+        assert_eq!(settings.extract_key(&req).as_deref(), Some("key-a"));
+
+        let req = RequestHeader::build("GET", b"/items?page=1&api_key=key%2Db", None).unwrap();
+        assert_eq!(settings.extract_key(&req).as_deref(), Some("key-b"));
+
+        let req = RequestHeader::build("GET", b"/items?page=1", None).unwrap();
+        assert_eq!(settings.extract_key(&req), None);
+    }
+
+    #[tokio::test]
+    async fn test_find_configured_key() {
+        let settings = settings();
+        assert_eq!(
+            settings.find_key("key-b").await.map(|k| k.name),
+            Some("client-b".to_string())
+        );
+        assert!(settings.find_key("unknown").await.is_none());
+    }
+
+    #[test]
+    fn test_strip_query_param() {
+        let uri: Uri = "/items?page=1&api_key=secret&sort=asc".parse().unwrap();
+        assert_eq!(
+            strip_query_param(&uri, "api_key").unwrap(),
+            "/items?page=1&sort=asc"
+        );
+
+        let uri: Uri = "/items?api_key=secret".parse().unwrap();
+        assert_eq!(strip_query_param(&uri, "api_key").unwrap(), "/items");
+    }
+}
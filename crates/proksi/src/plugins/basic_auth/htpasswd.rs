@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use openssl::{base64, sha::Sha256};
+
+use crate::stores::expiring::ExpiringMap;
+
+/// How long a successful verification of a bcrypt hash is reused, bcrypt is slow by design
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// Successful verifications of the bcrypt hashes, by digest of the user, the hash and
+/// the password (see `verified_key`)
+static VERIFIED: Lazy<ExpiringMap<()>> = Lazy::new(ExpiringMap::new);
+
+/// Users loaded from an htpasswd file
+#[derive(Debug, Default)]
+pub(super) struct HtpasswdFile {
+    users: HashMap<String, String>,
+}
+
+impl HtpasswdFile {
+    /// Parses the contents of an htpasswd file (`user:hash` per line).
+    /// Empty lines and lines starting with `#` are ignored.
+    fn parse(contents: &str) -> Self {
+        let users = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(user, hash)| (user.to_string(), hash.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        for (user, hash) in &users {
+            if hash.starts_with("$apr1$") {
+                tracing::warn!(
+                    "the APR1 hash of the htpasswd user {user} is not supported, use bcrypt (htpasswd -B)"
+                );
+            }
+        }
+
+        Self { users }
+    }
+
+    /// Returns true if the user exists and the password matches its hash
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => verify(user, password, hash).await,
+            None => false,
+        }
+    }
+}
+
+/// Keeps the parsed htpasswd files in memory. A file is read on its first use, then a task
+/// checks it for changes every `reload_interval` and reloads it, the requests never wait
+/// for the disk.
+#[derive(Default)]
+pub(super) struct HtpasswdStore {
+    files: papaya::HashMap<PathBuf, Arc<ArcSwap<HtpasswdFile>>>,
+}
+
+impl HtpasswdStore {
+    /// Returns the users of the file, reading it the first time
+    pub async fn get(
+        &self,
+        path: &Path,
+        reload_interval: Duration,
+    ) -> anyhow::Result<Arc<HtpasswdFile>> {
+        if let Some(file) = self.files.pin().get(path) {
+            return Ok(file.load_full());
+        }
+
+        let (file, modified) = read(path).await?;
+        let file = Arc::new(ArcSwap::from_pointee(file));
+
+        // Another request may have read it meanwhile, a single task watches the file
+        let files = self.files.pin();
+        match files.try_insert(path.to_path_buf(), file.clone()) {
+            Ok(_) => {
+                tokio::spawn(watch(
+                    path.to_path_buf(),
+                    Arc::downgrade(&file),
+                    modified,
+                    reload_interval,
+                ));
+                Ok(file.load_full())
+            }
+            Err(occupied) => Ok(occupied.current.load_full()),
+        }
+    }
+}
+
+async fn read(path: &Path) -> anyhow::Result<(HtpasswdFile, Option<SystemTime>)> {
+    let modified = tokio::fs::metadata(path).await?.modified().ok();
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok((HtpasswdFile::parse(&contents), modified))
+}
+
+/// Reloads the file whenever its modification time changes. A file that can't be read
+/// again keeps its previous users.
+async fn watch(
+    path: PathBuf,
+    file: Weak<ArcSwap<HtpasswdFile>>,
+    mut modified: Option<SystemTime>,
+    reload_interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(reload_interval.max(Duration::from_secs(1))).await;
+        let Some(file) = file.upgrade() else {
+            return;
+        };
+
+        let current = tokio::fs::metadata(&path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        if current.is_some() && current == modified {
+            continue;
+        }
+
+        match read(&path).await {
+            Ok((users, read_modified)) => {
+                tracing::info!("reloaded the htpasswd file {path:?}");
+                file.store(Arc::new(users));
+                modified = read_modified;
+            }
+            Err(err) => tracing::error!("failed to reload the htpasswd file {path:?}: {err}"),
+        }
+    }
+}
+
+/// Verifies the password of a user against its credential. The bcrypt hashes are verified
+/// on the blocking threads, and their successful verifications are reused for a minute.
+pub(super) async fn verify(user: &str, password: &str, hash: &str) -> bool {
+    if !hash.starts_with("$2") {
+        return verify_password(password, hash);
+    }
+
+    let key = verified_key(user, password, hash);
+    if VERIFIED.get(&key).is_some() {
+        return true;
+    }
+
+    let (password, hash) = (password.to_string(), hash.to_string());
+    let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+        .await
+        .is_ok_and(|verified| verified.unwrap_or(false));
+    if valid {
+        VERIFIED.insert(key, (), VERIFIED_TTL);
+    }
+    valid
+}
+
+/// The passwords are never kept, only a digest of the verified credentials
+fn verified_key(user: &str, password: &str, hash: &str) -> String {
+    let mut digest = Sha256::new();
+    for part in [user, hash, password] {
+        digest.update(part.as_bytes());
+        digest.update(&[0]);
+    }
+    base64::encode_block(&digest.finish())
+}
+
+/// Verifies a password against a stored credential. Supported formats are
+/// bcrypt (`$2y$`, `$2b$`, `$2a$`), `{SHA}` and plain text.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let computed = base64::encode_block(&openssl::sha::sha1(password.as_bytes()));
+        return computed.len() == expected.len()
+            && openssl::memcmp::eq(computed.as_bytes(), expected.as_bytes());
+    }
+
+    // Never compared as plain text
+    if hash.starts_with("$apr1$") {
+        return false;
+    }
+
+    password.len() == hash.len() && openssl::memcmp::eq(password.as_bytes(), hash.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BCRYPT_ABC: &str = "$2y$06$If6bvum7DFjUnE9p2uDeDu0YHzrHM6tf.iqN8.yx.jNN1ILEf7h0i";
+
+    #[test]
+    fn test_verify_formats() {
+        assert!(verify_password(
+            "password",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="
+        ));
+        assert!(!verify_password(
+            "passw0rd",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="
+        ));
+
+        assert!(verify_password("abc", BCRYPT_ABC));
+        assert!(!verify_password("abd", BCRYPT_ABC));
+
+        assert!(
+            !verify_password(
+                "$apr1$r31abcde$ouL8QL9v/FwrkrtBccxbL.",
+                "$apr1$r31abcde$ouL8QL9v/FwrkrtBccxbL."
+            ),
+            "the APR1 hashes are not supported"
+        );
+
+        assert!(verify_password("plain", "plain"));
+        assert!(!verify_password("plain", "plainer"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_caches_bcrypt() {
+        assert!(!verify("carol", "abd", BCRYPT_ABC).await);
+        assert!(VERIFIED
+            .get(&verified_key("carol", "abd", BCRYPT_ABC))
+            .is_none());
+
+        assert!(verify("carol", "abc", BCRYPT_ABC).await);
+        assert!(VERIFIED
+            .get(&verified_key("carol", "abc", BCRYPT_ABC))
+            .is_some());
+        assert!(verify("carol", "abc", BCRYPT_ABC).await);
+    }
+
+    #[tokio::test]
+    async fn test_parse_file() {
+        let file = HtpasswdFile::parse(
+            "# staging users\n\nalice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\nbob:secret\ninvalid-line\n",
+        );
+
+        assert_eq!(file.users.len(), 2);
+        assert!(file.verify("alice", "password").await);
+        assert!(file.verify("bob", "secret").await);
+        assert!(!file.verify("bob", "password").await);
+        assert!(!file.verify("carol", "secret").await);
+    }
+
+    #[tokio::test]
+    async fn test_store_reloads_modified_file() {
+        let path = std::env::temp_dir().join(format!("proksi-htpasswd-{}", std::process::id()));
+        std::fs::write(&path, "alice:first\n").unwrap();
+
+        let store = HtpasswdStore::default();
+        let users = store.get(&path, Duration::from_secs(1)).await.unwrap();
+        assert!(users.verify("alice", "first").await);
+
+        // Make sure the modification time changes on filesystems with coarse timestamps
+        std::fs::write(&path, "alice:second\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let users = store.get(&path, Duration::from_secs(1)).await.unwrap();
+        assert!(users.verify("alice", "second").await);
+        assert!(!users.verify("alice", "first").await);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, path::Path, time::Duration};
 
 use async_trait::async_trait;
 use http::{header, StatusCode};
@@ -12,10 +12,20 @@ use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
 use super::MiddlewarePlugin;
 
-pub struct BasicAuth;
+mod htpasswd;
+
+/// How often (in seconds) htpasswd files are checked for changes by default
+const DEFAULT_RELOAD_INTERVAL: u64 = 5;
+
+pub struct BasicAuth {
+    htpasswd: htpasswd::HtpasswdStore,
+}
+
 impl BasicAuth {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            htpasswd: htpasswd::HtpasswdStore::default(),
+        }
     }
 
     /// Returns a WWW-Authenticate header response indicating to downstream that
@@ -37,12 +47,44 @@ impl BasicAuth {
         Some((user, pass))
     }
 
-    /// Validates the 'Authorization' header against the configured 'user' and 'pass'
-    fn validate_auth_header(auth_header: &str, user: &str, pass: &str) -> anyhow::Result<bool> {
+    /// Decodes the 'Authorization' header into the user and password sent by downstream
+    fn decode_auth_header(auth_header: &str) -> anyhow::Result<(String, String)> {
         let encoded = auth_header.trim_start_matches("Basic ");
         let decoded = String::from_utf8(base64::decode_block(encoded)?)?;
         let (auth_user, auth_pass) = decoded.split_once(':').unwrap_or_default();
-        Ok(auth_user == user && auth_pass == pass)
+        Ok((auth_user.to_string(), auth_pass.to_string()))
+    }
+
+    /// Validates the credentials against the configured 'user' and 'pass' and,
+    /// if present, the users of the 'htpasswd_file'.
+    /// 'pass' can either be a plain text password or a htpasswd compatible hash.
+    async fn validate_credentials(
+        &self,
+        config: &HashMap<Cow<'static, str>, serde_json::Value>,
+        auth_user: &str,
+        auth_pass: &str,
+    ) -> anyhow::Result<bool> {
+        if let Some((user, pass)) = Self::get_auth_config(config) {
+            if auth_user == user && htpasswd::verify(auth_user, auth_pass, &pass).await {
+                return Ok(true);
+            }
+        }
+
+        let Some(file) = config.get("htpasswd_file").and_then(|v| v.as_str()) else {
+            return Ok(false);
+        };
+
+        let reload_interval = config
+            .get("reload_interval")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(DEFAULT_RELOAD_INTERVAL);
+
+        let users = self
+            .htpasswd
+            .get(Path::new(file), Duration::from_secs(reload_interval))
+            .await?;
+
+        Ok(users.verify(auth_user, auth_pass).await)
     }
 }
 
@@ -61,13 +103,6 @@ impl MiddlewarePlugin for BasicAuth {
 
         let config = plugin.config.as_ref().unwrap();
 
-        let Some((user, pass)) = Self::get_auth_config(config) else {
-            session
-                .write_response_header(Self::respond_with_authenticate(&ctx.host)?, true)
-                .await?;
-            return Ok(true);
-        };
-
         // Get auth header but if missing returns 401
         let auth_header = if let Some(header) = session.req_header().headers.get("authorization") {
             header.to_str()?
@@ -78,9 +113,19 @@ impl MiddlewarePlugin for BasicAuth {
             return Ok(true);
         };
 
-        if !auth_header.starts_with("Basic ")
-            || !Self::validate_auth_header(auth_header, &user, &pass)?
-        {
+        let is_valid = if auth_header.starts_with("Basic ") {
+            let (auth_user, auth_pass) = Self::decode_auth_header(auth_header)?;
+            self.validate_credentials(config, &auth_user, &auth_pass)
+                .await
+                .unwrap_or_else(|err| {
+                    tracing::error!("failed to validate basic auth credentials: {err}");
+                    false
+                })
+        } else {
+            false
+        };
+
+        if !is_valid {
             session
                 .write_response_header(Self::respond_with_authenticate(&ctx.host)?, true)
                 .await?;
@@ -0,0 +1,440 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+use regex::Regex;
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{
+    rate_limit::RateLimiter, respond_with_retry_after, respond_with_status, route_settings,
+    settings_cache::SettingsCache, MiddlewarePlugin,
+};
+
+/// Default rate limit window (in seconds)
+const DEFAULT_RATE_LIMIT_WINDOW: u64 = 60;
+
+/// Well-known crawlers (search engines and link previews), matched case-insensitively
+const KNOWN_CRAWLERS: &[&str] = &[
+    "googlebot",
+    "bingbot",
+    "duckduckbot",
+    "baiduspider",
+    "yandexbot",
+    "applebot",
+    "slurp",
+    "facebookexternalhit",
+    "twitterbot",
+    "linkedinbot",
+    "slackbot",
+    "discordbot",
+    "telegrambot",
+];
+
+/// Well-known scrapers (HTTP libraries, headless browsers, SEO and AI crawlers)
+const KNOWN_SCRAPERS: &[&str] = &[
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "scrapy",
+    "curl",
+    "wget",
+    "go-http-client",
+    "okhttp",
+    "java/",
+    "libwww-perl",
+    "headlesschrome",
+    "phantomjs",
+    "ahrefsbot",
+    "semrushbot",
+    "mj12bot",
+    "dotbot",
+    "petalbot",
+    "bytespider",
+    "gptbot",
+    "ccbot",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BotClass {
+    Crawler,
+    Scraper,
+}
+
+impl BotClass {
+    fn as_str(self) -> &'static str {
+        match self {
+            BotClass::Crawler => "crawler",
+            BotClass::Scraper => "scraper",
+        }
+    }
+}
+
+/// What happens to the requests of a class of bots (they are always tagged in the access logs)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BotAction {
+    Tag,
+    Block,
+    RateLimit,
+}
+
+impl BotAction {
+    fn from_config(value: Option<&serde_json::Value>, key: &str) -> Result<Self> {
+        match value.map(|v| v.as_str()) {
+            None | Some(Some("tag")) => Ok(BotAction::Tag),
+            Some(Some("block")) => Ok(BotAction::Block),
+            Some(Some("rate_limit")) => Ok(BotAction::RateLimit),
+            _ => bail!("Missing or invalid {key}"),
+        }
+    }
+}
+
+/// Per-route settings of the bot filter plugin
+struct BotFilterSettings {
+    allow_user_agents: Vec<Regex>,
+    deny_user_agents: Vec<Regex>,
+    /// Patterns added to the well-known bots
+    crawlers: Vec<Regex>,
+    scrapers: Vec<Regex>,
+    crawler_action: BotAction,
+    scraper_action: BotAction,
+    rate_limit: Option<u64>,
+    rate_limit_window: Duration,
+}
+
+impl BotFilterSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let patterns = |key: &str| -> Result<Vec<Regex>> {
+            let Some(values) = config.get(key) else {
+                return Ok(vec![]);
+            };
+
+            values
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))?
+                .iter()
+                .map(|v| {
+                    let pattern = v
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Missing or invalid {key}"))?;
+                    Ok(Regex::new(pattern)?)
+                })
+                .collect()
+        };
+
+        let settings = Self {
+            allow_user_agents: patterns("allow_user_agents")?,
+            deny_user_agents: patterns("deny_user_agents")?,
+            crawlers: patterns("crawlers")?,
+            scrapers: patterns("scrapers")?,
+            crawler_action: BotAction::from_config(config.get("crawler_action"), "crawler_action")?,
+            scraper_action: BotAction::from_config(config.get("scraper_action"), "scraper_action")?,
+            rate_limit: config.get("rate_limit").and_then(serde_json::Value::as_u64),
+            rate_limit_window: Duration::from_secs(
+                config
+                    .get("rate_limit_window")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_RATE_LIMIT_WINDOW),
+            ),
+        };
+
+        let rate_limited = settings.crawler_action == BotAction::RateLimit
+            || settings.scraper_action == BotAction::RateLimit;
+        if rate_limited && settings.rate_limit.is_none() {
+            bail!("The rate_limit action requires a rate_limit");
+        }
+
+        Ok(settings)
+    }
+
+    /// Classifies a user agent, returning the class and the name of the bot.
+    /// Requests without a user agent are considered scrapers.
+    fn classify<'a>(&self, user_agent: &'a str) -> Option<(BotClass, Cow<'a, str>)> {
+        if user_agent.trim().is_empty() {
+            return Some((BotClass::Scraper, Cow::Borrowed("empty")));
+        }
+
+        // Custom patterns first, so they can reclassify well-known bots
+        let custom = [
+            (BotClass::Crawler, &self.crawlers),
+            (BotClass::Scraper, &self.scrapers),
+        ];
+        for (class, patterns) in custom {
+            if let Some(found) = patterns.iter().find_map(|p| p.find(user_agent)) {
+                return Some((class, Cow::Borrowed(found.as_str())));
+            }
+        }
+
+        let lowercase = user_agent.to_ascii_lowercase();
+        let known = [
+            (BotClass::Crawler, KNOWN_CRAWLERS),
+            (BotClass::Scraper, KNOWN_SCRAPERS),
+        ];
+        known.into_iter().find_map(|(class, names)| {
+            let name = names.iter().find(|name| lowercase.contains(*name))?;
+            Some((class, Cow::Owned(name.trim_end_matches('/').to_string())))
+        })
+    }
+
+    fn action(&self, class: BotClass) -> BotAction {
+        match class {
+            BotClass::Crawler => self.crawler_action,
+            BotClass::Scraper => self.scraper_action,
+        }
+    }
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Filters requests by their user agent, classifying well-known bots
+/// (crawlers and scrapers) to block, rate-limit or tag them
+pub struct BotFilter {
+    settings: SettingsCache<BotFilterSettings>,
+    pub(super) rate_limiter: RateLimiter,
+}
+
+impl BotFilter {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for BotFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            BotFilterSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let user_agent = session
+            .req_header()
+            .headers
+            .get(header::USER_AGENT)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .unwrap_or_default();
+
+        // Allowed user agents are exceptions to every other rule
+        if settings
+            .allow_user_agents
+            .iter()
+            .any(|p| p.is_match(&user_agent))
+        {
+            return Ok(false);
+        }
+
+        let bot = settings.classify(&user_agent);
+        let class = bot.as_ref().map_or("unknown", |(class, _)| class.as_str());
+
+        // Used by the access logs
+        if let Some((class, name)) = &bot {
+            ctx.extensions
+                .insert(Cow::Borrowed("bot"), class.as_str().to_string());
+            ctx.extensions
+                .insert(Cow::Borrowed("bot_name"), name.to_string());
+        }
+
+        if settings
+            .deny_user_agents
+            .iter()
+            .any(|p| p.is_match(&user_agent))
+        {
+            metrics::BOT_REQUESTS
+                .with_label_values(&[ctx.host.as_str(), class, "blocked"])
+                .inc();
+            return respond_with_status(session, StatusCode::FORBIDDEN).await;
+        }
+
+        let Some((bot_class, _)) = bot else {
+            return Ok(false);
+        };
+
+        match settings.action(bot_class) {
+            BotAction::Tag => {}
+            BotAction::Block => {
+                metrics::BOT_REQUESTS
+                    .with_label_values(&[ctx.host.as_str(), class, "blocked"])
+                    .inc();
+                return respond_with_status(session, StatusCode::FORBIDDEN).await;
+            }
+            BotAction::RateLimit => {
+                // Each client has its own limit, so a single scraper can't exhaust it for all
+                let client_ip = ctx.extensions.get("client_ip").map_or("", String::as_str);
+                let id = format!("{}:{class}:{client_ip}", ctx.host);
+                let limit = settings.rate_limit.unwrap_or_default();
+
+                if let Err(retry_after) = self.rate_limiter.check(
+                    &id,
+                    limit,
+                    settings.rate_limit_window,
+                    current_timestamp(),
+                ) {
+                    metrics::BOT_REQUESTS
+                        .with_label_values(&[ctx.host.as_str(), class, "rate_limited"])
+                        .inc();
+                    return respond_with_retry_after(session, retry_after).await;
+                }
+            }
+        }
+
+        metrics::BOT_REQUESTS
+            .with_label_values(&[ctx.host.as_str(), class, "allowed"])
+            .inc();
+
+        Ok(false)
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<BotFilterSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        BotFilterSettings::from_config(&config)
+    }
+
+    fn classify(settings: &BotFilterSettings, user_agent: &str) -> Option<(BotClass, String)> {
+        settings
+            .classify(user_agent)
+            .map(|(class, name)| (class, name.into_owned()))
+    }
+
+    #[test]
+    fn test_classify_known_bots() {
+        let settings = settings(&[]).unwrap();
+
+        assert_eq!(
+            classify(
+                &settings,
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"
+            ),
+            Some((BotClass::Crawler, "googlebot".to_string()))
+        );
+        assert_eq!(
+            classify(&settings, "python-requests/2.31.0"),
+            Some((BotClass::Scraper, "python-requests".to_string()))
+        );
+        assert_eq!(
+            classify(&settings, "Java/17.0.2"),
+            Some((BotClass::Scraper, "java".to_string()))
+        );
+        assert_eq!(
+            classify(&settings, ""),
+            Some((BotClass::Scraper, "empty".to_string()))
+        );
+        assert_eq!(
+            classify(
+                &settings,
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_0) AppleWebKit/605.1.15 Safari/605.1.15"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_custom_patterns() {
+        let settings = settings(&[
+            ("crawlers", json!(["(?i)curl/"])),
+            ("scrapers", json!(["MyScraper/\\d+"])),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            classify(&settings, "curl/8.4.0"),
+            Some((BotClass::Crawler, "curl/".to_string()))
+        );
+        assert_eq!(
+            classify(&settings, "MyScraper/2"),
+            Some((BotClass::Scraper, "MyScraper/2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let defaults = settings(&[]).unwrap();
+        assert_eq!(defaults.action(BotClass::Crawler), BotAction::Tag);
+        assert_eq!(defaults.action(BotClass::Scraper), BotAction::Tag);
+
+        let custom = settings(&[
+            ("scraper_action", json!("rate_limit")),
+            ("crawler_action", json!("block")),
+            ("rate_limit", json!(10)),
+        ])
+        .unwrap();
+        assert_eq!(custom.action(BotClass::Scraper), BotAction::RateLimit);
+        assert_eq!(custom.action(BotClass::Crawler), BotAction::Block);
+        assert_eq!(custom.rate_limit_window, Duration::from_secs(60));
+
+        assert!(settings(&[("scraper_action", json!("rate_limit"))]).is_err());
+        assert!(settings(&[("crawler_action", json!("drop"))]).is_err());
+        assert!(settings(&[("deny_user_agents", json!(["("]))]).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use std::{borrow::Cow, collections::HashMap, fmt::Write, time::Duration, time::SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use cookie::{Cookie, SameSite};
+use http::{header, StatusCode};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sha::sha256, sign::Signer};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{
+    rate_limit::RateLimiter, route_settings, settings_cache::SettingsCache, MiddlewarePlugin,
+};
+
+/// Page solving the proof of work with the Web Crypto API, then sending
+/// the solution to the verification path
+const CHALLENGE_PAGE: &str = include_str!("challenge.html");
+
+const DEFAULT_DIFFICULTY: u32 = 16;
+const DEFAULT_COOKIE_NAME: &str = "__proksi_clearance";
+const DEFAULT_CLEARANCE_TTL: u64 = 3600;
+const DEFAULT_VERIFY_PATH: &str = "/__/challenge/verify";
+const DEFAULT_RATE_LIMIT_WINDOW: u64 = 60;
+
+/// How long a challenge can be solved after being issued
+const CHALLENGE_TTL: u64 = 300;
+
+/// Per-route settings of the challenge plugin
+struct ChallengeSettings {
+    secret: Vec<u8>,
+    /// Leading zero bits required in the hash of the solution
+    difficulty: u32,
+    cookie_name: String,
+    clearance_ttl: u64,
+    verify_path: String,
+    /// Clients are only challenged above this number of requests per window
+    /// (every client is challenged if not set)
+    rate_limit: Option<u64>,
+    rate_limit_window: Duration,
+}
+
+impl ChallengeSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let secret = config
+            .get("secret")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Missing or invalid secret"))?;
+
+        let difficulty = match config.get("difficulty") {
+            Some(value) => value
+                .as_u64()
+                .filter(|v| (1..=32).contains(v))
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| anyhow!("Missing or invalid difficulty (between 1 and 32)"))?,
+            None => DEFAULT_DIFFICULTY,
+        };
+
+        let string_or = |key: &str, default: &str| -> Result<String> {
+            match config.get(key) {
+                Some(value) => value
+                    .as_str()
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}")),
+                None => Ok(default.to_string()),
+            }
+        };
+
+        let verify_path = string_or("verify_path", DEFAULT_VERIFY_PATH)?;
+        if !verify_path.starts_with('/') {
+            bail!("Missing or invalid verify_path (must start with /)");
+        }
+
+        Ok(Self {
+            secret: secret.as_bytes().to_vec(),
+            difficulty,
+            cookie_name: string_or("cookie_name", DEFAULT_COOKIE_NAME)?,
+            clearance_ttl: config
+                .get("clearance_ttl")
+                .and_then(serde_json::Value::as_u64)
+                .unwrap_or(DEFAULT_CLEARANCE_TTL),
+            verify_path,
+            rate_limit: config.get("rate_limit").and_then(serde_json::Value::as_u64),
+            rate_limit_window: Duration::from_secs(
+                config
+                    .get("rate_limit_window")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_RATE_LIMIT_WINDOW),
+            ),
+        })
+    }
+
+    /// Hex-encoded HMAC-SHA256 of the message
+    fn sign(&self, message: &str) -> Result<String> {
+        let key = PKey::hmac(&self.secret)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(message.as_bytes())?;
+
+        Ok(to_hex(&signer.sign_to_vec()?))
+    }
+
+    fn verify_signature(&self, message: &str, signature: &str) -> bool {
+        self.sign(message).is_ok_and(|expected| {
+            expected.len() == signature.len()
+                && memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        })
+    }
+
+    /// Challenges are stateless: `<issued_at>.<random>.<signature>`, bound to the client IP
+    fn issue_challenge(&self, client_ip: &str, now: u64) -> Result<String> {
+        let random = uuid::Uuid::new_v4().simple().to_string();
+        let signature = self.sign(&format!("challenge.{now}.{random}.{client_ip}"))?;
+        Ok(format!("{now}.{random}.{signature}"))
+    }
+
+    /// Checks that the challenge was issued to the client, has not expired,
+    /// and that the nonce solves it
+    fn verify_solution(&self, challenge: &str, nonce: &str, client_ip: &str, now: u64) -> bool {
+        let mut parts = challenge.splitn(3, '.');
+        let (Some(issued_at), Some(random), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+
+        let Ok(issued_at_secs) = issued_at.parse::<u64>() else {
+            return false;
+        };
+
+        let valid_nonce =
+            !nonce.is_empty() && nonce.len() <= 20 && nonce.bytes().all(|b| b.is_ascii_digit());
+
+        valid_nonce
+            && now.saturating_sub(issued_at_secs) <= CHALLENGE_TTL
+            && self.verify_signature(
+                &format!("challenge.{issued_at}.{random}.{client_ip}"),
+                signature,
+            )
+            && leading_zero_bits(&sha256(format!("{challenge}{nonce}").as_bytes()))
+                >= self.difficulty
+    }
+
+    /// Clearance cookies are `<expires_at>.<signature>`, bound to the client IP
+    fn clearance_cookie(&self, client_ip: &str, now: u64) -> Result<Cookie<'static>> {
+        let expires_at = now + self.clearance_ttl;
+        let signature = self.sign(&format!("clearance.{expires_at}.{client_ip}"))?;
+
+        Ok(Cookie::build((
+            self.cookie_name.clone(),
+            format!("{expires_at}.{signature}"),
+        ))
+        .secure(true)
+        .path("/")
+        .max_age(cookie::time::Duration::seconds(
+            i64::try_from(self.clearance_ttl).unwrap_or(i64::MAX),
+        ))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build())
+    }
+
+    fn has_clearance<'a>(
+        &self,
+        cookie_headers: impl Iterator<Item = &'a str>,
+        client_ip: &str,
+        now: u64,
+    ) -> bool {
+        cookie_headers
+            .flat_map(Cookie::split_parse)
+            .filter_map(Result::ok)
+            .filter(|cookie| cookie.name() == self.cookie_name)
+            .any(|cookie| {
+                let Some((expires_at, signature)) = cookie.value().split_once('.') else {
+                    return false;
+                };
+
+                expires_at.parse::<u64>().is_ok_and(|v| v >= now)
+                    && self
+                        .verify_signature(&format!("clearance.{expires_at}.{client_ip}"), signature)
+            })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Only relative paths are accepted, so the verification can't be used as an open redirect
+fn sanitize_redirect(redirect: Option<&str>) -> &str {
+    match redirect {
+        Some(path)
+            if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") =>
+        {
+            path
+        }
+        _ => "/",
+    }
+}
+
+/// Renders the challenge page, the values are embedded as JSON strings
+fn render_page(challenge: &str, difficulty: u32, verify_path: &str, redirect: &str) -> String {
+    // Prevents the values from closing the script tag
+    let js_string = |value: &str| {
+        serde_json::Value::from(value)
+            .to_string()
+            .replace('<', "\\u003c")
+    };
+
+    CHALLENGE_PAGE
+        .replace("{{challenge}}", &js_string(challenge))
+        .replace("{{difficulty}}", &difficulty.to_string())
+        .replace("{{verify_path}}", &js_string(verify_path))
+        .replace("{{redirect}}", &js_string(redirect))
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Serves a JavaScript proof-of-work challenge to clients without a clearance cookie,
+/// always or only once they exceed a request rate
+pub struct Challenge {
+    settings: SettingsCache<ChallengeSettings>,
+    pub(super) rate_limiter: RateLimiter,
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+
+    async fn respond_with_challenge(
+        session: &mut Session,
+        settings: &ChallengeSettings,
+        client_ip: &str,
+        redirect: &str,
+    ) -> Result<bool> {
+        let challenge = settings.issue_challenge(client_ip, current_timestamp())?;
+        let body = Bytes::from(render_page(
+            &challenge,
+            settings.difficulty,
+            &settings.verify_path,
+            redirect,
+        ));
+
+        let mut res_headers = ResponseHeader::build_no_case(StatusCode::FORBIDDEN, Some(3))?;
+        res_headers.insert_header(header::CONTENT_TYPE, "text/html; charset=utf-8")?;
+        res_headers.insert_header(header::CONTENT_LENGTH, body.len())?;
+        res_headers.insert_header(
+            header::CACHE_CONTROL,
+            "no-store, no-cache, must-revalidate, max-age=0",
+        )?;
+
+        session
+            .write_response_header(Box::new(res_headers), false)
+            .await?;
+        session.write_response_body(Some(body), true).await?;
+
+        Ok(true)
+    }
+
+    /// Handles the solution sent by the challenge page
+    async fn handle_verification(
+        session: &mut Session,
+        settings: &ChallengeSettings,
+        host: &str,
+        client_ip: &str,
+    ) -> Result<bool> {
+        let uri = &session.req_header().uri;
+        let query = reqwest::Url::parse(&format!("https://{host}{uri}"))?;
+        let params = query.query_pairs().collect::<HashMap<_, _>>();
+
+        let redirect = sanitize_redirect(params.get("redirect").map(AsRef::as_ref)).to_string();
+        let now = current_timestamp();
+
+        let solved = match (params.get("challenge"), params.get("nonce")) {
+            (Some(challenge), Some(nonce)) => {
+                settings.verify_solution(challenge, nonce, client_ip, now)
+            }
+            _ => false,
+        };
+
+        if !solved {
+            metrics::CHALLENGE_REQUESTS
+                .with_label_values(&[host, "failed"])
+                .inc();
+            return Self::respond_with_challenge(session, settings, client_ip, &redirect).await;
+        }
+
+        metrics::CHALLENGE_REQUESTS
+            .with_label_values(&[host, "passed"])
+            .inc();
+
+        let cookie = settings.clearance_cookie(client_ip, now)?;
+        let mut res_headers = ResponseHeader::build_no_case(StatusCode::FOUND, Some(3))?;
+        res_headers.insert_header(header::LOCATION, redirect)?;
+        res_headers.insert_header(
+            header::CACHE_CONTROL,
+            "no-store, no-cache, must-revalidate, max-age=0",
+        )?;
+        res_headers.insert_header(header::SET_COOKIE, cookie.to_string())?;
+
+        session
+            .write_response_header(Box::new(res_headers), true)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Challenge {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            ChallengeSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let client_ip = ctx
+            .extensions
+            .get("client_ip")
+            .map_or("", String::as_str)
+            .to_string();
+
+        if session.req_header().uri.path() == settings.verify_path {
+            return Self::handle_verification(session, &settings, &ctx.host, &client_ip).await;
+        }
+
+        let now = current_timestamp();
+        let cookies = session
+            .req_header()
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+
+        if settings.has_clearance(cookies, &client_ip, now) {
+            return Ok(false);
+        }
+
+        if let Some(limit) = settings.rate_limit {
+            let id = format!("{}:{client_ip}", ctx.host);
+            if self
+                .rate_limiter
+                .check(&id, limit, settings.rate_limit_window, now)
+                .is_ok()
+            {
+                return Ok(false);
+            }
+        }
+
+        metrics::CHALLENGE_REQUESTS
+            .with_label_values(&[ctx.host.as_str(), "issued"])
+            .inc();
+
+        let redirect = session
+            .req_header()
+            .uri
+            .path_and_query()
+            .map_or("/", |v| v.as_str())
+            .to_string();
+
+        Self::respond_with_challenge(session, &settings, &client_ip, &redirect).await
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> ChallengeSettings {
+        let mut config = HashMap::from([(Cow::Borrowed("secret"), json!("s3cr3t"))]);
+        for (key, value) in extra {
+            config.insert(Cow::Borrowed(*key), value.clone());
+        }
+        ChallengeSettings::from_config(&config).unwrap()
+    }
+
+    fn solve(challenge: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                leading_zero_bits(&sha256(format!("{challenge}{nonce}").as_bytes())) >= difficulty
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_verify_solution() {
+        let settings = settings(&[("difficulty", json!(8))]);
+        let challenge = settings.issue_challenge("10.0.0.1", 1000).unwrap();
+        let nonce = solve(&challenge, 8);
+
+        assert!(settings.verify_solution(&challenge, &nonce, "10.0.0.1", 1010));
+        // issued to another client
+        assert!(!settings.verify_solution(&challenge, &nonce, "10.0.0.2", 1010));
+        // expired
+        assert!(!settings.verify_solution(&challenge, &nonce, "10.0.0.1", 1301));
+        // forged
+        let forged = challenge.replacen("1000", "1200", 1);
+        assert!(!settings.verify_solution(&forged, &solve(&forged, 8), "10.0.0.1", 1210));
+
+        let wrong = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| leading_zero_bits(&sha256(format!("{challenge}{nonce}").as_bytes())) < 8)
+            .unwrap();
+        assert!(!settings.verify_solution(&challenge, &wrong, "10.0.0.1", 1010));
+        assert!(!settings.verify_solution(&challenge, "-1", "10.0.0.1", 1010));
+    }
+
+    #[test]
+    fn test_clearance() {
+        let settings = settings(&[("clearance_ttl", json!(60))]);
+        let cookie = settings
+            .clearance_cookie("10.0.0.1", 1000)
+            .unwrap()
+            .stripped()
+            .to_string();
+        let has_clearance =
+            |header: &str, ip, now| settings.has_clearance([header].into_iter(), ip, now);
+
+        assert!(has_clearance(&cookie, "10.0.0.1", 1060));
+        assert!(has_clearance(&format!("a=b; {cookie}"), "10.0.0.1", 1000));
+        assert!(!has_clearance(&cookie, "10.0.0.1", 1061));
+        assert!(!has_clearance(&cookie, "10.0.0.2", 1000));
+        assert!(!has_clearance(
+            &cookie.replacen("1060", "9999", 1),
+            "10.0.0.1",
+            1000
+        ));
+        assert!(!has_clearance("", "10.0.0.1", 1000));
+    }
+
+    #[test]
+    fn test_render_page() {
+        assert_eq!(sanitize_redirect(Some("/a?b=c")), "/a?b=c");
+        assert_eq!(sanitize_redirect(Some("//evil.com")), "/");
+        assert_eq!(sanitize_redirect(Some("https://evil.com")), "/");
+        assert_eq!(sanitize_redirect(None), "/");
+
+        let page = render_page("1.a.b", 16, "/verify", "/</script>\"");
+        assert!(page.contains(r#"const challenge = "1.a.b";"#));
+        assert!(page.contains("const difficulty = 16;"));
+        assert!(page.contains(r#"const redirect = "/\u003c/script>\"";"#));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(ChallengeSettings::from_config(&HashMap::new()).is_err());
+
+        for (key, value) in [
+            ("difficulty", json!(0)),
+            ("difficulty", json!(33)),
+            ("verify_path", json!("verify")),
+            ("cookie_name", json!("")),
+        ] {
+            let config = HashMap::from([
+                (Cow::Borrowed("secret"), json!("s3cr3t")),
+                (Cow::Borrowed(key), value),
+            ]);
+            assert!(ChallengeSettings::from_config(&config).is_err());
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cookie::SameSite;
+use http::{header, HeaderValue};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{settings_cache::SettingsCache, MiddlewarePlugin};
+
+fn parse_same_site(value: &str) -> Result<SameSite> {
+    match value.to_ascii_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => bail!("invalid same_site {value}"),
+    }
+}
+
+/// Per-route settings of the cookie rewrite plugin
+#[derive(Debug, Default)]
+struct CookieRewriteSettings {
+    /// Upstream domains (lowercase, without leading dot) with their replacement,
+    /// an empty replacement removes the attribute (host-only cookie)
+    domains: Vec<(String, String)>,
+    /// Upstream path prefixes with their replacement, longest prefixes first
+    paths: Vec<(String, String)>,
+    secure: Option<bool>,
+    http_only: Option<bool>,
+    same_site: Option<SameSite>,
+    /// Cookies that are neither sent to the upstream nor to the client
+    strip: Vec<String>,
+    /// Upstream cookie names with the name used by the client
+    rename: Vec<(String, String)>,
+}
+
+impl CookieRewriteSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let string_map = |key: &str| -> Result<Vec<(String, String)>> {
+            let Some(value) = config.get(key) else {
+                return Ok(vec![]);
+            };
+
+            value
+                .as_object()
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))?
+                .iter()
+                .map(|(from, to)| {
+                    to.as_str()
+                        .map(|to| (from.clone(), to.to_string()))
+                        .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+                })
+                .collect()
+        };
+
+        let bool_option = |key: &str| -> Result<Option<bool>> {
+            config
+                .get(key)
+                .map(|v| {
+                    v.as_bool()
+                        .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+                })
+                .transpose()
+        };
+
+        let domains = string_map("domain")?
+            .into_iter()
+            .map(|(from, to)| (normalize_domain(&from), to))
+            .collect();
+
+        let mut paths = string_map("path")?;
+        if paths.iter().any(|(from, _)| !from.starts_with('/')) {
+            bail!("Missing or invalid path");
+        }
+        paths.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        let same_site = config
+            .get("same_site")
+            .map(|v| {
+                v.as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid same_site"))
+                    .and_then(parse_same_site)
+            })
+            .transpose()?;
+
+        let strip = match config.get("strip") {
+            Some(names) => names
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid strip"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(ToString::to_string)
+                        .ok_or_else(|| anyhow!("Missing or invalid strip"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        let rename = string_map("rename")?;
+        if rename
+            .iter()
+            .any(|(from, to)| from.is_empty() || to.is_empty())
+        {
+            bail!("Missing or invalid rename");
+        }
+
+        Ok(Self {
+            domains,
+            paths,
+            secure: bool_option("secure")?,
+            http_only: bool_option("http_only")?,
+            same_site,
+            strip,
+            rename,
+        })
+    }
+
+    /// Rewrites the `Cookie` header sent to the upstream, returning `None`
+    /// if no cookie is left
+    fn rewrite_request_cookies(&self, cookies: &str) -> Option<String> {
+        let rewritten = cookies
+            .split(';')
+            .map(str::trim)
+            .filter(|cookie| !cookie.is_empty())
+            .filter_map(|cookie| {
+                let (name, value) = cookie.split_once('=').unwrap_or((cookie, ""));
+
+                // The client knows cookies by their renamed version
+                let renamed = self.rename.iter().find(|(_, to)| to == name);
+                let name = renamed.map_or(name, |(from, _)| from.as_str());
+
+                if self.strip.iter().any(|strip| strip == name) {
+                    return None;
+                }
+
+                match renamed {
+                    Some(_) => Some(format!("{name}={value}")),
+                    None => Some(cookie.to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if rewritten.is_empty() {
+            return None;
+        }
+
+        Some(rewritten.join("; "))
+    }
+
+    /// Rewrites a `Set-Cookie` header sent by the upstream, returning `None`
+    /// if the cookie is stripped. The header is parsed by hand, as `cookie::Cookie`
+    /// would drop the attributes it doesn't know about (e.g. `Priority`).
+    fn rewrite_set_cookie(&self, set_cookie: &str) -> Option<String> {
+        let mut parts = set_cookie.split(';').map(str::trim);
+        let cookie = parts.next()?;
+        let (name, value) = cookie.split_once('=').unwrap_or((cookie, ""));
+
+        if self.strip.iter().any(|strip| strip == name) {
+            return None;
+        }
+
+        let name = self
+            .rename
+            .iter()
+            .find(|(from, _)| from == name)
+            .map_or(name, |(_, to)| to.as_str());
+
+        let mut attributes = vec![];
+        let mut same_site_set = false;
+        let mut secure_set = false;
+        let mut http_only_set = false;
+
+        for attribute in parts.filter(|part| !part.is_empty()) {
+            let (key, attribute_value) = attribute
+                .split_once('=')
+                .map_or((attribute, None), |(k, v)| (k.trim(), Some(v.trim())));
+
+            match (key.to_ascii_lowercase().as_str(), attribute_value) {
+                ("domain", Some(domain)) => {
+                    match self.rewrite_domain(domain) {
+                        Some("") => {}
+                        Some(domain) => attributes.push(format!("Domain={domain}")),
+                        None => attributes.push(attribute.to_string()),
+                    }
+                    continue;
+                }
+                ("path", Some(path)) => {
+                    attributes.push(format!("Path={}", self.rewrite_path(path)));
+                    continue;
+                }
+                ("secure", _) => {
+                    secure_set = true;
+                    if self.secure == Some(false) {
+                        continue;
+                    }
+                }
+                ("httponly", _) => {
+                    http_only_set = true;
+                    if self.http_only == Some(false) {
+                        continue;
+                    }
+                }
+                ("samesite", _) => {
+                    same_site_set = true;
+                    if let Some(same_site) = self.same_site {
+                        attributes.push(format!("SameSite={same_site}"));
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            attributes.push(attribute.to_string());
+        }
+
+        if let (Some(same_site), false) = (self.same_site, same_site_set) {
+            attributes.push(format!("SameSite={same_site}"));
+        }
+
+        // Browsers reject `SameSite=None` cookies that are not secure
+        let secure = self.secure == Some(true) || self.same_site == Some(SameSite::None);
+        if secure && !secure_set {
+            attributes.push("Secure".to_string());
+        }
+
+        if self.http_only == Some(true) && !http_only_set {
+            attributes.push("HttpOnly".to_string());
+        }
+
+        let mut rewritten = format!("{name}={value}");
+        for attribute in attributes {
+            rewritten.push_str("; ");
+            rewritten.push_str(&attribute);
+        }
+
+        Some(rewritten)
+    }
+
+    fn rewrite_domain(&self, domain: &str) -> Option<&str> {
+        let domain = normalize_domain(domain);
+        self.domains
+            .iter()
+            .find(|(from, _)| *from == domain)
+            .map(|(_, to)| to.as_str())
+    }
+
+    fn rewrite_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let Some((from, to)) = self
+            .paths
+            .iter()
+            .find(|(from, _)| path.starts_with(from.as_str()))
+        else {
+            return Cow::Borrowed(path);
+        };
+
+        let rewritten = format!("{to}{}", &path[from.len()..]);
+        if rewritten.is_empty() {
+            return Cow::Borrowed("/");
+        }
+
+        Cow::Owned(rewritten.replace("//", "/"))
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_start_matches('.').to_ascii_lowercase()
+}
+
+/// Rewrites the attributes of the cookies set by the upstreams (Domain, Path, Secure,
+/// SameSite, etc.), and strips or renames cookies in both directions
+pub struct CookieRewrite {
+    settings: SettingsCache<CookieRewriteSettings>,
+}
+
+impl CookieRewrite {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    fn get_settings(&self, ctx: &RouterContext) -> Result<Option<Arc<CookieRewriteSettings>>> {
+        let Some(plugin) = ctx.route_container.plugins.get("cookie_rewrite") else {
+            return Ok(None);
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        self.settings
+            .get_or_try_build(
+                ctx.route_container.id,
+                config,
+                CookieRewriteSettings::from_config,
+            )
+            .inspect_err(|err| {
+                tracing::error!("invalid cookie_rewrite plugin configuration: {err}");
+            })
+            .map(Some)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for CookieRewrite {
+    // Nothing to do before the upstream request
+    async fn request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(settings) = self.get_settings(ctx)? else {
+            return Ok(());
+        };
+
+        if settings.strip.is_empty() && settings.rename.is_empty() {
+            return Ok(());
+        }
+
+        // HTTP/2 clients may send each cookie in its own header
+        let cookies = upstream_request
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        if cookies.is_empty() {
+            return Ok(());
+        }
+
+        match settings.rewrite_request_cookies(&cookies) {
+            Some(cookies) => upstream_request.insert_header(header::COOKIE, cookies)?,
+            None => {
+                upstream_request.remove_header(&header::COOKIE);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(settings) = self.get_settings(ctx)? else {
+            return Ok(());
+        };
+
+        let set_cookies = upstream_response
+            .headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| match v.to_str() {
+                // Cookies that can't be parsed are kept as they are
+                Ok(set_cookie) => settings
+                    .rewrite_set_cookie(set_cookie)
+                    .map(HeaderValue::try_from)
+                    .transpose(),
+                Err(_) => Ok(Some(v.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if set_cookies.is_empty() {
+            return Ok(());
+        }
+
+        upstream_response.remove_header(&header::SET_COOKIE);
+        for set_cookie in set_cookies.into_iter().flatten() {
+            upstream_response.append_header(header::SET_COOKIE, set_cookie)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<CookieRewriteSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        CookieRewriteSettings::from_config(&config)
+    }
+
+    #[test]
+    fn test_rewrite_set_cookie() {
+        let settings = settings(&[
+            (
+                "domain",
+                json!({ "internal.local": "example.com", ".legacy.local": "" }),
+            ),
+            ("path", json!({ "/": "/app/", "/api": "/app/api" })),
+            ("secure", json!(true)),
+            ("same_site", json!("lax")),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            settings
+                .rewrite_set_cookie("session=abc; Domain=.Internal.local; Path=/api/v1; HttpOnly")
+                .unwrap(),
+            "session=abc; Domain=example.com; Path=/app/api/v1; HttpOnly; SameSite=Lax; Secure"
+        );
+        assert_eq!(
+            settings
+                .rewrite_set_cookie(
+                    "theme=dark; domain=legacy.local; path=/; SameSite=None; Secure"
+                )
+                .unwrap(),
+            "theme=dark; Path=/app/; SameSite=Lax; Secure"
+        );
+        // Other domains are kept
+        assert_eq!(
+            settings
+                .rewrite_set_cookie("a=1; Domain=other.com; Secure; SameSite=Strict")
+                .unwrap(),
+            "a=1; Domain=other.com; Secure; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn test_remove_attributes() {
+        let removed = settings(&[("secure", json!(false)), ("http_only", json!(false))]).unwrap();
+        assert_eq!(
+            removed
+                .rewrite_set_cookie("a=1; Secure; HttpOnly; Max-Age=60")
+                .unwrap(),
+            "a=1; Max-Age=60"
+        );
+
+        // `SameSite=None` requires `Secure`
+        let none = settings(&[("same_site", json!("None"))]).unwrap();
+        assert_eq!(
+            none.rewrite_set_cookie("a=1").unwrap(),
+            "a=1; SameSite=None; Secure"
+        );
+    }
+
+    #[test]
+    fn test_strip_and_rename() {
+        let settings = settings(&[
+            ("strip", json!(["tracking"])),
+            ("rename", json!({ "JSESSIONID": "app_session" })),
+        ])
+        .unwrap();
+
+        assert!(settings.rewrite_set_cookie("tracking=1; Path=/").is_none());
+        assert_eq!(
+            settings
+                .rewrite_set_cookie("JSESSIONID=abc; Path=/")
+                .unwrap(),
+            "app_session=abc; Path=/"
+        );
+
+        assert_eq!(
+            settings
+                .rewrite_request_cookies("app_session=abc; tracking=1;theme=dark")
+                .unwrap(),
+            "JSESSIONID=abc; theme=dark"
+        );
+        assert!(settings.rewrite_request_cookies("tracking=1").is_none());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(settings(&[("same_site", json!("relaxed"))]).is_err());
+        assert!(settings(&[("secure", json!("yes"))]).is_err());
+        assert!(settings(&[("domain", json!(["example.com"]))]).is_err());
+        assert!(settings(&[("path", json!({ "api": "/api" }))]).is_err());
+        assert!(settings(&[("rename", json!({ "a": "" }))]).is_err());
+        assert!(settings(&[("strip", json!("a"))]).is_err());
+    }
+}
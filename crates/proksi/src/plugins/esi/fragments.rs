@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+
+use crate::metrics;
+
+/// HTTP client of the fragments. Redirects are not followed, they could leave the allowed hosts.
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+});
+
+/// Fragments cached in memory, by host and URL
+static FRAGMENTS: Lazy<papaya::HashMap<String, Fragment>> = Lazy::new(papaya::HashMap::new);
+
+/// Fragments cached at most, the expired ones are removed when it's reached
+const MAX_FRAGMENTS: usize = 10_000;
+
+#[derive(Clone)]
+struct Fragment {
+    body: Bytes,
+    expires_at: Instant,
+}
+
+/// A fragment of a page, fetched with the `Host` of the page when it's on the origin
+pub struct FragmentRequest {
+    pub url: Url,
+    pub host: Option<String>,
+    pub timeout: Duration,
+    /// Time the fragment is cached when its response has no `Cache-Control`
+    pub ttl: Duration,
+    pub max_size: usize,
+}
+
+impl FragmentRequest {
+    fn cache_key(&self) -> String {
+        format!("{} {}", self.host.as_deref().unwrap_or_default(), self.url)
+    }
+}
+
+/// The body of the fragment, from the cache or from its server. `page_host` labels the metrics.
+pub async fn fetch(request: &FragmentRequest, page_host: &str) -> Result<Bytes> {
+    let key = request.cache_key();
+    if let Some(fragment) = FRAGMENTS.pin().get(&key) {
+        if fragment.expires_at > Instant::now() {
+            count(page_host, "hit");
+            return Ok(fragment.body.clone());
+        }
+    }
+
+    let (body, ttl) = match fetch_from_server(request).await {
+        Ok(fetched) => fetched,
+        Err(err) => {
+            count(page_host, "error");
+            return Err(err);
+        }
+    };
+    count(page_host, "miss");
+
+    if let Some(ttl) = ttl.filter(|ttl| !ttl.is_zero()) {
+        let mut fragments = FRAGMENTS.pin();
+        if fragments.len() >= MAX_FRAGMENTS {
+            let now = Instant::now();
+            fragments.retain(|_, fragment| fragment.expires_at > now);
+        }
+        if fragments.len() < MAX_FRAGMENTS {
+            fragments.insert(
+                key,
+                Fragment {
+                    body: body.clone(),
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+        }
+    }
+
+    Ok(body)
+}
+
+/// The body of a successful response, and how long it can be cached
+async fn fetch_from_server(request: &FragmentRequest) -> Result<(Bytes, Option<Duration>)> {
+    let mut builder = HTTP_CLIENT
+        .get(request.url.clone())
+        .timeout(request.timeout);
+    if let Some(host) = request.host.as_deref() {
+        builder = builder.header(header::HOST, host);
+    }
+
+    let mut response = builder.send().await?;
+    if !response.status().is_success() {
+        bail!("{} answered with {}", request.url, response.status());
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > request.max_size {
+            return Err(anyhow!(
+                "{} is larger than {} bytes",
+                request.url,
+                request.max_size
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok((body.freeze(), cache_ttl(response.headers(), request.ttl)))
+}
+
+/// Time a fragment can be cached from its `Cache-Control` (`s-maxage` first), `None` when it
+/// mustn't be cached
+fn cache_ttl(headers: &HeaderMap, default: Duration) -> Option<Duration> {
+    let directives = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    if directives
+        .iter()
+        .any(|d| matches!(d.as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+
+    let max_age = |name: &str| {
+        directives.iter().find_map(|d| {
+            d.strip_prefix(name)?
+                .strip_prefix('=')?
+                .trim_matches('"')
+                .parse()
+                .ok()
+        })
+    };
+    match max_age("s-maxage").or_else(|| max_age("max-age")) {
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(default),
+    }
+}
+
+fn count(host: &str, result: &str) {
+    metrics::ESI_FRAGMENTS
+        .with_label_values(&[host, result])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(cache_control: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::CACHE_CONTROL, cache_control.parse().unwrap())])
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let default = Duration::from_secs(60);
+        assert_eq!(cache_ttl(&HeaderMap::new(), default), Some(default));
+        assert_eq!(
+            cache_ttl(&headers("public, max-age=300"), default),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            cache_ttl(&headers("max-age=300, s-maxage=30"), default),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            cache_ttl(&headers("max-age=0"), default),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(cache_ttl(&headers("private, max-age=300"), default), None);
+        assert_eq!(cache_ttl(&headers("no-store"), default), None);
+    }
+}
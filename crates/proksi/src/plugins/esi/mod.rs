@@ -0,0 +1,481 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, Method, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+use reqwest::Url;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task::JoinSet,
+};
+
+use crate::{
+    config::RoutePlugin,
+    proxy_server::{
+        compression::{self, has_content_type, Compressor},
+        https_proxy::RouterContext,
+    },
+    stores::routes::RouteStoreContainer,
+};
+
+use super::{response_rewrite::prepare_response, settings_cache::SettingsCache, MiddlewarePlugin};
+
+use fragments::FragmentRequest;
+use parser::Part;
+
+mod fragments;
+mod parser;
+
+/// Default maximum size of a page (and of a fragment), larger pages are sent unprocessed
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Default timeout (in seconds) of the fragment requests
+const DEFAULT_TIMEOUT: u64 = 5;
+
+/// Default time (in seconds) the fragments are cached, when their response has no `Cache-Control`
+const DEFAULT_TTL: u64 = 60;
+
+/// Includes processed in a page, the next ones are removed
+const MAX_INCLUDES: usize = 100;
+
+/// Per-route settings of the ESI plugin
+#[derive(Debug)]
+struct EsiSettings {
+    /// Server of the relative includes, the first upstream of the route by default
+    origin: Option<Url>,
+    /// Hosts of the includes with an absolute URL
+    allowed_hosts: Vec<String>,
+    max_body_size: usize,
+    timeout: Duration,
+    ttl: Duration,
+}
+
+impl EsiSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let number = |key: &str, default: u64| -> Result<u64> {
+            config.get(key).map_or(Ok(default), |v| {
+                v.as_u64()
+                    .filter(|v| *v > 0)
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+            })
+        };
+
+        let origin = match config.get("origin") {
+            Some(origin) => {
+                let origin = origin
+                    .as_str()
+                    .and_then(|v| Url::parse(v).ok())
+                    .ok_or_else(|| anyhow!("Missing or invalid origin"))?;
+                if !matches!(origin.scheme(), "http" | "https") {
+                    bail!("origin must be an http or https URL");
+                }
+                Some(origin)
+            }
+            None => None,
+        };
+
+        let allowed_hosts = match config.get("allowed_hosts") {
+            Some(values) => values
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid allowed_hosts"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(str::to_ascii_lowercase)
+                        .ok_or_else(|| anyhow!("Missing or invalid allowed_hosts"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        Ok(Self {
+            origin,
+            allowed_hosts,
+            max_body_size: usize::try_from(number("max_body_size", DEFAULT_MAX_BODY_SIZE as u64)?)?,
+            timeout: Duration::from_secs(number("timeout", DEFAULT_TIMEOUT)?),
+            ttl: Duration::from_secs(number("ttl", DEFAULT_TTL)?),
+        })
+    }
+}
+
+/// Whether the includes of the response are processed: successful HTML pages that aren't
+/// compressed
+pub fn is_processed(resp: &ResponseHeader) -> bool {
+    let identity = resp
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|v| v.as_bytes() == b"identity");
+
+    resp.status == StatusCode::OK && identity && has_content_type(resp, &["text/html"])
+}
+
+/// A page whose includes are replaced by their fragments, kept in the request context. The
+/// page is buffered, and its fragments fetched once it's complete.
+pub struct EsiPage {
+    settings: Arc<EsiSettings>,
+    /// The URL of the page on the origin, the relative includes are resolved from it
+    base: Option<Url>,
+    host: String,
+    body: Vec<u8>,
+    /// Set when the page is larger than `max_body_size`, it's then sent unprocessed
+    passthrough: bool,
+    /// The stitched page is compressed, the page itself isn't (it's cached uncompressed)
+    compressor: Option<Compressor>,
+}
+
+impl EsiPage {
+    fn new(settings: Arc<EsiSettings>) -> Self {
+        Self {
+            settings,
+            base: None,
+            host: String::new(),
+            body: vec![],
+            passthrough: false,
+            compressor: None,
+        }
+    }
+
+    /// Prepares the headers of a page whose includes are processed, returns `false` if the
+    /// response is sent as it is
+    pub fn prepare(
+        &mut self,
+        req: &RequestHeader,
+        resp: &mut ResponseHeader,
+        route: &RouteStoreContainer,
+        host: &str,
+    ) -> pingora::Result<bool> {
+        if !is_processed(resp) {
+            return Ok(false);
+        }
+
+        let origin = self.settings.origin.clone().or_else(|| {
+            let upstream = route.upstreams.first()?;
+            Url::parse(&format!("http://{}:{}", upstream.ip, upstream.port)).ok()
+        });
+        let path = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+        self.base = origin.and_then(|origin| origin.join(path).ok());
+        host.clone_into(&mut self.host);
+
+        // The length is checked before it's removed
+        let compressible = route
+            .compression
+            .as_ref()
+            .filter(|config| compression::is_compressible(req, resp, config));
+        prepare_response(resp)
+            .map_err(|_| pingora::Error::new_str("failed to prepare the page for its includes"))?;
+        resp.remove_header("surrogate-control");
+        // The validators of the page don't cover its fragments
+        resp.remove_header(&header::ETAG);
+        resp.remove_header(&header::LAST_MODIFIED);
+
+        if let Some(config) = compressible {
+            compression::add_vary_header(resp)?;
+            let accept_encoding = req.headers.get(header::ACCEPT_ENCODING);
+            if let Some(algorithm) = compression::negotiate(accept_encoding, &config.algorithms) {
+                self.compressor = compression::compress_response(resp, algorithm, config.level)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Buffers the page until it's complete, then sends it with its fragments
+    pub fn process(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        let data = body.take().unwrap_or_default();
+        let output = if self.passthrough {
+            data
+        } else {
+            self.body.extend_from_slice(&data);
+            if self.body.len() > self.settings.max_body_size {
+                tracing::warn!(
+                    "page of {} larger than {} bytes, its includes are not processed",
+                    self.host,
+                    self.settings.max_body_size
+                );
+                self.passthrough = true;
+                Bytes::from(std::mem::take(&mut self.body))
+            } else if end_of_stream {
+                let page = std::mem::take(&mut self.body);
+                self.stitch(&page)
+            } else {
+                // An empty chunk holds the page
+                Bytes::new()
+            }
+        };
+
+        *body = Some(match self.compressor.as_mut() {
+            Some(compressor) => compressor.encode(&output, end_of_stream)?,
+            None => output,
+        });
+        Ok(())
+    }
+
+    /// The page with its includes replaced by their fragments
+    fn stitch(&self, page: &[u8]) -> Bytes {
+        let parts = parser::parse(page);
+        let includes = parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Include { src, alt } => Some((
+                    self.resolve(src),
+                    alt.as_deref().map(|alt| self.resolve(alt)),
+                )),
+                Part::Text(_) => None,
+            })
+            .take(MAX_INCLUDES)
+            .collect::<Vec<_>>();
+
+        let mut fragments = self.fetch_all(includes).into_iter();
+        let mut stitched = Vec::with_capacity(page.len());
+        for part in parts {
+            match part {
+                Part::Text(text) => stitched.extend_from_slice(text),
+                Part::Include { .. } => stitched.extend(fragments.next().unwrap_or_default()),
+            }
+        }
+        Bytes::from(stitched)
+    }
+
+    /// Fetches the fragments of the includes (with their `alt` if they fail) concurrently.
+    /// Response body filters can't wait, the thread blocks until the fragments are fetched
+    /// while the other requests are moved to another thread.
+    fn fetch_all(
+        &self,
+        includes: Vec<(Result<FragmentRequest>, Option<Result<FragmentRequest>>)>,
+    ) -> Vec<Bytes> {
+        let count = includes.len();
+        if count == 0 {
+            return vec![];
+        }
+
+        let handle = Handle::try_current()
+            .ok()
+            .filter(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread);
+        let Some(handle) = handle else {
+            tracing::warn!(
+                "the includes of {} are removed, the runtime can't wait for them",
+                self.host
+            );
+            return vec![Bytes::new(); count];
+        };
+
+        let host = self.host.clone();
+        tokio::task::block_in_place(|| {
+            handle.block_on(async move {
+                let mut requests = JoinSet::new();
+                for (index, (src, alt)) in includes.into_iter().enumerate() {
+                    let host = host.clone();
+                    requests.spawn(async move { (index, fetch_include(src, alt, &host).await) });
+                }
+
+                let mut fragments = vec![Bytes::new(); count];
+                while let Some(result) = requests.join_next().await {
+                    if let Ok((index, fragment)) = result {
+                        fragments[index] = fragment;
+                    }
+                }
+                fragments
+            })
+        })
+    }
+
+    /// The request of an include: relative URLs are fetched from the origin (with the `Host`
+    /// of the page), absolute URLs only from the allowed hosts
+    fn resolve(&self, src: &str) -> Result<FragmentRequest> {
+        let url = match Url::parse(src) {
+            Ok(url) => {
+                let allowed = matches!(url.scheme(), "http" | "https")
+                    && url
+                        .host_str()
+                        .is_some_and(|host| self.settings.allowed_hosts.iter().any(|h| h == host));
+                if !allowed {
+                    bail!("{src} is not on an allowed host");
+                }
+                url
+            }
+            Err(_) => self
+                .base
+                .as_ref()
+                .ok_or_else(|| anyhow!("no origin to fetch {src} from"))?
+                .join(src)?,
+        };
+
+        let on_origin = self
+            .base
+            .as_ref()
+            .is_some_and(|base| base.origin() == url.origin());
+        Ok(FragmentRequest {
+            url,
+            host: on_origin.then(|| self.host.clone()),
+            timeout: self.settings.timeout,
+            ttl: self.settings.ttl,
+            max_size: self.settings.max_body_size,
+        })
+    }
+}
+
+/// The fragment of an include, its `alt` when it fails, and nothing when both fail
+async fn fetch_include(
+    src: Result<FragmentRequest>,
+    alt: Option<Result<FragmentRequest>>,
+    host: &str,
+) -> Bytes {
+    for request in std::iter::once(src).chain(alt) {
+        let fragment = match request {
+            Ok(request) => fragments::fetch(&request, host).await,
+            Err(err) => Err(err),
+        };
+        match fragment {
+            Ok(fragment) => return fragment,
+            Err(err) => tracing::warn!("failed to fetch an include of {host}: {err}"),
+        }
+    }
+    Bytes::new()
+}
+
+/// Edge-Side Includes: the `<esi:include>` tags of the HTML pages of a route are replaced by
+/// their fragments, fetched (and cached separately) for each response
+pub struct Esi {
+    settings: SettingsCache<EsiSettings>,
+}
+
+impl Esi {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Esi {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Streams are sent as they are received
+        if session.req_header().method != Method::GET || ctx.streaming {
+            return Ok(false);
+        }
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+        match self.settings.get_or_try_build(
+            ctx.route_container.id,
+            config,
+            EsiSettings::from_config,
+        ) {
+            Ok(settings) => ctx.esi = Some(EsiPage::new(settings)),
+            Err(err) => tracing::error!("invalid esi plugin configuration: {err}"),
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        // Compressed pages can't be processed (the stitched pages are still compressed)
+        if ctx.esi.is_some() {
+            upstream_request.remove_header(&header::ACCEPT_ENCODING);
+        }
+        Ok(())
+    }
+
+    // The page is prepared by the router once its headers are known (even from the cache)
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn page(config: serde_json::Value) -> EsiPage {
+        let config = serde_json::from_value(config).unwrap();
+        let mut page = EsiPage::new(Arc::new(EsiSettings::from_config(&config).unwrap()));
+        page.base = Url::parse("http://10.0.0.1:3000/blog/post?id=1").ok();
+        page.host = "example.com".to_string();
+        page
+    }
+
+    #[test]
+    fn test_settings() {
+        let config = |value: serde_json::Value| {
+            EsiSettings::from_config(&serde_json::from_value(value).unwrap())
+        };
+
+        let settings = config(json!({})).unwrap();
+        assert_eq!(settings.origin, None);
+        assert_eq!(settings.timeout, Duration::from_secs(DEFAULT_TIMEOUT));
+        assert!(config(json!({ "origin": "ftp://example.com" })).is_err());
+        assert!(config(json!({ "timeout": 0 })).is_err());
+        assert!(config(json!({ "allowed_hosts": "cdn.example.com" })).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let page = page(json!({ "allowed_hosts": ["fragments.example.com"] }));
+
+        let request = page.resolve("/fragments/header").unwrap();
+        assert_eq!(
+            request.url.as_str(),
+            "http://10.0.0.1:3000/fragments/header"
+        );
+        assert_eq!(request.host.as_deref(), Some("example.com"));
+
+        let request = page.resolve("comments").unwrap();
+        assert_eq!(request.url.as_str(), "http://10.0.0.1:3000/blog/comments");
+
+        let request = page
+            .resolve("https://fragments.example.com/footer")
+            .unwrap();
+        assert_eq!(request.host, None);
+
+        assert!(page.resolve("https://internal.example.com/admin").is_err());
+        assert!(page.resolve("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_large_pages_are_not_processed() {
+        let mut page = page(json!({ "max_body_size": 16 }));
+        let mut body = Some(Bytes::from_static(b"<html><esi:include"));
+        page.process(&mut body, false).unwrap();
+        assert_eq!(body.as_deref(), Some(&b"<html><esi:include"[..]));
+
+        let mut body = Some(Bytes::from_static(b" src=\"/a\"/></html>"));
+        page.process(&mut body, true).unwrap();
+        assert_eq!(body.as_deref(), Some(&b" src=\"/a\"/></html>"[..]));
+    }
+}
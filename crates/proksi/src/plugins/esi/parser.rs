@@ -0,0 +1,103 @@
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+
+/// `<esi:include src="..." />` tags (or `<esi:include ...></esi:include>`) and
+/// `<esi:remove>` blocks, the content shown by the servers that don't process ESI
+static ESI_TAG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?s)<esi:remove>.*?</esi:remove>|<esi:include\b([^>]*?)/?>(?:\s*</esi:include>)?")
+        .unwrap()
+});
+
+/// Attributes of a tag, with double or single quotes
+static ATTRIBUTE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"([a-z]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// A part of a page: text sent as it is, or a fragment included in its place
+#[derive(Debug, PartialEq)]
+pub enum Part<'a> {
+    Text(&'a [u8]),
+    Include { src: String, alt: Option<String> },
+}
+
+/// Splits a page into its text and its includes, `<esi:remove>` blocks and includes without
+/// a `src` are dropped
+pub fn parse(page: &[u8]) -> Vec<Part<'_>> {
+    let mut parts = vec![];
+    let mut start = 0;
+
+    for captures in ESI_TAG.captures_iter(page) {
+        let tag = captures.get(0).unwrap();
+        if tag.start() > start {
+            parts.push(Part::Text(&page[start..tag.start()]));
+        }
+        start = tag.end();
+
+        let Some(attributes) = captures.get(1) else {
+            continue;
+        };
+        let (mut src, mut alt) = (None, None);
+        for attribute in ATTRIBUTE.captures_iter(attributes.as_bytes()) {
+            let value = attribute
+                .get(2)
+                .or(attribute.get(3))
+                .map(|v| unescape(v.as_bytes()));
+            match &attribute[1] {
+                b"src" => src = value,
+                b"alt" => alt = value,
+                _ => {}
+            }
+        }
+
+        if let Some(src) = src.filter(|src| !src.is_empty()) {
+            parts.push(Part::Include { src, alt });
+        }
+    }
+
+    if start < page.len() {
+        parts.push(Part::Text(&page[start..]));
+    }
+    parts
+}
+
+/// Attribute values are HTML, `&amp;` separates the parameters of the URLs
+fn unescape(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let page = br#"<header><esi:include src="/fragments/header?user=1&amp;lang=en" /></header>
+<esi:remove><a href="/cart">Cart</a></esi:remove><main>Hello</main>
+<esi:include src='/fragments/footer' alt="/footer.html"></esi:include><esi:include alt="/none"/>"#;
+
+        assert_eq!(
+            parse(page),
+            vec![
+                Part::Text(b"<header>"),
+                Part::Include {
+                    src: "/fragments/header?user=1&lang=en".to_string(),
+                    alt: None,
+                },
+                Part::Text(b"</header>\n"),
+                Part::Text(b"<main>Hello</main>\n"),
+                Part::Include {
+                    src: "/fragments/footer".to_string(),
+                    alt: Some("/footer.html".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_without_tags() {
+        assert_eq!(
+            parse(b"<p>esi:include</p>"),
+            vec![Part::Text(b"<p>esi:include</p>")]
+        );
+        assert!(parse(b"").is_empty());
+    }
+}
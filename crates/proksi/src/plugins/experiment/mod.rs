@@ -0,0 +1,477 @@
+use std::{borrow::Cow, collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cookie::{Cookie, SameSite};
+use http::{header, HeaderName, HeaderValue};
+use openssl::sha::sha256;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{get_required_config, settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Keeps the assignment for 30 days by default
+const DEFAULT_MAX_AGE: u64 = 30 * 24 * 3600;
+const DEFAULT_HEADER: &str = "x-experiment-variant";
+
+/// A variant of the experiment, with the requests it receives out of the total weight
+#[derive(Debug)]
+struct Variant {
+    name: String,
+    weight: u64,
+    /// Headers added to the upstream requests of the variant
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Upstream of the route receiving the requests of the variant, while it's ready
+    upstream: Option<String>,
+}
+
+/// What identifies a client when it has no assignment yet
+#[derive(Debug)]
+enum Identifier {
+    Cookie(String),
+    Header(HeaderName),
+}
+
+/// Per-route settings of the experiment plugin
+#[derive(Debug)]
+struct ExperimentSettings {
+    name: String,
+    variants: Vec<Variant>,
+    /// Cookie keeping the variant of the client
+    cookie: String,
+    max_age: u64,
+    by: Option<Identifier>,
+    /// Header sending the variant to the upstream
+    header: HeaderName,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+impl Variant {
+    fn from_config(value: &serde_json::Value) -> Result<Self> {
+        let name = value
+            .get("name")
+            .and_then(|v| v.as_str())
+            .filter(|name| is_valid_name(name))
+            .ok_or_else(|| anyhow!("Missing or invalid variants.name"))?;
+
+        let weight = match value.get("weight") {
+            Some(weight) => weight
+                .as_u64()
+                .ok_or_else(|| anyhow!("Missing or invalid variants.weight"))?,
+            None => 1,
+        };
+
+        let headers = match value.get("headers") {
+            Some(headers) => headers
+                .as_object()
+                .ok_or_else(|| anyhow!("Missing or invalid variants.headers"))?
+                .iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Missing or invalid variants.headers"))?;
+                    Ok((HeaderName::from_str(name)?, HeaderValue::from_str(value)?))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        let upstream = value
+            .get("upstream")
+            .map(|v| {
+                v.as_str()
+                    .and_then(|address| address.parse::<SocketAddr>().ok())
+                    .map(|address| address.to_string())
+                    .ok_or_else(|| anyhow!("Missing or invalid variants.upstream"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            name: name.to_string(),
+            weight,
+            headers,
+            upstream,
+        })
+    }
+}
+
+impl ExperimentSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let name = get_required_config(config, "name")?;
+        if !is_valid_name(&name) {
+            bail!("Missing or invalid name");
+        }
+
+        let variants = config
+            .get("variants")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| anyhow!("Missing or invalid variants"))?
+            .iter()
+            .map(Variant::from_config)
+            .collect::<Result<Vec<_>>>()?;
+        if variants.iter().map(|v| v.weight).sum::<u64>() == 0 {
+            bail!("variants must have a weight greater than 0");
+        }
+        for (index, variant) in variants.iter().enumerate() {
+            if variants[..index].iter().any(|v| v.name == variant.name) {
+                bail!("variant {} is defined twice", variant.name);
+            }
+        }
+
+        let cookie = match config.get("cookie") {
+            Some(cookie) => cookie
+                .as_str()
+                .filter(|cookie| is_valid_name(cookie))
+                .map(ToString::to_string)
+                .ok_or_else(|| anyhow!("Missing or invalid cookie"))?,
+            None => format!("proksi_experiment_{name}"),
+        };
+
+        let max_age = match config.get("max_age") {
+            Some(max_age) => max_age
+                .as_u64()
+                .ok_or_else(|| anyhow!("Missing or invalid max_age"))?,
+            None => DEFAULT_MAX_AGE,
+        };
+
+        let by = match (config.get("by_cookie"), config.get("by_header")) {
+            (Some(_), Some(_)) => bail!("only one of by_cookie and by_header can be set"),
+            (Some(cookie), None) => Some(Identifier::Cookie(
+                cookie
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid by_cookie"))?
+                    .to_string(),
+            )),
+            (None, Some(name)) => Some(Identifier::Header(HeaderName::from_str(
+                name.as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid by_header"))?,
+            )?)),
+            (None, None) => None,
+        };
+
+        let header = match config.get("header") {
+            Some(header) => HeaderName::from_str(
+                header
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid header"))?,
+            )?,
+            None => HeaderName::from_static(DEFAULT_HEADER),
+        };
+
+        Ok(Self {
+            name,
+            variants,
+            cookie,
+            max_age,
+            by,
+            header,
+        })
+    }
+
+    fn variant(&self, name: &str) -> Option<&Variant> {
+        self.variants.iter().find(|v| v.name == name)
+    }
+
+    /// The variant of the request, and whether it was just assigned. Clients keep the
+    /// variant of their cookie, the others get the variant of their identifier (the
+    /// same on every instance), or a random one without identifier.
+    fn assign(&self, req: &RequestHeader) -> (&Variant, bool) {
+        let assigned = request_cookie(req, &self.cookie)
+            .and_then(|name| self.variant(&name))
+            .filter(|variant| variant.weight > 0);
+        if let Some(variant) = assigned {
+            return (variant, false);
+        }
+
+        let id = match self.by.as_ref() {
+            Some(Identifier::Cookie(name)) => request_cookie(req, name),
+            Some(Identifier::Header(name)) => req
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.is_empty())
+                .map(ToString::to_string),
+            None => None,
+        };
+        let id = id.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+
+        // Each experiment splits the clients on its own
+        let digest = sha256(format!("{}:{id}", self.name).as_bytes());
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&digest[..8]);
+        let total = self.variants.iter().map(|v| v.weight).sum::<u64>();
+        let mut bucket = u64::from_be_bytes(hash) % total;
+
+        for variant in &self.variants {
+            if bucket < variant.weight {
+                return (variant, true);
+            }
+            bucket -= variant.weight;
+        }
+        // Not reached, the buckets are below the total weight
+        (&self.variants[0], true)
+    }
+
+    fn set_cookie(&self, variant: &str) -> String {
+        Cookie::build((self.cookie.clone(), variant.to_string()))
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(cookie::time::Duration::seconds(
+                i64::try_from(self.max_age).unwrap_or(i64::MAX),
+            ))
+            .build()
+            .to_string()
+    }
+}
+
+/// Value of the cookie `name` of the request
+fn request_cookie(req: &RequestHeader, name: &str) -> Option<String> {
+    req.headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// Assigns the clients to the variants of an A/B experiment, sends the variant to the
+/// upstream (header, upstream of the variant) and tags the logs and metrics with it
+pub struct Experiment {
+    settings: SettingsCache<ExperimentSettings>,
+}
+
+impl Experiment {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    fn get_settings(&self, ctx: &RouterContext) -> Result<Option<Arc<ExperimentSettings>>> {
+        let Some(plugin) = ctx.route_container.plugins.get("experiment") else {
+            return Ok(None);
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        self.settings
+            .get_or_try_build(
+                ctx.route_container.id,
+                config,
+                ExperimentSettings::from_config,
+            )
+            .inspect_err(|err| {
+                tracing::error!("invalid experiment plugin configuration: {err}");
+            })
+            .map(Some)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Experiment {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        // Requests of misconfigured experiments are still served, without a variant
+        let Some(settings) = self.get_settings(ctx)? else {
+            return Ok(false);
+        };
+
+        let (variant, is_new) = settings.assign(session.req_header());
+        ctx.extensions
+            .insert(Cow::Borrowed("experiment"), settings.name.clone());
+        ctx.extensions
+            .insert(Cow::Borrowed("experiment_variant"), variant.name.clone());
+        if let Some(upstream) = variant.upstream.as_ref() {
+            ctx.extensions
+                .insert(Cow::Borrowed("experiment_upstream"), upstream.clone());
+        }
+        if is_new {
+            ctx.extensions.insert(
+                Cow::Borrowed("experiment_cookie"),
+                settings.set_cookie(&variant.name),
+            );
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(settings) = self.get_settings(ctx)? else {
+            return Ok(());
+        };
+        let Some(variant) = ctx
+            .extensions
+            .get("experiment_variant")
+            .and_then(|name| settings.variant(name))
+        else {
+            return Ok(());
+        };
+
+        upstream_request.insert_header(settings.header.clone(), &variant.name)?;
+        for (name, value) in &variant.headers {
+            upstream_request.insert_header(name.clone(), value.clone())?;
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        if let Some(cookie) = ctx.extensions.get("experiment_cookie") {
+            upstream_response.append_header(header::SET_COOKIE, cookie)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<ExperimentSettings> {
+        let mut config: HashMap<Cow<'static, str>, serde_json::Value> = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        config
+            .entry(Cow::Borrowed("name"))
+            .or_insert_with(|| json!("checkout"));
+        config.entry(Cow::Borrowed("variants")).or_insert_with(|| {
+            json!([
+                { "name": "control", "weight": 50 },
+                {
+                    "name": "one_page",
+                    "weight": 50,
+                    "headers": { "x-checkout": "one-page" },
+                    "upstream": "10.0.0.5:3000"
+                }
+            ])
+        });
+        ExperimentSettings::from_config(&config)
+    }
+
+    fn request(headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_assign() {
+        let settings = settings(&[]).unwrap();
+        assert_eq!(settings.cookie, "proksi_experiment_checkout");
+        assert_eq!(
+            settings.variants[1].upstream.as_deref(),
+            Some("10.0.0.5:3000")
+        );
+
+        // The split follows the weights
+        let one_page = (0..1000)
+            .filter(|_| settings.assign(&request(&[])).0.name == "one_page")
+            .count();
+        assert!((400..600).contains(&one_page), "{one_page} one_page");
+
+        // Clients keep the variant of their cookie
+        let (variant, is_new) = settings.assign(&request(&[(
+            "cookie",
+            "a=b; proksi_experiment_checkout=one_page",
+        )]));
+        assert_eq!(variant.name, "one_page");
+        assert!(!is_new);
+
+        // Unknown variants get a new assignment
+        let (_, is_new) = settings.assign(&request(&[(
+            "cookie",
+            "proksi_experiment_checkout=removed",
+        )]));
+        assert!(is_new);
+
+        let set_cookie = settings.set_cookie("control");
+        assert!(set_cookie.starts_with("proksi_experiment_checkout=control"));
+        assert!(set_cookie.contains("Max-Age=2592000"));
+    }
+
+    #[test]
+    fn test_assign_by_identifier() {
+        let by_header = settings(&[("by_header", json!("x-user-id"))]).unwrap();
+        let req = request(&[("x-user-id", "42")]);
+        let (first, is_new) = by_header.assign(&req);
+        assert!(is_new);
+        assert!((0..10).all(|_| by_header.assign(&req).0.name == first.name));
+
+        // Both variants are reached by some users
+        let variants = (0..100)
+            .map(|id| {
+                let req = request(&[("x-user-id", &id.to_string())]);
+                by_header.assign(&req).0.name.clone()
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(variants.len(), 2);
+
+        let by_cookie = settings(&[("by_cookie", json!("uid"))]).unwrap();
+        let req = request(&[("cookie", "uid=abc")]);
+        let first = by_cookie.assign(&req).0.name.clone();
+        assert!((0..10).all(|_| by_cookie.assign(&req).0.name == first));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(settings(&[("name", json!("with space"))]).is_err());
+        assert!(settings(&[("variants", json!([]))]).is_err());
+        assert!(settings(&[("variants", json!([{ "name": "a", "weight": 0 }]))]).is_err());
+        assert!(settings(&[("variants", json!([{ "name": "a" }, { "name": "a" }]))]).is_err());
+        assert!(settings(&[(
+            "variants",
+            json!([{ "name": "a", "upstream": "not-an-address" }])
+        )])
+        .is_err());
+        assert!(settings(&[
+            ("by_cookie", json!("uid")),
+            ("by_header", json!("x-user-id"))
+        ])
+        .is_err());
+    }
+}
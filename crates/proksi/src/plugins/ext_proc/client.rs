@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h2::{
+    client::{ResponseFuture, SendRequest},
+    RecvStream, SendStream,
+};
+use http::{header, Method, Request};
+use tokio::{net::TcpStream, sync::Mutex};
+
+use super::proto::ProcessingResponse;
+
+/// gRPC method of the ext_proc service
+const PROCESS_PATH: &str = "/envoy.service.ext_proc.v3.ExternalProcessor/Process";
+
+/// Size of the gRPC message prefix (compression flag and length)
+const MESSAGE_PREFIX_SIZE: usize = 5;
+
+/// HTTP/2 (cleartext) connection to an external processor, shared by the requests
+pub(super) struct ExtProcClient {
+    address: String,
+    connection: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl ExtProcClient {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Returns a handle to the connection, connecting again if it was closed
+    async fn sender(&self) -> Result<SendRequest<Bytes>> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(sender) = connection.clone() {
+            if let Ok(sender) = sender.ready().await {
+                return Ok(sender);
+            }
+        }
+
+        let stream = TcpStream::connect(&self.address).await?;
+        stream.set_nodelay(true)?;
+
+        let (sender, driver) = h2::client::handshake(stream).await?;
+        let address = self.address.clone();
+        tokio::spawn(async move {
+            if let Err(err) = driver.await {
+                tracing::debug!("connection to external processor {address} closed: {err}");
+            }
+        });
+
+        *connection = Some(sender.clone());
+        Ok(sender.ready().await?)
+    }
+
+    /// Opens the stream used to process a request
+    pub async fn open_stream(&self) -> Result<ProcessingStream> {
+        let mut sender = self.sender().await?;
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}{PROCESS_PATH}", self.address))
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header(header::TE, "trailers")
+            .body(())?;
+
+        let (response, send) = sender.send_request(request, false)?;
+
+        Ok(ProcessingStream {
+            send,
+            response: Some(response),
+            recv: None,
+            buffer: BytesMut::new(),
+        })
+    }
+}
+
+/// Bidirectional stream of a request: one response is expected for each message sent
+pub(super) struct ProcessingStream {
+    send: SendStream<Bytes>,
+    /// The response headers are only awaited when reading the first message
+    response: Option<ResponseFuture>,
+    recv: Option<RecvStream>,
+    buffer: BytesMut,
+}
+
+impl ProcessingStream {
+    pub async fn exchange(&mut self, message: Vec<u8>) -> Result<ProcessingResponse> {
+        let mut framed = BytesMut::with_capacity(MESSAGE_PREFIX_SIZE + message.len());
+        framed.put_u8(0);
+        framed.put_u32(u32::try_from(message.len())?);
+        framed.put_slice(&message);
+        self.send.send_data(framed.freeze(), false)?;
+
+        let response = self.receive().await?;
+        ProcessingResponse::decode(&response)
+    }
+
+    async fn receive(&mut self) -> Result<Bytes> {
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(message);
+            }
+
+            let recv = match self.recv.as_mut() {
+                Some(recv) => recv,
+                None => {
+                    let response = self
+                        .response
+                        .take()
+                        .ok_or_else(|| anyhow!("stream already closed"))?
+                        .await?;
+
+                    if !response.status().is_success() {
+                        bail!("external processor returned {}", response.status());
+                    }
+
+                    self.recv.insert(response.into_body())
+                }
+            };
+
+            match recv.data().await {
+                Some(data) => {
+                    let data = data?;
+                    recv.flow_control().release_capacity(data.len())?;
+                    self.buffer.extend_from_slice(&data);
+                }
+                None => {
+                    let status = recv
+                        .trailers()
+                        .await?
+                        .and_then(|trailers| trailers.get("grpc-message").cloned());
+                    bail!("stream closed by the external processor ({status:?})");
+                }
+            }
+        }
+    }
+
+    /// Returns the next complete gRPC message, if any
+    fn next_message(&mut self) -> Result<Option<Bytes>> {
+        if self.buffer.len() < MESSAGE_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        if self.buffer[0] != 0 {
+            bail!("compressed messages are not supported");
+        }
+
+        let length = usize::try_from(u32::from_be_bytes([
+            self.buffer[1],
+            self.buffer[2],
+            self.buffer[3],
+            self.buffer[4],
+        ]))?;
+
+        if self.buffer.len() < MESSAGE_PREFIX_SIZE + length {
+            return Ok(None);
+        }
+
+        self.buffer.advance(MESSAGE_PREFIX_SIZE);
+        Ok(Some(self.buffer.split_to(length).freeze()))
+    }
+}
+
+impl Drop for ProcessingStream {
+    // Once the request is done the stream is closed, as Envoy does
+    fn drop(&mut self) {
+        self.send.send_data(Bytes::new(), true).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Processor answering each message with an immediate response (status 403)
+    async fn start_processor() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(stream).await.unwrap();
+
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                assert_eq!(request.uri().path(), PROCESS_PATH);
+
+                tokio::spawn(async move {
+                    let response = http::Response::builder().status(200).body(()).unwrap();
+                    let mut send = respond.send_response(response, false).unwrap();
+                    let mut body = request.into_body();
+
+                    while let Some(Ok(data)) = body.data().await {
+                        body.flow_control().release_capacity(data.len()).unwrap();
+                        // immediate_response { status { code: 403 } }, split in three frames
+                        send.send_data(Bytes::from_static(&[0, 0, 0, 0, 7, 0x3a]), false)
+                            .unwrap();
+                        send.send_data(Bytes::from_static(&[5, 0x0a, 3, 0x08, 0x93]), false)
+                            .unwrap();
+                        send.send_data(Bytes::from_static(&[0x03]), false).unwrap();
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let client = ExtProcClient::new(start_processor().await);
+
+        for _ in 0..2 {
+            let mut stream = client.open_stream().await.unwrap();
+            for _ in 0..2 {
+                let response = stream.exchange(vec![0x12, 0x00]).await.unwrap();
+                let ProcessingResponse::ImmediateResponse(response) = response else {
+                    panic!("unexpected response {response:?}");
+                };
+                assert_eq!(response.status, 403);
+            }
+        }
+    }
+}
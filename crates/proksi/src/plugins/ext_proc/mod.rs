@@ -0,0 +1,586 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+    ErrorType::HTTPStatus,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, settings_cache::SettingsCache, MiddlewarePlugin};
+
+mod client;
+mod proto;
+
+use client::{ExtProcClient, ProcessingStream};
+use proto::{AppendAction, BodyMutation, HeaderMutation, ImmediateResponse, ProcessingResponse};
+
+/// Default timeout of each message sent to the external processor
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Default maximum size of the buffered request body
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Per-route settings of the external processing plugin
+struct ExtProcSettings {
+    client: ExtProcClient,
+    timeout: Duration,
+    /// Requests are proxied as they are when the processor fails (instead of a 500)
+    fail_open: bool,
+    request_headers: bool,
+    response_headers: bool,
+    request_body: bool,
+    max_body_size: usize,
+}
+
+impl ExtProcSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let address = config
+            .get("address")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Missing or invalid address"))?;
+
+        let fail_open = match config.get("failure_mode").map(|v| v.as_str()) {
+            None | Some(Some("closed")) => false,
+            Some(Some("open")) => true,
+            _ => bail!("Missing or invalid failure_mode"),
+        };
+
+        let bool_option = |key: &str, default: bool| -> Result<bool> {
+            config.get(key).map_or(Ok(default), |v| {
+                v.as_bool()
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+            })
+        };
+
+        let timeout = config
+            .get("timeout")
+            .map(|v| {
+                v.as_u64()
+                    .filter(|v| *v > 0)
+                    .map(Duration::from_millis)
+                    .ok_or_else(|| anyhow!("Missing or invalid timeout"))
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        Ok(Self {
+            client: ExtProcClient::new(address.to_string()),
+            timeout,
+            fail_open,
+            request_headers: bool_option("request_headers", true)?,
+            response_headers: bool_option("response_headers", false)?,
+            request_body: bool_option("request_body", false)?,
+            max_body_size: usize::try_from(
+                config
+                    .get("max_body_size")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            )?,
+        })
+    }
+
+    /// Logs a failure of the processor, returning `true` if the request can continue
+    fn log_failure(&self, phase: &str, err: &anyhow::Error) -> bool {
+        if self.fail_open {
+            tracing::warn!("external processing of the {phase} failed, skipping it: {err}");
+        } else {
+            tracing::error!("external processing of the {phase} failed: {err}");
+        }
+
+        self.fail_open
+    }
+}
+
+/// Headers that can be changed by the external processor
+trait MutableHeaders {
+    fn headers(&self) -> &HeaderMap;
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()>;
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+impl MutableHeaders for RequestHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl MutableHeaders for ResponseHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+/// Applies the header mutation of the processor. Pseudo-headers (e.g. `:path`) can't be changed.
+fn apply_header_mutation(
+    target: &mut impl MutableHeaders,
+    mutation: &HeaderMutation,
+) -> Result<()> {
+    for name in &mutation.remove {
+        if !name.starts_with(':') {
+            target.remove(&HeaderName::try_from(name.as_str())?);
+        }
+    }
+
+    for (name, value, action) in &mutation.set {
+        if name.starts_with(':') {
+            continue;
+        }
+
+        let name = HeaderName::try_from(name.as_str())?;
+        let value = HeaderValue::from_bytes(value)?;
+        let exists = target.headers().contains_key(&name);
+
+        match action {
+            AppendAction::Append => target.append(name, value)?,
+            AppendAction::AddIfAbsent if exists => {}
+            AppendAction::OverwriteIfExists if !exists => {}
+            _ => target.insert(name, value)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn request_headers_message(req: &RequestHeader, host: &str, end_of_stream: bool) -> Vec<u8> {
+    let path = req.uri.path_and_query().map_or("/", |v| v.as_str());
+
+    // Requests reaching the router are always received over TLS
+    let pseudo_headers = [
+        (":method", req.method.as_str().as_bytes()),
+        (":scheme", b"https".as_slice()),
+        (":authority", host.as_bytes()),
+        (":path", path.as_bytes()),
+    ];
+
+    let headers = req
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()));
+
+    proto::encode_request_headers(pseudo_headers.into_iter().chain(headers), end_of_stream)
+}
+
+fn response_headers_message(resp: &ResponseHeader) -> Vec<u8> {
+    let status = resp.status.as_u16().to_string();
+
+    let headers = resp
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()));
+
+    proto::encode_response_headers(
+        [(":status", status.as_bytes())].into_iter().chain(headers),
+        false,
+    )
+}
+
+/// Requests without these headers have no body to process
+fn has_body(req: &RequestHeader) -> bool {
+    let content_length = req
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+    content_length.is_some_and(|length| length > 0)
+        || req.headers.contains_key(header::TRANSFER_ENCODING)
+}
+
+/// Processing of a request after its headers (the request body and the response headers)
+pub struct ExtProcessor {
+    settings: Arc<ExtProcSettings>,
+    stream: ProcessingStream,
+    /// The request body is only sent once complete
+    body: Vec<u8>,
+    /// Set once the processor failed in `failure_mode = "open"`, the request is then proxied as is
+    skipped: bool,
+}
+
+impl ExtProcessor {
+    async fn exchange(&mut self, message: Vec<u8>) -> Result<ProcessingResponse> {
+        tokio::time::timeout(self.settings.timeout, self.stream.exchange(message))
+            .await
+            .map_err(|_| anyhow!("timed out"))?
+    }
+
+    /// Returns the error to send to the client, or `None` if the request can continue
+    fn handle_failure(&mut self, phase: &str, err: &anyhow::Error) -> Option<Box<pingora::Error>> {
+        if self.settings.log_failure(phase, err) {
+            self.skipped = true;
+            return None;
+        }
+
+        Some(pingora::Error::explain(
+            HTTPStatus(StatusCode::INTERNAL_SERVER_ERROR.as_u16()),
+            "external processing failed",
+        ))
+    }
+
+    /// Whether the upstream request body can be changed (it's then sent chunked)
+    pub fn processes_request_body(&self) -> bool {
+        self.settings.request_body && !self.skipped
+    }
+
+    /// Buffers the request body, sending it to the processor once complete
+    pub async fn process_request_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if !self.processes_request_body() {
+            return Ok(());
+        }
+
+        if let Some(data) = body.take() {
+            self.body.extend_from_slice(&data);
+        }
+
+        if self.body.len() > self.settings.max_body_size {
+            return Err(pingora::Error::explain(
+                HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE.as_u16()),
+                "request body too large for external processing",
+            ));
+        }
+
+        if !end_of_stream {
+            // An empty chunk holds the body, `None` would end it
+            *body = Some(Bytes::new());
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.body);
+        let message = proto::encode_request_body(&data, true);
+
+        match self.exchange(message).await {
+            Ok(ProcessingResponse::RequestBody(response)) => {
+                *body = Some(match response.body_mutation {
+                    Some(BodyMutation::Replace(replaced)) => Bytes::from(replaced),
+                    Some(BodyMutation::Clear) => Bytes::new(),
+                    None => Bytes::from(data),
+                });
+                Ok(())
+            }
+            Ok(ProcessingResponse::ImmediateResponse(response)) => Err(pingora::Error::explain(
+                HTTPStatus(response.status),
+                "rejected by the external processor",
+            )),
+            result => {
+                let err = unexpected_response(result);
+                match self.handle_failure("request body", &err) {
+                    Some(err) => Err(err),
+                    None => {
+                        *body = Some(Bytes::from(data));
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the response headers to the processor, applying its mutations
+    pub async fn process_response_headers(
+        &mut self,
+        resp: &mut ResponseHeader,
+    ) -> pingora::Result<()> {
+        if !self.settings.response_headers || self.skipped {
+            return Ok(());
+        }
+
+        let message = response_headers_message(resp);
+
+        let result = match self.exchange(message).await {
+            Ok(ProcessingResponse::ResponseHeaders(response)) => {
+                apply_header_mutation(resp, &response.header_mutation)
+            }
+            // Only the status can be replaced, as the response body comes from the upstream
+            Ok(ProcessingResponse::ImmediateResponse(response)) => {
+                return Err(pingora::Error::explain(
+                    HTTPStatus(response.status),
+                    "rejected by the external processor",
+                ))
+            }
+            result => Err(unexpected_response(result)),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => self
+                .handle_failure("response headers", &err)
+                .map_or(Ok(()), Err),
+        }
+    }
+}
+
+fn unexpected_response(result: Result<ProcessingResponse>) -> anyhow::Error {
+    match result {
+        Ok(response) => anyhow!("unexpected response {response:?}"),
+        Err(err) => err,
+    }
+}
+
+/// Sends requests (and optionally responses) to an external gRPC service implementing
+/// Envoy's `ExternalProcessor` API, which can mutate or reject them
+pub struct ExtProc {
+    settings: SettingsCache<ExtProcSettings>,
+}
+
+impl ExtProc {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    async fn respond_immediately(
+        session: &mut Session,
+        response: ImmediateResponse,
+    ) -> Result<bool> {
+        let mut res_headers = ResponseHeader::build(response.status, None)?;
+        apply_header_mutation(&mut res_headers, &response.headers)?;
+        res_headers.insert_header(header::CONTENT_LENGTH, response.body.len())?;
+
+        let has_body = !response.body.is_empty();
+        session
+            .write_response_header(Box::new(res_headers), !has_body)
+            .await?;
+
+        if has_body {
+            session
+                .write_response_body(Some(Bytes::from(response.body)), true)
+                .await?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for ExtProc {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let settings = match self.settings.get_or_try_build(
+            ctx.route_container.id,
+            config,
+            ExtProcSettings::from_config,
+        ) {
+            Ok(settings) => settings,
+            Err(err) => {
+                tracing::error!("invalid ext_proc plugin configuration: {err}");
+                return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        let has_body = has_body(session.req_header());
+        let request_body = settings.request_body && has_body;
+        if !settings.request_headers && !request_body && !settings.response_headers {
+            return Ok(false);
+        }
+
+        let stream = tokio::time::timeout(settings.timeout, settings.client.open_stream())
+            .await
+            .map_err(|_| anyhow!("timed out connecting"))
+            .and_then(|stream| stream);
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) if settings.log_failure("request", &err) => return Ok(false),
+            Err(_) => return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await,
+        };
+
+        let mut processor = ExtProcessor {
+            settings,
+            stream,
+            body: vec![],
+            skipped: false,
+        };
+
+        if processor.settings.request_headers {
+            let message = request_headers_message(session.req_header(), &ctx.host, !has_body);
+
+            let result = match processor.exchange(message).await {
+                Ok(ProcessingResponse::RequestHeaders(response)) => {
+                    apply_header_mutation(session.req_header_mut(), &response.header_mutation)
+                }
+                Ok(ProcessingResponse::ImmediateResponse(response)) => {
+                    return Self::respond_immediately(session, response).await;
+                }
+                result => Err(unexpected_response(result)),
+            };
+
+            if let Err(err) = result {
+                if processor.handle_failure("request headers", &err).is_some() {
+                    return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+                }
+                return Ok(false);
+            }
+        }
+
+        if request_body || processor.settings.response_headers {
+            ctx.ext_proc = Some(processor);
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        // The processor can replace the body, so its length isn't known yet
+        if ctx
+            .ext_proc
+            .as_ref()
+            .is_some_and(ExtProcessor::processes_request_body)
+        {
+            upstream_request.remove_header(&header::CONTENT_LENGTH);
+            upstream_request.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<ExtProcSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        ExtProcSettings::from_config(&config)
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let defaults = settings(&[("address", json!("127.0.0.1:50051"))]).unwrap();
+        assert!(!defaults.fail_open);
+        assert!(defaults.request_headers);
+        assert!(!defaults.response_headers);
+        assert!(!defaults.request_body);
+        assert_eq!(defaults.timeout, DEFAULT_TIMEOUT);
+
+        let custom = settings(&[
+            ("address", json!("processor:50051")),
+            ("failure_mode", json!("open")),
+            ("timeout", json!(50)),
+            ("request_body", json!(true)),
+        ])
+        .unwrap();
+        assert!(custom.fail_open);
+        assert!(custom.request_body);
+        assert_eq!(custom.timeout, Duration::from_millis(50));
+
+        assert!(settings(&[]).is_err());
+        assert!(settings(&[("address", json!("a:1")), ("failure_mode", json!("allow"))]).is_err());
+        assert!(settings(&[("address", json!("a:1")), ("timeout", json!(0))]).is_err());
+        assert!(settings(&[("address", json!("a:1")), ("request_body", json!("yes"))]).is_err());
+    }
+
+    #[test]
+    fn test_apply_header_mutation() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-existing", "a").unwrap();
+        req.insert_header("authorization", "secret").unwrap();
+
+        let mutation = HeaderMutation {
+            set: vec![
+                (
+                    "x-existing".to_string(),
+                    b"b".to_vec(),
+                    AppendAction::Append,
+                ),
+                (
+                    "x-user".to_string(),
+                    b"42".to_vec(),
+                    AppendAction::Overwrite,
+                ),
+                (
+                    "x-user".to_string(),
+                    b"43".to_vec(),
+                    AppendAction::AddIfAbsent,
+                ),
+                (
+                    "x-missing".to_string(),
+                    b"1".to_vec(),
+                    AppendAction::OverwriteIfExists,
+                ),
+                (
+                    ":path".to_string(),
+                    b"/admin".to_vec(),
+                    AppendAction::Overwrite,
+                ),
+            ],
+            remove: vec!["authorization".to_string()],
+        };
+        apply_header_mutation(&mut req, &mutation).unwrap();
+
+        assert_eq!(req.headers.get_all("x-existing").iter().count(), 2);
+        assert_eq!(req.headers["x-user"], "42");
+        assert!(req.headers.get("x-missing").is_none());
+        assert!(req.headers.get("authorization").is_none());
+        assert_eq!(req.uri.path(), "/");
+    }
+}
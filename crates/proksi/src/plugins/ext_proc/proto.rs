@@ -0,0 +1,476 @@
+//! Protobuf encoding of the ext_proc messages used by Proksi, see
+//! `envoy/service/ext_proc/v3/external_processor.proto` for their definition.
+//! Only the fields Proksi sends or handles are implemented, others are skipped.
+
+use anyhow::{anyhow, bail, Result};
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LENGTH_DELIMITED: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+#[derive(Default)]
+struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buffer.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint((u64::from(field) << 3) | u64::from(wire_type));
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_LENGTH_DELIMITED);
+        self.varint(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        if value {
+            self.key(field, WIRE_VARINT);
+            self.varint(1);
+        }
+    }
+
+    fn message(&mut self, field: u32, encode: impl FnOnce(&mut Encoder)) {
+        let mut message = Encoder::default();
+        encode(&mut message);
+        self.bytes(field, &message.buffer);
+    }
+}
+
+/// Value of a decoded field
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Iterates over the fields of a message, skipping the fixed-size ones
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = self
+                .data
+                .split_first()
+                .ok_or_else(|| anyhow!("truncated varint"))?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("invalid varint")
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            bail!("truncated field");
+        }
+        let (value, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u32, FieldValue<'a>)>> {
+        loop {
+            if self.data.is_empty() {
+                return Ok(None);
+            }
+
+            let key = self.varint()?;
+            let field = u32::try_from(key >> 3)?;
+
+            match (key & 0x7) as u8 {
+                WIRE_VARINT => return Ok(Some((field, FieldValue::Varint(self.varint()?)))),
+                WIRE_LENGTH_DELIMITED => {
+                    let length = usize::try_from(self.varint()?)?;
+                    return Ok(Some((field, FieldValue::Bytes(self.take(length)?))));
+                }
+                WIRE_FIXED64 => {
+                    self.take(8)?;
+                }
+                WIRE_FIXED32 => {
+                    self.take(4)?;
+                }
+                wire_type => bail!("unsupported wire type {wire_type}"),
+            }
+        }
+    }
+}
+
+/// Calls `handle` for each field of the message
+fn decode_fields<'a>(
+    data: &'a [u8],
+    mut handle: impl FnMut(u32, FieldValue<'a>) -> Result<()>,
+) -> Result<()> {
+    let mut decoder = Decoder::new(data);
+    while let Some((field, value)) = decoder.next_field()? {
+        handle(field, value)?;
+    }
+    Ok(())
+}
+
+fn as_str(value: &[u8]) -> Result<&str> {
+    std::str::from_utf8(value).map_err(|_| anyhow!("invalid string"))
+}
+
+/// `ProcessingRequest.request_headers` or `ProcessingRequest.response_headers`
+fn encode_headers<'a>(
+    field: u32,
+    headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    end_of_stream: bool,
+) -> Vec<u8> {
+    let mut request = Encoder::default();
+    request.message(field, |http_headers| {
+        // HttpHeaders.headers (config.core.v3.HeaderMap)
+        http_headers.message(1, |header_map| {
+            for (key, value) in headers {
+                // HeaderMap.headers (config.core.v3.HeaderValue: key and raw_value)
+                header_map.message(1, |header| {
+                    header.bytes(1, key.as_bytes());
+                    header.bytes(3, value);
+                });
+            }
+        });
+        http_headers.bool(3, end_of_stream);
+    });
+    request.buffer
+}
+
+pub fn encode_request_headers<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    end_of_stream: bool,
+) -> Vec<u8> {
+    encode_headers(2, headers, end_of_stream)
+}
+
+pub fn encode_response_headers<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    end_of_stream: bool,
+) -> Vec<u8> {
+    encode_headers(3, headers, end_of_stream)
+}
+
+/// `ProcessingRequest.request_body`
+pub fn encode_request_body(body: &[u8], end_of_stream: bool) -> Vec<u8> {
+    let mut request = Encoder::default();
+    request.message(4, |http_body| {
+        http_body.bytes(1, body);
+        http_body.bool(2, end_of_stream);
+    });
+    request.buffer
+}
+
+/// How a header of a mutation is combined with the existing one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendAction {
+    Append,
+    AddIfAbsent,
+    Overwrite,
+    OverwriteIfExists,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct HeaderMutation {
+    pub set: Vec<(String, Vec<u8>, AppendAction)>,
+    pub remove: Vec<String>,
+}
+
+impl HeaderMutation {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut mutation = Self::default();
+
+        decode_fields(data, |field, value| {
+            match (field, value) {
+                // set_headers (config.core.v3.HeaderValueOption)
+                (1, FieldValue::Bytes(option)) => mutation.set.push(decode_header_option(option)?),
+                // remove_headers
+                (2, FieldValue::Bytes(name)) => mutation.remove.push(as_str(name)?.to_string()),
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(mutation)
+    }
+}
+
+/// The deprecated `append` field takes precedence (defaulting to `false` as in Envoy),
+/// then `append_action`, apart from its default `APPEND_IF_EXISTS_OR_ADD`
+fn decode_header_option(data: &[u8]) -> Result<(String, Vec<u8>, AppendAction)> {
+    let mut key = None;
+    let mut value = vec![];
+    let mut append = None;
+    let mut action = AppendAction::Overwrite;
+
+    decode_fields(data, |field, field_value| {
+        match (field, field_value) {
+            (1, FieldValue::Bytes(header)) => decode_fields(header, |field, header_value| {
+                match (field, header_value) {
+                    (1, FieldValue::Bytes(name)) => key = Some(as_str(name)?.to_string()),
+                    (2 | 3, FieldValue::Bytes(raw)) if !raw.is_empty() => value = raw.to_vec(),
+                    _ => {}
+                }
+                Ok(())
+            })?,
+            // google.protobuf.BoolValue (empty when false)
+            (2, FieldValue::Bytes(wrapper)) => {
+                append = Some(false);
+                decode_fields(wrapper, |field, wrapped| {
+                    if let (1, FieldValue::Varint(v)) = (field, wrapped) {
+                        append = Some(v != 0);
+                    }
+                    Ok(())
+                })?;
+            }
+            (3, FieldValue::Varint(1)) => action = AppendAction::AddIfAbsent,
+            (3, FieldValue::Varint(3)) => action = AppendAction::OverwriteIfExists,
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let key = key.ok_or_else(|| anyhow!("header mutation without a key"))?;
+    let action = match append {
+        Some(true) => AppendAction::Append,
+        Some(false) => AppendAction::Overwrite,
+        None => action,
+    };
+
+    Ok((key, value, action))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BodyMutation {
+    Replace(Vec<u8>),
+    Clear,
+}
+
+/// `CommonResponse`, returned for each phase
+#[derive(Debug, Default, PartialEq)]
+pub struct CommonResponse {
+    pub header_mutation: HeaderMutation,
+    pub body_mutation: Option<BodyMutation>,
+}
+
+impl CommonResponse {
+    /// Decodes `HeadersResponse` or `BodyResponse`, which only wrap a `CommonResponse`
+    fn decode_wrapped(data: &[u8]) -> Result<Self> {
+        let mut response = Self::default();
+
+        decode_fields(data, |field, value| {
+            if let (1, FieldValue::Bytes(common)) = (field, value) {
+                response = Self::decode(common)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(response)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut response = Self::default();
+
+        decode_fields(data, |field, value| {
+            match (field, value) {
+                (2, FieldValue::Bytes(mutation)) => {
+                    response.header_mutation = HeaderMutation::decode(mutation)?;
+                }
+                (3, FieldValue::Bytes(mutation)) => {
+                    decode_fields(mutation, |field, value| {
+                        match (field, value) {
+                            (1, FieldValue::Bytes(body)) => {
+                                response.body_mutation = Some(BodyMutation::Replace(body.to_vec()));
+                            }
+                            (2, FieldValue::Varint(1)) => {
+                                response.body_mutation = Some(BodyMutation::Clear);
+                            }
+                            _ => {}
+                        }
+                        Ok(())
+                    })?;
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(response)
+    }
+}
+
+/// Response sent to the client instead of proxying the request
+#[derive(Debug, Default, PartialEq)]
+pub struct ImmediateResponse {
+    pub status: u16,
+    pub headers: HeaderMutation,
+    pub body: Vec<u8>,
+}
+
+impl ImmediateResponse {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut response = Self::default();
+
+        decode_fields(data, |field, value| {
+            match (field, value) {
+                // type.v3.HttpStatus
+                (1, FieldValue::Bytes(status)) => decode_fields(status, |field, value| {
+                    if let (1, FieldValue::Varint(code)) = (field, value) {
+                        response.status = u16::try_from(code)?;
+                    }
+                    Ok(())
+                })?,
+                (2, FieldValue::Bytes(mutation)) => {
+                    response.headers = HeaderMutation::decode(mutation)?;
+                }
+                (3, FieldValue::Bytes(body)) => response.body = body.to_vec(),
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        if !(100..=599).contains(&response.status) {
+            bail!("invalid immediate response status {}", response.status);
+        }
+
+        Ok(response)
+    }
+}
+
+/// `ProcessingResponse`
+#[derive(Debug, PartialEq)]
+pub enum ProcessingResponse {
+    RequestHeaders(CommonResponse),
+    ResponseHeaders(CommonResponse),
+    RequestBody(CommonResponse),
+    ImmediateResponse(ImmediateResponse),
+    /// Responses to phases Proksi doesn't send (e.g. trailers)
+    Unsupported,
+}
+
+impl ProcessingResponse {
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut response = None;
+
+        decode_fields(data, |field, value| {
+            let FieldValue::Bytes(message) = value else {
+                return Ok(());
+            };
+
+            let decoded = match field {
+                1 => ProcessingResponse::RequestHeaders(CommonResponse::decode_wrapped(message)?),
+                2 => ProcessingResponse::ResponseHeaders(CommonResponse::decode_wrapped(message)?),
+                3 => ProcessingResponse::RequestBody(CommonResponse::decode_wrapped(message)?),
+                7 => ProcessingResponse::ImmediateResponse(ImmediateResponse::decode(message)?),
+                4..=6 => ProcessingResponse::Unsupported,
+                _ => return Ok(()),
+            };
+            response = Some(decoded);
+            Ok(())
+        })?;
+
+        response.ok_or_else(|| anyhow!("empty processing response"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request_headers() {
+        let encoded = encode_request_headers([(":method", b"GET".as_slice())], true);
+        assert_eq!(
+            encoded,
+            [
+                0x12, 0x14, // request_headers
+                0x0a, 0x10, // headers
+                0x0a, 0x0e, // headers[0]
+                0x0a, 0x07, b':', b'm', b'e', b't', b'h', b'o', b'd', // key
+                0x1a, 0x03, b'G', b'E', b'T', // raw_value
+                0x18, 0x01, // end_of_stream
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_headers_response() {
+        let mut header = Encoder::default();
+        header.bytes(1, b"x-user");
+        header.bytes(3, b"42");
+
+        let mut option = Encoder::default();
+        option.bytes(1, &header.buffer);
+        option.key(3, WIRE_VARINT);
+        option.varint(1);
+
+        let mut response = Encoder::default();
+        response.message(1, |headers_response| {
+            headers_response.message(1, |common| {
+                common.message(2, |mutation| {
+                    mutation.bytes(1, &option.buffer);
+                    mutation.bytes(2, b"authorization");
+                });
+                common.message(3, |body| body.bytes(1, b"{}"));
+            });
+        });
+
+        assert_eq!(
+            ProcessingResponse::decode(&response.buffer).unwrap(),
+            ProcessingResponse::RequestHeaders(CommonResponse {
+                header_mutation: HeaderMutation {
+                    set: vec![(
+                        "x-user".to_string(),
+                        b"42".to_vec(),
+                        AppendAction::AddIfAbsent
+                    )],
+                    remove: vec!["authorization".to_string()],
+                },
+                body_mutation: Some(BodyMutation::Replace(b"{}".to_vec())),
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_immediate_response() {
+        let mut response = Encoder::default();
+        response.message(7, |immediate| {
+            immediate.message(1, |status| {
+                status.key(1, WIRE_VARINT);
+                status.varint(403);
+            });
+            immediate.bytes(3, b"denied");
+            // grpc_status (skipped)
+            immediate.message(4, |grpc_status| grpc_status.bool(1, true));
+        });
+
+        assert_eq!(
+            ProcessingResponse::decode(&response.buffer).unwrap(),
+            ProcessingResponse::ImmediateResponse(ImmediateResponse {
+                status: 403,
+                headers: HeaderMutation::default(),
+                body: b"denied".to_vec(),
+            })
+        );
+
+        assert!(ProcessingResponse::decode(&[]).is_err());
+        assert!(ProcessingResponse::decode(&[0x3a, 0x05, 0x0a]).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use http::StatusCode;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Sent with the injected responses, so that they can be told from the upstream ones
+const FAULT_HEADER: &str = "x-proksi-fault";
+
+/// Per-route settings of the fault injection plugin
+#[derive(Debug, PartialEq)]
+struct FaultSettings {
+    enabled: bool,
+    /// Delay added to `delay_percentage`% of the requests
+    delay: Option<(Duration, u32)>,
+    /// Status of the response sent instead of the upstream one to `abort_percentage`% of
+    /// the requests
+    abort: Option<(StatusCode, u32)>,
+    /// Share of the requests whose connection is closed without a response
+    disconnect_percentage: u32,
+}
+
+/// A percentage of the configuration, from 0 to 100
+fn percentage(
+    config: &HashMap<Cow<'static, str>, serde_json::Value>,
+    key: &str,
+    default: u32,
+) -> Result<u32> {
+    match config.get(key) {
+        Some(value) => value
+            .as_u64()
+            .filter(|percentage| *percentage <= 100)
+            .and_then(|percentage| u32::try_from(percentage).ok())
+            .ok_or_else(|| anyhow!("Missing or invalid {key}, must be from 0 to 100")),
+        None => Ok(default),
+    }
+}
+
+fn sampled(percentage: u32) -> bool {
+    (uuid::Uuid::new_v4().as_u128() % 100) < u128::from(percentage)
+}
+
+impl FaultSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let enabled = match config.get("enabled") {
+            Some(enabled) => enabled
+                .as_bool()
+                .ok_or_else(|| anyhow!("Missing or invalid enabled"))?,
+            None => true,
+        };
+
+        let delay = match config.get("delay_ms") {
+            Some(delay) => {
+                let delay = delay
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("Missing or invalid delay_ms"))?;
+                Some((
+                    Duration::from_millis(delay),
+                    percentage(config, "delay_percentage", 100)?,
+                ))
+            }
+            None => None,
+        };
+
+        let abort = match config.get("abort_status") {
+            Some(status) => {
+                let status = status
+                    .as_u64()
+                    .and_then(|status| u16::try_from(status).ok())
+                    .and_then(|status| StatusCode::from_u16(status).ok())
+                    .filter(|status| status.is_client_error() || status.is_server_error())
+                    .ok_or_else(|| {
+                        anyhow!("Missing or invalid abort_status, must be 4xx or 5xx")
+                    })?;
+                Some((status, percentage(config, "abort_percentage", 100)?))
+            }
+            None => None,
+        };
+
+        let disconnect_percentage = percentage(config, "disconnect_percentage", 0)?;
+
+        if delay.is_none() && abort.is_none() && disconnect_percentage == 0 {
+            bail!("one of delay_ms, abort_status and disconnect_percentage must be set");
+        }
+
+        Ok(Self {
+            enabled,
+            delay,
+            abort,
+            disconnect_percentage,
+        })
+    }
+}
+
+/// Checks the configuration of the plugin, e.g. before the admin API applies it
+pub fn check_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<()> {
+    FaultSettings::from_config(config).map(|_| ())
+}
+
+/// Injects faults in the requests of a route (delays, error responses and closed
+/// connections), to test how the clients behave when the upstreams fail
+pub struct FaultInjection {
+    settings: SettingsCache<FaultSettings>,
+}
+
+impl FaultInjection {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    fn get_settings(&self, ctx: &RouterContext) -> Result<Option<Arc<FaultSettings>>> {
+        let Some(plugin) = ctx.route_container.plugins.get("fault_injection") else {
+            return Ok(None);
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        self.settings
+            .get_or_try_build(ctx.route_container.id, config, FaultSettings::from_config)
+            .inspect_err(|err| {
+                tracing::error!("invalid fault_injection plugin configuration: {err}");
+            })
+            .map(Some)
+    }
+
+    fn count(ctx: &RouterContext, fault: &str) {
+        metrics::FAULT_INJECTIONS
+            .with_label_values(&[ctx.host.as_str(), fault])
+            .inc();
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for FaultInjection {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        // Requests of misconfigured routes are served without faults
+        let Some(settings) = self.get_settings(ctx)? else {
+            return Ok(false);
+        };
+        if !settings.enabled {
+            return Ok(false);
+        }
+
+        if let Some((delay, percentage)) = settings.delay {
+            if sampled(percentage) {
+                Self::count(ctx, "delay");
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        if sampled(settings.disconnect_percentage) {
+            Self::count(ctx, "disconnect");
+            // Nothing is written, the connection is closed once the request is done
+            session.set_keepalive(None);
+            return Ok(true);
+        }
+
+        if let Some((status, percentage)) = settings.abort {
+            if sampled(percentage) {
+                Self::count(ctx, "abort");
+                let mut res_headers = ResponseHeader::build_no_case(status, Some(2))?;
+                res_headers.insert_header(FAULT_HEADER, "abort")?;
+                res_headers.insert_header(http::header::CONTENT_LENGTH, "0")?;
+                session
+                    .write_response_header(Box::new(res_headers), true)
+                    .await?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Nothing to do before the upstream request
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(config: &[(&'static str, serde_json::Value)]) -> Result<FaultSettings> {
+        let config = config
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        FaultSettings::from_config(&config)
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = settings(&[
+            ("delay_ms", json!(250)),
+            ("delay_percentage", json!(10)),
+            ("abort_status", json!(503)),
+        ])
+        .unwrap();
+        assert!(settings.enabled);
+        assert_eq!(settings.delay, Some((Duration::from_millis(250), 10)));
+        assert_eq!(settings.abort, Some((StatusCode::SERVICE_UNAVAILABLE, 100)));
+        assert_eq!(settings.disconnect_percentage, 0);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(settings(&[]).is_err());
+        assert!(settings(&[("enabled", json!(false))]).is_err());
+        assert!(settings(&[("abort_status", json!(200))]).is_err());
+        assert!(settings(&[
+            ("abort_status", json!(503)),
+            ("abort_percentage", json!(101))
+        ])
+        .is_err());
+        assert!(settings(&[("delay_ms", json!("1s"))]).is_err());
+        assert!(settings(&[("disconnect_percentage", json!(5))]).is_ok());
+    }
+
+    #[test]
+    fn test_sampled() {
+        assert!(!sampled(0));
+        assert!(sampled(100));
+    }
+}
@@ -0,0 +1,389 @@
+use std::{borrow::Cow, collections::HashMap, net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use once_cell::sync::Lazy;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{
+    get_required_config, respond_with_status, route_settings, settings_cache::SettingsCache,
+    MiddlewarePlugin,
+};
+
+/// HTTP client used to call the auth endpoints. Redirects are never followed,
+/// they are returned to downstream instead (e.g. redirect to a login page)
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+});
+
+/// Prefix of the context extensions holding the headers forwarded to the upstream
+const AUTH_HEADER_PREFIX: &str = "forward_auth_header:";
+
+/// Default timeout (in seconds) for the auth request
+const DEFAULT_TIMEOUT: u64 = 5;
+
+/// Headers that only make sense for a single connection and are never copied
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Per-route settings of the forward auth plugin
+struct ForwardAuthSettings {
+    address: String,
+    timeout: Duration,
+    trust_forward_header: bool,
+    /// Request headers sent to the auth endpoint. All headers are sent when empty.
+    auth_request_headers: Vec<HeaderName>,
+    /// Auth response headers copied to the upstream request
+    auth_response_headers: Vec<HeaderName>,
+}
+
+impl ForwardAuthSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let header_list = |key: &str| -> Result<Vec<HeaderName>> {
+            let Some(values) = config.get(key) else {
+                return Ok(vec![]);
+            };
+
+            values
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))?
+                .iter()
+                .map(|v| {
+                    let name = v
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Missing or invalid {key}"))?;
+                    Ok(HeaderName::try_from(name)?)
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            address: get_required_config(config, "address")?,
+            timeout: Duration::from_secs(
+                config
+                    .get("timeout")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_TIMEOUT),
+            ),
+            trust_forward_header: config
+                .get("trust_forward_header")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(false),
+            auth_request_headers: header_list("auth_request_headers")?,
+            auth_response_headers: header_list("auth_response_headers")?,
+        })
+    }
+}
+
+/// Builds the headers sent to the auth endpoint: the (selected) original request headers
+/// and the `X-Forwarded-*` headers describing the original request
+fn build_auth_request_headers(
+    req: &RequestHeader,
+    client_ip: Option<IpAddr>,
+    host: &str,
+    settings: &ForwardAuthSettings,
+) -> HeaderMap {
+    let mut headers = HeaderMap::with_capacity(req.headers.len() + 6);
+
+    for (name, value) in &req.headers {
+        let selected = settings.auth_request_headers.is_empty()
+            || settings.auth_request_headers.contains(name);
+
+        if selected
+            && !HOP_BY_HOP_HEADERS.contains(name)
+            && name != header::CONTENT_LENGTH
+            && name != header::HOST
+        {
+            headers.append(name, value.clone());
+        }
+    }
+
+    let uri = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+
+    let forwarded = [
+        ("x-forwarded-method", req.method.as_str()),
+        ("x-forwarded-proto", "https"),
+        ("x-forwarded-host", host),
+        ("x-forwarded-uri", uri),
+        // nginx `auth_request` compatible
+        ("x-original-uri", uri),
+        ("x-original-method", req.method.as_str()),
+    ];
+
+    for (name, value) in forwarded {
+        if settings.trust_forward_header && headers.contains_key(name) {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    }
+
+    if let Some(ip) = client_ip {
+        let existing = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| settings.trust_forward_header)
+            .map(ToString::to_string);
+
+        let value = match existing {
+            Some(existing) => format!("{existing}, {ip}"),
+            None => ip.to_string(),
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert("x-forwarded-for", value);
+        }
+    }
+
+    headers
+}
+
+/// Delegates the authentication of each request to an external service.
+/// 2xx responses allow the request, any other response is sent back to downstream.
+pub struct ForwardAuth {
+    settings: SettingsCache<ForwardAuthSettings>,
+}
+
+impl ForwardAuth {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    /// Sends the auth service response (status, headers and body) to downstream
+    async fn respond_with_auth_response(
+        session: &mut Session,
+        response: reqwest::Response,
+    ) -> Result<bool> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await.unwrap_or_default();
+
+        let mut res_headers = ResponseHeader::build_no_case(status, Some(headers.len() + 1))?;
+        for (name, value) in &headers {
+            if HOP_BY_HOP_HEADERS.contains(name) || name == header::CONTENT_LENGTH {
+                continue;
+            }
+            res_headers.append_header(name.clone(), value.clone())?;
+        }
+        res_headers.insert_header(header::CONTENT_LENGTH, body.len())?;
+
+        session
+            .write_response_header(Box::new(res_headers), body.is_empty())
+            .await?;
+        if !body.is_empty() {
+            session.write_response_body(Some(body), true).await?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for ForwardAuth {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Nothing to do if the plugin configuration is not present
+        let Some(config) = plugin.config.as_ref() else {
+            return Ok(false);
+        };
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            ForwardAuthSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let client_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(std::net::SocketAddr::ip);
+
+        let headers =
+            build_auth_request_headers(session.req_header(), client_ip, &ctx.host, &settings);
+
+        let response = HTTP_CLIENT
+            .get(&settings.address)
+            .headers(headers)
+            .timeout(settings.timeout)
+            .send()
+            .await;
+
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::error!("forward auth request to {} failed: {err}", settings.address);
+                return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        if !response.status().is_success() {
+            return Self::respond_with_auth_response(session, response).await;
+        }
+
+        // Headers are always registered (even if missing from the auth response)
+        // so that downstream can't inject its own values
+        for name in &settings.auth_response_headers {
+            let value = response
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+
+            ctx.extensions.insert(
+                Cow::Owned(format!("{AUTH_HEADER_PREFIX}{}", name.as_str())),
+                value,
+            );
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        for (key, value) in &ctx.extensions {
+            let Some(header) = key.strip_prefix(AUTH_HEADER_PREFIX) else {
+                continue;
+            };
+
+            upstream_request.remove_header(header);
+            if !value.is_empty() {
+                upstream_request.insert_header(header.to_string(), value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> ForwardAuthSettings {
+        let mut config =
+            HashMap::from([(Cow::Borrowed("address"), json!("http://auth.local/verify"))]);
+        for (key, value) in extra {
+            config.insert(Cow::Borrowed(*key), value.clone());
+        }
+        ForwardAuthSettings::from_config(&config).unwrap()
+    }
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/admin?page=2", None).unwrap();
+        req.insert_header("cookie", "session=abc").unwrap();
+        req.insert_header("authorization", "Bearer token").unwrap();
+        req.insert_header("connection", "keep-alive").unwrap();
+        req.insert_header("x-forwarded-for", "10.0.0.1").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let ip = "192.168.1.10".parse().ok();
+        let headers = build_auth_request_headers(&request(), ip, "app.example.com", &settings(&[]));
+
+        assert_eq!(headers["x-forwarded-method"], "POST");
+        assert_eq!(headers["x-forwarded-host"], "app.example.com");
+        assert_eq!(headers["x-forwarded-uri"], "/admin?page=2");
+        assert_eq!(headers["x-original-uri"], "/admin?page=2");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        // Untrusted forward headers are replaced
+        assert_eq!(headers["x-forwarded-for"], "192.168.1.10");
+        assert_eq!(headers["cookie"], "session=abc");
+        assert!(!headers.contains_key("connection"));
+    }
+
+    #[test]
+    fn test_trusted_forward_header() {
+        let ip = "192.168.1.10".parse().ok();
+        let settings = settings(&[("trust_forward_header", json!(true))]);
+        let headers = build_auth_request_headers(&request(), ip, "app.example.com", &settings);
+
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 192.168.1.10");
+    }
+
+    #[test]
+    fn test_selected_request_headers() {
+        let settings = settings(&[
+            ("auth_request_headers", json!(["Cookie"])),
+            ("auth_response_headers", json!(["X-Auth-User"])),
+        ]);
+        let headers = build_auth_request_headers(&request(), None, "app.example.com", &settings);
+
+        assert_eq!(headers["cookie"], "session=abc");
+        assert!(!headers.contains_key("authorization"));
+        assert!(!headers.contains_key("x-forwarded-for"));
+        assert_eq!(
+            settings.auth_response_headers,
+            vec![HeaderName::from_static("x-auth-user")]
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = HashMap::from([
+            (Cow::Borrowed("address"), json!("http://auth.local/verify")),
+            (Cow::Borrowed("auth_response_headers"), json!("X-Auth-User")),
+        ]);
+        assert!(ForwardAuthSettings::from_config(&config).is_err());
+        assert!(ForwardAuthSettings::from_config(&HashMap::new()).is_err());
+    }
+}
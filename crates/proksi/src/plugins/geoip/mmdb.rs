@@ -0,0 +1,369 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value};
+
+/// Marks the beginning of the metadata section, at the end of the file
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// Size of the (zeroed) separator between the search tree and the data section
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// Maximum nesting of maps/arrays/pointers accepted while decoding
+const MAX_DEPTH: usize = 32;
+
+/// Reader for databases in the MaxMind DB format (e.g. `GeoLite2-Country.mmdb`).
+/// The whole file is kept in memory and records are decoded as JSON values.
+pub(super) struct Reader {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Node where the lookups of IPv4 addresses start in IPv6 databases (`::/96`)
+    ipv4_start: usize,
+}
+
+impl Reader {
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("invalid database: metadata not found"))?;
+
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder::new(&buf[metadata_start..]).decode(0, 0)?;
+
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or_else(|| anyhow!("invalid database: missing {name} in metadata"))
+        };
+
+        let node_count = usize::try_from(field("node_count")?)?;
+        let record_size = usize::try_from(field("record_size")?)?;
+        let ip_version = field("ip_version")?;
+
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("invalid database: unsupported record size {record_size}");
+        }
+
+        if node_count * record_size / 4 + DATA_SECTION_SEPARATOR > marker {
+            bail!("invalid database: search tree is larger than the file");
+        }
+
+        let mut reader = Self {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.read_record(node, 0)?;
+            }
+            reader.ipv4_start = node;
+        }
+
+        Ok(reader)
+    }
+
+    /// Returns the record for the network containing the IP, if any
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bytes, mut node) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => {
+                bail!("IPv6 lookups are not supported by an IPv4 database")
+            }
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bytes[i / 8] >> (7 - (i % 8))) & 1;
+            node = self.read_record(node, bit)?;
+        }
+
+        if node == self.node_count {
+            return Ok(None);
+        }
+
+        let offset = (node - self.node_count)
+            .checked_sub(DATA_SECTION_SEPARATOR)
+            .ok_or_else(|| anyhow!("invalid database: bad data pointer"))?;
+
+        let (value, _) = Decoder::new(self.data_section()).decode(offset, 0)?;
+        Ok(Some(value))
+    }
+
+    fn data_section(&self) -> &[u8] {
+        &self.buf[self.search_tree_size() + DATA_SECTION_SEPARATOR..]
+    }
+
+    fn search_tree_size(&self) -> usize {
+        self.node_count * self.record_size / 4
+    }
+
+    /// Reads the left (`bit == 0`) or right (`bit == 1`) record of a node
+    fn read_record(&self, node: usize, bit: u8) -> Result<usize> {
+        let node_size = self.record_size / 4;
+        let start = node * node_size;
+        let bytes = self
+            .buf
+            .get(start..start + node_size)
+            .ok_or_else(|| anyhow!("invalid database: node {node} out of bounds"))?;
+
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, b| (acc << 8) | usize::from(*b));
+
+        let record = match (self.record_size, bit) {
+            (24, 0) => be(&bytes[0..3]),
+            (24, _) => be(&bytes[3..6]),
+            (28, 0) => (usize::from(bytes[3] & 0xF0) << 20) | be(&bytes[0..3]),
+            (28, _) => (usize::from(bytes[3] & 0x0F) << 24) | be(&bytes[4..7]),
+            (_, 0) => be(&bytes[0..4]),
+            (_, _) => be(&bytes[4..8]),
+        };
+
+        Ok(record)
+    }
+}
+
+/// Decodes the fields of a data section
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("invalid database: field out of bounds"))
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u128> {
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u128::from(*b)))
+    }
+
+    /// Decodes the field at `offset`, returning its value and the offset of the next field
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            bail!("invalid database: maximum depth exceeded");
+        }
+
+        let ctrl = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+
+        let mut kind = ctrl >> 5;
+        if kind == 1 {
+            return self.decode_pointer(ctrl, offset, depth);
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+
+        let mut size = usize::from(ctrl & 0x1F);
+        if size >= 29 {
+            let extra = size - 28;
+            // `uint` reads big-endian bytes, at most 3 here
+            let value = usize::try_from(self.uint(offset, extra)?)?;
+            size = match extra {
+                1 => 29 + value,
+                2 => 285 + value,
+                _ => 65_821 + value,
+            };
+            offset += extra;
+        }
+
+        match kind {
+            // UTF-8 string
+            2 => {
+                let value = std::str::from_utf8(self.bytes(offset, size)?)?;
+                Ok((Value::String(value.to_string()), offset + size))
+            }
+            // Double
+            3 => {
+                let bytes: [u8; 8] = self.bytes(offset, 8)?.try_into()?;
+                let value =
+                    Number::from_f64(f64::from_be_bytes(bytes)).map_or(Value::Null, Value::Number);
+                Ok((value, offset + 8))
+            }
+            // Bytes
+            4 => {
+                let bytes = self.bytes(offset, size)?;
+                let value = bytes.iter().map(|b| Value::from(*b)).collect();
+                Ok((Value::Array(value), offset + size))
+            }
+            // Unsigned integers (16, 32, 64 and 128 bits)
+            5 | 6 | 9 | 10 => {
+                let value = self.uint(offset, size)?;
+                let value = u64::try_from(value)
+                    .map_or_else(|_| Value::String(value.to_string()), Value::from);
+                Ok((value, offset + size))
+            }
+            // Map
+            7 => {
+                let mut map = Map::with_capacity(size);
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let Value::String(key) = key else {
+                        bail!("invalid database: map keys must be strings");
+                    };
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    map.insert(key, value);
+                    offset = next;
+                }
+                Ok((Value::Object(map), offset))
+            }
+            // Int32
+            8 => {
+                let value = self.uint(offset, size)?;
+                #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                let value = (value as u32) as i32;
+                Ok((Value::from(value), offset + size))
+            }
+            // Array
+            11 => {
+                let mut values = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (value, next) = self.decode(offset, depth + 1)?;
+                    values.push(value);
+                    offset = next;
+                }
+                Ok((Value::Array(values), offset))
+            }
+            // Boolean (the value is stored in the size)
+            14 => Ok((Value::Bool(size != 0), offset)),
+            // Float
+            15 => {
+                let bytes: [u8; 4] = self.bytes(offset, 4)?.try_into()?;
+                let value = Number::from_f64(f64::from(f32::from_be_bytes(bytes)))
+                    .map_or(Value::Null, Value::Number);
+                Ok((value, offset + 4))
+            }
+            _ => bail!("invalid database: unsupported field type {kind}"),
+        }
+    }
+
+    /// Decodes the value a pointer refers to. The next field is the one after the pointer itself.
+    fn decode_pointer(&self, ctrl: u8, offset: usize, depth: usize) -> Result<(Value, usize)> {
+        let size = usize::from((ctrl >> 3) & 0x3) + 1;
+        let value = usize::try_from(self.uint(offset, size)?)?;
+        let high = usize::from(ctrl & 0x7);
+
+        let pointer = match size {
+            1 => (high << 8) | value,
+            2 => ((high << 16) | value) + 2048,
+            3 => ((high << 24) | value) + 526_336,
+            _ => value,
+        };
+
+        let (value, _) = self.decode(pointer, depth + 1)?;
+        Ok((value, offset + size))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![(2 << 5) | u8::try_from(value.len()).unwrap()];
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut bytes = vec![(6 << 5) | 4];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    fn map(pairs: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![(7 << 5) | u8::try_from(pairs.len()).unwrap()];
+        for (key, value) in pairs {
+            bytes.extend(string(key));
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /// Builds an IPv4 database (24 bits records) with a single node:
+    /// `0.0.0.0/1` points to `record` and `128.0.0.0/1` has no data
+    pub fn database(record: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        // "DE" is stored first and referenced through a pointer by the record
+        let mut data = string("DE");
+        let record_offset = data.len();
+        data.extend(map(record));
+
+        let node_count = 1;
+        let data_record =
+            u32::try_from(node_count + DATA_SECTION_SEPARATOR + record_offset).unwrap();
+
+        let mut buf = data_record.to_be_bytes()[1..].to_vec();
+        buf.extend_from_slice(&u32::try_from(node_count).unwrap().to_be_bytes()[1..]);
+        buf.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+        buf.extend(data);
+        buf.extend_from_slice(METADATA_MARKER);
+        buf.extend(map(&[
+            ("node_count", uint32(1)),
+            ("record_size", vec![(5 << 5) | 1, 24]),
+            ("ip_version", vec![(5 << 5) | 1, 4]),
+            ("database_type", string("Test")),
+        ]));
+        buf
+    }
+
+    /// Pointer to the "DE" string at the start of the data section
+    pub fn country_code_pointer() -> Vec<u8> {
+        vec![1 << 5, 0]
+    }
+
+    #[test]
+    fn test_lookup() {
+        let buf = database(&[
+            ("country", map(&[("iso_code", country_code_pointer())])),
+            ("autonomous_system_number", uint32(3320)),
+            // Booleans are an extended type (14 - 7)
+            ("is_anycast", vec![1, 7]),
+        ]);
+        let reader = Reader::from_bytes(buf).unwrap();
+
+        let record = reader.lookup("10.1.2.3".parse().unwrap()).unwrap().unwrap();
+        assert_eq!(record["country"]["iso_code"], "DE");
+        assert_eq!(record["autonomous_system_number"], 3320);
+        assert_eq!(record["is_anycast"], true);
+
+        assert!(reader
+            .lookup("200.1.2.3".parse().unwrap())
+            .unwrap()
+            .is_none());
+        assert!(reader.lookup("::1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        assert!(Reader::from_bytes(b"not a database".to_vec()).is_err());
+
+        let mut buf = database(&[]);
+        // Corrupt the record size (value after the key and the control byte)
+        let key = buf.windows(11).rposition(|w| w == b"record_size").unwrap();
+        buf[key + 12] = 27;
+        assert!(Reader::from_bytes(buf).is_err());
+    }
+}
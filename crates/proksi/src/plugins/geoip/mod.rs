@@ -0,0 +1,451 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::StatusCode;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+mod mmdb;
+
+/// How often (in seconds) databases are checked for changes by default
+const DEFAULT_RELOAD_INTERVAL: u64 = 60;
+
+/// A database loaded in memory, along with the information needed to
+/// know when the file has to be read again
+struct GeoIpDatabase {
+    reader: Arc<mmdb::Reader>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+/// Keeps the databases in memory, reloading them whenever they change on disk
+#[derive(Default)]
+struct DatabaseStore {
+    databases: papaya::HashMap<PathBuf, Arc<GeoIpDatabase>>,
+}
+
+impl DatabaseStore {
+    /// Returns the reader for the given database, reading it again if it was modified
+    /// since the last check. `reload_interval` limits how often the file metadata is checked.
+    fn get(&self, path: &Path, reload_interval: Duration) -> Result<Arc<mmdb::Reader>> {
+        let databases = self.databases.pin();
+
+        let modified = match databases.get(path) {
+            Some(database) if database.checked_at.elapsed() < reload_interval => {
+                return Ok(database.reader.clone());
+            }
+            Some(database) => {
+                let modified = std::fs::metadata(path)?.modified().ok();
+                if modified.is_some() && modified == database.modified {
+                    databases.insert(
+                        path.to_path_buf(),
+                        Arc::new(GeoIpDatabase {
+                            reader: database.reader.clone(),
+                            modified,
+                            checked_at: Instant::now(),
+                        }),
+                    );
+                    return Ok(database.reader.clone());
+                }
+                modified
+            }
+            None => std::fs::metadata(path)?.modified().ok(),
+        };
+
+        let reader = Arc::new(mmdb::Reader::from_bytes(std::fs::read(path)?)?);
+        tracing::info!("loaded geoip database {path:?}");
+
+        databases.insert(
+            path.to_path_buf(),
+            Arc::new(GeoIpDatabase {
+                reader: reader.clone(),
+                modified,
+                checked_at: Instant::now(),
+            }),
+        );
+
+        Ok(reader)
+    }
+}
+
+/// Geo information found for a client IP
+#[derive(Debug, Default, PartialEq)]
+struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code (uppercase)
+    country: Option<String>,
+    asn: Option<u64>,
+}
+
+impl GeoInfo {
+    /// Reads the country from a country/city record. The registered country
+    /// is used when the IP has no country of its own (e.g. anycast networks).
+    fn with_country_record(&mut self, record: &serde_json::Value) {
+        self.country = ["country", "registered_country"]
+            .iter()
+            .find_map(|key| record.get(key)?.get("iso_code")?.as_str())
+            .map(str::to_ascii_uppercase);
+    }
+
+    fn with_asn_record(&mut self, record: &serde_json::Value) {
+        self.asn = record
+            .get("autonomous_system_number")
+            .and_then(serde_json::Value::as_u64);
+    }
+}
+
+/// Per-route settings of the GeoIP plugin
+struct GeoIpSettings {
+    /// Country (or city) database
+    database: Option<PathBuf>,
+    asn_database: Option<PathBuf>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    allow_asns: Vec<u64>,
+    deny_asns: Vec<u64>,
+    reload_interval: Duration,
+}
+
+impl GeoIpSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let path = |key: &str| -> Result<Option<PathBuf>> {
+            config
+                .get(key)
+                .map(|v| {
+                    v.as_str()
+                        .map(PathBuf::from)
+                        .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+                })
+                .transpose()
+        };
+
+        let countries = |key: &str| -> Result<Vec<String>> {
+            list(config, key, |v| v.as_str().map(str::to_ascii_uppercase))
+        };
+        let asns = |key: &str| -> Result<Vec<u64>> { list(config, key, serde_json::Value::as_u64) };
+
+        let settings = Self {
+            database: path("database")?,
+            asn_database: path("asn_database")?,
+            allow_countries: countries("allow_countries")?,
+            deny_countries: countries("deny_countries")?,
+            allow_asns: asns("allow_asns")?,
+            deny_asns: asns("deny_asns")?,
+            reload_interval: Duration::from_secs(
+                config
+                    .get("reload_interval")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_RELOAD_INTERVAL),
+            ),
+        };
+
+        if settings.database.is_none() && settings.asn_database.is_none() {
+            return Err(anyhow!("Missing database or asn_database"));
+        }
+
+        let has_country_rules =
+            !settings.allow_countries.is_empty() || !settings.deny_countries.is_empty();
+        if has_country_rules && settings.database.is_none() {
+            return Err(anyhow!("Country rules require a database"));
+        }
+
+        let has_asn_rules = !settings.allow_asns.is_empty() || !settings.deny_asns.is_empty();
+        if has_asn_rules && settings.asn_database.is_none() {
+            return Err(anyhow!("ASN rules require an asn_database"));
+        }
+
+        Ok(settings)
+    }
+
+    /// Deny lists take precedence. When an allow list is not empty, clients
+    /// without a matching (or known) country/ASN are denied.
+    fn is_allowed(&self, info: &GeoInfo) -> bool {
+        let denied_country = info
+            .country
+            .as_ref()
+            .is_some_and(|c| self.deny_countries.contains(c));
+        let denied_asn = info.asn.is_some_and(|asn| self.deny_asns.contains(&asn));
+
+        if denied_country || denied_asn {
+            return false;
+        }
+
+        let allowed_country = self.allow_countries.is_empty()
+            || info
+                .country
+                .as_ref()
+                .is_some_and(|c| self.allow_countries.contains(c));
+        let allowed_asn = self.allow_asns.is_empty()
+            || info.asn.is_some_and(|asn| self.allow_asns.contains(&asn));
+
+        allowed_country && allowed_asn
+    }
+}
+
+fn list<T>(
+    config: &HashMap<Cow<'static, str>, serde_json::Value>,
+    key: &str,
+    parse: impl Fn(&serde_json::Value) -> Option<T>,
+) -> Result<Vec<T>> {
+    let Some(values) = config.get(key) else {
+        return Ok(vec![]);
+    };
+
+    values
+        .as_array()
+        .ok_or_else(|| anyhow!("Missing or invalid {key}"))?
+        .iter()
+        .map(|v| parse(v).ok_or_else(|| anyhow!("Missing or invalid {key}")))
+        .collect()
+}
+
+/// Allows or denies requests based on the country/ASN of the client IP,
+/// using databases in the MaxMind DB format (e.g. GeoLite2)
+pub struct GeoIp {
+    databases: DatabaseStore,
+    settings: SettingsCache<GeoIpSettings>,
+}
+
+impl GeoIp {
+    pub fn new() -> Self {
+        Self {
+            databases: DatabaseStore::default(),
+            settings: SettingsCache::default(),
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr, settings: &GeoIpSettings) -> Result<GeoInfo> {
+        let mut info = GeoInfo::default();
+
+        if let Some(path) = &settings.database {
+            let reader = self.databases.get(path, settings.reload_interval)?;
+            if let Some(record) = reader.lookup(ip)? {
+                info.with_country_record(&record);
+            }
+        }
+
+        if let Some(path) = &settings.asn_database {
+            let reader = self.databases.get(path, settings.reload_interval)?;
+            if let Some(record) = reader.lookup(ip)? {
+                info.with_asn_record(&record);
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for GeoIp {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Nothing to do if the plugin configuration is not present
+        let Some(config) = plugin.config.as_ref() else {
+            return Ok(false);
+        };
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            GeoIpSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let client_ip = ctx
+            .extensions
+            .get("client_ip")
+            .and_then(|ip| ip.parse::<IpAddr>().ok());
+
+        let info = match client_ip.map(|ip| self.lookup(ip, &settings)) {
+            Some(Ok(info)) => info,
+            None => GeoInfo::default(),
+            Some(Err(err)) => {
+                tracing::error!("geoip lookup failed: {err}");
+                return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        let allowed = settings.is_allowed(&info);
+        let country = info.country.as_deref().unwrap_or("unknown");
+
+        metrics::GEOIP_REQUESTS
+            .with_label_values(&[
+                ctx.host.as_str(),
+                country,
+                if allowed { "allowed" } else { "denied" },
+            ])
+            .inc();
+
+        // Used by the access logs
+        ctx.extensions
+            .insert(Cow::Borrowed("geoip_country"), country.to_string());
+        if let Some(asn) = info.asn {
+            ctx.extensions
+                .insert(Cow::Borrowed("geoip_asn"), asn.to_string());
+        }
+
+        if !allowed {
+            return respond_with_status(session, StatusCode::FORBIDDEN).await;
+        }
+
+        Ok(false)
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<GeoIpSettings> {
+        let mut config = HashMap::from([(Cow::Borrowed("database"), json!("/tmp/country.mmdb"))]);
+        for (key, value) in extra {
+            config.insert(Cow::Borrowed(*key), value.clone());
+        }
+        GeoIpSettings::from_config(&config)
+    }
+
+    fn info(country: Option<&str>, asn: Option<u64>) -> GeoInfo {
+        GeoInfo {
+            country: country.map(ToString::to_string),
+            asn,
+        }
+    }
+
+    fn settings_with_allow() -> GeoIpSettings {
+        settings(&[
+            ("allow_countries", json!(["de", "AT"])),
+            ("asn_database", json!("/tmp/asn.mmdb")),
+            ("deny_asns", json!([64512])),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_country_rules() {
+        let settings = settings(&[("deny_countries", json!(["ru", "KP"]))]).unwrap();
+        assert!(!settings.is_allowed(&info(Some("RU"), None)));
+        assert!(settings.is_allowed(&info(Some("DE"), None)));
+        assert!(settings.is_allowed(&info(None, None)));
+
+        let settings = settings_with_allow();
+        assert!(settings.is_allowed(&info(Some("DE"), Some(3320))));
+        assert!(!settings.is_allowed(&info(Some("FR"), Some(3320))));
+        assert!(!settings.is_allowed(&info(None, Some(3320))));
+    }
+
+    #[test]
+    fn test_asn_rules() {
+        let denied = settings_with_allow();
+        assert!(!denied.is_allowed(&info(Some("DE"), Some(64512))));
+
+        let settings = settings(&[
+            ("asn_database", json!("/tmp/asn.mmdb")),
+            ("allow_asns", json!([3320])),
+        ])
+        .unwrap();
+        assert!(settings.is_allowed(&info(None, Some(3320))));
+        assert!(!settings.is_allowed(&info(Some("DE"), None)));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(settings(&[("deny_asns", json!([64512]))]).is_err());
+        assert!(settings(&[("allow_countries", json!("DE"))]).is_err());
+        assert!(GeoIpSettings::from_config(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_lookup_and_reload() {
+        let dir = std::env::temp_dir().join(format!("proksi-geoip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("country.mmdb");
+
+        let record = |iso_code: Vec<u8>| {
+            let mut country = vec![(7 << 5) | 1, (2 << 5) | 8];
+            country.extend_from_slice(b"iso_code");
+            country.extend(iso_code);
+            mmdb::tests::database(&[("country", country)])
+        };
+        std::fs::write(&path, record(mmdb::tests::country_code_pointer())).unwrap();
+
+        let geoip = GeoIp::new();
+        let settings = GeoIpSettings {
+            database: Some(path.clone()),
+            reload_interval: Duration::ZERO,
+            ..settings(&[]).unwrap()
+        };
+
+        let ip = "10.0.0.1".parse().unwrap();
+        assert_eq!(geoip.lookup(ip, &settings).unwrap(), info(Some("DE"), None));
+        assert_eq!(
+            geoip
+                .lookup("200.0.0.1".parse().unwrap(), &settings)
+                .unwrap(),
+            info(None, None)
+        );
+
+        // Wait for the modification time to change
+        std::thread::sleep(Duration::from_millis(20));
+        let mut iso_code = vec![(2 << 5) | 2];
+        iso_code.extend_from_slice(b"at");
+        std::fs::write(&path, record(iso_code)).unwrap();
+
+        assert_eq!(geoip.lookup(ip, &settings).unwrap(), info(Some("AT"), None));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, StatusCode, Uri};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Extensions protected by default (images, audio and video)
+const DEFAULT_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "avif", "svg", "ico", "bmp", "mp3", "mp4", "webm", "ogg",
+];
+
+/// Content types of the placeholders, by extension
+const PLACEHOLDER_TYPES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+];
+
+/// Response sent instead of the protected asset
+#[derive(Debug)]
+struct Placeholder {
+    content_type: &'static str,
+    body: Bytes,
+}
+
+/// Per-route settings of the hotlink protection plugin
+#[derive(Debug)]
+struct HotlinkSettings {
+    /// Lowercase extensions of the protected paths (all paths if empty)
+    extensions: Vec<String>,
+    /// Prefixes of the protected paths (all paths if empty)
+    paths: Vec<String>,
+    /// Domains allowed to embed the assets (besides the route host),
+    /// `*.example.com` matches any subdomain of `example.com`
+    allowed_domains: Vec<String>,
+    /// Requests without a `Referer` (direct visits, privacy settings) are allowed
+    allow_empty: bool,
+    placeholder: Option<Placeholder>,
+}
+
+impl HotlinkSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let extensions = string_list(config, "extensions")?.map_or_else(
+            || DEFAULT_EXTENSIONS.iter().map(ToString::to_string).collect(),
+            |extensions| {
+                extensions
+                    .iter()
+                    .map(|e| e.trim_start_matches('.').to_ascii_lowercase())
+                    .collect()
+            },
+        );
+
+        let placeholder = match config.get("placeholder") {
+            Some(value) => {
+                let path = value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid placeholder"))?;
+                Some(load_placeholder(path)?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            extensions,
+            // Paths are case-sensitive
+            paths: string_list(config, "paths")?.unwrap_or_default(),
+            allowed_domains: string_list(config, "allowed_domains")?
+                .unwrap_or_default()
+                .iter()
+                .map(|domain| domain.to_ascii_lowercase())
+                .collect(),
+            allow_empty: config
+                .get("allow_empty")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(true),
+            placeholder,
+        })
+    }
+
+    fn is_protected(&self, path: &str) -> bool {
+        let in_paths =
+            self.paths.is_empty() || self.paths.iter().any(|prefix| path.starts_with(prefix));
+
+        let extension = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        let has_extension = self.extensions.is_empty()
+            || extension.is_some_and(|extension| self.extensions.contains(&extension));
+
+        in_paths && has_extension
+    }
+
+    /// Returns `true` if the request can be served
+    fn is_allowed(&self, req: &RequestHeader, host: &str) -> bool {
+        if !self.is_protected(req.uri.path()) {
+            return true;
+        }
+
+        let referer = req
+            .headers
+            .get(header::REFERER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty());
+
+        let Some(referer) = referer else {
+            return self.allow_empty;
+        };
+
+        // Unparseable referers are never allowed
+        let Some(referer_host) = referer
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_ascii_lowercase))
+        else {
+            return false;
+        };
+
+        referer_host.eq_ignore_ascii_case(host)
+            || self
+                .allowed_domains
+                .iter()
+                .any(|domain| matches_domain(domain, &referer_host))
+    }
+}
+
+fn string_list(
+    config: &HashMap<Cow<'static, str>, serde_json::Value>,
+    key: &str,
+) -> Result<Option<Vec<String>>> {
+    config
+        .get(key)
+        .map(|value| {
+            value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map(ToString::to_string))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+        })
+        .transpose()
+}
+
+fn matches_domain(domain: &str, host: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(parent) => host
+            .strip_suffix(parent)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => domain == host,
+    }
+}
+
+fn load_placeholder(path: &str) -> Result<Placeholder> {
+    let body = std::fs::read(path)
+        .map_err(|err| anyhow!("Failed to read the placeholder {path}: {err}"))?;
+
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    let content_type = PLACEHOLDER_TYPES
+        .iter()
+        .find(|(name, _)| *name == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type);
+
+    Ok(Placeholder {
+        content_type,
+        body: Bytes::from(body),
+    })
+}
+
+/// Blocks the assets of a route (images, videos, etc.) from being embedded
+/// by other websites, based on the `Referer` of the requests
+pub struct Hotlink {
+    settings: SettingsCache<HotlinkSettings>,
+}
+
+impl Hotlink {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    async fn respond_with_placeholder(
+        session: &mut Session,
+        placeholder: &Placeholder,
+    ) -> Result<bool> {
+        let mut res_headers = ResponseHeader::build_no_case(StatusCode::OK, Some(3))?;
+        res_headers.insert_header(header::CONTENT_TYPE, placeholder.content_type)?;
+        res_headers.insert_header(header::CONTENT_LENGTH, placeholder.body.len())?;
+        // The placeholder must not replace the asset in shared caches
+        res_headers.insert_header(header::CACHE_CONTROL, "no-store")?;
+
+        session
+            .write_response_header(Box::new(res_headers), false)
+            .await?;
+        session
+            .write_response_body(Some(placeholder.body.clone()), true)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Hotlink {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            HotlinkSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        if settings.is_allowed(session.req_header(), &ctx.host) {
+            return Ok(false);
+        }
+
+        tracing::debug!(
+            host = ctx.host,
+            path = session.req_header().uri.path(),
+            "hotlinked request blocked"
+        );
+
+        match settings.placeholder.as_ref() {
+            Some(placeholder) => Self::respond_with_placeholder(session, placeholder).await,
+            None => respond_with_status(session, StatusCode::FORBIDDEN).await,
+        }
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(path: &str, referer: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        if let Some(referer) = referer {
+            req.insert_header(header::REFERER, referer).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let config = HashMap::from([(
+            Cow::Borrowed("allowed_domains"),
+            json!(["partner.com", "*.Friends.org"]),
+        )]);
+        let settings = HotlinkSettings::from_config(&config).unwrap();
+        let allowed = |path, referer| settings.is_allowed(&request(path, referer), "mysite.com");
+
+        assert!(allowed("/img/cat.PNG", None));
+        assert!(allowed("/img/cat.png", Some("https://mysite.com/blog")));
+        assert!(allowed("/img/cat.png", Some("https://MySite.com:8443/")));
+        assert!(allowed("/img/cat.png", Some("https://partner.com/")));
+        assert!(allowed(
+            "/img/cat.png",
+            Some("https://blog.friends.org/post")
+        ));
+        assert!(!allowed("/img/cat.png", Some("https://friends.org/")));
+        assert!(!allowed("/img/cat.png", Some("https://notfriends.org/")));
+        assert!(!allowed(
+            "/img/cat.png",
+            Some("https://evil.com/?mysite.com")
+        ));
+        assert!(!allowed("/img/cat.png", Some("not a url")));
+
+        // Pages are not protected
+        assert!(allowed("/blog/post", Some("https://evil.com/")));
+        assert!(allowed("/img.d/readme", Some("https://evil.com/")));
+    }
+
+    #[test]
+    fn test_paths_and_empty_referers() {
+        let config = HashMap::from([
+            (Cow::Borrowed("paths"), json!(["/media/"])),
+            (Cow::Borrowed("extensions"), json!([])),
+            (Cow::Borrowed("allow_empty"), json!(false)),
+        ]);
+        let settings = HotlinkSettings::from_config(&config).unwrap();
+        let allowed = |path, referer| settings.is_allowed(&request(path, referer), "mysite.com");
+
+        assert!(!allowed("/media/video", None));
+        assert!(!allowed("/media/cat.png", Some("")));
+        assert!(allowed("/media/video", Some("https://mysite.com/")));
+        assert!(allowed("/img/cat.png", None));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = HashMap::from([(Cow::Borrowed("placeholder"), json!("/missing.png"))]);
+        assert!(HotlinkSettings::from_config(&config).is_err());
+
+        let config = HashMap::from([(Cow::Borrowed("allowed_domains"), json!("partner.com"))]);
+        assert!(HotlinkSettings::from_config(&config).is_err());
+    }
+}
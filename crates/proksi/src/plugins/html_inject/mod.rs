@@ -0,0 +1,207 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use http::header;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{
+    config::RoutePlugin,
+    proxy_server::{compression::has_content_type, https_proxy::RouterContext},
+};
+
+use super::{
+    response_rewrite::{is_rewritable, prepare_response, BodyRewriter, RewriteRule},
+    settings_cache::SettingsCache,
+    MiddlewarePlugin,
+};
+
+/// Configuration keys of the fragments, with the tag they are inserted before
+const INJECTION_POINTS: &[(&str, &str)] = &[("head", "</head>"), ("body", "</body>")];
+
+/// Per-route settings of the HTML injection plugin
+#[derive(Debug)]
+struct HtmlInjectSettings {
+    rules: Vec<Arc<RewriteRule>>,
+}
+
+impl HtmlInjectSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let mut rules = vec![];
+
+        for (key, tag) in INJECTION_POINTS {
+            let Some(fragment) = config.get(*key) else {
+                continue;
+            };
+
+            let fragment = fragment
+                .as_str()
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))?;
+
+            if !fragment.is_empty() {
+                rules.push(Arc::new(RewriteRule::insert_before(tag, fragment)?));
+            }
+        }
+
+        if rules.is_empty() {
+            bail!("At least one of head or body is required");
+        }
+
+        Ok(Self { rules })
+    }
+}
+
+/// Injects HTML fragments (analytics snippets, banners, etc.) in the HTML responses of a route
+pub struct HtmlInject {
+    settings: SettingsCache<HtmlInjectSettings>,
+}
+
+impl HtmlInject {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for HtmlInject {
+    // Nothing to do before the upstream request
+    async fn request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        // Compressed pages can't be modified (responses can still be compressed by Proksi)
+        upstream_request.remove_header(&header::ACCEPT_ENCODING);
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(plugin) = ctx.route_container.plugins.get("html_inject") else {
+            return Ok(());
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let settings = self
+            .settings
+            .get_or_try_build(
+                ctx.route_container.id,
+                config,
+                HtmlInjectSettings::from_config,
+            )
+            .inspect_err(|err| {
+                tracing::error!("invalid html_inject plugin configuration: {err}");
+            })?;
+
+        if !is_rewritable(session.req_header(), upstream_response)
+            || !has_content_type(upstream_response, &["text/html"])
+        {
+            return Ok(());
+        }
+
+        prepare_response(upstream_response)?;
+        ctx.body_rewriter
+            .get_or_insert_with(BodyRewriter::default)
+            .add_rules(&settings.rules);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<HtmlInjectSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        HtmlInjectSettings::from_config(&config)
+    }
+
+    fn inject(settings: &HtmlInjectSettings, page: &str, chunk_size: usize) -> String {
+        let mut rewriter = BodyRewriter::default();
+        rewriter.add_rules(&settings.rules);
+
+        let mut output = vec![];
+        for chunk in page.as_bytes().chunks(chunk_size) {
+            output.extend(rewriter.rewrite(chunk, false));
+        }
+        output.extend(rewriter.rewrite(&[], true));
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_inject_fragments() {
+        let settings = settings(&[
+            (
+                "head",
+                json!("<script src=\"/a.js\" data-price=\"$1\"></script>"),
+            ),
+            ("body", json!("<div id=\"banner\"></div>")),
+        ])
+        .unwrap();
+
+        let page = "<html><HEAD><title>t</title></HEAD><body><p>hi</p></Body></html>";
+        let expected = "<html><HEAD><title>t</title>\
+                        <script src=\"/a.js\" data-price=\"$1\"></script></HEAD>\
+                        <body><p>hi</p><div id=\"banner\"></div></Body></html>";
+
+        for size in [1, 2, 5, 13, 1024] {
+            assert_eq!(inject(&settings, page, size), expected);
+        }
+    }
+
+    #[test]
+    fn test_inject_once() {
+        let settings = settings(&[("body", json!("<footer></footer>"))]).unwrap();
+
+        assert_eq!(
+            inject(&settings, "<body></body><template></body></template>", 4),
+            "<body><footer></footer></body><template></body></template>"
+        );
+        // Pages without the tag are left untouched
+        assert_eq!(inject(&settings, "<p>partial", 3), "<p>partial");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(settings(&[]).is_err());
+        assert!(settings(&[("head", json!(""))]).is_err());
+        assert!(settings(&[("body", json!(["<div>"]))]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use jsonwebtoken::jwk::JwkSet;
+use once_cell::sync::Lazy;
+
+/// HTTP client used to fetch the JWKS documents
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default()
+});
+
+/// Cached JWKS documents, keyed by their URI
+static JWKS_CACHE: Lazy<papaya::HashMap<String, Arc<CachedJwks>>> = Lazy::new(papaya::HashMap::new);
+
+/// Minimum time between two forced refreshes (when a token references an unknown key)
+const MIN_FORCED_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A JWKS document along with the time it was fetched
+pub(crate) struct CachedJwks {
+    pub keys: JwkSet,
+    fetched_at: Instant,
+    refreshing: AtomicBool,
+}
+
+impl CachedJwks {
+    fn new(keys: JwkSet) -> Self {
+        Self {
+            keys,
+            fetched_at: Instant::now(),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Fetches a JWKS document from the given URI and stores it in the cache
+async fn fetch(uri: &str) -> Result<Arc<CachedJwks>> {
+    let keys = HTTP_CLIENT
+        .get(uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?;
+
+    let cached = Arc::new(CachedJwks::new(keys));
+    JWKS_CACHE.pin().insert(uri.to_string(), cached.clone());
+
+    Ok(cached)
+}
+
+/// Returns the JWKS for the given URI.
+///
+/// The first request for a URI waits for the document to be fetched. After that,
+/// the cached document is always returned and, once it is older than `refresh_interval`,
+/// a new version is fetched in the background.
+pub(crate) async fn get(uri: &str, refresh_interval: Duration) -> Result<Arc<CachedJwks>> {
+    let cached = JWKS_CACHE.pin().get(uri).cloned();
+    let Some(cached) = cached else {
+        return fetch(uri).await;
+    };
+
+    if cached.fetched_at.elapsed() >= refresh_interval
+        && !cached.refreshing.swap(true, Ordering::AcqRel)
+    {
+        let uri = uri.to_string();
+        let stale = cached.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = fetch(&uri).await {
+                tracing::error!("failed to refresh JWKS from {uri}: {err}");
+                // allow the next request to try again
+                stale.refreshing.store(false, Ordering::Release);
+            }
+        });
+    }
+
+    Ok(cached)
+}
+
+/// Fetches the JWKS again (e.g. a token was signed by a key that was rotated in) unless
+/// the cached version is very recent.
+pub(crate) async fn refresh(uri: &str) -> Result<Arc<CachedJwks>> {
+    let cached = JWKS_CACHE.pin().get(uri).cloned();
+    match cached {
+        Some(cached) if cached.fetched_at.elapsed() < MIN_FORCED_REFRESH_INTERVAL => Ok(cached),
+        _ => fetch(uri).await,
+    }
+}
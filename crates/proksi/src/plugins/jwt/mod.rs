@@ -1,11 +1,32 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
+    str::FromStr,
     time::{Duration, SystemTime},
 };
 
-use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header, Validation};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use jsonwebtoken::{encode, jwk::JwkSet, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{get_required_config, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+pub(super) mod jwks;
+
+/// Prefix of the context extensions holding the headers forwarded to the upstream
+const CLAIM_HEADER_PREFIX: &str = "jwt_claim_header:";
+
+/// How often (in seconds) the JWKS is refreshed by default
+const DEFAULT_REFRESH_INTERVAL: u64 = 300;
+
 /// Struct that holds the claims for a JWT token
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct JwtClaims {
@@ -54,10 +75,400 @@ pub(crate) fn decode_jwt(token: &str, secret: &[u8]) -> Result<JwtClaims, anyhow
     Ok(data.claims)
 }
 
+/// Per-route settings of the JWT plugin
+struct JwtSettings {
+    jwks_uri: String,
+    issuer: Option<Vec<String>>,
+    audience: Option<Vec<String>>,
+    algorithms: Vec<Algorithm>,
+    /// (claim, header) pairs forwarded to the upstream
+    forward_claims: Vec<(String, String)>,
+    refresh_interval: Duration,
+    leeway: u64,
+}
+
+impl JwtSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let jwks_uri = get_required_config(config, "jwks_uri")?;
+
+        let algorithms = match config.get("algorithms") {
+            Some(value) => string_list(value)
+                .ok_or_else(|| anyhow!("Missing or invalid algorithms"))?
+                .iter()
+                .map(|alg| Algorithm::from_str(alg))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![Algorithm::RS256],
+        };
+
+        let forward_claims = match config.get("forward_claims") {
+            Some(serde_json::Value::Object(claims)) => claims
+                .iter()
+                .filter_map(|(claim, header)| {
+                    Some((claim.clone(), header.as_str()?.to_ascii_lowercase()))
+                })
+                .collect(),
+            Some(_) => return Err(anyhow!("Missing or invalid forward_claims")),
+            None => vec![],
+        };
+
+        let refresh_interval = config
+            .get("refresh_interval")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+
+        Ok(Self {
+            jwks_uri,
+            issuer: config.get("issuer").and_then(string_list),
+            audience: config.get("audience").and_then(string_list),
+            algorithms,
+            forward_claims,
+            refresh_interval: Duration::from_secs(refresh_interval),
+            leeway: config
+                .get("leeway")
+                .and_then(serde_json::Value::as_u64)
+                .unwrap_or(60),
+        })
+    }
+}
+
+/// Reads a config value that can either be a single string or a list of strings
+fn string_list(value: &serde_json::Value) -> Option<Vec<String>> {
+    match value {
+        serde_json::Value::String(s) => Some(vec![s.clone()]),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|v| v.as_str().map(ToString::to_string))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Looks up a (possibly nested, dot separated) claim and formats it as a header value
+fn claim_to_header_value(
+    claims: &serde_json::Map<String, serde_json::Value>,
+    claim: &str,
+) -> Option<String> {
+    let mut parts = claim.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Array(values) => Some(
+            values
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map_or_else(|| v.to_string(), ToString::to_string)
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        serde_json::Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Validates a token against the keys of a JWKS.
+/// Returns `Ok(None)` if none of the keys can be used to verify the token.
+fn validate_token(
+    token: &str,
+    jwks: &JwkSet,
+    settings: &JwtSettings,
+) -> Result<Option<serde_json::Map<String, serde_json::Value>>> {
+    let header = jsonwebtoken::decode_header(token)?;
+
+    if !settings.algorithms.contains(&header.alg) {
+        return Err(anyhow!("algorithm {:?} is not allowed", header.alg));
+    }
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+
+    let Some(jwk) = jwk else {
+        return Ok(None);
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = settings.leeway;
+    if let Some(issuer) = &settings.issuer {
+        validation.set_issuer(issuer);
+    }
+    if let Some(audience) = &settings.audience {
+        validation.set_audience(audience);
+    } else {
+        validation.validate_aud = false;
+    }
+
+    let data = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+        token,
+        &DecodingKey::from_jwk(jwk)?,
+        &validation,
+    )?;
+
+    Ok(Some(data.claims))
+}
+
+/// Validates `Authorization: Bearer` tokens against the keys published by
+/// the configured JWKS endpoint
+pub struct Jwt {
+    settings: SettingsCache<JwtSettings>,
+}
+
+impl Jwt {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    /// Returns a 401 indicating to downstream that a valid bearer token is required
+    async fn unauthorized_response(session: &mut Session, host: &str) -> Result<bool> {
+        let mut res_headers = ResponseHeader::build_no_case(StatusCode::UNAUTHORIZED, Some(1))?;
+        let challenge = format!("Bearer realm=\"{host}\", error=\"invalid_token\"");
+        res_headers.insert_header(header::WWW_AUTHENTICATE, &challenge)?;
+
+        session
+            .write_response_header(Box::new(res_headers), true)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Validates the token, fetching the JWKS again if the token was signed
+    /// by a key we don't know about yet
+    async fn validate(
+        token: &str,
+        settings: &JwtSettings,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let cached = jwks::get(&settings.jwks_uri, settings.refresh_interval).await?;
+        if let Some(claims) = validate_token(token, &cached.keys, settings)? {
+            return Ok(claims);
+        }
+
+        let refreshed = jwks::refresh(&settings.jwks_uri).await?;
+        validate_token(token, &refreshed.keys, settings)?
+            .ok_or_else(|| anyhow!("no matching key found in the JWKS"))
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Jwt {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Nothing to do if the plugin configuration is not present
+        let Some(config) = plugin.config.as_ref() else {
+            return Ok(false);
+        };
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            JwtSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let token = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(ToString::to_string);
+
+        let Some(token) = token else {
+            return Self::unauthorized_response(session, &ctx.host).await;
+        };
+
+        let claims = match Self::validate(token.trim(), &settings).await {
+            Ok(claims) => claims,
+            Err(err) => {
+                tracing::info!("invalid JWT: {err}");
+                return Self::unauthorized_response(session, &ctx.host).await;
+            }
+        };
+
+        // Headers are always registered (even if the claim is missing) so that
+        // downstream can't inject its own values
+        for (claim, header) in &settings.forward_claims {
+            let value = claim_to_header_value(&claims, claim).unwrap_or_default();
+            ctx.extensions
+                .insert(Cow::Owned(format!("{CLAIM_HEADER_PREFIX}{header}")), value);
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        for (key, value) in &ctx.extensions {
+            let Some(header) = key.strip_prefix(CLAIM_HEADER_PREFIX) else {
+                continue;
+            };
+
+            upstream_request.remove_header(header);
+            if !value.is_empty() {
+                upstream_request.insert_header(header.to_string(), value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use serde_json::json;
+
     use super::*;
 
+    fn test_jwks() -> JwkSet {
+        serde_json::from_value(json!({
+            "keys": [{ "kty": "oct", "kid": "key-1", "alg": "HS256", "k": "c2VjcmV0" }]
+        }))
+        .unwrap()
+    }
+
+    fn test_settings() -> JwtSettings {
+        let config = HashMap::from([
+            (
+                Cow::Borrowed("jwks_uri"),
+                json!("https://example.com/jwks.json"),
+            ),
+            (Cow::Borrowed("issuer"), json!("https://issuer.example.com")),
+            (Cow::Borrowed("audience"), json!(["api"])),
+            (Cow::Borrowed("algorithms"), json!(["HS256"])),
+            (
+                Cow::Borrowed("forward_claims"),
+                json!({ "sub": "X-User-Id", "org.roles": "x-user-roles" }),
+            ),
+        ]);
+        JwtSettings::from_config(&config).unwrap()
+    }
+
+    fn sign(claims: &serde_json::Value, kid: Option<&str>) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = kid.map(ToString::to_string);
+        encode(&header, claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    fn valid_claims() -> serde_json::Value {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        json!({
+            "sub": "user-1",
+            "iss": "https://issuer.example.com",
+            "aud": "api",
+            "exp": now + 60,
+            "org": { "roles": ["admin", "dev"] }
+        })
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let settings = test_settings();
+        assert_eq!(settings.algorithms, vec![Algorithm::HS256]);
+        assert_eq!(settings.audience, Some(vec!["api".to_string()]));
+        assert!(settings
+            .forward_claims
+            .contains(&("sub".to_string(), "x-user-id".to_string())));
+        assert_eq!(settings.refresh_interval, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_validate_token() {
+        let settings = test_settings();
+        let token = sign(&valid_claims(), Some("key-1"));
+
+        let claims = validate_token(&token, &test_jwks(), &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            claim_to_header_value(&claims, "sub").as_deref(),
+            Some("user-1")
+        );
+        assert_eq!(
+            claim_to_header_value(&claims, "org.roles").as_deref(),
+            Some("admin,dev")
+        );
+        assert_eq!(claim_to_header_value(&claims, "missing"), None);
+    }
+
+    #[test]
+    fn test_validate_token_unknown_key() {
+        let token = sign(&valid_claims(), Some("key-2"));
+        assert!(validate_token(&token, &test_jwks(), &test_settings())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_validate_token_wrong_audience_and_issuer() {
+        let settings = test_settings();
+
+        let mut claims = valid_claims();
+        claims["aud"] = json!("other");
+        let token = sign(&claims, Some("key-1"));
+        assert!(validate_token(&token, &test_jwks(), &settings).is_err());
+
+        let mut claims = valid_claims();
+        claims["iss"] = json!("https://evil.example.com");
+        let token = sign(&claims, Some("key-1"));
+        assert!(validate_token(&token, &test_jwks(), &settings).is_err());
+    }
+
+    #[test]
+    fn test_validate_token_disallowed_algorithm() {
+        let config = HashMap::from([(
+            Cow::Borrowed("jwks_uri"),
+            json!("https://example.com/jwks.json"),
+        )]);
+        let settings = JwtSettings::from_config(&config).unwrap();
+
+        let token = sign(&valid_claims(), Some("key-1"));
+        assert!(validate_token(&token, &test_jwks(), &settings).is_err());
+    }
+
     #[test]
     fn test_encode_jwt_with_secret() {
         let token = encode_jwt("test", b"secret");
@@ -0,0 +1,289 @@
+//! Lua scripts running at the request and response phases of a route, built with the `lua`
+//! feature. Without it, the requests of the routes with the plugin receive a `500`.
+
+use std::{borrow::Cow, collections::HashMap, fs, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+#[cfg(feature = "lua")]
+mod script;
+
+/// Time a hook of a script can run for by default, in milliseconds
+const DEFAULT_TIMEOUT_MS: u64 = 10;
+
+/// Per-route settings of the lua plugin
+#[cfg_attr(not(feature = "lua"), allow(dead_code))]
+#[derive(Debug)]
+struct LuaSettings {
+    /// Path of the script, or `source` when it's inline
+    name: String,
+    source: String,
+    /// Time a hook can run for before it's stopped
+    timeout: Duration,
+}
+
+impl LuaSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        if cfg!(not(feature = "lua")) {
+            bail!("proksi was built without the lua feature");
+        }
+
+        let (name, source) = match (config.get("script"), config.get("source")) {
+            (Some(_), Some(_)) => bail!("only one of script and source can be set"),
+            (Some(path), None) => {
+                let path = path
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid script"))?;
+                let source = fs::read_to_string(path)
+                    .map_err(|err| anyhow!("failed to read the script {path}: {err}"))?;
+                (path.to_string(), source)
+            }
+            (None, Some(source)) => (
+                "source".to_string(),
+                source
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid source"))?
+                    .to_string(),
+            ),
+            (None, None) => bail!("Missing or invalid script"),
+        };
+
+        let timeout = match config.get("timeout_ms") {
+            Some(timeout) => timeout
+                .as_u64()
+                .filter(|timeout| *timeout > 0)
+                .ok_or_else(|| anyhow!("Missing or invalid timeout_ms"))?,
+            None => DEFAULT_TIMEOUT_MS,
+        };
+
+        #[cfg(feature = "lua")]
+        script::compile(&name, &source)?;
+
+        Ok(Self {
+            name,
+            source,
+            timeout: Duration::from_millis(timeout),
+        })
+    }
+}
+
+/// Runs the `on_request` and `on_response` functions of a Lua script, for the edge logic
+/// of a route (headers, upstream, responses) that doesn't need a new build of proksi
+pub struct LuaHooks {
+    settings: SettingsCache<LuaSettings>,
+}
+
+impl LuaHooks {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+impl LuaHooks {
+    async fn on_request(
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        settings: &Arc<LuaSettings>,
+    ) -> Result<bool> {
+        use bytes::Bytes;
+        use http::{header, StatusCode};
+
+        use super::respond_with_status;
+
+        let backends = ctx.route_container.load_balancer.backends().get_backend();
+        let request = script::Request::new(
+            session.req_header(),
+            &ctx.host,
+            ctx.extensions.get("client_ip").cloned(),
+            backends.iter().map(|b| b.addr.to_string()).collect(),
+        );
+
+        // A script that fails doesn't let the request through
+        let (request, reply) = match script::on_request(ctx.route_container.id, settings, request) {
+            Ok(done) => done,
+            Err(err) => {
+                tracing::error!("lua script {} failed: {err}", settings.name);
+                return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        request.headers.apply_to_request(session.req_header_mut())?;
+        if let Some(upstream) = request.upstream {
+            ctx.extensions
+                .insert(Cow::Borrowed("lua_upstream"), upstream);
+        }
+
+        let Some((status, body)) = reply else {
+            return Ok(false);
+        };
+        let mut res_headers = ResponseHeader::build_no_case(status, Some(2))?;
+        res_headers.insert_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")?;
+        res_headers.insert_header(header::CONTENT_LENGTH, body.len())?;
+        session
+            .write_response_header(Box::new(res_headers), body.is_empty())
+            .await?;
+        if !body.is_empty() {
+            session
+                .write_response_body(Some(Bytes::from(body)), true)
+                .await?;
+        }
+        Ok(true)
+    }
+
+    fn on_response(
+        ctx: &RouterContext,
+        settings: &Arc<LuaSettings>,
+        upstream_response: &mut ResponseHeader,
+    ) -> Result<()> {
+        let response = script::Response::new(upstream_response);
+        let response = script::on_response(ctx.route_container.id, settings, response)
+            .inspect_err(|err| tracing::error!("lua script {} failed: {err}", settings.name))?;
+
+        upstream_response.set_status(response.status)?;
+        response.headers.apply_to_response(upstream_response)
+    }
+}
+
+// The settings are never built without the lua feature
+#[cfg(not(feature = "lua"))]
+impl LuaHooks {
+    async fn on_request(
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &Arc<LuaSettings>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn on_response(_: &RouterContext, _: &Arc<LuaSettings>, _: &mut ResponseHeader) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for LuaHooks {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            LuaSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        Self::on_request(session, ctx, &settings).await
+    }
+
+    // The headers changed by `on_request` are already in the request
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(plugin) = ctx.route_container.plugins.get("lua") else {
+            return Ok(());
+        };
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+        let settings = self.settings.get_or_try_build(
+            ctx.route_container.id,
+            config,
+            LuaSettings::from_config,
+        )?;
+
+        Self::on_response(ctx, &settings, upstream_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(config: &[(&'static str, serde_json::Value)]) -> Result<LuaSettings> {
+        let config = config
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        LuaSettings::from_config(&config)
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(settings(&[]).is_err());
+        assert!(settings(&[("source", json!(1))]).is_err());
+        assert!(settings(&[("script", json!("/nonexistent/proksi.lua"))]).is_err());
+        assert!(settings(&[("source", json!("x = 1")), ("script", json!("a.lua"))]).is_err());
+        assert!(settings(&[("source", json!("x = 1")), ("timeout_ms", json!(0))]).is_err());
+        // Syntax errors are found with the configuration
+        assert!(settings(&[("source", json!("function on_request(req)"))]).is_err());
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn test_settings() {
+        let inline = settings(&[("source", json!("x = 1")), ("timeout_ms", json!(50))]).unwrap();
+        assert_eq!(inline.name, "source");
+        assert_eq!(inline.timeout, Duration::from_millis(50));
+
+        let path = std::env::temp_dir().join(format!("{}.lua", uuid::Uuid::new_v4()));
+        fs::write(&path, "x = 1").unwrap();
+        let script = settings(&[("script", json!(path.to_str().unwrap()))]).unwrap();
+        assert_eq!(script.name, path.to_str().unwrap());
+        assert_eq!(script.source, "x = 1");
+        assert_eq!(script.timeout, Duration::from_millis(DEFAULT_TIMEOUT_MS));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(not(feature = "lua"))]
+    #[test]
+    fn test_without_feature() {
+        let err = settings(&[("source", json!("x = 1"))]).unwrap_err();
+        assert_eq!(err.to_string(), "proksi was built without the lua feature");
+    }
+}
@@ -0,0 +1,602 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use mlua::{
+    FromLua, Function, HookTriggers, IntoLua, Lua, LuaOptions, StdLib, UserData, UserDataFields,
+    UserDataMethods, Value, VmState,
+};
+use once_cell::sync::Lazy;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::stores::expiring::ExpiringMap;
+
+use super::LuaSettings;
+
+/// Memory a Lua state can allocate
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Instructions run between two checks of the time a hook ran for
+const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+
+/// Entries of the shared dictionary expire after an hour by default, in seconds
+const DEFAULT_SHARED_TTL: u64 = 3600;
+
+/// The dictionary shared by the scripts of every route and thread (`proksi.shared`)
+static SHARED: Lazy<ExpiringMap<SharedValue>> = Lazy::new(ExpiringMap::new);
+
+thread_local! {
+    /// The Lua states of the scripts on this thread, by route. The hooks of a route run one
+    /// at a time in the state of the thread.
+    static STATES: RefCell<HashMap<u64, State>> = RefCell::new(HashMap::new());
+}
+
+struct State {
+    settings: Weak<LuaSettings>,
+    lua: Lua,
+}
+
+/// When the running hook is stopped
+struct Deadline(Instant);
+
+/// The response a script sends instead of the upstream: its status and body
+pub type Reply = (StatusCode, Vec<u8>);
+
+/// Compiles the script, so that its syntax errors are found with the configuration
+pub fn compile(name: &str, source: &str) -> Result<()> {
+    Lua::new_with(StdLib::NONE, LuaOptions::default())?
+        .load(source)
+        .set_name(format!("@{name}"))
+        .into_function()?;
+    Ok(())
+}
+
+/// A state running the script, sandboxed: no files, processes or bytecode, and bounded in
+/// memory and time
+fn new_state(settings: &LuaSettings) -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+
+    let globals = lua.globals();
+    for name in ["dofile", "loadfile", "load", "print"] {
+        globals.raw_remove(name)?;
+    }
+
+    let proksi = lua.create_table()?;
+    proksi.set("shared", SharedDict)?;
+    let name = settings.name.clone();
+    proksi.set(
+        "log",
+        lua.create_function(move |_, message: String| {
+            tracing::info!(script = name, "{message}");
+            Ok(())
+        })?,
+    )?;
+    globals.set("proksi", proksi)?;
+
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK),
+        |lua, _| match lua.app_data_ref::<Deadline>() {
+            Some(deadline) if Instant::now() >= deadline.0 => {
+                Err(mlua::Error::runtime("the script ran out of time"))
+            }
+            _ => Ok(VmState::Continue),
+        },
+    );
+
+    lua.set_app_data(Deadline(Instant::now() + settings.timeout));
+    lua.load(&settings.source)
+        .set_name(format!("@{}", settings.name))
+        .exec()?;
+    Ok(lua)
+}
+
+/// Runs `run` in the state of the route on this thread, created for each version of the
+/// route, within the time budget of a hook
+fn with_state<R>(
+    route_id: u64,
+    settings: &Arc<LuaSettings>,
+    run: impl FnOnce(&Lua) -> mlua::Result<R>,
+) -> Result<R> {
+    STATES.with_borrow_mut(|states| {
+        let current = states
+            .get(&route_id)
+            .is_some_and(|state| state.settings.as_ptr() == Arc::as_ptr(settings));
+        if !current {
+            // The states of the routes that changed or were removed are dropped
+            states.retain(|_, state| state.settings.strong_count() > 0);
+            let state = State {
+                settings: Arc::downgrade(settings),
+                lua: new_state(settings)?,
+            };
+            states.insert(route_id, state);
+        }
+
+        let lua = &states[&route_id].lua;
+        lua.set_app_data(Deadline(Instant::now() + settings.timeout));
+        Ok(run(lua)?)
+    })
+}
+
+/// Runs `on_request(req)`, returns the request changed by the script and the response it
+/// sent instead of the upstream, when it returned a status (and a body)
+pub fn on_request(
+    route_id: u64,
+    settings: &Arc<LuaSettings>,
+    request: Request,
+) -> Result<(Request, Option<Reply>)> {
+    with_state(route_id, settings, |lua| {
+        let Some(hook) = lua.globals().get::<Option<Function>>("on_request")? else {
+            return Ok((request, None));
+        };
+
+        let request = lua.create_userdata(request)?;
+        let (status, body): (Option<u16>, Option<mlua::String>) = hook.call(&request)?;
+        let reply = status
+            .map(|status| {
+                let status = StatusCode::from_u16(status).map_err(mlua::Error::external)?;
+                let body = body.map(|body| body.as_bytes().to_vec());
+                Ok::<_, mlua::Error>((status, body.unwrap_or_default()))
+            })
+            .transpose()?;
+        Ok((request.take()?, reply))
+    })
+}
+
+/// Runs `on_response(res)`, returns the response changed by the script
+pub fn on_response(
+    route_id: u64,
+    settings: &Arc<LuaSettings>,
+    response: Response,
+) -> Result<Response> {
+    with_state(route_id, settings, |lua| {
+        let Some(hook) = lua.globals().get::<Option<Function>>("on_response")? else {
+            return Ok(response);
+        };
+
+        let response = lua.create_userdata(response)?;
+        hook.call::<()>(&response)?;
+        response.take()
+    })
+}
+
+/// Headers of a request or response, and the changes of the script to apply to it
+pub struct Headers {
+    map: HeaderMap,
+    changes: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl Headers {
+    fn new(map: &HeaderMap) -> Self {
+        Self {
+            map: map.clone(),
+            changes: vec![],
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        let value = self.map.get(name)?;
+        value.to_str().ok().map(ToString::to_string)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> mlua::Result<()> {
+        let name = HeaderName::from_str(name).map_err(mlua::Error::external)?;
+        let value = HeaderValue::from_str(value).map_err(mlua::Error::external)?;
+        self.map.insert(name.clone(), value.clone());
+        self.changes.push((name, Some(value)));
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> mlua::Result<()> {
+        let name = HeaderName::from_str(name).map_err(mlua::Error::external)?;
+        self.map.remove(&name);
+        self.changes.push((name, None));
+        Ok(())
+    }
+
+    pub fn apply_to_request(self, req: &mut RequestHeader) -> Result<()> {
+        for (name, value) in self.changes {
+            match value {
+                Some(value) => req.insert_header(name, value)?,
+                None => drop(req.remove_header(&name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_to_response(self, res: &mut ResponseHeader) -> Result<()> {
+        for (name, value) in self.changes {
+            match value {
+                Some(value) => res.insert_header(name, value)?,
+                None => drop(res.remove_header(&name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `header(name)`, `set_header(name, value)` and `remove_header(name)` of the requests and
+/// responses
+fn add_header_methods<T: 'static, M: UserDataMethods<T>>(
+    methods: &mut M,
+    headers: fn(&mut T) -> &mut Headers,
+) {
+    methods.add_method_mut("header", move |_, this, name: String| {
+        Ok(headers(this).get(&name))
+    });
+    methods.add_method_mut(
+        "set_header",
+        move |_, this, (name, value): (String, String)| headers(this).set(&name, &value),
+    );
+    methods.add_method_mut("remove_header", move |_, this, name: String| {
+        headers(this).remove(&name)
+    });
+}
+
+/// The request given to `on_request`
+pub struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    host: String,
+    client_ip: Option<String>,
+    /// Addresses of the upstreams of the route
+    upstreams: Vec<String>,
+    pub headers: Headers,
+    /// Upstream the script sent the request to
+    pub upstream: Option<String>,
+}
+
+impl Request {
+    pub fn new(
+        req: &RequestHeader,
+        host: &str,
+        client_ip: Option<String>,
+        upstreams: Vec<String>,
+    ) -> Self {
+        Self {
+            method: req.method.to_string(),
+            path: req.uri.path().to_string(),
+            query: req.uri.query().map(ToString::to_string),
+            host: host.to_string(),
+            client_ip,
+            upstreams,
+            headers: Headers::new(&req.headers),
+            upstream: None,
+        }
+    }
+}
+
+impl UserData for Request {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("method", |_, req| Ok(req.method.clone()));
+        fields.add_field_method_get("path", |_, req| Ok(req.path.clone()));
+        fields.add_field_method_get("query", |_, req| Ok(req.query.clone()));
+        fields.add_field_method_get("host", |_, req| Ok(req.host.clone()));
+        fields.add_field_method_get("client_ip", |_, req| Ok(req.client_ip.clone()));
+        fields.add_field_method_get("upstreams", |_, req| Ok(req.upstreams.clone()));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        add_header_methods(methods, |req| &mut req.headers);
+        methods.add_method_mut("set_upstream", |_, req, address: String| {
+            if !req.upstreams.contains(&address) {
+                return Err(mlua::Error::runtime(format!(
+                    "{address} is not an upstream of the route"
+                )));
+            }
+            req.upstream = Some(address);
+            Ok(())
+        });
+    }
+}
+
+/// The response of the upstream given to `on_response`
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+impl Response {
+    pub fn new(res: &ResponseHeader) -> Self {
+        Self {
+            status: res.status,
+            headers: Headers::new(&res.headers),
+        }
+    }
+}
+
+impl UserData for Response {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("status", |_, res| Ok(res.status.as_u16()));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        add_header_methods(methods, |res| &mut res.headers);
+        methods.add_method_mut("set_status", |_, res, status: u16| {
+            res.status = StatusCode::from_u16(status).map_err(mlua::Error::external)?;
+            Ok(())
+        });
+    }
+}
+
+/// A value of the shared dictionary
+#[derive(Clone, Debug, PartialEq)]
+enum SharedValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(Vec<u8>),
+}
+
+impl FromLua for SharedValue {
+    fn from_lua(value: Value, _: &Lua) -> mlua::Result<Self> {
+        match value {
+            Value::Boolean(value) => Ok(Self::Boolean(value)),
+            Value::Integer(value) => Ok(Self::Integer(value)),
+            Value::Number(value) => Ok(Self::Number(value)),
+            Value::String(value) => Ok(Self::String(value.as_bytes().to_vec())),
+            value => Err(mlua::Error::runtime(format!(
+                "a {} can't be shared",
+                value.type_name()
+            ))),
+        }
+    }
+}
+
+impl IntoLua for SharedValue {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<Value> {
+        Ok(match self {
+            Self::Boolean(value) => Value::Boolean(value),
+            Self::Integer(value) => Value::Integer(value),
+            Self::Number(value) => Value::Number(value),
+            Self::String(value) => Value::String(lua.create_string(value)?),
+        })
+    }
+}
+
+fn shared_ttl(ttl: Option<u64>) -> Duration {
+    Duration::from_secs(ttl.unwrap_or(DEFAULT_SHARED_TTL))
+}
+
+/// `proksi.shared`: `get(key)`, `set(key, value, ttl)`, `incr(key, by, ttl)` and
+/// `delete(key)`. The TTLs are in seconds.
+struct SharedDict;
+
+impl UserData for SharedDict {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get", |_, _, key: String| Ok(SHARED.get(&key)));
+        methods.add_method(
+            "set",
+            |_, _, (key, value, ttl): (String, Option<SharedValue>, Option<u64>)| {
+                match value {
+                    Some(value) => SHARED.insert(key, value, shared_ttl(ttl)),
+                    None => SHARED.remove(&key),
+                }
+                Ok(())
+            },
+        );
+        // Counters keep their expiry, only a new counter expires after `ttl`
+        methods.add_method(
+            "incr",
+            |_, _, (key, by, ttl): (String, Option<i64>, Option<u64>)| {
+                let by = by.unwrap_or(1);
+                let value = SHARED.update(key, shared_ttl(ttl), |value| match value {
+                    None => Some(SharedValue::Integer(by)),
+                    Some(SharedValue::Integer(value)) => {
+                        Some(SharedValue::Integer(value.saturating_add(by)))
+                    }
+                    Some(_) => None,
+                });
+                match value {
+                    Some(SharedValue::Integer(value)) => Ok(value),
+                    _ => Err(mlua::Error::runtime("the value is not an integer")),
+                }
+            },
+        );
+        methods.add_method("delete", |_, _, key: String| {
+            SHARED.remove(&key);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(source: &str) -> Arc<LuaSettings> {
+        Arc::new(LuaSettings {
+            name: "test.lua".to_string(),
+            source: source.to_string(),
+            timeout: Duration::from_millis(50),
+        })
+    }
+
+    fn request() -> Request {
+        let mut req = RequestHeader::build("GET", b"/api/items?page=2", None).unwrap();
+        req.insert_header("x-tier", "free").unwrap();
+        req.insert_header("x-debug", "1").unwrap();
+        Request::new(
+            &req,
+            "lua.localhost",
+            Some("10.1.2.3".to_string()),
+            vec!["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_on_request() {
+        let script = settings(
+            r#"
+            function on_request(req)
+              if req.path == "/blocked" then
+                return 403, "blocked"
+              end
+              local route = req.method .. " " .. req.host .. req.path
+              req:set_header("x-route", route .. "?" .. req.query)
+              req:set_header("x-client", req.client_ip)
+              req:set_header("x-tier", req:header("X-Tier") .. "+")
+              req:remove_header("x-debug")
+              req:set_upstream(req.upstreams[2])
+            end
+            "#,
+        );
+
+        let (changed, reply) = on_request(1, &script, request()).unwrap();
+        assert!(reply.is_none());
+        assert_eq!(changed.upstream.as_deref(), Some("10.0.0.2:80"));
+
+        let mut req = RequestHeader::build("GET", b"/api/items?page=2", None).unwrap();
+        req.insert_header("x-debug", "1").unwrap();
+        changed.headers.apply_to_request(&mut req).unwrap();
+        assert_eq!(req.headers["x-route"], "GET lua.localhost/api/items?page=2");
+        assert_eq!(req.headers["x-client"], "10.1.2.3");
+        assert_eq!(req.headers["x-tier"], "free+");
+        assert!(req.headers.get("x-debug").is_none());
+
+        let mut blocked = request();
+        blocked.path = "/blocked".to_string();
+        let (_, reply) = on_request(1, &script, blocked).unwrap();
+        assert_eq!(reply, Some((StatusCode::FORBIDDEN, b"blocked".to_vec())));
+    }
+
+    #[test]
+    fn test_on_response() {
+        let script = settings(
+            r#"
+            function on_response(res)
+              if res.status == 404 then
+                res:set_status(410)
+              end
+              res:set_header("x-powered-by", "lua")
+              res:remove_header("server")
+            end
+            "#,
+        );
+
+        let mut res = ResponseHeader::build(StatusCode::NOT_FOUND, None).unwrap();
+        res.insert_header("server", "upstream").unwrap();
+        let response = on_response(2, &script, Response::new(&res)).unwrap();
+        assert_eq!(response.status, StatusCode::GONE);
+        response.headers.apply_to_response(&mut res).unwrap();
+        assert_eq!(res.headers["x-powered-by"], "lua");
+        assert!(res.headers.get("server").is_none());
+
+        // Without the hook, nothing changes
+        let response = on_response(3, &settings("x = 1"), Response::new(&res)).unwrap();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_script_errors() {
+        // Unknown upstream, invalid header or status
+        for source in [
+            r#"function on_request(req) req:set_upstream("10.0.0.9:80") end"#,
+            r#"function on_request(req) req:set_header("x-a", "\n") end"#,
+            "function on_request(req) return 1000 end",
+            "function on_request(req) error('failed') end",
+        ] {
+            assert!(
+                on_request(4, &settings(source), request()).is_err(),
+                "{source}"
+            );
+        }
+
+        // The hooks are stopped when they run for too long, or use too much memory
+        let looping = settings("function on_request(req) while true do end end");
+        let started = Instant::now();
+        let err = on_request(5, &looping, request()).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("ran out of time"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let growing =
+            settings("function on_request(req) local s = 'x' while true do s = s .. s end end");
+        assert!(on_request(6, &growing, request()).is_err());
+
+        // Sandboxed
+        for source in [
+            "function on_request(req) io.open('/etc/passwd') end",
+            "function on_request(req) os.exit(1) end",
+            "function on_request(req) dofile('/etc/passwd') end",
+            "function on_request(req) load('return 1')() end",
+        ] {
+            assert!(
+                on_request(7, &settings(source), request()).is_err(),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_states() {
+        let first = settings(
+            "calls = 0 function on_request(req) calls = calls + 1 return 200, tostring(calls) end",
+        );
+        let body =
+            |settings: &Arc<LuaSettings>| on_request(8, settings, request()).unwrap().1.unwrap().1;
+
+        // The state of the route is kept between requests
+        assert_eq!(body(&first), b"1");
+        assert_eq!(body(&first), b"2");
+
+        // A new version of the route starts from a new state, the old one is dropped
+        let second = settings(&first.source);
+        drop(first);
+        assert_eq!(body(&second), b"1");
+        STATES.with_borrow(|states| assert_eq!(states.len(), 1));
+    }
+
+    #[test]
+    fn test_shared_dict() {
+        let limiter = settings(
+            r#"
+            function on_request(req)
+              local hits = proksi.shared:incr("test:hits:" .. req.client_ip, 1, 60)
+              proksi.shared:set("test:last", req.path)
+              if hits > 2 then
+                return 429, proksi.shared:get("test:last")
+              end
+            end
+            "#,
+        );
+
+        assert!(on_request(9, &limiter, request()).unwrap().1.is_none());
+        assert!(on_request(10, &limiter, request()).unwrap().1.is_none());
+        // Shared by the routes
+        let (_, reply) = on_request(11, &limiter, request()).unwrap();
+        assert_eq!(
+            reply,
+            Some((StatusCode::TOO_MANY_REQUESTS, b"/api/items".to_vec()))
+        );
+
+        assert_eq!(
+            SHARED.get("test:last"),
+            Some(SharedValue::String(b"/api/items".to_vec()))
+        );
+        let delete = settings(
+            r#"
+            function on_request(req)
+              proksi.shared:delete("test:last")
+              proksi.shared:incr("test:hits:" .. req.client_ip)
+            end
+            "#,
+        );
+        // Not a counter
+        SHARED.insert(
+            "test:hits:10.1.2.3".to_string(),
+            SharedValue::Boolean(true),
+            shared_ttl(None),
+        );
+        assert!(on_request(12, &delete, request()).is_err());
+        assert_eq!(SHARED.get("test:last"), None);
+    }
+}
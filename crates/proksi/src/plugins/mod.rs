@@ -1,34 +1,166 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Result};
+use api_key::ApiKeyAuth;
 use async_trait::async_trait;
 use basic_auth::BasicAuth;
+use bot_filter::BotFilter;
+use challenge::Challenge;
+use cookie_rewrite::CookieRewrite;
+use esi::Esi;
+use experiment::Experiment;
+use ext_proc::ExtProc;
+use fault_injection::FaultInjection;
+use forward_auth::ForwardAuth;
+use geoip::GeoIp;
+use hotlink::Hotlink;
+use html_inject::HtmlInject;
+use http::{header, StatusCode};
+use jwt::Jwt;
+use lua::LuaHooks;
 use oauth2::Oauth2;
+use oidc::Oidc;
 use once_cell::sync::Lazy;
+use openapi::OpenApi;
 use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::proxy::Session;
+use rate_limit::RateLimiter;
+pub use rate_limit::WindowState;
+use redirects::Redirects;
+use request_decompression::RequestDecompression;
 use request_id::RequestId;
+use response_rewrite::ResponseRewrite;
+use security_headers::SecurityHeaders;
+use settings_cache::SettingsCache;
+use signed_url::SignedUrl;
+use tls_fingerprint::TlsFingerprintFilter;
+use waf::Waf;
 
 use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
+pub mod api_key;
 pub mod basic_auth;
+pub mod bot_filter;
+pub mod challenge;
+pub mod cookie_rewrite;
+pub mod esi;
+pub mod experiment;
+pub mod ext_proc;
+pub mod fault_injection;
+pub mod forward_auth;
+pub mod geoip;
+pub mod hotlink;
+pub mod html_inject;
 pub mod jwt;
+pub mod lua;
 pub mod oauth2;
+pub mod oidc;
+pub mod openapi;
+pub mod redirects;
+pub mod request_decompression;
 pub mod request_id;
+pub mod response_rewrite;
+pub mod security_headers;
+pub mod signed_url;
+pub mod tls_fingerprint;
+pub mod waf;
+
+mod rate_limit;
+mod settings_cache;
 
 pub(crate) struct ProxyPlugins {
     pub basic_auth: Lazy<BasicAuth>,
+    pub jwt: Lazy<Jwt>,
+    pub lua: Lazy<LuaHooks>,
     pub oauth2: Lazy<Oauth2>,
+    pub oidc: Lazy<Oidc>,
     pub request_id: Lazy<RequestId>,
+    pub challenge: Lazy<Challenge>,
+    pub tls_fingerprint: Lazy<TlsFingerprintFilter>,
+    pub hotlink: Lazy<Hotlink>,
+    pub redirects: Lazy<Redirects>,
+    pub signed_url: Lazy<SignedUrl>,
+    pub openapi: Lazy<OpenApi>,
+    pub ext_proc: Lazy<ExtProc>,
+    pub cookie_rewrite: Lazy<CookieRewrite>,
+    pub html_inject: Lazy<HtmlInject>,
+    pub esi: Lazy<Esi>,
+    pub experiment: Lazy<Experiment>,
+    pub fault_injection: Lazy<FaultInjection>,
+    pub response_rewrite: Lazy<ResponseRewrite>,
+    pub security_headers: Lazy<SecurityHeaders>,
+    pub bot_filter: Lazy<BotFilter>,
+    pub waf: Lazy<Waf>,
+    pub request_decompression: Lazy<RequestDecompression>,
+    pub geoip: Lazy<GeoIp>,
+    pub api_key: Lazy<ApiKeyAuth>,
+    pub forward_auth: Lazy<ForwardAuth>,
 }
 
 /// Static plugin registry (plugins that don't generate a new instance for each request)
 pub static PLUGINS: Lazy<ProxyPlugins> = Lazy::new(|| ProxyPlugins {
     basic_auth: Lazy::new(BasicAuth::new),
+    jwt: Lazy::new(Jwt::new),
+    lua: Lazy::new(LuaHooks::new),
     oauth2: Lazy::new(Oauth2::new),
+    oidc: Lazy::new(Oidc::new),
     request_id: Lazy::new(RequestId::new),
+    challenge: Lazy::new(Challenge::new),
+    tls_fingerprint: Lazy::new(TlsFingerprintFilter::new),
+    hotlink: Lazy::new(Hotlink::new),
+    redirects: Lazy::new(Redirects::new),
+    signed_url: Lazy::new(SignedUrl::new),
+    openapi: Lazy::new(OpenApi::new),
+    ext_proc: Lazy::new(ExtProc::new),
+    cookie_rewrite: Lazy::new(CookieRewrite::new),
+    html_inject: Lazy::new(HtmlInject::new),
+    esi: Lazy::new(Esi::new),
+    experiment: Lazy::new(Experiment::new),
+    fault_injection: Lazy::new(FaultInjection::new),
+    response_rewrite: Lazy::new(ResponseRewrite::new),
+    security_headers: Lazy::new(SecurityHeaders::new),
+    bot_filter: Lazy::new(BotFilter::new),
+    waf: Lazy::new(Waf::new),
+    request_decompression: Lazy::new(RequestDecompression::new),
+    geoip: Lazy::new(GeoIp::new),
+    api_key: Lazy::new(ApiKeyAuth::new),
+    forward_auth: Lazy::new(ForwardAuth::new),
 });
 
+/// Windows of the rate limiters of the plugins, by plugin and identifier
+pub fn rate_limit_windows() -> BTreeMap<String, BTreeMap<String, WindowState>> {
+    rate_limiters()
+        .into_iter()
+        .map(|(name, limiter)| (name.to_string(), limiter.windows()))
+        .collect()
+}
+
+/// Restores the windows of the rate limiters (e.g. saved before a restart)
+pub fn restore_rate_limit_windows(mut windows: BTreeMap<String, BTreeMap<String, WindowState>>) {
+    for (name, limiter) in rate_limiters() {
+        if let Some(windows) = windows.remove(name) {
+            limiter.restore(windows);
+        }
+    }
+}
+
+fn rate_limiters() -> [(&'static str, &'static RateLimiter); 3] {
+    [
+        ("api_key", &PLUGINS.api_key.rate_limiter),
+        ("bot_filter", &PLUGINS.bot_filter.rate_limiter),
+        ("challenge", &PLUGINS.challenge.rate_limiter),
+    ]
+}
+
+/// Builds the settings of the plugins again, after the routes (and their plugins) changed
+pub fn clear_settings_caches() {
+    settings_cache::clear_all();
+}
+
 /// Get a required configuration value from a plugin config
 fn get_required_config(
     plugin_config: &HashMap<Cow<'static, str>, serde_json::Value>,
@@ -41,6 +173,46 @@ fn get_required_config(
         .ok_or_else(|| anyhow!("Missing or invalid {}", key))
 }
 
+/// Answers the request with an empty response of `status`, the request is then handled
+async fn respond_with_status(session: &mut Session, status: StatusCode) -> Result<bool> {
+    let res_headers = ResponseHeader::build_no_case(status, Some(1))?;
+    session
+        .write_response_header(Box::new(res_headers), true)
+        .await?;
+    Ok(true)
+}
+
+/// Answers a rate limited request with a `429`, telling the client when to retry
+async fn respond_with_retry_after(session: &mut Session, retry_after: u64) -> Result<bool> {
+    let mut res_headers = ResponseHeader::build_no_case(StatusCode::TOO_MANY_REQUESTS, Some(1))?;
+    res_headers.insert_header(header::RETRY_AFTER, retry_after.to_string())?;
+    session
+        .write_response_header(Box::new(res_headers), true)
+        .await?;
+    Ok(true)
+}
+
+/// The settings of the plugin for the route, built once per version of the route. A
+/// misconfigured route never lets the requests through: they are answered with a 500 and
+/// `None` is returned.
+async fn route_settings<T: Send + Sync>(
+    session: &mut Session,
+    ctx: &RouterContext,
+    name: &str,
+    cache: &SettingsCache<T>,
+    config: &HashMap<Cow<'static, str>, serde_json::Value>,
+    build: impl FnOnce(&HashMap<Cow<'static, str>, serde_json::Value>) -> Result<T>,
+) -> Result<Option<Arc<T>>> {
+    match cache.get_or_try_build(ctx.route_container.id, config, build) {
+        Ok(settings) => Ok(Some(settings)),
+        Err(err) => {
+            tracing::error!("invalid {name} plugin configuration: {err}");
+            respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
+            Ok(None)
+        }
+    }
+}
+
 #[async_trait]
 pub trait MiddlewarePlugin {
     /// Create a new state for the middleware
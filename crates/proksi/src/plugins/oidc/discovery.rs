@@ -0,0 +1,98 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::stores::expiring::ExpiringMap;
+
+/// HTTP client used to talk to the identity providers
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default()
+});
+
+/// Provider metadata, keyed by issuer
+static PROVIDERS: Lazy<ExpiringMap<Arc<ProviderMetadata>>> = Lazy::new(ExpiringMap::new);
+
+/// How long the provider metadata is kept before being fetched again
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Subset of the OpenID provider metadata used by the plugin
+/// <https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata>
+#[derive(Debug, Deserialize)]
+pub(super) struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Response of the token endpoint (only the fields we care about)
+#[derive(Debug, Deserialize)]
+pub(super) struct TokenResponse {
+    pub id_token: String,
+}
+
+/// Returns the provider metadata for the issuer, using the
+/// `.well-known/openid-configuration` document
+pub(super) async fn get_provider(issuer: &str) -> Result<Arc<ProviderMetadata>> {
+    if let Some(metadata) = PROVIDERS.get(issuer) {
+        return Ok(metadata);
+    }
+
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+
+    let metadata = HTTP_CLIENT
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ProviderMetadata>()
+        .await?;
+
+    if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+        return Err(anyhow!(
+            "issuer mismatch in discovery document: {}",
+            metadata.issuer
+        ));
+    }
+
+    let metadata = Arc::new(metadata);
+    PROVIDERS.insert(issuer.to_string(), metadata.clone(), METADATA_TTL);
+
+    Ok(metadata)
+}
+
+/// Exchanges an authorization code for the provider tokens
+pub(super) async fn exchange_code(
+    provider: &ProviderMetadata,
+    client_id: &str,
+    client_secret: &str,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<TokenResponse> {
+    let response = HTTP_CLIENT
+        .post(&provider.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+
+    Ok(response)
+}
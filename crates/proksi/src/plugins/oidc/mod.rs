@@ -0,0 +1,619 @@
+use std::{borrow::Cow, collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use cookie::Cookie;
+use http::{header, StatusCode};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use openssl::{base64, sha::sha256};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{
+    get_required_config, jwt::jwks, respond_with_status, route_settings,
+    settings_cache::SettingsCache, MiddlewarePlugin,
+};
+
+mod discovery;
+mod session;
+
+/// Prefix of the context extensions holding the headers forwarded to the upstream
+const USER_HEADER_PREFIX: &str = "oidc_user_header:";
+
+/// Headers sent to the upstream with the authenticated user information
+const USER_HEADERS: [&str; 3] = [
+    "x-forwarded-user",
+    "x-forwarded-email",
+    "x-forwarded-groups",
+];
+
+/// How long a login attempt can take before its state is considered expired
+const STATE_TTL: Duration = Duration::from_secs(300);
+
+/// How often (in seconds) the provider keys are refreshed
+const JWKS_REFRESH_INTERVAL: u64 = 300;
+
+/// Default session duration (in seconds)
+const DEFAULT_SESSION_TTL: u64 = 60 * 60 * 8;
+
+/// Algorithms accepted for the `id_token` signature
+const ID_TOKEN_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Per-route settings of the OIDC plugin
+struct OidcSettings {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    cookie_secret: String,
+    scopes: String,
+    callback_path: String,
+    session_ttl: Duration,
+    groups_claim: String,
+    allowed_emails: Vec<String>,
+    allowed_domains: Vec<String>,
+    allowed_groups: Vec<String>,
+}
+
+impl OidcSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let cookie_secret = get_required_config(config, "cookie_secret")?;
+        if cookie_secret.len() < 32 {
+            bail!("cookie_secret needs to be at least 32 characters long");
+        }
+
+        let string_or = |key: &str, default: &str| {
+            config
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or(default)
+                .to_string()
+        };
+
+        let list = |key: &str| -> Vec<String> {
+            config
+                .get(key)
+                .and_then(|v| v.as_array())
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|v| v.as_str().map(str::to_lowercase))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Ok(Self {
+            issuer: get_required_config(config, "issuer")?,
+            client_id: get_required_config(config, "client_id")?,
+            client_secret: get_required_config(config, "client_secret")?,
+            cookie_secret,
+            scopes: string_or("scopes", "openid email profile"),
+            callback_path: string_or("callback_path", "/__/oidc/callback"),
+            session_ttl: Duration::from_secs(
+                config
+                    .get("session_ttl")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_SESSION_TTL),
+            ),
+            groups_claim: string_or("groups_claim", "groups"),
+            allowed_emails: list("allowed_emails"),
+            allowed_domains: list("allowed_domains"),
+            allowed_groups: list("allowed_groups"),
+        })
+    }
+
+    /// Returns true if the session is allowed to access the route.
+    /// Without any `allowed_*` option, every authenticated user is allowed.
+    fn is_authorized(&self, claims: &session::SessionClaims) -> bool {
+        if self.allowed_emails.is_empty()
+            && self.allowed_domains.is_empty()
+            && self.allowed_groups.is_empty()
+        {
+            return true;
+        }
+
+        let email = claims.email.as_deref().unwrap_or_default().to_lowercase();
+        let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+
+        (!email.is_empty() && self.allowed_emails.contains(&email))
+            || (!domain.is_empty() && self.allowed_domains.iter().any(|d| d == domain))
+            || claims
+                .groups
+                .iter()
+                .any(|g| self.allowed_groups.contains(&g.to_lowercase()))
+    }
+
+    fn redirect_uri(&self, host: &str) -> String {
+        format!("https://{host}{}", self.callback_path)
+    }
+}
+
+/// OpenID Connect plugin
+/// Redirects unauthenticated browser sessions to the identity provider, handles
+/// the provider callback and keeps the user session in a signed cookie.
+pub struct Oidc {
+    settings: SettingsCache<OidcSettings>,
+}
+
+impl Oidc {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    async fn redirect(
+        session: &mut Session,
+        location: &str,
+        cookies: &[Cookie<'_>],
+    ) -> Result<bool> {
+        let mut res_headers = ResponseHeader::build_no_case(StatusCode::FOUND, Some(4))?;
+        res_headers.insert_header(header::LOCATION, location)?;
+        res_headers.insert_header(
+            header::CACHE_CONTROL,
+            "no-store, no-cache, must-revalidate, max-age=0",
+        )?;
+        for cookie in cookies {
+            res_headers.append_header(header::SET_COOKIE, cookie.to_string())?;
+        }
+
+        session
+            .write_response_header(Box::new(res_headers), true)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Only requests that accept HTML are redirected to the login page,
+    /// API clients receive a 401 instead
+    fn is_browser_request(session: &Session) -> bool {
+        session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+    }
+
+    /// Builds the authorization URL of the provider for a login in progress
+    fn authorization_url(
+        settings: &OidcSettings,
+        authorization_endpoint: &str,
+        host: &str,
+        login: &session::LoginState,
+    ) -> Result<String> {
+        let redirect_uri = settings.redirect_uri(host);
+        let code_challenge = pkce_challenge(&login.verifier);
+        let url = reqwest::Url::parse_with_params(
+            authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", settings.client_id.as_str()),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("scope", settings.scopes.as_str()),
+                ("state", login.state.as_str()),
+                ("nonce", login.nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.to_string())
+    }
+
+    /// Validates the `id_token` returned by the provider and extracts the session claims
+    async fn validate_id_token(
+        settings: &OidcSettings,
+        jwks_uri: &str,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<session::SessionClaims> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            bail!("id_token algorithm {:?} is not allowed", header.alg);
+        }
+
+        let find_key = |keys: &jsonwebtoken::jwk::JwkSet| match &header.kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None => keys.keys.first().cloned(),
+        };
+
+        let cached = jwks::get(jwks_uri, Duration::from_secs(JWKS_REFRESH_INTERVAL)).await?;
+        let jwk = match find_key(&cached.keys) {
+            Some(jwk) => jwk,
+            None => find_key(&jwks::refresh(jwks_uri).await?.keys)
+                .ok_or_else(|| anyhow!("no matching key found for the id_token"))?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[settings.issuer.as_str()]);
+        validation.set_audience(&[settings.client_id.as_str()]);
+
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            id_token,
+            &DecodingKey::from_jwk(&jwk)?,
+            &validation,
+        )?
+        .claims;
+
+        if claims.get("nonce").and_then(|v| v.as_str()) != Some(nonce) {
+            bail!("id_token nonce does not match");
+        }
+
+        let sub = claims
+            .get("sub")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("id_token is missing the sub claim"))?
+            .to_string();
+
+        // Unverified emails are never trusted for authorization
+        let email_verified = claims
+            .get("email_verified")
+            .is_none_or(|v| v.as_bool().unwrap_or(false));
+        let email = claims
+            .get("email")
+            .and_then(|v| v.as_str())
+            .filter(|_| email_verified)
+            .map(ToString::to_string);
+
+        let groups = claims
+            .get(&settings.groups_claim)
+            .and_then(|v| v.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(ToString::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(session::SessionClaims::new(
+            sub,
+            email,
+            groups,
+            settings.session_ttl,
+        ))
+    }
+
+    /// Handles the redirect from the identity provider after the user logged in
+    async fn handle_callback(
+        &self,
+        session: &mut Session,
+        settings: &OidcSettings,
+        host: &str,
+    ) -> Result<bool> {
+        let uri = &session.req_header().uri;
+        let query = reqwest::Url::parse(&format!("https://{host}{uri}"))?;
+        let params = query.query_pairs().collect::<HashMap<_, _>>();
+
+        let (Some(code), Some(state)) = (params.get("code"), params.get("state")) else {
+            tracing::info!("missing code or state in the oidc callback");
+            return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+        };
+
+        // The state has to be the one issued to this browser, otherwise the login could
+        // be completed with the code of another user (login CSRF)
+        let cookies = session
+            .req_header()
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        let Some(login) = session::read_login(cookies, &settings.cookie_secret, state) else {
+            tracing::info!("oidc state is invalid, has expired or was issued to another browser");
+            return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+        };
+
+        let provider = discovery::get_provider(&settings.issuer).await?;
+        let tokens = discovery::exchange_code(
+            &provider,
+            &settings.client_id,
+            &settings.client_secret,
+            code,
+            &settings.redirect_uri(host),
+            &login.verifier,
+        )
+        .await;
+
+        let claims = match tokens {
+            Ok(tokens) => {
+                Self::validate_id_token(
+                    settings,
+                    &provider.jwks_uri,
+                    &tokens.id_token,
+                    &login.nonce,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+
+        let claims = match claims {
+            Ok(claims) => claims,
+            Err(err) => {
+                tracing::error!("failed to complete the oidc login: {err}");
+                return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+            }
+        };
+
+        if !settings.is_authorized(&claims) {
+            tracing::info!("oidc user is not authorized {:?}", claims.sub);
+            return respond_with_status(session, StatusCode::FORBIDDEN).await;
+        }
+
+        let cookie =
+            session::create_session_cookie(&claims, &settings.cookie_secret, settings.session_ttl)?;
+
+        let cleared = session::clear_login_cookie(&settings.callback_path);
+
+        Self::redirect(session, &login.uri, &[cookie, cleared]).await
+    }
+}
+
+/// The S256 PKCE challenge of a verifier, the base64url encoded SHA-256 of the verifier
+fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_block(&sha256(verifier.as_bytes()))
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Oidc {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        // Nothing to do if the plugin configuration is not present
+        let Some(config) = plugin.config.as_ref() else {
+            return Ok(false);
+        };
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            OidcSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        if session.req_header().uri.path() == settings.callback_path {
+            return match self.handle_callback(session, &settings, &ctx.host).await {
+                Ok(handled) => Ok(handled),
+                Err(err) => {
+                    tracing::error!("oidc callback failed: {err}");
+                    respond_with_status(session, StatusCode::BAD_GATEWAY).await
+                }
+            };
+        }
+
+        let cookies = session
+            .req_header()
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+
+        if let Some(claims) = session::read_session(cookies, &settings.cookie_secret) {
+            if !settings.is_authorized(&claims) {
+                return respond_with_status(session, StatusCode::FORBIDDEN).await;
+            }
+
+            let values = [
+                claims.sub,
+                claims.email.unwrap_or_default(),
+                claims.groups.join(","),
+            ];
+            for (header, value) in USER_HEADERS.iter().zip(values) {
+                ctx.extensions
+                    .insert(Cow::Owned(format!("{USER_HEADER_PREFIX}{header}")), value);
+            }
+
+            return Ok(false);
+        }
+
+        if !Self::is_browser_request(session) {
+            return respond_with_status(session, StatusCode::UNAUTHORIZED).await;
+        }
+
+        let provider = match discovery::get_provider(&settings.issuer).await {
+            Ok(provider) => provider,
+            Err(err) => {
+                tracing::error!("failed to fetch oidc provider metadata: {err}");
+                return respond_with_status(session, StatusCode::BAD_GATEWAY).await;
+            }
+        };
+
+        let original_uri = session
+            .req_header()
+            .uri
+            .path_and_query()
+            .map_or("/", |pq| pq.as_str())
+            .to_string();
+
+        // The state, nonce and PKCE verifier are signed in a cookie of this browser only, so
+        // that any instance sharing the same configuration can complete the login
+        let login = session::LoginState::new(original_uri, STATE_TTL);
+        let cookie = session::create_login_cookie(
+            &login,
+            &settings.cookie_secret,
+            &settings.callback_path,
+            STATE_TTL,
+        )?;
+
+        let location = Self::authorization_url(
+            &settings,
+            &provider.authorization_endpoint,
+            &ctx.host,
+            &login,
+        )?;
+
+        Self::redirect(session, &location, &[cookie]).await
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        // Never forward user headers sent by downstream
+        for header in USER_HEADERS {
+            upstream_request.remove_header(header);
+        }
+
+        for (key, value) in &ctx.extensions {
+            let Some(header) = key.strip_prefix(USER_HEADER_PREFIX) else {
+                continue;
+            };
+
+            if !value.is_empty() {
+                upstream_request.insert_header(header.to_string(), value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn test_settings(extra: &[(&'static str, serde_json::Value)]) -> OidcSettings {
+        let mut config = HashMap::from([
+            (Cow::Borrowed("issuer"), json!("https://idp.example.com")),
+            (Cow::Borrowed("client_id"), json!("proksi")),
+            (Cow::Borrowed("client_secret"), json!("secret")),
+            (
+                Cow::Borrowed("cookie_secret"),
+                json!("0123456789abcdef0123456789abcdef"),
+            ),
+        ]);
+        for (key, value) in extra {
+            config.insert(Cow::Borrowed(*key), value.clone());
+        }
+
+        OidcSettings::from_config(&config).unwrap()
+    }
+
+    fn claims(email: Option<&str>, groups: &[&str]) -> session::SessionClaims {
+        session::SessionClaims::new(
+            "user-1".to_string(),
+            email.map(ToString::to_string),
+            groups.iter().map(ToString::to_string).collect(),
+            Duration::from_secs(60),
+        )
+    }
+
+    #[test]
+    fn test_settings_defaults() {
+        let settings = test_settings(&[]);
+        assert_eq!(settings.scopes, "openid email profile");
+        assert_eq!(settings.callback_path, "/__/oidc/callback");
+        assert_eq!(
+            settings.redirect_uri("dash.example.com"),
+            "https://dash.example.com/__/oidc/callback"
+        );
+    }
+
+    #[test]
+    fn test_settings_short_cookie_secret() {
+        let config = HashMap::from([
+            (Cow::Borrowed("issuer"), json!("https://idp.example.com")),
+            (Cow::Borrowed("client_id"), json!("proksi")),
+            (Cow::Borrowed("client_secret"), json!("secret")),
+            (Cow::Borrowed("cookie_secret"), json!("short")),
+        ]);
+        assert!(OidcSettings::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let open = test_settings(&[]);
+        assert!(open.is_authorized(&claims(None, &[])));
+
+        let restricted = test_settings(&[
+            ("allowed_domains", json!(["example.com"])),
+            ("allowed_emails", json!(["Friend@Other.com"])),
+            ("allowed_groups", json!(["ops"])),
+        ]);
+        assert!(restricted.is_authorized(&claims(Some("me@example.com"), &[])));
+        assert!(restricted.is_authorized(&claims(Some("friend@other.com"), &[])));
+        assert!(restricted.is_authorized(&claims(None, &["ops"])));
+        assert!(!restricted.is_authorized(&claims(Some("me@evil.com"), &["dev"])));
+        assert!(!restricted.is_authorized(&claims(None, &[])));
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Example of the RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_authorization_url() {
+        let settings = test_settings(&[]);
+        let login = session::LoginState::new("/".to_string(), STATE_TTL);
+        let url = Oidc::authorization_url(
+            &settings,
+            "https://idp.example.com/authorize",
+            "dash.example.com",
+            &login,
+        )
+        .unwrap();
+
+        assert!(url.starts_with("https://idp.example.com/authorize?response_type=code"));
+        assert!(url.contains("redirect_uri=https%3A%2F%2Fdash.example.com%2F__%2Foidc%2Fcallback"));
+        assert!(url.contains("scope=openid+email+profile"));
+        assert!(url.contains(&format!("state={}", login.state)));
+        assert!(url.contains(&format!("nonce={}", login.nonce)));
+        assert!(url.contains(&format!(
+            "code_challenge={}&code_challenge_method=S256",
+            pkce_challenge(&login.verifier)
+        )));
+        assert!(!url.contains(&login.verifier));
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use cookie::{Cookie, SameSite};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+/// Name of the cookie holding the signed session
+pub(super) const SESSION_COOKIE_NAME: &str = "__Secure_Auth_PRK_OIDC";
+
+/// Name of the cookie binding a login in progress to the browser that started it
+pub(super) const LOGIN_COOKIE_NAME: &str = "__Secure_Auth_PRK_OIDC_LOGIN";
+
+/// Claims stored in the session cookie
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(super) struct SessionClaims {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+impl SessionClaims {
+    pub fn new(sub: String, email: Option<String>, groups: Vec<String>, ttl: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            sub,
+            email,
+            groups,
+            iat: now,
+            exp: now + ttl.as_secs(),
+        }
+    }
+}
+
+/// A login in progress: the `state` sent to the provider, the `nonce` expected in the
+/// `id_token`, the PKCE verifier and the URI to return to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(super) struct LoginState {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+    pub uri: String,
+    pub exp: u64,
+}
+
+impl LoginState {
+    pub fn new(uri: String, ttl: Duration) -> Self {
+        let random = || uuid::Uuid::new_v4().simple().to_string();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            state: random(),
+            nonce: random(),
+            // 64 characters, within the 43 to 128 required by PKCE
+            verifier: format!("{}{}", random(), random()),
+            uri,
+            exp: now + ttl.as_secs(),
+        }
+    }
+}
+
+/// Signs the login state and returns it as a cookie only sent to the callback path
+pub(super) fn create_login_cookie(
+    login: &LoginState,
+    secret: &str,
+    callback_path: &str,
+    ttl: Duration,
+) -> Result<Cookie<'static>> {
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        login,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?;
+
+    Ok(Cookie::build((LOGIN_COOKIE_NAME, token))
+        .secure(true)
+        .path(callback_path.to_string())
+        .max_age(cookie::time::Duration::seconds(
+            i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX),
+        ))
+        .http_only(true)
+        // Lax, the cookie has to be sent with the redirect of the provider
+        .same_site(SameSite::Lax)
+        .build())
+}
+
+/// Expires the login cookie once the callback was handled
+pub(super) fn clear_login_cookie(callback_path: &str) -> Cookie<'static> {
+    Cookie::build((LOGIN_COOKIE_NAME, ""))
+        .secure(true)
+        .path(callback_path.to_string())
+        .max_age(cookie::time::Duration::ZERO)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+/// Finds the login cookie and returns its state if the signature is valid, the login has
+/// not expired and the query `state` is the one of the cookie
+pub(super) fn read_login<'a>(
+    cookie_headers: impl Iterator<Item = &'a str>,
+    secret: &str,
+    state: &str,
+) -> Option<LoginState> {
+    let token = cookie_headers
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == LOGIN_COOKIE_NAME)?;
+
+    let login = jsonwebtoken::decode::<LoginState>(
+        token.value(),
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+    .ok()?;
+
+    // Only relative URIs are accepted to avoid open redirects
+    let matches = login.state.len() == state.len()
+        && openssl::memcmp::eq(login.state.as_bytes(), state.as_bytes());
+    (matches && login.uri.starts_with('/')).then_some(login)
+}
+
+/// Signs the session claims and returns them as a cookie for the current host
+pub(super) fn create_session_cookie(
+    claims: &SessionClaims,
+    secret: &str,
+    ttl: Duration,
+) -> Result<Cookie<'static>> {
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?;
+
+    Ok(Cookie::build((SESSION_COOKIE_NAME, token))
+        .secure(true)
+        .path("/")
+        .max_age(cookie::time::Duration::seconds(
+            i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX),
+        ))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build())
+}
+
+/// Finds the session cookie in the given `Cookie` header values and returns
+/// its claims if the signature is valid and the session has not expired
+pub(super) fn read_session<'a>(
+    cookie_headers: impl Iterator<Item = &'a str>,
+    secret: &str,
+) -> Option<SessionClaims> {
+    let token = cookie_headers
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)?;
+
+    jsonwebtoken::decode::<SessionClaims>(
+        token.value(),
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a-very-long-secret-used-only-for-testing-sessions";
+
+    #[test]
+    fn test_session_roundtrip() {
+        let ttl = Duration::from_secs(60);
+        let claims = SessionClaims::new(
+            "user-1".to_string(),
+            Some("user@example.com".to_string()),
+            vec!["admins".to_string()],
+            ttl,
+        );
+
+        let cookie = create_session_cookie(&claims, SECRET, ttl).unwrap();
+        let header = format!("other=1; {}", cookie.stripped());
+
+        assert_eq!(
+            read_session([header.as_str()].into_iter(), SECRET),
+            Some(claims)
+        );
+    }
+
+    #[test]
+    fn test_session_wrong_secret_or_missing() {
+        let ttl = Duration::from_secs(60);
+        let claims = SessionClaims::new("user-1".to_string(), None, vec![], ttl);
+        let cookie = create_session_cookie(&claims, SECRET, ttl).unwrap();
+        let header = cookie.stripped().to_string();
+
+        assert!(read_session([header.as_str()].into_iter(), "another-secret").is_none());
+        assert!(read_session(["other=1"].into_iter(), SECRET).is_none());
+    }
+
+    #[test]
+    fn test_login_bound_to_cookie() {
+        let ttl = Duration::from_secs(60);
+        let login = LoginState::new("/dashboard?tab=1".to_string(), ttl);
+        let cookie = create_login_cookie(&login, SECRET, "/__/oidc/callback", ttl).unwrap();
+        assert_eq!(cookie.path(), Some("/__/oidc/callback"));
+        assert_eq!(cookie.http_only(), Some(true));
+
+        let header = cookie.stripped().to_string();
+        let cookies = || [header.as_str()].into_iter();
+        assert_eq!(
+            read_login(cookies(), SECRET, &login.state),
+            Some(login.clone())
+        );
+
+        // A state issued to another browser, or a forged cookie
+        assert!(read_login(cookies(), SECRET, "attacker-state").is_none());
+        assert!(read_login(cookies(), "another-secret", &login.state).is_none());
+        assert!(read_login(["other=1"].into_iter(), SECRET, &login.state).is_none());
+
+        let absolute = LoginState::new("https://evil.com".to_string(), ttl);
+        let cookie = create_login_cookie(&absolute, SECRET, "/", ttl).unwrap();
+        let header = cookie.stripped().to_string();
+        assert!(read_login([header.as_str()].into_iter(), SECRET, &absolute.state).is_none());
+    }
+}
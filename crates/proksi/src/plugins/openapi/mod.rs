@@ -0,0 +1,521 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+    ErrorType::HTTPStatus,
+};
+use regex::Regex;
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{route_settings, settings_cache::SettingsCache, waf::url_decode, MiddlewarePlugin};
+
+mod schema;
+mod spec;
+
+use schema::{compile_patterns, SchemaValidator, ValidationError};
+use spec::{Operation, PathMatch, RequestParameters, Spec};
+
+/// Default maximum size of the bodies that are validated
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Per-route settings of the OpenAPI plugin
+struct OpenApiSettings {
+    spec: Spec,
+    /// Compiled `pattern`s of the spec's schemas
+    patterns: HashMap<String, Regex>,
+    /// Prefix of the API paths (e.g. `/api/v1`), removed before matching the spec
+    base_path: String,
+    max_body_size: usize,
+}
+
+impl OpenApiSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let path = config
+            .get("spec")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing or invalid spec"))?;
+
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Failed to read the OpenAPI spec {path}: {err}"))?;
+        let spec =
+            Spec::parse(&content).map_err(|err| anyhow!("Invalid OpenAPI spec {path}: {err}"))?;
+
+        let base_path = match config.get("base_path") {
+            Some(value) => value
+                .as_str()
+                .filter(|v| v.starts_with('/'))
+                .ok_or_else(|| anyhow!("Missing or invalid base_path"))?
+                .trim_end_matches('/')
+                .to_string(),
+            None => String::new(),
+        };
+
+        let max_body_size = match config.get("max_body_size") {
+            Some(value) => value
+                .as_u64()
+                .and_then(|v| usize::try_from(v).ok())
+                .ok_or_else(|| anyhow!("Missing or invalid max_body_size"))?,
+            None => DEFAULT_MAX_BODY_SIZE,
+        };
+
+        let mut patterns = HashMap::new();
+        compile_patterns(&spec.root, &mut patterns);
+
+        Ok(Self {
+            spec,
+            patterns,
+            base_path,
+            max_body_size,
+        })
+    }
+
+    /// Validates the request headers, returning a validator for the body if it has to be
+    /// validated once received
+    fn validate_request(
+        self: &Arc<Self>,
+        req: &RequestHeader,
+    ) -> Result<Option<OpenApiBodyValidator>, Vec<ValidationError>> {
+        let unknown_path = || {
+            vec![ValidationError {
+                location: "path".to_string(),
+                message: format!("{} is not a path of the API", req.uri.path()),
+            }]
+        };
+
+        let path = match req.uri.path().strip_prefix(self.base_path.as_str()) {
+            Some("") => "/",
+            Some(path) if path.starts_with('/') => path,
+            _ => return Err(unknown_path()),
+        };
+
+        let (operation, path_parameters) = match self.spec.find(&req.method, path) {
+            PathMatch::Found {
+                operation,
+                path_parameters,
+            } => (operation, path_parameters),
+            PathMatch::UnknownPath => return Err(unknown_path()),
+            PathMatch::UnknownMethod => {
+                return Err(vec![ValidationError {
+                    location: "method".to_string(),
+                    message: format!("{} is not allowed for this path", req.method),
+                }])
+            }
+        };
+
+        let parameters = RequestParameters {
+            path: path_parameters,
+            query: query_pairs(req.uri.query().unwrap_or_default()),
+            headers: &req.headers,
+            cookies: cookie_pairs(req),
+        };
+
+        let mut validator = SchemaValidator::new(&self.spec.root, &self.patterns);
+        operation.validate_parameters(&self.spec.root, &mut validator, &parameters);
+
+        let body_validator = self.body_validator(&operation, req, &mut validator);
+
+        if validator.errors.is_empty() {
+            Ok(body_validator)
+        } else {
+            Err(validator.errors)
+        }
+    }
+
+    /// Checks the presence and content type of the body
+    fn body_validator(
+        self: &Arc<Self>,
+        operation: &Arc<Operation>,
+        req: &RequestHeader,
+        validator: &mut SchemaValidator,
+    ) -> Option<OpenApiBodyValidator> {
+        let body = operation.body.as_ref()?;
+
+        if !has_body(req) {
+            if body.required {
+                validator.error("body", "is required");
+            }
+            return None;
+        }
+
+        let content_type = req
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        let Some(media_type) = body.media_type(content_type) else {
+            validator.error(
+                "header.content-type",
+                format!("{content_type:?} is not an accepted content type"),
+            );
+            return None;
+        };
+
+        // Only JSON bodies are validated against their schema
+        if media_type.1.is_none() || !is_json(content_type) {
+            return None;
+        }
+
+        Some(OpenApiBodyValidator {
+            settings: self.clone(),
+            operation: operation.clone(),
+            media_type: media_type.0.clone(),
+            body: vec![],
+        })
+    }
+}
+
+/// Decodes the pairs of a query string (values are kept borrowed when possible)
+fn query_pairs(query: &str) -> Vec<(Cow<'_, str>, Cow<'_, str>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(name, true), url_decode(value, true))
+        })
+        .collect()
+}
+
+fn cookie_pairs(req: &RequestHeader) -> Vec<(&str, &str)> {
+    req.headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .collect()
+}
+
+/// Requests without these headers have no body
+fn has_body(req: &RequestHeader) -> bool {
+    let content_length = req
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+    content_length.is_some_and(|length| length > 0)
+        || req.headers.contains_key(header::TRANSFER_ENCODING)
+}
+
+/// `application/json` and structured syntax suffixes such as `application/problem+json`
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// Responds with a 400 listing the validation errors
+async fn respond_with_errors(session: &mut Session, errors: &[ValidationError]) -> Result<()> {
+    let body = Bytes::from(serde_json::to_vec(&serde_json::json!({
+        "error": "request doesn't match the API specification",
+        "details": errors,
+    }))?);
+
+    let mut res_headers = ResponseHeader::build_no_case(StatusCode::BAD_REQUEST, Some(2))?;
+    res_headers.insert_header(header::CONTENT_TYPE, "application/json")?;
+    res_headers.insert_header(header::CONTENT_LENGTH, body.len())?;
+
+    session
+        .write_response_header(Box::new(res_headers), false)
+        .await?;
+    session.write_response_body(Some(body), true).await?;
+
+    Ok(())
+}
+
+/// Validates the request body against the schema of its operation, once it's complete
+pub struct OpenApiBodyValidator {
+    settings: Arc<OpenApiSettings>,
+    operation: Arc<Operation>,
+    media_type: String,
+    body: Vec<u8>,
+}
+
+impl OpenApiBodyValidator {
+    /// Holds the body until it's complete, so invalid requests never reach the upstream.
+    /// The error response is sent before returning an error.
+    pub async fn validate_body(
+        &mut self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if let Some(data) = body.take() {
+            self.body.extend_from_slice(&data);
+        }
+
+        if self.body.len() > self.settings.max_body_size {
+            return Err(pingora::Error::explain(
+                HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE.as_u16()),
+                "request body too large for OpenAPI validation",
+            ));
+        }
+
+        if !end_of_stream {
+            // An empty chunk holds the body, `None` would end it
+            *body = Some(Bytes::new());
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.body);
+        let errors = self.validate(&data);
+        if errors.is_empty() {
+            *body = Some(Bytes::from(data));
+            return Ok(());
+        }
+
+        if let Err(err) = respond_with_errors(session, &errors).await {
+            tracing::debug!("failed to send the OpenAPI validation errors: {err}");
+        }
+
+        Err(pingora::Error::explain(
+            HTTPStatus(StatusCode::BAD_REQUEST.as_u16()),
+            "request body doesn't match the API specification",
+        ))
+    }
+
+    fn validate(&self, data: &[u8]) -> Vec<ValidationError> {
+        let schema = self
+            .operation
+            .body
+            .as_ref()
+            .and_then(|body| body.media_type(&self.media_type))
+            .and_then(|(_, schema)| schema.as_ref());
+
+        let Some(schema) = schema else {
+            return vec![];
+        };
+
+        let value = match serde_json::from_slice::<serde_json::Value>(data) {
+            Ok(value) => value,
+            Err(err) => {
+                return vec![ValidationError {
+                    location: "body".to_string(),
+                    message: format!("invalid JSON: {err}"),
+                }]
+            }
+        };
+
+        let mut validator = SchemaValidator::new(&self.settings.spec.root, &self.settings.patterns);
+        validator.validate(schema, &value, "body");
+        validator.errors
+    }
+}
+
+/// Rejects the requests that don't match the OpenAPI spec of a route
+/// (unknown paths or methods, invalid parameters or JSON bodies)
+pub struct OpenApi {
+    settings: SettingsCache<OpenApiSettings>,
+}
+
+impl OpenApi {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for OpenApi {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            OpenApiSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        match settings.validate_request(session.req_header()) {
+            Ok(body_validator) => {
+                ctx.openapi = body_validator;
+                Ok(false)
+            }
+            Err(errors) => {
+                respond_with_errors(session, &errors).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SPEC: &str = r#"{
+        "openapi": "3.1.0",
+        "paths": {
+            "/pets": {
+                "get": {
+                    "parameters": [{ "name": "limit", "in": "query", "schema": { "type": "integer" } }]
+                },
+                "post": {
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["name"],
+                                    "properties": { "name": { "type": "string", "pattern": "^[A-Z]" } }
+                                }
+                            },
+                            "text/plain": {}
+                        }
+                    }
+                }
+            }
+        }
+    }"#;
+
+    fn settings(base_path: Option<&str>) -> Arc<OpenApiSettings> {
+        let path = std::env::temp_dir().join(format!("proksi-openapi-{}.json", std::process::id()));
+        std::fs::write(&path, SPEC).unwrap();
+
+        let mut config = HashMap::from([(Cow::Borrowed("spec"), json!(path.to_str().unwrap()))]);
+        if let Some(base_path) = base_path {
+            config.insert(Cow::Borrowed("base_path"), json!(base_path));
+        }
+
+        Arc::new(OpenApiSettings::from_config(&config).unwrap())
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn error_locations(
+        result: Result<Option<OpenApiBodyValidator>, Vec<ValidationError>>,
+    ) -> Vec<String> {
+        result
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|e| e.location)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let settings = settings(Some("/api/"));
+
+        let validate = |method, uri, headers| {
+            error_locations(settings.validate_request(&request(method, uri, headers)))
+        };
+
+        assert!(validate("GET", "/api/pets?limit=10", &[]).is_empty());
+        assert_eq!(validate("GET", "/api/pets?limit=ten", &[]), ["query.limit"]);
+        assert_eq!(validate("GET", "/pets", &[]), ["path"]);
+        assert_eq!(validate("GET", "/apipets", &[]), ["path"]);
+        assert_eq!(validate("DELETE", "/api/pets", &[]), ["method"]);
+        assert_eq!(validate("POST", "/api/pets", &[]), ["body"]);
+        assert_eq!(
+            validate(
+                "POST",
+                "/api/pets",
+                &[("content-type", "text/html"), ("content-length", "2")]
+            ),
+            ["header.content-type"]
+        );
+
+        // Declared bodies without a schema are not validated
+        let result = settings.validate_request(&request(
+            "POST",
+            "/api/pets",
+            &[("content-type", "text/plain"), ("content-length", "2")],
+        ));
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validate_body() {
+        let settings = settings(None);
+        let req = request(
+            "POST",
+            "/pets",
+            &[
+                ("content-type", "application/json; charset=utf-8"),
+                ("transfer-encoding", "chunked"),
+            ],
+        );
+        let validator = settings.validate_request(&req).unwrap().unwrap();
+
+        assert!(validator.validate(br#"{"name": "Rex"}"#).is_empty());
+
+        let errors = validator.validate(br#"{"name": "rex"}"#);
+        assert_eq!(errors[0].location, "body.name");
+
+        let errors = validator.validate(b"{");
+        assert_eq!(errors[0].location, "body");
+        assert!(errors[0].message.starts_with("invalid JSON"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = HashMap::from([(Cow::Borrowed("spec"), json!("/missing/openapi.yaml"))]);
+        assert!(OpenApiSettings::from_config(&config).is_err());
+        assert!(OpenApiSettings::from_config(&HashMap::new()).is_err());
+    }
+}
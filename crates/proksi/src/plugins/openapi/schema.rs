@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde_json::Value;
+
+/// Maximum depth of nested schemas (and `$ref`s), to protect against recursive specs
+const MAX_DEPTH: usize = 64;
+
+/// Maximum number of errors reported for a request
+pub const MAX_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ValidationError {
+    /// Where the error is, e.g. `query.limit` or `body.items[0].name`
+    pub location: String,
+    pub message: String,
+}
+
+/// Validates values against the schemas of a spec (the subset of JSON Schema used by
+/// OpenAPI 3.0 and 3.1). Annotations such as `format` are not validated.
+pub struct SchemaValidator<'a> {
+    /// The whole spec, `$ref`s are resolved against it
+    root: &'a Value,
+    /// Compiled `pattern`s
+    patterns: &'a HashMap<String, Regex>,
+    pub errors: Vec<ValidationError>,
+}
+
+impl<'a> SchemaValidator<'a> {
+    pub fn new(root: &'a Value, patterns: &'a HashMap<String, Regex>) -> Self {
+        Self {
+            root,
+            patterns,
+            errors: vec![],
+        }
+    }
+
+    pub fn error(&mut self, location: &str, message: impl Into<String>) {
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(ValidationError {
+                location: location.to_string(),
+                message: message.into(),
+            });
+        }
+    }
+
+    /// Validates `value`, returning `true` if it's valid
+    pub fn validate(&mut self, schema: &Value, value: &Value, location: &str) -> bool {
+        let errors = self.errors.len();
+        self.validate_schema(schema, value, location, 0);
+        self.errors.len() == errors
+    }
+
+    /// Checks without reporting errors (used by `anyOf` and `oneOf`)
+    fn matches(&self, schema: &Value, value: &Value, depth: usize) -> bool {
+        let mut validator = SchemaValidator::new(self.root, self.patterns);
+        validator.validate_schema(schema, value, "", depth);
+        validator.errors.is_empty()
+    }
+
+    fn validate_schema(&mut self, schema: &Value, value: &Value, location: &str, depth: usize) {
+        if depth > MAX_DEPTH {
+            self.error(location, "schema is nested too deeply");
+            return;
+        }
+
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return self.error(location, "no value is allowed"),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match resolve_ref(self.root, reference) {
+                Some(resolved) => self.validate_schema(resolved, value, location, depth + 1),
+                None => self.error(location, format!("unknown reference {reference}")),
+            }
+            // Siblings of `$ref` are ignored in OpenAPI 3.0
+            return;
+        }
+
+        if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+            return;
+        }
+
+        if let Some(types) = schema.get("type") {
+            let allowed = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+
+            if !allowed.is_empty() && !allowed.iter().any(|name| has_type(value, name)) {
+                return self.error(
+                    location,
+                    format!(
+                        "expected {}, got {}",
+                        allowed.join(" or "),
+                        type_name(value)
+                    ),
+                );
+            }
+        }
+
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if !values.contains(value) {
+                self.error(location, "value is not one of the allowed values");
+            }
+        }
+
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                self.error(location, "value is not the allowed value");
+            }
+        }
+
+        match value {
+            Value::String(string) => self.validate_string(schema, string, location),
+            Value::Number(_) => self.validate_number(schema, value, location),
+            Value::Array(items) => self.validate_array(schema, items, location, depth),
+            Value::Object(object) => self.validate_object(schema, object, location, depth),
+            _ => {}
+        }
+
+        if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+            for sub_schema in schemas {
+                self.validate_schema(sub_schema, value, location, depth + 1);
+            }
+        }
+
+        if let Some(schemas) = schema.get("anyOf").and_then(Value::as_array) {
+            if !schemas.iter().any(|s| self.matches(s, value, depth + 1)) {
+                self.error(location, "value doesn't match any of the allowed schemas");
+            }
+        }
+
+        if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+            let matching = schemas
+                .iter()
+                .filter(|s| self.matches(s, value, depth + 1))
+                .count();
+            if matching != 1 {
+                self.error(
+                    location,
+                    "value must match exactly one of the allowed schemas",
+                );
+            }
+        }
+
+        if let Some(not) = schema.get("not") {
+            if self.matches(not, value, depth + 1) {
+                self.error(location, "value matches a schema that is not allowed");
+            }
+        }
+    }
+
+    fn validate_string(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        string: &str,
+        location: &str,
+    ) {
+        let length = string.chars().count() as u64;
+
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if length < min {
+                self.error(location, format!("must be at least {min} characters long"));
+            }
+        }
+
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if length > max {
+                self.error(location, format!("must be at most {max} characters long"));
+            }
+        }
+
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            let matched = self
+                .patterns
+                .get(pattern)
+                .is_none_or(|re| re.is_match(string));
+            if !matched {
+                self.error(location, format!("must match the pattern {pattern}"));
+            }
+        }
+    }
+
+    fn validate_number(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        value: &Value,
+        location: &str,
+    ) {
+        let Some(number) = value.as_f64() else {
+            return;
+        };
+
+        let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+        // OpenAPI 3.0 uses booleans, OpenAPI 3.1 (JSON Schema) uses the bound itself
+        let exclusive = |key: &str| schema.get(key).and_then(Value::as_bool) == Some(true);
+
+        if let Some(min) = bound("minimum") {
+            if number < min || (exclusive("exclusiveMinimum") && number == min) {
+                self.error(location, format!("must be greater than or equal to {min}"));
+            }
+        }
+
+        if let Some(min) = bound("exclusiveMinimum") {
+            if number <= min {
+                self.error(location, format!("must be greater than {min}"));
+            }
+        }
+
+        if let Some(max) = bound("maximum") {
+            if number > max || (exclusive("exclusiveMaximum") && number == max) {
+                self.error(location, format!("must be less than or equal to {max}"));
+            }
+        }
+
+        if let Some(max) = bound("exclusiveMaximum") {
+            if number >= max {
+                self.error(location, format!("must be less than {max}"));
+            }
+        }
+    }
+
+    fn validate_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        items: &[Value],
+        location: &str,
+        depth: usize,
+    ) {
+        let length = items.len() as u64;
+
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if length < min {
+                self.error(location, format!("must have at least {min} items"));
+            }
+        }
+
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if length > max {
+                self.error(location, format!("must have at most {max} items"));
+            }
+        }
+
+        if schema.get("uniqueItems").and_then(Value::as_bool) == Some(true) {
+            let duplicated = items
+                .iter()
+                .enumerate()
+                .any(|(i, item)| items[..i].contains(item));
+            if duplicated {
+                self.error(location, "items must be unique");
+            }
+        }
+
+        if let Some(item_schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                let item_location = format!("{location}[{index}]");
+                self.validate_schema(item_schema, item, &item_location, depth + 1);
+            }
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        object: &serde_json::Map<String, Value>,
+        location: &str,
+        depth: usize,
+    ) {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    self.error(&format!("{location}.{name}"), "is required");
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema.get("additionalProperties");
+
+        for (name, property) in object {
+            let property_location = format!("{location}.{name}");
+
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => {
+                    self.validate_schema(property_schema, property, &property_location, depth + 1);
+                }
+                None => match additional {
+                    Some(Value::Bool(false)) => {
+                        self.error(&property_location, "is not an allowed property");
+                    }
+                    Some(additional_schema @ Value::Object(_)) => {
+                        self.validate_schema(
+                            additional_schema,
+                            property,
+                            &property_location,
+                            depth + 1,
+                        );
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+}
+
+/// Resolves a local reference (e.g. `#/components/schemas/Pet`)
+pub fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|v| v.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Compiles every `pattern` of the spec, ignoring the ones that are not valid regexes
+pub fn compile_patterns(value: &Value, patterns: &mut HashMap<String, Regex>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match (key.as_str(), value) {
+                    ("pattern", Value::String(pattern)) if !patterns.contains_key(pattern) => {
+                        match Regex::new(pattern) {
+                            Ok(regex) => {
+                                patterns.insert(pattern.clone(), regex);
+                            }
+                            Err(err) => {
+                                tracing::warn!("ignoring OpenAPI pattern {pattern}: {err}");
+                            }
+                        }
+                    }
+                    _ => compile_patterns(value, patterns),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                compile_patterns(value, patterns);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn validate(root: &Value, schema: &Value, value: &Value) -> Vec<ValidationError> {
+        let mut patterns = HashMap::new();
+        compile_patterns(root, &mut patterns);
+        compile_patterns(schema, &mut patterns);
+
+        let mut validator = SchemaValidator::new(root, &patterns);
+        validator.validate(schema, value, "body");
+        validator.errors
+    }
+
+    fn locations(errors: &[ValidationError]) -> Vec<&str> {
+        let mut locations = errors
+            .iter()
+            .map(|e| e.location.as_str())
+            .collect::<Vec<_>>();
+        locations.sort_unstable();
+        locations
+    }
+
+    #[test]
+    fn test_validate_object() {
+        let root = json!({
+            "components": { "schemas": { "Tag": {
+                "type": "object",
+                "required": ["name"],
+                "properties": { "name": { "type": "string", "pattern": "^[a-z]+$" } }
+            }}}
+        });
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 2 },
+                "age": { "type": "integer", "minimum": 0 },
+                "nickname": { "type": "string", "nullable": true },
+                "tags": { "type": "array", "maxItems": 2, "items": { "$ref": "#/components/schemas/Tag" } }
+            }
+        });
+
+        assert!(validate(
+            &root,
+            &schema,
+            &json!({ "name": "Tom", "age": 3, "nickname": null, "tags": [{ "name": "cat" }] })
+        )
+        .is_empty());
+
+        let errors = validate(
+            &root,
+            &schema,
+            &json!({ "name": "T", "age": -1.5, "color": "grey", "tags": [{ "name": "Cat" }, {}, {}] }),
+        );
+        assert_eq!(
+            locations(&errors),
+            [
+                "body.age",
+                "body.color",
+                "body.name",
+                "body.tags",
+                "body.tags[0].name",
+                "body.tags[1].name",
+                "body.tags[2].name"
+            ]
+        );
+        assert!(errors
+            .iter()
+            .any(|e| e.location == "body.age" && e.message == "expected integer, got number"));
+    }
+
+    #[test]
+    fn test_validate_combinations() {
+        let root = json!({});
+        let schema = json!({
+            "oneOf": [
+                { "type": "string", "enum": ["auto"] },
+                { "type": "integer", "exclusiveMinimum": 0, "maximum": 10 }
+            ]
+        });
+
+        assert!(validate(&root, &schema, &json!("auto")).is_empty());
+        assert!(validate(&root, &schema, &json!(10)).is_empty());
+        assert_eq!(validate(&root, &schema, &json!(0)).len(), 1);
+        assert_eq!(validate(&root, &schema, &json!("manual")).len(), 1);
+
+        // OpenAPI 3.1 types
+        let schema = json!({ "type": ["string", "null"], "not": { "const": "" } });
+        assert!(validate(&root, &schema, &Value::Null).is_empty());
+        assert_eq!(validate(&root, &schema, &json!("")).len(), 1);
+        assert_eq!(validate(&root, &schema, &json!(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_recursive_refs() {
+        let root = json!({ "components": { "schemas": { "Loop": { "$ref": "#/components/schemas/Loop" } } } });
+        let schema = json!({ "$ref": "#/components/schemas/Loop" });
+
+        let errors = validate(&root, &schema, &json!({}));
+        assert_eq!(errors[0].message, "schema is nested too deeply");
+
+        let errors = validate(
+            &root,
+            &json!({ "$ref": "#/components/schemas/Missing" }),
+            &json!(1),
+        );
+        assert_eq!(
+            errors[0].message,
+            "unknown reference #/components/schemas/Missing"
+        );
+    }
+}
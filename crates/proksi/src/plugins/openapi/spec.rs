@@ -0,0 +1,634 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use http::Method;
+use serde_json::Value;
+
+use super::schema::{resolve_ref, SchemaValidator};
+use crate::plugins::waf::url_decode;
+
+const METHODS: &[(&str, Method)] = &[
+    ("get", Method::GET),
+    ("put", Method::PUT),
+    ("post", Method::POST),
+    ("delete", Method::DELETE),
+    ("options", Method::OPTIONS),
+    ("head", Method::HEAD),
+    ("patch", Method::PATCH),
+    ("trace", Method::TRACE),
+];
+
+const IGNORED_HEADERS: &[&str] = &["accept", "content-type", "authorization"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterLocation {
+    Path,
+    Query,
+    Header,
+    Cookie,
+}
+
+impl ParameterLocation {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "path" => Ok(ParameterLocation::Path),
+            "query" => Ok(ParameterLocation::Query),
+            "header" => Ok(ParameterLocation::Header),
+            "cookie" => Ok(ParameterLocation::Cookie),
+            _ => bail!("invalid parameter location {value}"),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ParameterLocation::Path => "path",
+            ParameterLocation::Query => "query",
+            ParameterLocation::Header => "header",
+            ParameterLocation::Cookie => "cookie",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Parameter {
+    pub name: String,
+    pub location: ParameterLocation,
+    pub required: bool,
+    pub schema: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct RequestBody {
+    pub required: bool,
+    /// Media types (lowercase) with their schema
+    pub content: Vec<(String, Option<Value>)>,
+}
+
+impl RequestBody {
+    /// Returns the declared media type matching `content_type`
+    /// (`application/*` and `*/*` match any subtype)
+    pub fn media_type(&self, content_type: &str) -> Option<&(String, Option<Value>)> {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let main_type = content_type.split('/').next().unwrap_or_default();
+
+        self.content.iter().find(|(media_type, _)| {
+            *media_type == content_type
+                || media_type == "*/*"
+                || media_type
+                    .strip_suffix("/*")
+                    .is_some_and(|prefix| prefix == main_type)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Operation {
+    pub parameters: Vec<Parameter>,
+    pub body: Option<RequestBody>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+}
+
+#[derive(Debug)]
+struct PathTemplate {
+    segments: Vec<Segment>,
+    operations: HashMap<Method, Arc<Operation>>,
+}
+
+impl PathTemplate {
+    /// Returns the values of the path parameters if `segments` match the template
+    fn matches<'a>(&self, segments: &[&'a str]) -> Option<Vec<(&str, &'a str)>> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut parameters = vec![];
+        for (segment, value) in self.segments.iter().zip(segments) {
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Parameter(name) if !value.is_empty() => {
+                    parameters.push((name.as_str(), *value))
+                }
+                _ => return None,
+            }
+        }
+
+        Some(parameters)
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Parameter(_)))
+            .count()
+    }
+}
+
+/// Result of matching a request against the spec
+#[derive(Debug)]
+pub enum PathMatch<'a> {
+    Found {
+        operation: Arc<Operation>,
+        path_parameters: Vec<(&'a str, String)>,
+    },
+    UnknownPath,
+    UnknownMethod,
+}
+
+/// OpenAPI spec of a route
+pub struct Spec {
+    /// The whole document, `$ref`s are resolved against it
+    pub root: Value,
+    paths: Vec<PathTemplate>,
+}
+
+impl Spec {
+    pub fn parse(content: &str) -> Result<Self> {
+        // JSON is a subset of YAML
+        let root: Value = match serde_json::from_str(content) {
+            Ok(root) => root,
+            Err(_) => serde_yaml::from_str(content)?,
+        };
+
+        let version = root
+            .get("openapi")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("missing openapi version (only OpenAPI 3 is supported)"))?;
+        if !version.starts_with("3.") {
+            bail!("unsupported OpenAPI version {version}");
+        }
+
+        let mut paths = vec![];
+        if let Some(items) = root.get("paths").and_then(Value::as_object) {
+            for (template, item) in items {
+                paths.push(parse_path(&root, template, item)?);
+            }
+        }
+
+        // Concrete paths match before templated ones (e.g. `/pets/mine` before `/pets/{id}`)
+        paths.sort_by_key(PathTemplate::parameter_count);
+
+        Ok(Self { root, paths })
+    }
+
+    pub fn find(&self, method: &Method, path: &str) -> PathMatch<'_> {
+        let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        let mut found_path = false;
+
+        for template in &self.paths {
+            let Some(parameters) = template.matches(&segments) else {
+                continue;
+            };
+            found_path = true;
+
+            if let Some(operation) = template.operations.get(method) {
+                return PathMatch::Found {
+                    operation: operation.clone(),
+                    path_parameters: parameters
+                        .into_iter()
+                        .map(|(name, value)| (name, url_decode(value, false).into_owned()))
+                        .collect(),
+                };
+            }
+        }
+
+        if found_path {
+            PathMatch::UnknownMethod
+        } else {
+            PathMatch::UnknownPath
+        }
+    }
+}
+
+fn resolve<'a>(root: &'a Value, value: &'a Value) -> Result<&'a Value> {
+    match value.get("$ref").and_then(Value::as_str) {
+        Some(reference) => {
+            resolve_ref(root, reference).ok_or_else(|| anyhow!("unknown reference {reference}"))
+        }
+        None => Ok(value),
+    }
+}
+
+fn parse_path(root: &Value, template: &str, item: &Value) -> Result<PathTemplate> {
+    let segments = template
+        .trim_start_matches('/')
+        .split('/')
+        .map(
+            |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Parameter(name.to_string()),
+                None => Segment::Literal(segment.to_string()),
+            },
+        )
+        .collect();
+
+    let item = resolve(root, item)?;
+    let common_parameters = parse_parameters(root, item.get("parameters"))?;
+
+    let mut operations = HashMap::new();
+    for (key, method) in METHODS {
+        let Some(operation) = item.get(*key) else {
+            continue;
+        };
+
+        // Operation parameters override the parameters of the path with the same name
+        let mut parameters = parse_parameters(root, operation.get("parameters"))?;
+        for common in &common_parameters {
+            let overridden = parameters
+                .iter()
+                .any(|p| p.name == common.name && p.location == common.location);
+            if !overridden {
+                parameters.push(Parameter {
+                    name: common.name.clone(),
+                    location: common.location,
+                    required: common.required,
+                    schema: common.schema.clone(),
+                });
+            }
+        }
+
+        let body = operation
+            .get("requestBody")
+            .map(|body| parse_request_body(root, body))
+            .transpose()?;
+
+        operations.insert(method.clone(), Arc::new(Operation { parameters, body }));
+    }
+
+    Ok(PathTemplate {
+        segments,
+        operations,
+    })
+}
+
+fn parse_parameters(root: &Value, parameters: Option<&Value>) -> Result<Vec<Parameter>> {
+    let Some(parameters) = parameters.and_then(Value::as_array) else {
+        return Ok(vec![]);
+    };
+
+    let parameters = parameters
+        .iter()
+        .map(|parameter| {
+            let parameter = resolve(root, parameter)?;
+            let name = parameter
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("parameter without a name"))?;
+            let location = ParameterLocation::parse(
+                parameter
+                    .get("in")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("parameter {name} without a location"))?,
+            )?;
+
+            Ok(Parameter {
+                // Header names are case-insensitive
+                name: match location {
+                    ParameterLocation::Header => name.to_ascii_lowercase(),
+                    _ => name.to_string(),
+                },
+                location,
+                required: location == ParameterLocation::Path
+                    || parameter.get("required").and_then(Value::as_bool) == Some(true),
+                schema: parameter.get("schema").cloned(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // These headers are described by other fields of the spec and have to be ignored
+    Ok(parameters
+        .into_iter()
+        .filter(|p| {
+            p.location != ParameterLocation::Header || !IGNORED_HEADERS.contains(&p.name.as_str())
+        })
+        .collect())
+}
+
+fn parse_request_body(root: &Value, body: &Value) -> Result<RequestBody> {
+    let body = resolve(root, body)?;
+
+    let content = body
+        .get("content")
+        .and_then(Value::as_object)
+        .map(|content| {
+            content
+                .iter()
+                .map(|(media_type, media)| {
+                    (
+                        media_type.to_ascii_lowercase(),
+                        media.get("schema").cloned(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(RequestBody {
+        required: body.get("required").and_then(Value::as_bool) == Some(true),
+        content,
+    })
+}
+
+/// Values of the parameters of a request (query parameters can be repeated)
+pub struct RequestParameters<'a> {
+    pub path: Vec<(&'a str, String)>,
+    pub query: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    pub headers: &'a http::HeaderMap,
+    pub cookies: Vec<(&'a str, &'a str)>,
+}
+
+impl RequestParameters<'_> {
+    fn values(&self, parameter: &Parameter) -> Vec<&str> {
+        match parameter.location {
+            ParameterLocation::Path => self
+                .path
+                .iter()
+                .filter(|(name, _)| *name == parameter.name)
+                .map(|(_, value)| value.as_str())
+                .collect(),
+            ParameterLocation::Query => self
+                .query
+                .iter()
+                .filter(|(name, _)| *name == parameter.name)
+                .map(|(_, value)| value.as_ref())
+                .collect(),
+            ParameterLocation::Header => self
+                .headers
+                .get_all(parameter.name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect(),
+            ParameterLocation::Cookie => self
+                .cookies
+                .iter()
+                .filter(|(name, _)| *name == parameter.name)
+                .map(|(_, value)| *value)
+                .collect(),
+        }
+    }
+}
+
+impl Operation {
+    /// Validates the parameters of a request, adding the errors to the validator
+    pub fn validate_parameters(
+        &self,
+        root: &Value,
+        validator: &mut SchemaValidator,
+        parameters: &RequestParameters,
+    ) {
+        for parameter in &self.parameters {
+            let location = format!("{}.{}", parameter.location.as_str(), parameter.name);
+            let values = parameters.values(parameter);
+
+            if values.is_empty() {
+                if parameter.required {
+                    validator.error(&location, "is required");
+                }
+                continue;
+            }
+
+            let Some(schema) = parameter.schema.as_ref() else {
+                continue;
+            };
+
+            let value = coerce_parameter(root, schema, &values);
+            validator.validate(schema, &value, &location);
+        }
+    }
+}
+
+/// Parameters are strings, converted to the type of their schema before being validated
+fn coerce_parameter(root: &Value, schema: &Value, values: &[&str]) -> Value {
+    let schema = resolve(root, schema).unwrap_or(schema);
+    let schema_type = schema.get("type").and_then(Value::as_str);
+
+    if schema_type == Some("array") {
+        let item_schema = schema.get("items").unwrap_or(&Value::Null);
+
+        // `?id=1&id=2` (form, exploded) or `?id=1,2` (form, not exploded)
+        let items = if values.len() == 1 {
+            values[0].split(',').collect::<Vec<_>>()
+        } else {
+            values.to_vec()
+        };
+
+        return Value::Array(
+            items
+                .into_iter()
+                .map(|item| coerce_value(resolve(root, item_schema).unwrap_or(item_schema), item))
+                .collect(),
+        );
+    }
+
+    coerce_value(schema, values[0])
+}
+
+fn coerce_value(schema: &Value, value: &str) -> Value {
+    let types = match schema.get("type") {
+        Some(Value::String(name)) => vec![name.as_str()],
+        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+
+    for name in types {
+        let coerced = match name {
+            "integer" => value.parse::<i64>().ok().map(Value::from),
+            "number" => value
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            "boolean" => value.parse::<bool>().ok().map(Value::Bool),
+            "null" if value.is_empty() => Some(Value::Null),
+            _ => None,
+        };
+
+        if let Some(coerced) = coerced {
+            return coerced;
+        }
+    }
+
+    Value::String(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r##"
+openapi: 3.0.3
+info: { title: Pets, version: "1" }
+paths:
+  /pets:
+    get:
+      parameters:
+        - { name: limit, in: query, schema: { type: integer, maximum: 100 } }
+        - { name: tags, in: query, schema: { type: array, items: { type: string } } }
+        - { name: X-Tenant, in: header, required: true, schema: { type: string } }
+    post:
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema: { $ref: "#/components/schemas/Pet" }
+  /pets/mine:
+    get: {}
+  /pets/{id}:
+    parameters:
+      - $ref: "#/components/parameters/PetId"
+    get: {}
+    delete: {}
+components:
+  parameters:
+    PetId: { name: id, in: path, schema: { type: integer, minimum: 1 } }
+  schemas:
+    Pet:
+      type: object
+      required: [name]
+      properties: { name: { type: string } }
+"##;
+
+    #[test]
+    fn test_find_operation() {
+        let spec = Spec::parse(SPEC).unwrap();
+
+        let PathMatch::Found {
+            operation,
+            path_parameters,
+        } = spec.find(&Method::GET, "/pets/mine")
+        else {
+            panic!("operation not found");
+        };
+        assert!(operation.parameters.is_empty());
+        assert!(path_parameters.is_empty());
+
+        let PathMatch::Found {
+            operation,
+            path_parameters,
+        } = spec.find(&Method::DELETE, "/pets/42")
+        else {
+            panic!("operation not found");
+        };
+        assert_eq!(operation.parameters[0].name, "id");
+        assert_eq!(path_parameters, [("id", "42".to_string())]);
+
+        let PathMatch::Found { operation, .. } = spec.find(&Method::POST, "/pets") else {
+            panic!("operation not found");
+        };
+        let body = operation.body.as_ref().unwrap();
+        assert!(body.required);
+        assert!(body.media_type("application/json; charset=utf-8").is_some());
+        assert!(body.media_type("text/plain").is_none());
+
+        assert!(matches!(
+            spec.find(&Method::PUT, "/pets/42"),
+            PathMatch::UnknownMethod
+        ));
+        assert!(matches!(
+            spec.find(&Method::GET, "/owners"),
+            PathMatch::UnknownPath
+        ));
+        assert!(matches!(
+            spec.find(&Method::GET, "/pets/"),
+            PathMatch::UnknownPath
+        ));
+    }
+
+    #[test]
+    fn test_validate_parameters() {
+        let spec = Spec::parse(SPEC).unwrap();
+        let patterns = HashMap::new();
+        let mut headers = http::HeaderMap::new();
+
+        let validate = |headers: &http::HeaderMap,
+                        method: &Method,
+                        path: &str,
+                        query: &[(&'static str, &'static str)]| {
+            let PathMatch::Found {
+                operation,
+                path_parameters,
+            } = spec.find(method, path)
+            else {
+                panic!("operation not found");
+            };
+
+            let mut validator = SchemaValidator::new(&spec.root, &patterns);
+            operation.validate_parameters(
+                &spec.root,
+                &mut validator,
+                &RequestParameters {
+                    path: path_parameters,
+                    query: query
+                        .iter()
+                        .map(|(k, v)| (Cow::Borrowed(*k), Cow::Borrowed(*v)))
+                        .collect(),
+                    headers,
+                    cookies: vec![],
+                },
+            );
+            validator
+                .errors
+                .into_iter()
+                .map(|e| e.location)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            validate(&headers, &Method::GET, "/pets", &[]),
+            ["header.x-tenant"]
+        );
+
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        assert!(validate(
+            &headers,
+            &Method::GET,
+            "/pets",
+            &[("limit", "10"), ("tags", "a,b")]
+        )
+        .is_empty());
+        assert!(validate(
+            &headers,
+            &Method::GET,
+            "/pets",
+            &[("tags", "a"), ("tags", "b")]
+        )
+        .is_empty());
+        assert_eq!(
+            validate(&headers, &Method::GET, "/pets", &[("limit", "ten")]),
+            ["query.limit"]
+        );
+        assert_eq!(
+            validate(&headers, &Method::GET, "/pets", &[("limit", "1000")]),
+            ["query.limit"]
+        );
+
+        assert!(validate(&headers, &Method::GET, "/pets/1", &[]).is_empty());
+        assert_eq!(
+            validate(&headers, &Method::GET, "/pets/0", &[]),
+            ["path.id"]
+        );
+        assert_eq!(
+            validate(&headers, &Method::GET, "/pets/abc", &[]),
+            ["path.id"]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Spec::parse("swagger: '2.0'").is_err());
+        assert!(Spec::parse("openapi: 4.0.0").is_err());
+        assert!(Spec::parse(
+            "openapi: 3.1.0\npaths:\n  /a:\n    get:\n      parameters: [{ $ref: '#/missing' }]"
+        )
+        .is_err());
+        assert!(Spec::parse(r#"{ "openapi": "3.1.0", "paths": {} }"#).is_ok());
+    }
+}
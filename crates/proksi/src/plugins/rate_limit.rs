@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A fixed window counter
+struct Window {
+    started_at: AtomicU64,
+    count: AtomicU64,
+}
+
+/// A window of a rate limiter, as kept across restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowState {
+    /// Unix timestamp (in seconds) the window started at
+    pub started_at: u64,
+    pub count: u64,
+}
+
+/// Fixed window rate limiter, counting requests per identifier (e.g. an API key)
+/// shared by the plugins limiting requests
+#[derive(Default)]
+pub(super) struct RateLimiter {
+    windows: papaya::HashMap<String, Arc<Window>>,
+}
+
+impl RateLimiter {
+    /// Registers a new request for `id`. Returns `Err` with the number of seconds
+    /// until the window resets if the limit was reached.
+    pub fn check(&self, id: &str, limit: u64, window: Duration, now: u64) -> Result<(), u64> {
+        let window_secs = window.as_secs().max(1);
+
+        let current = self
+            .windows
+            .pin()
+            .get_or_insert_with(id.to_string(), || {
+                Arc::new(Window {
+                    started_at: AtomicU64::new(now),
+                    count: AtomicU64::new(0),
+                })
+            })
+            .clone();
+
+        let started_at = current.started_at.load(Ordering::Acquire);
+        if now.saturating_sub(started_at) >= window_secs
+            && current
+                .started_at
+                .compare_exchange(started_at, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            current.count.store(0, Ordering::Release);
+        }
+
+        let count = current.count.fetch_add(1, Ordering::AcqRel) + 1;
+        if count > limit {
+            let started_at = current.started_at.load(Ordering::Acquire);
+            return Err((started_at + window_secs).saturating_sub(now).max(1));
+        }
+
+        Ok(())
+    }
+
+    /// The windows of every identifier
+    pub fn windows(&self) -> BTreeMap<String, WindowState> {
+        self.windows
+            .pin()
+            .iter()
+            .map(|(id, window)| {
+                let state = WindowState {
+                    started_at: window.started_at.load(Ordering::Acquire),
+                    count: window.count.load(Ordering::Acquire),
+                };
+                (id.clone(), state)
+            })
+            .collect()
+    }
+
+    /// Restores the windows, the identifiers that already have one keep it
+    pub fn restore(&self, windows: BTreeMap<String, WindowState>) {
+        let current = self.windows.pin();
+        for (id, state) in windows {
+            current.get_or_insert_with(id, || {
+                Arc::new(Window {
+                    started_at: AtomicU64::new(state.started_at),
+                    count: AtomicU64::new(state.count),
+                })
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_window() {
+        let limiter = RateLimiter::default();
+        let window = Duration::from_secs(60);
+
+        assert!(limiter.check("a", 2, window, 1000).is_ok());
+        assert!(limiter.check("a", 2, window, 1010).is_ok());
+        assert_eq!(limiter.check("a", 2, window, 1020), Err(40));
+
+        // other keys have their own window
+        assert!(limiter.check("b", 2, window, 1020).is_ok());
+
+        // the window resets after 60 seconds
+        assert!(limiter.check("a", 2, window, 1060).is_ok());
+    }
+
+    #[test]
+    fn test_restore_windows() {
+        let limiter = RateLimiter::default();
+        let window = Duration::from_secs(60);
+        assert!(limiter.check("a", 1, window, 1000).is_ok());
+
+        let restored = RateLimiter::default();
+        restored.restore(limiter.windows());
+        assert_eq!(restored.check("a", 1, window, 1010), Err(50));
+        assert_eq!(
+            restored.windows()["a"],
+            WindowState {
+                started_at: 1000,
+                count: 2
+            }
+        );
+    }
+}
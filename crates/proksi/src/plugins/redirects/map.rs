@@ -0,0 +1,278 @@
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use http::StatusCode;
+use serde::Deserialize;
+
+/// A redirect of the map: the target of a path, or of every path under a prefix
+#[derive(Debug, Clone, PartialEq)]
+struct Redirect {
+    /// With a prefix source (`/blog/*`) and a target ending with `*`, the rest of the path
+    /// is appended to the target
+    target: String,
+    append_rest: bool,
+    status: Option<StatusCode>,
+}
+
+/// A line of a JSON map
+#[derive(Deserialize)]
+struct Entry {
+    source: String,
+    target: String,
+    status: Option<u16>,
+}
+
+/// Redirects of the paths of a host, read from a CSV or a JSON file. A lookup is a single
+/// hash for the exact paths, and one hash per segment of the path for the prefixes.
+#[derive(Debug, Default)]
+pub struct RedirectMap {
+    exact: HashMap<String, Redirect>,
+    /// By prefix, with its trailing slash (`/blog/`)
+    prefixes: HashMap<String, Redirect>,
+}
+
+impl RedirectMap {
+    /// Reads a map from its file, in JSON when its extension is `.json` and in CSV otherwise
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&content)
+        } else {
+            Self::from_csv(&content)
+        }
+    }
+
+    /// Lines are `source,target[,status]`. Empty lines, `#` comments and a header line
+    /// (`source,...`) are skipped, fields with commas are quoted (`"..."`).
+    pub fn from_csv(content: &str) -> Result<Self> {
+        let mut map = Self::default();
+        let mut first = true;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = csv_fields(line).map_err(|err| anyhow!("line {}: {err}", index + 1))?;
+            let is_header = fields
+                .first()
+                .is_some_and(|f| f.eq_ignore_ascii_case("source"));
+            if std::mem::take(&mut first) && is_header {
+                continue;
+            }
+
+            let (source, target, status) = match fields.as_slice() {
+                [source, target] => (source, target, None),
+                [source, target, status] if status.is_empty() => (source, target, None),
+                [source, target, status] => (source, target, Some(status.parse::<u16>()?)),
+                _ => bail!("line {}: expected source,target[,status]", index + 1),
+            };
+            map.insert(source, target, status)
+                .map_err(|err| anyhow!("line {}: {err}", index + 1))?;
+        }
+        Ok(map)
+    }
+
+    /// An array of `{ "source": "...", "target": "...", "status": 301 }`
+    pub fn from_json(content: &str) -> Result<Self> {
+        let entries: Vec<Entry> = serde_json::from_str(content)?;
+
+        let mut map = Self::default();
+        for (index, entry) in entries.iter().enumerate() {
+            map.insert(&entry.source, &entry.target, entry.status)
+                .map_err(|err| anyhow!("entry {index}: {err}"))?;
+        }
+        Ok(map)
+    }
+
+    /// Number of redirects, exact and prefixes
+    pub fn count(&self) -> usize {
+        self.exact.len() + self.prefixes.len()
+    }
+
+    fn insert(&mut self, source: &str, target: &str, status: Option<u16>) -> Result<()> {
+        if !source.starts_with('/') {
+            bail!("source {source:?} must be a path");
+        }
+        if target.is_empty() {
+            bail!("empty target of {source:?}");
+        }
+        let status = status.map(redirect_status).transpose()?;
+
+        let (target, append_rest) = match target.strip_suffix('*') {
+            Some(target) => (target.to_string(), true),
+            None => (target.to_string(), false),
+        };
+        let redirect = Redirect {
+            target,
+            append_rest,
+            status,
+        };
+
+        match source.strip_suffix('*') {
+            Some(prefix) if prefix.ends_with('/') => {
+                self.prefixes.insert(prefix.to_string(), redirect);
+            }
+            Some(_) => bail!("prefix {source:?} must end with /*"),
+            None => {
+                self.exact.insert(normalize(source).to_string(), redirect);
+            }
+        }
+        Ok(())
+    }
+
+    /// The location and the status (when the map sets one) of the redirect of a path.
+    /// Exact paths take precedence over prefixes, and longer prefixes over shorter ones.
+    pub fn lookup<'a>(&'a self, path: &str) -> Option<(Cow<'a, str>, Option<StatusCode>)> {
+        if let Some(redirect) = self.exact.get(normalize(path)) {
+            return Some((Cow::Borrowed(&redirect.target), redirect.status));
+        }
+        if self.prefixes.is_empty() {
+            return None;
+        }
+
+        let mut end = path.len();
+        while let Some(index) = path[..end].rfind('/') {
+            let (prefix, rest) = path.split_at(index + 1);
+            if let Some(redirect) = self.prefixes.get(prefix) {
+                let location = if redirect.append_rest {
+                    Cow::Owned(format!("{}{rest}", redirect.target))
+                } else {
+                    Cow::Borrowed(redirect.target.as_str())
+                };
+                return Some((location, redirect.status));
+            }
+            end = index;
+        }
+        None
+    }
+}
+
+/// Paths match with or without their trailing slash
+fn normalize(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path,
+    }
+}
+
+pub fn redirect_status(status: u16) -> Result<StatusCode> {
+    match status {
+        301 | 302 | 303 | 307 | 308 => Ok(StatusCode::from_u16(status)?),
+        _ => bail!("{status} is not a redirect status (301, 302, 303, 307 or 308)"),
+    }
+}
+
+/// Fields of a CSV line, trimmed. Quoted fields can contain commas, and `""` is a quote.
+fn csv_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("unterminated quoted field");
+    }
+    fields.push(field.trim().to_string());
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_csv() {
+        let map = RedirectMap::from_csv(
+            "source,target,status
+# moved in 2019
+/old-page,/new-page
+/about/,https://about.example.com/,302
+\"/a,b\",\"/c\"\"d\",
+/blog/*,/articles/*,308
+/shop/*,/store",
+        )
+        .unwrap();
+
+        assert_eq!(map.count(), 5);
+        assert_eq!(map.lookup("/old-page"), Some(("/new-page".into(), None)));
+        assert_eq!(map.lookup("/old-page/"), Some(("/new-page".into(), None)));
+        assert_eq!(
+            map.lookup("/about"),
+            Some(("https://about.example.com/".into(), Some(StatusCode::FOUND)))
+        );
+        assert_eq!(map.lookup("/a,b"), Some(("/c\"d".into(), None)));
+        assert_eq!(
+            map.lookup("/blog/2019/hello"),
+            Some((
+                "/articles/2019/hello".into(),
+                Some(StatusCode::PERMANENT_REDIRECT)
+            ))
+        );
+        assert_eq!(map.lookup("/shop/cart/1"), Some(("/store".into(), None)));
+        assert_eq!(map.lookup("/blog"), None);
+        assert_eq!(map.lookup("/missing"), None);
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let map = RedirectMap::from_csv(
+            "/docs/*,/v1/*
+/docs/api/*,/reference/*
+/docs/api/auth,/guides/auth",
+        )
+        .unwrap();
+
+        assert_eq!(map.lookup("/docs/intro"), Some(("/v1/intro".into(), None)));
+        assert_eq!(
+            map.lookup("/docs/api/users"),
+            Some(("/reference/users".into(), None))
+        );
+        assert_eq!(
+            map.lookup("/docs/api/auth"),
+            Some(("/guides/auth".into(), None))
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let map = RedirectMap::from_json(
+            r#"[
+                { "source": "/old", "target": "/new" },
+                { "source": "/tmp/*", "target": "/", "status": 307 }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(map.lookup("/old"), Some(("/new".into(), None)));
+        assert_eq!(
+            map.lookup("/tmp/file"),
+            Some(("/".into(), Some(StatusCode::TEMPORARY_REDIRECT)))
+        );
+    }
+
+    #[test]
+    fn test_invalid_maps() {
+        assert!(RedirectMap::from_csv("old,/new").is_err());
+        assert!(RedirectMap::from_csv("/old,/new,200").is_err());
+        assert!(RedirectMap::from_csv("/old").is_err());
+        assert!(RedirectMap::from_csv("/old*,/new").is_err());
+        assert!(RedirectMap::from_csv("\"/old,/new").is_err());
+        assert!(RedirectMap::from_json(r#"[{ "source": "/old" }]"#).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{settings_cache::SettingsCache, MiddlewarePlugin};
+
+use map::{redirect_status, RedirectMap};
+
+mod map;
+
+/// How often (in seconds) the maps are checked for changes by default
+const DEFAULT_RELOAD_INTERVAL: u64 = 60;
+
+/// A map loaded in memory, along with the information needed to know when the file has to
+/// be read again
+struct LoadedMap {
+    map: Arc<RedirectMap>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+/// Keeps the maps in memory, reloading them whenever they change on disk
+#[derive(Default)]
+struct MapStore {
+    maps: papaya::HashMap<PathBuf, Arc<LoadedMap>>,
+}
+
+impl MapStore {
+    /// Returns the map of the file, reading it again if it was modified since the last check.
+    /// A map that can't be read again is kept (an empty one if it was never read), the file
+    /// is read again at the next check.
+    fn get(&self, path: &Path, reload_interval: Duration) -> Arc<RedirectMap> {
+        let maps = self.maps.pin();
+        let current = maps.get(path);
+        if let Some(loaded) = current.filter(|loaded| loaded.checked_at.elapsed() < reload_interval)
+        {
+            return loaded.map.clone();
+        }
+
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let unchanged = current.filter(|loaded| modified.is_some() && modified == loaded.modified);
+        let (map, modified) = match unchanged {
+            Some(loaded) => (loaded.map.clone(), modified),
+            None => match RedirectMap::from_file(path) {
+                Ok(map) => {
+                    tracing::info!("loaded {} redirects from {path:?}", map.count());
+                    (Arc::new(map), modified)
+                }
+                Err(err) => {
+                    tracing::error!("failed to load the redirects of {path:?}: {err}");
+                    match current {
+                        Some(loaded) => (loaded.map.clone(), loaded.modified),
+                        None => (Arc::default(), None),
+                    }
+                }
+            },
+        };
+
+        maps.insert(
+            path.to_path_buf(),
+            Arc::new(LoadedMap {
+                map: map.clone(),
+                modified,
+                checked_at: Instant::now(),
+            }),
+        );
+        map
+    }
+}
+
+/// Per-route settings of the redirects plugin
+#[derive(Debug)]
+struct RedirectsSettings {
+    file: PathBuf,
+    /// Status of the redirects that don't set one
+    status: StatusCode,
+    /// The query of the request is added to the location
+    preserve_query: bool,
+    reload_interval: Duration,
+}
+
+impl RedirectsSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let file = config
+            .get("file")
+            .and_then(serde_json::Value::as_str)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("Missing or invalid file"))?;
+
+        let status = match config.get("status") {
+            Some(status) => {
+                let status = status
+                    .as_u64()
+                    .and_then(|v| u16::try_from(v).ok())
+                    .ok_or_else(|| anyhow!("Missing or invalid status"))?;
+                redirect_status(status)?
+            }
+            None => StatusCode::MOVED_PERMANENTLY,
+        };
+
+        Ok(Self {
+            file,
+            status,
+            preserve_query: config
+                .get("preserve_query")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(true),
+            reload_interval: Duration::from_secs(
+                config
+                    .get("reload_interval")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_RELOAD_INTERVAL),
+            ),
+        })
+    }
+}
+
+/// The location of a redirect, with the query of the request
+fn location<'a>(target: &'a str, query: Option<&str>) -> Cow<'a, str> {
+    match query.filter(|q| !q.is_empty()) {
+        Some(query) if target.contains('?') => Cow::Owned(format!("{target}&{query}")),
+        Some(query) => Cow::Owned(format!("{target}?{query}")),
+        None => Cow::Borrowed(target),
+    }
+}
+
+/// Redirects the paths of a route from a map file (e.g. the legacy URLs of a migrated
+/// website), reloaded whenever the file changes
+pub struct Redirects {
+    settings: SettingsCache<RedirectsSettings>,
+    maps: MapStore,
+}
+
+impl Redirects {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+            maps: MapStore::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Redirects {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let settings = match self.settings.get_or_try_build(
+            ctx.route_container.id,
+            config,
+            RedirectsSettings::from_config,
+        ) {
+            Ok(settings) => settings,
+            Err(err) => {
+                tracing::error!("invalid redirects plugin configuration: {err}");
+                return Ok(false);
+            }
+        };
+
+        let map = self.maps.get(&settings.file, settings.reload_interval);
+        let uri = &session.req_header().uri;
+        let Some((target, status)) = map.lookup(uri.path()) else {
+            return Ok(false);
+        };
+
+        let query = uri.query().filter(|_| settings.preserve_query);
+        let status = status.unwrap_or(settings.status);
+        let mut res_headers = ResponseHeader::build_no_case(status, Some(2))?;
+        res_headers.insert_header(header::LOCATION, location(&target, query).as_ref())?;
+        res_headers.insert_header(header::CONTENT_LENGTH, 0)?;
+
+        metrics::REDIRECTS
+            .with_label_values(&[ctx.host.as_str(), status.as_str()])
+            .inc();
+
+        session
+            .write_response_header(Box::new(res_headers), true)
+            .await?;
+        Ok(true)
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(values: serde_json::Value) -> HashMap<Cow<'static, str>, serde_json::Value> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings =
+            RedirectsSettings::from_config(&config(serde_json::json!({ "file": "a.csv" })))
+                .unwrap();
+        assert_eq!(settings.status, StatusCode::MOVED_PERMANENTLY);
+        assert!(settings.preserve_query);
+
+        assert!(RedirectsSettings::from_config(&config(serde_json::json!({}))).is_err());
+        assert!(RedirectsSettings::from_config(&config(
+            serde_json::json!({ "file": "a.csv", "status": 404 })
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!(location("/new", None), "/new");
+        assert_eq!(location("/new", Some("a=1")), "/new?a=1");
+        assert_eq!(location("/new?lang=en", Some("a=1")), "/new?lang=en&a=1");
+        assert_eq!(location("/new", Some("")), "/new");
+    }
+
+    #[test]
+    fn test_map_store_reload() {
+        let path = std::env::temp_dir().join(format!("{}.csv", uuid::Uuid::new_v4()));
+        let store = MapStore::default();
+
+        // Missing files are an empty map, until they're created
+        assert_eq!(store.get(&path, Duration::ZERO).count(), 0);
+
+        std::fs::write(&path, "/a,/b").unwrap();
+        let map = store.get(&path, Duration::ZERO);
+        assert!(map.lookup("/a").is_some());
+
+        // Invalid changes keep the previous map
+        std::fs::write(&path, "/a,/b,200").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(store.get(&path, Duration::ZERO).lookup("/a").is_some());
+
+        // Within the interval, the file isn't checked
+        std::fs::write(&path, "/c,/d").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(20))
+            .unwrap();
+        assert!(store
+            .get(&path, Duration::from_secs(60))
+            .lookup("/c")
+            .is_none());
+        assert!(store.get(&path, Duration::ZERO).lookup("/c").is_some());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,335 @@
+use std::{borrow::Cow, collections::HashMap, io::Write};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::write::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use http::{header, StatusCode, Version};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, MiddlewarePlugin};
+
+/// Default maximum size (in bytes) of a decompressed body
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default maximum ratio between the decompressed and the compressed size
+const DEFAULT_MAX_RATIO: u64 = 100;
+
+/// Compressed data is fed to the decoder in slices of this size, so that a single
+/// chunk can't expand much past the limits before they are checked (deflate can
+/// expand ~1000 times at most)
+const INPUT_SLICE_SIZE: usize = 1024;
+
+enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    /// Raw deflate stream (sent by some clients as `deflate` instead of zlib)
+    Deflate(DeflateDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Decoder::Gzip(d) => d.write_all(data),
+            Decoder::Zlib(d) => d.write_all(data),
+            Decoder::Deflate(d) => d.write_all(data),
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Decoder::Gzip(d) => d.try_finish(),
+            Decoder::Zlib(d) => d.try_finish(),
+            Decoder::Deflate(d) => d.try_finish(),
+        }
+    }
+
+    /// Takes the data decompressed so far
+    fn take_output(&mut self) -> Vec<u8> {
+        let output = match self {
+            Decoder::Gzip(d) => d.get_mut(),
+            Decoder::Zlib(d) => d.get_mut(),
+            Decoder::Deflate(d) => d.get_mut(),
+        };
+        std::mem::take(output)
+    }
+}
+
+/// Limits applied to a decompressed body, to prevent decompression bombs
+struct Limits {
+    max_size: u64,
+    max_ratio: u64,
+    compressed_size: u64,
+    decompressed_size: u64,
+}
+
+impl Limits {
+    fn add(&mut self, compressed: usize, decompressed: usize) -> Result<()> {
+        self.compressed_size += compressed as u64;
+        self.decompressed_size += decompressed as u64;
+
+        if self.decompressed_size > self.max_size {
+            bail!("decompressed body is larger than {} bytes", self.max_size);
+        }
+
+        // Small bodies are allowed to have high ratios (e.g. repetitive JSON payloads)
+        let ratio_limit = self.compressed_size.max(1024) * self.max_ratio;
+        if self.decompressed_size > ratio_limit {
+            bail!("decompressed body exceeds the ratio of {}", self.max_ratio);
+        }
+
+        Ok(())
+    }
+}
+
+/// Streaming decompressor of a request body, enforcing size limits
+pub struct RequestDecompressor {
+    /// Created with the first chunk (the `deflate` format is only known from its first byte)
+    decoder: Option<Decoder>,
+    encoding: &'static str,
+    limits: Limits,
+}
+
+impl RequestDecompressor {
+    fn new(encoding: &'static str, max_size: u64, max_ratio: u64) -> Self {
+        Self {
+            decoder: None,
+            encoding,
+            limits: Limits {
+                max_size,
+                max_ratio,
+                compressed_size: 0,
+                decompressed_size: 0,
+            },
+        }
+    }
+
+    /// Decompresses a chunk of the body, returning the decompressed data
+    pub fn decompress(&mut self, data: &[u8], end: bool) -> Result<Bytes> {
+        if let (None, Some(first_byte)) = (&self.decoder, data.first()) {
+            self.decoder = Some(match self.encoding {
+                "gzip" | "x-gzip" => Decoder::Gzip(GzDecoder::new(vec![])),
+                // zlib streams always start with 0x?8 (deflate compression method)
+                _ if first_byte & 0x0F == 0x08 => Decoder::Zlib(ZlibDecoder::new(vec![])),
+                _ => Decoder::Deflate(DeflateDecoder::new(vec![])),
+            });
+        }
+
+        let Some(decoder) = self.decoder.as_mut() else {
+            return Ok(Bytes::new());
+        };
+
+        let mut output = Vec::with_capacity(data.len() * 4);
+
+        for slice in data.chunks(INPUT_SLICE_SIZE) {
+            decoder.write_all(slice)?;
+            let decompressed = decoder.take_output();
+            self.limits.add(slice.len(), decompressed.len())?;
+            output.extend(decompressed);
+        }
+
+        if end {
+            decoder.finish()?;
+            let decompressed = decoder.take_output();
+            self.limits.add(0, decompressed.len())?;
+            output.extend(decompressed);
+        }
+
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Decompresses gzip/deflate request bodies before sending them to the upstream
+pub struct RequestDecompression;
+
+impl RequestDecompression {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn get_limit(
+        config: &HashMap<Cow<'static, str>, serde_json::Value>,
+        key: &str,
+    ) -> Result<Option<u64>> {
+        config
+            .get(key)
+            .map(|v| {
+                v.as_u64()
+                    .filter(|v| *v > 0)
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+            })
+            .transpose()
+    }
+}
+
+/// Returns the encoding of the body, if it can be decompressed
+fn supported_encoding(req: &RequestHeader) -> Option<&'static str> {
+    let encoding = req.headers.get(header::CONTENT_ENCODING)?.to_str().ok()?;
+
+    match encoding.trim().to_ascii_lowercase().as_str() {
+        "gzip" => Some("gzip"),
+        "x-gzip" => Some("x-gzip"),
+        "deflate" => Some("deflate"),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for RequestDecompression {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let limits = Self::get_limit(config, "max_size")
+            .and_then(|size| Ok((size, Self::get_limit(config, "max_ratio")?)));
+
+        let (max_size, max_ratio) = match limits {
+            Ok(limits) => limits,
+            Err(err) => {
+                tracing::error!("invalid request_decompression plugin configuration: {err}");
+                return respond_with_status(session, StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        // Other encodings (or no encoding at all) are sent as is
+        let Some(encoding) = supported_encoding(session.req_header()) else {
+            return Ok(false);
+        };
+
+        ctx.request_decompressor = Some(RequestDecompressor::new(
+            encoding,
+            max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_ratio.unwrap_or(DEFAULT_MAX_RATIO),
+        ));
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        if ctx.request_decompressor.is_none() {
+            return Ok(());
+        }
+
+        // The decompressed length is only known once the whole body is read
+        upstream_request.remove_header(&header::CONTENT_ENCODING);
+        upstream_request.remove_header(&header::CONTENT_LENGTH);
+        if upstream_request.version != Version::HTTP_2 {
+            upstream_request.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decompress_in_chunks() {
+        let body = "some json payload ".repeat(200);
+        let compressed = compress(body.as_bytes());
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+
+        let mut decompressor =
+            RequestDecompressor::new("gzip", DEFAULT_MAX_SIZE, DEFAULT_MAX_RATIO);
+        let mut output = decompressor.decompress(first, false).unwrap().to_vec();
+        output.extend(decompressor.decompress(second, false).unwrap());
+        output.extend(decompressor.decompress(&[], true).unwrap());
+
+        assert_eq!(output, body.as_bytes());
+    }
+
+    #[test]
+    fn test_deflate_formats() {
+        let body = b"hello deflate";
+
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(body).unwrap();
+        let mut decompressor =
+            RequestDecompressor::new("deflate", DEFAULT_MAX_SIZE, DEFAULT_MAX_RATIO);
+        let output = decompressor
+            .decompress(&zlib.finish().unwrap(), true)
+            .unwrap();
+        assert_eq!(&output[..], body);
+
+        let mut raw = flate2::write::DeflateEncoder::new(vec![], Compression::default());
+        raw.write_all(body).unwrap();
+        let mut decompressor =
+            RequestDecompressor::new("deflate", DEFAULT_MAX_SIZE, DEFAULT_MAX_RATIO);
+        let output = decompressor
+            .decompress(&raw.finish().unwrap(), true)
+            .unwrap();
+        assert_eq!(&output[..], body);
+    }
+
+    #[test]
+    fn test_limits() {
+        // ~10MB of zeros compress to a few KB
+        let bomb = compress(&vec![0; 10 * 1024 * 1024]);
+
+        let mut decompressor = RequestDecompressor::new("gzip", 1024 * 1024, 10_000);
+        assert!(decompressor.decompress(&bomb, true).is_err());
+        assert!(decompressor.limits.decompressed_size < 2 * 1024 * 1024);
+
+        let mut decompressor = RequestDecompressor::new("gzip", DEFAULT_MAX_SIZE * 2, 10);
+        assert!(decompressor.decompress(&bomb, true).is_err());
+
+        let mut decompressor = RequestDecompressor::new("gzip", 1024, DEFAULT_MAX_RATIO);
+        assert!(decompressor.decompress(b"not gzip", true).is_err());
+    }
+
+    #[test]
+    fn test_supported_encoding() {
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        assert_eq!(supported_encoding(&req), None);
+
+        req.insert_header(header::CONTENT_ENCODING, "GZIP").unwrap();
+        assert_eq!(supported_encoding(&req), Some("gzip"));
+
+        req.insert_header(header::CONTENT_ENCODING, "br").unwrap();
+        assert_eq!(supported_encoding(&req), None);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, Method, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+use regex::bytes::Regex;
+
+use crate::{
+    config::RoutePlugin,
+    proxy_server::{compression::has_content_type, https_proxy::RouterContext},
+};
+
+use super::{settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Default maximum length of a regex match, see [`RewriteRule::window`]
+const DEFAULT_MAX_MATCH_SIZE: usize = 1024;
+
+/// Content types rewritten by default
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+];
+
+/// A substitution applied to the response body
+#[derive(Debug)]
+pub(super) struct RewriteRule {
+    pattern: Regex,
+    replacement: Vec<u8>,
+    /// Data kept between chunks, as a match may span multiple chunks.
+    /// Any match must fit in this window.
+    window: usize,
+    /// Maximum number of replacements in a response
+    limit: Option<usize>,
+}
+
+impl RewriteRule {
+    fn from_config(value: &serde_json::Value, max_match_size: usize) -> Result<Self> {
+        let replacement = value
+            .get("replace")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing or invalid replace"))?;
+
+        let search = value.get("search").and_then(|v| v.as_str());
+        let pattern = value.get("pattern").and_then(|v| v.as_str());
+
+        match (search, pattern) {
+            (Some(search), None) if !search.is_empty() => Ok(Self {
+                pattern: Regex::new(&regex::escape(search))?,
+                replacement: escape_replacement(replacement).into_bytes(),
+                window: search.len(),
+                limit: None,
+            }),
+            (None, Some(pattern)) => Ok(Self {
+                pattern: Regex::new(pattern)?,
+                replacement: replacement.as_bytes().to_vec(),
+                window: max_match_size,
+                limit: None,
+            }),
+            _ => bail!("Each rule requires either a search or a pattern"),
+        }
+    }
+
+    /// Inserts `fragment` before the first occurrence of `tag` (ignoring its case)
+    pub(super) fn insert_before(tag: &str, fragment: &str) -> Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(&format!("(?i){}", regex::escape(tag)))?,
+            replacement: format!("{}$0", escape_replacement(fragment)).into_bytes(),
+            window: tag.len(),
+            limit: Some(1),
+        })
+    }
+}
+
+/// Literal replacements don't expand `$1` references
+fn escape_replacement(value: &str) -> String {
+    value.replace('$', "$$")
+}
+
+/// Per-route settings of the response rewrite plugin
+#[derive(Debug)]
+struct ResponseRewriteSettings {
+    rules: Vec<Arc<RewriteRule>>,
+    content_types: Vec<String>,
+}
+
+impl ResponseRewriteSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let max_match_size = config
+            .get("max_match_size")
+            .map(|v| {
+                v.as_u64()
+                    .filter(|v| *v > 0)
+                    .ok_or_else(|| anyhow!("Missing or invalid max_match_size"))
+            })
+            .transpose()?
+            .map_or(Ok(DEFAULT_MAX_MATCH_SIZE), usize::try_from)?;
+
+        let rules = config
+            .get("rules")
+            .and_then(|v| v.as_array())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Missing or invalid rules"))?
+            .iter()
+            .map(|rule| RewriteRule::from_config(rule, max_match_size).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        let content_types = match config.get("content_types") {
+            Some(values) => values
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid content_types"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(str::to_ascii_lowercase)
+                        .ok_or_else(|| anyhow!("Missing or invalid content_types"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        };
+
+        Ok(Self {
+            rules,
+            content_types,
+        })
+    }
+}
+
+/// A rule applied to the current response
+struct ActiveRule {
+    rule: Arc<RewriteRule>,
+    /// Data not processed yet (the output of a rule is the input of the next one)
+    pending: Vec<u8>,
+    replacements: usize,
+}
+
+/// Streaming rewriter of a response body, kept in the request context
+/// (shared by the `response_rewrite` and `html_inject` plugins)
+#[derive(Default)]
+pub struct BodyRewriter {
+    rules: Vec<ActiveRule>,
+}
+
+impl BodyRewriter {
+    /// Adds rules to apply after the current ones
+    pub(super) fn add_rules(&mut self, rules: &[Arc<RewriteRule>]) {
+        self.rules.extend(rules.iter().map(|rule| ActiveRule {
+            rule: rule.clone(),
+            pending: vec![],
+            replacements: 0,
+        }));
+    }
+
+    /// Rewrites a chunk of the body. Data that could still be part of a match
+    /// is kept until the next chunk (or the end of the body).
+    pub fn rewrite(&mut self, data: &[u8], end: bool) -> Bytes {
+        let mut output = data.to_vec();
+
+        for rule in &mut self.rules {
+            rule.pending.extend_from_slice(&output);
+            output = rule.rewrite_chunk(end);
+        }
+
+        Bytes::from(output)
+    }
+}
+
+impl ActiveRule {
+    /// Replaces the matches in the pending data, returning the data that can be sent
+    /// and keeping the rest (the end of the window, or a match that could still grow)
+    fn rewrite_chunk(&mut self, end: bool) -> Vec<u8> {
+        let pending = &mut self.pending;
+
+        if self
+            .rule
+            .limit
+            .is_some_and(|limit| self.replacements >= limit)
+        {
+            return std::mem::take(pending);
+        }
+
+        // Any match starting before this point is complete
+        let mut cut = if end {
+            pending.len()
+        } else {
+            pending.len().saturating_sub(self.rule.window)
+        };
+
+        let mut output = Vec::with_capacity(pending.len());
+        let mut last = 0;
+
+        for captures in self.rule.pattern.captures_iter(pending) {
+            let Some(found) = captures.get(0) else {
+                continue;
+            };
+
+            if found.start() >= cut {
+                break;
+            }
+
+            // Greedy patterns could match more data with the next chunk
+            if !end && found.end() == pending.len() {
+                cut = found.start();
+                break;
+            }
+
+            output.extend_from_slice(&pending[last..found.start()]);
+            captures.expand(&self.rule.replacement, &mut output);
+            last = found.end();
+            cut = cut.max(last);
+
+            self.replacements += 1;
+            if self
+                .rule
+                .limit
+                .is_some_and(|limit| self.replacements >= limit)
+            {
+                cut = pending.len();
+                break;
+            }
+        }
+
+        output.extend_from_slice(&pending[last..cut]);
+        pending.drain(..cut);
+
+        output
+    }
+}
+
+/// Whether the response has a body that can be rewritten
+pub(super) fn is_rewritable(req: &RequestHeader, resp: &ResponseHeader) -> bool {
+    let has_body = req.method != Method::HEAD
+        && resp.status != StatusCode::NO_CONTENT
+        && resp.status != StatusCode::NOT_MODIFIED
+        && resp.status != StatusCode::PARTIAL_CONTENT
+        && !resp.status.is_informational();
+
+    let encoded = resp
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| v.as_bytes() != b"identity");
+
+    has_body && !encoded
+}
+
+/// Updates the headers of a response whose body is rewritten
+pub(super) fn prepare_response(resp: &mut ResponseHeader) -> Result<()> {
+    // The length is only known once the whole body is rewritten
+    resp.remove_header(&header::CONTENT_LENGTH);
+    resp.remove_header(&header::ACCEPT_RANGES);
+    resp.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+
+    if let Some(etag) = resp.headers.get(header::ETAG).cloned() {
+        if etag.as_bytes().starts_with(b"\"") {
+            resp.insert_header(header::ETAG, [b"W/", etag.as_bytes()].concat())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites response bodies with string/regex substitutions
+pub struct ResponseRewrite {
+    settings: SettingsCache<ResponseRewriteSettings>,
+}
+
+impl ResponseRewrite {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for ResponseRewrite {
+    // Nothing to do before the upstream request
+    async fn request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        // Compressed bodies can't be rewritten (responses can still be compressed by Proksi)
+        upstream_request.remove_header(&header::ACCEPT_ENCODING);
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(plugin) = ctx.route_container.plugins.get("response_rewrite") else {
+            return Ok(());
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let settings = self
+            .settings
+            .get_or_try_build(
+                ctx.route_container.id,
+                config,
+                ResponseRewriteSettings::from_config,
+            )
+            .inspect_err(|err| {
+                tracing::error!("invalid response_rewrite plugin configuration: {err}");
+            })?;
+
+        if !is_rewritable(session.req_header(), upstream_response)
+            || !has_content_type(upstream_response, &settings.content_types)
+        {
+            return Ok(());
+        }
+
+        prepare_response(upstream_response)?;
+        ctx.body_rewriter
+            .get_or_insert_with(BodyRewriter::default)
+            .add_rules(&settings.rules);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rewriter(rules: serde_json::Value) -> BodyRewriter {
+        let config = HashMap::from([
+            (Cow::Borrowed("rules"), rules),
+            (Cow::Borrowed("max_match_size"), json!(32)),
+        ]);
+        let mut rewriter = BodyRewriter::default();
+        rewriter.add_rules(&ResponseRewriteSettings::from_config(&config).unwrap().rules);
+        rewriter
+    }
+
+    /// Rewrites the body, split in chunks of `size` bytes
+    fn rewrite_in_chunks(rewriter: &mut BodyRewriter, body: &str, size: usize) -> String {
+        let mut output = vec![];
+        for chunk in body.as_bytes().chunks(size) {
+            output.extend(rewriter.rewrite(chunk, false));
+        }
+        output.extend(rewriter.rewrite(&[], true));
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_across_chunks() {
+        let body =
+            r#"<a href="http://internal-host/a">a</a> <img src="http://internal-host/b.png">"#
+                .repeat(20);
+        let expected = body.replace("http://internal-host", "https://example.com");
+
+        for size in [1, 3, 7, 19, 64, 4096] {
+            let mut rewriter = rewriter(json!([
+                { "search": "http://internal-host", "replace": "https://example.com" }
+            ]));
+            assert_eq!(rewrite_in_chunks(&mut rewriter, &body, size), expected);
+        }
+    }
+
+    #[test]
+    fn test_regex_rules() {
+        let body = "api: internal-users.local, internal-orders.local; price: $5";
+
+        for size in [1, 4, 100] {
+            let mut rewriter = rewriter(json!([
+                { "pattern": "internal-([a-z]+)\\.local", "replace": "$1.example.com" },
+                { "search": "$5", "replace": "$10" }
+            ]));
+            assert_eq!(
+                rewrite_in_chunks(&mut rewriter, body, size),
+                "api: users.example.com, orders.example.com; price: $10"
+            );
+        }
+
+        // Greedy patterns are only replaced once the match can't grow anymore
+        let mut rewriter = rewriter(json!([{ "pattern": "a+", "replace": "b" }]));
+        assert_eq!(rewrite_in_chunks(&mut rewriter, "xaaaay", 2), "xby");
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let settings = |config: serde_json::Value| {
+            let config = config
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (Cow::Owned(k.clone()), v.clone()))
+                .collect();
+            ResponseRewriteSettings::from_config(&config)
+        };
+
+        let defaults = settings(json!({ "rules": [{ "search": "a", "replace": "b" }] })).unwrap();
+        assert_eq!(defaults.content_types.len(), DEFAULT_CONTENT_TYPES.len());
+
+        assert!(settings(json!({})).is_err());
+        assert!(settings(json!({ "rules": [{ "search": "a" }] })).is_err());
+        assert!(settings(json!({ "rules": [{ "replace": "b" }] })).is_err());
+        assert!(settings(json!({ "rules": [{ "pattern": "(", "replace": "b" }] })).is_err());
+    }
+
+    #[test]
+    fn test_is_rewritable() {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        assert!(is_rewritable(&req, &resp));
+
+        resp.insert_header(header::CONTENT_ENCODING, "gzip")
+            .unwrap();
+        assert!(!is_rewritable(&req, &resp));
+
+        let head = RequestHeader::build("HEAD", b"/", None).unwrap();
+        let resp = ResponseHeader::build(200, None).unwrap();
+        assert!(!is_rewritable(&head, &resp));
+    }
+}
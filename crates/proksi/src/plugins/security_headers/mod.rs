@@ -0,0 +1,336 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use http::{HeaderName, HeaderValue};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::{settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Configuration keys of the supported headers, with their default values
+const SECURITY_HEADERS: &[(&str, &str, Option<&str>)] = &[
+    ("content_security_policy", "content-security-policy", None),
+    (
+        "x_content_type_options",
+        "x-content-type-options",
+        Some("nosniff"),
+    ),
+    ("x_frame_options", "x-frame-options", Some("SAMEORIGIN")),
+    (
+        "referrer_policy",
+        "referrer-policy",
+        Some("strict-origin-when-cross-origin"),
+    ),
+    ("permissions_policy", "permissions-policy", None),
+    (
+        "strict_transport_security",
+        "strict-transport-security",
+        None,
+    ),
+];
+
+/// How a header is combined with the one sent by the upstream
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeaderMode {
+    /// Replaces the upstream header
+    Override,
+    /// Adds the header, keeping the upstream one (browsers enforce every CSP header)
+    Append,
+    /// Only adds the header if the upstream didn't send it
+    Default,
+}
+
+impl HeaderMode {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "override" => Ok(HeaderMode::Override),
+            "append" => Ok(HeaderMode::Append),
+            "default" => Ok(HeaderMode::Default),
+            _ => bail!("invalid mode {value}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SecurityHeader {
+    name: HeaderName,
+    value: HeaderValue,
+    mode: HeaderMode,
+}
+
+/// Per-route settings of the security headers plugin
+#[derive(Debug)]
+struct SecurityHeadersSettings {
+    headers: Vec<SecurityHeader>,
+}
+
+impl SecurityHeadersSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let default_mode = match config.get("mode") {
+            Some(mode) => HeaderMode::parse(
+                mode.as_str()
+                    .ok_or_else(|| anyhow!("Missing or invalid mode"))?,
+            )?,
+            None => HeaderMode::Override,
+        };
+
+        let mut headers = vec![];
+
+        for (key, name, default_value) in SECURITY_HEADERS {
+            let (value, mode) = match config.get(*key) {
+                None => (default_value.map(ToString::to_string), default_mode),
+                Some(value) => parse_header_value(value, default_mode)
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}"))??,
+            };
+
+            if let Some(value) = value {
+                headers.push(SecurityHeader {
+                    name: HeaderName::from_static(name),
+                    value: HeaderValue::try_from(value)?,
+                    mode,
+                });
+            }
+        }
+
+        // Any other header (e.g. Cross-Origin-Opener-Policy)
+        if let Some(custom) = config.get("headers") {
+            let custom = custom
+                .as_object()
+                .ok_or_else(|| anyhow!("Missing or invalid headers"))?;
+
+            for (name, value) in custom {
+                let (value, mode) = parse_header_value(value, default_mode)
+                    .ok_or_else(|| anyhow!("Missing or invalid header {name}"))??;
+
+                if let Some(value) = value {
+                    headers.push(SecurityHeader {
+                        name: HeaderName::try_from(name.as_str())?,
+                        value: HeaderValue::try_from(value)?,
+                        mode,
+                    });
+                }
+            }
+        }
+
+        Ok(Self { headers })
+    }
+
+    fn apply(&self, resp: &mut ResponseHeader) -> Result<()> {
+        for header in &self.headers {
+            match header.mode {
+                HeaderMode::Override => {
+                    resp.insert_header(header.name.clone(), header.value.clone())?;
+                }
+                HeaderMode::Append => {
+                    resp.append_header(header.name.clone(), header.value.clone())?;
+                }
+                HeaderMode::Default if !resp.headers.contains_key(&header.name) => {
+                    resp.insert_header(header.name.clone(), header.value.clone())?;
+                }
+                HeaderMode::Default => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A header is either configured as its value, as `{ value, mode }`,
+/// or disabled with `false` (or an empty value)
+fn parse_header_value(
+    value: &serde_json::Value,
+    default_mode: HeaderMode,
+) -> Option<Result<(Option<String>, HeaderMode)>> {
+    let parsed = match value {
+        serde_json::Value::Bool(false) => (None, default_mode),
+        serde_json::Value::String(value) => {
+            (Some(value.clone()).filter(|v| !v.is_empty()), default_mode)
+        }
+        serde_json::Value::Object(header) => {
+            let mode = match header.get("mode").map(|v| v.as_str()) {
+                None => default_mode,
+                Some(Some(mode)) => match HeaderMode::parse(mode) {
+                    Ok(mode) => mode,
+                    Err(err) => return Some(Err(err)),
+                },
+                Some(None) => return None,
+            };
+            let value = header.get("value")?.as_str()?;
+            (Some(value.to_string()).filter(|v| !v.is_empty()), mode)
+        }
+        _ => return None,
+    };
+
+    Some(Ok(parsed))
+}
+
+/// Adds security headers (CSP, X-Frame-Options, etc.) to the upstream responses
+pub struct SecurityHeaders {
+    settings: SettingsCache<SecurityHeadersSettings>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for SecurityHeaders {
+    // Nothing to do before the upstream request
+    async fn request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        let Some(plugin) = ctx.route_container.plugins.get("security_headers") else {
+            return Ok(());
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let settings = self
+            .settings
+            .get_or_try_build(
+                ctx.route_container.id,
+                config,
+                SecurityHeadersSettings::from_config,
+            )
+            .inspect_err(|err| {
+                tracing::error!("invalid security_headers plugin configuration: {err}");
+            })?;
+
+        settings.apply(upstream_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<SecurityHeadersSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        SecurityHeadersSettings::from_config(&config)
+    }
+
+    fn upstream_response(headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(name.to_string(), *value).unwrap();
+        }
+        resp
+    }
+
+    fn values(resp: &ResponseHeader, name: &str) -> Vec<String> {
+        resp.headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_headers() {
+        let mut resp = upstream_response(&[("x-frame-options", "DENY")]);
+        settings(&[]).unwrap().apply(&mut resp).unwrap();
+
+        assert_eq!(values(&resp, "x-content-type-options"), ["nosniff"]);
+        assert_eq!(values(&resp, "x-frame-options"), ["SAMEORIGIN"]);
+        assert_eq!(
+            values(&resp, "referrer-policy"),
+            ["strict-origin-when-cross-origin"]
+        );
+        assert!(resp.headers.get("content-security-policy").is_none());
+    }
+
+    #[test]
+    fn test_header_modes() {
+        let settings = settings(&[
+            ("mode", json!("default")),
+            (
+                "content_security_policy",
+                json!({ "value": "frame-ancestors 'none'", "mode": "append" }),
+            ),
+            ("x_frame_options", json!(false)),
+            ("referrer_policy", json!("no-referrer")),
+            (
+                "headers",
+                json!({ "cross-origin-opener-policy": { "value": "same-origin", "mode": "override" } }),
+            ),
+        ])
+        .unwrap();
+
+        let mut resp = upstream_response(&[
+            ("content-security-policy", "default-src 'self'"),
+            ("x-frame-options", "DENY"),
+            ("referrer-policy", "origin"),
+            ("cross-origin-opener-policy", "unsafe-none"),
+        ]);
+        settings.apply(&mut resp).unwrap();
+
+        assert_eq!(
+            values(&resp, "content-security-policy"),
+            ["default-src 'self'", "frame-ancestors 'none'"]
+        );
+        assert_eq!(values(&resp, "x-frame-options"), ["DENY"]);
+        assert_eq!(values(&resp, "referrer-policy"), ["origin"]);
+        assert_eq!(values(&resp, "x-content-type-options"), ["nosniff"]);
+        assert_eq!(values(&resp, "cross-origin-opener-policy"), ["same-origin"]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(settings(&[("mode", json!("replace"))]).is_err());
+        assert!(settings(&[("x_frame_options", json!(1))]).is_err());
+        assert!(settings(&[("referrer_policy", json!({ "mode": "append" }))]).is_err());
+        assert!(settings(&[(
+            "headers",
+            json!({ "x-custom": { "value": "a", "mode": "x" } })
+        )])
+        .is_err());
+        assert!(settings(&[("headers", json!({ "invalid header": "a" }))]).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+
+/// Incremented when the routes change, the caches then drop the settings they hold
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Drops the settings of every cache on their next use, the settings of the routes that
+/// were removed or changed are not kept forever
+pub(super) fn clear_all() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Keeps the settings built from a plugin configuration (e.g. compiled regexes), so they
+/// are only built once for each version of a route (by the id of its container)
+pub(super) struct SettingsCache<T> {
+    settings: papaya::HashMap<u64, Arc<T>>,
+    generation: AtomicU64,
+}
+
+impl<T> Default for SettingsCache<T> {
+    fn default() -> Self {
+        Self {
+            settings: papaya::HashMap::new(),
+            generation: AtomicU64::new(GENERATION.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<T> SettingsCache<T> {
+    /// Returns the cached settings of the route, building them from `config` if needed.
+    /// Errors are not cached.
+    pub fn get_or_try_build(
+        &self,
+        route_id: u64,
+        config: &HashMap<Cow<'static, str>, serde_json::Value>,
+        build: impl FnOnce(&HashMap<Cow<'static, str>, serde_json::Value>) -> Result<T>,
+    ) -> Result<Arc<T>> {
+        let settings = self.settings.pin();
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if self.generation.swap(generation, Ordering::Relaxed) != generation {
+            settings.clear();
+        }
+
+        if let Some(cached) = settings.get(&route_id) {
+            return Ok(cached.clone());
+        }
+
+        let built = Arc::new(build(config)?);
+        settings.insert(route_id, built.clone());
+        Ok(built)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_by_route() {
+        let cache = SettingsCache::<String>::default();
+        let config = HashMap::from([(Cow::Borrowed("name"), serde_json::json!("a"))]);
+        let build =
+            |config: &HashMap<Cow<'static, str>, serde_json::Value>| Ok(config["name"].to_string());
+
+        let first = cache.get_or_try_build(1, &config, build).unwrap();
+        // Built once for the route, the configuration isn't read again
+        let other = HashMap::from([(Cow::Borrowed("name"), serde_json::json!("b"))]);
+        assert!(Arc::ptr_eq(
+            &first,
+            &cache.get_or_try_build(1, &other, build).unwrap()
+        ));
+        // Another version of the route
+        assert_eq!(*cache.get_or_try_build(2, &other, build).unwrap(), "\"b\"");
+
+        // Errors are not cached
+        assert!(cache
+            .get_or_try_build(3, &config, |_| Err(anyhow::anyhow!("invalid")))
+            .is_err());
+        assert_eq!(*cache.get_or_try_build(3, &config, build).unwrap(), "\"a\"");
+
+        clear_all();
+        let rebuilt = cache.get_or_try_build(1, &config, build).unwrap();
+        assert!(!Arc::ptr_eq(&first, &rebuilt));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{borrow::Cow, collections::HashMap, fmt::Write, time::SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use http::{StatusCode, Uri};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Default query parameter holding the signature
+const DEFAULT_SIGNATURE_PARAM: &str = "signature";
+
+/// Default query parameter holding the expiration timestamp
+const DEFAULT_EXPIRES_PARAM: &str = "expires";
+
+/// Why a signed URL was rejected (also the `reason` label of the metric)
+#[derive(Debug, PartialEq)]
+enum Rejection {
+    Missing,
+    Expired,
+    Invalid,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::Expired => "expired",
+            Rejection::Invalid => "invalid",
+        }
+    }
+}
+
+/// Per-route settings of the signed URL plugin
+struct SignedUrlSettings {
+    secret: Vec<u8>,
+    digest: MessageDigest,
+    signature_param: String,
+    expires_param: String,
+    /// Signature parameters are removed before the request is cached or proxied
+    strip_params: bool,
+}
+
+impl SignedUrlSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let secret = config
+            .get("secret")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Missing or invalid secret"))?;
+
+        let digest = match config.get("algorithm").map(|v| v.as_str()) {
+            None | Some(Some("sha256")) => MessageDigest::sha256(),
+            Some(Some("sha1")) => MessageDigest::sha1(),
+            Some(Some("sha512")) => MessageDigest::sha512(),
+            Some(_) => bail!("Missing or invalid algorithm (sha1, sha256 or sha512)"),
+        };
+
+        let param = |key: &str, default: &str| -> Result<String> {
+            match config.get(key) {
+                Some(value) => value
+                    .as_str()
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .ok_or_else(|| anyhow!("Missing or invalid {key}")),
+                None => Ok(default.to_string()),
+            }
+        };
+
+        Ok(Self {
+            secret: secret.as_bytes().to_vec(),
+            digest,
+            signature_param: param("signature_param", DEFAULT_SIGNATURE_PARAM)?,
+            expires_param: param("expires_param", DEFAULT_EXPIRES_PARAM)?,
+            strip_params: config
+                .get("strip_params")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(true),
+        })
+    }
+
+    /// Hex-encoded HMAC of the URL
+    fn sign(&self, message: &str) -> Result<String> {
+        let key = PKey::hmac(&self.secret)?;
+        let mut signer = Signer::new(self.digest, &key)?;
+        signer.update(message.as_bytes())?;
+
+        Ok(signer
+            .sign_to_vec()?
+            .iter()
+            .fold(String::new(), |mut hex, b| {
+                let _ = write!(hex, "{b:02x}");
+                hex
+            }))
+    }
+
+    /// Checks the signature and expiration of the URL, returning the URI without the
+    /// signature parameters if they have to be stripped
+    fn verify(&self, uri: &Uri, now: u64) -> Result<Option<Uri>, Rejection> {
+        let query = uri.query().unwrap_or_default();
+        let param = |pair: &str, name: &str| {
+            pair.split_once('=')
+                .filter(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+
+        let mut signature = None;
+        let mut expires = None;
+        // The signature covers everything but itself, as sent by the client
+        let mut signed_pairs = vec![];
+        let mut remaining_pairs = vec![];
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            if let Some(value) = param(pair, &self.signature_param) {
+                signature = Some(value);
+                continue;
+            }
+
+            signed_pairs.push(pair);
+            match param(pair, &self.expires_param) {
+                Some(value) => expires = Some(value),
+                None => remaining_pairs.push(pair),
+            }
+        }
+
+        let (Some(signature), Some(expires)) = (signature, expires) else {
+            return Err(Rejection::Missing);
+        };
+
+        let message = format!("{}?{}", uri.path(), signed_pairs.join("&"));
+        let expected = self.sign(&message).map_err(|_| Rejection::Invalid)?;
+        let signature = signature.to_ascii_lowercase();
+
+        let valid = signature.len() == expected.len()
+            && memcmp::eq(signature.as_bytes(), expected.as_bytes());
+        if !valid {
+            return Err(Rejection::Invalid);
+        }
+
+        // Only checked once the signature is valid, so it can be trusted
+        let expires = expires.parse::<u64>().map_err(|_| Rejection::Invalid)?;
+        if now > expires {
+            return Err(Rejection::Expired);
+        }
+
+        if !self.strip_params {
+            return Ok(None);
+        }
+
+        let path_and_query = if remaining_pairs.is_empty() {
+            uri.path().to_string()
+        } else {
+            format!("{}?{}", uri.path(), remaining_pairs.join("&"))
+        };
+
+        Ok(path_and_query.parse().ok())
+    }
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Only serves requests with a valid, unexpired HMAC signature in their query string
+/// (e.g. download links issued by an application)
+pub struct SignedUrl {
+    settings: SettingsCache<SignedUrlSettings>,
+}
+
+impl SignedUrl {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for SignedUrl {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            SignedUrlSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        match settings.verify(&session.req_header().uri, current_timestamp()) {
+            Ok(stripped) => {
+                // Done before the cache lookup, so every signed link of a resource shares
+                // the same cache entry
+                if let Some(uri) = stripped {
+                    session.req_header_mut().set_uri(uri);
+                }
+                Ok(false)
+            }
+            Err(rejection) => {
+                metrics::SIGNED_URL_REJECTIONS
+                    .with_label_values(&[ctx.host.as_str(), rejection.as_str()])
+                    .inc();
+                respond_with_status(session, StatusCode::FORBIDDEN).await
+            }
+        }
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> SignedUrlSettings {
+        let mut config = HashMap::from([(Cow::Borrowed("secret"), json!("s3cr3t"))]);
+        for (key, value) in extra {
+            config.insert(Cow::Borrowed(*key), value.clone());
+        }
+        SignedUrlSettings::from_config(&config).unwrap()
+    }
+
+    fn signed_uri(settings: &SignedUrlSettings, path_and_query: &str) -> Uri {
+        let signature = settings.sign(path_and_query).unwrap();
+        format!("{path_and_query}&signature={signature}")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        // echo -n "/file.zip?expires=100" | openssl dgst -sha256 -hmac s3cr3t
+        assert_eq!(
+            settings(&[]).sign("/file.zip?expires=100").unwrap(),
+            "ff80f27dbbe1601c6928cfedf4c9b5e259979cf3caa6ccb0610937045c9de065"
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let settings = settings(&[]);
+        let uri = signed_uri(&settings, "/files/report.pdf?user=42&expires=100");
+
+        let stripped = settings.verify(&uri, 50).unwrap().unwrap();
+        assert_eq!(stripped, "/files/report.pdf?user=42");
+        assert_eq!(settings.verify(&uri, 101), Err(Rejection::Expired));
+
+        let tampered = uri.to_string().replace("user=42", "user=43");
+        assert_eq!(
+            settings.verify(&tampered.parse().unwrap(), 50),
+            Err(Rejection::Invalid)
+        );
+
+        let tampered = uri.to_string().replace("report", "secret");
+        assert_eq!(
+            settings.verify(&tampered.parse().unwrap(), 50),
+            Err(Rejection::Invalid)
+        );
+
+        assert_eq!(
+            settings.verify(&"/files/report.pdf?expires=100".parse().unwrap(), 50),
+            Err(Rejection::Missing)
+        );
+    }
+
+    #[test]
+    fn test_custom_params() {
+        let settings = settings(&[
+            ("signature_param", json!("sig")),
+            ("expires_param", json!("exp")),
+            ("algorithm", json!("sha1")),
+            ("strip_params", json!(false)),
+        ]);
+
+        let signature = settings.sign("/a.png?exp=100").unwrap();
+        assert_eq!(signature.len(), 40);
+
+        let uri = format!("/a.png?sig={}&exp=100", signature.to_ascii_uppercase());
+        assert_eq!(settings.verify(&uri.parse().unwrap(), 0), Ok(None));
+
+        let config = HashMap::from([
+            (Cow::Borrowed("secret"), json!("s3cr3t")),
+            (Cow::Borrowed("algorithm"), json!("md5")),
+        ]);
+        assert!(SignedUrlSettings::from_config(&config).is_err());
+        assert!(SignedUrlSettings::from_config(&HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::StatusCode;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{
+    config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext,
+    proxy_server::tls_fingerprint::TlsFingerprint, stores,
+};
+
+use super::{respond_with_status, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+/// Headers used to forward the fingerprints to the upstream
+const JA3_HEADER: &str = "x-ja3-fingerprint";
+const JA4_HEADER: &str = "x-ja4-fingerprint";
+
+/// Per-route settings of the TLS fingerprint plugin
+#[derive(Debug)]
+struct TlsFingerprintSettings {
+    /// Lowercase JA3 or JA4 fingerprints (only these are accepted if not empty)
+    allow: Vec<String>,
+    /// Lowercase JA3 or JA4 fingerprints that are rejected
+    deny: Vec<String>,
+    forward_headers: bool,
+}
+
+impl TlsFingerprintSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let list = |key: &str| -> Result<Vec<String>> {
+            let Some(value) = config.get(key) else {
+                return Ok(vec![]);
+            };
+
+            value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map(str::to_ascii_lowercase))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| anyhow!("Missing or invalid {key}"))
+        };
+
+        Ok(Self {
+            allow: list("allow")?,
+            deny: list("deny")?,
+            forward_headers: config
+                .get("forward_headers")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+
+    fn is_allowed(&self, fingerprint: &TlsFingerprint) -> bool {
+        let matches = |list: &[String]| {
+            list.iter()
+                .any(|value| value == &fingerprint.ja3 || value == &fingerprint.ja4)
+        };
+
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
+
+/// Rejects TLS clients by their JA3/JA4 fingerprints (e.g. known automation tools),
+/// and forwards the fingerprints to the upstream
+pub struct TlsFingerprintFilter {
+    settings: SettingsCache<TlsFingerprintSettings>,
+}
+
+impl TlsFingerprintFilter {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+
+    /// Checks the fingerprint against the route of the server name, during the handshake.
+    /// Unlike the request filter, this covers HTTP/2 connections as well.
+    pub fn is_handshake_allowed(&self, server_name: &str, fingerprint: &TlsFingerprint) -> bool {
+        let Some(route_container) = stores::get_route_by_key(server_name) else {
+            return true;
+        };
+        let Some(plugin) = route_container.plugins.get("tls_fingerprint") else {
+            return true;
+        };
+
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        // Misconfigured routes are rejected by the request filter
+        let Ok(settings) = self.settings.get_or_try_build(
+            route_container.id,
+            config,
+            TlsFingerprintSettings::from_config,
+        ) else {
+            return true;
+        };
+
+        let allowed = settings.is_allowed(fingerprint);
+        if !allowed {
+            metrics::TLS_FINGERPRINT_REJECTIONS
+                .with_label_values(&[server_name, "handshake"])
+                .inc();
+        }
+
+        allowed
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for TlsFingerprintFilter {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            TlsFingerprintSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        // The fingerprints are only known for HTTP/1.1 requests (see `get_fingerprint`),
+        // HTTP/2 connections are checked during the handshake
+        let (Some(ja3), Some(ja4)) = (ctx.extensions.get("tls_ja3"), ctx.extensions.get("tls_ja4"))
+        else {
+            return Ok(false);
+        };
+
+        let fingerprint = TlsFingerprint {
+            ja3: ja3.clone(),
+            ja4: ja4.clone(),
+        };
+
+        // Requests can be sent to another route than the one of the server name
+        if !settings.is_allowed(&fingerprint) {
+            metrics::TLS_FINGERPRINT_REJECTIONS
+                .with_label_values(&[ctx.host.as_str(), "request"])
+                .inc();
+            return respond_with_status(session, StatusCode::FORBIDDEN).await;
+        }
+
+        if settings.forward_headers {
+            ctx.extensions
+                .insert(Cow::Borrowed("tls_fingerprint_forward"), String::new());
+        }
+
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut RouterContext,
+    ) -> Result<()> {
+        // Never trust the fingerprints sent by the client
+        upstream_request.remove_header(JA3_HEADER);
+        upstream_request.remove_header(JA4_HEADER);
+
+        if ctx.extensions.contains_key("tls_fingerprint_forward") {
+            for (header, key) in [(JA3_HEADER, "tls_ja3"), (JA4_HEADER, "tls_ja4")] {
+                if let Some(value) = ctx.extensions.get(key) {
+                    upstream_request.insert_header(header, value.as_str())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let fingerprint = TlsFingerprint {
+            ja3: "e7d705a3286e19ea42f587b344ee6865".to_string(),
+            ja4: "t13d1516h2_8daaf6152771_e5627efa2ab1".to_string(),
+        };
+
+        let settings = |config: serde_json::Value| {
+            let config = config
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (Cow::Owned(k.clone()), v.clone()))
+                .collect();
+            TlsFingerprintSettings::from_config(&config).unwrap()
+        };
+
+        assert!(settings(json!({})).is_allowed(&fingerprint));
+        assert!(
+            !settings(json!({ "deny": ["E7D705A3286E19EA42F587B344EE6865"] }))
+                .is_allowed(&fingerprint)
+        );
+        assert!(
+            settings(json!({ "deny": ["t13d1516h2_000000000000_000000000000"] }))
+                .is_allowed(&fingerprint)
+        );
+        assert!(
+            settings(json!({ "allow": ["t13d1516h2_8daaf6152771_e5627efa2ab1"] }))
+                .is_allowed(&fingerprint)
+        );
+        assert!(
+            !settings(json!({ "allow": ["t13d1516h2_000000000000_000000000000"] }))
+                .is_allowed(&fingerprint)
+        );
+        assert!(!settings(json!({
+            "allow": ["t13d1516h2_8daaf6152771_e5627efa2ab1"],
+            "deny": ["e7d705a3286e19ea42f587b344ee6865"]
+        }))
+        .is_allowed(&fingerprint));
+
+        let config = HashMap::from([(Cow::Borrowed("deny"), json!("abc"))]);
+        assert!(TlsFingerprintSettings::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, metrics, proxy_server::https_proxy::RouterContext};
+
+use super::{respond_with_status, route_settings, settings_cache::SettingsCache, MiddlewarePlugin};
+
+mod rules;
+
+pub(crate) use rules::url_decode;
+
+use rules::{Inspection, PendingRule, Rule, RuleSet};
+
+/// Default maximum number of body bytes inspected
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Per-route settings of the WAF plugin
+#[derive(Debug)]
+struct WafSettings {
+    rules: RuleSet,
+    /// Matches are only logged, requests are never blocked
+    detection_only: bool,
+    /// Score from which a request is blocked
+    threshold: u64,
+    max_body_size: usize,
+}
+
+impl WafSettings {
+    fn from_config(config: &HashMap<Cow<'static, str>, serde_json::Value>) -> Result<Self> {
+        let detection_only = match config.get("mode").map(|v| v.as_str()) {
+            None | Some(Some("block")) => false,
+            Some(Some("detect")) => true,
+            _ => return Err(anyhow!("Missing or invalid mode")),
+        };
+
+        let custom_rules = match config.get("rules") {
+            Some(rules) => rules
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid rules"))?
+                .iter()
+                .map(Rule::from_config)
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        let disabled_rules = match config.get("disabled_rules") {
+            Some(ids) => ids
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing or invalid disabled_rules"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(ToString::to_string)
+                        .ok_or_else(|| anyhow!("Missing or invalid disabled_rules"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        let use_default_rules = config
+            .get("default_rules")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(true);
+
+        Ok(Self {
+            rules: RuleSet::new(use_default_rules, custom_rules, &disabled_rules),
+            detection_only,
+            threshold: config
+                .get("threshold")
+                .and_then(serde_json::Value::as_u64)
+                .filter(|v| *v > 0)
+                .unwrap_or(rules::DEFAULT_SCORE),
+            max_body_size: usize::try_from(
+                config
+                    .get("max_body_size")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            )?,
+        })
+    }
+
+    /// Logs the matched rules and returns `true` if the request has to be blocked
+    fn conclude(&self, host: &str, request: &str, inspection: &Inspection) -> bool {
+        if inspection.matched.is_empty() {
+            return false;
+        }
+
+        let blocked = !self.detection_only && inspection.score >= self.threshold;
+
+        tracing::warn!(
+            host,
+            request,
+            rules = inspection.matched.join(","),
+            score = inspection.score,
+            blocked,
+            "request matched WAF rules"
+        );
+
+        metrics::WAF_REQUESTS
+            .with_label_values(&[host, if blocked { "blocked" } else { "detected" }])
+            .inc();
+
+        blocked
+    }
+}
+
+/// Inspects the request body for the rules that couldn't be decided with the headers
+pub struct WafInspector {
+    settings: Arc<WafSettings>,
+    request: String,
+    inspection: Inspection,
+    pending: Vec<PendingRule>,
+    /// Forms are percent-decoded before being matched
+    is_form: bool,
+    body: Vec<u8>,
+    done: bool,
+}
+
+impl WafInspector {
+    /// Buffers the body until it's complete (or the inspection limit is reached),
+    /// returning `true` if the request has to be blocked
+    pub fn inspect_body(&mut self, data: &[u8], end: bool, host: &str) -> bool {
+        if self.done {
+            return false;
+        }
+
+        let remaining = self.settings.max_body_size - self.body.len();
+        self.body
+            .extend_from_slice(&data[..data.len().min(remaining)]);
+
+        if !end && self.body.len() < self.settings.max_body_size {
+            return false;
+        }
+
+        self.done = true;
+
+        let body = String::from_utf8_lossy(&self.body);
+        let body = if self.is_form {
+            rules::url_decode(&body, true).into_owned()
+        } else {
+            body.into_owned()
+        };
+
+        let body_inspection = self.settings.rules.inspect_body(&self.pending, &body);
+        self.inspection.matched.extend(body_inspection.matched);
+        self.inspection.score += body_inspection.score;
+
+        self.settings
+            .conclude(host, &self.request, &self.inspection)
+    }
+}
+
+/// Requests without these headers have no body to inspect
+fn has_body(req: &RequestHeader) -> bool {
+    let content_length = req
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+    content_length.is_some_and(|length| length > 0)
+        || req.headers.contains_key(header::TRANSFER_ENCODING)
+}
+
+fn is_form(req: &RequestHeader) -> bool {
+    req.headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.to_ascii_lowercase()
+                .starts_with("application/x-www-form-urlencoded")
+        })
+}
+
+/// Blocks requests matching a set of rules (a starter ruleset covering common
+/// injection and traversal attempts, along with custom rules)
+pub struct Waf {
+    /// Rules are compiled once per plugin configuration
+    settings: SettingsCache<WafSettings>,
+}
+
+impl Waf {
+    pub fn new() -> Self {
+        Self {
+            settings: SettingsCache::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Waf {
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> Result<bool> {
+        let empty = HashMap::new();
+        let config = plugin.config.as_ref().unwrap_or(&empty);
+
+        let Some(settings) = route_settings(
+            session,
+            ctx,
+            &plugin.name,
+            &self.settings,
+            config,
+            WafSettings::from_config,
+        )
+        .await?
+        else {
+            return Ok(true);
+        };
+
+        let req = session.req_header();
+        let request = format!("{} {}", req.method, req.uri);
+        let mut inspection = settings.rules.inspect_request(req);
+        let pending = std::mem::take(&mut inspection.pending);
+
+        // The body is only inspected if some rules depend on it and
+        // the request isn't already blocked by its headers
+        let blocked_early = !settings.detection_only && inspection.score >= settings.threshold;
+        if !pending.is_empty() && !blocked_early && has_body(req) {
+            ctx.waf = Some(WafInspector {
+                is_form: is_form(req),
+                settings,
+                request,
+                inspection,
+                pending,
+                body: vec![],
+                done: false,
+            });
+            return Ok(false);
+        }
+
+        if settings.conclude(&ctx.host, &request, &inspection) {
+            return respond_with_status(session, StatusCode::FORBIDDEN).await;
+        }
+
+        Ok(false)
+    }
+
+    // Nothing to do before sending the request to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Nothing to do after upstream response
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(extra: &[(&'static str, serde_json::Value)]) -> Result<WafSettings> {
+        let config = extra
+            .iter()
+            .map(|(key, value)| (Cow::Borrowed(*key), value.clone()))
+            .collect();
+        WafSettings::from_config(&config)
+    }
+
+    fn inspector(settings: WafSettings, req: &RequestHeader) -> WafInspector {
+        let mut inspection = settings.rules.inspect_request(req);
+        WafInspector {
+            pending: std::mem::take(&mut inspection.pending),
+            settings: Arc::new(settings),
+            request: "POST /".to_string(),
+            inspection,
+            is_form: is_form(req),
+            body: vec![],
+            done: false,
+        }
+    }
+
+    fn form_request() -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/comments", None).unwrap();
+        req.insert_header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .unwrap();
+        req.insert_header(header::CONTENT_LENGTH, "100").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let defaults = settings(&[]).unwrap();
+        assert!(!defaults.detection_only);
+        assert_eq!(defaults.threshold, 5);
+        assert_eq!(defaults.max_body_size, 64 * 1024);
+        assert!(!defaults.rules.rules.is_empty());
+
+        let custom = settings(&[
+            ("mode", json!("detect")),
+            ("default_rules", json!(false)),
+            ("threshold", json!(10)),
+            (
+                "rules",
+                json!([{ "id": "admin", "match": [{ "target": "path", "pattern": "^/admin" }] }]),
+            ),
+        ])
+        .unwrap();
+        assert!(custom.detection_only);
+        assert_eq!(custom.threshold, 10);
+        assert_eq!(custom.rules.rules.len(), 1);
+
+        assert!(settings(&[("mode", json!("drop"))]).is_err());
+        assert!(settings(&[("disabled_rules", json!("sqli-comment"))]).is_err());
+        assert!(settings(&[("rules", json!([{ "id": "empty" }]))]).is_err());
+    }
+
+    #[test]
+    fn test_conclude() {
+        let settings = settings(&[("threshold", json!(8))]).unwrap();
+        let inspection = |score| Inspection {
+            matched: vec!["rule".to_string()],
+            score,
+            pending: vec![],
+        };
+
+        assert!(!settings.conclude("example.com", "GET /", &inspection(5)));
+        assert!(settings.conclude("example.com", "GET /", &inspection(8)));
+        assert!(!settings.conclude("example.com", "GET /", &Inspection::default()));
+
+        let detect = WafSettings {
+            detection_only: true,
+            ..settings
+        };
+        assert!(!detect.conclude("example.com", "GET /", &inspection(100)));
+    }
+
+    #[test]
+    fn test_inspect_body() {
+        let req = form_request();
+        assert!(has_body(&req));
+
+        let mut waf = inspector(settings(&[]).unwrap(), &req);
+        assert!(!waf.inspect_body(b"text=hello%20%3Cscr", false, "example.com"));
+        assert!(waf.inspect_body(b"ipt%3Ealert(1)", true, "example.com"));
+        assert_eq!(waf.inspection.matched, ["xss-script-tag"]);
+
+        let mut waf = inspector(settings(&[]).unwrap(), &req);
+        assert!(!waf.inspect_body(b"text=hello+world", true, "example.com"));
+
+        // Anything past the limit is not inspected
+        let mut waf = inspector(settings(&[("max_body_size", json!(8))]).unwrap(), &req);
+        assert!(!waf.inspect_body(b"text=hello <script>", false, "example.com"));
+        assert!(waf.done);
+    }
+}
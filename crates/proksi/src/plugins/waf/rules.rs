@@ -0,0 +1,506 @@
+use std::borrow::Cow;
+
+use anyhow::{anyhow, bail, Result};
+use http::{header, HeaderName};
+use pingora::http::RequestHeader;
+use regex::Regex;
+
+/// Part of the request a pattern is matched against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Method,
+    /// Percent-decoded path
+    Path,
+    /// Percent-decoded query string
+    Query,
+    /// Value of a single header
+    Header(HeaderName),
+    /// Values of all headers
+    Headers,
+    /// Request body (percent-decoded for forms)
+    Body,
+}
+
+impl Target {
+    fn parse(value: &str) -> Result<Self> {
+        let target = match value {
+            "method" => Target::Method,
+            "path" => Target::Path,
+            "query" => Target::Query,
+            "headers" => Target::Headers,
+            "body" => Target::Body,
+            _ => match value.strip_prefix("header:") {
+                Some(name) => Target::Header(HeaderName::try_from(name.trim())?),
+                None => bail!("invalid target {value}"),
+            },
+        };
+        Ok(target)
+    }
+}
+
+/// Matches when the pattern is found in any of its targets
+#[derive(Debug, Clone)]
+struct Condition {
+    targets: Vec<Target>,
+    pattern: Regex,
+}
+
+/// What happens when a rule matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleAction {
+    /// Adds the score of the rule to the request
+    Block,
+    /// Only logs the match
+    Log,
+}
+
+/// A rule matches when all of its conditions match
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub id: String,
+    conditions: Vec<Condition>,
+    pub score: u64,
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// Parses a rule from the plugin configuration, e.g.
+    /// `{ id = "no-sqlmap", match = [{ target = "header:user-agent", pattern = "sqlmap" }] }`
+    pub fn from_config(value: &serde_json::Value) -> Result<Self> {
+        let id = value
+            .get("id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing or invalid rule id"))?;
+
+        let conditions = value
+            .get("match")
+            .and_then(|v| v.as_array())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Missing or invalid match for rule {id}"))?
+            .iter()
+            .map(|condition| {
+                let targets = match condition.get("target") {
+                    Some(serde_json::Value::String(target)) => vec![Target::parse(target)?],
+                    Some(serde_json::Value::Array(targets)) if !targets.is_empty() => targets
+                        .iter()
+                        .map(|t| Target::parse(t.as_str().unwrap_or_default()))
+                        .collect::<Result<Vec<_>>>()?,
+                    _ => bail!("Missing or invalid target for rule {id}"),
+                };
+
+                let pattern = condition
+                    .get("pattern")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Missing or invalid pattern for rule {id}"))?;
+
+                Ok(Condition {
+                    targets,
+                    pattern: Regex::new(pattern)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let action = match value.get("action").and_then(|v| v.as_str()) {
+            None | Some("block") => RuleAction::Block,
+            Some("log") => RuleAction::Log,
+            Some(action) => bail!("invalid action {action} for rule {id}"),
+        };
+
+        Ok(Self {
+            id: id.to_string(),
+            conditions,
+            score: value
+                .get("score")
+                .and_then(serde_json::Value::as_u64)
+                .unwrap_or(DEFAULT_SCORE),
+            action,
+        })
+    }
+
+    fn new(id: &str, targets: &[Target], pattern: &str, score: u64) -> Self {
+        Self {
+            id: id.to_string(),
+            conditions: vec![Condition {
+                targets: targets.to_vec(),
+                pattern: Regex::new(pattern).expect("invalid default WAF rule"),
+            }],
+            score,
+            action: RuleAction::Block,
+        }
+    }
+}
+
+/// Score of a rule when not configured (which is also the default threshold,
+/// so a single match blocks the request)
+pub const DEFAULT_SCORE: u64 = 5;
+
+/// Starter ruleset, covering the most common injection and traversal attempts
+pub fn default_rules() -> Vec<Rule> {
+    use Target::{Body, Header, Path, Query};
+
+    vec![
+        Rule::new(
+            "sqli-union-select",
+            &[Query, Body],
+            r"(?i)\bunion\b(?:\s|/\*.*?\*/|\+)+(?:all\s+|distinct\s+)?select\b",
+            5,
+        ),
+        Rule::new(
+            "sqli-tautology",
+            &[Query, Body],
+            r#"(?i)['"]\s*\)?\s*(?:or|and)\s+['"]?[\w-]+['"]?\s*(?:=|<>|!=|<|>|\blike\b)"#,
+            5,
+        ),
+        Rule::new(
+            "sqli-comment",
+            &[Query],
+            r#"(?i)['"]\s*\)?\s*(?:;|--|#|/\*)"#,
+            3,
+        ),
+        Rule::new(
+            "sqli-time-based",
+            &[Query, Body],
+            r"(?i)\b(?:sleep|benchmark|pg_sleep)\s*\(\s*\d|\bwaitfor\s+delay\s+'",
+            5,
+        ),
+        Rule::new(
+            "xss-script-tag",
+            &[Path, Query, Body],
+            r"(?i)<\s*/?\s*script\b",
+            5,
+        ),
+        Rule::new(
+            "xss-event-handler",
+            &[Query, Body],
+            r"(?i)<[^>]*[\s/]on[a-z]+\s*=",
+            5,
+        ),
+        Rule::new(
+            "xss-javascript-uri",
+            &[Query, Body],
+            r"(?i)\b(?:javascript|vbscript)\s*:",
+            3,
+        ),
+        Rule::new(
+            "path-traversal",
+            &[Path, Query],
+            r"(?i)(?:^|[\\/=])\.\.(?:[\\/]|$)|%2e%2e|\.\.%2f|%252e",
+            5,
+        ),
+        Rule::new(
+            "sensitive-files",
+            &[Path, Query],
+            r"(?i)/etc/(?:passwd|shadow|hosts)\b|(?:^|/)\.(?:env|git/|htaccess|htpasswd)|(?:boot|win)\.ini|/proc/self/",
+            5,
+        ),
+        Rule::new(
+            "command-injection",
+            &[Query, Body],
+            r"(?i)(?:[;|`]|&&|\$\()\s*(?:cat|ls|id|whoami|uname|wget|curl|nc|bash|sh|python|perl)\b",
+            5,
+        ),
+        Rule::new(
+            "scanner-user-agent",
+            &[Header(header::USER_AGENT)],
+            r"(?i)\b(?:sqlmap|nikto|nmap|masscan|wpscan|acunetix|nuclei|dirbuster|gobuster)\b",
+            5,
+        ),
+    ]
+}
+
+/// Decodes `%XX` sequences, and `+` as a space for forms and query strings
+pub fn url_decode(value: &str, plus_as_space: bool) -> Cow<'_, str> {
+    let needs_decoding = value.contains('%') || (plus_as_space && value.contains('+'));
+    if !needs_decoding {
+        return Cow::Borrowed(value);
+    }
+
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push((high << 4) | low);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Rules of a route, along with the decisions that depend on them
+#[derive(Debug)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+}
+
+/// A rule whose other conditions already matched the request headers
+#[derive(Debug, PartialEq)]
+pub struct PendingRule {
+    rule: usize,
+    conditions: Vec<usize>,
+}
+
+/// Result of matching the rules against (a part of) a request
+#[derive(Debug, Default)]
+pub struct Inspection {
+    /// Ids of the rules that matched
+    pub matched: Vec<String>,
+    pub score: u64,
+    /// Rules whose conditions (by index) can only be decided with the body
+    pub pending: Vec<PendingRule>,
+}
+
+impl Inspection {
+    fn add_match(&mut self, rule: &Rule) {
+        self.matched.push(rule.id.clone());
+        if rule.action == RuleAction::Block {
+            self.score += rule.score;
+        }
+    }
+}
+
+impl RuleSet {
+    /// Combines the default rules (if enabled) with the custom ones. Custom rules
+    /// replace default rules with the same id.
+    pub fn new(use_default_rules: bool, custom: Vec<Rule>, disabled: &[String]) -> Self {
+        let mut rules = if use_default_rules {
+            default_rules()
+        } else {
+            vec![]
+        };
+
+        rules.retain(|rule| custom.iter().all(|custom| custom.id != rule.id));
+        rules.extend(custom);
+        rules.retain(|rule| !disabled.contains(&rule.id));
+
+        Self { rules }
+    }
+
+    /// Matches the rules against the request headers. Conditions on the body
+    /// that couldn't be satisfied by other targets are left pending.
+    pub fn inspect_request(&self, req: &RequestHeader) -> Inspection {
+        let path = url_decode(req.uri.path(), false);
+        let query = url_decode(req.uri.query().unwrap_or_default(), true);
+
+        let matches = |target: &Target, pattern: &Regex| match target {
+            Target::Method => pattern.is_match(req.method.as_str()),
+            Target::Path => pattern.is_match(&path),
+            Target::Query => pattern.is_match(&query),
+            Target::Header(name) => req
+                .headers
+                .get_all(name)
+                .iter()
+                .any(|v| pattern.is_match(&String::from_utf8_lossy(v.as_bytes()))),
+            Target::Headers => req
+                .headers
+                .values()
+                .any(|v| pattern.is_match(&String::from_utf8_lossy(v.as_bytes()))),
+            Target::Body => false,
+        };
+
+        let mut inspection = Inspection::default();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            let mut pending = vec![];
+
+            let matched = rule.conditions.iter().enumerate().all(|(i, condition)| {
+                if condition
+                    .targets
+                    .iter()
+                    .any(|target| matches(target, &condition.pattern))
+                {
+                    return true;
+                }
+
+                let has_body = condition.targets.contains(&Target::Body);
+                if has_body {
+                    pending.push(i);
+                }
+                has_body
+            });
+
+            if matched && !pending.is_empty() {
+                inspection.pending.push(PendingRule {
+                    rule: index,
+                    conditions: pending,
+                });
+            } else if matched {
+                inspection.add_match(rule);
+            }
+        }
+
+        inspection
+    }
+
+    /// Matches the pending rules against the request body
+    pub fn inspect_body(&self, pending: &[PendingRule], body: &str) -> Inspection {
+        let mut inspection = Inspection::default();
+
+        for pending in pending {
+            let Some(rule) = self.rules.get(pending.rule) else {
+                continue;
+            };
+
+            let matched = pending.conditions.iter().all(|i| {
+                rule.conditions
+                    .get(*i)
+                    .is_some_and(|condition| condition.pattern.is_match(body))
+            });
+
+            if matched {
+                inspection.add_match(rule);
+            }
+        }
+
+        inspection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn matched_rules(uri: &str, headers: &[(&str, &str)]) -> Vec<String> {
+        RuleSet::new(true, vec![], &[])
+            .inspect_request(&request("GET", uri, headers))
+            .matched
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("/a%20b+c", false), "/a b+c");
+        assert_eq!(url_decode("q=a%20b+c", true), "q=a b c");
+        assert_eq!(url_decode("100%", true), "100%");
+        assert_eq!(url_decode("%zz%2", false), "%zz%2");
+    }
+
+    #[test]
+    fn test_default_rules() {
+        assert_eq!(
+            matched_rules("/items?id=1%20UNION%20ALL%20SELECT%20password", &[]),
+            ["sqli-union-select"]
+        );
+        assert_eq!(
+            matched_rules("/login?user=admin'+or+'1'='1", &[]),
+            ["sqli-tautology"]
+        );
+        assert_eq!(
+            matched_rules("/search?q=%3Cscript%3Ealert(1)%3C/script%3E", &[]),
+            ["xss-script-tag"]
+        );
+        assert_eq!(
+            matched_rules("/files?name=../../etc/passwd", &[]),
+            ["path-traversal", "sensitive-files"]
+        );
+        assert_eq!(
+            matched_rules("/static/%2e%2e/.env", &[]),
+            ["path-traversal", "sensitive-files"]
+        );
+        assert_eq!(
+            matched_rules("/ping?host=1.1.1.1;cat+/tmp/x", &[]),
+            ["command-injection"]
+        );
+        assert_eq!(
+            matched_rules("/", &[("user-agent", "sqlmap/1.7")]),
+            ["scanner-user-agent"]
+        );
+
+        // Regular requests
+        assert!(matched_rules("/items?page=2&id=3&sort=name", &[]).is_empty());
+        assert!(matched_rules("/search?q=rock+and+roll", &[]).is_empty());
+        assert!(matched_rules("/blog/o'reilly-books?ref=home", &[]).is_empty());
+        assert!(matched_rules("/", &[("user-agent", "Mozilla/5.0")]).is_empty());
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let rule = Rule::from_config(&json!({
+            "id": "sqli-comment",
+            "match": [
+                { "target": "method", "pattern": "^POST$" },
+                { "target": ["header:x-debug", "body"], "pattern": "(?i)enabled" }
+            ],
+            "score": 2,
+            "action": "log"
+        }))
+        .unwrap();
+        assert_eq!(rule.score, 2);
+        assert_eq!(rule.action, RuleAction::Log);
+
+        let rules = RuleSet::new(true, vec![rule], &["xss-script-tag".to_string()]);
+        assert_eq!(
+            rules
+                .rules
+                .iter()
+                .filter(|r| r.id == "sqli-comment")
+                .count(),
+            1
+        );
+        assert!(rules.rules.iter().all(|r| r.id != "xss-script-tag"));
+
+        // Matched by the header, no score as it's only logged
+        let inspection = rules.inspect_request(&request("POST", "/", &[("x-debug", "Enabled")]));
+        assert_eq!(inspection.matched, ["sqli-comment"]);
+        assert_eq!(inspection.score, 0);
+
+        // Only decided once the body is read
+        let inspection = rules.inspect_request(&request("POST", "/", &[]));
+        assert!(inspection.matched.is_empty());
+        let is_pending = |inspection: &Inspection| {
+            inspection
+                .pending
+                .iter()
+                .any(|pending| rules.rules[pending.rule].id == "sqli-comment")
+        };
+        assert!(is_pending(&inspection));
+        assert_eq!(
+            rules
+                .inspect_body(&inspection.pending, "debug=enabled")
+                .matched,
+            ["sqli-comment"]
+        );
+        assert!(rules
+            .inspect_body(&inspection.pending, "debug=no")
+            .matched
+            .is_empty());
+
+        // Other conditions didn't match
+        let inspection = rules.inspect_request(&request("GET", "/", &[]));
+        assert!(!is_pending(&inspection));
+
+        assert!(Rule::from_config(&json!({ "id": "no-match" })).is_err());
+        assert!(Rule::from_config(&json!({
+            "id": "bad-target",
+            "match": [{ "target": "cookies", "pattern": "x" }]
+        }))
+        .is_err());
+        assert!(Rule::from_config(&json!({
+            "id": "bad-pattern",
+            "match": [{ "target": "path", "pattern": "(" }]
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use http::{Response, StatusCode};
+use pingora::{
+    apps::http_app::{HttpServer, ServeHttp},
+    protocols::http::ServerSession,
+    services::listening::Service,
+};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+
+use crate::{
+    admin::json_response,
+    config::Config,
+    services::discovery,
+    stores::{self, health},
+};
+
+/// Serves `/healthz` (the process is alive) and `/readyz` (it can serve traffic)
+pub struct ProbesApp {
+    /// Addresses of the HTTP and HTTPS listeners, they must accept connections to be ready
+    listeners: Vec<String>,
+    min_healthy_upstreams: usize,
+}
+
+impl ProbesApp {
+    pub fn new(config: &Config) -> Self {
+        let listeners = [&config.server.https_address, &config.server.http_address]
+            .into_iter()
+            .flatten()
+            .map(ToString::to_string)
+            .collect();
+
+        Self {
+            listeners,
+            min_healthy_upstreams: config.server.probes.min_healthy_upstreams,
+        }
+    }
+
+    async fn readiness(&self) -> (bool, Value) {
+        let routes_loaded = discovery::routes_loaded();
+
+        let mut listeners = serde_json::Map::new();
+        for address in &self.listeners {
+            listeners.insert(address.clone(), json!(is_listening(address).await));
+        }
+        let listening = listeners.values().all(|bound| bound == true);
+
+        let unhealthy_routes = unhealthy_routes(self.min_healthy_upstreams);
+        let ready = routes_loaded && listening && unhealthy_routes.is_empty();
+
+        let body = json!({
+            "ready": ready,
+            "routes_loaded": routes_loaded,
+            "listeners": listeners,
+            "unhealthy_routes": unhealthy_routes,
+        });
+        (ready, body)
+    }
+}
+
+/// Connects to the listener, which is reachable on the loopback when bound to all interfaces
+pub async fn is_listening(address: &str) -> bool {
+    let address = match address.parse::<SocketAddr>() {
+        Ok(mut addr) if addr.ip().is_unspecified() => {
+            if addr.is_ipv4() {
+                addr.set_ip(Ipv4Addr::LOCALHOST.into());
+            } else {
+                addr.set_ip(Ipv6Addr::LOCALHOST.into());
+            }
+            addr.to_string()
+        }
+        _ => address.to_string(),
+    };
+
+    let connect = TcpStream::connect(address);
+    matches!(
+        tokio::time::timeout(Duration::from_secs(1), connect).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Routes with less healthy upstreams than required, sorted by host
+fn unhealthy_routes(min_healthy_upstreams: usize) -> Vec<String> {
+    if min_healthy_upstreams == 0 {
+        return vec![];
+    }
+
+    let routes = stores::get_routes();
+    let mut hosts = routes
+        .iter()
+        .filter(|(host, route)| {
+            let backends = route.load_balancer.backends();
+            let healthy = backends
+                .get_backend()
+                .iter()
+                .filter(|backend| {
+                    health::is_healthy(host, &backend.addr.to_string())
+                        .unwrap_or_else(|| backends.ready(backend))
+                })
+                .count();
+            healthy < min_healthy_upstreams
+        })
+        .map(|(host, _)| host.clone())
+        .collect::<Vec<_>>();
+
+    hosts.sort();
+    hosts
+}
+
+#[async_trait]
+impl ServeHttp for ProbesApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        match session.req_header().uri.path() {
+            "/healthz" => json_response(StatusCode::OK, &json!({ "status": "ok" })),
+            "/readyz" => {
+                let (ready, body) = self.readiness().await;
+                let status = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                json_response(status, &body)
+            }
+            _ => json_response(StatusCode::NOT_FOUND, &json!({ "error": "not found" })),
+        }
+    }
+}
+
+/// Creates the service exposing the probes, when an address is configured
+pub fn probes_service(config: &Config) -> Option<Service<HttpServer<ProbesApp>>> {
+    let address = config.server.probes.address.as_deref()?;
+
+    let mut service = Service::new(
+        "probes".to_string(),
+        HttpServer::new_app(ProbesApp::new(config)),
+    );
+    service.add_tcp(address);
+    Some(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(is_listening(&format!("0.0.0.0:{port}")).await);
+
+        let mut config = Config::default();
+        config.server.https_address = Some(Cow::Owned(format!("127.0.0.1:{port}")));
+        config.server.http_address = None;
+        let probes = ProbesApp::new(&config);
+
+        // The routes of the configuration are not loaded without the discovery service
+        let (ready, body) = probes.readiness().await;
+        assert!(!ready);
+        assert_eq!(body["routes_loaded"], false);
+        assert_eq!(body["listeners"][format!("127.0.0.1:{port}")], true);
+
+        drop(listener);
+        assert!(!is_listening(&format!("127.0.0.1:{port}")).await);
+    }
+}
@@ -0,0 +1,452 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap};
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::bytes::Regex;
+use serde_json::Value;
+
+use super::capture::REDACTED;
+use crate::config::RouteBodyLogging;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024;
+
+/// A step of the path of a JSON field (`$.cards[*].number`)
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// `.name` or `['name']`
+    Key(String),
+    /// `[0]`
+    Index(usize),
+    /// `.*` or `[*]`, every field or element
+    Any,
+    /// `..name`, the fields with this name at any depth
+    Descendant(String),
+}
+
+/// Parses the path of a JSON field, from the root (`$`)
+fn parse_path(path: &str) -> anyhow::Result<Vec<Segment>> {
+    let invalid = || anyhow!("{path} is not a JSON path (e.g. $.user.password)");
+    let name_end = |rest: &str| rest.find(['.', '[']).unwrap_or(rest.len());
+
+    let mut rest = path.trim().strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            let (name, after) = after.split_at(name_end(after));
+            if name.is_empty() || name == "*" {
+                return Err(invalid());
+            }
+            segments.push(Segment::Descendant(name.to_string()));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('.') {
+            let (name, after) = after.split_at(name_end(after));
+            segments.push(match name {
+                "" => return Err(invalid()),
+                "*" => Segment::Any,
+                name => Segment::Key(name.to_string()),
+            });
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after.split_once(']').ok_or_else(invalid)?;
+            let quoted = index
+                .strip_prefix('\'')
+                .and_then(|index| index.strip_suffix('\''));
+            segments.push(match (index, quoted) {
+                (_, Some(name)) => Segment::Key(name.to_string()),
+                ("*", None) => Segment::Any,
+                (index, None) => Segment::Index(index.parse().map_err(|_| invalid())?),
+            });
+            rest = after;
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(invalid());
+    }
+    Ok(segments)
+}
+
+/// Replaces the fields of the value at the path by `<redacted>`
+fn redact_json(value: &mut Value, path: &[Segment]) {
+    let Some((segment, rest)) = path.split_first() else {
+        *value = Value::from(REDACTED);
+        return;
+    };
+
+    match segment {
+        Segment::Key(name) => {
+            if let Some(field) = value.get_mut(name.as_str()) {
+                redact_json(field, rest);
+            }
+        }
+        Segment::Index(index) => {
+            if let Some(element) = value.get_mut(*index) {
+                redact_json(element, rest);
+            }
+        }
+        Segment::Any => match value {
+            Value::Object(fields) => fields.values_mut().for_each(|v| redact_json(v, rest)),
+            Value::Array(elements) => elements.iter_mut().for_each(|v| redact_json(v, rest)),
+            _ => {}
+        },
+        Segment::Descendant(name) => {
+            if let Some(field) = value.get_mut(name.as_str()) {
+                redact_json(field, rest);
+            }
+            match value {
+                Value::Object(fields) => fields.values_mut().for_each(|v| redact_json(v, path)),
+                Value::Array(elements) => elements.iter_mut().for_each(|v| redact_json(v, path)),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| {
+            let mime = mime.trim().to_ascii_lowercase();
+            mime == "application/json" || mime.ends_with("+json")
+        })
+}
+
+fn encoding(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty() && value != "identity")
+}
+
+/// The body logging of a route, writing the bodies of `percentage`% of its requests to the
+/// logs once redacted
+pub struct BodyLogging {
+    percentage: u32,
+    max_body_size: usize,
+    request: bool,
+    response: bool,
+    redact_body: Vec<Regex>,
+    redact_json: Vec<Vec<Segment>>,
+}
+
+/// The beginning of a body, as received
+struct Body {
+    data: BytesMut,
+    size: usize,
+    truncated: bool,
+    json: bool,
+    /// Encoded bodies (e.g. `gzip`) are not kept, they can't be redacted
+    encoding: Option<String>,
+}
+
+impl Body {
+    fn new(headers: &HeaderMap, decoded: bool) -> Self {
+        Self {
+            data: BytesMut::new(),
+            size: 0,
+            truncated: false,
+            json: is_json(headers),
+            encoding: encoding(headers).filter(|_| !decoded),
+        }
+    }
+
+    fn push(&mut self, data: Option<&Bytes>, max_body_size: usize) {
+        let data = data.map_or(&[][..], |d| d.as_ref());
+        self.size += data.len();
+        if self.encoding.is_some() {
+            return;
+        }
+
+        let left = max_body_size.saturating_sub(self.data.len());
+        if data.len() > left {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&data[..data.len().min(left)]);
+    }
+}
+
+/// The bodies of a logged request, written once its response was sent
+pub struct BodyLog {
+    logging: Arc<BodyLogging>,
+    host: String,
+    method: String,
+    path: String,
+    request: Option<Body>,
+    response: Option<Body>,
+}
+
+impl BodyLogging {
+    pub fn from_config(config: &RouteBodyLogging) -> anyhow::Result<Self> {
+        if config.percentage > 100 {
+            return Err(anyhow!("percentage must be from 0 to 100"));
+        }
+
+        let redact_body = config
+            .redact_body
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| anyhow!("redact_body: {err}"))?;
+        let redact_json = config
+            .redact_json
+            .iter()
+            .map(|path| parse_path(path))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|err| anyhow!("redact_json: {err}"))?;
+
+        Ok(Self {
+            percentage: u32::from(config.percentage),
+            max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            request: config.request,
+            response: config.response,
+            redact_body,
+            redact_json,
+        })
+    }
+
+    /// The log of the request, for the sampled ones. `decoded` tells whether the body is
+    /// decompressed before it reaches the filters.
+    pub fn start(
+        self: &Arc<Self>,
+        req: &RequestHeader,
+        host: &str,
+        decoded: bool,
+    ) -> Option<BodyLog> {
+        let sampled = (uuid::Uuid::new_v4().as_u128() % 100) < u128::from(self.percentage);
+        if !sampled {
+            return None;
+        }
+
+        Some(BodyLog {
+            logging: Arc::clone(self),
+            host: host.to_string(),
+            method: req.method.to_string(),
+            path: req.uri.path().to_string(),
+            request: self.request.then(|| Body::new(&req.headers, decoded)),
+            response: None,
+        })
+    }
+
+    /// The body as written to the logs: the JSON fields and the patterns are redacted first
+    fn render(&self, body: &Body) -> String {
+        if let Some(encoding) = body.encoding.as_deref() {
+            return format!("<{encoding} encoded>");
+        }
+        if body.data.is_empty() {
+            return String::new();
+        }
+
+        let mut data = body.data.to_vec();
+        if body.json && !self.redact_json.is_empty() {
+            // The fields of the bodies that can't be parsed (e.g. cut) could leak
+            let value = (!body.truncated)
+                .then(|| serde_json::from_slice::<Value>(&data).ok())
+                .flatten();
+            let Some(mut value) = value else {
+                return REDACTED.to_string();
+            };
+            for path in &self.redact_json {
+                redact_json(&mut value, path);
+            }
+            data = serde_json::to_vec(&value).unwrap_or_default();
+        }
+        for pattern in &self.redact_body {
+            data = pattern.replace_all(&data, REDACTED.as_bytes()).into_owned();
+        }
+        String::from_utf8_lossy(&data).into_owned()
+    }
+}
+
+impl BodyLog {
+    /// Keeps a part of the body of the request, up to `max_body_size`
+    pub fn push_request(&mut self, data: Option<&Bytes>) {
+        if let Some(body) = self.request.as_mut() {
+            body.push(data, self.logging.max_body_size);
+        }
+    }
+
+    /// Starts the body of the response, with the headers of the upstream
+    pub fn start_response(&mut self, response: &ResponseHeader) {
+        if self.logging.response {
+            self.response = Some(Body::new(&response.headers, false));
+        }
+    }
+
+    /// Keeps a part of the body of the response, up to `max_body_size`
+    pub fn push_response(&mut self, data: Option<&Bytes>) {
+        if let Some(body) = self.response.as_mut() {
+            body.push(data, self.logging.max_body_size);
+        }
+    }
+
+    /// Writes the bodies to the logs, with the status of the response
+    pub fn finish(self, status: u16, request_id: Option<&str>, trace_id: Option<&str>) {
+        let (request, response) = (self.request.as_ref(), self.response.as_ref());
+        let request_body = request.map(|body| self.logging.render(body));
+        let response_body = response.map(|body| self.logging.render(body));
+
+        tracing::info!(
+            host = self.host,
+            method = self.method,
+            path = self.path,
+            status,
+            request_id,
+            trace_id,
+            request_body = request_body.as_deref(),
+            request_body_size = request.map(|body| body.size),
+            request_body_truncated = request.map(|body| body.truncated),
+            response_body = response_body.as_deref(),
+            response_body_size = response.map(|body| body.size),
+            response_body_truncated = response.map(|body| body.truncated),
+            body_log = true,
+            "body log"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn body_logging(redact_json: &[&str]) -> Arc<BodyLogging> {
+        let config = RouteBodyLogging {
+            percentage: 100,
+            max_body_size: Some(64),
+            request: true,
+            response: true,
+            redact_body: vec![r"\d{4}-\d{4}".to_string()],
+            redact_json: redact_json.iter().map(ToString::to_string).collect(),
+        };
+        Arc::new(BodyLogging::from_config(&config).unwrap())
+    }
+
+    fn request(content_type: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/payments", None).unwrap();
+        req.insert_header("content-type", content_type).unwrap();
+        req
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("$.cards[*].number").unwrap(),
+            vec![
+                Segment::Key("cards".to_string()),
+                Segment::Any,
+                Segment::Key("number".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_path("$['user'].emails[0]").unwrap(),
+            vec![
+                Segment::Key("user".to_string()),
+                Segment::Key("emails".to_string()),
+                Segment::Index(0),
+            ]
+        );
+        assert_eq!(
+            parse_path("$..token").unwrap(),
+            vec![Segment::Descendant("token".to_string())]
+        );
+        for path in ["$", "password", "$.", "$[x]", "$.user[0", "$..*"] {
+            assert!(parse_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_redact_json() {
+        let mut value = json!({
+            "user": { "name": "a", "password": "hunter2" },
+            "cards": [{ "number": "4242" }, { "number": "4343" }],
+            "session": { "auth": { "token": "t1" } },
+            "token": "t2",
+        });
+        for path in [
+            "$.user.password",
+            "$.cards[*].number",
+            "$..token",
+            "$.missing.field",
+        ] {
+            redact_json(&mut value, &parse_path(path).unwrap());
+        }
+        assert_eq!(
+            value,
+            json!({
+                "user": { "name": "a", "password": REDACTED },
+                "cards": [{ "number": REDACTED }, { "number": REDACTED }],
+                "session": { "auth": { "token": REDACTED } },
+                "token": REDACTED,
+            })
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let logging = body_logging(&["$.password"]);
+
+        let mut log = logging
+            .start(
+                &request("application/json; charset=utf-8"),
+                "example.com",
+                false,
+            )
+            .unwrap();
+        log.push_request(Some(&Bytes::from_static(b"{\"password\":\"hunter2\",")));
+        log.push_request(Some(&Bytes::from_static(b"\"card\":\"1234-5678\"}")));
+        let body = log.request.as_ref().unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&logging.render(body)).unwrap(),
+            json!({ "password": REDACTED, "card": REDACTED })
+        );
+
+        // The JSON bodies that were cut are not logged
+        log.push_request(Some(&Bytes::from(vec![b' '; 64])));
+        let body = log.request.as_ref().unwrap();
+        assert!(body.truncated);
+        assert_eq!(body.size, 105);
+        assert_eq!(logging.render(body), REDACTED);
+
+        let mut log = logging
+            .start(&request("text/plain"), "example.com", false)
+            .unwrap();
+        log.push_request(Some(&Bytes::from_static(b"card 1234-5678")));
+        assert_eq!(
+            logging.render(log.request.as_ref().unwrap()),
+            "card <redacted>"
+        );
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("content-encoding", "gzip").unwrap();
+        log.start_response(&response);
+        log.push_response(Some(&Bytes::from_static(b"\x1f\x8b")));
+        let body = log.response.as_ref().unwrap();
+        assert!(body.data.is_empty());
+        assert_eq!(logging.render(body), "<gzip encoded>");
+    }
+
+    #[test]
+    fn test_invalid_body_logging() {
+        let mut config = RouteBodyLogging {
+            percentage: 101,
+            max_body_size: None,
+            request: true,
+            response: false,
+            redact_body: vec![],
+            redact_json: vec![],
+        };
+        assert!(BodyLogging::from_config(&config).is_err());
+
+        config.percentage = 10;
+        config.redact_json = vec!["password".to_string()];
+        assert!(BodyLogging::from_config(&config).is_err());
+    }
+}
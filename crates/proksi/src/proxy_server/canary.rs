@@ -0,0 +1,264 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use http::{header, HeaderName};
+use pingora::{
+    http::RequestHeader,
+    lb::{selection::RoundRobin, LoadBalancer},
+};
+
+use crate::{
+    config::{CanaryStickyKey, RouteCanary, RouteUpstream},
+    services::health_check::TimedHealthCheck,
+};
+
+const DEFAULT_COOKIE: &str = "proksi_canary";
+
+/// What identifies the clients keeping their pool
+#[derive(Debug)]
+enum StickyKey {
+    Cookie(String),
+    Header(HeaderName),
+    ClientIp,
+}
+
+/// The canary pool of a route, receiving `weight`% of its requests
+pub struct Canary {
+    pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    pub upstreams: Vec<RouteUpstream>,
+    weight: u32,
+    sticky: Option<StickyKey>,
+}
+
+/// The pool of a request, picked once (retries of the request use the same one)
+pub struct CanarySplit {
+    pub canary: bool,
+    /// Set when the client had no cookie yet, sent with the response
+    set_cookie: Option<String>,
+}
+
+impl CanarySplit {
+    pub fn pool(&self) -> &'static str {
+        if self.canary {
+            "canary"
+        } else {
+            "stable"
+        }
+    }
+
+    /// The `Set-Cookie` header of a new client
+    pub fn set_cookie(&self) -> Option<&str> {
+        self.set_cookie.as_deref()
+    }
+}
+
+impl Canary {
+    pub fn from_config(host: &str, config: &RouteCanary) -> anyhow::Result<Self> {
+        if config.upstreams.is_empty() {
+            return Err(anyhow!("upstreams must have at least one upstream"));
+        }
+        if config.weight > 100 {
+            return Err(anyhow!("weight must be a percentage, from 0 to 100"));
+        }
+
+        let sticky = match config.sticky.as_ref() {
+            None => None,
+            Some(sticky) => Some(match sticky.by {
+                CanaryStickyKey::Cookie => {
+                    let cookie = sticky.name.as_deref().unwrap_or(DEFAULT_COOKIE);
+                    let valid = !cookie.is_empty()
+                        && cookie
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+                    if !valid {
+                        return Err(anyhow!(
+                            "sticky.name must only contain letters, digits, '-', '_' or '.'"
+                        ));
+                    }
+                    StickyKey::Cookie(cookie.to_string())
+                }
+                CanaryStickyKey::Header => {
+                    let name = sticky
+                        .name
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("sticky.name is required with a header"))?;
+                    let name = HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|_| anyhow!("sticky.name {name} is not a header name"))?;
+                    StickyKey::Header(name)
+                }
+                CanaryStickyKey::ClientIp => StickyKey::ClientIp,
+            }),
+        };
+
+        let addresses = config
+            .upstreams
+            .iter()
+            .map(|u| format!("{}:{}", u.ip, u.port));
+        let mut load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(addresses)
+            .map_err(|err| anyhow!("invalid upstreams: {err}"))?;
+        load_balancer.set_health_check(TimedHealthCheck::new(host));
+        load_balancer.health_check_frequency = Some(Duration::from_secs(15));
+
+        Ok(Self {
+            load_balancer: Arc::new(load_balancer),
+            upstreams: config.upstreams.clone(),
+            weight: u32::from(config.weight),
+            sticky,
+        })
+    }
+
+    /// Picks the pool of a request. Sticky clients always fall in the same bucket out of
+    /// 100: raising the weight only moves clients from the stable pool to the canary.
+    pub fn split(&self, req: &RequestHeader, client_ip: Option<&str>) -> CanarySplit {
+        let mut set_cookie = None;
+        let key = match self.sticky.as_ref() {
+            Some(StickyKey::Cookie(name)) => {
+                let id = request_cookie(req, name)
+                    .filter(|id| is_valid_id(id))
+                    .map(ToString::to_string);
+                Some(id.unwrap_or_else(|| {
+                    let id = uuid::Uuid::new_v4().simple().to_string();
+                    set_cookie = Some(format!(
+                        "{name}={id}; Path=/; HttpOnly; Secure; SameSite=Lax"
+                    ));
+                    id
+                }))
+            }
+            Some(StickyKey::Header(name)) => req
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(ToString::to_string),
+            Some(StickyKey::ClientIp) => client_ip.map(ToString::to_string),
+            None => None,
+        };
+
+        // Requests without a key are split one by one
+        let key = key.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        CanarySplit {
+            canary: bucket(&key) < self.weight,
+            set_cookie,
+        }
+    }
+}
+
+/// The bucket of a client, the same on every instance
+fn bucket(key: &str) -> u32 {
+    let digest = openssl::sha::sha256(key.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100
+}
+
+/// Value of the cookie `name` of the request
+fn request_cookie<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// The IDs are generated by proksi, anything else is replaced by a new one
+fn is_valid_id(id: &str) -> bool {
+    (1..=64).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::config::RouteCanarySticky;
+
+    use super::*;
+
+    fn canary(weight: u8, by: Option<CanaryStickyKey>) -> Canary {
+        let config = RouteCanary {
+            upstreams: vec![RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port: 4000,
+                ..RouteUpstream::default()
+            }],
+            weight,
+            sticky: by.map(|by| RouteCanarySticky {
+                by,
+                name: (by == CanaryStickyKey::Header).then(|| "x-user-id".to_string()),
+            }),
+        };
+        Canary::from_config("canary.localhost", &config).unwrap()
+    }
+
+    #[test]
+    fn test_split() {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(!canary(0, None).split(&req, None).canary);
+        assert!(canary(100, None).split(&req, None).canary);
+
+        // The share of the canary follows its weight
+        let split = canary(30, None);
+        let canaries = (0..1000).filter(|_| split.split(&req, None).canary).count();
+        assert!((200..400).contains(&canaries), "{canaries} canaries");
+    }
+
+    #[test]
+    fn test_sticky_split() {
+        let split = canary(50, Some(CanaryStickyKey::Cookie));
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let first = split.split(&req, None);
+        let set_cookie = first.set_cookie().unwrap();
+        assert!(set_cookie.starts_with("proksi_canary="));
+
+        // The client keeps its pool with the cookie
+        let cookie = set_cookie.split(';').next().unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("cookie", cookie).unwrap();
+        for _ in 0..10 {
+            let next = split.split(&req, None);
+            assert_eq!(next.canary, first.canary);
+            assert!(next.set_cookie().is_none());
+        }
+
+        // Raising the weight only moves the clients of the stable pool
+        let id = cookie.trim_start_matches("proksi_canary=");
+        let clients = (0..100).map(|n| format!("{id}{n}")).collect::<Vec<_>>();
+        let by_ip = canary(20, Some(CanaryStickyKey::ClientIp));
+        let more = canary(60, Some(CanaryStickyKey::ClientIp));
+        for client in &clients {
+            if by_ip.split(&req, Some(client)).canary {
+                assert!(more.split(&req, Some(client)).canary);
+            }
+        }
+
+        let by_header = canary(50, Some(CanaryStickyKey::Header));
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-user-id", "42").unwrap();
+        let pool = by_header.split(&req, None).canary;
+        assert!((0..10).all(|_| by_header.split(&req, None).canary == pool));
+    }
+
+    #[test]
+    fn test_invalid_canary() {
+        let config = RouteCanary {
+            upstreams: vec![],
+            weight: 10,
+            sticky: None,
+        };
+        assert!(Canary::from_config("canary.localhost", &config).is_err());
+
+        let mut config = RouteCanary {
+            upstreams: canary(0, None).upstreams,
+            weight: 101,
+            sticky: None,
+        };
+        assert!(Canary::from_config("canary.localhost", &config).is_err());
+
+        config.weight = 10;
+        config.sticky = Some(RouteCanarySticky {
+            by: CanaryStickyKey::Header,
+            name: None,
+        });
+        assert!(Canary::from_config("canary.localhost", &config).is_err());
+    }
+}
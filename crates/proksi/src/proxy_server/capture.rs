@@ -0,0 +1,271 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderName};
+use openssl::base64;
+use pingora::http::RequestHeader;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::RouteCapture;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Replaces the values of the redacted headers and the redacted parts of the bodies
+pub const REDACTED: &str = "<redacted>";
+
+/// Headers holding credentials, always redacted
+const REDACTED_HEADERS: [HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// A request written by a capture, on a line of its file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    /// Unix timestamp, in seconds
+    pub at: u64,
+    pub host: String,
+    pub method: String,
+    /// Path and query of the request
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    /// Base64 of the body, cut to `max_body_size`
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub body_truncated: bool,
+    /// Status of the response, `0` when none was sent
+    #[serde(default)]
+    pub status: u16,
+}
+
+/// The capture of a route, writing `percentage`% of its requests to its file
+pub struct Capture {
+    path: PathBuf,
+    /// Opened with the first request, the configuration is checked without creating it
+    file: Mutex<Option<File>>,
+    percentage: u32,
+    max_body_size: usize,
+    redact_headers: Vec<HeaderName>,
+    redact_body: Vec<Regex>,
+}
+
+/// A captured request, written once its response was sent
+pub struct CaptureRequest {
+    capture: Arc<Capture>,
+    request: CapturedRequest,
+    body: BytesMut,
+}
+
+impl Capture {
+    pub fn from_config(config: &RouteCapture) -> anyhow::Result<Self> {
+        if config.percentage > 100 {
+            return Err(anyhow!("percentage must be from 0 to 100"));
+        }
+
+        let mut redact_headers = REDACTED_HEADERS.to_vec();
+        for name in &config.redact_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow!("redact_headers: {name} is not a header name"))?;
+            redact_headers.push(name);
+        }
+        let redact_body = config
+            .redact_body
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| anyhow!("redact_body: {err}"))?;
+
+        Ok(Self {
+            path: config.path.clone(),
+            file: Mutex::new(None),
+            percentage: u32::from(config.percentage),
+            max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            redact_headers,
+            redact_body,
+        })
+    }
+
+    /// The capture of the request, for the sampled ones
+    pub fn start(self: &Arc<Self>, req: &RequestHeader, host: &str) -> Option<CaptureRequest> {
+        let sampled = (uuid::Uuid::new_v4().as_u128() % 100) < u128::from(self.percentage);
+        if !sampled {
+            return None;
+        }
+
+        let headers = req
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.redact_headers.contains(name) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+
+        Some(CaptureRequest {
+            capture: Arc::clone(self),
+            request: CapturedRequest {
+                at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                host: host.to_string(),
+                method: req.method.to_string(),
+                uri: req
+                    .uri
+                    .path_and_query()
+                    .map_or("/", |p| p.as_str())
+                    .to_string(),
+                headers,
+                body: String::new(),
+                body_truncated: false,
+                status: 0,
+            },
+            body: BytesMut::new(),
+        })
+    }
+
+    /// Appends a request to the file, a line at a time so that the requests of the
+    /// threads don't mix
+    fn write(&self, request: &CapturedRequest) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().map_err(|_| anyhow!("poisoned lock"))?;
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        if let Some(file) = file.as_mut() {
+            file.write_all(&line)?;
+        }
+        Ok(())
+    }
+}
+
+impl CaptureRequest {
+    /// Keeps a part of the body of the request, up to `max_body_size`
+    pub fn push(&mut self, data: Option<&Bytes>) {
+        let data = data.map_or(&[][..], |d| d.as_ref());
+        let left = self.capture.max_body_size.saturating_sub(self.body.len());
+        if data.len() > left {
+            self.request.body_truncated = true;
+        }
+        self.body.extend_from_slice(&data[..data.len().min(left)]);
+    }
+
+    /// Writes the request, with the status of its response
+    pub fn finish(mut self, status: u16) {
+        let mut body = self.body.to_vec();
+        for pattern in &self.capture.redact_body {
+            body = pattern.replace_all(&body, REDACTED.as_bytes()).into_owned();
+        }
+        self.request.body = base64::encode_block(&body);
+        self.request.status = status;
+
+        if let Err(err) = self.capture.write(&self.request) {
+            tracing::error!(
+                "failed to capture a request of {} in {:?}: {err}",
+                self.request.host,
+                self.capture.path
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(path: PathBuf) -> Arc<Capture> {
+        let config = RouteCapture {
+            path,
+            percentage: 100,
+            max_body_size: Some(64),
+            redact_headers: vec!["x-api-key".to_string()],
+            redact_body: vec![r#""password":\s*"[^"]*""#.to_string()],
+        };
+        Arc::new(Capture::from_config(&config).unwrap())
+    }
+
+    #[test]
+    fn test_capture() {
+        let path = std::env::temp_dir().join(format!("proksi-capture-{}", uuid::Uuid::new_v4()));
+        let capture = capture(path.clone());
+
+        let mut req = RequestHeader::build("POST", b"/login?next=/", None).unwrap();
+        req.insert_header("authorization", "Bearer secret").unwrap();
+        req.insert_header("x-api-key", "secret").unwrap();
+        req.insert_header("content-type", "application/json").unwrap();
+        let mut request = capture.start(&req, "example.com").unwrap();
+        request.push(Some(&Bytes::from_static(b"{\"user\":\"a\",")));
+        request.push(Some(&Bytes::from_static(b"\"password\": \"hunter2\"}")));
+        request.finish(200);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let captured = serde_json::from_str::<CapturedRequest>(content.trim_end()).unwrap();
+        assert_eq!(captured.host, "example.com");
+        assert_eq!(captured.method, "POST");
+        assert_eq!(captured.uri, "/login?next=/");
+        assert_eq!(captured.status, 200);
+        let header = |name: &str| {
+            captured
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("authorization"), Some(REDACTED));
+        assert_eq!(header("x-api-key"), Some(REDACTED));
+        assert_eq!(header("content-type"), Some("application/json"));
+
+        let body = base64::decode_block(&captured.body).unwrap();
+        assert_eq!(body, b"{\"user\":\"a\",<redacted>}");
+        assert!(!captured.body_truncated);
+
+        // The next requests are appended
+        let mut request = capture.start(&req, "example.com").unwrap();
+        request.push(Some(&Bytes::from(vec![b'a'; 80])));
+        request.finish(0);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let line = content.lines().nth(1).unwrap();
+        let captured = serde_json::from_str::<CapturedRequest>(line).unwrap();
+        assert!(captured.body_truncated);
+        assert_eq!(base64::decode_block(&captured.body).unwrap().len(), 64);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_capture() {
+        let mut config = RouteCapture {
+            path: PathBuf::from("/tmp/capture.jsonl"),
+            percentage: 101,
+            max_body_size: None,
+            redact_headers: vec![],
+            redact_body: vec![],
+        };
+        assert!(Capture::from_config(&config).is_err());
+
+        config.percentage = 10;
+        config.redact_body = vec!["(".to_string()];
+        assert!(Capture::from_config(&config).is_err());
+    }
+}
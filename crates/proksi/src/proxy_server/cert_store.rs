@@ -40,7 +40,11 @@ impl TlsAccept for CertStore {
     /// based on the server name
     async fn certificate_callback(&self, ssl: &mut pingora::tls::ssl::SslRef) {
         // Due to the sni_callback function, we can safely unwrap here
-        let host_name = ssl.servername(NameType::HOST_NAME).unwrap_or_default();
+        let host_name = ssl
+            .servername(NameType::HOST_NAME)
+            .unwrap_or_default()
+            .to_string();
+        let host_name = host_name.as_str();
 
         let Some(cert) = stores::global::get_store().get_certificate(host_name).await else {
             tracing::info!("No certificate found for host: {:?}", host_name);
@@ -53,5 +57,11 @@ impl TlsAccept for CertStore {
         if let Some(chain) = &cert.chain {
             ext::ssl_add_chain_cert(ssl, chain).unwrap();
         }
+
+        if let Some(client_auth) = stores::get_route_by_key(host_name).and_then(|r| r.client_auth) {
+            if let Err(err) = client_auth.configure_handshake(ssl) {
+                tracing::error!("failed to verify the client certificates of {host_name}: {err}");
+            }
+        }
     }
 }
@@ -0,0 +1,453 @@
+use std::{fmt::Write, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use once_cell::sync::Lazy;
+use openssl::{
+    hash::MessageDigest,
+    ssl::{SslRef, SslVerifyMode},
+    x509::{store::X509StoreBuilder, X509NameRef, X509Ref, X509VerifyResult, X509},
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use pingora::{http::RequestHeader, proxy::Session};
+
+use crate::config::{ClientCertificateFormat, RouteClientCertificateForward, RouteSslClientAuth};
+
+use super::revocation::Revocation;
+
+/// Most certificates remembered, they are forgotten all at once past it
+const MAX_VERIFIED_CERTIFICATES: usize = 10_000;
+
+/// Certificates verified during the handshakes, by fingerprint. HTTP/2 sessions don't
+/// expose their TLS connection, only the fingerprint of the certificate of the client.
+static VERIFIED_CERTIFICATES: Lazy<papaya::HashMap<String, Arc<ClientCertificate>>> =
+    Lazy::new(papaya::HashMap::new);
+
+/// Headers carrying the client certificate, removed from the requests of the clients
+const CERTIFICATE_HEADERS: [&str; 8] = [
+    "x-forwarded-client-cert",
+    "x-client-cert",
+    "x-client-cert-fingerprint",
+    "x-client-cert-subject",
+    "x-client-cert-issuer",
+    "x-client-cert-serial",
+    "x-client-cert-dns",
+    "x-client-cert-uri",
+];
+
+/// Verification of the client certificates of a route
+#[derive(Debug, Clone)]
+pub struct ClientAuth {
+    ca: Vec<X509>,
+    pub required: bool,
+    pub forward: Option<RouteClientCertificateForward>,
+    revocation: Option<Arc<Revocation>>,
+}
+
+impl ClientAuth {
+    pub fn from_config(config: &RouteSslClientAuth) -> anyhow::Result<Self> {
+        let pem = std::fs::read(&config.ca)
+            .with_context(|| format!("failed to read the CA certificates {:?}", config.ca))?;
+        let ca = X509::stack_from_pem(&pem)?;
+        if ca.is_empty() {
+            return Err(anyhow!("{:?} has no certificate", config.ca));
+        }
+
+        let revocation = config
+            .revocation
+            .as_ref()
+            .map(|revocation| Revocation::from_config(revocation, &ca))
+            .transpose()?
+            .map(Arc::new);
+
+        Ok(ClientAuth {
+            ca,
+            required: config.required,
+            forward: config.forward.clone(),
+            revocation,
+        })
+    }
+
+    /// Requests the certificate of the client, verified against the CAs of the route
+    pub fn configure_handshake(&self, ssl: &mut SslRef) -> anyhow::Result<()> {
+        let mut store = X509StoreBuilder::new()?;
+        for ca in &self.ca {
+            store.add_cert(ca.clone())?;
+        }
+        ssl.set_verify_cert_store(store.build())?;
+
+        let mut mode = SslVerifyMode::PEER;
+        if self.required {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        // The certificate of the client is verified last, once its chain is
+        let revocation = self.revocation.clone();
+        ssl.set_verify_callback(mode, move |verified, context| {
+            if !verified || context.error_depth() != 0 {
+                return verified;
+            }
+            let Some(certificate) = context.current_cert() else {
+                return verified;
+            };
+
+            if revocation
+                .as_ref()
+                .is_some_and(|revocation| !revocation.check_crl(certificate))
+            {
+                context.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+                return false;
+            }
+            remember(certificate);
+            true
+        });
+
+        Ok(())
+    }
+
+    /// Whether the certificate of the client of the session isn't revoked according to
+    /// the OCSP responder of its issuer (always without OCSP checks or certificate)
+    pub async fn check_ocsp(&self, session: &Session) -> bool {
+        let Some(revocation) = self.revocation.as_deref() else {
+            return true;
+        };
+        let Some(certificate) = get_certificate(session) else {
+            return true;
+        };
+        revocation.check_ocsp(&certificate, &self.ca).await
+    }
+}
+
+/// Details of a verified client certificate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientCertificate {
+    /// SHA-256 of the certificate (DER), in hex
+    pub fingerprint: String,
+    /// Distinguished name (RFC 4514), e.g. `CN=client,O=Acme`
+    pub subject: String,
+    pub issuer: String,
+    /// Serial number, in hex
+    pub serial: String,
+    /// DNS names of the subject alternative names
+    pub dns: Vec<String>,
+    /// URIs of the subject alternative names (e.g. SPIFFE IDs)
+    pub uri: Vec<String>,
+    /// Unknown when it was not verified by this instance, with HTTP/2
+    pub pem: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_x509(certificate: &X509Ref) -> anyhow::Result<Self> {
+        let names = certificate
+            .subject_alt_names()
+            .map(|names| names.into_iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        Ok(ClientCertificate {
+            fingerprint: hex(&certificate.digest(MessageDigest::sha256())?),
+            subject: distinguished_name(certificate.subject_name()),
+            issuer: distinguished_name(certificate.issuer_name()),
+            serial: certificate
+                .serial_number()
+                .to_bn()?
+                .to_hex_str()?
+                .to_string(),
+            dns: names
+                .iter()
+                .filter_map(|name| name.dnsname().map(ToString::to_string))
+                .collect(),
+            uri: names
+                .iter()
+                .filter_map(|name| name.uri().map(ToString::to_string))
+                .collect(),
+            pem: Some(String::from_utf8(certificate.to_pem()?)?),
+        })
+    }
+
+    /// `X-Forwarded-Client-Cert`, as sent by Envoy
+    fn xfcc(&self, include_pem: bool) -> String {
+        let mut value = format!("Hash={}", self.fingerprint);
+        if let Some(pem) = self.pem.as_deref().filter(|_| include_pem) {
+            let _ = write!(value, ";Cert=\"{}\"", url_encode(pem));
+        }
+        if !self.subject.is_empty() {
+            let _ = write!(value, ";Subject={}", quoted(&self.subject));
+        }
+        for uri in &self.uri {
+            let _ = write!(value, ";URI={}", quoted(uri));
+        }
+        for dns in &self.dns {
+            let _ = write!(value, ";DNS={}", quoted(dns));
+        }
+        value
+    }
+
+    fn headers(&self, include_pem: bool) -> Vec<(&'static str, String)> {
+        let pem = self
+            .pem
+            .as_deref()
+            .filter(|_| include_pem)
+            .map(url_encode)
+            .unwrap_or_default();
+
+        [
+            ("x-client-cert-fingerprint", self.fingerprint.clone()),
+            ("x-client-cert-subject", self.subject.clone()),
+            ("x-client-cert-issuer", self.issuer.clone()),
+            ("x-client-cert-serial", self.serial.clone()),
+            ("x-client-cert-dns", self.dns.join(",")),
+            ("x-client-cert-uri", self.uri.join(",")),
+            ("x-client-cert", pem),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+    }
+}
+
+fn remember(certificate: &X509Ref) {
+    let Ok(certificate) = ClientCertificate::from_x509(certificate) else {
+        return;
+    };
+
+    let certificates = VERIFIED_CERTIFICATES.pin();
+    if certificates.len() >= MAX_VERIFIED_CERTIFICATES {
+        certificates.clear();
+    }
+    certificates.insert(certificate.fingerprint.clone(), Arc::new(certificate));
+}
+
+/// Whether the client of the session sent a certificate, verified during the handshake
+pub fn has_certificate(session: &Session) -> bool {
+    session
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_deref())
+        .is_some_and(|digest| !digest.cert_digest.is_empty())
+}
+
+/// The certificate of the client of the session. A session resumed by a client, or one
+/// whose certificate was forgotten, has its certificate read from the connection
+/// (HTTP/1.1), otherwise it only has the details kept by the TLS digest.
+pub fn get_certificate(session: &Session) -> Option<Arc<ClientCertificate>> {
+    let digest = session
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_deref())
+        .filter(|digest| !digest.cert_digest.is_empty())?;
+    let fingerprint = hex(&digest.cert_digest);
+
+    if let Some(certificate) = VERIFIED_CERTIFICATES.pin().get(&fingerprint) {
+        return Some(certificate.clone());
+    }
+
+    let peer_certificate = session
+        .as_downstream()
+        .stream()
+        .and_then(|stream| stream.get_ssl())
+        .and_then(|ssl| ssl.peer_certificate());
+    if let Some(Ok(certificate)) = peer_certificate.map(|c| ClientCertificate::from_x509(&c)) {
+        return Some(Arc::new(certificate));
+    }
+
+    Some(Arc::new(ClientCertificate {
+        fingerprint,
+        subject: digest
+            .organization
+            .as_deref()
+            .map(|organization| format!("O={}", escape_dn_value(organization)))
+            .unwrap_or_default(),
+        serial: digest.serial_number.clone().unwrap_or_default(),
+        ..Default::default()
+    }))
+}
+
+/// Replaces the certificate headers of the upstream request with the details of the
+/// certificate of the client (none without a certificate)
+pub fn set_certificate_headers(
+    upstream_request: &mut RequestHeader,
+    certificate: Option<&ClientCertificate>,
+    forward: &RouteClientCertificateForward,
+) {
+    for header in CERTIFICATE_HEADERS {
+        upstream_request.remove_header(header);
+    }
+
+    let Some(certificate) = certificate else {
+        return;
+    };
+    let headers = match forward.format {
+        ClientCertificateFormat::Xfcc => vec![(
+            "x-forwarded-client-cert",
+            certificate.xfcc(forward.include_pem),
+        )],
+        ClientCertificateFormat::Headers => certificate.headers(forward.include_pem),
+    };
+
+    // A name that isn't a valid header value isn't sent
+    for (name, value) in headers {
+        upstream_request.insert_header(name, value).ok();
+    }
+}
+
+/// The entries of the name, the most specific first (e.g. `CN=client,O=Acme,C=FR`)
+fn distinguished_name(name: &X509NameRef) -> String {
+    let entries = name
+        .entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            Some(format!("{key}={}", escape_dn_value(&value)))
+        })
+        .collect::<Vec<_>>();
+
+    entries.into_iter().rev().collect::<Vec<_>>().join(",")
+}
+
+fn escape_dn_value(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        if matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+/// A value of `X-Forwarded-Client-Cert`, quoted when it has separators
+fn quoted(value: &str) -> String {
+    match value.contains([',', ';', '=', '"']) {
+        true => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Characters encoded in the values, all but the unreserved ones of RFC 3986
+const URL_ENCODED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn url_encode(value: &str) -> String {
+    utf8_percent_encode(value, URL_ENCODED).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder},
+    };
+
+    use super::*;
+
+    fn certificate() -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Acme, Inc").unwrap();
+        name.append_entry_by_text("CN", "client").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(0x1f).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let names = SubjectAlternativeName::new()
+            .dns("client.internal")
+            .uri("spiffe://acme/client")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_certificate_details() {
+        let certificate = ClientCertificate::from_x509(&certificate()).unwrap();
+        assert_eq!(certificate.subject, r"CN=client,O=Acme\, Inc");
+        assert_eq!(certificate.issuer, certificate.subject);
+        assert_eq!(certificate.serial, "1F");
+        assert_eq!(certificate.dns, vec!["client.internal"]);
+        assert_eq!(certificate.uri, vec!["spiffe://acme/client"]);
+        assert_eq!(certificate.fingerprint.len(), 64);
+        assert!(certificate
+            .pem
+            .as_deref()
+            .unwrap()
+            .starts_with("-----BEGIN CERTIFICATE-----"));
+    }
+
+    #[test]
+    fn test_xfcc() {
+        let certificate = ClientCertificate::from_x509(&certificate()).unwrap();
+        assert_eq!(
+            certificate.xfcc(false),
+            format!(
+                r#"Hash={};Subject="CN=client,O=Acme\\, Inc";URI=spiffe://acme/client;DNS=client.internal"#,
+                certificate.fingerprint
+            )
+        );
+
+        let xfcc = certificate.xfcc(true);
+        assert!(xfcc.contains(";Cert=\"-----BEGIN%20CERTIFICATE-----%0A"));
+    }
+
+    #[test]
+    fn test_certificate_headers() {
+        let certificate = ClientCertificate::from_x509(&certificate()).unwrap();
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request
+            .insert_header("x-forwarded-client-cert", "Hash=spoofed")
+            .unwrap();
+        request
+            .insert_header("x-client-cert-subject", "CN=admin")
+            .unwrap();
+
+        let forward = RouteClientCertificateForward {
+            format: ClientCertificateFormat::Headers,
+            include_pem: false,
+        };
+        set_certificate_headers(&mut request, Some(&certificate), &forward);
+        assert!(request.headers.get("x-forwarded-client-cert").is_none());
+        assert!(request.headers.get("x-client-cert").is_none());
+        assert_eq!(
+            request.headers.get("x-client-cert-subject").unwrap(),
+            r"CN=client,O=Acme\, Inc"
+        );
+        assert_eq!(
+            request.headers.get("x-client-cert-uri").unwrap(),
+            "spiffe://acme/client"
+        );
+
+        // The headers of the clients are removed, even without a certificate
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request
+            .insert_header("x-forwarded-client-cert", "Hash=spoofed")
+            .unwrap();
+        set_certificate_headers(
+            &mut request,
+            None,
+            &RouteClientCertificateForward::default(),
+        );
+        assert!(request.headers.get("x-forwarded-client-cert").is_none());
+    }
+}
@@ -0,0 +1,254 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use pingora::{http::RequestHeader, proxy::Session};
+
+use crate::config::{ForwardedHeaders, ForwardedMode};
+
+/// Returns the IP of the client that originated the request.
+///
+/// When the peer is one of the trusted proxies, the `X-Forwarded-For` header is read from
+/// right to left and the first IP that is not a trusted proxy is returned. Otherwise
+/// the header can be spoofed by anyone and the peer IP is used instead.
+pub fn get_client_ip(session: &Session, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer_ip = session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(std::net::SocketAddr::ip)?;
+
+    Some(resolve_client_ip(
+        peer_ip,
+        session.req_header(),
+        trusted_proxies,
+    ))
+}
+
+/// Returns the IP of the connection (the client, or a proxy in front of proksi)
+pub fn get_peer_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(std::net::SocketAddr::ip)
+}
+
+fn resolve_client_ip(peer_ip: IpAddr, req: &RequestHeader, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer_ip) {
+        return peer_ip;
+    }
+
+    let forwarded_ips = req
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+
+    let mut client_ip = peer_ip;
+    for ip in forwarded_ips.into_iter().rev() {
+        // An invalid entry can't be trusted, use the last valid hop instead
+        let Ok(ip) = ip else {
+            break;
+        };
+
+        client_ip = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+
+    client_ip
+}
+
+/// Sets the `X-Forwarded-*` and `X-Real-IP` headers of the upstream request.
+///
+/// The headers sent by a trusted proxy are kept in `append` mode, the ones sent by anyone
+/// else are always replaced as they can be spoofed.
+pub fn set_forwarded_headers(
+    upstream_request: &mut RequestHeader,
+    config: &ForwardedHeaders,
+    peer_ip: Option<IpAddr>,
+    client_ip: Option<IpAddr>,
+    host: &str,
+    trusted_proxies: &[IpNet],
+) -> pingora::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let keep_existing = config.mode == ForwardedMode::Append
+        && peer_ip.is_some_and(|ip| trusted_proxies.iter().any(|net| net.contains(&ip)));
+
+    let forwarded_for = match (keep_existing, peer_ip) {
+        (true, Some(peer_ip)) => {
+            let existing = upstream_request
+                .headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            if existing.is_empty() {
+                Some(peer_ip.to_string())
+            } else {
+                Some(format!("{existing}, {peer_ip}"))
+            }
+        }
+        _ => client_ip.map(|ip| ip.to_string()),
+    };
+
+    match forwarded_for {
+        Some(value) => upstream_request.insert_header("x-forwarded-for", value)?,
+        None => {
+            upstream_request.remove_header("x-forwarded-for");
+        }
+    }
+
+    if let Some(client_ip) = client_ip {
+        upstream_request.insert_header("x-real-ip", client_ip.to_string())?;
+    }
+
+    // Proksi only serves the requests of the routes over TLS
+    if !keep_existing || upstream_request.headers.get("x-forwarded-proto").is_none() {
+        upstream_request.insert_header("x-forwarded-proto", "https")?;
+    }
+
+    if !keep_existing || upstream_request.headers.get("x-forwarded-host").is_none() {
+        upstream_request.insert_header("x-forwarded-host", host)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(forwarded_for: &[&str]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for value in forwarded_for {
+            req.append_header("x-forwarded-for", *value).unwrap();
+        }
+        req
+    }
+
+    fn forwarded(req: &RequestHeader, name: &str) -> Option<String> {
+        req.headers
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_forwarded_headers_from_trusted_proxy() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut req = request(&["1.2.3.4"]);
+        req.insert_header("x-forwarded-proto", "http").unwrap();
+
+        let peer = "10.0.0.1".parse().ok();
+        let client = "1.2.3.4".parse().ok();
+        let config = ForwardedHeaders::default();
+        set_forwarded_headers(&mut req, &config, peer, client, "example.com", &trusted).unwrap();
+
+        assert_eq!(
+            forwarded(&req, "x-forwarded-for").as_deref(),
+            Some("1.2.3.4, 10.0.0.1")
+        );
+        assert_eq!(forwarded(&req, "x-real-ip").as_deref(), Some("1.2.3.4"));
+        assert_eq!(
+            forwarded(&req, "x-forwarded-proto").as_deref(),
+            Some("http")
+        );
+        assert_eq!(
+            forwarded(&req, "x-forwarded-host").as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers_are_replaced() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let client = "1.2.3.4".parse().ok();
+
+        // spoofed by an untrusted peer
+        let mut req = request(&["6.6.6.6"]);
+        req.insert_header("x-forwarded-host", "evil.com").unwrap();
+        let config = ForwardedHeaders::default();
+        set_forwarded_headers(&mut req, &config, client, client, "example.com", &trusted).unwrap();
+
+        assert_eq!(
+            forwarded(&req, "x-forwarded-for").as_deref(),
+            Some("1.2.3.4")
+        );
+        assert_eq!(
+            forwarded(&req, "x-forwarded-proto").as_deref(),
+            Some("https")
+        );
+        assert_eq!(
+            forwarded(&req, "x-forwarded-host").as_deref(),
+            Some("example.com")
+        );
+
+        // overwrite mode, even from a trusted proxy
+        let mut req = request(&["1.2.3.4"]);
+        let config = ForwardedHeaders {
+            enabled: true,
+            mode: ForwardedMode::Overwrite,
+        };
+        let peer = "10.0.0.1".parse().ok();
+        set_forwarded_headers(&mut req, &config, peer, client, "example.com", &trusted).unwrap();
+        assert_eq!(
+            forwarded(&req, "x-forwarded-for").as_deref(),
+            Some("1.2.3.4")
+        );
+
+        // disabled, nothing is changed
+        let mut req = request(&["6.6.6.6"]);
+        let config = ForwardedHeaders {
+            enabled: false,
+            mode: ForwardedMode::Append,
+        };
+        set_forwarded_headers(&mut req, &config, client, client, "example.com", &trusted).unwrap();
+        assert_eq!(
+            forwarded(&req, "x-forwarded-for").as_deref(),
+            Some("6.6.6.6")
+        );
+        assert_eq!(forwarded(&req, "x-real-ip"), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_header() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let req = request(&["1.2.3.4"]);
+
+        let peer = "192.168.1.1".parse().unwrap();
+        assert_eq!(resolve_client_ip(peer, &req, &trusted), peer);
+        assert_eq!(resolve_client_ip(peer, &req, &[]), peer);
+    }
+
+    #[test]
+    fn test_trusted_peer_uses_first_untrusted_hop() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let req = request(&["6.6.6.6, 1.2.3.4", "10.0.0.2"]);
+
+        let client = resolve_client_ip("10.0.0.1".parse().unwrap(), &req, &trusted);
+        assert_eq!(client, "1.2.3.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_trusted_peer_with_invalid_or_missing_header() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let peer = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(resolve_client_ip(peer, &request(&[]), &trusted), peer);
+
+        let req = request(&["1.2.3.4, invalid, 10.0.0.3"]);
+        assert_eq!(
+            resolve_client_ip(peer, &req, &trusted),
+            "10.0.0.3".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use http::{header, HeaderValue, Method, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::compression::{Algorithm, Encode},
+};
+use pingora_cache::{key::HashBinary, CacheMeta, VarianceBuilder};
+
+use crate::config::{CompressionAlgorithm, RouteCompression};
+
+/// Response body encoder, kept in the request context while the body is streamed
+pub type Compressor = Box<dyn Encode + Send + Sync>;
+
+impl CompressionAlgorithm {
+    /// Name of the algorithm in the `Accept-Encoding` and `Content-Encoding` headers
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    /// Level balancing speed and size for each algorithm
+    fn default_level(self) -> u32 {
+        match self {
+            CompressionAlgorithm::Gzip => 6,
+            CompressionAlgorithm::Brotli => 5,
+            CompressionAlgorithm::Zstd => 3,
+        }
+    }
+}
+
+/// Chooses the algorithm with the highest quality in the `Accept-Encoding` header.
+/// Ties are resolved using the order of the configured algorithms.
+pub fn negotiate(
+    accept_encoding: Option<&HeaderValue>,
+    algorithms: &[CompressionAlgorithm],
+) -> Option<CompressionAlgorithm> {
+    let accept_encoding = accept_encoding?.to_str().ok()?;
+
+    let codings = accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next()?.to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((name, quality))
+        })
+        .collect::<Vec<_>>();
+
+    let quality = |algorithm: CompressionAlgorithm| {
+        codings
+            .iter()
+            .find(|(name, _)| name == algorithm.as_str())
+            .or_else(|| codings.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    algorithms
+        .iter()
+        .map(|algorithm| (*algorithm, quality(*algorithm)))
+        .filter(|(_, quality)| *quality > 0.0)
+        // `max_by` returns the last max element, iterate in reverse to prefer the first one
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(algorithm, _)| algorithm)
+}
+
+/// Returns `true` if the configuration allows compressing the response,
+/// regardless of the encodings accepted by the client
+pub fn is_compressible(
+    req: &RequestHeader,
+    resp: &ResponseHeader,
+    config: &RouteCompression,
+) -> bool {
+    if req.method == Method::HEAD
+        || resp.status == StatusCode::NO_CONTENT
+        || resp.status == StatusCode::NOT_MODIFIED
+        || resp.status == StatusCode::PARTIAL_CONTENT
+        || resp.status.is_informational()
+    {
+        return false;
+    }
+
+    let already_encoded = resp
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| v.as_bytes() != b"identity");
+
+    let no_transform = resp
+        .headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+
+    if already_encoded || no_transform {
+        return false;
+    }
+
+    let too_small = resp
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length < config.min_size);
+
+    if too_small {
+        return false;
+    }
+
+    has_content_type(resp, &config.content_types)
+}
+
+/// Returns `true` if the content type of the response is one of `content_types`,
+/// where `text/*` matches every text type
+pub fn has_content_type(resp: &ResponseHeader, content_types: &[impl AsRef<str>]) -> bool {
+    let Some(content_type) = resp
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+    else {
+        return false;
+    };
+
+    content_types
+        .iter()
+        .any(|allowed| match allowed.as_ref().strip_suffix("/*") {
+            Some(prefix) => content_type
+                .split_once('/')
+                .is_some_and(|(kind, _)| kind == prefix),
+            None => content_type == allowed.as_ref(),
+        })
+}
+
+/// Adds `Vary: Accept-Encoding`, unless it's already present
+pub fn add_vary_header(resp: &mut ResponseHeader) -> pingora::Result<()> {
+    if !varies_on_accept_encoding(&resp.headers) {
+        resp.append_header(header::VARY, "accept-encoding")?;
+    }
+    Ok(())
+}
+
+/// Updates the response headers for a body compressed with the given algorithm
+/// and returns the compressor of the body
+pub fn compress_response(
+    resp: &mut ResponseHeader,
+    algorithm: CompressionAlgorithm,
+    level: Option<u32>,
+) -> pingora::Result<Option<Compressor>> {
+    let pingora_algorithm = match algorithm {
+        CompressionAlgorithm::Gzip => Algorithm::Gzip,
+        CompressionAlgorithm::Brotli => Algorithm::Brotli,
+        CompressionAlgorithm::Zstd => Algorithm::Zstd,
+    };
+
+    let Some(compressor) =
+        pingora_algorithm.compressor(level.unwrap_or_else(|| algorithm.default_level()))
+    else {
+        return Ok(None);
+    };
+
+    // The body is streamed, so the final length is unknown and ranges no longer apply
+    resp.remove_header(&header::CONTENT_LENGTH);
+    resp.remove_header(&header::ACCEPT_RANGES);
+    resp.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+    resp.insert_header(header::CONTENT_ENCODING, algorithm.as_str())?;
+
+    // A strong ETag can't be shared by two representations
+    if let Some(etag) = resp.headers.get(header::ETAG).cloned() {
+        if etag.as_bytes().starts_with(b"\"") {
+            resp.insert_header(header::ETAG, [b"W/", etag.as_bytes()].concat())?;
+        } else if !etag.as_bytes().starts_with(b"W/") {
+            resp.remove_header(&header::ETAG);
+        }
+    }
+
+    Ok(Some(compressor))
+}
+
+/// Cached responses that vary on `Accept-Encoding` are stored once per negotiated encoding
+pub fn cache_variance(
+    meta: &CacheMeta,
+    req: &RequestHeader,
+    config: &RouteCompression,
+) -> Option<HashBinary> {
+    if !varies_on_accept_encoding(meta.headers()) {
+        return None;
+    }
+
+    let encoding = negotiate(req.headers.get(header::ACCEPT_ENCODING), &config.algorithms)
+        .map_or("identity", CompressionAlgorithm::as_str);
+
+    let mut variance = VarianceBuilder::new();
+    variance.add_value("accept-encoding", encoding);
+    variance.finalize()
+}
+
+fn varies_on_accept_encoding(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|v| v == "*" || v.eq_ignore_ascii_case("accept-encoding"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn config() -> RouteCompression {
+        RouteCompression {
+            enabled: Some(true),
+            algorithms: vec![
+                CompressionAlgorithm::Zstd,
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip,
+            ],
+            level: None,
+            content_types: vec![Cow::Borrowed("text/*"), Cow::Borrowed("application/json")],
+            min_size: 100,
+        }
+    }
+
+    fn negotiate_str(accept_encoding: &str) -> Option<CompressionAlgorithm> {
+        negotiate(
+            Some(&HeaderValue::from_str(accept_encoding).unwrap()),
+            &config().algorithms,
+        )
+    }
+
+    fn response(content_type: &str, length: usize) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::CONTENT_TYPE, content_type)
+            .unwrap();
+        resp.insert_header(header::CONTENT_LENGTH, length).unwrap();
+        resp
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate_str("gzip, deflate, br"),
+            Some(CompressionAlgorithm::Brotli)
+        );
+        assert_eq!(
+            negotiate_str("gzip;q=1.0, br;q=0.5"),
+            Some(CompressionAlgorithm::Gzip)
+        );
+        assert_eq!(negotiate_str("*"), Some(CompressionAlgorithm::Zstd));
+        assert_eq!(
+            negotiate_str("zstd;q=0, *;q=0.1"),
+            Some(CompressionAlgorithm::Brotli)
+        );
+        assert_eq!(negotiate_str("identity, deflate"), None);
+        assert_eq!(negotiate(None, &config().algorithms), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let config = config();
+
+        assert!(is_compressible(
+            &req,
+            &response("text/html; charset=utf-8", 500),
+            &config
+        ));
+        assert!(is_compressible(
+            &req,
+            &response("application/json", 500),
+            &config
+        ));
+        assert!(!is_compressible(&req, &response("image/png", 500), &config));
+        assert!(!is_compressible(&req, &response("text/html", 10), &config));
+
+        let mut encoded = response("text/html", 500);
+        encoded
+            .insert_header(header::CONTENT_ENCODING, "gzip")
+            .unwrap();
+        assert!(!is_compressible(&req, &encoded, &config));
+
+        let mut no_transform = response("text/html", 500);
+        no_transform
+            .insert_header(header::CACHE_CONTROL, "public, no-transform")
+            .unwrap();
+        assert!(!is_compressible(&req, &no_transform, &config));
+
+        let head = RequestHeader::build("HEAD", b"/", None).unwrap();
+        assert!(!is_compressible(
+            &head,
+            &response("text/html", 500),
+            &config
+        ));
+    }
+
+    #[test]
+    fn test_compress_response() {
+        let mut resp = response("text/html", 500);
+        resp.insert_header(header::ETAG, "\"abc\"").unwrap();
+        add_vary_header(&mut resp).unwrap();
+        add_vary_header(&mut resp).unwrap();
+
+        let mut compressor = compress_response(&mut resp, CompressionAlgorithm::Gzip, None)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(resp.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers[header::ETAG], "W/\"abc\"");
+        assert_eq!(resp.headers.get_all(header::VARY).iter().count(), 1);
+        assert!(resp.headers.get(header::CONTENT_LENGTH).is_none());
+
+        let body = "hello world ".repeat(100);
+        let compressed = compressor.encode(body.as_bytes(), true).unwrap();
+        assert!(compressed.len() < body.len());
+        assert_eq!(&compressed[..2], &[0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn test_cache_variance() {
+        let config = config();
+        let mut resp = response("text/html", 500);
+        let meta = |resp: &ResponseHeader| {
+            let now = std::time::SystemTime::now();
+            CacheMeta::new(now, now, 0, 0, resp.clone())
+        };
+
+        let mut gzip = RequestHeader::build("GET", b"/", None).unwrap();
+        gzip.insert_header(header::ACCEPT_ENCODING, "gzip").unwrap();
+        let mut brotli = RequestHeader::build("GET", b"/", None).unwrap();
+        brotli.insert_header(header::ACCEPT_ENCODING, "br").unwrap();
+
+        assert!(cache_variance(&meta(&resp), &gzip, &config).is_none());
+
+        add_vary_header(&mut resp).unwrap();
+        let gzip_variance = cache_variance(&meta(&resp), &gzip, &config);
+        assert!(gzip_variance.is_some());
+        assert_ne!(
+            gzip_variance,
+            cache_variance(&meta(&resp), &brotli, &config)
+        );
+    }
+}
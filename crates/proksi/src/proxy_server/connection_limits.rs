@@ -0,0 +1,267 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use pingora::{protocols::SocketDigest, proxy::Session};
+
+use crate::{config::ConnectionLimits, metrics};
+
+use super::client_ip::get_peer_ip;
+
+/// Clients above which the clients without open connections are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Most rejected connections held at once by the tarpit, the next ones are closed right away
+const MAX_TARPITTED: usize = 1_000;
+
+/// Connections of each client IP, shared by the listeners
+static CLIENTS: Lazy<papaya::HashMap<IpAddr, Mutex<ClientConnections<SocketDigest>>>> =
+    Lazy::new(papaya::HashMap::new);
+
+/// Rejected connections held by the tarpit
+static TARPITTED: AtomicUsize = AtomicUsize::new(0);
+
+/// A connection held by the tarpit, until it's dropped
+struct Tarpitted;
+
+impl Tarpitted {
+    fn new() -> Self {
+        TARPITTED.fetch_add(1, Ordering::AcqRel);
+        Tarpitted
+    }
+}
+
+impl Drop for Tarpitted {
+    fn drop(&mut self) {
+        TARPITTED.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Admission {
+    /// The connection was already admitted by a previous request
+    Known,
+    Admitted,
+    Rejected(Limit),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Limit {
+    Concurrent,
+    Rate,
+}
+
+impl Limit {
+    fn reason(self) -> &'static str {
+        match self {
+            Limit::Concurrent => "concurrent",
+            Limit::Rate => "rate",
+        }
+    }
+}
+
+/// The connections of a client IP. A connection is identified by its socket, which
+/// pingora drops once the connection is closed.
+struct ClientConnections<T> {
+    open: Vec<Weak<T>>,
+    /// Start of the current rate window, and the connections opened since
+    window_start: Instant,
+    opened: u32,
+}
+
+impl<T> ClientConnections<T> {
+    fn new(now: Instant) -> Self {
+        Self {
+            open: vec![],
+            window_start: now,
+            opened: 0,
+        }
+    }
+
+    fn admit(&mut self, connection: &Arc<T>, limits: &ConnectionLimits, now: Instant) -> Admission {
+        self.open.retain(|open| open.strong_count() > 0);
+        if self
+            .open
+            .iter()
+            .any(|open| std::ptr::eq(open.as_ptr(), Arc::as_ptr(connection)))
+        {
+            return Admission::Known;
+        }
+
+        if now.duration_since(self.window_start) >= Duration::from_secs(limits.rate_window_secs) {
+            self.window_start = now;
+            self.opened = 0;
+        }
+        self.opened = self.opened.saturating_add(1);
+
+        if limits
+            .max_new_connections
+            .is_some_and(|max| self.opened > max)
+        {
+            return Admission::Rejected(Limit::Rate);
+        }
+        if limits
+            .max_connections
+            .is_some_and(|max| self.open.len() >= max)
+        {
+            return Admission::Rejected(Limit::Concurrent);
+        }
+
+        self.open.push(Arc::downgrade(connection));
+        Admission::Admitted
+    }
+
+    /// Whether the client has open connections, or connections counted by the current window
+    fn is_active(&self, limits: &ConnectionLimits, now: Instant) -> bool {
+        self.open.iter().any(|open| open.strong_count() > 0)
+            || now.duration_since(self.window_start) < Duration::from_secs(limits.rate_window_secs)
+    }
+}
+
+/// Registers the connection of the session at its first request, and rejects it when its
+/// client IP has too many connections open or opened too many recently. Returns whether
+/// the request was rejected (it was answered and its connection is closed).
+pub async fn reject_over_limit(
+    session: &mut Session,
+    limits: &ConnectionLimits,
+    listener: &'static str,
+) -> pingora::Result<bool> {
+    if limits.max_connections.is_none() && limits.max_new_connections.is_none() {
+        return Ok(false);
+    }
+
+    let Some(peer_ip) = get_peer_ip(session) else {
+        return Ok(false);
+    };
+    if limits.exempt.iter().any(|net| net.contains(&peer_ip)) {
+        return Ok(false);
+    }
+    let Some(connection) = session
+        .digest()
+        .and_then(|digest| digest.socket_digest.clone())
+    else {
+        return Ok(false);
+    };
+
+    let now = Instant::now();
+    // The guard of the map can't be held across the tarpit
+    let admission = {
+        let mut clients = CLIENTS.pin();
+        if clients.len() >= MAX_TRACKED_CLIENTS && clients.get(&peer_ip).is_none() {
+            clients.retain(|_, client| {
+                client
+                    .lock()
+                    .is_ok_and(|client| client.is_active(limits, now))
+            });
+        }
+        clients
+            .get_or_insert_with(peer_ip, || Mutex::new(ClientConnections::new(now)))
+            .lock()
+            .map_or(Admission::Known, |mut client| {
+                client.admit(&connection, limits, now)
+            })
+    };
+
+    let Admission::Rejected(limit) = admission else {
+        return Ok(false);
+    };
+
+    metrics::CONNECTION_LIMIT_REJECTIONS
+        .with_label_values(&[listener, limit.reason()])
+        .inc();
+    tracing::debug!(
+        "rejected a connection of {peer_ip} on the {listener} listener: {}",
+        limit.reason()
+    );
+
+    if limits.tarpit_secs > 0 {
+        let _tarpitted = Tarpitted::new();
+        if TARPITTED.load(Ordering::Acquire) <= MAX_TARPITTED {
+            tokio::time::sleep(Duration::from_secs(limits.tarpit_secs)).await;
+        }
+    }
+
+    session.set_keepalive(None);
+    session.respond_error(429).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(
+        max_connections: Option<usize>,
+        max_new_connections: Option<u32>,
+    ) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections,
+            max_new_connections,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_concurrent_connections() {
+        let limits = limits(Some(2), None);
+        let now = Instant::now();
+        let mut client = ClientConnections::new(now);
+
+        let first = Arc::new(1);
+        let second = Arc::new(2);
+        assert_eq!(client.admit(&first, &limits, now), Admission::Admitted);
+        assert_eq!(client.admit(&second, &limits, now), Admission::Admitted);
+        // The next requests of an admitted connection
+        assert_eq!(client.admit(&first, &limits, now), Admission::Known);
+
+        let third = Arc::new(3);
+        assert_eq!(
+            client.admit(&third, &limits, now),
+            Admission::Rejected(Limit::Concurrent)
+        );
+
+        // A closed connection frees its slot
+        drop(first);
+        assert_eq!(client.admit(&third, &limits, now), Admission::Admitted);
+    }
+
+    #[test]
+    fn test_connection_rate() {
+        let limits = ConnectionLimits {
+            rate_window_secs: 10,
+            ..limits(None, Some(2))
+        };
+        let now = Instant::now();
+        let mut client = ClientConnections::new(now);
+
+        let connections = (0..3).map(Arc::new).collect::<Vec<_>>();
+        assert_eq!(
+            client.admit(&connections[0], &limits, now),
+            Admission::Admitted
+        );
+        assert_eq!(
+            client.admit(&connections[1], &limits, now),
+            Admission::Admitted
+        );
+        assert_eq!(
+            client.admit(&connections[2], &limits, now),
+            Admission::Rejected(Limit::Rate)
+        );
+        assert!(client.is_active(&limits, now));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            client.admit(&connections[2], &limits, later),
+            Admission::Admitted
+        );
+
+        drop(connections);
+        assert!(!client.is_active(&limits, later + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    ffi::c_int,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Instant, SystemTime},
+};
+
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::Lazy;
+use openssl::{
+    ex_data::Index,
+    ssl::{Ssl, SslContextBuilder, SslRef},
+};
+use pingora::{
+    protocols::{Digest, SocketDigest},
+    proxy::Session,
+};
+
+use crate::metrics;
+
+/// `where` of the info callback once a handshake is done (`SSL_CB_HANDSHAKE_DONE`)
+const SSL_CB_HANDSHAKE_DONE: c_int = 0x20;
+
+extern "C" {
+    fn SSL_CTX_set_info_callback(
+        ctx: *mut openssl_sys::SSL_CTX,
+        callback: Option<unsafe extern "C" fn(*const openssl_sys::SSL, c_int, c_int)>,
+    );
+}
+
+/// Start of the handshake of a TLS connection, stored along with the connection
+static HANDSHAKE_INDEX: Lazy<Index<Ssl, Handshake>> =
+    Lazy::new(|| Ssl::new_ex_index().expect("failed to allocate the TLS handshake index"));
+
+/// Connections of the HTTPS and HTTP services, by the address of their socket
+static CONNECTIONS: Lazy<papaya::HashMap<usize, Connection<SocketDigest>>> =
+    Lazy::new(papaya::HashMap::new);
+
+struct Handshake {
+    started_at: Instant,
+    /// The handshake is recorded once, TLS 1.3 connections are "done" again after
+    /// post-handshake messages
+    recorded: AtomicBool,
+}
+
+/// A connection, identified by its socket which pingora drops once the connection is closed.
+/// The socket is only weakly referenced, so that its address is not reused by another
+/// connection while it's tracked.
+struct Connection<T> {
+    socket: Weak<T>,
+    accepted_at: SystemTime,
+    /// `https` or `http`
+    listener: &'static str,
+    /// `h2` or `http/1.1`
+    protocol: &'static str,
+    requests: AtomicU64,
+}
+
+impl<T> Connection<T> {
+    fn new(
+        socket: &Arc<T>,
+        accepted_at: SystemTime,
+        listener: &'static str,
+        protocol: &'static str,
+    ) -> Self {
+        Self {
+            socket: Arc::downgrade(socket),
+            accepted_at,
+            listener,
+            protocol,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.socket.strong_count() == 0
+    }
+
+    fn record_closed(&self, now: SystemTime) {
+        let lifetime = now
+            .duration_since(self.accepted_at)
+            .unwrap_or_default()
+            .as_secs_f64();
+        metrics::CONNECTION_DURATION_SECONDS
+            .with_label_values(&[self.listener, self.protocol])
+            .observe(lifetime);
+        metrics::CONNECTION_REQUESTS
+            .with_label_values(&[self.listener, self.protocol])
+            .observe(self.requests.load(Ordering::Relaxed) as f64);
+    }
+}
+
+/// Time between the TCP accept and the end of the TLS handshake, from the timings of the
+/// layers of the connection (TCP first, then TLS)
+fn accept_to_tls(digest: &Digest) -> Option<f64> {
+    let mut layers = digest.timing_digest.iter().flatten();
+    let accepted = layers.next()?.established_ts;
+    let established = layers.next()?.established_ts;
+    Some(
+        established
+            .duration_since(accepted)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    )
+}
+
+/// Counts the request of the session in its connection. The first request of a connection
+/// records the time it took to be established.
+pub fn record_request(session: &Session, listener: &'static str) {
+    let Some(digest) = session.digest() else {
+        return;
+    };
+    let Some(socket) = digest.socket_digest.as_ref() else {
+        return;
+    };
+    let protocol = if session.is_http2() { "h2" } else { "http/1.1" };
+
+    let connections = CONNECTIONS.pin();
+    let connection = connections.get_or_insert_with(Arc::as_ptr(socket) as usize, || {
+        let accepted_at = digest
+            .timing_digest
+            .iter()
+            .flatten()
+            .next()
+            .map_or_else(SystemTime::now, |timing| timing.established_ts);
+        Connection::new(socket, accepted_at, listener, protocol)
+    });
+
+    if connection.requests.fetch_add(1, Ordering::Relaxed) == 0 {
+        if let Some(seconds) = accept_to_tls(digest) {
+            metrics::CONNECTION_ACCEPT_TO_TLS_SECONDS
+                .with_label_values(&[protocol])
+                .observe(seconds);
+        }
+    }
+}
+
+/// Records the lifetime and the requests of the connections closed since the last call,
+/// their lifetime includes the time until then
+pub fn record_closed() {
+    let now = SystemTime::now();
+    CONNECTIONS.pin().retain(|_, connection| {
+        if connection.is_closed() {
+            connection.record_closed(now);
+            return false;
+        }
+        true
+    });
+}
+
+/// Starts timing the handshake of the connection, when its ClientHello is received
+pub fn handshake_started(ssl: &mut SslRef) {
+    if ssl.ex_data(*HANDSHAKE_INDEX).is_none() {
+        ssl.set_ex_data(
+            *HANDSHAKE_INDEX,
+            Handshake {
+                started_at: Instant::now(),
+                recorded: AtomicBool::new(false),
+            },
+        );
+    }
+}
+
+/// The protocol negotiated with ALPN, `none` when the client sent none
+fn alpn_protocol(protocol: Option<&[u8]>) -> &'static str {
+    match protocol {
+        Some(b"h2") => "h2",
+        Some(b"http/1.1") => "http/1.1",
+        Some(_) => "other",
+        None => "none",
+    }
+}
+
+unsafe extern "C" fn info_callback(ssl: *const openssl_sys::SSL, event: c_int, _: c_int) {
+    if event & SSL_CB_HANDSHAKE_DONE == 0 {
+        return;
+    }
+
+    // SAFETY: the connection outlives the callbacks of its handshake, which only read it
+    let ssl = unsafe { SslRef::from_ptr(ssl.cast_mut()) };
+    let Some(handshake) = ssl.ex_data(*HANDSHAKE_INDEX) else {
+        return;
+    };
+    if handshake.recorded.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let version = ssl.version_str();
+    let resumed = if ssl.session_reused() {
+        "true"
+    } else {
+        "false"
+    };
+    metrics::TLS_HANDSHAKE_SECONDS
+        .with_label_values(&[version, resumed])
+        .observe(handshake.started_at.elapsed().as_secs_f64());
+    metrics::TLS_HANDSHAKES
+        .with_label_values(&[
+            version,
+            alpn_protocol(ssl.selected_alpn_protocol()),
+            resumed,
+        ])
+        .inc();
+}
+
+/// Records the duration and the ALPN protocol of the TLS handshakes of the context
+pub fn set_info_callback(context: &mut SslContextBuilder) {
+    // SAFETY: the context is valid, the callback is set before it's used
+    unsafe { SSL_CTX_set_info_callback(context.as_ptr(), Some(info_callback)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_alpn_protocol() {
+        assert_eq!(alpn_protocol(Some(b"h2")), "h2");
+        assert_eq!(alpn_protocol(Some(b"http/1.1")), "http/1.1");
+        assert_eq!(alpn_protocol(Some(b"spdy/3")), "other");
+        assert_eq!(alpn_protocol(None), "none");
+    }
+
+    #[test]
+    fn test_accept_to_tls() {
+        let accepted = SystemTime::UNIX_EPOCH + Duration::from_secs(10);
+        let mut digest = Digest {
+            timing_digest: vec![Some(pingora::protocols::TimingDigest {
+                established_ts: accepted,
+            })],
+            ..Default::default()
+        };
+        assert_eq!(accept_to_tls(&digest), None, "not a TLS connection");
+
+        digest
+            .timing_digest
+            .push(Some(pingora::protocols::TimingDigest {
+                established_ts: accepted + Duration::from_millis(250),
+            }));
+        assert_eq!(accept_to_tls(&digest), Some(0.25));
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        let socket = Arc::new(1);
+        let connection = Connection::new(&socket, SystemTime::now(), "https", "h2");
+        connection.requests.fetch_add(3, Ordering::Relaxed);
+        assert!(!connection.is_closed());
+
+        drop(socket);
+        assert!(connection.is_closed());
+        connection.record_closed(SystemTime::now());
+        assert_eq!(
+            metrics::CONNECTION_REQUESTS
+                .with_label_values(&["https", "h2"])
+                .get_sample_count(),
+            1
+        );
+    }
+}
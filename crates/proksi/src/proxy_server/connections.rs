@@ -0,0 +1,224 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use pingora::proxy::Session;
+use serde_json::{json, Value};
+
+/// Requests being proxied, by id (see the `/connections` endpoints of the admin API)
+static CONNECTIONS: Lazy<papaya::HashMap<u64, Arc<ActiveConnection>>> =
+    Lazy::new(papaya::HashMap::new);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An in-flight request, with its downstream and upstream connections
+pub struct ActiveConnection {
+    pub id: u64,
+    started_at: Instant,
+    started_at_unix: u64,
+    method: String,
+    host: String,
+    path: String,
+    client_addr: String,
+    http_version: &'static str,
+    /// `http`, `websocket`, `grpc` or `streaming`
+    kind: &'static str,
+    upstream: Mutex<Option<(String, bool)>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    closing: AtomicBool,
+}
+
+impl ActiveConnection {
+    /// Upstream the request was sent to, and whether its connection was reused
+    pub fn set_upstream(&self, address: String, reused: bool) {
+        if let Ok(mut upstream) = self.upstream.lock() {
+            *upstream = Some((address, reused));
+        }
+    }
+
+    /// Bytes of the request body received from the client
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes of the response body sent to the client
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Whether the connection was asked to close, through the admin API
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    fn to_json(&self) -> Value {
+        let upstream = self.upstream.lock().ok().and_then(|u| u.clone());
+
+        json!({
+            "id": self.id,
+            "started_at": self.started_at_unix,
+            "age_secs": self.started_at.elapsed().as_secs(),
+            "kind": self.kind,
+            "method": self.method,
+            "host": self.host,
+            "path": self.path,
+            "downstream": {
+                "address": self.client_addr,
+                "http_version": self.http_version,
+                "bytes_received": self.bytes_received.load(Ordering::Relaxed),
+                "bytes_sent": self.bytes_sent.load(Ordering::Relaxed),
+            },
+            "upstream": upstream.map(|(address, reused)| json!({
+                "address": address,
+                "reused": reused,
+            })),
+            "closing": self.is_closing(),
+        })
+    }
+}
+
+/// Keeps the request in the list of connections until it's dropped with the request context
+pub struct ConnectionGuard(Arc<ActiveConnection>);
+
+impl ConnectionGuard {
+    pub fn register(session: &Session, host: &str, kind: &'static str) -> Self {
+        let request = session.req_header();
+        let client_addr = session
+            .client_addr()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let http_version = if session.is_http2() {
+            "http/2"
+        } else {
+            "http/1.1"
+        };
+
+        Self::new(
+            request.method.as_str(),
+            host,
+            request.uri.path(),
+            client_addr,
+            http_version,
+            kind,
+        )
+    }
+
+    fn new(
+        method: &str,
+        host: &str,
+        path: &str,
+        client_addr: String,
+        http_version: &'static str,
+        kind: &'static str,
+    ) -> Self {
+        let connection = Arc::new(ActiveConnection {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            started_at: Instant::now(),
+            started_at_unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            method: method.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            client_addr,
+            http_version,
+            kind,
+            upstream: Mutex::new(None),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+        });
+
+        CONNECTIONS.pin().insert(connection.id, connection.clone());
+        Self(connection)
+    }
+}
+
+impl std::ops::Deref for ConnectionGuard {
+    type Target = ActiveConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.pin().remove(&self.0.id);
+    }
+}
+
+/// Number of in-flight requests
+pub fn count() -> usize {
+    CONNECTIONS.pin().len()
+}
+
+/// In-flight requests, the oldest first
+pub fn list() -> Vec<Value> {
+    let connections = CONNECTIONS.pin();
+    let mut connections = connections.values().collect::<Vec<_>>();
+    connections.sort_by_key(|connection| connection.id);
+
+    connections
+        .into_iter()
+        .map(|connection| connection.to_json())
+        .collect()
+}
+
+/// Asks the request to close, returns `false` when it's already finished
+pub fn close(id: u64) -> bool {
+    let connections = CONNECTIONS.pin();
+    let Some(connection) = connections.get(&id) else {
+        return false;
+    };
+
+    connection.closing.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Error aborting a request closed through the admin API
+pub fn closed_error() -> Box<pingora::Error> {
+    pingora::Error::explain(
+        pingora::ErrorType::ConnectionClosed,
+        "connection closed through the admin API",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let guard = ConnectionGuard::new(
+            "GET",
+            "connections.localhost",
+            "/events",
+            "127.0.0.1:50000".to_string(),
+            "http/1.1",
+            "streaming",
+        );
+        guard.set_upstream("127.0.0.1:3000".to_string(), false);
+        guard.add_sent(42);
+
+        let listed = list().into_iter().find(|c| c["id"] == guard.id).unwrap();
+        assert_eq!(listed["kind"], "streaming");
+        assert_eq!(listed["downstream"]["bytes_sent"], 42);
+        assert_eq!(listed["upstream"]["address"], "127.0.0.1:3000");
+
+        assert!(close(guard.id));
+        assert!(guard.is_closing());
+
+        let id = guard.id;
+        drop(guard);
+        assert!(list().iter().all(|c| c["id"] != id));
+        assert!(!close(id));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use bytes::Bytes;
+use http::{header, HeaderValue, StatusCode};
+use pingora::protocols::http::error_resp::gen_error_response;
+use pingora::proxy::Session;
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::RouteErrorPages;
+
+use super::https_proxy::RouterContext;
+
+/// Name of the template of the maintenance page
+const MAINTENANCE: &str = "maintenance";
+
+/// Templates of the error responses of a route, loaded with its configuration
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    /// Templates by name: the status (`502`), the class (`5xx`), `error` or `maintenance`
+    templates: HashMap<String, String>,
+    pub maintenance: bool,
+    pub retry_after: Option<u64>,
+}
+
+/// Values of the variables of the templates
+pub struct PageVariables<'a> {
+    pub status: StatusCode,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub request_id: Option<&'a str>,
+    pub timestamp: String,
+}
+
+impl ErrorPages {
+    /// Reads the `.html` templates of the directory, the other files are ignored
+    pub fn from_config(config: &RouteErrorPages) -> anyhow::Result<Self> {
+        let mut templates = HashMap::new();
+        if let Some(directory) = config.directory.as_deref() {
+            let entries = std::fs::read_dir(directory)
+                .with_context(|| format!("failed to read the directory {directory:?}"))?;
+            for entry in entries {
+                let path = entry?.path();
+                let Some(name) = template_name(&path) else {
+                    continue;
+                };
+                let template = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read the template {path:?}"))?;
+                templates.insert(name.to_string(), template);
+            }
+        }
+
+        Ok(ErrorPages {
+            templates,
+            maintenance: config.maintenance,
+            retry_after: config.retry_after,
+        })
+    }
+
+    /// The most specific template of the status (the maintenance page for the maintenance)
+    fn template(&self, status: StatusCode, maintenance: bool) -> Option<&str> {
+        let status = status.as_u16();
+        let names = if maintenance {
+            vec![MAINTENANCE.to_string()]
+        } else {
+            vec![
+                status.to_string(),
+                format!("{}xx", status / 100),
+                "error".to_string(),
+            ]
+        };
+        names
+            .iter()
+            .find_map(|name| self.templates.get(name))
+            .map(String::as_str)
+    }
+
+    /// The content type and the body of a response: JSON when the client prefers it to HTML,
+    /// the template otherwise (`None` without a template)
+    pub fn render(
+        &self,
+        variables: &PageVariables,
+        accept: Option<&HeaderValue>,
+        maintenance: bool,
+    ) -> Option<(&'static str, Bytes)> {
+        let reason = variables.status.canonical_reason().unwrap_or("");
+        if prefers_json(accept) {
+            let body = json!({
+                "status": variables.status.as_u16(),
+                "error": reason,
+                "maintenance": maintenance,
+                "host": variables.host,
+                "path": variables.path,
+                "request_id": variables.request_id,
+                "timestamp": variables.timestamp,
+            });
+            return Some(("application/json", Bytes::from(body.to_string())));
+        }
+
+        let template = self.template(variables.status, maintenance)?;
+        let body = template
+            .replace("{{status}}", variables.status.as_str())
+            .replace("{{reason}}", reason)
+            .replace("{{host}}", &escape(variables.host))
+            .replace("{{path}}", &escape(variables.path))
+            .replace(
+                "{{request_id}}",
+                &escape(variables.request_id.unwrap_or("")),
+            )
+            .replace("{{timestamp}}", &variables.timestamp);
+        Some(("text/html; charset=utf-8", Bytes::from(body)))
+    }
+}
+
+/// Name of a template from its file name (`502.html` is `502`), `None` for the other files
+fn template_name(path: &Path) -> Option<&str> {
+    if path.extension()? != "html" {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?;
+    let is_status = name.len() == 3
+        && name.as_bytes()[0].is_ascii_digit()
+        && (name[1..].bytes().all(|b| b.is_ascii_digit()) || &name[1..] == "xx");
+    (is_status || name == "error" || name == MAINTENANCE).then_some(name)
+}
+
+/// Whether the `Accept` header ranks JSON (`application/json` or `+json` types) above HTML.
+/// Browsers accept `text/html` first, API clients often send `*/*` and get HTML.
+fn prefers_json(accept: Option<&HeaderValue>) -> bool {
+    let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    let (mut json, mut html) = (0.0_f32, 0.0_f32);
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            json = json.max(quality);
+        } else if media_type == "text/html" || media_type == "*/*" || media_type == "text/*" {
+            html = html.max(quality);
+        }
+    }
+    json > html
+}
+
+/// Escapes a value inserted in an HTML page
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Sends the error response of the status, with the page of the route when it has one
+pub async fn respond(
+    session: &mut Session,
+    ctx: &RouterContext,
+    status: u16,
+) -> pingora::Result<()> {
+    respond_page(session, ctx, status, false, None).await
+}
+
+/// Sends the `503` of the maintenance of the route, with its `Retry-After`
+pub async fn respond_maintenance(
+    session: &mut Session,
+    ctx: &RouterContext,
+) -> pingora::Result<()> {
+    respond_page(session, ctx, 503, true, None).await
+}
+
+/// Sends the `405` of a method the route doesn't allow, with the methods it allows
+pub async fn respond_method_not_allowed(
+    session: &mut Session,
+    ctx: &RouterContext,
+    allow: &HeaderValue,
+) -> pingora::Result<()> {
+    respond_page(session, ctx, 405, false, Some(allow)).await
+}
+
+async fn respond_page(
+    session: &mut Session,
+    ctx: &RouterContext,
+    status: u16,
+    maintenance: bool,
+    allow: Option<&HeaderValue>,
+) -> pingora::Result<()> {
+    let mut resp = gen_error_response(status);
+    if let Some(allow) = allow {
+        resp.insert_header(header::ALLOW, allow.clone())?;
+    }
+    let Some(pages) = ctx.error_pages.as_deref() else {
+        return session.write_error_response(resp, Bytes::new()).await;
+    };
+
+    if let Some(retry_after) = pages.retry_after.filter(|_| maintenance) {
+        resp.insert_header(header::RETRY_AFTER, retry_after)?;
+    }
+
+    let variables = PageVariables {
+        status: resp.status,
+        host: &ctx.host,
+        path: session.req_header().uri.path(),
+        request_id: ctx.extensions.get("request_id_header").map(String::as_str),
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+    };
+    let accept = session.req_header().headers.get(header::ACCEPT);
+    let body = match pages.render(&variables, accept, maintenance) {
+        Some((content_type, body)) => {
+            resp.insert_header(header::CONTENT_TYPE, content_type)?;
+            resp.set_content_length(body.len())?;
+            body
+        }
+        None => Bytes::new(),
+    };
+
+    // A `HEAD` response has the headers of the page, without its body
+    let body = if session.req_header().method == http::Method::HEAD {
+        Bytes::new()
+    } else {
+        body
+    };
+    session.write_error_response(resp, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages(templates: &[(&str, &str)]) -> ErrorPages {
+        ErrorPages {
+            templates: templates
+                .iter()
+                .map(|(name, template)| (name.to_string(), template.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn variables(status: u16) -> PageVariables<'static> {
+        PageVariables {
+            status: StatusCode::from_u16(status).unwrap(),
+            host: "example.com",
+            path: "/<script>",
+            request_id: Some("abc"),
+            timestamp: "2025-01-01T00:00:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_template_fallbacks() {
+        let pages = pages(&[
+            ("502", "bad gateway"),
+            ("5xx", "server error"),
+            ("error", "error"),
+            ("maintenance", "maintenance"),
+        ]);
+
+        let status = |code| StatusCode::from_u16(code).unwrap();
+        assert_eq!(pages.template(status(502), false), Some("bad gateway"));
+        assert_eq!(pages.template(status(504), false), Some("server error"));
+        assert_eq!(pages.template(status(404), false), Some("error"));
+        assert_eq!(pages.template(status(503), true), Some("maintenance"));
+        assert_eq!(self::pages(&[]).template(status(502), false), None);
+    }
+
+    #[test]
+    fn test_render_html() {
+        let pages = pages(&[(
+            "error",
+            "{{status}} {{reason}} {{host}}{{path}} {{request_id}} {{timestamp}}",
+        )]);
+
+        let (content_type, body) = pages.render(&variables(404), None, false).unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert_eq!(
+            body,
+            "404 Not Found example.com/&lt;script&gt; abc 2025-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let accept = HeaderValue::from_static("application/json");
+        let (content_type, body) = pages(&[])
+            .render(&variables(503), Some(&accept), true)
+            .unwrap();
+        assert_eq!(content_type, "application/json");
+
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["error"], "Service Unavailable");
+        assert_eq!(body["maintenance"], true);
+        assert_eq!(body["request_id"], "abc");
+
+        assert!(pages(&[]).render(&variables(503), None, true).is_none());
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        let prefers = |accept: &str| prefers_json(Some(&HeaderValue::from_str(accept).unwrap()));
+
+        assert!(prefers("application/json"));
+        assert!(prefers("application/problem+json, */*;q=0.8"));
+        assert!(prefers("text/html;q=0.5, application/json"));
+        assert!(!prefers("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers("application/json;q=0.5, text/html"));
+        assert!(!prefers_json(None));
+    }
+
+    #[test]
+    fn test_template_name() {
+        assert_eq!(template_name(Path::new("/pages/502.html")), Some("502"));
+        assert_eq!(template_name(Path::new("/pages/5xx.html")), Some("5xx"));
+        assert_eq!(template_name(Path::new("error.html")), Some("error"));
+        assert_eq!(
+            template_name(Path::new("maintenance.html")),
+            Some("maintenance")
+        );
+        assert_eq!(template_name(Path::new("502.txt")), None);
+        assert_eq!(template_name(Path::new("style.html")), None);
+        assert_eq!(template_name(Path::new("50x2.html")), None);
+    }
+}
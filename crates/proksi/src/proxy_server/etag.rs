@@ -0,0 +1,93 @@
+use std::{
+    fmt::Write,
+    fs::Metadata,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::header;
+use pingora::http::ResponseHeader;
+
+/// Strong entity tag of a file, from its modification time and its length
+pub fn file_etag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "\"{:x}-{:x}\"",
+        modified.as_nanos(),
+        metadata.len()
+    ))
+}
+
+/// Adds a weak entity tag to a response without one, so that clients can revalidate it with
+/// `If-None-Match` and get a `304` from the cache. The tag is derived from the `Last-Modified`
+/// of the response, or from the time it was received: it changes whenever the body may have.
+pub fn add_weak_etag(resp: &mut ResponseHeader) -> pingora::Result<()> {
+    if resp.headers.contains_key(header::ETAG) {
+        return Ok(());
+    }
+
+    let mut validator = match resp.headers.get(header::LAST_MODIFIED) {
+        Some(last_modified) => last_modified.as_bytes().to_vec(),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+            .into_bytes(),
+    };
+    // Each encoding of the response is a different representation
+    for name in [header::CONTENT_LENGTH, header::CONTENT_ENCODING] {
+        validator.push(b'\n');
+        validator.extend_from_slice(resp.headers.get(name).map_or(&[][..], |v| v.as_bytes()));
+    }
+
+    let digest = openssl::sha::sha256(&validator);
+    let tag = digest[..8].iter().fold(String::new(), |mut tag, b| {
+        let _ = write!(tag, "{b:02x}");
+        tag
+    });
+    resp.insert_header(header::ETAG, format!("W/\"{tag}\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&'static str, &'static str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.insert_header(*name, *value).unwrap();
+        }
+        resp
+    }
+
+    fn etag(resp: &ResponseHeader) -> &str {
+        resp.headers.get(header::ETAG).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn test_add_weak_etag() {
+        let mut resp = response(&[("etag", "\"origin\"")]);
+        add_weak_etag(&mut resp).unwrap();
+        assert_eq!(etag(&resp), "\"origin\"");
+
+        let last_modified = ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT");
+        let (mut a, mut b) = (response(&[last_modified]), response(&[last_modified]));
+        add_weak_etag(&mut a).unwrap();
+        add_weak_etag(&mut b).unwrap();
+        assert!(etag(&a).starts_with("W/\"") && etag(&a).ends_with('"'));
+        assert_eq!(etag(&a), etag(&b));
+
+        let mut gzip = response(&[last_modified, ("content-encoding", "gzip")]);
+        add_weak_etag(&mut gzip).unwrap();
+        assert_ne!(etag(&a), etag(&gzip));
+    }
+
+    #[test]
+    fn test_file_etag() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&path, "file").unwrap();
+        let etag = file_etag(&std::fs::metadata(&path).unwrap()).unwrap();
+        assert!(etag.starts_with('"') && etag.ends_with("-4\""));
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use http::{header, HeaderMap};
+use pingora::http::RequestHeader;
+
+use crate::config::RouteGrpc;
+
+/// `application/grpc` requests, with or without a subtype (e.g. `application/grpc+proto`).
+/// gRPC-Web (`application/grpc-web`) works over HTTP/1.1 and is proxied like any other request.
+pub fn is_grpc_request(req: &RequestHeader) -> bool {
+    let Some(content_type) = req
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let Some(rest) = mime
+        .get(..16)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("application/grpc"))
+        .map(|_| &mime[16..])
+    else {
+        return false;
+    };
+
+    rest.is_empty() || rest.starts_with('+')
+}
+
+/// A gRPC call, kept in the request context to log its status
+pub struct GrpcCall {
+    pub timeout: Option<Duration>,
+    pub max_streams: Option<usize>,
+    /// `grpc-status` of the call, from the trailers or the headers of
+    /// a trailers-only response
+    pub status: Option<String>,
+    pub message: Option<String>,
+}
+
+impl GrpcCall {
+    pub fn new(config: Option<&RouteGrpc>) -> Self {
+        Self {
+            timeout: config.and_then(|g| g.timeout).map(Duration::from_secs),
+            max_streams: config.and_then(|g| g.max_streams),
+            status: None,
+            message: None,
+        }
+    }
+
+    /// Called with the response headers and the trailers, the last `grpc-status` wins
+    pub fn capture_status(&mut self, headers: &HeaderMap) {
+        let value = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(ToString::to_string)
+        };
+
+        if let Some(status) = value("grpc-status") {
+            self.status = Some(status);
+            self.message = value("grpc-message");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_grpc_request() {
+        let request = |content_type: &str| {
+            let mut req =
+                RequestHeader::build("POST", b"/helloworld.Greeter/SayHello", None).unwrap();
+            req.insert_header(header::CONTENT_TYPE, content_type)
+                .unwrap();
+            req
+        };
+
+        assert!(!is_grpc_request(
+            &RequestHeader::build("POST", b"/", None).unwrap()
+        ));
+        assert!(is_grpc_request(&request("application/grpc")));
+        assert!(is_grpc_request(&request("Application/gRPC+proto")));
+        assert!(is_grpc_request(&request("application/grpc; charset=utf-8")));
+        assert!(!is_grpc_request(&request("application/grpc-web")));
+        assert!(!is_grpc_request(&request("application/json")));
+    }
+
+    #[test]
+    fn test_capture_status() {
+        let mut call = GrpcCall::new(None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/grpc".parse().unwrap());
+        call.capture_status(&headers);
+        assert_eq!(call.status, None);
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "5".parse().unwrap());
+        trailers.insert("grpc-message", "not found".parse().unwrap());
+        call.capture_status(&trailers);
+        assert_eq!(call.status.as_deref(), Some("5"));
+        assert_eq!(call.message.as_deref(), Some("not found"));
+    }
+}
@@ -0,0 +1,334 @@
+use std::borrow::Cow;
+
+use anyhow::{anyhow, bail, Result};
+use http::HeaderName;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::config::RouteHeaderRules;
+
+use super::https_proxy::RouterContext;
+
+/// Variables that can be used in the value of a header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variable {
+    /// Client IP (taking `server.trusted_proxies` into account)
+    RemoteAddr,
+    Host,
+    /// Address of the upstream the request is sent to
+    UpstreamAddr,
+    /// See the `request_id` plugin
+    RequestId,
+    RequestMethod,
+    /// Path and query of the request
+    RequestUri,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Result<Self> {
+        let variable = match name {
+            "remote_addr" => Variable::RemoteAddr,
+            "host" => Variable::Host,
+            "upstream_addr" => Variable::UpstreamAddr,
+            "request_id" => Variable::RequestId,
+            "request_method" => Variable::RequestMethod,
+            "request_uri" => Variable::RequestUri,
+            _ => bail!("unknown variable ${name}"),
+        };
+        Ok(variable)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// Header value with variables (ex: `$host` or `${host}`). `$$` is a literal `$`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl HeaderTemplate {
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = value;
+
+        while let Some(index) = rest.find('$') {
+            literal.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+
+            let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| anyhow!("unclosed variable in {value}"))?;
+                (&braced[..end], &braced[end + 1..])
+            } else {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            };
+
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(TemplatePart::Variable(Variable::parse(name)?));
+            rest = after;
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders the value, variables without a value (ex: no request id) are left empty
+    pub fn render(&self, values: &VariableValues) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.as_str(),
+                TemplatePart::Variable(variable) => values.get(*variable).unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+/// Values of the variables for the current request
+pub struct VariableValues<'a> {
+    pub remote_addr: Option<&'a str>,
+    pub host: &'a str,
+    pub upstream_addr: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    pub request_method: &'a str,
+    pub request_uri: Cow<'a, str>,
+}
+
+impl<'a> VariableValues<'a> {
+    pub fn new(req: &'a RequestHeader, ctx: &'a RouterContext) -> Self {
+        let request_uri = req
+            .uri
+            .path_and_query()
+            .map_or(Cow::Borrowed("/"), |v| Cow::Borrowed(v.as_str()));
+
+        Self {
+            remote_addr: ctx.extensions.get("client_ip").map(String::as_str),
+            host: &ctx.host,
+            upstream_addr: ctx.extensions.get("peer").map(String::as_str),
+            request_id: ctx.extensions.get("request_id_header").map(String::as_str),
+            request_method: req.method.as_str(),
+            request_uri,
+        }
+    }
+
+    fn get(&self, variable: Variable) -> Option<&str> {
+        match variable {
+            Variable::RemoteAddr => self.remote_addr,
+            Variable::Host => Some(self.host),
+            Variable::UpstreamAddr => self.upstream_addr,
+            Variable::RequestId => self.request_id,
+            Variable::RequestMethod => Some(self.request_method),
+            Variable::RequestUri => Some(&self.request_uri),
+        }
+    }
+}
+
+/// Header names with their rendered value
+type RenderedHeaders<'a> = Vec<(&'a HeaderName, String)>;
+
+/// Compiled header rules of a route, applied in order: remove, set, add
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderTemplate)>,
+    add: Vec<(HeaderName, HeaderTemplate)>,
+}
+
+impl HeaderRules {
+    pub fn from_config(config: &RouteHeaderRules) -> Result<Self> {
+        let templates = |headers: &[crate::config::RouteHeaderAdd]| {
+            headers
+                .iter()
+                .map(|header| {
+                    Ok((
+                        HeaderName::try_from(header.name.as_ref())?,
+                        HeaderTemplate::parse(&header.value)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            remove: config
+                .remove
+                .iter()
+                .map(|header| HeaderName::try_from(header.name.as_ref()))
+                .collect::<Result<Vec<_>, _>>()?,
+            set: templates(&config.set)?,
+            add: templates(&config.add)?,
+        })
+    }
+
+    /// Evaluates the rules, returning the headers to remove, set and add.
+    /// Headers whose value is empty once rendered are skipped.
+    fn evaluate<'a>(
+        &'a self,
+        values: &VariableValues,
+    ) -> (&'a [HeaderName], RenderedHeaders<'a>, RenderedHeaders<'a>) {
+        let render = |headers: &'a [(HeaderName, HeaderTemplate)]| {
+            headers
+                .iter()
+                .map(|(name, template)| (name, template.render(values)))
+                .filter(|(_, value)| !value.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        (&self.remove, render(&self.set), render(&self.add))
+    }
+
+    pub fn apply_to_request(
+        &self,
+        req: &mut RequestHeader,
+        values: &VariableValues,
+    ) -> pingora::Result<()> {
+        let (remove, set, add) = self.evaluate(values);
+
+        for name in remove {
+            req.remove_header(name);
+        }
+        for (name, value) in set {
+            req.insert_header(name.clone(), value)?;
+        }
+        for (name, value) in add {
+            req.append_header(name.clone(), value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_to_response(
+        &self,
+        resp: &mut ResponseHeader,
+        values: &VariableValues,
+    ) -> pingora::Result<()> {
+        let (remove, set, add) = self.evaluate(values);
+
+        for name in remove {
+            resp.remove_header(name);
+        }
+        for (name, value) in set {
+            resp.insert_header(name.clone(), value)?;
+        }
+        for (name, value) in add {
+            resp.append_header(name.clone(), value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{RouteHeaderAdd, RouteHeaderRemove};
+
+    use super::*;
+
+    fn values() -> VariableValues<'static> {
+        VariableValues {
+            remote_addr: Some("203.0.113.7"),
+            host: "example.com",
+            upstream_addr: Some("10.0.0.2:3000"),
+            request_id: None,
+            request_method: "GET",
+            request_uri: Cow::Borrowed("/items?page=2"),
+        }
+    }
+
+    fn header(name: &'static str, value: &'static str) -> RouteHeaderAdd {
+        RouteHeaderAdd {
+            name: Cow::Borrowed(name),
+            value: Cow::Borrowed(value),
+        }
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let template = HeaderTemplate::parse("$remote_addr via ${host}$$").unwrap();
+        assert_eq!(
+            template.parts,
+            [
+                TemplatePart::Variable(Variable::RemoteAddr),
+                TemplatePart::Literal(" via ".to_string()),
+                TemplatePart::Variable(Variable::Host),
+                TemplatePart::Literal("$".to_string()),
+            ]
+        );
+        assert_eq!(template.render(&values()), "203.0.113.7 via example.com$");
+
+        assert_eq!(
+            HeaderTemplate::parse("${request_method}:$request_uri")
+                .unwrap()
+                .render(&values()),
+            "GET:/items?page=2"
+        );
+
+        assert!(HeaderTemplate::parse("$unknown").is_err());
+        assert!(HeaderTemplate::parse("${host").is_err());
+    }
+
+    #[test]
+    fn test_apply_rules() {
+        let rules = HeaderRules::from_config(&RouteHeaderRules {
+            add: vec![
+                header("x-forwarded-for", "$remote_addr"),
+                header("x-request-id", "$request_id"),
+            ],
+            set: vec![header("x-upstream", "$upstream_addr")],
+            remove: vec![RouteHeaderRemove {
+                name: Cow::Borrowed("x-internal"),
+            }],
+        })
+        .unwrap();
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-forwarded-for", "198.51.100.1")
+            .unwrap();
+        req.insert_header("x-upstream", "old").unwrap();
+        req.insert_header("x-internal", "secret").unwrap();
+
+        rules.apply_to_request(&mut req, &values()).unwrap();
+
+        let forwarded_for = req
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(forwarded_for, ["198.51.100.1", "203.0.113.7"]);
+        assert_eq!(req.headers["x-upstream"], "10.0.0.2:3000");
+        assert!(req.headers.get("x-internal").is_none());
+        // Empty values are skipped
+        assert!(req.headers.get("x-request-id").is_none());
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        rules.apply_to_response(&mut resp, &values()).unwrap();
+        assert_eq!(resp.headers["x-upstream"], "10.0.0.2:3000");
+
+        assert!(HeaderRules::from_config(&RouteHeaderRules {
+            add: vec![header("invalid name", "value")],
+            ..RouteHeaderRules::default()
+        })
+        .is_err());
+    }
+}
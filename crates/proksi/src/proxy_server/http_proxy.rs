@@ -1,24 +1,60 @@
 use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
 use http::{
     header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
-    uri::Scheme,
-    StatusCode, Uri,
+    uri::Authority,
+    HeaderValue, StatusCode,
 };
+use once_cell::sync::Lazy;
 use pingora::http::ResponseHeader;
 use pingora::upstreams::peer::HttpPeer;
 
 use pingora::proxy::{ProxyHttp, Session};
 use tracing::info;
 
+use crate::config::{ConnectionLimits, StrictParsingRules};
+use crate::services::drain::InFlight;
 use crate::stores::global;
 
-pub struct HttpLB {}
+use super::{connection_limits, connection_metrics, strict_parsing};
+
+const TEXT_PLAIN: HeaderValue = HeaderValue::from_static("text/plain");
+const PONG: &[u8] = b"pong";
+
+/// Headers of the redirects to HTTPS, without their `Location`. They are built once, and
+/// cloned for each request.
+static REDIRECT_HEADERS: Lazy<ResponseHeader> = Lazy::new(|| {
+    let mut headers = ResponseHeader::build_no_case(StatusCode::PERMANENT_REDIRECT, Some(3))
+        .expect("valid redirect headers");
+    headers.append_header(CONTENT_TYPE, TEXT_PLAIN).ok();
+    headers.append_header(CONTENT_LENGTH, 0).ok();
+    headers
+});
+
+/// Headers of the responses to `/ping`
+static PONG_HEADERS: Lazy<ResponseHeader> = Lazy::new(|| {
+    let mut headers =
+        ResponseHeader::build_no_case(StatusCode::OK, Some(2)).expect("valid ping headers");
+    headers.append_header(CONTENT_TYPE, TEXT_PLAIN).ok();
+    headers.append_header(CONTENT_LENGTH, PONG.len()).ok();
+    headers
+});
+
+pub struct HttpLB {
+    /// Connections of each client IP
+    pub connection_limits: ConnectionLimits,
+    /// Checks of the requests against request smuggling
+    pub strict_parsing: StrictParsingRules,
+}
 
 #[async_trait]
 impl ProxyHttp for HttpLB {
-    type CTX = ();
+    /// The request is drained by the graceful shutdowns until its context is dropped
+    type CTX = InFlight;
 
-    fn new_ctx(&self) -> Self::CTX {}
+    fn new_ctx(&self) -> Self::CTX {
+        InFlight::new()
+    }
 
     /// Filters based on path (used by LetsEncrypt/ZeroSSL challenges)
     async fn request_filter(
@@ -26,6 +62,14 @@ impl ProxyHttp for HttpLB {
         session: &mut Session,
         _ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        connection_metrics::record_request(session, "http");
+        if connection_limits::reject_over_limit(session, &self.connection_limits, "http").await? {
+            return Ok(true);
+        }
+        if strict_parsing::reject_malformed(session, &self.strict_parsing, "http").await? {
+            return Ok(true);
+        }
+
         let req_header = session.req_header();
         let current_uri = &req_header.uri;
 
@@ -35,15 +79,12 @@ impl ProxyHttp for HttpLB {
         }
 
         if current_uri.path() == "/ping" {
-            let sample_body = bytes::Bytes::from("pong");
-            let mut res_headers = ResponseHeader::build_no_case(StatusCode::OK, None)?;
-            res_headers.append_header(CONTENT_TYPE, "text/plain")?;
-            res_headers.append_header(CONTENT_LENGTH, sample_body.len())?;
-
             session
-                .write_response_header(Box::new(res_headers), false)
+                .write_response_header(Box::new(PONG_HEADERS.clone()), false)
+                .await?;
+            session
+                .write_response_body(Some(bytes::Bytes::from_static(PONG)), true)
                 .await?;
-            session.write_response_body(Some(sample_body), true).await?;
             return Ok(true);
         }
 
@@ -52,27 +93,15 @@ impl ProxyHttp for HttpLB {
             .path()
             .starts_with("/.well-known/acme-challenge")
         {
-            let challenge_from_host = global::get_store().get_challenge(host).await;
-
-            if challenge_from_host.is_none() {
+            // Any instance sharing the store can answer, whichever started the order
+            let token = current_uri.path().rsplit('/').next().unwrap_or_default();
+            let Some(proof) = global::get_store().get_challenge_proof(host, token).await else {
                 return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
-            }
-
-            let challenge_from_host = challenge_from_host.unwrap();
-
-            // Get the token and proof from the challenge store
-            let (token, proof) = challenge_from_host;
-            // Get the token from the URL
-            let token_from_url = current_uri.path().split('/').last().unwrap();
+            };
 
-            // Token is not the same as the one provided
-            if token != token_from_url {
-                return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)));
-            }
-
-            let sample_body = bytes::Bytes::from(proof.clone());
+            let sample_body = bytes::Bytes::from(proof);
             let mut res_headers = ResponseHeader::build_no_case(StatusCode::OK, Some(2))?;
-            res_headers.append_header(CONTENT_TYPE, "text/plain")?;
+            res_headers.append_header(CONTENT_TYPE, TEXT_PLAIN)?;
             res_headers.append_header(CONTENT_LENGTH, sample_body.len())?;
 
             session
@@ -83,27 +112,19 @@ impl ProxyHttp for HttpLB {
             return Ok(true);
         }
 
-        // Redirect to https
-        let new_uri = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(host)
-            .path_and_query(current_uri.path_and_query().unwrap().to_owned())
-            .build()
-            .unwrap();
-
-        let mut res_headers =
-            ResponseHeader::build_no_case(StatusCode::PERMANENT_REDIRECT, Some(1))?;
+        // Redirect to https, the location is written once into a buffer of its size
+        let path_and_query = current_uri.path_and_query().map_or("/", |pq| pq.as_str());
+        let Some(location) = https_location(host, path_and_query) else {
+            return Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(400)));
+        };
 
-        info!("redirecting to https {new_uri:?}");
-        res_headers.append_header(LOCATION, new_uri.to_string())?;
-        res_headers.append_header(CONTENT_TYPE, "text/plain")?;
-        res_headers.append_header(CONTENT_LENGTH, 0)?;
+        info!("redirecting to https {location:?}");
+        let mut res_headers = REDIRECT_HEADERS.clone();
+        res_headers.insert_header(LOCATION, location)?;
 
+        // The redirect has no body, the response ends with its headers
         session
-            .write_response_header(Box::new(res_headers), false)
-            .await?;
-        session
-            .write_response_body(Some(bytes::Bytes::from_static(b"")), true)
+            .write_response_header(Box::new(res_headers), true)
             .await?;
 
         return Ok(true);
@@ -121,6 +142,33 @@ impl ProxyHttp for HttpLB {
     }
 }
 
+/// The `Location` of the redirect to HTTPS. The host must be an authority (`host[:port]`,
+/// without credentials), so that a `Host` header can't make the redirect point to
+/// another URL.
+fn https_location(host: &str, path_and_query: &str) -> Option<HeaderValue> {
+    let authority = Authority::try_from(host).ok()?;
+    // Only the host and an optional numeric port, without credentials
+    let valid = match authority.as_str().strip_prefix(authority.host()) {
+        Some(port) => {
+            port.is_empty()
+                || port
+                    .strip_prefix(':')
+                    .is_some_and(|p| p.parse::<u16>().is_ok())
+        }
+        None => false,
+    };
+    if !valid {
+        return None;
+    }
+
+    let mut location =
+        BytesMut::with_capacity("https://".len() + host.len() + path_and_query.len());
+    location.put_slice(b"https://");
+    location.put_slice(authority.as_str().as_bytes());
+    location.put_slice(path_and_query.as_bytes());
+    HeaderValue::from_maybe_shared(location.freeze()).ok()
+}
+
 /// Retrieves the host from the request headers based on
 /// whether the request is HTTP/1.1 or HTTP/2
 fn get_host(session: &Session) -> &str {
@@ -134,3 +182,39 @@ fn get_host(session: &Session) -> &str {
 
     ""
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("example.com", "/a?b=c").unwrap(),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            https_location("example.com:8080", "/").unwrap(),
+            "https://example.com:8080/"
+        );
+        assert_eq!(
+            https_location("[::1]:80", "/").unwrap(),
+            "https://[::1]:80/"
+        );
+
+        // Hosts that would redirect elsewhere, or that are not hosts at all
+        for host in [
+            "evil.com/x",
+            "example.com@evil.com",
+            "user:pass@example.com",
+            "example.com?evil.com",
+            "example.com#evil.com",
+            "example.com:port",
+            "exa mple.com",
+            "example.com\\evil.com",
+            "",
+        ] {
+            assert!(https_location(host, "/").is_none(), "{host}");
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use std::net::ToSocketAddrs;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use std::{borrow::Cow, collections::HashMap};
 
@@ -7,37 +9,98 @@ use async_trait::async_trait;
 
 use http::uri::PathAndQuery;
 use http::{HeaderName, HeaderValue, Uri};
+use ipnet::IpNet;
 use once_cell::sync::Lazy;
 
 use openssl::base64;
 use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::protocols::Digest;
-use pingora::proxy::{ProxyHttp, Session};
+use pingora::lb::Backend;
+use pingora::protocols::http::conditional_filter::not_modified_filter;
+use pingora::protocols::{Digest, ALPN};
+use pingora::proxy::{FailToProxy, ProxyHttp, Session};
 use pingora::upstreams::peer::Peer;
-use pingora::{upstreams::peer::HttpPeer, ErrorType::HTTPStatus};
+use pingora::{
+    upstreams::peer::HttpPeer,
+    ErrorSource,
+    ErrorType::{self, HTTPStatus},
+};
 
 use pingora_cache::lock::CacheLock;
 
+use pingora_cache::key::HashBinary;
 use pingora_cache::{CacheKey, CacheMeta, ForcedInvalidationKind, NoCacheReason, RespCacheable};
 
 use crate::cache::disk::storage::DiskCache;
-use crate::config::{RouteCacheType, RouteUpstream};
-use crate::stores::{self, routes::RouteStoreContainer};
-
-use super::default_peer_opts;
+use crate::config::{
+    ConnectionLimits, ForwardedHeaders, IpFilter, RouteCacheType, RouteUpstream, SlowClients,
+    StrictParsingRules, UpstreamPool,
+};
+use crate::metrics;
+use crate::plugins::esi::{self, EsiPage};
+use crate::plugins::ext_proc::ExtProcessor;
+use crate::plugins::openapi::OpenApiBodyValidator;
+use crate::plugins::request_decompression::RequestDecompressor;
+use crate::plugins::response_rewrite::BodyRewriter;
+use crate::plugins::waf::WafInspector;
+use crate::services::blue_green;
+use crate::services::log_export::{self, AccessLogEvent};
+use crate::stores::{self, health, routes::RouteStoreContainer};
+
+use super::body_logging::BodyLog;
+use super::canary::CanarySplit;
+use super::capture::CaptureRequest;
+use super::client_certificates;
+use super::client_ip::{get_client_ip, get_peer_ip, set_forwarded_headers};
+use super::compression::{self, Compressor};
+use super::connection_limits;
+use super::connection_metrics;
+use super::connections::{self, ConnectionGuard};
+use super::error_pages::{self, ErrorPages};
+use super::etag;
+use super::grpc::{self, GrpcCall};
+use super::header_rules::VariableValues;
+use super::limits::{self, BodyLimiter};
 use super::middleware::{
     execute_request_plugins, execute_response_plugins, execute_upstream_request_plugins,
     execute_upstream_response_plugins,
 };
+use super::mirror::MirrorRequest;
+use super::recent_errors;
+use super::slow_clients::{BodyTimer, SlowClientReason};
+use super::static_files;
+use super::sticky_sessions::StickySession;
+use super::streaming;
+use super::strict_parsing;
+use super::tap;
+use super::tls_fingerprint::get_fingerprint;
+use super::trace_context;
+use super::upstream_peer_opts;
+use super::websocket::{self, WebSocketTunnel};
 
 static STORAGE_MEM_CACHE: Lazy<pingora_cache::MemCache> = Lazy::new(pingora_cache::MemCache::new);
-static STORAGE_CACHE: Lazy<DiskCache> = Lazy::new(DiskCache::new);
+/// Shared with the admin API, which purges it
+pub static STORAGE_CACHE: Lazy<DiskCache> = Lazy::new(DiskCache::new);
 static CACHEABLE_METHODS: Lazy<Vec<http::Method>> =
     Lazy::new(|| vec![http::Method::GET, http::Method::HEAD]);
 static CACHE_LOCK: Lazy<CacheLock> = Lazy::new(|| CacheLock::new(Duration::from_secs(1)));
 
 /// Load balancer proxy struct
-pub struct Router {}
+pub struct Router {
+    /// Global IP allow/deny lists, applied before the route ones
+    pub ip_filter: IpFilter,
+    /// Proxies allowed to set the client IP through `X-Forwarded-For`
+    pub trusted_proxies: Vec<IpNet>,
+    /// `X-Forwarded-*` headers sent to the upstreams
+    pub forwarded_headers: ForwardedHeaders,
+    /// Timeouts and minimum transfer rate of the request bodies
+    pub slow_clients: SlowClients,
+    /// Connections of each client IP
+    pub connection_limits: ConnectionLimits,
+    /// Idle timeout of the connections to the upstreams
+    pub upstream_pool: UpstreamPool,
+    /// Checks of the requests against request smuggling
+    pub strict_parsing: StrictParsingRules,
+}
 
 // type Container = mapref::one::Ref<'static, String, RouteStoreContainer>;
 
@@ -52,17 +115,131 @@ fn get_cache_storage(cache_type: &RouteCacheType) -> &'static (dyn pingora_cache
     }
 }
 
+/// The key of the cached responses of the host for the path (and query), also used by the
+/// admin API to find them
+pub fn cache_key(host: &str, path_and_query: &str) -> CacheKey {
+    CacheKey::new(
+        host.to_string(),
+        base64::encode_block(path_and_query.as_bytes()),
+        "",
+    )
+}
+
+/// Requests without a client IP (e.g. unix sockets) are only allowed
+/// if the filter has no rules at all
+fn is_ip_allowed(ip_filter: &IpFilter, client_ip: Option<std::net::IpAddr>) -> bool {
+    match client_ip {
+        Some(ip) => ip_filter.is_allowed(&ip),
+        None => ip_filter.allow.is_empty() && ip_filter.deny.is_empty(),
+    }
+}
+
+/// Slow clients get their own log and metric, as they are likely attacks (e.g. Slowloris)
+/// Label of the `proksi_http_requests_total` metric
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "none",
+    }
+}
+
+fn log_slow_client(session: &Session, host: &str, reason: &SlowClientReason) {
+    tracing::warn!(
+        host,
+        client_ip = session
+            .client_addr()
+            .map(ToString::to_string)
+            .unwrap_or_default(),
+        reason = reason.as_str(),
+        "slow client disconnected"
+    );
+
+    metrics::SLOW_CLIENT_DISCONNECTIONS
+        .with_label_values(&[host, reason.as_str()])
+        .inc();
+}
+
 pub struct RouterContext {
     pub host: String,
     pub route_container: RouteStoreContainer,
     pub upstream: RouteUpstream,
     pub extensions: HashMap<Cow<'static, str>, String>,
 
+    /// Compressor of the response body, when compression was negotiated
+    pub compressor: Option<Compressor>,
+
+    /// Rewriter of the response body (see the `response_rewrite` and `html_inject` plugins)
+    pub body_rewriter: Option<BodyRewriter>,
+
+    /// Decompressor of the request body (see the `request_decompression` plugin)
+    pub request_decompressor: Option<RequestDecompressor>,
+
+    /// Inspector of the request body (see the `waf` plugin)
+    pub waf: Option<WafInspector>,
+
+    /// Validator of the request body (see the `openapi` plugin)
+    pub openapi: Option<OpenApiBodyValidator>,
+
+    /// Processing of the request body and response headers (see the `ext_proc` plugin)
+    pub ext_proc: Option<ExtProcessor>,
+    /// Page whose includes are replaced by their fragments (see the `esi` plugin)
+    pub esi: Option<EsiPage>,
+
+    /// Templates of the error responses of the route (see the route `error_pages`)
+    pub error_pages: Option<Arc<ErrorPages>>,
+
+    /// Size limits and buffering of the request body (see the route `limits`)
+    pub body_limiter: Option<BodyLimiter>,
+
+    /// Transfer rate of the request body (see `server.slow_clients`)
+    pub body_timer: Option<BodyTimer>,
+
+    /// Set for WebSocket upgrade requests (see the route `websocket`)
+    pub websocket: Option<WebSocketTunnel>,
+
+    /// Set for gRPC calls (see the route `grpc`)
+    pub grpc: Option<GrpcCall>,
+
+    /// Set for the requests of streaming routes (see the route `streaming`)
+    pub streaming: bool,
+
+    /// Set for the requests of routes with sticky sessions (see the route `sticky_sessions`)
+    pub sticky_session: Option<StickySession>,
+
+    /// Set for the requests of routes with a canary pool (see the route `canary`)
+    pub canary: Option<CanarySplit>,
+
+    /// Copy of the request sent to the shadow upstream (see the route `mirror`)
+    pub mirror: Option<MirrorRequest>,
+
+    /// Capture of the request, written to the file of the route (see the route `capture`)
+    pub capture: Option<CaptureRequest>,
+
+    /// Bodies of the request and its response, written to the logs (see the route
+    /// `body_logging`)
+    pub body_log: Option<BodyLog>,
+
+    /// Lists the request in the admin API while it's in flight
+    pub connection: Option<ConnectionGuard>,
+
+    /// Counts the request on its upstream until the response is complete
+    pub upstream_in_flight: Option<metrics::UpstreamInFlight>,
+
+    /// ID of the trace the request is part of (`traceparent` or B3 headers), attached to the
+    /// latency observations as an exemplar
+    pub trace_id: Option<String>,
+
     pub timings: RouterTimings,
 }
 
 pub struct RouterTimings {
     request_filter_start: std::time::Instant,
+    /// When the upstream was selected, a new connection to it is established from then on
+    upstream_selected: Option<std::time::Instant>,
 }
 
 #[async_trait]
@@ -77,9 +254,31 @@ impl ProxyHttp for Router {
             route_container: RouteStoreContainer::default(),
             upstream: RouteUpstream::default(),
             extensions: HashMap::with_capacity(2),
+            compressor: None,
+            body_rewriter: None,
+            request_decompressor: None,
+            waf: None,
+            openapi: None,
+            ext_proc: None,
+            esi: None,
+            error_pages: None,
+            body_limiter: None,
+            body_timer: None,
+            websocket: None,
+            grpc: None,
+            streaming: false,
+            sticky_session: None,
+            canary: None,
+            mirror: None,
+            capture: None,
+            body_log: None,
+            connection: None,
+            upstream_in_flight: None,
+            trace_id: None,
 
             timings: RouterTimings {
                 request_filter_start: std::time::Instant::now(),
+                upstream_selected: None,
             },
         }
     }
@@ -92,29 +291,175 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        // Only applies to HTTP/1.1, pingora has no read timeouts for HTTP/2 streams
+        if let Some(read_timeout) = self.slow_clients.read_timeout {
+            session.set_read_timeout(Duration::from_secs(read_timeout));
+        }
+        ctx.body_timer = BodyTimer::new(&self.slow_clients);
+        connection_metrics::record_request(session, "https");
+        ctx.trace_id = trace_context::trace_id(&session.req_header().headers);
+
+        if connection_limits::reject_over_limit(session, &self.connection_limits, "https").await? {
+            return Ok(true);
+        }
+
+        // Before anything reads the headers
+        if strict_parsing::reject_malformed(session, &self.strict_parsing, "https").await? {
+            return Ok(true);
+        }
+
+        let client_ip = get_client_ip(session, &self.trusted_proxies);
         let req_host = get_host(session);
         let host_without_port = req_host.split(':').collect::<Vec<_>>()[0];
         host_without_port.clone_into(&mut ctx.host);
 
         ctx.host = host_without_port.to_string();
 
+        if !is_ip_allowed(&self.ip_filter, client_ip) {
+            session.respond_error(403).await?;
+            return Ok(true);
+        }
+
+        // Used by plugins relying on the client IP (e.g. geoip)
+        if let Some(client_ip) = client_ip {
+            ctx.extensions
+                .insert(Cow::Borrowed("client_ip"), client_ip.to_string());
+        }
+
         // If there's no host matching, returns a 404
         let Some(route_container) = stores::get_route_by_key(host_without_port) else {
             session.respond_error(404).await?;
             return Ok(true);
         };
+        // The plugins build their settings once per version of the route (see its id)
+        ctx.route_container = route_container.clone();
+        ctx.error_pages.clone_from(&route_container.error_pages);
 
         // Match request pattern based on the URI
         let uri = get_uri(session);
 
         match &route_container.path_matcher.pattern {
             Some(pattern) if pattern.find(uri.path()).is_none() => {
-                session.respond_error(404).await?;
+                error_pages::respond(session, ctx, 404).await?;
                 return Ok(true);
             }
             _ => {}
         }
 
+        if let Some(ip_filter) = &route_container.ip_filter {
+            if !is_ip_allowed(ip_filter, client_ip) {
+                error_pages::respond(session, ctx, 403).await?;
+                return Ok(true);
+            }
+        }
+
+        // A session resumed with a ticket of another route had no certificate verified
+        if route_container
+            .client_auth
+            .as_ref()
+            .is_some_and(|auth| auth.required)
+            && !client_certificates::has_certificate(session)
+        {
+            error_pages::respond(session, ctx, 403).await?;
+            return Ok(true);
+        }
+        if let Some(client_auth) = route_container.client_auth.as_ref() {
+            if !client_auth.check_ocsp(session).await {
+                error_pages::respond(session, ctx, 403).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(methods) = route_container.methods.as_ref() {
+            if !methods.is_allowed(&session.req_header().method) {
+                error_pages::respond_method_not_allowed(session, ctx, &methods.allow).await?;
+                return Ok(true);
+            }
+        }
+
+        // Denied clients stay denied, the others wait for the end of the maintenance
+        if ctx
+            .error_pages
+            .as_ref()
+            .is_some_and(|pages| pages.maintenance)
+        {
+            error_pages::respond_maintenance(session, ctx).await?;
+            return Ok(true);
+        }
+
+        // Rejected before the plugins, so oversized requests are as cheap as possible
+        if let Some(route_limits) = route_container.limits.as_ref() {
+            match limits::check_headers(session.req_header(), route_limits) {
+                Ok(header_size) => ctx.body_limiter = BodyLimiter::new(route_limits, header_size),
+                Err(status) => {
+                    error_pages::respond(session, ctx, status.as_u16()).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if websocket::is_websocket_upgrade(session.req_header()) {
+            let Some(tunnel) = WebSocketTunnel::new(&ctx.host, route_container.websocket.as_ref())
+            else {
+                error_pages::respond(session, ctx, 403).await?;
+                return Ok(true);
+            };
+
+            // Pingora uses the read timeout for the frames sent by the client
+            if let Some(idle_timeout) = tunnel.idle_timeout {
+                session.set_read_timeout(idle_timeout);
+            }
+
+            // Frames are tunneled as the body, they can't be limited or buffered
+            ctx.body_timer = None;
+            ctx.body_limiter = None;
+            ctx.websocket = Some(tunnel);
+        }
+
+        let grpc_enabled = route_container
+            .grpc
+            .as_ref()
+            .is_none_or(|g| g.enabled.unwrap_or(true));
+        if grpc_enabled && grpc::is_grpc_request(session.req_header()) {
+            // Streaming calls send messages for as long as they need to
+            ctx.body_timer = None;
+            ctx.grpc = Some(GrpcCall::new(route_container.grpc.as_ref()));
+        }
+
+        // Events must reach the client as soon as they are sent
+        ctx.streaming = route_container.streaming.is_some();
+        ctx.sticky_session = route_container
+            .sticky_sessions
+            .as_ref()
+            .map(|config| StickySession::from_request(session.req_header(), config));
+        ctx.canary = route_container.canary.as_ref().map(|canary| {
+            let client_ip = ctx.extensions.get("client_ip").map(String::as_str);
+            let split = canary.split(session.req_header(), client_ip);
+            metrics::CANARY_REQUESTS
+                .with_label_values(&[ctx.host.as_str(), split.pool()])
+                .inc();
+            split
+        });
+
+        let kind = if ctx.websocket.is_some() {
+            "websocket"
+        } else if ctx.grpc.is_some() {
+            "grpc"
+        } else if ctx.streaming {
+            "streaming"
+        } else {
+            "http"
+        };
+        ctx.connection = Some(ConnectionGuard::register(session, &ctx.host, kind));
+
+        // Used by the access logs and the tls_fingerprint plugin
+        if let Some(fingerprint) = get_fingerprint(session) {
+            ctx.extensions
+                .insert(Cow::Borrowed("tls_ja3"), fingerprint.ja3.clone());
+            ctx.extensions
+                .insert(Cow::Borrowed("tls_ja4"), fingerprint.ja4.clone());
+        }
+
         // Middleware phase: request_filterx
         // We are checking to see if the request has already been handled
         // by the plugins i.e. (ok(true))
@@ -122,7 +467,19 @@ impl ProxyHttp for Router {
             return Ok(true);
         }
 
-        if route_container.cache.is_some() {
+        // Files are served from the disk, the route has no upstream
+        if let Some(files) = route_container.static_files.as_ref() {
+            static_files::serve(session, ctx, files, route_container.mime_types.as_deref()).await?;
+            return Ok(true);
+        }
+
+        // Tunnels and streams are never cached, and the cache key has no method: the other
+        // methods never read the cached responses
+        if route_container.cache.is_some()
+            && ctx.websocket.is_none()
+            && !ctx.streaming
+            && CACHEABLE_METHODS.contains(&session.req_header().method)
+        {
             let cache = route_container.cache.as_ref().unwrap();
             if cache.enabled.unwrap_or(false) {
                 let storage = get_cache_storage(&cache.cache_type);
@@ -138,7 +495,26 @@ impl ProxyHttp for Router {
             }
         }
 
-        ctx.route_container = route_container.clone();
+        // Only the proxied requests are copied, tunnels and gRPC streams never are
+        ctx.mirror = route_container
+            .mirror
+            .as_ref()
+            .filter(|_| ctx.websocket.is_none() && ctx.grpc.is_none())
+            .and_then(|mirror| mirror.copy(session.req_header()));
+        ctx.capture = route_container
+            .capture
+            .as_ref()
+            .filter(|_| ctx.websocket.is_none() && ctx.grpc.is_none())
+            .and_then(|capture| capture.start(session.req_header(), &ctx.host));
+        // The request body is logged once decompressed, if it is (see `request_decompression`)
+        ctx.body_log = route_container
+            .body_logging
+            .as_ref()
+            .filter(|_| ctx.websocket.is_none() && ctx.grpc.is_none())
+            .and_then(|logging| {
+                let decoded = ctx.request_decompressor.is_some();
+                logging.start(session.req_header(), &ctx.host, decoded)
+            });
 
         Ok(false)
     }
@@ -159,17 +535,68 @@ impl ProxyHttp for Router {
             session.cache.set_max_file_size_bytes(100 * 1024 * 1024);
         }
 
-        let Some(healthy_upstream) = route_container.load_balancer.select(b"", 32) else {
+        // Upstreams failing the passive checks are skipped too
+        let is_ready = |backend: &Backend, ready: bool| {
+            health::is_healthy(&ctx.host, &backend.addr.to_string()).unwrap_or(ready)
+        };
+
+        // The requests split to the canary go to its pool
+        let (load_balancer, upstreams) = match route_container.canary.as_ref() {
+            Some(canary) if ctx.canary.as_ref().is_some_and(|split| split.canary) => {
+                (&canary.load_balancer, &canary.upstreams)
+            }
+            _ => (&route_container.load_balancer, &route_container.upstreams),
+        };
+
+        // Sessions stay on their upstream for as long as it's ready, the variants of the
+        // experiments with an upstream and the requests sent to an upstream by a Lua script
+        // go to it first
+        let store = stores::global::get_store().as_ref();
+        let backends = load_balancer.backends();
+        let assigned = match ctx.sticky_session.as_mut() {
+            Some(sticky) => {
+                let upstreams = backends.get_backend();
+                let addresses = upstreams.iter().map(|b| b.addr.to_string());
+                sticky.upstream(store, &ctx.host, addresses).await
+            }
+            None => None,
+        };
+        let assigned = ctx
+            .extensions
+            .get("lua_upstream")
+            .or_else(|| ctx.extensions.get("experiment_upstream"))
+            .cloned()
+            .or(assigned);
+        let assigned = assigned.and_then(|address| {
+            backends
+                .get_backend()
+                .iter()
+                .find(|b| b.addr.to_string() == address && is_ready(b, backends.ready(b)))
+                .cloned()
+        });
+
+        let Some(healthy_upstream) =
+            assigned.or_else(|| load_balancer.select_with(b"", 32, is_ready))
+        else {
+            let pool = ctx.canary.as_ref().map_or("stable", CanarySplit::pool);
+            metrics::UPSTREAM_POOL_EXHAUSTED
+                .with_label_values(&[ctx.host.as_str(), pool])
+                .inc();
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
+        if let Some(sticky) = ctx.sticky_session.as_mut() {
+            let address = healthy_upstream.addr.to_string();
+            sticky.assign(store, &ctx.host, &address).await;
+        }
+
         let (healthy_ip, healthy_port) = if let Some(scr) = healthy_upstream.addr.as_inet() {
             (scr.ip().to_string(), scr.port())
         } else {
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
-        let Some(upstream) = route_container.upstreams.iter().find(|u| {
+        let Some(upstream) = upstreams.iter().find(|u| {
             format!("{}:{}", u.ip, u.port)
                 .to_socket_addrs()
                 .unwrap()
@@ -186,7 +613,34 @@ impl ProxyHttp for Router {
             healthy_port == 443,
             upstream.sni.clone().unwrap_or(String::new()),
         );
-        peer.options = default_peer_opts();
+        peer.options = upstream_peer_opts(upstream, &self.upstream_pool);
+
+        if let Some(tunnel) = ctx.websocket.as_ref() {
+            // Upgrades are an HTTP/1.1 mechanism
+            peer.options.alpn = ALPN::H1;
+            if let Some(idle_timeout) = tunnel.idle_timeout {
+                peer.options.read_timeout = Some(idle_timeout);
+                peer.options.idle_timeout = Some(idle_timeout);
+            }
+        }
+
+        if let Some(streaming) = route_container.streaming.as_ref() {
+            streaming::extend_timeouts(&mut peer.options, streaming);
+        }
+
+        if let Some(call) = ctx.grpc.as_ref() {
+            // gRPC requires HTTP/2 end to end, plaintext upstreams use h2c with prior knowledge
+            peer.options.alpn = ALPN::H2;
+            if let Some(timeout) = call.timeout {
+                peer.options.read_timeout = Some(timeout);
+                peer.options.idle_timeout = Some(timeout);
+            }
+            if let Some(max_streams) = call.max_streams {
+                peer.options.max_h2_streams = max_streams;
+            }
+        }
+
+        ctx.timings.upstream_selected = Some(std::time::Instant::now());
         Ok(Box::new(peer))
     }
 
@@ -200,6 +654,11 @@ impl ProxyHttp for Router {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
+        // The response is still sent, but the connection is not reused
+        if ctx.connection.as_ref().is_some_and(|c| c.is_closing()) {
+            session.set_keepalive(None);
+        }
+
         // If there's no host matching, returns a 404
         let route_container = &ctx.route_container;
 
@@ -207,6 +666,17 @@ impl ProxyHttp for Router {
             upstream_response.insert_header(name, value)?;
         }
 
+        if let Some(cookie) = ctx
+            .sticky_session
+            .as_ref()
+            .and_then(StickySession::set_cookie)
+        {
+            upstream_response.append_header(http::header::SET_COOKIE, cookie)?;
+        }
+        if let Some(cookie) = ctx.canary.as_ref().and_then(CanarySplit::set_cookie) {
+            upstream_response.append_header(http::header::SET_COOKIE, cookie)?;
+        }
+
         // Remove headers from the upstream response
         for name in &route_container.host_header_remove {
             upstream_response.remove_header(name);
@@ -228,12 +698,112 @@ impl ProxyHttp for Router {
             )?;
         }
 
+        if let Some(ext_proc) = ctx.ext_proc.as_mut() {
+            ext_proc.process_response_headers(upstream_response).await?;
+        }
+
+        // The includes are processed after the cache, so each response gets current fragments
+        if let Some(mut page) = ctx.esi.take() {
+            let req = session.req_header();
+            if page.prepare(req, upstream_response, &ctx.route_container, &ctx.host)? {
+                ctx.esi = Some(page);
+            }
+        }
+
         // Middleware phase: response_filterx
         execute_response_plugins(session, ctx).await?;
 
         Ok(())
     }
 
+    /// Handle the incoming request body, decompressing, inspecting, validating and processing it
+    /// if needed
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        if let Some(connection) = ctx.connection.as_ref() {
+            if connection.is_closing() {
+                session.set_keepalive(None);
+                return Err(connections::closed_error());
+            }
+            connection.add_received(body.as_ref().map_or(0, bytes::Bytes::len));
+        }
+
+        if let Some(body_timer) = ctx.body_timer.as_mut() {
+            let chunk_size = body.as_ref().map_or(0, bytes::Bytes::len);
+            if let Err(reason) = body_timer.check(chunk_size, std::time::Instant::now()) {
+                // The connection can't be reused, the body was not fully read
+                session.set_keepalive(None);
+                log_slow_client(session, &ctx.host, &reason);
+                return Err(pingora::Error::explain(
+                    HTTPStatus(408),
+                    "request body received too slowly",
+                ));
+            }
+        }
+
+        // Limits apply to the body as sent by the client
+        if let Some(body_limiter) = ctx.body_limiter.as_mut() {
+            body_limiter.check(body.as_ref())?;
+        }
+
+        // The shadow gets the body as sent by the client, along with its headers
+        if let Some(mirror) = ctx.mirror.as_mut() {
+            mirror.push(body.as_ref(), end_of_stream);
+        }
+        if let Some(mirror) = ctx.mirror.take_if(|_| end_of_stream) {
+            mirror.send(&ctx.host);
+        }
+        if let Some(capture) = ctx.capture.as_mut() {
+            capture.push(body.as_ref());
+        }
+
+        if let Some(decompressor) = ctx.request_decompressor.as_mut() {
+            let data = body.as_deref().unwrap_or_default();
+            match decompressor.decompress(data, end_of_stream) {
+                Ok(decompressed) => *body = Some(decompressed),
+                // Invalid compressed data
+                Err(err) if err.is::<std::io::Error>() => {
+                    return Err(pingora::Error::explain(HTTPStatus(400), err.to_string()));
+                }
+                Err(err) => return Err(pingora::Error::explain(HTTPStatus(413), err.to_string())),
+            }
+        }
+
+        if let Some(body_log) = ctx.body_log.as_mut() {
+            body_log.push_request(body.as_ref());
+        }
+
+        // The decompressed body is inspected, as that's what the upstream receives
+        if let Some(waf) = ctx.waf.as_mut() {
+            let data = body.as_deref().unwrap_or_default();
+            if waf.inspect_body(data, end_of_stream, &ctx.host) {
+                return Err(pingora::Error::explain(
+                    HTTPStatus(403),
+                    "request blocked by the WAF",
+                ));
+            }
+        }
+
+        if let Some(openapi) = ctx.openapi.as_mut() {
+            openapi.validate_body(session, body, end_of_stream).await?;
+        }
+
+        if let Some(ext_proc) = ctx.ext_proc.as_mut() {
+            ext_proc.process_request_body(body, end_of_stream).await?;
+        }
+
+        if let Some(body_limiter) = ctx.body_limiter.as_mut() {
+            body_limiter.buffer(body, end_of_stream);
+        }
+
+        Ok(())
+    }
+
     /// Modify the request before it is sent to the upstream
     ///
     /// Unlike [Self::request_filter()], this filter allows to change the request headers to send
@@ -247,6 +817,34 @@ impl ProxyHttp for Router {
         // If there's no host matching, returns a 404
         // let route_container = &ctx.route_container;
 
+        // Before the header rules, so that they can still change them
+        let client_ip = ctx
+            .extensions
+            .get("client_ip")
+            .and_then(|ip| ip.parse().ok());
+        set_forwarded_headers(
+            upstream_request,
+            &self.forwarded_headers,
+            get_peer_ip(session),
+            client_ip,
+            get_host(session),
+            &self.trusted_proxies,
+        )?;
+
+        if let Some(forward) = ctx
+            .route_container
+            .client_auth
+            .as_ref()
+            .and_then(|auth| auth.forward.as_ref())
+        {
+            let certificate = client_certificates::get_certificate(session);
+            client_certificates::set_certificate_headers(
+                upstream_request,
+                certificate.as_deref(),
+                forward,
+            );
+        }
+
         let upstream = &ctx.upstream;
 
         // TODO: refactor
@@ -260,6 +858,11 @@ impl ProxyHttp for Router {
             }
         }
 
+        if let Some(rules) = ctx.route_container.request_headers.clone() {
+            let values = VariableValues::new(session.req_header(), ctx);
+            rules.apply_to_request(upstream_request, &values)?;
+        }
+
         execute_upstream_request_plugins(session, upstream_request, ctx)
             .await
             .ok();
@@ -283,11 +886,138 @@ impl ProxyHttp for Router {
         // If there's no host matching, returns a 404
         // let route_container = process_route(ctx);
 
+        // The types of the route replace the ones of the upstream, before any type is checked
+        if let Some(mime_types) = ctx.route_container.mime_types.as_deref() {
+            let path = Path::new(session.req_header().uri.path());
+            if let Some(content_type) = mime_types
+                .get(path)
+                .filter(|_| upstream_response.status.is_success())
+            {
+                upstream_response
+                    .insert_header(http::header::CONTENT_TYPE, content_type.clone())?;
+            }
+        }
+
         execute_upstream_response_plugins(session, upstream_response, ctx);
 
+        if let Some(tunnel) = ctx.websocket.as_mut() {
+            tunnel.on_upstream_response(upstream_response.status);
+        }
+
+        // Trailers-only responses (usually errors) have the status in the headers
+        if let Some(call) = ctx.grpc.as_mut() {
+            call.capture_status(&upstream_response.headers);
+        }
+
+        if let Some(rules) = ctx.route_container.response_headers.clone() {
+            let values = VariableValues::new(session.req_header(), ctx);
+            rules.apply_to_response(upstream_response, &values)?;
+        }
+
+        // With the encoding of the upstream, the body is logged before it is compressed
+        if let Some(body_log) = ctx.body_log.as_mut() {
+            body_log.start_response(upstream_response);
+        }
+
+        if ctx.streaming {
+            // The rewriters and the compressor hold data back until they have enough of it
+            ctx.body_rewriter = None;
+            streaming::disable_buffering(upstream_response)?;
+        }
+
+        // Compression happens before caching so that each encoding is cached separately
+        if let Some(config) = ctx
+            .route_container
+            .compression
+            .as_ref()
+            .filter(|_| !ctx.streaming)
+            // Pages with includes are cached uncompressed, the stitched pages are compressed
+            .filter(|_| ctx.esi.is_none() || !esi::is_processed(upstream_response))
+        {
+            let req = session.req_header();
+            if compression::is_compressible(req, upstream_response, config) {
+                compression::add_vary_header(upstream_response)?;
+
+                let accept_encoding = req.headers.get(http::header::ACCEPT_ENCODING);
+                if let Some(algorithm) = compression::negotiate(accept_encoding, &config.algorithms)
+                {
+                    ctx.compressor =
+                        compression::compress_response(upstream_response, algorithm, config.level)?;
+                }
+            }
+        }
+
+        // Clients revalidate the cached responses with the tag, and get a `304` from the cache
+        if session.cache.enabled() && upstream_response.status == http::StatusCode::OK {
+            etag::add_weak_etag(upstream_response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Trailers are forwarded as they are (HTTP/2 only), they carry the status of gRPC calls
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        if let Some(call) = ctx.grpc.as_mut() {
+            call.capture_status(upstream_trailers);
+        }
+
         Ok(())
     }
 
+    /// Rewrites and compresses the response body (if enabled in the response headers)
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        // Substitutions are made on the identity body, before any compression
+        if let Some(rewriter) = ctx.body_rewriter.as_mut() {
+            let data = body.as_deref().unwrap_or_default();
+            *body = Some(rewriter.rewrite(data, end_of_stream));
+        }
+
+        if let Some(body_log) = ctx.body_log.as_mut() {
+            body_log.push_response(body.as_ref());
+        }
+
+        if let Some(compressor) = ctx.compressor.as_mut() {
+            let data = body.as_deref().unwrap_or_default();
+            *body = Some(compressor.encode(data, end_of_stream)?);
+        }
+
+        Ok(())
+    }
+
+    /// Stitches the fragments of the pages with includes, counts the bytes sent to the client,
+    /// and aborts the requests closed through the admin API
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<Duration>> {
+        if let Some(page) = ctx.esi.as_mut() {
+            page.process(body, end_of_stream)?;
+        }
+
+        if let Some(connection) = ctx.connection.as_ref() {
+            if connection.is_closing() {
+                return Err(connections::closed_error());
+            }
+            connection.add_sent(body.as_ref().map_or(0, bytes::Bytes::len));
+        }
+
+        Ok(None)
+    }
+
     /// This filter is called when the entire response is sent to the downstream successfully or
     /// there is a fatal error that terminate the request.
     ///
@@ -296,9 +1026,29 @@ impl ProxyHttp for Router {
     async fn logging(
         &self,
         session: &mut Session,
-        _: Option<&pingora::Error>,
+        error: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        if let Some(reason) = error.and_then(SlowClientReason::from_error) {
+            log_slow_client(session, &ctx.host, &reason);
+        }
+
+        // Requests whose body never reached the filters (e.g. served from the cache)
+        if let Some(mirror) = ctx.mirror.take().filter(MirrorRequest::is_complete) {
+            mirror.send(&ctx.host);
+        }
+
+        if let Some(cache_state) = ctx.extensions.get("cache_state") {
+            let result = if cache_state == "fwd=miss" {
+                "miss"
+            } else {
+                cache_state
+            };
+            metrics::CACHE_REQUESTS
+                .with_label_values(&[ctx.host.as_str(), result])
+                .inc();
+        }
+
         let duration_ms = ctx.timings.request_filter_start.elapsed().as_millis();
 
         let http_version = if session.is_http2() {
@@ -324,7 +1074,9 @@ impl ProxyHttp for Router {
             .get("user-agent")
             .unwrap_or(&empty_header);
 
-        let client_ip = session
+        // Resolved from `X-Forwarded-For` for requests of trusted proxies
+        let client_ip = ctx.extensions.get("client_ip").cloned().unwrap_or_default();
+        let remote_addr = session
             .client_addr()
             .map(ToString::to_string)
             .unwrap_or_default();
@@ -334,6 +1086,92 @@ impl ProxyHttp for Router {
             .map(|v| v.status.as_u16())
             .unwrap_or_default();
 
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[ctx.host.as_str(), status_class(status_code)])
+            .inc();
+        let request_id = request_id(session, ctx);
+        metrics::HTTP_REQUEST_SECONDS.observe(
+            &[ctx.host.as_str()],
+            ctx.timings.request_filter_start.elapsed().as_secs_f64(),
+            trace_context::exemplar(ctx.trace_id.as_deref(), request_id.as_deref()),
+        );
+        if let Some(capture) = ctx.capture.take() {
+            capture.finish(status_code);
+        }
+        if let Some(body_log) = ctx.body_log.take() {
+            body_log.finish(status_code, request_id.as_deref(), ctx.trace_id.as_deref());
+        }
+        if tap::is_tapped() {
+            let cache = ctx.extensions.get("cache_state").map(|state| {
+                if state == "fwd=miss" {
+                    "miss"
+                } else {
+                    state.as_str()
+                }
+            });
+            tap::publish(
+                &ctx.host,
+                &method,
+                path,
+                status_code,
+                duration_ms,
+                ctx.extensions.get("peer"),
+                cache,
+            );
+        }
+        if log_export::is_enabled() {
+            log_export::export(AccessLogEvent {
+                timestamp: log_export::unix_millis(),
+                host: ctx.host.clone(),
+                method: method.clone(),
+                path: path.to_string(),
+                query: query.to_string(),
+                status: status_code,
+                duration_ms,
+                client_ip: client_ip.clone(),
+                remote_addr: remote_addr.clone(),
+                user_agent: user_agent.to_str().unwrap_or_default().to_string(),
+                referer: referer.to_str().unwrap_or_default().to_string(),
+                http_version: http_version.to_string(),
+                upstream: ctx.extensions.get("peer").cloned(),
+                cache: ctx.extensions.get("cache_state").map(|state| {
+                    if state == "fwd=miss" {
+                        "miss".to_string()
+                    } else {
+                        state.clone()
+                    }
+                }),
+                request_id: request_id.clone(),
+                trace_id: ctx.trace_id.clone(),
+            });
+        }
+        let experiment = ctx.extensions.get("experiment");
+        let variant = ctx.extensions.get("experiment_variant");
+        if let (Some(experiment), Some(variant)) = (experiment, variant) {
+            metrics::EXPERIMENT_REQUESTS
+                .with_label_values(&[
+                    ctx.host.as_str(),
+                    experiment.as_str(),
+                    variant.as_str(),
+                    status_class(status_code),
+                ])
+                .inc();
+        }
+        // Clients going away (e.g. closing a stream) are not errors of the proxy
+        let error = error.filter(|err| err.esource != pingora::ErrorSource::Downstream);
+        let failed = status_code >= 500 || error.is_some();
+        blue_green::record(&ctx.host, failed);
+        if failed {
+            recent_errors::record(
+                &ctx.host,
+                &method,
+                path,
+                status_code,
+                ctx.extensions.get("peer"),
+                error,
+            );
+        }
+
         tracing::info!(
             method,
             path,
@@ -343,11 +1181,24 @@ impl ProxyHttp for Router {
             user_agent = user_agent.to_str().unwrap_or(""),
             referer = referer.to_str().unwrap_or(""),
             client_ip,
+            remote_addr,
             status_code,
             http_version,
             reused_connection = ctx.extensions.get("reused").unwrap_or(&String::new()),
             peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
-            request_id = ctx.extensions.get("request_id_header"),
+            request_id,
+            trace_id = ctx.trace_id.as_deref(),
+            country = ctx.extensions.get("geoip_country"),
+            asn = ctx.extensions.get("geoip_asn"),
+            bot = ctx.extensions.get("bot"),
+            bot_name = ctx.extensions.get("bot_name"),
+            canary = ctx.canary.as_ref().map(CanarySplit::pool),
+            experiment,
+            variant,
+            ja3 = ctx.extensions.get("tls_ja3"),
+            ja4 = ctx.extensions.get("tls_ja4"),
+            grpc_status = ctx.grpc.as_ref().and_then(|call| call.status.as_deref()),
+            grpc_message = ctx.grpc.as_ref().and_then(|call| call.message.as_deref()),
             access_log = true
         );
     }
@@ -362,19 +1213,23 @@ impl ProxyHttp for Router {
         session: &Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<CacheKey> {
-        let req_header = session.req_header();
-        Ok(CacheKey::new(
-            ctx.host.clone(),
-            base64::encode_block(
-                req_header
-                    .uri
-                    .path_and_query()
-                    .unwrap_or(&PathAndQuery::from_static("/"))
-                    .as_str()
-                    .as_bytes(),
-            ),
-            "",
-        ))
+        let path_and_query = session
+            .req_header()
+            .uri
+            .path_and_query()
+            .map_or("/", PathAndQuery::as_str);
+        Ok(cache_key(&ctx.host, path_and_query))
+    }
+
+    /// Responses varying on `Accept-Encoding` (i.e. compressed) are stored per encoding
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        let config = ctx.route_container.compression.as_ref()?;
+        compression::cache_variance(meta, req, config)
     }
 
     /// This callback is invoked when a cacheable response is ready to be admitted to cache
@@ -407,6 +1262,20 @@ impl ProxyHttp for Router {
         Ok(None)
     }
 
+    /// The cached pages with includes are always sent, their fragments may have changed
+    fn cache_not_modified_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<bool> {
+        if ctx.esi.is_some() && esi::is_processed(resp) {
+            return Ok(false);
+        }
+
+        Ok(not_modified_filter(session.req_header(), resp))
+    }
+
     /// Decide if the response is cacheable
     fn response_cache_filter(
         &self,
@@ -437,12 +1306,78 @@ impl ProxyHttp for Router {
         )))
     }
 
+    /// This filter is called when the connection to the upstream failed, which counts as a
+    /// failed passive check of the upstream.
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        health::report_failure(&ctx.host, &peer.address().to_string(), e.to_string());
+        e
+    }
+
+    /// Summary of the request in the error logs of pingora, with its trace and request IDs to
+    /// find it in the traces and the access logs
+    fn request_summary(&self, session: &Session, ctx: &Self::CTX) -> String {
+        let mut summary = session.as_downstream().request_summary();
+        if let Some(trace_id) = ctx.trace_id.as_deref() {
+            summary.push_str(", trace_id: ");
+            summary.push_str(trace_id);
+        }
+        if let Some(request_id) = request_id(session, ctx) {
+            summary.push_str(", request_id: ");
+            summary.push_str(&request_id);
+        }
+        summary
+    }
+
+    /// This filter is called when the request failed before a response was sent, the status
+    /// is the one of pingora (e.g. `502` for the errors of the upstreams) and the body is the
+    /// error page of the route.
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // The connection of the client is already gone
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            error_pages::respond(session, ctx, code)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::error!("failed to send error response to downstream: {e}");
+                });
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     /// This filter is called when the request just established or reused a connection to the upstream
     ///
     /// This filter allows user to log timing and connection related info.
     async fn connected_to_upstream(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         reused: bool,
         peer: &HttpPeer,
         _fd: std::os::unix::io::RawFd,
@@ -456,10 +1391,49 @@ impl ProxyHttp for Router {
             .insert(Cow::Borrowed("reused"), reused.to_string());
         ctx.extensions
             .insert(Cow::Borrowed("peer"), peer.address().to_string());
+        let upstream = peer.address().to_string();
+        health::report_success(&ctx.host, &upstream);
+
+        metrics::UPSTREAM_CONNECTIONS
+            .with_label_values(&[
+                ctx.host.as_str(),
+                &upstream,
+                if reused { "true" } else { "false" },
+            ])
+            .inc();
+        if let (false, Some(selected)) = (reused, ctx.timings.upstream_selected) {
+            let request_id = request_id(session, ctx);
+            metrics::UPSTREAM_CONNECT_SECONDS.observe(
+                &[&ctx.host, &upstream],
+                selected.elapsed().as_secs_f64(),
+                trace_context::exemplar(ctx.trace_id.as_deref(), request_id.as_deref()),
+            );
+        }
+
+        // Replaces the request counted on the upstream of a previous attempt
+        ctx.upstream_in_flight = Some(metrics::UpstreamInFlight::new(&ctx.host, &upstream));
+        if let Some(connection) = ctx.connection.as_ref() {
+            connection.set_upstream(upstream, reused);
+        }
         Ok(())
     }
 }
 
+/// ID of the request, generated by the `request_id` plugin or sent by the client
+fn request_id(session: &Session, ctx: &RouterContext) -> Option<String> {
+    ctx.extensions
+        .get("request_id_header")
+        .cloned()
+        .or_else(|| {
+            session
+                .req_header()
+                .headers
+                .get("x-request-id")
+                .and_then(|id| id.to_str().ok())
+                .map(ToString::to_string)
+        })
+}
+
 fn get_uri(session: &mut Session) -> Uri {
     session.req_header().uri.clone()
 }
@@ -0,0 +1,207 @@
+use bytes::{Bytes, BytesMut};
+use http::{header, StatusCode};
+use pingora::{http::RequestHeader, ErrorType::HTTPStatus};
+
+use crate::config::RouteLimits;
+
+/// Size of the request line and headers, as they would be sent over HTTP/1.1
+/// (HTTP/2 requests are compressed on the wire)
+pub fn header_size(req: &RequestHeader) -> u64 {
+    // <method> <uri> HTTP/1.1\r\n
+    let request_line = req.method.as_str().len() + req.uri.to_string().len() + 12;
+    // <name>: <value>\r\n
+    let headers = req
+        .headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum::<usize>();
+
+    // Final empty line
+    (request_line + headers + 2) as u64
+}
+
+/// Checks the limits known before receiving the body (headers and `Content-Length`),
+/// returning the size of the headers or the status of the rejection
+pub fn check_headers(req: &RequestHeader, limits: &RouteLimits) -> Result<u64, StatusCode> {
+    let header_size = header_size(req);
+    let too_large = |max: Option<u64>, size: u64| max.is_some_and(|max| size > max);
+
+    if too_large(limits.max_header_size, header_size)
+        || too_large(limits.max_request_size, header_size)
+    {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    let content_length = req
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(content_length) = content_length {
+        if too_large(limits.max_body_size, content_length)
+            || too_large(
+                limits.max_request_size,
+                header_size.saturating_add(content_length),
+            )
+        {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    Ok(header_size)
+}
+
+/// Enforces the body limits of a route while it is received (e.g. chunked bodies),
+/// and holds it until the end of the stream when buffering is enabled
+pub struct BodyLimiter {
+    /// Maximum size of the body, from the body and request limits
+    max_size: Option<u64>,
+    received: u64,
+    buffer: Option<BytesMut>,
+}
+
+impl BodyLimiter {
+    /// Returns `None` if the body doesn't need to be limited or buffered
+    pub fn new(limits: &RouteLimits, header_size: u64) -> Option<Self> {
+        let max_size = [
+            limits.max_body_size,
+            limits
+                .max_request_size
+                .map(|max| max.saturating_sub(header_size)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        if max_size.is_none() && !limits.buffer_body {
+            return None;
+        }
+
+        Some(Self {
+            max_size,
+            received: 0,
+            buffer: limits.buffer_body.then(BytesMut::new),
+        })
+    }
+
+    /// Counts the bytes received from the client, failing with a `413` once
+    /// the limit is exceeded
+    pub fn check(&mut self, body: Option<&Bytes>) -> pingora::Result<()> {
+        self.received += body.map_or(0, |data| data.len() as u64);
+
+        if self.max_size.is_some_and(|max| self.received > max) {
+            return Err(pingora::Error::explain(
+                HTTPStatus(413),
+                "request body is too large",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the chunks by empty ones until the end of the stream, where the whole
+    /// body is sent at once
+    pub fn buffer(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        let Some(buffer) = self.buffer.as_mut() else {
+            return;
+        };
+
+        if let Some(data) = body.as_ref() {
+            buffer.extend_from_slice(data);
+        }
+
+        *body = if end_of_stream {
+            Some(buffer.split().freeze())
+        } else {
+            Some(Bytes::new())
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content_length: Option<u64>) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/upload", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        if let Some(content_length) = content_length {
+            req.insert_header(header::CONTENT_LENGTH, content_length)
+                .unwrap();
+        }
+        req
+    }
+
+    fn limits(
+        max_header_size: Option<u64>,
+        max_body_size: Option<u64>,
+        max_request_size: Option<u64>,
+    ) -> RouteLimits {
+        RouteLimits {
+            max_header_size,
+            max_body_size,
+            max_request_size,
+            buffer_body: false,
+        }
+    }
+
+    #[test]
+    fn test_header_size() {
+        // "POST /upload HTTP/1.1\r\nhost: example.com\r\n\r\n"
+        assert_eq!(header_size(&request(None)), 44);
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let req = request(Some(100));
+
+        assert_eq!(check_headers(&req, &limits(None, None, None)), Ok(65));
+        assert_eq!(
+            check_headers(&req, &limits(Some(50), None, None)),
+            Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+        assert_eq!(
+            check_headers(&req, &limits(None, Some(99), None)),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(
+            check_headers(&req, &limits(None, None, Some(150))),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(
+            check_headers(&req, &limits(None, None, Some(60))),
+            Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+        assert_eq!(
+            check_headers(&req, &limits(Some(65), Some(100), Some(165))),
+            Ok(65)
+        );
+    }
+
+    #[test]
+    fn test_body_limiter() {
+        assert!(BodyLimiter::new(&limits(Some(100), None, None), 50).is_none());
+
+        // the smallest of the body and remaining request limits is used
+        let mut limiter = BodyLimiter::new(&limits(None, Some(20), Some(60)), 50).unwrap();
+        assert!(limiter.check(Some(&Bytes::from_static(b"12345"))).is_ok());
+        assert!(limiter.check(None).is_ok());
+        assert!(limiter.check(Some(&Bytes::from_static(b"123456"))).is_err());
+    }
+
+    #[test]
+    fn test_body_buffering() {
+        let mut limits = limits(None, None, None);
+        limits.buffer_body = true;
+        let mut limiter = BodyLimiter::new(&limits, 0).unwrap();
+
+        let mut body = Some(Bytes::from_static(b"hello "));
+        limiter.buffer(&mut body, false);
+        assert_eq!(body, Some(Bytes::new()));
+
+        let mut body = Some(Bytes::from_static(b"world"));
+        limiter.buffer(&mut body, true);
+        assert_eq!(body, Some(Bytes::from_static(b"hello world")));
+    }
+}
@@ -0,0 +1,73 @@
+use anyhow::anyhow;
+use http::{HeaderValue, Method};
+
+/// Methods allowed by a route, the requests with another method get a `405`
+#[derive(Debug)]
+pub struct AllowedMethods {
+    methods: Vec<Method>,
+    /// Value of the `Allow` header of the `405` responses
+    pub allow: HeaderValue,
+}
+
+impl AllowedMethods {
+    /// `HEAD` is allowed along with `GET`, as HTTP requires
+    pub fn from_config(methods: &[String]) -> anyhow::Result<Self> {
+        if methods.is_empty() {
+            return Err(anyhow!("at least one method must be allowed"));
+        }
+
+        let mut allowed = vec![];
+        for method in methods {
+            let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| anyhow!("{method} is not an HTTP method"))?;
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+        if allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+            allowed.push(Method::HEAD);
+        }
+
+        let allow = allowed
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(AllowedMethods {
+            allow: HeaderValue::from_str(&allow)?,
+            methods: allowed,
+        })
+    }
+
+    pub fn is_allowed(&self, method: &Method) -> bool {
+        self.methods.contains(method)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn methods(methods: &[&str]) -> anyhow::Result<AllowedMethods> {
+        AllowedMethods::from_config(&methods.iter().map(ToString::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let allowed = methods(&["get", "POST", "GET"]).unwrap();
+        assert_eq!(allowed.allow, "GET, POST, HEAD");
+        assert!(allowed.is_allowed(&Method::GET));
+        assert!(allowed.is_allowed(&Method::HEAD));
+        assert!(!allowed.is_allowed(&Method::DELETE));
+
+        let allowed = methods(&["PURGE"]).unwrap();
+        assert!(allowed.is_allowed(&Method::from_bytes(b"PURGE").unwrap()));
+        assert!(!allowed.is_allowed(&Method::GET));
+    }
+
+    #[test]
+    fn test_invalid_methods() {
+        assert!(methods(&[]).is_err());
+        assert!(methods(&["GET /"]).is_err());
+    }
+}
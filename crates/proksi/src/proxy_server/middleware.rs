@@ -66,6 +66,190 @@ pub async fn execute_request_plugins(
                     return Ok(true);
                 }
             }
+            "jwt" => {
+                if crate::plugins::PLUGINS
+                    .jwt
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "oidc" => {
+                if crate::plugins::PLUGINS
+                    .oidc
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "forward_auth" => {
+                if crate::plugins::PLUGINS
+                    .forward_auth
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "api_key" => {
+                if crate::plugins::PLUGINS
+                    .api_key
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "geoip" => {
+                if crate::plugins::PLUGINS
+                    .geoip
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "lua" => {
+                if crate::plugins::PLUGINS
+                    .lua
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "request_decompression" => {
+                if crate::plugins::PLUGINS
+                    .request_decompression
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "waf" => {
+                if crate::plugins::PLUGINS
+                    .waf
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "bot_filter" => {
+                if crate::plugins::PLUGINS
+                    .bot_filter
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "ext_proc" => {
+                if crate::plugins::PLUGINS
+                    .ext_proc
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "openapi" => {
+                if crate::plugins::PLUGINS
+                    .openapi
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "signed_url" => {
+                if crate::plugins::PLUGINS
+                    .signed_url
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "hotlink" => {
+                if crate::plugins::PLUGINS
+                    .hotlink
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "redirects" => {
+                if crate::plugins::PLUGINS
+                    .redirects
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "tls_fingerprint" => {
+                if crate::plugins::PLUGINS
+                    .tls_fingerprint
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "challenge" => {
+                if crate::plugins::PLUGINS
+                    .challenge
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
+            "esi" => {
+                crate::plugins::PLUGINS
+                    .esi
+                    .request_filter(session, ctx, value)
+                    .await
+                    .ok();
+            }
+            "experiment" => {
+                crate::plugins::PLUGINS
+                    .experiment
+                    .request_filter(session, ctx, value)
+                    .await
+                    .ok();
+            }
+            "fault_injection" => {
+                if crate::plugins::PLUGINS
+                    .fault_injection
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
             _ => {}
         }
     }
@@ -87,6 +271,90 @@ pub async fn execute_upstream_request_plugins(
                     .await
                     .ok();
             }
+            "jwt" => {
+                crate::plugins::PLUGINS
+                    .jwt
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "oidc" => {
+                crate::plugins::PLUGINS
+                    .oidc
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "forward_auth" => {
+                crate::plugins::PLUGINS
+                    .forward_auth
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "api_key" => {
+                crate::plugins::PLUGINS
+                    .api_key
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "request_decompression" => {
+                crate::plugins::PLUGINS
+                    .request_decompression
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "response_rewrite" => {
+                crate::plugins::PLUGINS
+                    .response_rewrite
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "html_inject" => {
+                crate::plugins::PLUGINS
+                    .html_inject
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "esi" => {
+                crate::plugins::PLUGINS
+                    .esi
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "cookie_rewrite" => {
+                crate::plugins::PLUGINS
+                    .cookie_rewrite
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "experiment" => {
+                crate::plugins::PLUGINS
+                    .experiment
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "ext_proc" => {
+                crate::plugins::PLUGINS
+                    .ext_proc
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
+            "tls_fingerprint" => {
+                crate::plugins::PLUGINS
+                    .tls_fingerprint
+                    .upstream_request_filter(session, upstream_request, ctx)
+                    .await
+                    .ok();
+            }
             "other" => continue,
             _ => {}
         }
@@ -108,6 +376,42 @@ pub fn execute_upstream_response_plugins(
                     .upstream_response_filter(session, upstream_response, ctx)
                     .ok();
             }
+            "security_headers" => {
+                crate::plugins::PLUGINS
+                    .security_headers
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
+            "lua" => {
+                crate::plugins::PLUGINS
+                    .lua
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
+            "response_rewrite" => {
+                crate::plugins::PLUGINS
+                    .response_rewrite
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
+            "html_inject" => {
+                crate::plugins::PLUGINS
+                    .html_inject
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
+            "cookie_rewrite" => {
+                crate::plugins::PLUGINS
+                    .cookie_rewrite
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
+            "experiment" => {
+                crate::plugins::PLUGINS
+                    .experiment
+                    .upstream_response_filter(session, upstream_response, ctx)
+                    .ok();
+            }
             "other" => continue,
             _ => {}
         }
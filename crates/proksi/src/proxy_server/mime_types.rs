@@ -0,0 +1,140 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use http::HeaderValue;
+
+/// Content types of the file extensions of a route (e.g. `wasm: application/wasm`), they
+/// override the default ones
+#[derive(Debug, Default)]
+pub struct MimeTypes(HashMap<String, HeaderValue>);
+
+impl MimeTypes {
+    /// Extensions are matched regardless of their case, with or without their leading dot
+    pub fn from_config(types: &HashMap<String, String>) -> Result<Self> {
+        types
+            .iter()
+            .map(|(extension, content_type)| {
+                let extension = extension
+                    .strip_prefix('.')
+                    .unwrap_or(extension)
+                    .to_ascii_lowercase();
+                if extension.is_empty() || extension.contains(['.', '/']) {
+                    bail!("invalid extension {extension:?}");
+                }
+
+                let is_media_type = content_type
+                    .split_once('/')
+                    .is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.trim().is_empty());
+                let content_type = HeaderValue::from_str(content_type)
+                    .ok()
+                    .filter(|_| is_media_type)
+                    .ok_or_else(|| {
+                        anyhow!("invalid content type {content_type:?} of .{extension}")
+                    })?;
+                Ok((extension, content_type))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// Content type of the extension of a path, `None` when the route doesn't define it
+    pub fn get(&self, path: &Path) -> Option<&HeaderValue> {
+        self.0.get(&extension(path)?)
+    }
+}
+
+/// Content type of a file from its extension: the one defined by the route, or the default one
+pub fn content_type(path: &Path, mime_types: Option<&MimeTypes>) -> HeaderValue {
+    match mime_types.and_then(|types| types.get(path)) {
+        Some(content_type) => content_type.clone(),
+        None => HeaderValue::from_static(default_content_type(path)),
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()?.to_str().map(str::to_ascii_lowercase)
+}
+
+fn default_content_type(path: &Path) -> &'static str {
+    match extension(path).as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mime_types(types: &[(&str, &str)]) -> Result<MimeTypes> {
+        let types = types
+            .iter()
+            .map(|(extension, content_type)| (extension.to_string(), content_type.to_string()))
+            .collect();
+        MimeTypes::from_config(&types)
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Path::new("index.HTML"), None),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(Path::new("module.wasm"), None),
+            "application/wasm"
+        );
+        assert_eq!(
+            content_type(Path::new("LICENSE"), None),
+            "application/octet-stream"
+        );
+
+        let types = mime_types(&[
+            ("wasm", "application/x-custom-wasm"),
+            (".GLB", "model/gltf-binary"),
+        ])
+        .unwrap();
+        assert_eq!(
+            content_type(Path::new("module.wasm"), Some(&types)),
+            "application/x-custom-wasm"
+        );
+        assert_eq!(
+            content_type(Path::new("/models/car.glb"), Some(&types)),
+            "model/gltf-binary"
+        );
+        assert_eq!(
+            content_type(Path::new("app.js"), Some(&types)),
+            "text/javascript; charset=utf-8"
+        );
+        assert!(types.get(Path::new("app.js")).is_none());
+    }
+
+    #[test]
+    fn test_invalid_mime_types() {
+        assert!(mime_types(&[("", "text/plain")]).is_err());
+        assert!(mime_types(&[("tar.gz", "application/gzip")]).is_err());
+        assert!(mime_types(&[("txt", "plain")]).is_err());
+        assert!(mime_types(&[("txt", "text/plain\n")]).is_err());
+    }
+}
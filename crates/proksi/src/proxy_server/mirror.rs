@@ -0,0 +1,265 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderName, Method};
+use once_cell::sync::Lazy;
+use pingora::http::RequestHeader;
+use tokio::sync::Semaphore;
+
+use crate::{config::RouteMirror, metrics};
+
+/// Redirects of the shadow upstreams are not followed, their responses are discarded anyway
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+});
+
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_TIMEOUT: u64 = 5;
+const DEFAULT_MAX_IN_FLIGHT: usize = 100;
+
+/// Headers of the connection with the client, the copies have their own
+const HOP_BY_HOP_HEADERS: [HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::CONTENT_LENGTH,
+];
+
+/// The shadow upstream of a route, receiving a copy of `percentage`% of its requests
+#[derive(Debug)]
+pub struct Mirror {
+    url: reqwest::Url,
+    percentage: u32,
+    include_body: bool,
+    max_body_size: usize,
+    timeout: Duration,
+    /// Copies waiting for the shadow, so that a slow shadow can't pile them up
+    in_flight: Arc<Semaphore>,
+}
+
+/// The copy of a request, sent once its body was received
+pub struct MirrorRequest {
+    mirror: Arc<Mirror>,
+    method: Method,
+    path_and_query: String,
+    headers: HeaderMap,
+    body: BytesMut,
+    /// Set while a part of the body was received without its end
+    pending_body: bool,
+    too_large: bool,
+}
+
+impl Mirror {
+    pub fn from_config(config: &RouteMirror) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(&config.url)
+            .map_err(|err| anyhow!("url {} is invalid: {err}", config.url))?;
+        if !["http", "https"].contains(&url.scheme()) {
+            return Err(anyhow!("url must be an http or https URL"));
+        }
+        if config.percentage > 100 {
+            return Err(anyhow!("percentage must be from 0 to 100"));
+        }
+
+        Ok(Self {
+            url,
+            percentage: u32::from(config.percentage),
+            include_body: config.include_body,
+            max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT)),
+            in_flight: Arc::new(Semaphore::new(
+                config.max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+            )),
+        })
+    }
+
+    /// The copy of the request, for the sampled ones
+    pub fn copy(self: &Arc<Self>, req: &RequestHeader) -> Option<MirrorRequest> {
+        let sampled = (uuid::Uuid::new_v4().as_u128() % 100) < u128::from(self.percentage);
+        if !sampled {
+            return None;
+        }
+
+        // The original host is kept, the shadow gets the requests as the upstreams do
+        let mut headers = req.headers.clone();
+        for name in &HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        if !headers.contains_key(header::HOST) {
+            if let Some(host) = req.uri.host() {
+                headers.insert(header::HOST, host.parse().ok()?);
+            }
+        }
+
+        Some(MirrorRequest {
+            mirror: Arc::clone(self),
+            method: req.method.clone(),
+            path_and_query: req
+                .uri
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_string(),
+            headers,
+            body: BytesMut::new(),
+            pending_body: false,
+            too_large: false,
+        })
+    }
+}
+
+impl MirrorRequest {
+    /// Keeps a part of the body of the request, when the bodies are copied
+    pub fn push(&mut self, data: Option<&Bytes>, end_of_stream: bool) {
+        self.pending_body = !end_of_stream;
+        if !self.mirror.include_body || self.too_large {
+            return;
+        }
+
+        let data = data.map_or(&[][..], |d| d.as_ref());
+        if self.body.len() + data.len() > self.mirror.max_body_size {
+            self.too_large = true;
+            self.body = BytesMut::new();
+            return;
+        }
+        self.body.extend_from_slice(data);
+    }
+
+    /// Whether the whole body was received (requests without a body included)
+    pub fn is_complete(&self) -> bool {
+        !self.pending_body
+    }
+
+    /// Sends the copy in the background, its response is discarded
+    pub fn send(self, host: &str) {
+        let count = |result: &str| {
+            metrics::MIRROR_REQUESTS
+                .with_label_values(&[host, result])
+                .inc();
+        };
+        if self.too_large {
+            count("too_large");
+            return;
+        }
+        let Ok(permit) = Arc::clone(&self.mirror.in_flight).try_acquire_owned() else {
+            count("dropped");
+            return;
+        };
+        let Ok(url) = self.mirror.url.join(&self.path_and_query) else {
+            count("error");
+            return;
+        };
+
+        let mut request = HTTP_CLIENT
+            .request(self.method, url)
+            .headers(self.headers)
+            .timeout(self.mirror.timeout);
+        if self.mirror.include_body {
+            request = request.body(self.body.freeze());
+        }
+
+        let host = host.to_string();
+        tokio::spawn(async move {
+            let result = match request.send().await {
+                Ok(response) => format!("{}xx", response.status().as_u16() / 100),
+                Err(err) => {
+                    tracing::debug!("failed to send the copy of a request of {host}: {err}");
+                    "error".to_string()
+                }
+            };
+            metrics::MIRROR_REQUESTS
+                .with_label_values(&[host.as_str(), result.as_str()])
+                .inc();
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(percentage: u8, include_body: bool) -> Arc<Mirror> {
+        let config = RouteMirror {
+            url: "http://127.0.0.1:4000".to_string(),
+            percentage,
+            include_body,
+            max_body_size: Some(8),
+            timeout_secs: None,
+            max_in_flight: None,
+        };
+        Arc::new(Mirror::from_config(&config).unwrap())
+    }
+
+    #[test]
+    fn test_sampling() {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(mirror(0, false).copy(&req).is_none());
+        assert!(mirror(100, false).copy(&req).is_some());
+
+        let half = mirror(50, false);
+        let copies = (0..1000).filter(|_| half.copy(&req).is_some()).count();
+        assert!((400..600).contains(&copies), "{copies} copies");
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut req = RequestHeader::build("POST", b"/api/users?page=2", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        req.insert_header("connection", "keep-alive").unwrap();
+        req.insert_header("content-length", "12").unwrap();
+        req.insert_header("x-request-id", "42").unwrap();
+
+        let copy = mirror(100, true).copy(&req).unwrap();
+        assert_eq!(copy.method, Method::POST);
+        assert_eq!(copy.path_and_query, "/api/users?page=2");
+        assert_eq!(copy.headers["host"], "example.com");
+        assert_eq!(copy.headers["x-request-id"], "42");
+        assert!(!copy.headers.contains_key("connection"));
+        assert!(!copy.headers.contains_key("content-length"));
+    }
+
+    #[test]
+    fn test_body() {
+        let req = RequestHeader::build("POST", b"/", None).unwrap();
+
+        let mut copy = mirror(100, true).copy(&req).unwrap();
+        copy.push(Some(&Bytes::from_static(b"{\"a\"")), false);
+        assert!(!copy.is_complete());
+        copy.push(Some(&Bytes::from_static(b":1}")), true);
+        assert!(copy.is_complete());
+        assert_eq!(&copy.body[..], b"{\"a\":1}");
+
+        // Larger bodies are not copied
+        copy.push(Some(&Bytes::from_static(b"1234")), true);
+        assert!(copy.too_large);
+        assert!(copy.body.is_empty());
+
+        // Nor kept when they are not copied
+        let mut copy = mirror(100, false).copy(&req).unwrap();
+        copy.push(Some(&Bytes::from_static(b"{}")), true);
+        assert!(copy.body.is_empty() && !copy.too_large);
+    }
+
+    #[test]
+    fn test_invalid_mirror() {
+        let mut config = RouteMirror {
+            url: "ftp://127.0.0.1".to_string(),
+            percentage: 10,
+            include_body: false,
+            max_body_size: None,
+            timeout_secs: None,
+            max_in_flight: None,
+        };
+        assert!(Mirror::from_config(&config).is_err());
+
+        config.url = "http://127.0.0.1:4000".to_string();
+        config.percentage = 101;
+        assert!(Mirror::from_config(&config).is_err());
+    }
+}
@@ -2,13 +2,56 @@ use std::{collections::BTreeMap, time::Duration};
 
 use pingora::{
     protocols::{TcpKeepalive, ALPN},
+    services::Service,
     upstreams::peer::PeerOptions,
 };
 
+use crate::config::{ConfigListener, ListenerProtocol, RouteUpstream, UpstreamPool};
+
+pub mod body_logging;
+pub mod canary;
+pub mod capture;
 pub mod cert_store;
+pub mod client_certificates;
+pub mod client_ip;
+pub mod compression;
+pub mod connection_limits;
+pub mod connection_metrics;
+pub mod connections;
+pub mod error_pages;
+pub mod etag;
+pub mod grpc;
+pub mod header_rules;
 pub mod http_proxy;
 pub mod https_proxy;
+pub mod limits;
+pub mod methods;
 pub mod middleware;
+pub mod mime_types;
+pub mod mirror;
+pub mod proxy_protocol;
+pub mod recent_errors;
+pub mod revocation;
+pub mod session_tickets;
+pub mod slow_clients;
+pub mod static_files;
+pub mod sticky_sessions;
+pub mod streaming;
+pub mod strict_parsing;
+pub mod tap;
+pub mod tcp_proxy;
+pub mod tls_fingerprint;
+pub mod trace_context;
+pub mod udp_proxy;
+pub mod websocket;
+
+/// Creates the services proxying the connections (or datagrams) of a layer 4 listener
+pub fn listener_services(listener: &ConfigListener) -> anyhow::Result<Vec<Box<dyn Service>>> {
+    match listener.protocol {
+        ListenerProtocol::Tcp => tcp_proxy::tcp_proxy_services(listener),
+        ListenerProtocol::Udp => Ok(vec![Box::new(udp_proxy::UdpProxyService::new(listener)?)]),
+    }
+}
 
 /// Default peer options to be used on every upstream connection
 pub fn default_peer_opts() -> PeerOptions {
@@ -42,3 +85,149 @@ pub fn default_peer_opts() -> PeerOptions {
     po.custom_l4 = None;
     po
 }
+
+/// Peer options of the connections to an upstream, with the HTTP version it's configured with
+/// and the time its connections stay in the pool
+pub fn upstream_peer_opts(upstream: &RouteUpstream, pool: &UpstreamPool) -> PeerOptions {
+    let mut po = default_peer_opts();
+    po.idle_timeout = Some(Duration::from_secs(pool.idle_timeout));
+    if let Some(protocol) = upstream.protocol {
+        po.alpn = protocol.into();
+    }
+    po
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use pingora::{
+        connectors::{http::Connector, ConnectorOptions},
+        http::RequestHeader,
+        protocols::http::client::HttpSession,
+        upstreams::peer::HttpPeer,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::config::UpstreamProtocol;
+
+    use super::*;
+
+    /// Opens a connection to a plaintext upstream like the proxy does. Returns whether it
+    /// speaks HTTP/2, and whether the upstream received the HTTP/2 preface (h2c).
+    async fn connect(protocol: Option<UpstreamProtocol>) -> (bool, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = RouteUpstream {
+            protocol,
+            ..Default::default()
+        };
+        let mut peer = HttpPeer::new(listener.local_addr().unwrap(), false, String::new());
+        peer.options = upstream_peer_opts(&upstream, &UpstreamPool::default());
+
+        let (session, _) = Connector::new(None).get_http_session(&peer).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut preface = [0; 24];
+        let read =
+            tokio::time::timeout(Duration::from_millis(200), stream.read_exact(&mut preface)).await;
+
+        let h2c = read.is_ok() && preface.as_slice() == b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        (matches!(session, HttpSession::H2(_)), h2c)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_protocol() {
+        // Plaintext upstreams can't negotiate HTTP/2, they get HTTP/1.1 unless it's forced
+        assert_eq!(connect(None).await, (false, false));
+        assert_eq!(connect(Some(UpstreamProtocol::Auto)).await, (false, false));
+        assert_eq!(connect(Some(UpstreamProtocol::Http1)).await, (false, false));
+        assert_eq!(connect(Some(UpstreamProtocol::H2)).await, (true, true));
+
+        // TLS upstreams offer the versions they are configured with
+        let pool = UpstreamPool::default();
+        let upstream = |protocol| RouteUpstream {
+            protocol: Some(protocol),
+            ..Default::default()
+        };
+        assert!(matches!(
+            upstream_peer_opts(&RouteUpstream::default(), &pool).alpn,
+            ALPN::H2H1
+        ));
+        assert!(matches!(
+            upstream_peer_opts(&upstream(UpstreamProtocol::Http1), &pool).alpn,
+            ALPN::H1
+        ));
+        assert!(matches!(
+            upstream_peer_opts(&upstream(UpstreamProtocol::H2), &pool).alpn,
+            ALPN::H2
+        ));
+    }
+
+    /// An upstream answering `204` to every request, and counting its connections
+    async fn no_content_upstream() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while matches!(stream.read(&mut buf).await, Ok(read) if read > 0) {
+                        let response = b"HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, connections)
+    }
+
+    /// Sends a request like the proxy does, then releases its connection to the pool.
+    /// Returns whether the connection was reused.
+    async fn request(connector: &Connector, peer: &HttpPeer) -> bool {
+        let (mut session, reused) = connector.get_http_session(peer).await.unwrap();
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        session.write_request_header(Box::new(req)).await.unwrap();
+        session.read_response_header().await.unwrap();
+        connector
+            .release_http_session(session, peer, peer.options.idle_timeout)
+            .await;
+        reused
+    }
+
+    #[tokio::test]
+    async fn test_upstream_pool() {
+        let (addr, connections) = no_content_upstream().await;
+        let pool = UpstreamPool {
+            size: 1,
+            idle_timeout: 1,
+        };
+        let mut peer = HttpPeer::new(addr, false, String::new());
+        peer.options = upstream_peer_opts(&RouteUpstream::default(), &pool);
+        assert_eq!(peer.options.idle_timeout, Some(Duration::from_secs(1)));
+
+        let connector = Connector::new(Some(ConnectorOptions {
+            keepalive_pool_size: pool.size,
+            ..ConnectorOptions::new(1)
+        }));
+        assert!(!request(&connector, &peer).await);
+        assert!(request(&connector, &peer).await);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // Closed once idle for longer than the idle timeout of the pool
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!request(&connector, &peer).await);
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+}
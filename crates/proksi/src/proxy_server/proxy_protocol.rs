@@ -0,0 +1,306 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use pingora::{connectors::L4Connect, protocols::l4::socket::SocketAddr as PeerAddr, OrErr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::config::ProxyProtocolVersion;
+
+/// Signature of the binary (v2) header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, including the final `\r\n`
+const V1_MAX_LENGTH: usize = 107;
+
+/// Addresses of the original connection, `None` when the balancer didn't relay
+/// a connection (e.g. its own health checks)
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    fn local() -> Self {
+        Self {
+            source: None,
+            destination: None,
+        }
+    }
+}
+
+/// Reads the PROXY protocol header (v1 or v2) at the start of the stream, without
+/// consuming anything after it
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<ProxyHeader> {
+    // The shortest header (`PROXY UNKNOWN\r\n`) is longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        bail!("missing PROXY protocol header");
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> anyhow::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).context("invalid PROXY protocol v1 header")?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::local()),
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> anyhow::Result<SocketAddr> {
+                Ok(SocketAddr::new(ip.parse::<IpAddr>()?, port.parse::<u16>()?))
+            };
+
+            Ok(ProxyHeader {
+                source: Some(address(source, source_port)?),
+                destination: Some(address(destination, destination_port)?),
+            })
+        }
+        _ => bail!("invalid PROXY protocol v1 header"),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<ProxyHeader> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        bail!("invalid PROXY protocol v2 version");
+    }
+
+    // LOCAL command, or a protocol other than IPv4/IPv6 (e.g. unix sockets)
+    if version_command & 0x0F == 0 {
+        return Ok(ProxyHeader::local());
+    }
+
+    let (source, destination) = match family >> 4 {
+        0x1 if length >= 12 => {
+            let ip = |offset: usize| {
+                let octets: [u8; 4] = addresses[offset..offset + 4].try_into().unwrap();
+                IpAddr::from(octets)
+            };
+            let port =
+                |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+            (
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )
+        }
+        0x2 if length >= 36 => {
+            let ip = |offset: usize| {
+                let octets: [u8; 16] = addresses[offset..offset + 16].try_into().unwrap();
+                IpAddr::from(octets)
+            };
+            let port =
+                |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+            (
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )
+        }
+        0x0 | 0x3 => return Ok(ProxyHeader::local()),
+        _ => bail!("invalid PROXY protocol v2 addresses"),
+    };
+
+    Ok(ProxyHeader {
+        source: Some(source),
+        destination: Some(destination),
+    })
+}
+
+/// Encodes the header sent to the upstreams, for a TCP connection from `source` to `destination`
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    // Both addresses have to be of the same family
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (to_ipv6(source), destination),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (source, to_ipv6(destination)),
+        _ => (source, destination),
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            header.push(0x21);
+
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    // TCP over IPv4
+                    header.push(0x11);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    // TCP over IPv6
+                    header.push(0x21);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&ipv6_octets(src));
+                    header.extend_from_slice(&ipv6_octets(dst));
+                }
+            }
+
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(ipv6_octets(addr.ip()).into()), addr.port())
+}
+
+/// Connects to the upstream and sends the PROXY protocol header, before the TLS
+/// handshake (if any) is made by pingora
+#[derive(Debug)]
+pub struct ProxyProtocolConnect {
+    pub header: Vec<u8>,
+}
+
+#[async_trait]
+impl L4Connect for ProxyProtocolConnect {
+    async fn connect(
+        &self,
+        addr: &PeerAddr,
+    ) -> pingora::Result<pingora::protocols::l4::stream::Stream> {
+        let Some(addr) = addr.as_inet() else {
+            return Err(pingora::Error::explain(
+                pingora::ErrorType::SocketError,
+                "PROXY protocol requires an inet upstream",
+            ));
+        };
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.or_err(
+            pingora::ErrorType::ConnectError,
+            "failed to connect to upstream",
+        )?;
+        stream.write_all(&self.header).await.or_err(
+            pingora::ErrorType::WriteError,
+            "failed to send PROXY protocol header",
+        )?;
+
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1";
+        let header = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(header.source, Some(addr("192.168.0.1:56324")));
+        assert_eq!(header.destination, Some(addr("10.0.0.1:443")));
+        // nothing after the header is consumed
+        assert_eq!(stream, b"GET / HTTP/1.1");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            ProxyHeader::local()
+        );
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_round_trip() {
+        let source = addr("192.168.0.1:56324");
+        let destination = addr("10.0.0.1:5432");
+
+        let mut encoded = encode_header(ProxyProtocolVersion::V2, source, destination);
+        encoded.extend_from_slice(b"data");
+        let mut stream = encoded.as_slice();
+
+        let header = read_header(&mut stream).await.unwrap();
+        assert_eq!(header.source, Some(source));
+        assert_eq!(header.destination, Some(destination));
+        assert_eq!(stream, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_connect_sends_header() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = PeerAddr::Inet(listener.local_addr().unwrap());
+
+        let connect = ProxyProtocolConnect {
+            header: b"PROXY UNKNOWN\r\n".to_vec(),
+        };
+        let _stream = connect.connect(&upstream_addr).await.unwrap();
+
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut received = [0u8; 15];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"PROXY UNKNOWN\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_mixed_families() {
+        let source = addr("[2001:db8::1]:56324");
+        let destination = addr("10.0.0.1:5432");
+
+        let encoded = encode_header(ProxyProtocolVersion::V1, source, destination);
+        assert_eq!(
+            encoded,
+            b"PROXY TCP6 2001:db8::1 ::ffff:10.0.0.1 56324 5432\r\n"
+        );
+
+        let encoded = encode_header(ProxyProtocolVersion::V2, source, destination);
+        let header = read_header(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!(header.source, Some(source));
+        assert_eq!(header.destination, Some(addr("[::ffff:10.0.0.1]:5432")));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Errors kept for the admin API (`/errors`) and its dashboard, the oldest are dropped first
+const MAX_RECENT_ERRORS: usize = 100;
+
+static RECENT_ERRORS: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+
+/// A request answered with a 5xx status, or that failed to be proxied
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    /// Unix timestamp, in seconds
+    pub at: u64,
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    /// `0` when no response was sent (e.g. the client disconnected)
+    pub status: u16,
+    pub upstream: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn record(
+    host: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    upstream: Option<&String>,
+    error: Option<&pingora::Error>,
+) {
+    let error = RecentError {
+        at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        host: host.to_string(),
+        method: method.to_string(),
+        path: path.to_string(),
+        status,
+        upstream: upstream.cloned(),
+        error: error.map(ToString::to_string),
+    };
+
+    if let Ok(mut errors) = RECENT_ERRORS.lock() {
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+}
+
+/// The recent errors, the newest first
+pub fn list() -> Vec<RecentError> {
+    RECENT_ERRORS
+        .lock()
+        .map(|errors| errors.iter().rev().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_errors() {
+        let error = pingora::Error::new(pingora::ErrorType::ConnectRefused);
+        for index in 0..=MAX_RECENT_ERRORS {
+            let path = format!("/{index}");
+            record("errors.localhost", "GET", &path, 502, None, Some(&error));
+        }
+
+        let errors = list();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].path, format!("/{MAX_RECENT_ERRORS}"));
+        assert_eq!(errors[MAX_RECENT_ERRORS - 1].path, "/1");
+        assert!(errors[0].at > 0);
+        assert!(errors[0]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.contains("ConnectRefused")));
+    }
+}
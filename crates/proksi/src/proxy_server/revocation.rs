@@ -0,0 +1,422 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use http::header::CONTENT_TYPE;
+use once_cell::sync::Lazy;
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus},
+    stack::Stack,
+    x509::{store::X509StoreBuilder, CrlStatus, X509Crl, X509Ref, X509VerifyResult, X509},
+};
+
+use crate::{
+    config::{RevocationPolicy, RouteClientCertificateRevocation},
+    metrics,
+};
+
+use super::client_certificates::ClientCertificate;
+
+/// Most OCSP statuses cached, they are forgotten all at once past it
+const MAX_OCSP_STATUSES: usize = 10_000;
+
+/// How long an unknown status is cached (e.g. the responder is down), before asking again
+const UNKNOWN_STATUS_CACHE: Duration = Duration::from_secs(30);
+
+/// Tolerated drift of the clock of the OCSP responders, in seconds
+const OCSP_CLOCK_LEEWAY_SECS: u32 = 300;
+
+const BEGIN_CRL: &str = "-----BEGIN X509 CRL-----";
+
+/// HTTP client of the OCSP requests, their timeout is the one of their route
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// CRL files of the routes, by path. They are read again when they change (see
+/// `reload_crls`).
+static CRL_FILES: Lazy<papaya::HashMap<PathBuf, Arc<CrlFile>>> = Lazy::new(papaya::HashMap::new);
+
+/// Statuses given by the OCSP responders, by certificate fingerprint
+static OCSP_STATUSES: Lazy<papaya::HashMap<String, CachedStatus>> = Lazy::new(papaya::HashMap::new);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RevocationStatus {
+    Good,
+    Revoked,
+    /// No valid CRL of the issuer, or no answer of the OCSP responder
+    Unknown,
+}
+
+impl RevocationStatus {
+    fn label(self) -> &'static str {
+        match self {
+            RevocationStatus::Good => "good",
+            RevocationStatus::Revoked => "revoked",
+            RevocationStatus::Unknown => "unknown",
+        }
+    }
+}
+
+struct CrlFile {
+    modified: Option<SystemTime>,
+    /// CA certificates the CRLs are verified with, again when they are reloaded
+    ca: Vec<X509>,
+    crls: Vec<X509Crl>,
+}
+
+#[derive(Clone, Copy)]
+struct CachedStatus {
+    status: RevocationStatus,
+    expires_at: Instant,
+}
+
+/// Revocation checks of the client certificates of a route: with CRLs during the
+/// handshake, with OCSP for each request
+#[derive(Debug)]
+pub struct Revocation {
+    crl: Vec<PathBuf>,
+    ocsp: bool,
+    ocsp_timeout: Duration,
+    ocsp_cache: Duration,
+    policy: RevocationPolicy,
+}
+
+impl Revocation {
+    /// Loads the CRL files, which must be signed by one of the CA certificates
+    pub fn from_config(
+        config: &RouteClientCertificateRevocation,
+        ca: &[X509],
+    ) -> anyhow::Result<Self> {
+        if config.crl.is_empty() && !config.ocsp {
+            return Err(anyhow!("revocation needs crl files or ocsp"));
+        }
+        if config.ocsp_timeout_secs == 0 {
+            return Err(anyhow!(
+                "revocation.ocsp_timeout_secs must be greater than 0"
+            ));
+        }
+
+        for path in &config.crl {
+            let file = load_crl_file(path, ca)?;
+            CRL_FILES.pin().insert(path.clone(), Arc::new(file));
+        }
+
+        Ok(Revocation {
+            crl: config.crl.clone(),
+            ocsp: config.ocsp,
+            ocsp_timeout: Duration::from_secs(config.ocsp_timeout_secs),
+            ocsp_cache: Duration::from_secs(config.ocsp_cache_secs),
+            policy: config.policy,
+        })
+    }
+
+    /// Whether the certificate verified during the handshake is accepted by the CRLs of
+    /// its issuer (always without CRL files)
+    pub fn check_crl(&self, certificate: &X509Ref) -> bool {
+        if self.crl.is_empty() {
+            return true;
+        }
+        self.accepts("crl", crl_status(&self.crl, certificate))
+    }
+
+    /// Whether the certificate is accepted by the OCSP responder of its issuer (always
+    /// without OCSP)
+    pub async fn check_ocsp(&self, certificate: &ClientCertificate, ca: &[X509]) -> bool {
+        if !self.ocsp {
+            return true;
+        }
+
+        let now = Instant::now();
+        let cached = OCSP_STATUSES
+            .pin()
+            .get(&certificate.fingerprint)
+            .filter(|cached| cached.expires_at > now)
+            .copied();
+        if let Some(cached) = cached {
+            return self.accepts("ocsp", cached.status);
+        }
+
+        let status = self
+            .ocsp_status(certificate, ca)
+            .await
+            .unwrap_or_else(|err| {
+                tracing::warn!(
+                    "failed to check the OCSP status of the client certificate {}: {err:#}",
+                    certificate.fingerprint
+                );
+                RevocationStatus::Unknown
+            });
+
+        let statuses = OCSP_STATUSES.pin();
+        if statuses.len() >= MAX_OCSP_STATUSES {
+            statuses.clear();
+        }
+        let cache = match status {
+            RevocationStatus::Unknown => UNKNOWN_STATUS_CACHE.min(self.ocsp_cache),
+            _ => self.ocsp_cache,
+        };
+        statuses.insert(
+            certificate.fingerprint.clone(),
+            CachedStatus {
+                status,
+                expires_at: now + cache,
+            },
+        );
+
+        self.accepts("ocsp", status)
+    }
+
+    fn accepts(&self, method: &'static str, status: RevocationStatus) -> bool {
+        metrics::CLIENT_CERTIFICATE_REVOCATION_CHECKS
+            .with_label_values(&[method, status.label()])
+            .inc();
+
+        match status {
+            RevocationStatus::Good => true,
+            RevocationStatus::Revoked => false,
+            RevocationStatus::Unknown => self.policy == RevocationPolicy::FailOpen,
+        }
+    }
+
+    /// Asks the OCSP responder of the certificate (from its Authority Information Access)
+    async fn ocsp_status(
+        &self,
+        certificate: &ClientCertificate,
+        ca: &[X509],
+    ) -> anyhow::Result<RevocationStatus> {
+        let pem = certificate
+            .pem
+            .as_deref()
+            .ok_or_else(|| anyhow!("the certificate isn't known by this instance"))?;
+        let leaf = X509::from_pem(pem.as_bytes())?;
+        let issuer = ca
+            .iter()
+            .find(|ca| ca.issued(&leaf) == X509VerifyResult::OK)
+            .ok_or_else(|| anyhow!("the certificate isn't issued by one of the CA certificates"))?;
+        let url = leaf
+            .ocsp_responders()?
+            .iter()
+            .next()
+            .map(ToString::to_string)
+            .ok_or_else(|| anyhow!("the certificate has no OCSP responder"))?;
+
+        let request = {
+            let mut request = OcspRequest::new()?;
+            request.add_id(OcspCertId::from_cert(MessageDigest::sha1(), &leaf, issuer)?)?;
+            request.to_der()?
+        };
+        let response = HTTP_CLIENT
+            .post(&url)
+            .header(CONTENT_TYPE, "application/ocsp-request")
+            .timeout(self.ocsp_timeout)
+            .body(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        parse_ocsp_response(&response, &leaf, issuer, ca)
+    }
+}
+
+/// The status of the certificate in the CRLs of its issuer. A revoked certificate stays
+/// revoked, but a CRL past its next update can't tell that a certificate is still good.
+fn crl_status(paths: &[PathBuf], certificate: &X509Ref) -> RevocationStatus {
+    let files = CRL_FILES.pin();
+    let now = Asn1Time::days_from_now(0).ok();
+    let mut status = RevocationStatus::Unknown;
+
+    let crls = paths
+        .iter()
+        .filter_map(|path| files.get(path))
+        .flat_map(|file| file.crls.iter());
+    for crl in crls {
+        if crl.issuer_name().try_cmp(certificate.issuer_name()).ok() != Some(Ordering::Equal) {
+            continue;
+        }
+
+        let expired = match (crl.next_update(), now.as_ref()) {
+            (Some(next_update), Some(now)) => !matches!(
+                next_update.compare(now),
+                Ok(Ordering::Greater | Ordering::Equal)
+            ),
+            _ => false,
+        };
+        match crl.get_by_serial(certificate.serial_number()) {
+            CrlStatus::Revoked(_) => return RevocationStatus::Revoked,
+            _ if !expired => status = RevocationStatus::Good,
+            _ => {}
+        }
+    }
+
+    status
+}
+
+fn parse_ocsp_response(
+    der: &[u8],
+    leaf: &X509Ref,
+    issuer: &X509Ref,
+    ca: &[X509],
+) -> anyhow::Result<RevocationStatus> {
+    let response = OcspResponse::from_der(der)?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(anyhow!(
+            "the OCSP responder answered with the status {}",
+            response.status().as_raw()
+        ));
+    }
+
+    // Signed by the CA, or by a responder it issued
+    let basic = response.basic()?;
+    let mut store = X509StoreBuilder::new()?;
+    for ca in ca {
+        store.add_cert(ca.clone())?;
+    }
+    let certs = Stack::<X509>::new()?;
+    basic
+        .verify(&certs, &store.build(), OcspFlag::empty())
+        .context("invalid signature of the OCSP response")?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?;
+    let status = basic
+        .find_status(&id)
+        .ok_or_else(|| anyhow!("the OCSP response has no status of the certificate"))?;
+    status
+        .check_validity(OCSP_CLOCK_LEEWAY_SECS, None)
+        .context("the OCSP response expired")?;
+
+    Ok(match status.status {
+        OcspCertStatus::GOOD => RevocationStatus::Good,
+        OcspCertStatus::REVOKED => RevocationStatus::Revoked,
+        _ => RevocationStatus::Unknown,
+    })
+}
+
+fn load_crl_file(path: &Path, ca: &[X509]) -> anyhow::Result<CrlFile> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let content =
+        std::fs::read(path).with_context(|| format!("failed to read the CRL {path:?}"))?;
+    let crls = parse_crls(&content).with_context(|| format!("{path:?} is not a CRL"))?;
+
+    for crl in &crls {
+        let signed = ca.iter().any(|ca| {
+            ca.subject_name().try_cmp(crl.issuer_name()).ok() == Some(Ordering::Equal)
+                && ca
+                    .public_key()
+                    .and_then(|key| crl.verify(&key))
+                    .unwrap_or(false)
+        });
+        if !signed {
+            return Err(anyhow!(
+                "a CRL of {path:?} isn't signed by one of the CA certificates"
+            ));
+        }
+    }
+
+    Ok(CrlFile {
+        modified,
+        ca: ca.to_vec(),
+        crls,
+    })
+}
+
+/// The CRLs of a PEM file, or the CRL of a DER file
+fn parse_crls(content: &[u8]) -> anyhow::Result<Vec<X509Crl>> {
+    let pem = std::str::from_utf8(content)
+        .ok()
+        .filter(|text| text.contains(BEGIN_CRL));
+    let Some(pem) = pem else {
+        return Ok(vec![X509Crl::from_der(content)?]);
+    };
+
+    pem.match_indices(BEGIN_CRL)
+        .map(|(start, _)| X509Crl::from_pem(pem[start..].as_bytes()).map_err(Into::into))
+        .collect()
+}
+
+/// Reads the CRL files again when they were modified. A file that fails to load keeps
+/// its previous CRLs.
+pub fn reload_crls() {
+    let files = CRL_FILES.pin();
+    let changed = files
+        .iter()
+        .filter(|(path, file)| {
+            std::fs::metadata(path).and_then(|m| m.modified()).ok() != file.modified
+        })
+        .map(|(path, file)| (path.clone(), file.clone()))
+        .collect::<Vec<_>>();
+
+    for (path, file) in changed {
+        match load_crl_file(&path, &file.ca) {
+            Ok(reloaded) => {
+                files.insert(path.clone(), Arc::new(reloaded));
+                tracing::info!("reloaded the CRL {path:?}");
+            }
+            Err(err) => tracing::error!("failed to reload the CRL {path:?}: {err:#}"),
+        }
+    }
+}
+
+/// Whether routes have CRL files to reload
+pub fn has_crls() -> bool {
+    !CRL_FILES.pin().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{pkey::PKey, rsa::Rsa, x509::X509NameBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_crls() {
+        assert!(parse_crls(b"").is_err());
+        assert!(parse_crls(b"-----BEGIN X509 CRL-----\nAQID\n-----END X509 CRL-----\n").is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let revocation = |policy| Revocation {
+            crl: vec![],
+            ocsp: true,
+            ocsp_timeout: Duration::from_secs(5),
+            ocsp_cache: Duration::from_secs(3600),
+            policy,
+        };
+
+        let fail_closed = revocation(RevocationPolicy::FailClosed);
+        assert!(fail_closed.accepts("ocsp", RevocationStatus::Good));
+        assert!(!fail_closed.accepts("ocsp", RevocationStatus::Revoked));
+        assert!(!fail_closed.accepts("ocsp", RevocationStatus::Unknown));
+
+        let fail_open = revocation(RevocationPolicy::FailOpen);
+        assert!(!fail_open.accepts("ocsp", RevocationStatus::Revoked));
+        assert!(fail_open.accepts("ocsp", RevocationStatus::Unknown));
+    }
+
+    #[test]
+    fn test_unknown_crl_status() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "client").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        // Without a CRL of its issuer
+        assert_eq!(
+            crl_status(&[PathBuf::from("/missing.crl")], &certificate),
+            RevocationStatus::Unknown
+        );
+    }
+}
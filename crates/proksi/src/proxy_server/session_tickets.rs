@@ -0,0 +1,340 @@
+use std::{
+    ffi::{c_int, c_uchar},
+    ptr, slice,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use openssl::{
+    base64::{decode_block, encode_block},
+    rand::rand_bytes,
+    ssl::{SslContextBuilder, SslOptions},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SessionTickets;
+
+/// `SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB`, not exported by `openssl-sys`
+const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+
+/// Time a new shared key waits before encrypting tickets, so that the other instances
+/// read it from the store first and accept its tickets
+pub const SHARED_KEY_ACTIVATION_SECS: u64 = 60;
+
+type TicketKeyCallback = unsafe extern "C" fn(
+    *mut openssl_sys::SSL,
+    *mut c_uchar,
+    *mut c_uchar,
+    *mut openssl_sys::EVP_CIPHER_CTX,
+    *mut openssl_sys::HMAC_CTX,
+    c_int,
+) -> c_int;
+
+/// Keys of the session tickets, the newest first
+static TICKET_KEYS: Lazy<ArcSwap<Vec<TicketKey>>> = Lazy::new(|| ArcSwap::from_pointee(vec![]));
+
+/// A key encrypting (AES-256-CBC) and authenticating (HMAC-SHA256) session tickets
+#[derive(Clone, PartialEq, Eq)]
+pub struct TicketKey {
+    /// Sent along with the tickets to find the key decrypting them
+    pub name: [u8; 16],
+    hmac_key: [u8; 32],
+    aes_key: [u8; 32],
+    /// Unix timestamp from which the key encrypts the new tickets
+    pub activates_at: u64,
+}
+
+impl std::fmt::Debug for TicketKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKey")
+            .field("name", &encode_block(&self.name))
+            .field("activates_at", &self.activates_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A ticket key as kept in the store
+#[derive(Serialize, Deserialize)]
+struct SerializableTicketKey {
+    name: String,
+    hmac_key: String,
+    aes_key: String,
+    activates_at: u64,
+}
+
+impl TicketKey {
+    pub fn generate(activates_at: u64) -> anyhow::Result<Self> {
+        let mut key = TicketKey {
+            name: [0; 16],
+            hmac_key: [0; 32],
+            aes_key: [0; 32],
+            activates_at,
+        };
+        rand_bytes(&mut key.name)?;
+        rand_bytes(&mut key.hmac_key)?;
+        rand_bytes(&mut key.aes_key)?;
+        Ok(key)
+    }
+
+    fn to_serializable(&self) -> SerializableTicketKey {
+        SerializableTicketKey {
+            name: encode_block(&self.name),
+            hmac_key: encode_block(&self.hmac_key),
+            aes_key: encode_block(&self.aes_key),
+            activates_at: self.activates_at,
+        }
+    }
+
+    fn from_serializable(key: &SerializableTicketKey) -> anyhow::Result<Self> {
+        let bytes = |value: &str| decode_block(value).map_err(|err| anyhow!("{err}"));
+        Ok(TicketKey {
+            name: bytes(&key.name)?
+                .try_into()
+                .map_err(|_| anyhow!("the name of a ticket key must be 16 bytes"))?,
+            hmac_key: bytes(&key.hmac_key)?
+                .try_into()
+                .map_err(|_| anyhow!("the HMAC key of a ticket key must be 32 bytes"))?,
+            aes_key: bytes(&key.aes_key)?
+                .try_into()
+                .map_err(|_| anyhow!("the AES key of a ticket key must be 32 bytes"))?,
+            activates_at: key.activates_at,
+        })
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The keys currently used, the newest first
+pub fn keys() -> Arc<Vec<TicketKey>> {
+    TICKET_KEYS.load_full()
+}
+
+pub fn set_keys(mut keys: Vec<TicketKey>) {
+    keys.sort_by(|a, b| b.activates_at.cmp(&a.activates_at));
+    TICKET_KEYS.store(Arc::new(keys));
+}
+
+pub fn serialize_keys(keys: &[TicketKey]) -> anyhow::Result<String> {
+    let keys = keys
+        .iter()
+        .map(TicketKey::to_serializable)
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_string(&keys)?)
+}
+
+pub fn deserialize_keys(keys: &str) -> anyhow::Result<Vec<TicketKey>> {
+    serde_json::from_str::<Vec<SerializableTicketKey>>(keys)?
+        .iter()
+        .map(TicketKey::from_serializable)
+        .collect()
+}
+
+/// The key encrypting the new tickets: the newest key that is active
+fn encryption_key(keys: &[TicketKey], now: u64) -> Option<&TicketKey> {
+    keys.iter().find(|key| key.activates_at <= now)
+}
+
+/// Whether a new key is due: none was created in the last `interval` seconds
+pub fn rotation_due(keys: &[TicketKey], now: u64, interval: u64) -> bool {
+    keys.first()
+        .is_none_or(|newest| newest.activates_at.saturating_add(interval) <= now)
+}
+
+/// The keys with a new one activating at `activates_at`, without the keys that stopped
+/// encrypting tickets more than `interval` seconds ago. A key encrypts for `interval`
+/// seconds and decrypts for as long after, then it's forgotten (forward secrecy).
+pub fn rotate(
+    keys: &[TicketKey],
+    now: u64,
+    interval: u64,
+    activates_at: u64,
+) -> anyhow::Result<Vec<TicketKey>> {
+    let mut rotated = vec![TicketKey::generate(activates_at)?];
+    rotated.extend(keys.iter().cloned());
+    rotated.sort_by(|a, b| b.activates_at.cmp(&a.activates_at));
+
+    // A key is replaced when the next one activates
+    let mut replaced_at = u64::MAX;
+    rotated.retain(|key| {
+        let kept = replaced_at.saturating_add(interval) > now;
+        replaced_at = key.activates_at;
+        kept
+    });
+
+    Ok(rotated)
+}
+
+/// Sets the keys of the session tickets of the TLS listener, or disables the tickets.
+/// Without shared keys, the first key is created right away.
+pub fn configure(builder: &mut SslContextBuilder, config: &SessionTickets) -> anyhow::Result<()> {
+    if !config.enabled {
+        builder.set_options(SslOptions::NO_TICKET);
+        return Ok(());
+    }
+
+    if !config.shared && TICKET_KEYS.load().is_empty() {
+        set_keys(rotate(&[], unix_now(), config.rotation_interval_secs, 0)?);
+    }
+
+    // SAFETY: the callback has the signature expected by `SSL_CTX_set_tlsext_ticket_key_cb`
+    unsafe {
+        openssl_sys::SSL_CTX_callback_ctrl__fixed_rust(
+            builder.as_ptr(),
+            SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB,
+            Some(std::mem::transmute::<
+                TicketKeyCallback,
+                unsafe extern "C" fn(),
+            >(ticket_key_callback)),
+        );
+    }
+
+    Ok(())
+}
+
+/// Sets up the encryption of a new ticket (`enc` = 1) or the decryption of a ticket sent
+/// by a client. Returns 0 to issue no ticket (no key is active yet) or to reject the ticket
+/// (unknown key), 2 for a ticket that is valid but renewed (its key no longer encrypts).
+///
+/// <https://docs.openssl.org/3.4/man3/SSL_CTX_set_tlsext_ticket_key_cb/>
+unsafe extern "C" fn ticket_key_callback(
+    _ssl: *mut openssl_sys::SSL,
+    key_name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher: *mut openssl_sys::EVP_CIPHER_CTX,
+    hmac: *mut openssl_sys::HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let keys = TICKET_KEYS.load();
+    let now = unix_now();
+    let current = encryption_key(&keys, now);
+
+    let key = match enc {
+        1 => {
+            let Some(key) = current else {
+                return 0;
+            };
+            ptr::copy_nonoverlapping(key.name.as_ptr(), key_name, key.name.len());
+            if openssl_sys::RAND_bytes(iv, 16) != 1 {
+                return -1;
+            }
+            key
+        }
+        _ => {
+            let name = slice::from_raw_parts(key_name, 16);
+            match keys.iter().find(|key| key.name == name) {
+                Some(key) => key,
+                None => return 0,
+            }
+        }
+    };
+
+    if openssl_sys::HMAC_Init_ex(
+        hmac,
+        key.hmac_key.as_ptr().cast(),
+        key.hmac_key.len() as c_int,
+        openssl_sys::EVP_sha256(),
+        ptr::null_mut(),
+    ) != 1
+    {
+        return -1;
+    }
+
+    let initialized = match enc {
+        1 => openssl_sys::EVP_EncryptInit_ex(
+            cipher,
+            openssl_sys::EVP_aes_256_cbc(),
+            ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        ),
+        _ => openssl_sys::EVP_DecryptInit_ex(
+            cipher,
+            openssl_sys::EVP_aes_256_cbc(),
+            ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        ),
+    };
+    if initialized != 1 {
+        return -1;
+    }
+
+    match enc == 1 || current.is_some_and(|current| current.name == key.name) {
+        true => 1,
+        false => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_keys() {
+        let interval = 3600;
+        let keys = rotate(&[], 1_000, interval, 1_000).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(!rotation_due(&keys, 1_000 + interval - 1, interval));
+        assert!(rotation_due(&keys, 1_000 + interval, interval));
+
+        // The previous key decrypts the tickets it encrypted for another interval
+        let now = 1_000 + interval;
+        let keys = rotate(&keys, now, interval, now).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(encryption_key(&keys, now).unwrap().name, keys[0].name);
+
+        let now = now + interval;
+        let previous = keys[0].clone();
+        let keys = rotate(&keys, now, interval, now).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1], previous);
+    }
+
+    #[test]
+    fn test_rotate_shared_keys() {
+        let interval = 3600;
+        let now = 10_000;
+        let keys = rotate(&[], now, interval, now).unwrap();
+
+        // A shared key is created ahead, the current key still encrypts until it activates
+        let keys = rotate(
+            &keys,
+            now + interval,
+            interval,
+            now + interval + SHARED_KEY_ACTIVATION_SECS,
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            encryption_key(&keys, now + interval).unwrap().name,
+            keys[1].name
+        );
+        assert_eq!(
+            encryption_key(&keys, now + interval + SHARED_KEY_ACTIVATION_SECS)
+                .unwrap()
+                .name,
+            keys[0].name
+        );
+        assert!(!rotation_due(&keys, now + interval + 1, interval));
+    }
+
+    #[test]
+    fn test_serialize_keys() {
+        let keys = rotate(&[], 1_000, 60, 1_000).unwrap();
+        let serialized = serialize_keys(&keys).unwrap();
+        assert_eq!(deserialize_keys(&serialized).unwrap(), keys);
+
+        assert!(deserialize_keys(
+            r#"[{"name":"AQID","hmac_key":"","aes_key":"","activates_at":0}]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use pingora::{Error, ErrorSource, ErrorType};
+
+use crate::config::SlowClients;
+
+/// Why a slow client was disconnected (also the `reason` label of the metric)
+#[derive(Debug, PartialEq)]
+pub enum SlowClientReason {
+    /// Nothing was received for longer than the read timeout
+    ReadTimeout,
+    /// The body took longer than the body timeout
+    BodyTimeout,
+    /// The body was received slower than the minimum rate
+    MinRate,
+}
+
+impl SlowClientReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SlowClientReason::ReadTimeout => "read_timeout",
+            SlowClientReason::BodyTimeout => "body_timeout",
+            SlowClientReason::MinRate => "min_rate",
+        }
+    }
+
+    /// Read timeouts are enforced by pingora, they are only found in the proxy errors
+    pub fn from_error(error: &Error) -> Option<Self> {
+        (error.etype() == &ErrorType::ReadTimedout && error.esource() == &ErrorSource::Downstream)
+            .then_some(SlowClientReason::ReadTimeout)
+    }
+}
+
+/// Measures how fast the body of a request is received
+pub struct BodyTimer {
+    body_timeout: Option<Duration>,
+    min_rate: Option<u64>,
+    min_rate_grace: Duration,
+    /// Set when the first chunk of the body is received
+    started_at: Option<Instant>,
+    received: u64,
+}
+
+impl BodyTimer {
+    /// Returns `None` if neither the body timeout nor the minimum rate are configured
+    pub fn new(config: &SlowClients) -> Option<Self> {
+        if config.body_timeout.is_none() && config.min_body_rate.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            body_timeout: config.body_timeout.map(Duration::from_secs),
+            min_rate: config.min_body_rate.filter(|rate| *rate > 0),
+            min_rate_grace: Duration::from_secs(config.min_body_rate_grace),
+            started_at: None,
+            received: 0,
+        })
+    }
+
+    /// Registers a chunk of the body, received at `now`
+    pub fn check(&mut self, chunk_size: usize, now: Instant) -> Result<(), SlowClientReason> {
+        let started_at = *self.started_at.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(started_at);
+        self.received += chunk_size as u64;
+
+        if self.body_timeout.is_some_and(|timeout| elapsed > timeout) {
+            return Err(SlowClientReason::BodyTimeout);
+        }
+
+        if let Some(min_rate) = self.min_rate {
+            if elapsed > self.min_rate_grace
+                && (self.received as f64 / elapsed.as_secs_f64()) < min_rate as f64
+            {
+                return Err(SlowClientReason::MinRate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(body_timeout: Option<u64>, min_body_rate: Option<u64>) -> SlowClients {
+        SlowClients {
+            read_timeout: None,
+            body_timeout,
+            min_body_rate,
+            min_body_rate_grace: 5,
+        }
+    }
+
+    #[test]
+    fn test_body_timeout() {
+        assert!(BodyTimer::new(&config(None, None)).is_none());
+
+        let mut timer = BodyTimer::new(&config(Some(10), None)).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(timer.check(1, start + Duration::from_secs(30)), Ok(()));
+        assert_eq!(timer.check(1, start + Duration::from_secs(40)), Ok(()));
+        assert_eq!(
+            timer.check(1, start + Duration::from_secs(41)),
+            Err(SlowClientReason::BodyTimeout)
+        );
+    }
+
+    #[test]
+    fn test_min_rate() {
+        let mut timer = BodyTimer::new(&config(None, Some(100))).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(timer.check(10, start), Ok(()));
+        // slow, but still within the grace period
+        assert_eq!(timer.check(10, start + Duration::from_secs(5)), Ok(()));
+        assert_eq!(timer.check(1000, start + Duration::from_secs(10)), Ok(()));
+        assert_eq!(
+            timer.check(10, start + Duration::from_secs(20)),
+            Err(SlowClientReason::MinRate)
+        );
+    }
+
+    #[test]
+    fn test_from_error() {
+        let mut error = Error::new(ErrorType::ReadTimedout);
+        assert_eq!(SlowClientReason::from_error(&error), None);
+
+        error.as_down();
+        assert_eq!(
+            SlowClientReason::from_error(&error),
+            Some(SlowClientReason::ReadTimeout)
+        );
+    }
+}
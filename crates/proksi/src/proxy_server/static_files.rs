@@ -0,0 +1,519 @@
+use std::{
+    fs::Metadata,
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use bytes::BytesMut;
+use http::{header, HeaderValue, Method, StatusCode};
+use httpdate::HttpDate;
+use percent_encoding::percent_decode_str;
+use pingora::{
+    http::ResponseHeader,
+    protocols::http::conditional_filter::{not_modified_filter, to_304},
+    proxy::Session,
+    ErrorType,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::{CompressionAlgorithm, RouteStaticFiles};
+
+use super::{
+    compression, error_pages, etag,
+    https_proxy::RouterContext,
+    mime_types::{self, MimeTypes},
+};
+
+/// File served for the paths of the directories
+const DEFAULT_INDEX: &str = "index.html";
+
+/// Bodies are read from the files and sent in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What a request path resolves to
+#[derive(Debug)]
+enum Resolved {
+    File(PathBuf, Metadata),
+    /// The path of a directory without its trailing slash, so that the relative links of its
+    /// index resolve from the directory
+    Redirect,
+    NotFound,
+}
+
+/// Part of a file requested with a `Range` header
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one that is ignored (several ranges, another unit or an invalid syntax)
+    Full,
+    /// First and last bytes of the range (inclusive)
+    Partial(u64, u64),
+    /// The range starts after the end of the file
+    Unsatisfiable,
+}
+
+/// Serves the file of the request path from the directory of the route. Only `GET` and
+/// `HEAD` requests are answered, the others get a `405`. A single byte range of the file can
+/// be requested (e.g. to seek in a video or resume a download).
+pub async fn serve(
+    session: &mut Session,
+    ctx: &RouterContext,
+    config: &RouteStaticFiles,
+    mime_types: Option<&MimeTypes>,
+) -> pingora::Result<()> {
+    let method = session.req_header().method.clone();
+    if method != Method::GET && method != Method::HEAD {
+        let mut res_headers =
+            ResponseHeader::build_no_case(StatusCode::METHOD_NOT_ALLOWED, Some(2))?;
+        res_headers.insert_header(header::ALLOW, "GET, HEAD")?;
+        res_headers.insert_header(header::CONTENT_LENGTH, 0)?;
+        return session
+            .write_response_header(Box::new(res_headers), true)
+            .await;
+    }
+
+    let uri = &session.req_header().uri;
+    let (path, metadata) = match resolve(config, uri.path()).await {
+        Resolved::File(path, metadata) => (path, metadata),
+        Resolved::Redirect => {
+            let location = match uri.query() {
+                Some(query) => format!("{}/?{query}", uri.path()),
+                None => format!("{}/", uri.path()),
+            };
+            let mut res_headers =
+                ResponseHeader::build_no_case(StatusCode::MOVED_PERMANENTLY, Some(2))?;
+            res_headers.insert_header(header::LOCATION, location)?;
+            res_headers.insert_header(header::CONTENT_LENGTH, 0)?;
+            return session
+                .write_response_header(Box::new(res_headers), true)
+                .await;
+        }
+        Resolved::NotFound => return error_pages::respond(session, ctx, 404).await,
+    };
+
+    let accept_encoding = session.req_header().headers.get(header::ACCEPT_ENCODING);
+    let content_type = mime_types::content_type(&path, mime_types);
+    let (path, metadata, encoding) =
+        match precompressed(&path, accept_encoding, &config.precompressed).await {
+            Some((path, metadata, algorithm)) => (path, metadata, Some(algorithm)),
+            None => (path, metadata, None),
+        };
+
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            tracing::debug!("failed to open the static file {path:?}: {err}");
+            let status = match err.kind() {
+                std::io::ErrorKind::NotFound => 404,
+                std::io::ErrorKind::PermissionDenied => 403,
+                _ => 500,
+            };
+            return error_pages::respond(session, ctx, status).await;
+        }
+    };
+
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag::file_etag(&metadata);
+
+    let mut res_headers = ResponseHeader::build_no_case(StatusCode::OK, Some(6))?;
+    res_headers.insert_header(header::CONTENT_TYPE, content_type)?;
+    res_headers.insert_header(header::ACCEPT_RANGES, "bytes")?;
+    if let Some(etag) = etag.as_deref() {
+        res_headers.insert_header(header::ETAG, etag)?;
+    }
+    if let Some(modified) = modified {
+        res_headers.insert_header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified))?;
+    }
+    if let Some(algorithm) = encoding {
+        res_headers.insert_header(header::CONTENT_ENCODING, algorithm.as_str())?;
+    }
+    // The same path is sent compressed or not depending on the client
+    if !config.precompressed.is_empty() {
+        compression::add_vary_header(&mut res_headers)?;
+    }
+
+    // The client already has the file (`If-None-Match` or `If-Modified-Since`)
+    if not_modified_filter(session.req_header(), &res_headers) {
+        to_304(&mut res_headers);
+        return session
+            .write_response_header(Box::new(res_headers), true)
+            .await;
+    }
+
+    let req_headers = &session.req_header().headers;
+    let if_range = req_headers.get(header::IF_RANGE);
+    let range = match req_headers.get(header::RANGE) {
+        Some(range)
+            if method == Method::GET && if_range_matches(if_range, etag.as_deref(), modified) =>
+        {
+            byte_range(range, len)
+        }
+        _ => ByteRange::Full,
+    };
+
+    let (first, mut remaining) = match range {
+        ByteRange::Full => (0, len),
+        ByteRange::Partial(first, last) => {
+            res_headers.set_status(StatusCode::PARTIAL_CONTENT)?;
+            res_headers
+                .insert_header(header::CONTENT_RANGE, format!("bytes {first}-{last}/{len}"))?;
+            (first, last - first + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut res_headers =
+                ResponseHeader::build_no_case(StatusCode::RANGE_NOT_SATISFIABLE, Some(2))?;
+            res_headers.insert_header(header::CONTENT_RANGE, format!("bytes */{len}"))?;
+            res_headers.insert_header(header::CONTENT_LENGTH, 0)?;
+            return session
+                .write_response_header(Box::new(res_headers), true)
+                .await;
+        }
+    };
+    res_headers.insert_header(header::CONTENT_LENGTH, remaining)?;
+    let end_of_stream = method == Method::HEAD || remaining == 0;
+    session
+        .write_response_header(Box::new(res_headers), end_of_stream)
+        .await?;
+    if end_of_stream {
+        return Ok(());
+    }
+
+    if first > 0 {
+        file.seek(SeekFrom::Start(first)).await.map_err(|err| {
+            pingora::Error::because(ErrorType::ReadError, "seeking in the static file", err)
+        })?;
+    }
+    // The file may have grown since its length was read
+    let mut file = file.take(remaining);
+
+    while remaining > 0 {
+        let mut chunk = BytesMut::with_capacity(CHUNK_SIZE.min(remaining as usize));
+        let read = file.read_buf(&mut chunk).await.map_err(|err| {
+            pingora::Error::because(ErrorType::ReadError, "reading the static file", err)
+        })?;
+        // The file was truncated after its length was sent
+        if read == 0 {
+            return pingora::Error::e_explain(ErrorType::ReadError, "static file truncated");
+        }
+
+        remaining -= read as u64;
+        session
+            .write_response_body(Some(chunk.freeze()), remaining == 0)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The range of a `Range` header (`bytes=0-499`, `bytes=500-` or `bytes=-500` for the last
+/// bytes). Several ranges are answered with the whole file.
+fn byte_range(range: &HeaderValue, len: u64) -> ByteRange {
+    let Some(spec) = range
+        .to_str()
+        .ok()
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .filter(|spec| !spec.contains(','))
+    else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let (first, last) = if start.is_empty() {
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(first) = start.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        match end.parse::<u64>() {
+            _ if end.is_empty() => (first, u64::MAX),
+            Ok(last) if last >= first => (first, last),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last.min(len - 1))
+}
+
+/// Whether the range of a request applies: it has no `If-Range`, or its `If-Range` is the
+/// entity tag or the date of the file (the file changed otherwise, and is sent whole)
+fn if_range_matches(
+    if_range: Option<&HeaderValue>,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> bool {
+    let Some(if_range) = if_range else {
+        return true;
+    };
+    // Entity tags are compared strongly, the weak ones never match
+    if if_range.as_bytes().starts_with(b"\"") || if_range.as_bytes().starts_with(b"W/") {
+        return etag.is_some_and(|etag| if_range.as_bytes() == etag.as_bytes());
+    }
+
+    let date = if_range
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse::<HttpDate>().ok());
+    match (date, modified) {
+        (Some(date), Some(modified)) => date == HttpDate::from(modified),
+        _ => false,
+    }
+}
+
+/// Resolves a request path to its file: the file itself, the index of a directory or the
+/// fallback of the route
+async fn resolve(config: &RouteStaticFiles, request_path: &str) -> Resolved {
+    let Some(relative_path) = relative_path(request_path) else {
+        return Resolved::NotFound;
+    };
+
+    let path = config.root.join(&relative_path);
+    match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => return Resolved::File(path, metadata),
+        Ok(metadata) if metadata.is_dir() => {
+            if !request_path.ends_with('/') {
+                return Resolved::Redirect;
+            }
+
+            let index = path.join(config.index.as_deref().unwrap_or(DEFAULT_INDEX));
+            if let Ok(metadata) = tokio::fs::metadata(&index).await {
+                if metadata.is_file() {
+                    return Resolved::File(index, metadata);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    // Assets are never replaced by the fallback, a missing script must not be an HTML page
+    let is_asset = relative_path.extension().is_some();
+    if let Some(fallback) = config.fallback.as_ref().filter(|_| !is_asset) {
+        let path = config.root.join(fallback);
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            if metadata.is_file() {
+                return Resolved::File(path, metadata);
+            }
+        }
+    }
+
+    Resolved::NotFound
+}
+
+/// The sibling of the file compressed with the preferred algorithm accepted by the client,
+/// e.g. `app.js.br` for `app.js`
+async fn precompressed(
+    path: &Path,
+    accept_encoding: Option<&HeaderValue>,
+    algorithms: &[CompressionAlgorithm],
+) -> Option<(PathBuf, Metadata, CompressionAlgorithm)> {
+    let mut candidates = algorithms.to_vec();
+    while let Some(algorithm) = compression::negotiate(accept_encoding, &candidates) {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(match algorithm {
+            CompressionAlgorithm::Gzip => ".gz",
+            CompressionAlgorithm::Brotli => ".br",
+            CompressionAlgorithm::Zstd => ".zst",
+        });
+
+        let sibling = PathBuf::from(sibling);
+        if let Ok(metadata) = tokio::fs::metadata(&sibling).await {
+            if metadata.is_file() {
+                return Some((sibling, metadata, algorithm));
+            }
+        }
+        candidates.retain(|candidate| *candidate != algorithm);
+    }
+
+    None
+}
+
+/// The decoded request path, relative to the root of the files. `None` for the paths that
+/// would leave the root (`..`).
+fn relative_path(request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    let mut relative_path = PathBuf::new();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(segment) => relative_path.push(segment),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(relative_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(root: &Path, fallback: Option<&str>) -> RouteStaticFiles {
+        RouteStaticFiles {
+            enabled: None,
+            root: root.to_path_buf(),
+            index: None,
+            fallback: fallback.map(PathBuf::from),
+            precompressed: vec![],
+        }
+    }
+
+    /// The file of a resolved path, `None` for a redirect or a 404
+    fn file_of(resolved: Resolved) -> Option<PathBuf> {
+        match resolved {
+            Resolved::File(path, _) => Some(path),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path("/assets/app%20v2.js"),
+            Some(PathBuf::from("assets/app v2.js"))
+        );
+        assert_eq!(relative_path("/./a//b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(relative_path("/"), Some(PathBuf::new()));
+        assert_eq!(relative_path("/../etc/passwd"), None);
+        assert_eq!(relative_path("/a/%2e%2e/%2e%2e/etc/passwd"), None);
+        assert_eq!(relative_path("/a%00.html"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "<html>").unwrap();
+        std::fs::write(root.join("app.js"), "app").unwrap();
+        std::fs::write(root.join("docs/index.html"), "<html>").unwrap();
+
+        let files = config(&root, None);
+        assert_eq!(
+            file_of(resolve(&files, "/app.js").await),
+            Some(root.join("app.js"))
+        );
+        assert_eq!(
+            file_of(resolve(&files, "/").await),
+            Some(root.join("index.html"))
+        );
+        assert!(matches!(resolve(&files, "/docs").await, Resolved::Redirect));
+        assert_eq!(
+            file_of(resolve(&files, "/docs/").await),
+            Some(root.join("docs/index.html"))
+        );
+        assert!(matches!(
+            resolve(&files, "/users/42").await,
+            Resolved::NotFound
+        ));
+
+        // Client-side routes get the entrypoint of the app, missing assets don't
+        let spa = config(&root, Some("index.html"));
+        assert_eq!(
+            file_of(resolve(&spa, "/users/42").await),
+            Some(root.join("index.html"))
+        );
+        assert!(matches!(
+            resolve(&spa, "/missing.js").await,
+            Resolved::NotFound
+        ));
+        assert!(matches!(
+            resolve(&spa, "/../secret").await,
+            Resolved::NotFound
+        ));
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_precompressed() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "app").unwrap();
+        std::fs::write(root.join("app.js.gz"), "gzip").unwrap();
+        std::fs::write(root.join("app.js.br"), "brotli").unwrap();
+        std::fs::write(root.join("style.css"), "style").unwrap();
+        std::fs::write(root.join("style.css.gz"), "gzip").unwrap();
+
+        let algorithms = [CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip];
+        let sibling = |file: &str, accept_encoding: Option<&'static str>| {
+            let path = root.join(file);
+            async move {
+                let accept_encoding = accept_encoding.map(HeaderValue::from_static);
+                precompressed(&path, accept_encoding.as_ref(), &algorithms)
+                    .await
+                    .map(|(path, _, algorithm)| (path, algorithm))
+            }
+        };
+
+        assert_eq!(
+            sibling("app.js", Some("gzip, deflate, br")).await,
+            Some((root.join("app.js.br"), CompressionAlgorithm::Brotli))
+        );
+        assert_eq!(
+            sibling("app.js", Some("gzip")).await,
+            Some((root.join("app.js.gz"), CompressionAlgorithm::Gzip))
+        );
+        // Brotli is preferred, but the file has no brotli sibling
+        assert_eq!(
+            sibling("style.css", Some("br, gzip")).await,
+            Some((root.join("style.css.gz"), CompressionAlgorithm::Gzip))
+        );
+        assert_eq!(sibling("style.css", Some("br")).await, None);
+        assert_eq!(sibling("app.js", Some("zstd")).await, None);
+        assert_eq!(sibling("app.js", None).await, None);
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let range = |value: &'static str| byte_range(&HeaderValue::from_static(value), 1000);
+        assert_eq!(range("bytes=0-499"), ByteRange::Partial(0, 499));
+        assert_eq!(range("bytes=500-"), ByteRange::Partial(500, 999));
+        assert_eq!(range("bytes=900-2000"), ByteRange::Partial(900, 999));
+        assert_eq!(range("bytes=-100"), ByteRange::Partial(900, 999));
+        assert_eq!(range("bytes=-2000"), ByteRange::Partial(0, 999));
+        assert_eq!(range("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,5-9"), ByteRange::Full);
+        assert_eq!(range("bytes=500-100"), ByteRange::Full);
+        assert_eq!(range("items=0-1"), ByteRange::Full);
+        assert_eq!(range("bytes=a-b"), ByteRange::Full);
+        assert_eq!(
+            byte_range(&HeaderValue::from_static("bytes=-10"), 0),
+            ByteRange::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let modified = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        let etag = Some("\"33a64df5\"");
+        let if_range = |value: &'static str| {
+            if_range_matches(Some(&HeaderValue::from_static(value)), etag, Some(modified))
+        };
+        assert!(if_range_matches(None, etag, Some(modified)));
+        assert!(if_range("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert!(!if_range("Wed, 21 Oct 2015 07:28:01 GMT"));
+        assert!(if_range("\"33a64df5\""));
+        assert!(!if_range("\"2c9c1e8f\""));
+        assert!(!if_range("W/\"33a64df5\""));
+        assert!(!if_range_matches(
+            Some(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+            None,
+            None
+        ));
+    }
+}
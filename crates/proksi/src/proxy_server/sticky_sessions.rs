@@ -0,0 +1,286 @@
+use std::{fmt::Write, time::Duration};
+
+use http::header;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sha::sha256, sign::Signer};
+use pingora::http::RequestHeader;
+
+use crate::{config::RouteStickySessions, stores::store_trait::Store};
+
+const DEFAULT_COOKIE: &str = "proksi_session";
+const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
+
+/// Hex characters of the signature of the session cookies (128 bits)
+const SIGNATURE_LEN: usize = 32;
+
+/// Key signing the session cookies, read from the store (or stored) on first use
+static SESSION_KEY: tokio::sync::OnceCell<Vec<u8>> = tokio::sync::OnceCell::const_new();
+
+/// The session of a request to a route with sticky sessions, kept in the request context.
+///
+/// The upstream of a session is in its cookie, signed with a key shared by the instances
+/// through the store: nothing is stored per client, and every instance sends a session to
+/// the same upstream.
+pub struct StickySession {
+    cookie: String,
+    idle_timeout: Duration,
+    /// Value of the session cookie of the request, verified with the key of the store
+    value: Option<String>,
+    /// Upstream (as a backend id) and expiry of the session, once verified
+    session: Option<(String, u64)>,
+    /// The cookie to send with the response, when the session is new, moved to another
+    /// upstream or extended
+    set_cookie: Option<String>,
+}
+
+impl StickySession {
+    /// The session of the cookie of the request, or a new one
+    pub fn from_request(req: &RequestHeader, config: &RouteStickySessions) -> Self {
+        let cookie = config.cookie.as_deref().unwrap_or(DEFAULT_COOKIE);
+
+        Self {
+            value: session_cookie(req, cookie).map(String::from),
+            cookie: cookie.to_string(),
+            idle_timeout: Duration::from_secs(config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT)),
+            session: None,
+            set_cookie: None,
+        }
+    }
+
+    /// The upstream of the session among the `addresses` of the route. Cookies that were
+    /// not signed for the route, or expired, are replaced by a new session.
+    pub async fn upstream(
+        &mut self,
+        store: &dyn Store,
+        host: &str,
+        mut addresses: impl Iterator<Item = String>,
+    ) -> Option<String> {
+        if let Some(value) = self.value.take() {
+            let key = session_key(store).await?;
+            self.session = verify(key, host, &value, unix_now());
+        }
+
+        let (id, _) = self.session.as_ref()?;
+        addresses.find(|address| backend_id(address) == *id)
+    }
+
+    /// Assigns the upstream to the session. The cookie is sent again when the upstream
+    /// changed, or at most every quarter of the idle timeout to extend the session.
+    pub async fn assign(&mut self, store: &dyn Store, host: &str, upstream: &str) {
+        let now = unix_now();
+        let id = backend_id(upstream);
+        let idle_timeout = self.idle_timeout.as_secs();
+        if self.session.as_ref().is_some_and(|(current, expires_at)| {
+            *current == id && expires_at.saturating_sub(now) > idle_timeout - idle_timeout / 4
+        }) {
+            return;
+        }
+
+        let Some(key) = session_key(store).await else {
+            return;
+        };
+        let expires_at = now + idle_timeout;
+        let Ok(value) = sign(key, host, &id, expires_at) else {
+            return;
+        };
+
+        self.set_cookie = Some(format!(
+            "{}={value}; Path=/; Max-Age={idle_timeout}; HttpOnly; Secure; SameSite=Lax",
+            self.cookie
+        ));
+        self.session = Some((id, expires_at));
+    }
+
+    /// The `Set-Cookie` header of a new, moved or extended session
+    pub fn set_cookie(&self) -> Option<String> {
+        self.set_cookie.clone()
+    }
+}
+
+/// The key signing the session cookies. The first instance stores a random key, the
+/// others read it.
+async fn session_key(store: &dyn Store) -> Option<&'static [u8]> {
+    let key = SESSION_KEY
+        .get_or_try_init(|| async {
+            let mut key = [0; 32];
+            openssl::rand::rand_bytes(&mut key).map_err(|err| err.to_string())?;
+
+            let key = store
+                .get_or_set_session_key(hex(&key))
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok::<_, String>(key.into_bytes())
+        })
+        .await;
+
+    match key {
+        Ok(key) => Some(key.as_slice()),
+        Err(err) => {
+            tracing::warn!("failed to read the key of the sticky sessions from the store: {err}");
+            None
+        }
+    }
+}
+
+/// Identifies an upstream in the cookies without revealing its address
+fn backend_id(address: &str) -> String {
+    hex(&sha256(address.as_bytes())[..8])
+}
+
+/// The cookie value of a session: the upstream, the expiry and their signature for the
+/// route (`{id}.{expires_at}.{signature}`)
+fn sign(key: &[u8], host: &str, id: &str, expires_at: u64) -> anyhow::Result<String> {
+    let session = format!("{id}.{expires_at}");
+    Ok(format!("{session}.{}", signature(key, host, &session)?))
+}
+
+/// The upstream and expiry of a cookie value, when it's signed for the route and did
+/// not expire
+fn verify(key: &[u8], host: &str, value: &str, now: u64) -> Option<(String, u64)> {
+    let (session, signature_) = value.rsplit_once('.')?;
+    let (id, expires_at) = session.split_once('.')?;
+    let expires_at = expires_at.parse::<u64>().ok()?;
+
+    let expected = signature(key, host, session).ok()?;
+    let valid = signature_.len() == expected.len()
+        && memcmp::eq(signature_.as_bytes(), expected.as_bytes());
+    (valid && expires_at > now).then(|| (id.to_string(), expires_at))
+}
+
+/// The signature covers the host, a session of a route is not valid for another one
+fn signature(key: &[u8], host: &str, session: &str) -> anyhow::Result<String> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(host.as_bytes())?;
+    signer.update(b"|")?;
+    signer.update(session.as_bytes())?;
+
+    let mut signature = hex(&signer.sign_to_vec()?);
+    signature.truncate(SIGNATURE_LEN);
+    Ok(signature)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+/// Value of the cookie `name` of the request
+fn session_cookie<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::MemoryStore;
+
+    use super::*;
+
+    const UPSTREAMS: [&str; 2] = ["10.0.0.1:80", "10.0.0.2:80"];
+
+    fn config() -> RouteStickySessions {
+        RouteStickySessions {
+            enabled: None,
+            cookie: Some("sid".to_string()),
+            idle_timeout: Some(60),
+        }
+    }
+
+    fn addresses() -> impl Iterator<Item = String> {
+        UPSTREAMS.into_iter().map(String::from)
+    }
+
+    fn request(cookie: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(header::COOKIE, format!("theme=dark; {cookie}"))
+            .unwrap();
+        req
+    }
+
+    /// The cookie pair of a `Set-Cookie` header
+    fn cookie_pair(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sticky_session() {
+        let store = MemoryStore::new();
+        let host = "sticky.localhost";
+
+        // No cookie, a new session is created
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut session = StickySession::from_request(&req, &config());
+        assert!(session.upstream(&store, host, addresses()).await.is_none());
+        session.assign(&store, host, UPSTREAMS[0]).await;
+        let set_cookie = session.set_cookie().unwrap();
+        assert!(set_cookie.starts_with("sid="));
+        assert!(set_cookie.contains("Max-Age=60; HttpOnly; Secure"));
+        assert!(!set_cookie.contains("10.0.0.1"));
+
+        // The next request of the client gets the same upstream, without a new cookie
+        let req = request(cookie_pair(&set_cookie));
+        let mut next = StickySession::from_request(&req, &config());
+        assert_eq!(
+            next.upstream(&store, host, addresses()).await.as_deref(),
+            Some(UPSTREAMS[0])
+        );
+        next.assign(&store, host, UPSTREAMS[0]).await;
+        assert!(next.set_cookie().is_none());
+
+        // Another upstream is assigned when the previous one is gone
+        let mut moved = StickySession::from_request(&req, &config());
+        assert!(moved
+            .upstream(&store, host, addresses().skip(1))
+            .await
+            .is_none());
+        moved.assign(&store, host, UPSTREAMS[1]).await;
+        let req = request(cookie_pair(&moved.set_cookie().unwrap()));
+        let mut last = StickySession::from_request(&req, &config());
+        assert_eq!(
+            last.upstream(&store, host, addresses()).await.as_deref(),
+            Some(UPSTREAMS[1])
+        );
+
+        // Sessions of the other routes are not valid
+        let mut other = StickySession::from_request(&req, &config());
+        assert!(other
+            .upstream(&store, "other.localhost", addresses())
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn test_signed_cookie() {
+        let key = b"0123456789abcdef0123456789abcdef";
+        let id = backend_id(UPSTREAMS[0]);
+        let value = sign(key, "a.localhost", &id, 1_000).unwrap();
+
+        assert_eq!(
+            verify(key, "a.localhost", &value, 999),
+            Some((id.clone(), 1_000))
+        );
+        // Expired, signed by another key, or changed by the client
+        assert!(verify(key, "a.localhost", &value, 1_000).is_none());
+        assert!(verify(b"another key", "a.localhost", &value, 999).is_none());
+        let forged = value.replacen(&id, &backend_id(UPSTREAMS[1]), 1);
+        assert!(verify(key, "a.localhost", &forged, 999).is_none());
+        let extended = value.replacen("1000", "9000", 1);
+        assert!(verify(key, "a.localhost", &extended, 999).is_none());
+        assert!(verify(key, "a.localhost", "a:b:c", 999).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use pingora::{http::ResponseHeader, upstreams::peer::PeerOptions};
+
+use crate::config::RouteStreaming;
+
+/// Default idle timeout of the streaming routes, in seconds
+const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
+
+/// Long-polling requests wait for an event, SSE streams can be quiet for a while
+pub fn extend_timeouts(options: &mut PeerOptions, config: &RouteStreaming) {
+    let idle_timeout = Duration::from_secs(config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+    options.read_timeout = Some(idle_timeout);
+    options.idle_timeout = Some(idle_timeout);
+}
+
+/// Proxies in front of proksi (e.g. nginx) are told not to buffer the events either
+pub fn disable_buffering(response: &mut ResponseHeader) -> pingora::Result<()> {
+    response.insert_header("x-accel-buffering", "no")
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use crate::proxy_server::default_peer_opts;
+
+    use super::*;
+
+    #[test]
+    fn test_extend_timeouts() {
+        let mut options = default_peer_opts();
+        let config = RouteStreaming {
+            enabled: None,
+            idle_timeout: None,
+        };
+        extend_timeouts(&mut options, &config);
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(3600)));
+        assert_eq!(options.idle_timeout, Some(Duration::from_secs(3600)));
+
+        let config = RouteStreaming {
+            enabled: None,
+            idle_timeout: Some(600),
+        };
+        extend_timeouts(&mut options, &config);
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(options.idle_timeout, Some(Duration::from_secs(600)));
+        // The connection itself is still bounded
+        assert_eq!(options.connection_timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_disable_buffering() {
+        let mut response = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        disable_buffering(&mut response).unwrap();
+        disable_buffering(&mut response).unwrap();
+
+        let values = response.headers.get_all("x-accel-buffering");
+        assert_eq!(values.iter().collect::<Vec<_>>(), ["no"]);
+    }
+}
@@ -0,0 +1,269 @@
+use http::header::HOST;
+use pingora::{http::RequestHeader, proxy::Session};
+
+use crate::{
+    config::{DuplicateHost, StrictParsingRules},
+    metrics,
+};
+
+/// A part of a request that proxies and upstreams could read differently
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Violation {
+    ConflictingLength,
+    ObsFold,
+    DuplicateHost,
+    TooManyHeaders,
+}
+
+impl Violation {
+    fn reason(self) -> &'static str {
+        match self {
+            Violation::ConflictingLength => "conflicting_length",
+            Violation::ObsFold => "obs_fold",
+            Violation::DuplicateHost => "duplicate_host",
+            Violation::TooManyHeaders => "too_many_headers",
+        }
+    }
+
+    fn status(self) -> u16 {
+        match self {
+            Violation::TooManyHeaders => 431,
+            _ => 400,
+        }
+    }
+}
+
+/// Checks the request of the session with the rules of its listener, and merges its
+/// identical `Host` headers. Returns whether the request was rejected (it was answered and
+/// its connection is closed).
+pub async fn reject_malformed(
+    session: &mut Session,
+    rules: &StrictParsingRules,
+    listener: &'static str,
+) -> pingora::Result<bool> {
+    if !rules.enabled {
+        return Ok(false);
+    }
+
+    // Pingora removes `Content-Length` when there's a `Transfer-Encoding`, and HTTP/2
+    // has neither obs-fold nor `Transfer-Encoding`: only the raw HTTP/1 headers have them
+    let raw_headers = session
+        .as_downstream()
+        .as_http1()
+        .map(|http1| http1.get_headers_raw_bytes());
+    let result = raw_headers
+        .map_or(Ok(()), |raw| check_raw_headers(&raw, rules))
+        .and_then(|()| check_headers(session.req_header_mut(), rules));
+    let Err(violation) = result else {
+        return Ok(false);
+    };
+
+    metrics::STRICT_PARSING_REJECTIONS
+        .with_label_values(&[listener, violation.reason()])
+        .inc();
+    tracing::warn!(
+        "rejected a request of the {listener} listener: {}",
+        violation.reason()
+    );
+
+    session.set_keepalive(None);
+    session.respond_error(violation.status()).await?;
+    Ok(true)
+}
+
+/// Checks the headers as they were sent, from the line after the request line to the
+/// empty line ending them
+fn check_raw_headers(raw: &[u8], rules: &StrictParsingRules) -> Result<(), Violation> {
+    let mut content_length = false;
+    let mut transfer_encodings = vec![];
+
+    for line in raw.split(|b| *b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let Some(first) = line.first() else {
+            break;
+        };
+
+        if matches!(first, b' ' | b'\t') {
+            if rules.reject_obs_fold {
+                return Err(Violation::ObsFold);
+            }
+            continue;
+        }
+
+        let Some(colon) = line.iter().position(|b| *b == b':') else {
+            continue;
+        };
+        let name = &line[..colon];
+        if name.eq_ignore_ascii_case(b"content-length") {
+            content_length = true;
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            transfer_encodings.push(&line[colon + 1..]);
+        }
+    }
+
+    if !rules.reject_conflicting_length || transfer_encodings.is_empty() {
+        return Ok(());
+    }
+
+    // The body only ends at the same place for every parser when it's chunked last
+    let last_coding = transfer_encodings
+        .iter()
+        .flat_map(|value| value.split(|b| *b == b','))
+        .map(<[u8]>::trim_ascii)
+        .filter(|coding| !coding.is_empty())
+        .last();
+    if content_length || !last_coding.is_some_and(|c| c.eq_ignore_ascii_case(b"chunked")) {
+        return Err(Violation::ConflictingLength);
+    }
+
+    Ok(())
+}
+
+fn check_headers(req: &mut RequestHeader, rules: &StrictParsingRules) -> Result<(), Violation> {
+    if req.headers.len() > rules.max_headers {
+        return Err(Violation::TooManyHeaders);
+    }
+
+    let hosts = req.headers.get_all(HOST).iter().collect::<Vec<_>>();
+    if hosts.len() < 2 {
+        return Ok(());
+    }
+
+    match rules.duplicate_host {
+        DuplicateHost::Allow => Ok(()),
+        DuplicateHost::Reject => Err(Violation::DuplicateHost),
+        DuplicateHost::Normalize => {
+            let host = hosts[0].clone();
+            if !hosts
+                .iter()
+                .all(|h| h.as_bytes().eq_ignore_ascii_case(host.as_bytes()))
+            {
+                return Err(Violation::DuplicateHost);
+            }
+
+            // Replaces every `Host` header
+            req.insert_header(HOST, host)
+                .map_err(|_| Violation::DuplicateHost)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(headers: &str) -> Vec<u8> {
+        format!("POST /upload HTTP/1.1\r\nHost: example.com\r\n{headers}\r\n").into_bytes()
+    }
+
+    #[test]
+    fn test_conflicting_length() {
+        let rules = StrictParsingRules::default();
+        assert_eq!(
+            check_raw_headers(&raw("Transfer-Encoding: chunked\r\n"), &rules),
+            Ok(())
+        );
+        assert_eq!(
+            check_raw_headers(&raw("Content-Length: 10\r\n"), &rules),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_raw_headers(
+                &raw("Content-Length: 10\r\nTransfer-Encoding: chunked\r\n"),
+                &rules
+            ),
+            Err(Violation::ConflictingLength)
+        );
+        assert_eq!(
+            check_raw_headers(&raw("transfer-encoding: chunked, identity\r\n"), &rules),
+            Err(Violation::ConflictingLength)
+        );
+        assert_eq!(
+            check_raw_headers(&raw("Transfer-Encoding: xchunked\r\n"), &rules),
+            Err(Violation::ConflictingLength)
+        );
+        assert_eq!(
+            check_raw_headers(
+                &raw("Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n"),
+                &rules
+            ),
+            Ok(())
+        );
+
+        let rules = StrictParsingRules {
+            reject_conflicting_length: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_raw_headers(
+                &raw("Content-Length: 10\r\nTransfer-Encoding: chunked\r\n"),
+                &rules
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_obs_fold() {
+        let rules = StrictParsingRules::default();
+        assert_eq!(
+            check_raw_headers(&raw("X-Custom: a\r\n b\r\n"), &rules),
+            Err(Violation::ObsFold)
+        );
+        // Only the headers are read, not the body after them
+        let mut request = raw("Content-Length: 3\r\n");
+        request.extend_from_slice(b" ab");
+        assert_eq!(check_raw_headers(&request, &rules), Ok(()));
+    }
+
+    #[test]
+    fn test_duplicate_host() {
+        let request = |hosts: &[&str]| {
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            for host in hosts {
+                req.append_header(HOST, *host).unwrap();
+            }
+            req
+        };
+        let rules = StrictParsingRules::default();
+
+        let mut req = request(&["example.com", "Example.com"]);
+        assert_eq!(check_headers(&mut req, &rules), Ok(()));
+        assert_eq!(req.headers.get_all(HOST).iter().count(), 1);
+
+        let mut req = request(&["example.com", "evil.com"]);
+        assert_eq!(
+            check_headers(&mut req, &rules),
+            Err(Violation::DuplicateHost)
+        );
+
+        let rules = StrictParsingRules {
+            duplicate_host: DuplicateHost::Reject,
+            ..Default::default()
+        };
+        let mut req = request(&["example.com", "example.com"]);
+        assert_eq!(
+            check_headers(&mut req, &rules),
+            Err(Violation::DuplicateHost)
+        );
+    }
+
+    #[test]
+    fn test_max_headers() {
+        let rules = StrictParsingRules {
+            max_headers: 2,
+            ..Default::default()
+        };
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header(HOST, "example.com").unwrap();
+        req.append_header("accept", "*/*").unwrap();
+        assert_eq!(check_headers(&mut req, &rules), Ok(()));
+
+        req.append_header("accept", "text/html").unwrap();
+        assert_eq!(
+            check_headers(&mut req, &rules),
+            Err(Violation::TooManyHeaders)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Requests a tap can fall behind by, it skips the ones it missed after that
+const TAP_CAPACITY: usize = 1024;
+
+static TAP: Lazy<broadcast::Sender<Arc<TapEvent>>> =
+    Lazy::new(|| broadcast::channel(TAP_CAPACITY).0);
+
+/// The summary of a request, sent to the taps of the admin API once its response was sent
+#[derive(Debug, Clone, Serialize)]
+pub struct TapEvent {
+    /// Unix timestamp, in milliseconds
+    pub at: u128,
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    /// `0` when no response was sent (e.g. the client disconnected)
+    pub status: u16,
+    pub duration_ms: u128,
+    pub upstream: Option<String>,
+    /// Result of the cache (`hit`, `miss`, `expired`...), for the routes with a cache
+    pub cache: Option<String>,
+}
+
+/// Whether an admin API client taps the requests, the summaries are only built then
+pub fn is_tapped() -> bool {
+    TAP.receiver_count() > 0
+}
+
+pub fn subscribe() -> broadcast::Receiver<Arc<TapEvent>> {
+    TAP.subscribe()
+}
+
+pub fn publish(
+    host: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    duration_ms: u128,
+    upstream: Option<&String>,
+    cache: Option<&str>,
+) {
+    let event = TapEvent {
+        at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default(),
+        host: host.to_string(),
+        method: method.to_string(),
+        path: path.to_string(),
+        status,
+        duration_ms,
+        upstream: upstream.cloned(),
+        cache: cache.map(ToString::to_string),
+    };
+    // The last tap may just have been closed
+    TAP.send(Arc::new(event)).ok();
+}
+
+/// The requests a tap receives, from the query of `GET /tap`
+#[derive(Debug, Default, PartialEq)]
+pub struct TapFilter {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    /// A status (`404`) or a status class (`5xx`)
+    pub status: Option<String>,
+    pub min_duration_ms: u128,
+    /// Share of the matching requests that are sent, from 0 to 100
+    pub percentage: u32,
+}
+
+impl TapFilter {
+    pub fn from_query(query: &str) -> anyhow::Result<Self> {
+        let mut filter = Self {
+            percentage: 100,
+            ..Self::default()
+        };
+        let url = reqwest::Url::parse(&format!("http://localhost/?{query}"))?;
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "host" => filter.host = Some(value.into_owned()),
+                "path_prefix" => filter.path_prefix = Some(value.into_owned()),
+                "status" => {
+                    let valid = value.len() == 3
+                        && (value.bytes().all(|b| b.is_ascii_digit())
+                            || (value.as_bytes()[0].is_ascii_digit() && &value[1..] == "xx"));
+                    if !valid {
+                        return Err(anyhow!("status must be a status (404) or a class (5xx)"));
+                    }
+                    filter.status = Some(value.into_owned());
+                }
+                "min_duration_ms" => {
+                    filter.min_duration_ms = value
+                        .parse()
+                        .map_err(|_| anyhow!("min_duration_ms must be a number"))?;
+                }
+                "percentage" => {
+                    filter.percentage = value
+                        .parse()
+                        .ok()
+                        .filter(|percentage| *percentage <= 100)
+                        .ok_or_else(|| anyhow!("percentage must be from 0 to 100"))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the request is sent to the tap, the matching ones are sampled
+    pub fn matches(&self, event: &TapEvent) -> bool {
+        if self.host.as_ref().is_some_and(|host| *host != event.host)
+            || self
+                .path_prefix
+                .as_ref()
+                .is_some_and(|prefix| !event.path.starts_with(prefix.as_str()))
+            || event.duration_ms < self.min_duration_ms
+        {
+            return false;
+        }
+
+        let status = event.status.to_string();
+        let status_matches = self.status.as_ref().is_none_or(|expected| {
+            expected == &status || (expected.ends_with("xx") && expected[..1] == status[..1])
+        });
+
+        status_matches && (uuid::Uuid::new_v4().as_u128() % 100) < u128::from(self.percentage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(host: &str, path: &str, status: u16, duration_ms: u128) -> TapEvent {
+        TapEvent {
+            at: 0,
+            host: host.to_string(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status,
+            duration_ms,
+            upstream: None,
+            cache: None,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter =
+            TapFilter::from_query("host=tap.localhost&path_prefix=%2Fapi&status=5xx").unwrap();
+        assert_eq!(filter.path_prefix.as_deref(), Some("/api"));
+        assert!(filter.matches(&event("tap.localhost", "/api/users", 503, 10)));
+        assert!(!filter.matches(&event("tap.localhost", "/api/users", 200, 10)));
+        assert!(!filter.matches(&event("tap.localhost", "/", 503, 10)));
+        assert!(!filter.matches(&event("other.localhost", "/api/users", 503, 10)));
+
+        let filter = TapFilter::from_query("status=404&min_duration_ms=100").unwrap();
+        assert!(filter.matches(&event("tap.localhost", "/", 404, 150)));
+        assert!(!filter.matches(&event("tap.localhost", "/", 404, 50)));
+
+        let filter = TapFilter::from_query("percentage=0").unwrap();
+        assert!(!filter.matches(&event("tap.localhost", "/", 200, 0)));
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        assert!(TapFilter::from_query("status=5x").is_err());
+        assert!(TapFilter::from_query("status=abc").is_err());
+        assert!(TapFilter::from_query("percentage=101").is_err());
+        assert!(TapFilter::from_query("min_duration_ms=fast").is_err());
+        assert_eq!(
+            TapFilter::from_query("").unwrap().percentage,
+            100,
+            "every request is sent by default"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let mut tap = subscribe();
+        assert!(is_tapped());
+        publish("tap.localhost", "GET", "/", 200, 3, None, Some("hit"));
+
+        // Requests of the other tests are published to the same taps
+        loop {
+            let event = tap.recv().await.unwrap();
+            if event.host == "tap.localhost" {
+                assert_eq!(event.cache.as_deref(), Some("hit"));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    connectors::TransportConnector,
+    lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer},
+    protocols::Stream,
+    server::ShutdownWatch,
+    services::{background::background_service, listening::Service as ListeningService, Service},
+    tls::ssl::{SslAcceptor, SslFiletype, SslMethod},
+    upstreams::peer::{HttpPeer, Peer, PeerOptions},
+};
+
+use crate::{
+    config::{ConfigListener, ListenerUpstreamTls, ProxyProtocolVersion, RouteSslPath},
+    services::drain::InFlight,
+};
+
+use super::proxy_protocol::{self, ProxyProtocolConnect};
+
+/// Time given to the load balancer in front of proksi to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Proxies the connections of a listener to its upstreams, as they are (layer 4)
+pub struct TcpProxy {
+    name: String,
+    load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    upstream_tls: Option<ListenerUpstreamTls>,
+    connect_timeout: Option<Duration>,
+    connector: TransportConnector,
+    /// Upstreams expecting a PROXY protocol header, by address
+    proxy_protocol: HashMap<SocketAddr, ProxyProtocolVersion>,
+    accept_proxy_protocol: bool,
+    /// TLS is terminated here (instead of pingora) when the connections start with
+    /// a PROXY protocol header, which comes before the handshake
+    tls_acceptor: Option<SslAcceptor>,
+}
+
+impl TcpProxy {
+    fn new(
+        listener: &ConfigListener,
+        load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    ) -> anyhow::Result<Self> {
+        let mut proxy_protocol = HashMap::new();
+        for upstream in &listener.upstreams {
+            if let Some(version) = upstream.proxy_protocol {
+                for addr in format!("{}:{}", upstream.ip, upstream.port).to_socket_addrs()? {
+                    proxy_protocol.insert(addr, version);
+                }
+            }
+        }
+
+        let tls_acceptor = match &listener.tls {
+            Some(tls) if listener.accept_proxy_protocol => Some(tls_acceptor(tls)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            name: listener.name.to_string(),
+            load_balancer,
+            upstream_tls: listener.upstream_tls.clone(),
+            connect_timeout: listener.connect_timeout.map(Duration::from_secs),
+            connector: TransportConnector::new(None),
+            proxy_protocol,
+            accept_proxy_protocol: listener.accept_proxy_protocol,
+            tls_acceptor,
+        })
+    }
+
+    /// Picks an upstream for a connection from `client_addr` to `local_addr`
+    fn peer(
+        &self,
+        client_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Option<HttpPeer> {
+        let backend = self.load_balancer.select(b"", 32)?;
+        let version = backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.proxy_protocol.get(addr))
+            .copied();
+
+        let sni = self
+            .upstream_tls
+            .as_ref()
+            .and_then(|tls| tls.sni.clone())
+            .unwrap_or_default();
+        let mut peer = HttpPeer::new(backend, self.upstream_tls.is_some(), sni);
+        // Not an HTTP connection, the timeouts of the HTTP upstreams don't apply
+        peer.options = PeerOptions::new();
+        peer.options.verify_cert = self.upstream_tls.as_ref().is_some_and(|tls| tls.verify);
+        peer.options.verify_hostname = peer.options.verify_cert;
+        if let Some(connect_timeout) = self.connect_timeout {
+            peer.options.connection_timeout = Some(connect_timeout);
+        }
+
+        if let (Some(version), Some(source), Some(destination)) = (version, client_addr, local_addr)
+        {
+            peer.options.custom_l4 = Some(Arc::new(ProxyProtocolConnect {
+                header: proxy_protocol::encode_header(version, source, destination),
+            }));
+        }
+
+        Some(peer)
+    }
+
+    /// Reads the PROXY protocol header and terminates TLS, when enabled. Returns the
+    /// stream and the address of the client
+    async fn accept(
+        &self,
+        mut downstream: Stream,
+        mut client_addr: Option<SocketAddr>,
+    ) -> anyhow::Result<(Stream, Option<SocketAddr>)> {
+        if !self.accept_proxy_protocol {
+            return Ok((downstream, client_addr));
+        }
+
+        let header = tokio::time::timeout(
+            PROXY_HEADER_TIMEOUT,
+            proxy_protocol::read_header(&mut downstream),
+        )
+        .await??;
+        client_addr = header.source.or(client_addr);
+
+        if let Some(acceptor) = self.tls_acceptor.as_ref() {
+            // The listener only accepts TCP connections when TLS is terminated here
+            let Ok(tcp_stream) = downstream
+                .into_any()
+                .downcast::<pingora::protocols::l4::stream::Stream>()
+            else {
+                anyhow::bail!("TLS can only be terminated on TCP connections");
+            };
+            let tls_stream =
+                pingora::protocols::tls::server::handshake(acceptor, *tcp_stream).await?;
+            downstream = Box::new(tls_stream);
+        }
+
+        Ok((downstream, client_addr))
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        downstream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let start = std::time::Instant::now();
+        // Drained by the graceful shutdowns until the connection is closed
+        let _in_flight = InFlight::new();
+        let digest = downstream.get_socket_digest();
+        let socket_addr = |addr: Option<&pingora::protocols::l4::socket::SocketAddr>| {
+            addr.and_then(|addr| addr.as_inet()).copied()
+        };
+        let client_addr = socket_addr(digest.as_ref().and_then(|d| d.peer_addr()));
+        let local_addr = socket_addr(digest.as_ref().and_then(|d| d.local_addr()));
+
+        let (mut downstream, client_addr) = match self.accept(downstream, client_addr).await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(
+                    listener = self.name,
+                    client_ip = client_addr.map(|addr| addr.to_string()),
+                    "failed to accept connection: {err}"
+                );
+                return None;
+            }
+        };
+        let client_ip = client_addr.map(|addr| addr.to_string()).unwrap_or_default();
+
+        let Some(peer) = self.peer(client_addr, local_addr) else {
+            tracing::error!(listener = self.name, client_ip, "no healthy upstream");
+            return None;
+        };
+
+        let mut upstream = match self.connector.new_stream(&peer).await {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                tracing::error!(
+                    listener = self.name,
+                    client_ip,
+                    peer_addr = peer.address().to_string(),
+                    "failed to connect to upstream: {err}"
+                );
+                return None;
+            }
+        };
+
+        let result = tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await;
+        let (bytes_received, bytes_sent) = result.as_ref().map_or((0, 0), |bytes| *bytes);
+
+        tracing::info!(
+            listener = self.name,
+            client_ip,
+            peer_addr = peer.address().to_string(),
+            duration_ms = start.elapsed().as_millis(),
+            bytes_received,
+            bytes_sent,
+            error = result.err().map(|err| err.to_string()),
+            access_log = true
+        );
+
+        // The connection is closed once one of the sides closed it
+        None
+    }
+}
+
+/// Creates the services of a listener: the listening service itself, and the background
+/// service running the health checks of its upstreams
+pub fn tcp_proxy_services(listener: &ConfigListener) -> anyhow::Result<Vec<Box<dyn Service>>> {
+    let upstreams = listener
+        .upstreams
+        .iter()
+        .map(|u| format!("{}:{}", u.ip, u.port));
+    let mut load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(upstreams)?;
+    load_balancer.set_health_check(TcpHealthCheck::new());
+    load_balancer.health_check_frequency =
+        Some(Duration::from_secs(listener.health_check_interval));
+
+    let health_check =
+        background_service(&format!("{} health check", listener.name), load_balancer);
+
+    let proxy = TcpProxy::new(listener, health_check.task())?;
+
+    let mut service = ListeningService::new(format!("{} listener", listener.name), proxy);
+    match &listener.tls {
+        Some(tls) if !listener.accept_proxy_protocol => service.add_tls(
+            &listener.address,
+            &tls.pem.to_string_lossy(),
+            &tls.key.to_string_lossy(),
+        )?,
+        _ => service.add_tcp(&listener.address),
+    }
+
+    Ok(vec![Box::new(service), Box::new(health_check)])
+}
+
+fn tls_acceptor(tls: &RouteSslPath) -> anyhow::Result<SslAcceptor> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_certificate_chain_file(&tls.pem)?;
+    acceptor.set_private_key_file(&tls.key, SslFiletype::PEM)?;
+    acceptor.check_private_key()?;
+
+    Ok(acceptor.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::config::{ListenerProtocol, ListenerUpstream};
+
+    use super::*;
+
+    fn listener(upstream_tls: Option<ListenerUpstreamTls>) -> ConfigListener {
+        ConfigListener {
+            name: Cow::Borrowed("postgres"),
+            address: Cow::Borrowed("127.0.0.1:15432"),
+            protocol: ListenerProtocol::Tcp,
+            upstreams: vec![ListenerUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port: 5432,
+                proxy_protocol: None,
+            }],
+            health_check_interval: 15,
+            connect_timeout: Some(3),
+            idle_timeout: None,
+            accept_proxy_protocol: false,
+            tls: None,
+            upstream_tls,
+        }
+    }
+
+    fn proxy(listener: &ConfigListener) -> TcpProxy {
+        let upstreams = listener
+            .upstreams
+            .iter()
+            .map(|u| format!("{}:{}", u.ip, u.port));
+
+        let load_balancer = LoadBalancer::try_from_iter(upstreams).unwrap();
+        TcpProxy::new(listener, Arc::new(load_balancer)).unwrap()
+    }
+
+    #[test]
+    fn test_plaintext_peer() {
+        let peer = proxy(&listener(None)).peer(None, None).unwrap();
+
+        assert!(!peer.tls());
+        assert!(peer.options.custom_l4.is_none());
+        assert_eq!(peer.address().to_string(), "127.0.0.1:5432");
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn test_tls_peer() {
+        let peer = proxy(&listener(Some(ListenerUpstreamTls {
+            sni: Some("db.internal".to_string()),
+            verify: true,
+        })))
+        .peer(None, None)
+        .unwrap();
+
+        assert!(peer.tls());
+        assert_eq!(peer.sni(), "db.internal");
+        assert!(peer.options.verify_cert);
+    }
+
+    #[test]
+    fn test_proxy_protocol_peer() {
+        let mut listener = listener(None);
+        listener.upstreams[0].proxy_protocol = Some(ProxyProtocolVersion::V1);
+        let proxy = proxy(&listener);
+
+        // the addresses of the connection are required
+        assert!(proxy.peer(None, None).unwrap().options.custom_l4.is_none());
+
+        let client_addr = "192.168.0.1:56324".parse().ok();
+        let local_addr = "127.0.0.1:15432".parse().ok();
+        let peer = proxy.peer(client_addr, local_addr).unwrap();
+        assert!(peer.options.custom_l4.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_accept_proxy_protocol() {
+        let mut listener = listener(None);
+        listener.accept_proxy_protocol = true;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = client;
+        tokio::io::AsyncWriteExt::write_all(
+            &mut client,
+            b"PROXY TCP4 192.168.0.1 127.0.0.1 56324 15432\r\nhello",
+        )
+        .await
+        .unwrap();
+
+        let (_, client_addr) = proxy(&listener)
+            .accept(Box::new(server), "10.0.0.1:40000".parse().ok())
+            .await
+            .unwrap();
+        assert_eq!(client_addr, "192.168.0.1:56324".parse().ok());
+    }
+
+    #[test]
+    fn test_services() {
+        assert_eq!(tcp_proxy_services(&listener(None)).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,405 @@
+use std::{fmt::Write, ptr, slice};
+
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::Lazy;
+use openssl::{
+    error::ErrorStack,
+    ex_data::Index,
+    hash::{hash, MessageDigest},
+    ssl::{ClientHelloResponse, Ssl, SslAlert, SslRef},
+};
+use pingora::proxy::Session;
+
+use crate::plugins::PLUGINS;
+
+use super::connection_metrics;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Fingerprints of the ClientHello of a connection, stored along with the connection
+static FINGERPRINT_INDEX: Lazy<Index<Ssl, TlsFingerprint>> =
+    Lazy::new(|| Ssl::new_ex_index().expect("failed to allocate the TLS fingerprint index"));
+
+/// Fingerprints of the TLS client of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFingerprint {
+    /// MD5 of the JA3 string (<https://github.com/salesforce/ja3>)
+    pub ja3: String,
+    /// JA4 fingerprint (<https://github.com/FoxIO-LLC/ja4>)
+    pub ja4: String,
+}
+
+/// Fields of a ClientHello used by the fingerprints (GREASE values excluded)
+#[derive(Debug, Default)]
+struct ClientHello {
+    legacy_version: u16,
+    ciphers: Vec<u16>,
+    /// In the order sent by the client
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    /// First protocol of the ALPN extension
+    alpn: Option<Vec<u8>>,
+    server_name: Option<String>,
+}
+
+/// Reserved values (`0x0a0a`, `0x1a1a`, ...) sent by clients to keep servers tolerant
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .filter(|value| !is_grease(*value))
+        .collect()
+}
+
+/// Returns the content of a vector prefixed by its length (on `size` bytes)
+fn length_prefixed(data: &[u8], size: usize) -> Option<(&[u8], &[u8])> {
+    let length = data
+        .get(..size)?
+        .iter()
+        .fold(0usize, |length, b| (length << 8) | usize::from(*b));
+    let end = size.checked_add(length)?;
+    Some((data.get(size..end)?, data.get(end..)?))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+/// First 12 characters of the SHA256 of a JA4 section
+fn ja4_hash(section: &str) -> String {
+    if section.is_empty() {
+        return "0".repeat(12);
+    }
+    hex(&openssl::sha::sha256(section.as_bytes()))[..12].to_string()
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+impl ClientHello {
+    /// Reads the ClientHello being processed (only available in the ClientHello callback)
+    fn from_ssl(ssl: &SslRef) -> Option<Self> {
+        let mut hello = ClientHello {
+            ciphers: u16_list(ssl.client_hello_ciphers()?),
+            ..Default::default()
+        };
+
+        // SAFETY: the ClientHello accessors are valid during the ClientHello callback,
+        // the extensions array is allocated by OpenSSL and freed right after being copied
+        unsafe {
+            let ssl_ptr = ssl.as_ptr();
+            hello.legacy_version =
+                u16::try_from(openssl_sys::SSL_client_hello_get0_legacy_version(ssl_ptr))
+                    .unwrap_or_default();
+
+            let mut extensions = ptr::null_mut();
+            let mut count = 0;
+            if openssl_sys::SSL_client_hello_get1_extensions_present(
+                ssl_ptr,
+                &mut extensions,
+                &mut count,
+            ) == 1
+            {
+                if !extensions.is_null() {
+                    hello.extensions = slice::from_raw_parts(extensions, count)
+                        .iter()
+                        .filter_map(|ext| u16::try_from(*ext).ok())
+                        .filter(|ext| !is_grease(*ext))
+                        .collect();
+                }
+                openssl_sys::OPENSSL_free(extensions.cast());
+            }
+        }
+
+        let extension = |ext_type: u16| -> Option<&[u8]> {
+            let mut data = ptr::null();
+            let mut length = 0;
+            // SAFETY: see above, the data is owned by the ClientHello (copied by the caller)
+            unsafe {
+                if openssl_sys::SSL_client_hello_get0_ext(
+                    ssl.as_ptr(),
+                    ext_type.into(),
+                    &mut data,
+                    &mut length,
+                ) != 1
+                    || data.is_null()
+                {
+                    return None;
+                }
+                Some(slice::from_raw_parts(data, length))
+            }
+        };
+
+        if let Some((groups, _)) =
+            extension(EXT_SUPPORTED_GROUPS).and_then(|d| length_prefixed(d, 2))
+        {
+            hello.groups = u16_list(groups);
+        }
+        if let Some((formats, _)) =
+            extension(EXT_EC_POINT_FORMATS).and_then(|d| length_prefixed(d, 1))
+        {
+            hello.point_formats = formats.to_vec();
+        }
+        if let Some((algorithms, _)) =
+            extension(EXT_SIGNATURE_ALGORITHMS).and_then(|d| length_prefixed(d, 2))
+        {
+            hello.signature_algorithms = u16_list(algorithms);
+        }
+        if let Some((versions, _)) =
+            extension(EXT_SUPPORTED_VERSIONS).and_then(|d| length_prefixed(d, 1))
+        {
+            hello.supported_versions = u16_list(versions);
+        }
+        hello.alpn = extension(EXT_ALPN)
+            .and_then(|d| length_prefixed(d, 2))
+            .and_then(|(protocols, _)| length_prefixed(protocols, 1))
+            .map(|(protocol, _)| protocol.to_vec());
+        hello.server_name = extension(EXT_SERVER_NAME).and_then(parse_server_name);
+
+        Some(hello)
+    }
+
+    /// `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+    fn ja3_string(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.legacy_version,
+            join(&self.ciphers, "-"),
+            join(&self.extensions, "-"),
+            join(&self.groups, "-"),
+            join(&self.point_formats, "-"),
+        )
+    }
+
+    fn ja3(&self) -> String {
+        hash(MessageDigest::md5(), self.ja3_string().as_bytes())
+            .map(|digest| hex(&digest))
+            .unwrap_or_default()
+    }
+
+    fn ja4(&self) -> String {
+        let version = self
+            .supported_versions
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(self.legacy_version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+
+        let destination = if self.extensions.contains(&EXT_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+
+        // First and last characters of the first protocol (their hex digits if not alphanumeric)
+        let alpn = match self.alpn.as_deref() {
+            Some(protocol) if !protocol.is_empty() => {
+                let (first, last) = (protocol[0], protocol[protocol.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let (first, last) = (hex(&[first]), hex(&[last]));
+                    format!("{}{}", &first[..1], &last[1..])
+                }
+            }
+            _ => "00".to_string(),
+        };
+
+        let mut ciphers = self
+            .ciphers
+            .iter()
+            .map(|c| format!("{c:04x}"))
+            .collect::<Vec<_>>();
+        ciphers.sort_unstable();
+
+        let mut extensions = self
+            .extensions
+            .iter()
+            .filter(|ext| **ext != EXT_SERVER_NAME && **ext != EXT_ALPN)
+            .map(|ext| format!("{ext:04x}"))
+            .collect::<Vec<_>>();
+        extensions.sort_unstable();
+
+        let mut extensions_section = extensions.join(",");
+        if !self.signature_algorithms.is_empty() && !extensions_section.is_empty() {
+            extensions_section.push('_');
+            extensions_section.push_str(
+                &self
+                    .signature_algorithms
+                    .iter()
+                    .map(|a| format!("{a:04x}"))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+        }
+
+        format!(
+            "t{version}{destination}{:02}{:02}{alpn}_{}_{}",
+            self.ciphers.len().min(99),
+            self.extensions.len().min(99),
+            ja4_hash(&ciphers.join(",")),
+            ja4_hash(&extensions_section),
+        )
+    }
+
+    fn fingerprint(&self) -> TlsFingerprint {
+        TlsFingerprint {
+            ja3: self.ja3(),
+            ja4: self.ja4(),
+        }
+    }
+}
+
+/// Host name of the `server_name` extension
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let (names, _) = length_prefixed(data, 2)?;
+    let (&name_type, name) = names.split_first()?;
+    if name_type != 0 {
+        return None;
+    }
+    let (name, _) = length_prefixed(name, 2)?;
+    Some(String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+/// Computes the fingerprints of the client, aborting the handshake if the route of the
+/// server name doesn't accept them (see the `tls_fingerprint` plugin)
+pub fn client_hello_callback(
+    ssl: &mut SslRef,
+    _: &mut SslAlert,
+) -> Result<ClientHelloResponse, ErrorStack> {
+    connection_metrics::handshake_started(ssl);
+
+    let Some(hello) = ClientHello::from_ssl(ssl) else {
+        return Ok(ClientHelloResponse::SUCCESS);
+    };
+
+    let fingerprint = hello.fingerprint();
+    tracing::debug!(
+        server_name = hello.server_name,
+        ja3 = fingerprint.ja3,
+        ja4 = fingerprint.ja4,
+        "received TLS client hello"
+    );
+
+    if let Some(server_name) = hello.server_name.as_deref() {
+        if !PLUGINS
+            .tls_fingerprint
+            .is_handshake_allowed(server_name, &fingerprint)
+        {
+            // An empty error stack aborts the handshake with an alert
+            return Err(ErrorStack::get());
+        }
+    }
+
+    ssl.set_ex_data(*FINGERPRINT_INDEX, fingerprint);
+    Ok(ClientHelloResponse::SUCCESS)
+}
+
+/// Returns the fingerprints of the client of a session.
+/// Only HTTP/1.1 sessions expose their TLS connection, so HTTP/2 sessions have none.
+pub fn get_fingerprint(session: &Session) -> Option<&TlsFingerprint> {
+    session
+        .as_downstream()
+        .stream()?
+        .get_ssl()?
+        .ex_data(*FINGERPRINT_INDEX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClientHello of the JA4 documentation (Chrome), with GREASE values already removed
+    fn chrome_hello() -> ClientHello {
+        ClientHello {
+            legacy_version: 0x0303,
+            ciphers: vec![
+                0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+                0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                0x001b, 0x0000, 0x0033, 0x0010, 0x4469, 0x0017, 0x002d, 0x000d, 0x0005, 0x0023,
+                0x0012, 0x002b, 0xff01, 0x000b, 0x000a, 0x0015,
+            ],
+            groups: vec![0x001d, 0x0017, 0x0018],
+            point_formats: vec![0],
+            signature_algorithms: vec![
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+            ],
+            supported_versions: vec![0x0304, 0x0303],
+            alpn: Some(b"h2".to_vec()),
+            server_name: Some("example.com".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_ja4() {
+        assert_eq!(chrome_hello().ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+
+        let hello = ClientHello {
+            legacy_version: 0x0303,
+            extensions: vec![0x000d],
+            alpn: Some(b"\xabhttp/1.1\xcd".to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(
+            hello.ja4(),
+            format!("t12i0001ad_000000000000_{}", ja4_hash("000d"))
+        );
+    }
+
+    #[test]
+    fn test_ja3() {
+        let hello = chrome_hello();
+        assert_eq!(
+            hello.ja3_string(),
+            "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,\
+             27-0-51-16-17513-23-45-13-5-35-18-43-65281-11-10-21,29-23-24,0"
+        );
+        assert_eq!(hello.ja3().len(), 32);
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert_eq!(u16_list(&[0x1a, 0x1a, 0x00, 0x1d, 0x00]), [0x001d]);
+
+        let server_name = [
+            0, 14, 0, 0, 11, b'E', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm',
+        ];
+        assert_eq!(
+            parse_server_name(&server_name).as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(parse_server_name(&server_name[..10]), None);
+    }
+}
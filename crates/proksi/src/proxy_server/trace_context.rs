@@ -0,0 +1,113 @@
+use http::HeaderMap;
+
+fn is_hex_id(id: &str, lengths: &[usize]) -> bool {
+    lengths.contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_hexdigit())
+        && id.bytes().any(|b| b != b'0')
+}
+
+/// The ID of the trace the request is part of, from its W3C `traceparent` header
+/// (`00-<trace id>-<parent id>-<flags>`) or its B3 headers (`b3`, `X-B3-TraceId`)
+pub fn trace_id(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(traceparent) = header("traceparent") {
+        let mut parts = traceparent.trim().split('-');
+        let (Some(version), Some(trace_id)) = (parts.next(), parts.next()) else {
+            return None;
+        };
+        // The version `00` is all zeros, unlike the IDs
+        let valid_version = version.len() == 2
+            && version.bytes().all(|b| b.is_ascii_hexdigit())
+            && !version.eq_ignore_ascii_case("ff");
+        return (valid_version && is_hex_id(trace_id, &[32]))
+            .then(|| trace_id.to_ascii_lowercase());
+    }
+
+    let b3 = header("b3").and_then(|b3| b3.split('-').next());
+    b3.or_else(|| header("x-b3-traceid"))
+        .map(str::trim)
+        .filter(|trace_id| is_hex_id(trace_id, &[16, 32]))
+        .map(str::to_ascii_lowercase)
+}
+
+/// The label and the value of the exemplars of the request: its trace, or else its ID
+pub fn exemplar<'a>(
+    trace_id: Option<&'a str>,
+    request_id: Option<&'a str>,
+) -> Option<(&'static str, &'a str)> {
+    trace_id
+        .map(|trace_id| ("trace_id", trace_id))
+        .or_else(|| request_id.map(|request_id| ("request_id", request_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    http::HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_trace_id() {
+        assert_eq!(
+            trace_id(&headers(&[(
+                "traceparent",
+                "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"
+            )]))
+            .as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(
+            trace_id(&headers(&[(
+                "b3",
+                "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1"
+            )]))
+            .as_deref(),
+            Some("80f198ee56343ba864fe8b2a57d3eff7")
+        );
+        assert_eq!(
+            trace_id(&headers(&[("x-b3-traceid", "463ac35c9f6413ad")])).as_deref(),
+            Some("463ac35c9f6413ad")
+        );
+        assert_eq!(trace_id(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_invalid_trace_id() {
+        for traceparent in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "not a traceparent",
+        ] {
+            assert_eq!(
+                trace_id(&headers(&[("traceparent", traceparent)])),
+                None,
+                "{traceparent}"
+            );
+        }
+        assert_eq!(trace_id(&headers(&[("x-b3-traceid", "<script>")])), None);
+    }
+
+    #[test]
+    fn test_exemplar() {
+        assert_eq!(
+            exemplar(Some("4bf9"), Some("abc")),
+            Some(("trace_id", "4bf9"))
+        );
+        assert_eq!(exemplar(None, Some("abc")), Some(("request_id", "abc")));
+        assert_eq!(exemplar(None, None), None);
+    }
+}
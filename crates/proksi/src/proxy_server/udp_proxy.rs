@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use async_trait::async_trait;
+use pingora::{
+    lb::{selection::RoundRobin, LoadBalancer},
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::net::UdpSocket;
+
+use crate::config::ConfigListener;
+
+/// Datagrams larger than this are truncated (the maximum size of an UDP payload)
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// The datagrams of a client (identified by its address and the listener address),
+/// forwarded to the same upstream until the session is idle
+struct UdpSession {
+    upstream: UdpSocket,
+    peer_addr: SocketAddr,
+    started_at: Instant,
+    /// Milliseconds since `started_at` of the last datagram, in either direction
+    last_activity: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        let elapsed = self.started_at.elapsed().as_millis() as u64;
+        self.last_activity.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.started_at.elapsed().saturating_sub(last_activity)
+    }
+}
+
+/// Forwards the datagrams received by a listener to its upstreams, and their replies back
+pub struct UdpProxyService {
+    name: String,
+    address: String,
+    load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    idle_timeout: Duration,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>,
+}
+
+impl UdpProxyService {
+    pub fn new(listener: &ConfigListener) -> anyhow::Result<Self> {
+        if listener.tls.is_some() || listener.upstream_tls.is_some() {
+            bail!("listener {}: TLS is not supported for udp", listener.name);
+        }
+
+        if listener.accept_proxy_protocol
+            || listener
+                .upstreams
+                .iter()
+                .any(|u| u.proxy_protocol.is_some())
+        {
+            bail!(
+                "listener {}: the PROXY protocol is not supported for udp",
+                listener.name
+            );
+        }
+
+        let upstreams = listener
+            .upstreams
+            .iter()
+            .map(|u| format!("{}:{}", u.ip, u.port));
+
+        Ok(Self {
+            name: listener.name.to_string(),
+            address: listener.address.to_string(),
+            load_balancer: Arc::new(LoadBalancer::try_from_iter(upstreams)?),
+            idle_timeout: Duration::from_secs(listener.idle_timeout.unwrap_or(30)),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Returns the session of the client, creating it (and picking its upstream) if needed
+    async fn session(
+        &self,
+        socket: &Arc<UdpSocket>,
+        client_addr: SocketAddr,
+    ) -> anyhow::Result<Arc<UdpSession>> {
+        if let Some(session) = self.sessions.lock().unwrap().get(&client_addr) {
+            return Ok(session.clone());
+        }
+
+        let Some(peer_addr) = self
+            .load_balancer
+            .select(b"", 1)
+            .and_then(|backend| backend.addr.as_inet().copied())
+        else {
+            bail!("no upstream available");
+        };
+
+        let bind_addr: SocketAddr = if peer_addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let upstream = UdpSocket::bind(bind_addr).await?;
+        upstream.connect(peer_addr).await?;
+
+        let session = Arc::new(UdpSession {
+            upstream,
+            peer_addr,
+            started_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(client_addr, session.clone());
+
+        tokio::spawn(forward_replies(
+            self.name.clone(),
+            socket.clone(),
+            client_addr,
+            session.clone(),
+            self.sessions.clone(),
+            self.idle_timeout,
+        ));
+
+        Ok(session)
+    }
+}
+
+/// Sends the datagrams of the upstream back to the client, until the session is idle
+async fn forward_replies(
+    listener: String,
+    socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    session: Arc<UdpSession>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>,
+    idle_timeout: Duration,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let remaining = idle_timeout.saturating_sub(session.idle_for());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let Ok(result) = tokio::time::timeout(remaining, session.upstream.recv(&mut buf)).await
+        else {
+            // Datagrams from the client may have kept the session active
+            continue;
+        };
+
+        match result {
+            Ok(size) => {
+                session.touch();
+                session.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+                if let Err(err) = socket.send_to(&buf[..size], client_addr).await {
+                    tracing::debug!(listener, "failed to send datagram to client: {err}");
+                }
+            }
+            // e.g. the upstream port is closed (ICMP port unreachable)
+            Err(err) => {
+                tracing::debug!(listener, "failed to receive datagram from upstream: {err}");
+                break;
+            }
+        }
+    }
+
+    sessions.lock().unwrap().remove(&client_addr);
+
+    tracing::info!(
+        listener,
+        client_ip = client_addr.to_string(),
+        peer_addr = session.peer_addr.to_string(),
+        duration_ms = session.started_at.elapsed().as_millis(),
+        bytes_received = session.bytes_received.load(Ordering::Relaxed),
+        bytes_sent = session.bytes_sent.load(Ordering::Relaxed),
+        access_log = true
+    );
+}
+
+#[async_trait]
+impl Service for UdpProxyService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let socket = match UdpSocket::bind(self.address.as_str()).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                tracing::error!(
+                    listener = self.name,
+                    "failed to bind {}: {err}",
+                    self.address
+                );
+                return;
+            }
+        };
+
+        tracing::info!(listener = self.name, "listening on udp {}", self.address);
+
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            // No datagram is forwarded anymore once proksi shuts down
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buf) => received,
+                _ = shutdown.changed() => break,
+            };
+            let (size, client_addr) = match received {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::debug!(listener = self.name, "failed to receive datagram: {err}");
+                    continue;
+                }
+            };
+
+            let session = match self.session(&socket, client_addr).await {
+                Ok(session) => session,
+                Err(err) => {
+                    tracing::error!(
+                        listener = self.name,
+                        client_ip = client_addr.to_string(),
+                        "failed to create udp session: {err}"
+                    );
+                    continue;
+                }
+            };
+
+            session.touch();
+            session
+                .bytes_received
+                .fetch_add(size as u64, Ordering::Relaxed);
+            if let Err(err) = session.upstream.send(&buf[..size]).await {
+                tracing::debug!(
+                    listener = self.name,
+                    "failed to send datagram to upstream: {err}"
+                );
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "udp_proxy_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::config::{ListenerProtocol, ListenerUpstream};
+
+    use super::*;
+
+    fn listener(upstream_port: u16) -> ConfigListener {
+        ConfigListener {
+            name: Cow::Borrowed("dns"),
+            address: Cow::Borrowed("127.0.0.1:0"),
+            protocol: ListenerProtocol::Udp,
+            upstreams: vec![ListenerUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port: upstream_port,
+                proxy_protocol: None,
+            }],
+            health_check_interval: 15,
+            connect_timeout: None,
+            idle_timeout: Some(1),
+            accept_proxy_protocol: false,
+            tls: None,
+            upstream_tls: None,
+        }
+    }
+
+    #[test]
+    fn test_tls_is_rejected() {
+        let mut listener = listener(53);
+        listener.upstream_tls = Some(crate::config::ListenerUpstreamTls::default());
+
+        assert!(UdpProxyService::new(&listener).is_err());
+    }
+
+    #[test]
+    fn test_proxy_protocol_is_rejected() {
+        let mut listener = listener(53);
+        listener.accept_proxy_protocol = true;
+
+        assert!(UdpProxyService::new(&listener).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service =
+            UdpProxyService::new(&listener(upstream.local_addr().unwrap().port())).unwrap();
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        // the same client reuses its session
+        let session = service.session(&socket, client_addr).await.unwrap();
+        let again = service.session(&socket, client_addr).await.unwrap();
+        assert!(Arc::ptr_eq(&session, &again));
+
+        // replies of the upstream are sent to the client through the listener socket
+        session.upstream.send(b"query").await.unwrap();
+        let mut buf = [0u8; 16];
+        let (size, from) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"query");
+
+        upstream.send_to(b"answer", from).await.unwrap();
+        let (size, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"answer");
+        assert_eq!(from, socket.local_addr().unwrap());
+
+        // idle sessions are removed
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(service.sessions.lock().unwrap().is_empty());
+    }
+}
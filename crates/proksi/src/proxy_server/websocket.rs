@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use http::{header, StatusCode};
+use pingora::http::RequestHeader;
+
+use crate::{config::RouteWebSocket, metrics};
+
+/// `Upgrade: websocket` requests (HTTP/1.1 only, pingora doesn't support
+/// WebSockets over HTTP/2 streams)
+pub fn is_websocket_upgrade(req: &RequestHeader) -> bool {
+    req.headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// A WebSocket request, kept in the request context until the tunnel is closed
+pub struct WebSocketTunnel {
+    host: String,
+    pub idle_timeout: Option<Duration>,
+    /// Set once the upstream accepted the upgrade
+    established: bool,
+}
+
+impl WebSocketTunnel {
+    /// Returns `None` if WebSockets are disabled for the route
+    pub fn new(host: &str, config: Option<&RouteWebSocket>) -> Option<Self> {
+        if config.is_some_and(|ws| !ws.enabled.unwrap_or(true)) {
+            return None;
+        }
+
+        Some(Self {
+            host: host.to_string(),
+            idle_timeout: config
+                .and_then(|ws| ws.idle_timeout)
+                .map(Duration::from_secs),
+            established: false,
+        })
+    }
+
+    /// The tunnel is established when the upstream answers with `101 Switching Protocols`
+    pub fn on_upstream_response(&mut self, status: StatusCode) {
+        if status != StatusCode::SWITCHING_PROTOCOLS || self.established {
+            return;
+        }
+
+        self.established = true;
+        metrics::WEBSOCKET_CONNECTIONS
+            .with_label_values(&[self.host.as_str()])
+            .inc();
+        metrics::WEBSOCKET_CONNECTIONS_TOTAL
+            .with_label_values(&[self.host.as_str()])
+            .inc();
+    }
+}
+
+/// The context (and the tunnel) is dropped once both sides are closed
+impl Drop for WebSocketTunnel {
+    fn drop(&mut self) {
+        if self.established {
+            metrics::WEBSOCKET_CONNECTIONS
+                .with_label_values(&[self.host.as_str()])
+                .dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let mut req = RequestHeader::build("GET", b"/ws", None).unwrap();
+        assert!(!is_websocket_upgrade(&req));
+
+        req.insert_header(header::UPGRADE, "h2c").unwrap();
+        assert!(!is_websocket_upgrade(&req));
+
+        req.insert_header(header::UPGRADE, "WebSocket").unwrap();
+        assert!(is_websocket_upgrade(&req));
+    }
+
+    #[test]
+    fn test_tunnel_metrics() {
+        let disabled = RouteWebSocket {
+            enabled: Some(false),
+            idle_timeout: None,
+        };
+        assert!(WebSocketTunnel::new("ws.test", Some(&disabled)).is_none());
+
+        let gauge = || {
+            metrics::WEBSOCKET_CONNECTIONS
+                .with_label_values(&["ws.test"])
+                .get()
+        };
+
+        let mut tunnel = WebSocketTunnel::new("ws.test", None).unwrap();
+        assert_eq!(tunnel.idle_timeout, None);
+
+        tunnel.on_upstream_response(StatusCode::FORBIDDEN);
+        assert_eq!(gauge(), 0);
+
+        tunnel.on_upstream_response(StatusCode::SWITCHING_PROTOCOLS);
+        tunnel.on_upstream_response(StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(gauge(), 1);
+
+        drop(tunnel);
+        assert_eq!(gauge(), 0);
+    }
+}
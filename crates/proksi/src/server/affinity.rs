@@ -0,0 +1,108 @@
+use std::{collections::HashSet, time::Duration};
+
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+/// Length of the names of the threads on Linux, longer names are truncated
+const THREAD_NAME_LEN: usize = 15;
+
+/// Times the threads of a service are looked up until they are all pinned, each thread
+/// names itself once it runs
+const PIN_ATTEMPTS: u32 = 20;
+const PIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Pins the `threads` threads named after the service to the CPUs. The threads it starts
+/// later (e.g. for blocking tasks) are started by these threads, and run on the same CPUs.
+pub async fn pin_threads(name: &str, cpus: &[usize], threads: usize) {
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus {
+        if let Err(err) = cpu_set.set(*cpu) {
+            tracing::warn!("failed to pin the threads of {name} to CPU {cpu}: {err}");
+            return;
+        }
+    }
+
+    let thread_name = &name.as_bytes()[..name.len().min(THREAD_NAME_LEN)];
+    let mut pinned = HashSet::new();
+    for _ in 0..PIN_ATTEMPTS {
+        let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+            tracing::warn!("failed to pin the threads of {name}: their list is not available");
+            return;
+        };
+
+        for task in tasks.flatten() {
+            let Some(tid) = task.file_name().to_str().and_then(|tid| tid.parse().ok()) else {
+                continue;
+            };
+            let is_service_thread = std::fs::read(task.path().join("comm"))
+                .is_ok_and(|comm| comm.strip_suffix(b"\n").unwrap_or(&comm) == thread_name);
+            if !is_service_thread || pinned.contains(&tid) {
+                continue;
+            }
+
+            match sched_setaffinity(Pid::from_raw(tid), &cpu_set) {
+                Ok(()) => {
+                    pinned.insert(tid);
+                }
+                Err(err) => {
+                    tracing::warn!("failed to pin the threads of {name}: {err}");
+                    return;
+                }
+            }
+        }
+
+        if pinned.len() >= threads {
+            break;
+        }
+        tokio::time::sleep(PIN_RETRY_DELAY).await;
+    }
+
+    tracing::info!(
+        "pinned {} threads of {name} to the CPUs {cpus:?}",
+        pinned.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use nix::{sched::sched_getaffinity, unistd::gettid};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pin_threads() {
+        let allowed = sched_getaffinity(Pid::from_raw(0)).unwrap();
+        let cpu = (0..CpuSet::count())
+            .find(|cpu| allowed.is_set(*cpu).unwrap())
+            .unwrap();
+
+        // The name of the thread is truncated like the names of the service threads
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("proksi-pin-threads".to_string())
+            .spawn(move || {
+                tid_tx.send(gettid()).unwrap();
+                done_rx.recv().ok();
+            })
+            .unwrap();
+        let tid = tid_rx.recv().unwrap();
+
+        pin_threads("proksi-pin-threads", &[cpu], 1).await;
+
+        let affinity = sched_getaffinity(tid).unwrap();
+        let cpus: Vec<usize> = (0..CpuSet::count())
+            .filter(|cpu| affinity.is_set(*cpu).unwrap())
+            .collect();
+        assert_eq!(cpus, vec![cpu]);
+        // The other threads keep their CPUs
+        assert_eq!(sched_getaffinity(Pid::from_raw(0)).unwrap(), allowed);
+
+        done_tx.send(()).unwrap();
+        thread.join().unwrap();
+    }
+}
@@ -1 +1,103 @@
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
 
+use crate::config::ServiceRuntime;
+
+#[cfg(target_os = "linux")]
+mod affinity;
+#[cfg(target_os = "linux")]
+use affinity::pin_threads;
+
+/// A service with the threads and CPUs of its runtime set in `services`
+pub struct RuntimeService {
+    inner: Box<dyn Service>,
+    /// Name of the service, and of its threads
+    name: Option<&'static str>,
+    runtime: ServiceRuntime,
+}
+
+/// Runs the service with the given threads and CPUs. `name` replaces the name of the
+/// service when given, the threads of the services of the same name are pinned together.
+pub fn with_runtime(
+    inner: Box<dyn Service>,
+    name: Option<&'static str>,
+    runtime: &ServiceRuntime,
+) -> RuntimeService {
+    RuntimeService {
+        inner,
+        name,
+        runtime: runtime.clone(),
+    }
+}
+
+#[async_trait]
+impl Service for RuntimeService {
+    async fn start_service(
+        &mut self,
+        fds: Option<ListenFds>,
+        shutdown: ShutdownWatch,
+        listeners_per_fd: usize,
+    ) {
+        // The threads of the runtime are all started before the service
+        if !self.runtime.cpus.is_empty() {
+            let threads = self.threads().unwrap_or(1);
+            pin_threads(self.name(), &self.runtime.cpus, threads).await;
+        }
+
+        self.inner
+            .start_service(fds, shutdown, listeners_per_fd)
+            .await;
+    }
+
+    fn name(&self) -> &str {
+        self.name.unwrap_or_else(|| self.inner.name())
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.runtime.threads.or_else(|| self.inner.threads())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn pin_threads(name: &str, _cpus: &[usize], _threads: usize) {
+    tracing::warn!("the threads of {name} are not pinned, CPU pinning is only available on Linux");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Logger;
+
+    #[async_trait]
+    impl Service for Logger {
+        async fn start_service(&mut self, _: Option<ListenFds>, _: ShutdownWatch, _: usize) {}
+
+        fn name(&self) -> &str {
+            "logger"
+        }
+
+        fn threads(&self) -> Option<usize> {
+            Some(1)
+        }
+    }
+
+    #[test]
+    fn test_runtime_service() {
+        // The defaults of the service are kept
+        let service = with_runtime(Box::new(Logger), None, &ServiceRuntime::default());
+        assert_eq!(service.name(), "logger");
+        assert_eq!(service.threads(), Some(1));
+
+        let runtime = ServiceRuntime {
+            threads: Some(4),
+            cpus: vec![0],
+        };
+        let service = with_runtime(Box::new(Logger), Some("background"), &runtime);
+        assert_eq!(service.name(), "background");
+        assert_eq!(service.threads(), Some(4));
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use prometheus::core::Collector;
+use serde_json::json;
+
+use crate::{
+    config::{AlertCondition, AlertRule, Alerts, Config},
+    metrics,
+    stores::{self, health},
+};
+
+/// Timeout of the requests to the webhooks
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests of a host since proksi started, read from the metrics
+#[derive(Debug, Clone, Default, PartialEq)]
+struct HostCounters {
+    requests: u64,
+    /// Requests answered with a 5xx
+    errors: u64,
+    /// Requests by upper bound of the buckets of their duration (in seconds), cumulative
+    latency: Vec<(f64, u64)>,
+}
+
+impl HostCounters {
+    /// The requests between an earlier value of the counters and this one
+    fn since(&self, earlier: Option<&Self>) -> Self {
+        let Some(earlier) = earlier else {
+            return self.clone();
+        };
+        let latency = self
+            .latency
+            .iter()
+            .enumerate()
+            .map(|(index, (bound, count))| {
+                let before = earlier.latency.get(index).map_or(0, |(_, count)| *count);
+                (*bound, count.saturating_sub(before))
+            })
+            .collect();
+
+        Self {
+            requests: self.requests.saturating_sub(earlier.requests),
+            errors: self.errors.saturating_sub(earlier.errors),
+            latency,
+        }
+    }
+}
+
+/// The counters of every host at a point in time
+struct Sample {
+    at: Instant,
+    hosts: HashMap<String, HostCounters>,
+}
+
+fn label<'a>(metric: &'a prometheus::proto::Metric, name: &str) -> &'a str {
+    metric
+        .get_label()
+        .iter()
+        .find(|label| label.name() == name)
+        .map_or("", |label| label.value())
+}
+
+/// Reads the requests of every host from the metrics of the HTTPS service
+fn sample() -> HashMap<String, HostCounters> {
+    let mut hosts: HashMap<String, HostCounters> = HashMap::new();
+
+    for family in metrics::HTTP_REQUESTS.collect() {
+        for metric in family.get_metric() {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let count = metric.get_counter().value() as u64;
+            let host = hosts.entry(label(metric, "host").to_string()).or_default();
+            host.requests += count;
+            if label(metric, "status") == "5xx" {
+                host.errors += count;
+            }
+        }
+    }
+
+    for family in metrics::HTTP_REQUEST_SECONDS.collect() {
+        for metric in family.get_metric() {
+            let host = hosts.entry(label(metric, "host").to_string()).or_default();
+            host.latency = metric
+                .get_histogram()
+                .get_bucket()
+                .iter()
+                .map(|bucket| (bucket.upper_bound(), bucket.cumulative_count()))
+                .collect();
+        }
+    }
+    hosts
+}
+
+/// The quantile of the durations of a histogram (cumulative buckets), interpolated within
+/// its bucket like `histogram_quantile` does. The durations above the last bucket are
+/// counted as its upper bound.
+#[allow(clippy::cast_precision_loss)]
+fn quantile(quantile: f64, buckets: &[(f64, u64)], count: u64) -> Option<f64> {
+    if count == 0 {
+        return None;
+    }
+    let rank = quantile * count as f64;
+
+    let mut previous = (0.0, 0);
+    for (bound, cumulative) in buckets.iter().copied() {
+        if cumulative as f64 >= rank {
+            let in_bucket = (cumulative - previous.1) as f64;
+            if in_bucket == 0.0 {
+                return Some(bound);
+            }
+            let share = (rank - previous.1 as f64) / in_bucket;
+            return Some(previous.0 + (bound - previous.0) * share);
+        }
+        previous = (bound, cumulative);
+    }
+    buckets.last().map(|(bound, _)| *bound)
+}
+
+/// The value of the condition of the rule for a host, `None` when it can't be evaluated
+/// (e.g. too few requests in the window)
+#[allow(clippy::cast_precision_loss)]
+fn evaluate(
+    rule: &AlertRule,
+    window: &HostCounters,
+    upstreams: Option<&health::UpstreamsHealth>,
+    host: &str,
+) -> Option<f64> {
+    match rule.condition {
+        AlertCondition::UnhealthyUpstreams => {
+            let upstreams = upstreams?.get(host)?;
+            Some(upstreams.values().filter(|u| !u.healthy).count() as f64)
+        }
+        _ if window.requests == 0 || window.requests < rule.min_requests => None,
+        AlertCondition::ErrorRate => Some(window.errors as f64 * 100.0 / window.requests as f64),
+        AlertCondition::LatencyP95 => {
+            quantile(0.95, &window.latency, window.requests).map(|seconds| seconds * 1000.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Firing => "firing",
+            Self::Resolved => "resolved",
+        }
+    }
+}
+
+/// State of a rule for a host
+#[derive(Debug, Default)]
+struct AlertState {
+    /// The threshold is breached
+    firing: bool,
+    /// The webhooks were notified since the rule started firing
+    notified: bool,
+    notified_at: Option<Instant>,
+}
+
+impl AlertState {
+    /// The notification to send once the condition is evaluated, if any. A rule firing
+    /// again within the cooldown (e.g. flapping around its threshold) is not notified.
+    fn update(&mut self, breached: bool, now: Instant, cooldown: Duration) -> Option<AlertStatus> {
+        if breached {
+            self.firing = true;
+            let cooling_down = self
+                .notified_at
+                .is_some_and(|at| now.duration_since(at) < cooldown);
+            if cooling_down {
+                return None;
+            }
+            self.notified = true;
+            self.notified_at = Some(now);
+            return Some(AlertStatus::Firing);
+        }
+
+        let notified = self.firing && self.notified;
+        self.firing = false;
+        self.notified = false;
+        notified.then_some(AlertStatus::Resolved)
+    }
+}
+
+/// Checks the webhooks and the rules of the alerts
+pub fn check_config(config: &Alerts) -> anyhow::Result<()> {
+    if config.interval_secs == 0 {
+        return Err(anyhow!("alerts.interval_secs must be greater than 0"));
+    }
+
+    for (index, webhook) in config.webhooks.iter().enumerate() {
+        if webhook.name.is_empty() {
+            return Err(anyhow!("alerts.webhooks[{index}].name cannot be empty"));
+        }
+        if config.webhooks[..index]
+            .iter()
+            .any(|other| other.name == webhook.name)
+        {
+            return Err(anyhow!(
+                "alerts.webhooks[{index}].name {} is already used",
+                webhook.name
+            ));
+        }
+        let url = reqwest::Url::parse(&webhook.url)
+            .map_err(|err| anyhow!("alerts.webhooks[{index}].url is invalid: {err}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("alerts.webhooks[{index}].url must be an HTTP(S) URL"));
+        }
+    }
+
+    if !config.rules.is_empty() && config.webhooks.is_empty() {
+        return Err(anyhow!("alerts.webhooks is required by alerts.rules"));
+    }
+    for (index, rule) in config.rules.iter().enumerate() {
+        if rule.name.is_empty() {
+            return Err(anyhow!("alerts.rules[{index}].name cannot be empty"));
+        }
+        if config.rules[..index]
+            .iter()
+            .any(|other| other.name == rule.name)
+        {
+            return Err(anyhow!(
+                "alerts.rules[{index}].name {} is already used",
+                rule.name
+            ));
+        }
+        if !rule.threshold.is_finite() || rule.threshold < 0.0 {
+            return Err(anyhow!(
+                "alerts.rules[{index}].threshold must be a positive number"
+            ));
+        }
+        if rule.window_secs < config.interval_secs {
+            return Err(anyhow!(
+                "alerts.rules[{index}].window_secs must be at least alerts.interval_secs"
+            ));
+        }
+        if let Some(name) = rule
+            .webhooks
+            .iter()
+            .find(|name| !config.webhooks.iter().any(|w| &w.name == *name))
+        {
+            return Err(anyhow!(
+                "alerts.rules[{index}].webhooks: {name} is not in alerts.webhooks"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A notification of a rule for a host
+struct Notification<'a> {
+    rule: &'a AlertRule,
+    host: &'a str,
+    status: AlertStatus,
+    value: f64,
+}
+
+impl Notification<'_> {
+    fn to_json(&self, service_name: &str) -> serde_json::Value {
+        let rule = self.rule;
+        let unit = match rule.condition {
+            AlertCondition::ErrorRate => "%",
+            AlertCondition::LatencyP95 => "ms",
+            AlertCondition::UnhealthyUpstreams => "",
+        };
+        let text = match self.status {
+            AlertStatus::Firing => format!(
+                "[{service_name}] {} is firing for {}: {} is {:.2}{unit} (threshold: {}{unit})",
+                rule.name,
+                self.host,
+                rule.condition.as_str(),
+                self.value,
+                rule.threshold
+            ),
+            AlertStatus::Resolved => format!(
+                "[{service_name}] {} is resolved for {}: {} is {:.2}{unit}",
+                rule.name,
+                self.host,
+                rule.condition.as_str(),
+                self.value
+            ),
+        };
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        json!({
+            "status": self.status.as_str(),
+            "rule": rule.name,
+            "host": self.host,
+            "condition": rule.condition.as_str(),
+            "value": self.value,
+            "threshold": rule.threshold,
+            "window_secs": rule.window_secs,
+            "service_name": service_name,
+            "at": at,
+            // Shown by the chat webhooks (e.g. Slack, Mattermost)
+            "text": text,
+        })
+    }
+}
+
+/// Evaluates the rules of the alerts on the requests and the upstreams of the routes, and
+/// notifies their webhooks when a rule starts firing for a route and once it's resolved
+pub struct AlertService {
+    config: Alerts,
+    service_name: String,
+    client: reqwest::Client,
+    /// Samples of the counters, the oldest first, over the longest window of the rules
+    samples: VecDeque<Sample>,
+    /// State of each rule, by rule and by host
+    states: HashMap<(String, String), AlertState>,
+}
+
+impl AlertService {
+    pub fn new(config: &Config) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            config: config.alerts.clone(),
+            service_name: config.service_name.to_string(),
+            client,
+            samples: VecDeque::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    /// The hosts a rule applies to
+    fn hosts(rule: &AlertRule) -> Vec<String> {
+        if !rule.hosts.is_empty() {
+            return rule.hosts.clone();
+        }
+        stores::get_routes()
+            .iter()
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+
+    fn evaluate(&mut self, now: Instant) -> Vec<(usize, String, AlertStatus, f64)> {
+        let longest = self.config.rules.iter().map(|r| r.window_secs).max();
+        let longest = Duration::from_secs(longest.unwrap_or_default());
+        self.samples.push_back(Sample {
+            at: now,
+            hosts: sample(),
+        });
+        // The oldest sample kept is the start of the longest window
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|sample| now.duration_since(sample.at) >= longest)
+        {
+            self.samples.pop_front();
+        }
+
+        let upstreams = health::snapshot();
+        let latest = self.samples.back().map(|sample| &sample.hosts);
+        let mut notifications = vec![];
+        for (index, rule) in self.config.rules.iter().enumerate() {
+            let window = Duration::from_secs(rule.window_secs);
+            // The latest sample before the start of the window, or the oldest one until
+            // the window is covered
+            let start = self
+                .samples
+                .iter()
+                .rev()
+                .find(|sample| now.duration_since(sample.at) >= window)
+                .or_else(|| self.samples.front())
+                .map(|sample| &sample.hosts);
+
+            for host in Self::hosts(rule) {
+                let counters = latest.and_then(|hosts| hosts.get(&host));
+                let window = counters
+                    .map(|counters| counters.since(start.and_then(|hosts| hosts.get(&host))))
+                    .unwrap_or_default();
+
+                let value = evaluate(rule, &window, Some(upstreams.as_ref()), &host);
+                let breached = value.is_some_and(|value| value > rule.threshold);
+                let state = self
+                    .states
+                    .entry((rule.name.clone(), host.clone()))
+                    .or_default();
+                let cooldown = Duration::from_secs(rule.cooldown_secs);
+                if let Some(status) = state.update(breached, now, cooldown) {
+                    notifications.push((index, host, status, value.unwrap_or_default()));
+                }
+            }
+        }
+        notifications
+    }
+
+    /// Sends the notification to the webhooks of its rule, in the background
+    fn notify(&self, notification: &Notification) {
+        let body = notification.to_json(&self.service_name);
+        let rule = notification.rule;
+        let status = notification.status.as_str();
+        tracing::warn!(
+            rule = rule.name,
+            host = notification.host,
+            status,
+            value = notification.value,
+            "alert {status}"
+        );
+
+        let webhooks =
+            self.config.webhooks.iter().filter(|webhook| {
+                rule.webhooks.is_empty() || rule.webhooks.contains(&webhook.name)
+            });
+        for webhook in webhooks {
+            let mut request = self.client.post(&webhook.url).json(&body);
+            for (name, value) in &webhook.headers {
+                request = request.header(name, value);
+            }
+            let rule = rule.name.clone();
+            let name = webhook.name.clone();
+            tokio::spawn(async move {
+                let result = match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => "sent",
+                    Err(err) => {
+                        tracing::error!("failed to notify the webhook {name}: {err}");
+                        "failed"
+                    }
+                };
+                metrics::ALERT_NOTIFICATIONS
+                    .with_label_values(&[rule.as_str(), status, result])
+                    .inc();
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Service for AlertService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        if self.config.rules.is_empty() {
+            return;
+        }
+
+        let mut ticks = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+
+            for (index, host, status, value) in self.evaluate(Instant::now()) {
+                self.notify(&Notification {
+                    rule: &self.config.rules[index],
+                    host: &host,
+                    status,
+                    value,
+                });
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "alert_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::stores::health::{HealthSource, UpstreamHealth};
+
+    use super::*;
+
+    fn rule(condition: AlertCondition, threshold: f64) -> AlertRule {
+        serde_json::from_value(json!({
+            "name": "test",
+            "condition": condition,
+            "threshold": threshold,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_quantile() {
+        let buckets = [(0.1, 50), (0.5, 90), (1.0, 100)];
+        assert_eq!(quantile(0.5, &buckets, 100), Some(0.1));
+        assert!(quantile(0.95, &buckets, 100).is_some_and(|q| (q - 0.75).abs() < 1e-9));
+        // Above the last bucket
+        assert_eq!(quantile(0.95, &[(0.1, 10)], 100), Some(0.1));
+        assert_eq!(quantile(0.95, &buckets, 0), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let window = HostCounters {
+            requests: 100,
+            errors: 7,
+            latency: vec![(0.1, 50), (0.5, 90), (1.0, 100)],
+        }
+        .since(Some(&HostCounters {
+            requests: 50,
+            errors: 2,
+            latency: vec![(0.1, 25), (0.5, 45), (1.0, 50)],
+        }));
+        assert_eq!(window.requests, 50);
+        assert_eq!(window.latency, vec![(0.1, 25), (0.5, 45), (1.0, 50)]);
+
+        let error_rate = rule(AlertCondition::ErrorRate, 5.0);
+        assert_eq!(evaluate(&error_rate, &window, None, "a"), Some(10.0));
+        let latency = rule(AlertCondition::LatencyP95, 500.0);
+        assert!(evaluate(&latency, &window, None, "a").is_some_and(|ms| (ms - 750.0).abs() < 1e-6));
+
+        let quiet = HostCounters {
+            requests: 3,
+            errors: 3,
+            latency: vec![],
+        };
+        assert_eq!(
+            evaluate(&error_rate, &quiet, None, "a"),
+            None,
+            "min_requests"
+        );
+
+        let upstream = |healthy| UpstreamHealth {
+            healthy,
+            source: HealthSource::Active,
+            failures: 0,
+            changed_at: 0,
+            last_error: None,
+        };
+        let upstreams = BTreeMap::from([(
+            "a".to_string(),
+            BTreeMap::from([
+                ("127.0.0.1:1".to_string(), upstream(false)),
+                ("127.0.0.1:2".to_string(), upstream(true)),
+            ]),
+        )]);
+        let unhealthy = rule(AlertCondition::UnhealthyUpstreams, 0.0);
+        assert_eq!(
+            evaluate(&unhealthy, &quiet, Some(&upstreams), "a"),
+            Some(1.0)
+        );
+        assert_eq!(evaluate(&unhealthy, &quiet, Some(&upstreams), "b"), None);
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let cooldown = Duration::from_secs(600);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut state = AlertState::default();
+
+        assert_eq!(state.update(false, at(0), cooldown), None);
+        assert_eq!(
+            state.update(true, at(30), cooldown),
+            Some(AlertStatus::Firing)
+        );
+        assert_eq!(state.update(true, at(60), cooldown), None);
+        assert_eq!(
+            state.update(false, at(90), cooldown),
+            Some(AlertStatus::Resolved)
+        );
+
+        // Flapping within the cooldown
+        assert_eq!(state.update(true, at(120), cooldown), None);
+        assert_eq!(state.update(false, at(150), cooldown), None);
+
+        // Still firing after the cooldown
+        assert_eq!(
+            state.update(true, at(640), cooldown),
+            Some(AlertStatus::Firing)
+        );
+        assert_eq!(
+            state.update(true, at(1300), cooldown),
+            Some(AlertStatus::Firing)
+        );
+    }
+
+    #[test]
+    fn test_check_config() {
+        let mut config: Alerts = serde_json::from_value(json!({
+            "webhooks": [{ "name": "ops", "url": "https://hooks.example.com/alerts" }],
+            "rules": [{
+                "name": "errors",
+                "condition": "error_rate",
+                "threshold": 5,
+                "webhooks": ["ops"],
+            }],
+        }))
+        .unwrap();
+        assert!(check_config(&config).is_ok());
+
+        config.rules[0].webhooks = vec!["pager".to_string()];
+        assert!(check_config(&config).is_err());
+        config.rules[0].webhooks.clear();
+
+        config.rules[0].window_secs = 10;
+        assert!(check_config(&config).is_err());
+        config.rules[0].window_secs = 300;
+
+        config.webhooks[0].url = "ftp://hooks.example.com".to_string();
+        assert!(check_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_notification() {
+        let rule = rule(AlertCondition::ErrorRate, 5.0);
+        let notification = Notification {
+            rule: &rule,
+            host: "api.localhost",
+            status: AlertStatus::Firing,
+            value: 12.5,
+        };
+        let body = notification.to_json("proksi");
+        assert_eq!(body["status"], "firing");
+        assert_eq!(body["condition"], "error_rate");
+        assert_eq!(
+            body["text"],
+            "[proksi] test is firing for api.localhost: error_rate is 12.50% (threshold: 5%)"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{
+    config::{BlueGreenPool, Config, RouteBlueGreenRollback},
+    metrics, stores,
+};
+
+use super::discovery;
+
+/// Interval of the checks of the error rates of the switched routes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The blue/green routes whose active pool was switched recently, by host
+static SWITCHES: Lazy<papaya::HashMap<String, Switch>> = Lazy::new(papaya::HashMap::new);
+
+/// A switch of the active pool of a route, watched until the end of its window
+struct Switch {
+    /// The pool made active by the switch
+    active: BlueGreenPool,
+    until: Instant,
+    rollback: RouteBlueGreenRollback,
+    requests: AtomicU32,
+    errors: AtomicU32,
+}
+
+impl Switch {
+    /// The error rate of the new pool, once it exceeds the limit of the rollback
+    fn exceeded(&self) -> Option<f64> {
+        let requests = self.requests.load(Ordering::Relaxed);
+        if requests == 0 || requests < self.rollback.min_requests {
+            return None;
+        }
+
+        let rate = f64::from(self.errors.load(Ordering::Relaxed)) / f64::from(requests);
+        (rate > self.rollback.max_error_rate).then_some(rate)
+    }
+}
+
+/// Records the switch of the active pool of a route through the admin API. Its errors
+/// are watched when the route has a `rollback`, until the end of its window.
+pub fn switched(host: &str, active: BlueGreenPool, rollback: Option<RouteBlueGreenRollback>) {
+    metrics::BLUE_GREEN_SWITCHES
+        .with_label_values(&[host, active.as_str(), "api"])
+        .inc();
+
+    let switches = SWITCHES.pin();
+    let Some(rollback) = rollback else {
+        switches.remove(host);
+        return;
+    };
+
+    let switch = Switch {
+        active,
+        until: Instant::now() + Duration::from_secs(rollback.window_secs),
+        rollback,
+        requests: AtomicU32::new(0),
+        errors: AtomicU32::new(0),
+    };
+    switches.insert(host.to_string(), switch);
+}
+
+/// Counts a request of a host, `failed` when it was answered with a 5xx or failed to be
+/// proxied. Only the requests of the routes switched recently are counted.
+pub fn record(host: &str, failed: bool) {
+    let switches = SWITCHES.pin();
+    let Some(switch) = switches.get(host) else {
+        return;
+    };
+
+    // Saturates instead of wrapping around, the rate stays meaningful
+    let _ = switch
+        .requests
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1));
+    if failed {
+        let _ = switch
+            .errors
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1));
+    }
+}
+
+/// Makes the previous pool of the route active again, unless the route was changed since
+async fn rollback(host: &str, switched_to: BlueGreenPool, persist: bool) -> anyhow::Result<()> {
+    let Some(mut route) = stores::get_route_definition(host) else {
+        return Ok(());
+    };
+    let Some(pools) = route.blue_green.as_mut() else {
+        return Ok(());
+    };
+    if pools.active != switched_to {
+        return Ok(());
+    }
+    pools.active = switched_to.other();
+
+    discovery::apply_route(&route).await?;
+    if persist {
+        let json = serde_json::to_string(&route)?;
+        stores::global::get_store()
+            .set_route(host, json)
+            .await
+            .map_err(|err| anyhow!("rolled back but not persisted: {err}"))?;
+    }
+
+    Ok(())
+}
+
+/// Watches the error rate of the blue/green routes after a switch of their active pool,
+/// and switches back to the previous pool when it exceeds `blue_green.rollback`
+pub struct BlueGreenService {
+    persist_routes: bool,
+}
+
+impl BlueGreenService {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            persist_routes: config.admin.persist_routes,
+        }
+    }
+
+    async fn check(&self) {
+        let now = Instant::now();
+        let mut rollbacks = Vec::new();
+        {
+            let switches = SWITCHES.pin();
+            for (host, switch) in switches.iter() {
+                if let Some(rate) = switch.exceeded() {
+                    rollbacks.push((host.clone(), switch.active, rate));
+                } else if now >= switch.until {
+                    tracing::info!(
+                        "switch of {host} to the {} pool is over, no rollback",
+                        switch.active.as_str()
+                    );
+                } else {
+                    continue;
+                }
+                switches.remove(host);
+            }
+        }
+
+        for (host, active, rate) in rollbacks {
+            let previous = active.other();
+            tracing::warn!(
+                "rolling back {host} to the {} pool, {:.1}% of the requests of the {} pool failed",
+                previous.as_str(),
+                rate * 100.0,
+                active.as_str()
+            );
+
+            match rollback(&host, active, self.persist_routes).await {
+                Ok(()) => metrics::BLUE_GREEN_SWITCHES
+                    .with_label_values(&[host.as_str(), previous.as_str(), "rollback"])
+                    .inc(),
+                Err(err) => tracing::error!("failed to roll back {host}: {err}"),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for BlueGreenService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+
+            self.check().await;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "blue_green_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_rate() {
+        let rollback = RouteBlueGreenRollback {
+            max_error_rate: 0.1,
+            min_requests: 10,
+            window_secs: 60,
+        };
+        switched("blue-green.localhost", BlueGreenPool::Green, Some(rollback));
+        // Only the switched routes are counted
+        record("other.localhost", true);
+        assert!(SWITCHES.pin().get("other.localhost").is_none());
+
+        for _ in 0..8 {
+            record("blue-green.localhost", false);
+        }
+        record("blue-green.localhost", true);
+        let switches = SWITCHES.pin();
+        let switch = switches.get("blue-green.localhost").unwrap();
+        // Not enough requests yet
+        assert_eq!(switch.exceeded(), None);
+
+        record("blue-green.localhost", false);
+        assert_eq!(switch.exceeded(), None);
+
+        record("blue-green.localhost", true);
+        assert!(switch.exceeded().is_some_and(|rate| rate > 0.1));
+
+        switched("blue-green.localhost", BlueGreenPool::Blue, None);
+        assert!(switches.get("blue-green.localhost").is_none());
+    }
+}
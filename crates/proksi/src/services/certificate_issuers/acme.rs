@@ -0,0 +1,464 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Context};
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509ReqBuilder},
+};
+use reqwest::{header, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{config::CertificateIssuer, services::letsencrypt::self_check, stores};
+
+use super::IssuedCertificate;
+
+/// Interval of the polls of the authorizations and orders, and number of polls
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+/// Orders the certificates from an ACME server (e.g. step-ca), validating the domains with
+/// HTTP-01 challenges answered by the HTTP service <https://www.rfc-editor.org/rfc/rfc8555>
+pub struct AcmeIssuer {
+    client: reqwest::Client,
+    directory_url: String,
+    email: Option<String>,
+    /// Key of the account, kept across restarts
+    account_key: EcKey<Private>,
+    /// Whether the challenges are requested before their validation
+    self_check: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    challenge_type: String,
+    url: String,
+    token: String,
+}
+
+/// Requests of an order, signed by the account and sent with the last nonce of the server
+struct AcmeSession<'a> {
+    issuer: &'a AcmeIssuer,
+    nonce: String,
+    /// URL of the account, identifying it once it's created
+    kid: Option<String>,
+}
+
+impl AcmeIssuer {
+    /// Loads the key of the account from `account_key`, creating it the first time
+    pub fn new(
+        config: &CertificateIssuer,
+        client: reqwest::Client,
+        account_key: &Path,
+    ) -> anyhow::Result<Self> {
+        let account_key = match std::fs::read(account_key) {
+            Ok(pem) => EcKey::private_key_from_pem(&pem)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = p256_key()?;
+                if let Some(parent) = account_key.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(account_key, key.private_key_to_pem()?)
+                    .with_context(|| format!("failed to save the account key {account_key:?}"))?;
+                key
+            }
+            Err(err) => {
+                return Err(anyhow!(
+                    "failed to read the account key {account_key:?}: {err}"
+                ))
+            }
+        };
+
+        Ok(AcmeIssuer {
+            client,
+            directory_url: config.url.to_string(),
+            email: config.email.as_deref().map(ToString::to_string),
+            account_key,
+            self_check: config.self_check.unwrap_or(true),
+        })
+    }
+
+    pub async fn issue(&self, domain: &str) -> anyhow::Result<IssuedCertificate> {
+        let directory = self
+            .client
+            .get(&self.directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Directory>()
+            .await?;
+
+        let mut session = AcmeSession {
+            issuer: self,
+            nonce: self.new_nonce(&directory).await?,
+            kid: None,
+        };
+
+        let contact = self
+            .email
+            .iter()
+            .map(|email| format!("mailto:{email}"))
+            .collect::<Vec<_>>();
+        let account = session
+            .post(
+                &directory.new_account,
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        session.kid = Some(location(&account)?);
+
+        let response = session
+            .post(
+                &directory.new_order,
+                Some(json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url = location(&response)?;
+        let order = response.json::<Order>().await?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(&mut session, domain, authorization_url)
+                .await?;
+        }
+
+        let (key, csr) = certificate_request(domain)?;
+        let csr = base64_url(&csr);
+        session
+            .post(&order.finalize, Some(json!({ "csr": csr })))
+            .await?;
+
+        let mut certificate_url = None;
+        for _ in 0..POLL_ATTEMPTS {
+            let order = session
+                .post(&order_url, None)
+                .await?
+                .json::<Order>()
+                .await?;
+            match order.status.as_str() {
+                "valid" => {
+                    certificate_url = order.certificate;
+                    break;
+                }
+                "invalid" => return Err(anyhow!("the order of {domain} is invalid")),
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+        let certificate_url = certificate_url
+            .ok_or_else(|| anyhow!("the order of {domain} wasn't issued in time"))?;
+
+        let bundle = session.post(&certificate_url, None).await?.text().await?;
+        Ok(IssuedCertificate {
+            bundle,
+            key: String::from_utf8(key.private_key_to_pem_pkcs8()?)?,
+        })
+    }
+
+    /// Answers the HTTP-01 challenge of the authorization, then waits until it's validated
+    async fn authorize(
+        &self,
+        session: &mut AcmeSession<'_>,
+        domain: &str,
+        authorization_url: &str,
+    ) -> anyhow::Result<()> {
+        let authorization = session
+            .post(authorization_url, None)
+            .await?
+            .json::<Authorization>()
+            .await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.challenge_type == "http-01")
+            .ok_or_else(|| anyhow!("the server offers no HTTP-01 challenge for {domain}"))?;
+
+        // Answered by the HTTP service of every instance sharing the store
+        let proof = format!("{}.{}", challenge.token, self.thumbprint()?);
+        stores::global::get_store()
+            .set_challenge(domain, challenge.token.clone(), proof.clone())
+            .await
+            .map_err(|err| anyhow!("failed to set the challenge in the store: {err}"))?;
+
+        if self.self_check {
+            self_check::check_challenge(domain, &challenge.token, &proof).await?;
+        }
+
+        session.post(&challenge.url, Some(json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let authorization = session
+                .post(authorization_url, None)
+                .await?
+                .json::<Authorization>()
+                .await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => return Err(anyhow!("the challenge of {domain} is {status}")),
+            }
+        }
+        Err(anyhow!(
+            "the challenge of {domain} wasn't validated in time"
+        ))
+    }
+
+    async fn new_nonce(&self, directory: &Directory) -> anyhow::Result<String> {
+        let response = self.client.head(&directory.new_nonce).send().await?;
+        replay_nonce(&response).ok_or_else(|| anyhow!("the server sent no nonce"))
+    }
+
+    /// Public key of the account, as a JSON Web Key
+    fn jwk(&self) -> anyhow::Result<Value> {
+        let mut ctx = BigNumContext::new()?;
+        let (mut x, mut y) = (openssl::bn::BigNum::new()?, openssl::bn::BigNum::new()?);
+        self.account_key.public_key().affine_coordinates(
+            self.account_key.group(),
+            &mut x,
+            &mut y,
+            &mut ctx,
+        )?;
+
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64_url(&x.to_vec_padded(32)?),
+            "y": base64_url(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// <https://www.rfc-editor.org/rfc/rfc7638>, the members of the key are in
+    /// lexicographic order (as serialized by `serde_json`)
+    fn thumbprint(&self) -> anyhow::Result<String> {
+        let jwk = serde_json::to_vec(&self.jwk()?)?;
+        Ok(base64_url(&hash(MessageDigest::sha256(), &jwk)?))
+    }
+
+    /// Flattened JWS of the payload, signed with ES256 (`None` is a POST-as-GET)
+    fn sign(&self, protected: &Value, payload: Option<&Value>) -> anyhow::Result<Value> {
+        let protected = base64_url(&serde_json::to_vec(protected)?);
+        let payload = match payload {
+            Some(payload) => base64_url(&serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+
+        let digest = hash(
+            MessageDigest::sha256(),
+            format!("{protected}.{payload}").as_bytes(),
+        )?;
+        let signature = EcdsaSig::sign(&digest, &self.account_key)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64_url(&raw),
+        }))
+    }
+}
+
+impl AcmeSession<'_> {
+    /// Sends a signed request, with a new nonce when the server rejects the current one
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> anyhow::Result<Response> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({ "alg": "ES256", "nonce": self.nonce, "url": url });
+            match self.kid.as_deref() {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.issuer.jwk()?,
+            }
+            let body = self.issuer.sign(&protected, payload.as_ref())?;
+
+            let response = self
+                .issuer
+                .client
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(serde_json::to_vec(&body)?)
+                .send()
+                .await?;
+            if let Some(nonce) = replay_nonce(&response) {
+                self.nonce = nonce;
+            }
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            let problem = response.json::<Value>().await.unwrap_or_default();
+            let bad_nonce = problem["type"] == "urn:ietf:params:acme:error:badNonce";
+            if status == StatusCode::BAD_REQUEST && bad_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(anyhow!(
+                "the server answered {status}: {}",
+                problem["detail"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+}
+
+/// Key of the certificate and its request (DER), for the domain only
+fn certificate_request(domain: &str) -> anyhow::Result<(PKey<Private>, Vec<u8>)> {
+    let key = PKey::from_ec_key(p256_key()?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", domain)?;
+    let name = name.build();
+
+    let mut request = X509ReqBuilder::new()?;
+    request.set_subject_name(&name)?;
+    request.set_pubkey(&key)?;
+    let mut extensions = Stack::new()?;
+    extensions.push(
+        SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&request.x509v3_context(None))?,
+    )?;
+    request.add_extensions(&extensions)?;
+    request.sign(&key, MessageDigest::sha256())?;
+
+    Ok((key, request.build().to_der()?))
+}
+
+fn p256_key() -> anyhow::Result<EcKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(EcKey::generate(&group)?)
+}
+
+fn location(response: &Response) -> anyhow::Result<String> {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+        .ok_or_else(|| anyhow!("the server sent no location for {}", response.url()))
+}
+
+fn replay_nonce(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+}
+
+/// Base64 with the URL alphabet and without padding, as in the JSON Web Signatures
+fn base64_url(bytes: &[u8]) -> String {
+    openssl::base64::encode_block(bytes)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{bn::BigNum, x509::X509Req};
+
+    use super::*;
+
+    fn issuer() -> AcmeIssuer {
+        AcmeIssuer {
+            client: reqwest::Client::new(),
+            directory_url: "https://ca.internal/acme/directory".to_string(),
+            email: None,
+            account_key: p256_key().unwrap(),
+            self_check: true,
+        }
+    }
+
+    fn decode_base64_url(value: &str) -> Vec<u8> {
+        let padding = "=".repeat((4 - value.len() % 4) % 4);
+        let value = value.replace('-', "+").replace('_', "/");
+        openssl::base64::decode_block(&format!("{value}{padding}")).unwrap()
+    }
+
+    #[test]
+    fn test_base64_url() {
+        assert_eq!(base64_url(&[0xfb, 0xff]), "-_8");
+        assert_eq!(base64_url(b""), "");
+    }
+
+    #[test]
+    fn test_signature() {
+        let issuer = issuer();
+        let jws = issuer
+            .sign(&json!({ "alg": "ES256" }), Some(&json!({})))
+            .unwrap();
+
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = decode_base64_url(jws["signature"].as_str().unwrap());
+        assert_eq!(signature.len(), 64);
+
+        let (r, s) = signature.split_at(32);
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(r).unwrap(),
+            BigNum::from_slice(s).unwrap(),
+        )
+        .unwrap();
+        let digest = hash(MessageDigest::sha256(), signed.as_bytes()).unwrap();
+        assert!(signature.verify(&digest, &issuer.account_key).unwrap());
+    }
+
+    #[test]
+    fn test_thumbprint_is_stable() {
+        let issuer = issuer();
+        assert_eq!(issuer.thumbprint().unwrap(), issuer.thumbprint().unwrap());
+        assert_eq!(issuer.thumbprint().unwrap().len(), 43);
+    }
+
+    #[test]
+    fn test_certificate_request() {
+        let (key, der) = certificate_request("app.internal").unwrap();
+        let request = X509Req::from_der(&der).unwrap();
+
+        assert!(request.verify(&key).unwrap());
+        let subject = request
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap();
+        assert_eq!(subject.data().as_slice(), b"app.internal");
+    }
+}
@@ -0,0 +1,277 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use openssl::{asn1::Asn1Time, x509::X509Ref};
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::{sync::broadcast::error::RecvError, time};
+
+use crate::{
+    config::{CertificateIssuer, CertificateIssuerType, Config},
+    stores::{
+        self,
+        certificates::RenewalState,
+        events::{self, StoreEvent},
+    },
+};
+
+use super::letsencrypt::http01::{
+    recently_failed, unix_now, LetsencryptService, ORDER_LOCK_TTL, ROUTES_CHECK_INTERVAL,
+};
+
+pub mod acme;
+pub mod vault;
+
+/// A certificate (PEM, followed by its chain) and its private key (PEM)
+pub struct IssuedCertificate {
+    pub bundle: String,
+    pub key: String,
+}
+
+enum Issuer {
+    Acme(acme::AcmeIssuer),
+    Vault(vault::VaultIssuer),
+}
+
+impl Issuer {
+    fn from_config(config: &CertificateIssuer, app_config: &Config) -> anyhow::Result<Self> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(path) = config.ca_certificate.as_deref() {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("failed to read the CA certificate {path:?}"))?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let client = client.build()?;
+
+        Ok(match config.issuer_type {
+            CertificateIssuerType::Acme => {
+                let account_key = app_config
+                    .paths
+                    .lets_encrypt
+                    .join("issuers")
+                    .join(config.name.as_ref())
+                    .join("account.pem");
+                Issuer::Acme(acme::AcmeIssuer::new(config, client, &account_key)?)
+            }
+            CertificateIssuerType::Vault => Issuer::Vault(vault::VaultIssuer::new(config, client)?),
+        })
+    }
+
+    async fn issue(&self, domain: &str) -> anyhow::Result<IssuedCertificate> {
+        match self {
+            Issuer::Acme(issuer) => issuer.issue(domain).await,
+            Issuer::Vault(issuer) => issuer.issue(domain).await,
+        }
+    }
+}
+
+/// Seconds before the certificate is renewed, once two thirds of its lifetime have passed
+/// (the 90 days of a Let's Encrypt certificate would be renewed 30 days before they end)
+pub fn renews_in_secs(leaf: &X509Ref) -> Option<i64> {
+    let now = Asn1Time::days_from_now(0).ok()?;
+    let lifetime = leaf.not_before().diff(leaf.not_after()).ok()?;
+    let remaining = now.diff(leaf.not_after()).ok()?;
+
+    let secs = |diff: openssl::asn1::TimeDiff| i64::from(diff.days) * 86_400 + i64::from(diff.secs);
+    Some(secs(remaining) - secs(lifetime) / 3)
+}
+
+/// A service ordering the certificates of the routes with an `ssl_certificate.issuer` from
+/// their private CA, and renewing them
+pub struct CertificateIssuerService {
+    config: Arc<Config>,
+}
+
+impl CertificateIssuerService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Orders the certificates that are missing, self-signed or about to expire
+    async fn check_certificates(issuers: &HashMap<String, Issuer>) {
+        for (domain, route) in stores::get_routes().iter() {
+            let Some(name) = route.certificate_issuer.as_deref() else {
+                continue;
+            };
+            let Some(issuer) = issuers.get(name) else {
+                tracing::error!("unknown certificate issuer {name} for domain {domain}");
+                continue;
+            };
+
+            let self_signed = stores::get_renewal_status(domain)
+                .is_some_and(|status| status.state == RenewalState::SelfSigned);
+            let certificate = stores::global::get_store().get_certificate(domain).await;
+            let renews_in = certificate
+                .as_ref()
+                .and_then(|certificate| renews_in_secs(&certificate.leaf));
+            if !self_signed && renews_in.is_some_and(|secs| secs > 0) {
+                continue;
+            }
+
+            // The CA isn't asked again right away after a failure
+            if recently_failed(domain) {
+                continue;
+            }
+
+            if let Err(err) = Self::order_certificate(domain, name, issuer).await {
+                tracing::error!("failed to order the certificate of {domain} from {name}: {err}");
+
+                // A valid certificate is kept until it expires
+                if certificate.is_none() && route.self_signed_certificate {
+                    let created =
+                        LetsencryptService::create_self_signed_certificate(domain, true).await;
+                    if created.is_ok() {
+                        stores::update_renewal_status(domain, |status| {
+                            status.state = RenewalState::SelfSigned;
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Orders the certificate of a domain, recording its outcome in the renewal status
+    async fn order_certificate(domain: &str, name: &str, issuer: &Issuer) -> anyhow::Result<()> {
+        // Instances sharing the store order each certificate once
+        let lock = format!("order:{domain}");
+        let store = stores::global::get_store();
+        if !store
+            .try_lock(&lock, ORDER_LOCK_TTL)
+            .await
+            .map_err(|err| anyhow!("failed to lock the order of {domain}: {err}"))?
+        {
+            tracing::info!("another instance is ordering the certificate of {domain}");
+            return Ok(());
+        }
+
+        tracing::info!("ordering the certificate of {domain} from {name}");
+        stores::update_renewal_status(domain, |status| {
+            status.state = RenewalState::Ordering;
+            status.last_attempt_at = Some(unix_now());
+        });
+
+        let result = match issuer.issue(domain).await {
+            Ok(issued) => {
+                LetsencryptService::insert_certificate(domain, &issued.bundle, &issued.key).await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = store.unlock(&lock).await {
+            tracing::warn!("failed to unlock the order of {domain}: {err}");
+        }
+
+        stores::update_renewal_status(domain, |status| match &result {
+            Ok(()) => {
+                status.state = RenewalState::Issued;
+                status.last_success_at = Some(unix_now());
+                status.last_error = None;
+            }
+            Err(err) => {
+                status.state = RenewalState::Failed;
+                status.last_error = Some(err.to_string());
+            }
+        });
+
+        result
+    }
+}
+
+#[async_trait]
+impl Service for CertificateIssuerService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let issuers = self
+            .config
+            .certificate_issuers
+            .iter()
+            .filter_map(|config| match Issuer::from_config(config, &self.config) {
+                Ok(issuer) => Some((config.name.to_string(), issuer)),
+                Err(err) => {
+                    tracing::error!(
+                        "failed to create the certificate issuer {}: {err}",
+                        config.name
+                    );
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        if issuers.is_empty() {
+            return;
+        }
+        tracing::info!("started the certificate issuer service");
+
+        let mut store_events = events::subscribe();
+        // Also the interval of the renewals, the certificates of private CAs are often
+        // short-lived
+        let mut interval = time::interval(ROUTES_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+                event = store_events.recv() => match event {
+                    Ok(StoreEvent::Routes { .. }) | Err(RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            }
+            Self::check_certificates(&issuers).await;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "certificate_issuer_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{X509Builder, X509},
+    };
+
+    use super::*;
+
+    fn certificate(not_before: u32, not_after: u32) -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        let now = unix_now() as i64;
+        builder
+            .set_not_before(&Asn1Time::from_unix(now - i64::from(not_before)).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(now + i64::from(not_after)).unwrap())
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_renews_in_secs() {
+        let day = 86_400;
+
+        // 90 days, issued 10 days ago: renewed in 50 days
+        let renews_in = renews_in_secs(&certificate(10 * day, 80 * day)).unwrap();
+        assert!((renews_in - 50 * i64::from(day)).abs() < 5);
+
+        // 24 hours, issued 20 hours ago: renewed 4 hours ago
+        let renews_in = renews_in_secs(&certificate(20 * 3600, 4 * 3600)).unwrap();
+        assert!(renews_in < 0);
+        assert!((renews_in + 4 * 3600).abs() < 5);
+    }
+}
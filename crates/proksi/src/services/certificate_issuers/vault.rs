@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{config::CertificateIssuer, stores};
+
+use super::IssuedCertificate;
+
+/// Issues the certificates with the PKI secrets engine of Vault
+/// <https://developer.hashicorp.com/vault/api-docs/secret/pki#generate-certificate-and-key>
+pub struct VaultIssuer {
+    client: reqwest::Client,
+    /// `<address>/v1/<mount>/issue/<role>`
+    issue_url: String,
+    token: String,
+    ttl: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueResponse {
+    data: IssueData,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueData {
+    certificate: String,
+    issuing_ca: Option<String>,
+    #[serde(default)]
+    ca_chain: Vec<String>,
+    private_key: String,
+}
+
+impl VaultIssuer {
+    pub fn new(config: &CertificateIssuer, client: reqwest::Client) -> anyhow::Result<Self> {
+        let token = match config.token.as_deref() {
+            Some(token) => token.to_string(),
+            None => std::env::var("VAULT_TOKEN")
+                .context("the issuer has no token and VAULT_TOKEN is not set")?,
+        };
+        let role = config
+            .role
+            .as_deref()
+            .ok_or_else(|| anyhow!("the issuer has no role"))?;
+
+        Ok(VaultIssuer {
+            client,
+            issue_url: format!(
+                "{}/v1/{}/issue/{role}",
+                config.url.trim_end_matches('/'),
+                config.mount.as_deref().unwrap_or("pki").trim_matches('/'),
+            ),
+            token,
+            ttl: config.ttl.as_deref().map(ToString::to_string),
+        })
+    }
+
+    pub async fn issue(&self, domain: &str) -> anyhow::Result<IssuedCertificate> {
+        let mut body = json!({ "common_name": domain });
+        if let Some(ttl) = self.ttl.as_deref() {
+            body["ttl"] = json!(ttl);
+        }
+
+        // The token can be a secret reference, refreshed by the secrets service
+        let token = stores::secrets::resolve(&self.token)
+            .ok_or_else(|| anyhow!("the token {} is not loaded", self.token))?;
+        let response = self
+            .client
+            .post(&self.issue_url)
+            .header("X-Vault-Token", token.as_ref())
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let errors = response.text().await.unwrap_or_default();
+            return Err(anyhow!("vault answered {status}: {errors}"));
+        }
+
+        let response = response.json::<IssueResponse>().await?;
+        Ok(issued_certificate(response.data))
+    }
+}
+
+/// The leaf followed by its chain, the issuing CA when Vault sends no chain
+fn issued_certificate(data: IssueData) -> IssuedCertificate {
+    let mut bundle = data.certificate;
+    let chain = match data.ca_chain.is_empty() {
+        true => data.issuing_ca.into_iter().collect(),
+        false => data.ca_chain,
+    };
+    for certificate in chain {
+        bundle.push('\n');
+        bundle.push_str(&certificate);
+    }
+
+    IssuedCertificate {
+        bundle,
+        key: data.private_key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issued_certificate() {
+        let response = serde_json::from_value::<IssueResponse>(json!({
+            "data": {
+                "certificate": "leaf",
+                "issuing_ca": "intermediate",
+                "ca_chain": ["intermediate", "root"],
+                "private_key": "key",
+                "serial_number": "39:dd:2e",
+            }
+        }))
+        .unwrap();
+
+        let issued = issued_certificate(response.data);
+        assert_eq!(issued.bundle, "leaf\nintermediate\nroot");
+        assert_eq!(issued.key, "key");
+
+        let response = serde_json::from_value::<IssueResponse>(json!({
+            "data": { "certificate": "leaf", "issuing_ca": "ca", "private_key": "key" }
+        }))
+        .unwrap();
+        assert_eq!(issued_certificate(response.data).bundle, "leaf\nca");
+    }
+}
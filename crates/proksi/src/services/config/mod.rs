@@ -71,6 +71,9 @@ impl EventHandler for FileWatcherServiceHandler {
         // remove the command path, take the rest
         let current_args = std::env::args().skip(1);
 
+        // The restarted process tells systemd when it's ready again
+        super::systemd::notify_reloading();
+
         // restart the process
         let _ = std::process::Command::new(cmd).args(current_args).exec();
 
@@ -90,7 +93,7 @@ impl Service for FileWatcherService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.auto_reload.enabled.is_some_and(|v| !v) {
@@ -126,7 +129,10 @@ impl Service for FileWatcherService {
             interval.tick().await;
 
             loop {
-                interval.tick().await;
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
                 if watcher.poll().is_ok() {
                     tracing::debug!("config watcher service tick");
                 }
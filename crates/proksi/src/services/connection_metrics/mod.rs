@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::proxy_server::connection_metrics;
+
+/// Interval of the checks of the closed client connections, the lifetime they record is
+/// longer by as much at most
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A service recording the lifetime and the requests of the client connections once
+/// they are closed
+pub struct ConnectionMetricsService;
+
+#[async_trait]
+impl Service for ConnectionMetricsService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => connection_metrics::record_closed(),
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "connection_metrics_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
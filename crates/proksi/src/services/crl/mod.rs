@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::proxy_server::revocation;
+
+/// Interval of the checks of the CRL files
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A service reading the CRL files of the client certificates again when they change, for
+/// the next handshakes
+pub struct CrlReloadService;
+
+#[async_trait]
+impl Service for CrlReloadService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut ticks = tokio::time::interval(RELOAD_INTERVAL);
+        ticks.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            if revocation::has_crls() {
+                revocation::reload_crls();
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "crl_reload_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
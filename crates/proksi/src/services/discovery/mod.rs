@@ -1,5 +1,6 @@
 use std::net::ToSocketAddrs;
-use std::{borrow::Cow, str::FromStr, sync::Arc, time::Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{borrow::Cow, collections::HashMap, str::FromStr, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -7,21 +8,50 @@ use async_trait::async_trait;
 use http::{HeaderName, HeaderValue};
 use openssl::pkey::PKey;
 use openssl::x509::X509;
-use pingora::lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer};
+use pingora::lb::{selection::RoundRobin, LoadBalancer};
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::{error::RecvError, Sender};
 
-use crate::config::{Route, RouteCache, RouteUpstream};
+use crate::config::validate::check_route;
+use crate::config::{
+    IpFilter, Route, RouteBodyLogging, RouteCache, RouteCanary, RouteCapture, RouteCompression,
+    RouteErrorPages, RouteGrpc, RouteHeaderRules, RouteLimits, RouteMirror, RouteSslClientAuth,
+    RouteStaticFiles, RouteStickySessions, RouteStreaming, RouteUpstream, RouteWebSocket,
+};
+use crate::plugins;
+use crate::proxy_server::body_logging::BodyLogging;
+use crate::proxy_server::canary::Canary;
+use crate::proxy_server::capture::Capture;
+use crate::proxy_server::client_certificates::ClientAuth;
+use crate::proxy_server::error_pages::ErrorPages;
+use crate::proxy_server::header_rules::HeaderRules;
+use crate::proxy_server::methods::AllowedMethods;
+use crate::proxy_server::mime_types::MimeTypes;
+use crate::proxy_server::mirror::Mirror;
 use crate::MsgRoute;
 use crate::{
     config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher, RoutePlugin},
-    stores::{self, routes::RouteStoreContainer},
+    stores::{
+        self,
+        events::{self, StoreEvent},
+        routes::RouteStoreContainer,
+        secrets,
+    },
     MsgProxy,
 };
 
+use super::health_check::TimedHealthCheck;
+
+/// Whether the routes of the configuration (and of the store) were added to the router
+static ROUTES_LOADED: AtomicBool = AtomicBool::new(false);
+
+pub fn routes_loaded() -> bool {
+    ROUTES_LOADED.load(Ordering::Relaxed)
+}
+
 // Service discovery for load balancers
 pub struct RoutingService {
     config: Arc<Config>,
@@ -34,13 +64,10 @@ impl RoutingService {
     }
 
     /// From a given configuration file, create the static load balancing configuration
+    /// The routes are added to the router at once, in a single version of the table.
     async fn add_routes_from_config(&mut self) {
+        let mut containers = Vec::with_capacity(self.config.routes.len());
         for route in &self.config.routes {
-            let self_signed_cert_on_failure = route
-                .ssl_certificate
-                .as_ref()
-                .and_then(|v| v.self_signed_on_failure);
-
             if let Err(err) = add_route_ssl_to_store(route).await {
                 tracing::error!(
                     "failed to add SSL certificate to store for host {:?}: {err}",
@@ -48,18 +75,49 @@ impl RoutingService {
                 );
             }
 
-            add_route_to_router(
-                &route.host,
-                route.upstreams.clone(),
-                route.match_with.clone(),
-                route.headers.as_ref(),
-                route.plugins.as_ref(),
-                route.cache.as_ref(),
-                self_signed_cert_on_failure.unwrap_or(false),
-            );
+            match route_container(route, false) {
+                Ok(container) => containers.extend(container.map(|c| (route.host.to_string(), c))),
+                Err(err) => {
+                    tracing::info!("{err}");
+                    continue;
+                }
+            }
+            stores::insert_route_definition(route.clone());
 
             tracing::debug!("Added route: {}, {:?}", route.host, route.upstreams);
         }
+
+        stores::update_routes(|routes| {
+            for (host, container) in containers {
+                routes.insert(host, container);
+            }
+        });
+    }
+
+    /// Restores the routes created or changed with the admin API, they take precedence
+    /// over the routes of the configuration
+    async fn add_routes_from_store() {
+        let routes = match stores::global::get_store().get_routes().await {
+            Ok(routes) => routes,
+            Err(err) => {
+                tracing::error!("failed to load the routes from the store: {err}");
+                return;
+            }
+        };
+
+        for route in routes {
+            let route = match serde_json::from_str::<Route>(&route) {
+                Ok(route) => route,
+                Err(err) => {
+                    tracing::error!("invalid route in the store: {err}");
+                    continue;
+                }
+            };
+
+            if let Err(err) = apply_route(&route).await {
+                tracing::error!("failed to restore route {}: {err}", route.host);
+            }
+        }
     }
 
     /// Watch for new routes being added and update the Router Store
@@ -78,6 +136,8 @@ impl RoutingService {
         let route_header = RouteHeader {
             add: Some(route.host_headers_add),
             remove: Some(route.host_headers_remove),
+            request: None,
+            response: None,
         };
 
         // create route upstreams from ip + port
@@ -94,6 +154,7 @@ impl RoutingService {
                         weight: Some(1),
                         headers: None,
                         sni: None,
+                        protocol: None,
                     })
                     .collect::<Vec<_>>()
                 } else {
@@ -102,15 +163,41 @@ impl RoutingService {
             })
             .collect::<Vec<_>>();
 
-        add_route_to_router(
+        let container = build_route_container(
             &route.host,
             upstreams,
             matcher,
             Some(&route_header),
             Some(&route.plugins),
             None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
             route.self_signed_certs,
+            None,
+            None,
+            false,
         );
+        match container {
+            Ok(Some(container)) => stores::insert_route(route.host.to_string(), container),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::info!("{err}");
+                return;
+            }
+        }
 
         tracing::debug!(
             "Added route: {}, {:?} self-signed: {}",
@@ -126,16 +213,43 @@ impl Service for RoutingService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         // Setup initial routes from config file
         self.add_routes_from_config().await;
+        if self.config.admin.persist_routes {
+            Self::add_routes_from_store().await;
+        }
+        ROUTES_LOADED.store(true, Ordering::Relaxed);
 
-        // Watch for new hosts being added and configure them accordingly
+        // Watch for new hosts being added and configure them accordingly, and for the
+        // events of the stores (e.g. routes changed by the other instances sharing the store)
         let mut receiver = self.broadcast.subscribe();
-        while let Ok(MsgProxy::NewRoute(route)) = receiver.recv().await {
-            Self::watch_for_route_changes(route);
+        let mut store_events = events::subscribe();
+        stores::global::get_store().forward_changes();
+        let persist_routes = self.config.admin.persist_routes;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                message = receiver.recv() => {
+                    let Ok(MsgProxy::NewRoute(route)) = message else {
+                        break;
+                    };
+                    Self::watch_for_route_changes(route);
+                }
+                event = store_events.recv() => match event {
+                    Ok(event) => apply_store_event(event, persist_routes).await,
+                    // Some routes of the other instances may have been missed
+                    Err(RecvError::Lagged(_)) => {
+                        plugins::clear_settings_caches();
+                        if persist_routes {
+                            Self::add_routes_from_store().await;
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
         }
     }
 
@@ -164,17 +278,143 @@ fn has_new_backend(host: &str, upstream_input: &LoadBalancer<RoundRobin>) -> boo
     }
 }
 
-/// Adds new routes to the store if there are changes to an existing route or
-/// if the host does not exist in the store.
-fn add_route_to_router(
+/// Adds (or replaces) a route of the admin API, after its SSL certificate (if any)
+pub async fn apply_route(route: &Route) -> Result<(), anyhow::Error> {
+    super::secrets::load_route(route).await?;
+    add_route_ssl_to_store(route).await?;
+    add_route(route, true)?;
+    stores::insert_route_definition(route.clone());
+
+    Ok(())
+}
+
+/// Keeps the state depending on the routes in sync with the router, and applies the
+/// routes created, changed or deleted through the admin API of another instance
+async fn apply_store_event(event: StoreEvent, persist_routes: bool) {
+    let (host, route) = match event {
+        StoreEvent::Routes { hosts, .. } => {
+            stores::remove_cache_routing(&hosts);
+            plugins::clear_settings_caches();
+            return;
+        }
+        StoreEvent::SharedRoute { host, route } if persist_routes => (host, route),
+        StoreEvent::SharedRoute { .. } | StoreEvent::Certificate { .. } => return,
+    };
+
+    let Some(route) = route else {
+        tracing::info!("route {host} deleted by another instance");
+        delete_route(&host);
+        return;
+    };
+
+    let route = serde_json::from_str::<Route>(&route)
+        .map_err(anyhow::Error::from)
+        .and_then(|route| check_route(&route).map(|()| route));
+    match route {
+        Ok(route) => match apply_route(&route).await {
+            Ok(()) => tracing::info!("route {host} changed by another instance"),
+            Err(err) => tracing::error!("failed to apply route {host} of another instance: {err}"),
+        },
+        Err(err) => tracing::error!("invalid route {host} in the store: {err}"),
+    }
+}
+
+/// Removes a route of the configuration or the admin API from the router
+pub fn delete_route(host: &str) {
+    stores::remove_route(host);
+    stores::remove_route_definition(host);
+}
+
+fn add_route(route: &Route, replace_existing: bool) -> Result<(), anyhow::Error> {
+    if let Some(container) = route_container(route, replace_existing)? {
+        stores::insert_route(route.host.to_string(), container);
+    }
+
+    Ok(())
+}
+
+fn route_container(
+    route: &Route,
+    replace_existing: bool,
+) -> Result<Option<RouteStoreContainer>, anyhow::Error> {
+    // The plugins, headers and upstreams get the values of the secret references
+    let route = secrets::resolve_route(route)?;
+    let self_signed_cert_on_failure = route
+        .ssl_certificate
+        .as_ref()
+        .and_then(|v| v.self_signed_on_failure);
+    let certificate_issuer = route
+        .ssl_certificate
+        .as_ref()
+        .and_then(|v| v.issuer.as_deref());
+    let client_auth = route.ssl.as_ref().and_then(|v| v.client_auth.as_ref());
+    // The requests of a blue/green route go to the upstreams of its active pool
+    let upstreams = route.blue_green.as_ref().map_or_else(
+        || route.upstreams.clone(),
+        |pools| pools.active_upstreams().to_vec(),
+    );
+
+    build_route_container(
+        &route.host,
+        upstreams,
+        route.match_with.clone(),
+        route.headers.as_ref(),
+        route.plugins.as_ref(),
+        route.cache.as_ref(),
+        route.compression.as_ref(),
+        route.limits.as_ref(),
+        route.websocket.as_ref(),
+        route.grpc.as_ref(),
+        route.streaming.as_ref(),
+        route.sticky_sessions.as_ref(),
+        route.static_files.as_ref(),
+        route.mime_types.as_ref(),
+        route.error_pages.as_ref(),
+        route.ip_filter.as_ref(),
+        route.methods.as_deref(),
+        route.canary.as_ref(),
+        route.mirror.as_ref(),
+        route.capture.as_ref(),
+        route.body_logging.as_ref(),
+        self_signed_cert_on_failure.unwrap_or(false),
+        certificate_issuer,
+        client_auth,
+        replace_existing,
+    )
+}
+
+/// Builds the route for the router if there are changes to an existing route or
+/// if the host does not exist in the router (or always, with `replace_existing`),
+/// `None` otherwise. The route is swapped in a single operation when it's inserted,
+/// requests use either the old or the new one.
+#[allow(clippy::too_many_arguments)]
+fn build_route_container(
     host: &str,
     upstream_input: Vec<RouteUpstream>,
     match_with: Option<RouteMatcher>,
     headers: Option<&RouteHeader>,
     plugins: Option<&Vec<RoutePlugin>>,
     cache: Option<&RouteCache>,
+    compression: Option<&RouteCompression>,
+    limits: Option<&RouteLimits>,
+    websocket: Option<&RouteWebSocket>,
+    grpc: Option<&RouteGrpc>,
+    streaming: Option<&RouteStreaming>,
+    sticky_sessions: Option<&RouteStickySessions>,
+    static_files: Option<&RouteStaticFiles>,
+    mime_types: Option<&HashMap<String, String>>,
+    error_pages: Option<&RouteErrorPages>,
+    ip_filter: Option<&IpFilter>,
+    methods: Option<&[String]>,
+    canary: Option<&RouteCanary>,
+    mirror: Option<&RouteMirror>,
+    capture: Option<&RouteCapture>,
+    body_logging: Option<&RouteBodyLogging>,
     should_self_sign_cert_on_failure: bool,
-) {
+    certificate_issuer: Option<&str>,
+    client_auth: Option<&RouteSslClientAuth>,
+    replace_existing: bool,
+) -> Result<Option<RouteStoreContainer>, anyhow::Error> {
     // Check if current route already exists
     let upstream_str = upstream_input
         .iter()
@@ -182,29 +422,102 @@ fn add_route_to_router(
         .collect::<Vec<String>>();
 
     let Ok(mut upstreams) = LoadBalancer::<RoundRobin>::try_from_iter(upstream_str) else {
-        tracing::info!(
+        return Err(anyhow!(
             "Could not create upstreams for host: {}, upstreams {:?}",
             host,
             upstream_input
-        );
-        return;
+        ));
     };
 
-    if stores::get_route_by_key(host).is_some() && !has_new_backend(host, &upstreams) {
+    if !replace_existing
+        && stores::get_route_by_key(host).is_some()
+        && !has_new_backend(host, &upstreams)
+    {
         tracing::debug!("skipping update, no routing changes for host: {}", host);
-        return;
+        return Ok(None);
     }
 
     // TODO: support defining health checks in the configuration file
-    let tcp_health_check = TcpHealthCheck::new();
-    upstreams.set_health_check(tcp_health_check);
+    upstreams.set_health_check(TimedHealthCheck::new(host));
     upstreams.health_check_frequency = Some(Duration::from_secs(15));
 
     // Create new routing container
     let mut route_store_container = RouteStoreContainer::new(upstreams);
     route_store_container.self_signed_certificate = should_self_sign_cert_on_failure;
+    route_store_container.certificate_issuer = certificate_issuer.map(ToString::to_string);
     route_store_container.upstreams = upstream_input;
     route_store_container.cache = cache.cloned();
+    route_store_container.compression = compression.filter(|c| c.enabled.unwrap_or(true)).cloned();
+    route_store_container.limits = limits.cloned();
+    route_store_container.websocket = websocket.cloned();
+    route_store_container.grpc = grpc.cloned();
+    route_store_container.streaming = streaming.filter(|s| s.enabled.unwrap_or(true)).cloned();
+    route_store_container.sticky_sessions = sticky_sessions
+        .filter(|s| s.enabled.unwrap_or(true))
+        .cloned();
+    route_store_container.static_files =
+        static_files.filter(|s| s.enabled.unwrap_or(true)).cloned();
+    route_store_container.ip_filter = ip_filter.cloned();
+    // Validated when the configuration is loaded (see `check_config`)
+    route_store_container.mime_types = mime_types
+        .map(MimeTypes::from_config)
+        .transpose()
+        .inspect_err(|err| tracing::error!("invalid mime_types for host {host}: {err}"))
+        .ok()
+        .flatten()
+        .map(Arc::new);
+    // The templates are read again when the configuration is reloaded
+    route_store_container.error_pages = error_pages
+        .filter(|e| e.enabled.unwrap_or(true))
+        .map(ErrorPages::from_config)
+        .transpose()
+        .inspect_err(|err| tracing::error!("invalid error_pages for host {host}: {err}"))
+        .ok()
+        .flatten()
+        .map(Arc::new);
+    // The CA certificates are read again when the configuration is reloaded. The route
+    // isn't served without them, rather than without verifying its clients.
+    route_store_container.client_auth = client_auth
+        .map(ClientAuth::from_config)
+        .transpose()
+        .map_err(|err| anyhow!("invalid ssl.client_auth for host {host}: {err}"))?
+        .map(Arc::new);
+    // Not served rather than accepting every method
+    route_store_container.methods = methods
+        .map(AllowedMethods::from_config)
+        .transpose()
+        .map_err(|err| anyhow!("invalid methods for host {host}: {err}"))?
+        .map(Arc::new);
+    // Not served rather than sending the share of the canary to the stable pool
+    route_store_container.canary = canary
+        .map(|canary| Canary::from_config(host, canary))
+        .transpose()
+        .map_err(|err| anyhow!("invalid canary for host {host}: {err}"))?
+        .map(Arc::new);
+    // Validated when the configuration is loaded (see `check_config`)
+    route_store_container.mirror = mirror
+        .map(Mirror::from_config)
+        .transpose()
+        .inspect_err(|err| tracing::error!("invalid mirror for host {host}: {err}"))
+        .ok()
+        .flatten()
+        .map(Arc::new);
+    // The file is kept open by the capture, a new one is opened when the route changes
+    route_store_container.capture = capture
+        .map(Capture::from_config)
+        .transpose()
+        .inspect_err(|err| tracing::error!("invalid capture for host {host}: {err}"))
+        .ok()
+        .flatten()
+        .map(Arc::new);
+    // Validated when the configuration is loaded (see `check_config`)
+    route_store_container.body_logging = body_logging
+        .map(BodyLogging::from_config)
+        .transpose()
+        .inspect_err(|err| tracing::error!("invalid body_logging for host {host}: {err}"))
+        .ok()
+        .flatten()
+        .map(Arc::new);
 
     if let Some(headers) = headers {
         if let Some(headers) = headers.add.as_ref() {
@@ -223,12 +536,50 @@ fn add_route_to_router(
             route_store_container.host_header_remove =
                 to_remove.iter().map(|v| v.name.to_string()).collect();
         }
+
+        // Validated when the configuration is loaded (see `check_config`)
+        let compile = |rules: Option<&RouteHeaderRules>| {
+            rules
+                .map(HeaderRules::from_config)
+                .transpose()
+                .inspect_err(|err| tracing::error!("invalid header rules for host {host}: {err}"))
+                .ok()
+                .flatten()
+                .map(Arc::new)
+        };
+        route_store_container.request_headers = compile(headers.request.as_ref());
+        route_store_container.response_headers = compile(headers.response.as_ref());
     }
 
     if let Some(plugins) = plugins {
         for plugin in plugins {
             match plugin.name.as_ref() {
-                "oauth2" | "request_id" | "basic_auth" => {
+                "oauth2"
+                | "request_id"
+                | "basic_auth"
+                | "jwt"
+                | "lua"
+                | "oidc"
+                | "forward_auth"
+                | "api_key"
+                | "geoip"
+                | "request_decompression"
+                | "waf"
+                | "bot_filter"
+                | "security_headers"
+                | "response_rewrite"
+                | "html_inject"
+                | "esi"
+                | "experiment"
+                | "fault_injection"
+                | "cookie_rewrite"
+                | "ext_proc"
+                | "openapi"
+                | "signed_url"
+                | "hotlink"
+                | "redirects"
+                | "tls_fingerprint"
+                | "challenge" => {
                     route_store_container
                         .plugins
                         .insert(plugin.name.to_string(), plugin.clone());
@@ -252,11 +603,12 @@ fn add_route_to_router(
         }
     }
 
-    stores::insert_route(host.to_string(), route_store_container);
+    Ok(Some(route_store_container))
 }
 
 // TODO: refactor this into its own module
 async fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
+    let route = secrets::resolve_route(route)?;
     let Some(ssl_path) = route.ssl.as_ref().and_then(|v| v.path.as_ref()) else {
         return Ok(());
     };
@@ -274,7 +626,13 @@ async fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
         )
     })?;
 
-    let key = PKey::private_key_from_pem(key_from_file.as_bytes()).map_err(|err| {
+    let key = match ssl_path.passphrase.as_deref() {
+        Some(passphrase) => {
+            PKey::private_key_from_pem_passphrase(key_from_file.as_bytes(), passphrase.as_bytes())
+        }
+        None => PKey::private_key_from_pem(key_from_file.as_bytes()),
+    };
+    let key = key.map_err(|err| {
         anyhow::anyhow!(
             "Failed to load private key from file {:?}: {err}",
             ssl_path.key
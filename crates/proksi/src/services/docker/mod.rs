@@ -464,7 +464,7 @@ impl Service for LabelService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        mut _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.docker.enabled.is_some_and(|v| !v) {
@@ -480,7 +480,10 @@ impl Service for LabelService {
 
         interval.tick().await;
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
             self.send_route_message(self.get_routes_from_docker().await);
         }
     }
@@ -0,0 +1,156 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::proxy_server::connections;
+
+/// Interval of the checks of the in-flight requests, once proksi shuts down
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Requests of the HTTP service and connections of the TCP listeners being served (the
+/// requests of the HTTPS service are the `connections`)
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether something was still in flight at the end of its deadline, proksi then exits
+/// with a failure status
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// A request or connection being served, until it's dropped
+pub struct InFlight;
+
+impl InFlight {
+    pub fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+        InFlight
+    }
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Acquire) + connections::count()
+}
+
+/// Whether the shutdown had to terminate requests, logs or cache writes still in flight
+pub fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::Acquire)
+}
+
+/// Records that something was still in flight at the end of its deadline
+pub fn record_timeout() {
+    TIMED_OUT.store(true, Ordering::Release);
+}
+
+/// Waits until nothing is `pending` anymore, for at most `deadline`. Returns whether
+/// everything finished in time, the shutdown is recorded as timed out otherwise.
+pub async fn wait_for(deadline: Duration, pending: impl Fn() -> usize) -> bool {
+    let started = Instant::now();
+    while pending() > 0 {
+        if started.elapsed() >= deadline {
+            record_timeout();
+            return false;
+        }
+        tokio::time::sleep(DRAIN_INTERVAL.min(deadline)).await;
+    }
+    true
+}
+
+/// Waits for the in-flight requests and connections to finish once proksi shuts down, for
+/// at most the drain period (`server.shutdown.drain_secs`). The other background services
+/// stop with it.
+pub struct DrainService {
+    drain: Duration,
+}
+
+impl DrainService {
+    pub fn new(drain_secs: u64) -> Self {
+        Self {
+            drain: Duration::from_secs(drain_secs),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for DrainService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+
+        let started = Instant::now();
+        tracing::info!(
+            "shutting down, draining {} in-flight requests for at most {}s",
+            in_flight(),
+            self.drain.as_secs()
+        );
+
+        // The requests still in flight after the drain period are terminated with the
+        // runtimes of their services
+        if !wait_for(self.drain, in_flight).await {
+            tracing::warn!("the drain period ended before the in-flight requests finished");
+        }
+        tracing::info!(
+            "drained the in-flight requests in {}ms, {} left",
+            started.elapsed().as_millis(),
+            in_flight()
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        "drain_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_for() {
+        assert!(wait_for(Duration::from_secs(1), || 0).await);
+        assert!(!timed_out());
+
+        // Finishes before the deadline
+        let started = Instant::now();
+        assert!(
+            wait_for(Duration::from_secs(5), || usize::from(
+                started.elapsed() < DRAIN_INTERVAL
+            ))
+            .await
+        );
+        assert!(!timed_out());
+
+        // Still in flight at the deadline
+        let started = Instant::now();
+        assert!(!wait_for(Duration::from_millis(250), || 1).await);
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(timed_out());
+    }
+}
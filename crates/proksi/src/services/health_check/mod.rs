@@ -1,12 +1,25 @@
-use std::time::Duration;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use pingora::{
+    lb::{
+        health_check::{HealthCheck, TcpHealthCheck},
+        Backend,
+    },
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
 
-use crate::stores::{self};
+use crate::{
+    metrics,
+    stores::{
+        self,
+        health::{self, HealthSource, UpstreamsHealth},
+    },
+};
 
 /// Health check service that will run health checks on all upstreams
 /// And update the route store with the new healthy upstreams.
@@ -19,27 +32,143 @@ impl HealthService {
     }
 }
 
-async fn run_health_check_loop() {
+/// TCP health check of the upstreams of a host, recording how long each check took
+/// (`proksi_upstream_health_check_duration_seconds`)
+pub struct TimedHealthCheck {
+    host: String,
+    check: Box<TcpHealthCheck>,
+}
+
+impl TimedHealthCheck {
+    pub fn new(host: &str) -> Box<Self> {
+        Box::new(Self {
+            host: host.to_string(),
+            check: TcpHealthCheck::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TimedHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let started = Instant::now();
+        let result = self.check.check(target).await;
+        metrics::UPSTREAM_HEALTH_CHECK_SECONDS
+            .with_label_values(&[self.host.as_str(), &target.addr.to_string()])
+            .set(started.elapsed().as_secs_f64());
+        result
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.check.health_threshold(success)
+    }
+}
+
+async fn run_health_check_loop(mut shutdown: ShutdownWatch) {
     let mut interval = tokio::time::interval(Duration::from_secs(30));
     interval.tick().await;
 
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
 
-        for (host, route_container) in &stores::get_routes() {
+        // The upstreams that were removed lose their last check
+        metrics::UPSTREAM_HEALTH_CHECK_SECONDS.reset();
+        let routes = stores::get_routes();
+        for (host, route_container) in routes.iter() {
             tracing::trace!("Running health check for host {}", host);
 
-            // clone the route_container
-            let route_container = route_container.clone();
-            route_container.load_balancer.update().await.ok();
-            route_container
-                .load_balancer
-                .backends()
-                .run_health_check(false)
-                .await;
-
-            // insert it back into the store
-            stores::insert_route(host.clone(), route_container);
+            // The load balancers are shared with the store, there is nothing to insert back
+            // (which could also restore a route changed or deleted in the meantime)
+            let canary = route_container.canary.as_ref().map(|c| &c.load_balancer);
+            for load_balancer in std::iter::once(&route_container.load_balancer).chain(canary) {
+                load_balancer.update().await.ok();
+                let backends = load_balancer.backends();
+                backends.run_health_check(false).await;
+
+                for backend in backends.get_backend().iter() {
+                    health::report_active(host, &backend.addr.to_string(), backends.ready(backend));
+                }
+            }
+        }
+
+        health::retain(|host, address| {
+            routes.get(host).is_some_and(|route| {
+                let canary = route.canary.as_ref().map(|c| &c.load_balancer);
+                std::iter::once(&route.load_balancer)
+                    .chain(canary)
+                    .any(|lb| {
+                        let backends = lb.backends().get_backend();
+                        backends.iter().any(|b| b.addr.to_string() == address)
+                    })
+            })
+        });
+    }
+}
+
+/// The upstreams that were healthy (or unknown) in `previous` and are unhealthy in `state`,
+/// with the check that took them out
+fn ejected<'a>(
+    previous: &'a UpstreamsHealth,
+    state: &'a UpstreamsHealth,
+) -> impl Iterator<Item = (&'a str, &'a str, HealthSource)> {
+    state.iter().flat_map(move |(host, upstreams)| {
+        upstreams
+            .iter()
+            .filter(move |(address, upstream)| {
+                let was_healthy = previous
+                    .get(host)
+                    .and_then(|upstreams| upstreams.get(*address))
+                    .is_none_or(|previous| previous.healthy);
+                was_healthy && !upstream.healthy
+            })
+            .map(move |(address, upstream)| (host.as_str(), address.as_str(), upstream.source))
+    })
+}
+
+/// Keeps the metrics of the upstreams and their pools in sync with their health
+async fn export_health_metrics(mut shutdown: ShutdownWatch) {
+    let mut receiver = health::subscribe();
+    // The upstreams restored unhealthy on startup were not ejected by this instance
+    let mut previous: Option<Arc<UpstreamsHealth>> = None;
+    loop {
+        let state = receiver.borrow_and_update().clone();
+        metrics::UPSTREAM_HEALTHY.reset();
+        metrics::UPSTREAM_POOL_UPSTREAMS.reset();
+        for (host, upstreams) in state.iter() {
+            let healthy = upstreams.values().filter(|u| u.healthy).count();
+            for (label, count) in [
+                ("healthy", healthy),
+                ("unhealthy", upstreams.len() - healthy),
+            ] {
+                metrics::UPSTREAM_POOL_UPSTREAMS
+                    .with_label_values(&[host.as_str(), label])
+                    .set(i64::try_from(count).unwrap_or(i64::MAX));
+            }
+            for (address, upstream) in upstreams {
+                metrics::UPSTREAM_HEALTHY
+                    .with_label_values(&[host.as_str(), address.as_str()])
+                    .set(i64::from(upstream.healthy));
+            }
+        }
+        if let Some(previous) = previous.as_ref() {
+            for (host, address, source) in ejected(previous, &state) {
+                metrics::UPSTREAM_EJECTIONS
+                    .with_label_values(&[host, address, source.as_str()])
+                    .inc();
+            }
+        }
+        previous = Some(state);
+
+        tokio::select! {
+            changed = receiver.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            _ = shutdown.changed() => return,
         }
     }
 }
@@ -49,12 +178,15 @@ impl Service for HealthService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         tracing::info!("Starting health check service");
 
-        run_health_check_loop().await;
+        tokio::join!(
+            run_health_check_loop(shutdown.clone()),
+            export_health_metrics(shutdown)
+        );
     }
 
     fn name(&self) -> &'static str {
@@ -65,3 +197,52 @@ impl Service for HealthService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::stores::health::UpstreamHealth;
+
+    use super::*;
+
+    fn state(upstreams: &[(&str, bool, HealthSource)]) -> UpstreamsHealth {
+        let upstreams = upstreams
+            .iter()
+            .map(|(address, healthy, source)| {
+                let health = UpstreamHealth {
+                    healthy: *healthy,
+                    source: *source,
+                    failures: 0,
+                    changed_at: 0,
+                    last_error: None,
+                };
+                (address.to_string(), health)
+            })
+            .collect::<BTreeMap<_, _>>();
+        BTreeMap::from([("pool.localhost".to_string(), upstreams)])
+    }
+
+    #[test]
+    fn test_ejected() {
+        let previous = state(&[
+            ("10.0.0.1:3000", true, HealthSource::Active),
+            ("10.0.0.2:3000", false, HealthSource::Active),
+        ]);
+        let next = state(&[
+            ("10.0.0.1:3000", false, HealthSource::Passive),
+            ("10.0.0.2:3000", false, HealthSource::Active),
+            ("10.0.0.3:3000", false, HealthSource::Active),
+        ]);
+
+        // Upstreams that were already out of the pool are not ejected again
+        let ejected = ejected(&previous, &next).collect::<Vec<_>>();
+        assert_eq!(
+            ejected,
+            vec![
+                ("pool.localhost", "10.0.0.1:3000", HealthSource::Passive),
+                ("pool.localhost", "10.0.0.3:3000", HealthSource::Active),
+            ]
+        );
+    }
+}
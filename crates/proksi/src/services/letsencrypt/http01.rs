@@ -9,18 +9,29 @@ use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
-use tokio::time;
+use tokio::{sync::broadcast::error::RecvError, time};
 use tracing::info;
 
 use crate::{
     config::Config,
-    stores::{self, certificates::Certificate},
+    stores::{
+        self,
+        certificates::{Certificate, RenewalState},
+        events::{self, StoreEvent},
+    },
 };
 
-use super::storage::PersistType;
+use super::{self_check, storage::PersistType};
 
 /// Default interval in days to attempt renewal of certificates
-const DEFAULT_RENEW_INTERVAL_DAYS: i64 = 30;
+pub const DEFAULT_RENEW_INTERVAL_DAYS: i64 = 30;
+
+/// Interval of the checks for routes without a certificate, in between the changes of the
+/// routes (e.g. to retry the orders that failed)
+pub(crate) const ROUTES_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Longest time an order stays locked, in case its instance stops before unlocking it
+pub(crate) const ORDER_LOCK_TTL: Duration = Duration::from_secs(600);
 
 /// A service that handles the creation of certificates using the Let's Encrypt API
 pub struct LetsencryptService {
@@ -46,7 +57,7 @@ impl LetsencryptService {
 
     /// Update global certificate store with new `X509` and `PKey` for the
     /// given domain also considering that the certificate could be a bundle file.
-    async fn insert_certificate(
+    pub(crate) async fn insert_certificate(
         domain: &str,
         bundle: &str,
         key_pem: &str,
@@ -84,6 +95,7 @@ impl LetsencryptService {
 
     /// Start an HTTP-01 challenge for a given order
     async fn handle_http_01_challenge(
+        &self,
         order: &mut NewOrder<PersistType>,
     ) -> Result<(), anyhow::Error> {
         for auth in order.authorizations()? {
@@ -105,6 +117,16 @@ impl LetsencryptService {
                 return Err(anyhow!("Failed to set challenge in store: {}", err));
             }
 
+            // A validation that can't succeed still counts against the rate limits
+            if self.config.lets_encrypt.self_check.unwrap_or(true) {
+                self_check::check_challenge(
+                    auth.domain_name(),
+                    challenge.http_token(),
+                    &challenge.http_proof(),
+                )
+                .await?;
+            }
+
             // Let's Encrypt will check the domain's URL to validate the challenge
             tracing::info!("HTTP-01 validating (retry: 5s)...");
             challenge.validate(5000)?; // Retry every 5000 ms
@@ -116,7 +138,7 @@ impl LetsencryptService {
     /// cannot be used.
     /// Note this is only useful for local development or testing purposes
     /// and should be used sparingly
-    async fn create_self_signed_certificate(
+    pub(crate) async fn create_self_signed_certificate(
         domain: &str,
         enabled: bool,
     ) -> Result<(), anyhow::Error> {
@@ -176,8 +198,53 @@ impl LetsencryptService {
         }
     }
 
-    /// Create a new order for a domain (HTTP-01 challenge)
+    /// Create a new order for a domain, recording its outcome in the renewal status of the domain
     async fn create_order_for_domain(
+        &self,
+        domain: &str,
+        account: &Account<PersistType>,
+    ) -> Result<(), anyhow::Error> {
+        // Instances sharing the store order each certificate once, the others get it
+        // from the store when it's issued
+        let lock = format!("order:{domain}");
+        let store = stores::global::get_store();
+        if !store
+            .try_lock(&lock, ORDER_LOCK_TTL)
+            .await
+            .map_err(|err| anyhow!("failed to lock the order of {domain}: {err}"))?
+        {
+            tracing::info!("another instance is ordering the certificate of {domain}");
+            return Ok(());
+        }
+
+        stores::update_renewal_status(domain, |status| {
+            status.state = RenewalState::Ordering;
+            status.last_attempt_at = Some(unix_now());
+        });
+
+        let result = self.order_certificate(domain, account).await;
+        if let Err(err) = store.unlock(&lock).await {
+            tracing::warn!("failed to unlock the order of {domain}: {err}");
+        }
+
+        stores::update_renewal_status(domain, |status| match &result {
+            Ok(()) => {
+                status.state = RenewalState::Issued;
+                status.last_success_at = Some(unix_now());
+                status.last_error = None;
+            }
+            Err(err) => {
+                status.state = RenewalState::Failed;
+                status.last_error = Some(err.to_string());
+            }
+        });
+
+        result
+    }
+
+    /// Order a certificate for a domain (HTTP-01 challenge)
+    async fn order_certificate(
+        &self,
         domain: &str,
         account: &Account<PersistType>,
     ) -> Result<(), anyhow::Error> {
@@ -191,7 +258,7 @@ impl LetsencryptService {
 
             // Get the possible authorizations (for a single domain
             // this will only be one element).
-            Self::handle_http_01_challenge(&mut order)
+            self.handle_http_01_challenge(&mut order)
                 .await
                 .map_err(|err| anyhow!("Failed to handle HTTP-01 challenge: {err}"))?;
 
@@ -212,13 +279,31 @@ impl LetsencryptService {
     }
 
     /// Watch for route changes and create or update certificates for new routes
-    async fn watch_for_route_changes(&self, account: &Account<PersistType>) {
-        let mut interval = time::interval(Duration::from_secs(20));
+    async fn watch_for_route_changes(
+        &self,
+        account: &Account<PersistType>,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let mut store_events = events::subscribe();
+        let mut interval = time::interval(ROUTES_CHECK_INTERVAL);
 
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+                event = store_events.recv() => match event {
+                    Ok(StoreEvent::Routes { .. }) | Err(RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            }
             tracing::debug!("checking for new routes to create certificates for");
-            for (key, value) in &stores::get_routes() {
+            for (key, value) in stores::get_routes().iter() {
+                // Ordered from their own issuer (see `certificate_issuers`)
+                if value.certificate_issuer.is_some() {
+                    continue;
+                }
+
                 if stores::global::get_store()
                     .get_certificates()
                     .await
@@ -227,14 +312,18 @@ impl LetsencryptService {
                     continue;
                 }
 
-                Self::handle_certificate_for_domain(key, account, value.self_signed_certificate)
+                self.handle_certificate_for_domain(key, account, value.self_signed_certificate)
                     .await;
             }
         }
     }
 
     /// Check for certificates expiration and renew them if needed
-    async fn check_for_certificates_expiration(&self, account: &Account<PersistType>) {
+    async fn check_for_certificates_expiration(
+        &self,
+        account: &Account<PersistType>,
+        mut shutdown: ShutdownWatch,
+    ) {
         let mut interval = time::interval(Duration::from_secs(
             self.config
                 .lets_encrypt
@@ -244,7 +333,11 @@ impl LetsencryptService {
 
         loop {
             tracing::debug!("checking for certificates to renew");
-            for (domain, _) in &stores::get_routes() {
+            for (domain, route) in stores::get_routes().iter() {
+                if route.certificate_issuer.is_some() {
+                    continue;
+                }
+
                 let Ok(Some(cert)) = account.certificate(domain) else {
                     continue;
                 };
@@ -258,7 +351,8 @@ impl LetsencryptService {
                 }
 
                 tracing::info!("trying to renew certificate for domain: {domain}");
-                if let Err(error) = Self::create_order_for_domain(domain, account)
+                if let Err(error) = self
+                    .create_order_for_domain(domain, account)
                     .await
                     .map_err(|e| anyhow!("Failed to create order for {domain}: {e}"))
                 {
@@ -266,17 +360,28 @@ impl LetsencryptService {
                 }
             }
 
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
         }
     }
 
     async fn handle_certificate_for_domain(
+        &self,
         domain: &str,
         account: &Account<PersistType>,
         self_signed_on_failure: bool,
     ) {
         match account.certificate(domain) {
             Ok(Some(cert)) => {
+                // Issued by a previous run
+                if stores::get_renewal_status(domain).is_none() {
+                    stores::update_renewal_status(domain, |status| {
+                        status.state = RenewalState::Issued;
+                    });
+                }
+
                 // Certificate already exists
                 if stores::global::get_store()
                     .get_certificates()
@@ -293,26 +398,64 @@ impl LetsencryptService {
                 };
             }
             Ok(None) => {
-                if Self::create_order_for_domain(domain, account)
-                    .await
-                    .is_err()
-                {
-                    Self::create_self_signed_certificate(domain, self_signed_on_failure)
-                        .await
-                        .ok();
+                // Orders that failed a moment ago (e.g. before a restart) are not retried
+                // right away, the CA limits the failed validations
+                if recently_failed(domain) {
+                    if self_signed_on_failure {
+                        Self::create_self_signed_certificate(domain, true)
+                            .await
+                            .ok();
+                    }
+                    return;
+                }
+
+                if self.create_order_for_domain(domain, account).await.is_err() {
+                    let created =
+                        Self::create_self_signed_certificate(domain, self_signed_on_failure).await;
+
+                    if self_signed_on_failure && created.is_ok() {
+                        stores::update_renewal_status(domain, |status| {
+                            status.state = RenewalState::SelfSigned;
+                        });
+                    }
                 }
             }
-            _ => {}
+            Err(err) => {
+                tracing::error!("failed to read the certificate of domain {domain}: {err}");
+                stores::update_renewal_status(domain, |status| {
+                    status.state = RenewalState::Failed;
+                    status.last_error = Some(err.to_string());
+                });
+            }
         }
     }
 }
 
+/// Whether the last order of the domain failed less than `ROUTES_CHECK_INTERVAL` ago
+pub(crate) fn recently_failed(domain: &str) -> bool {
+    stores::get_renewal_status(domain).is_some_and(|status| {
+        matches!(
+            status.state,
+            RenewalState::Failed | RenewalState::SelfSigned
+        ) && status
+            .last_attempt_at
+            .is_some_and(|at| unix_now().saturating_sub(at) < ROUTES_CHECK_INTERVAL.as_secs())
+    })
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 #[async_trait]
 impl Service for LetsencryptService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        mut _shutdown: ShutdownWatch,
+        shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.lets_encrypt.enabled.is_some_and(|v| !v) {
@@ -332,8 +475,8 @@ impl Service for LetsencryptService {
             .expect("failed to create or retrieve existing account");
 
         let _ = tokio::join!(
-            self.watch_for_route_changes(&account),
-            self.check_for_certificates_expiration(&account)
+            self.watch_for_route_changes(&account, shutdown.clone()),
+            self.check_for_certificates_expiration(&account, shutdown)
         );
     }
 
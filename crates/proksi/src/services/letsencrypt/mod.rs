@@ -1,2 +1,3 @@
 pub mod http01;
+pub mod self_check;
 pub mod storage;
@@ -0,0 +1,118 @@
+//! Checks that the proof of an HTTP-01 challenge is served on the domain before the CA is
+//! asked to validate it: every failed validation counts against the rate limits of the CA.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use reqwest::StatusCode;
+
+/// Requests of the proof, the store may take a moment to share the challenge between the
+/// instances
+const ATTEMPTS: usize = 3;
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Characters of an unexpected body shown in the errors
+const MAX_BODY_SHOWN: usize = 64;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// Whether the response is the proof, as the CA expects it
+fn verify(status: StatusCode, body: &str, proof: &str) -> Result<(), String> {
+    if status != StatusCode::OK {
+        return Err(format!("responded with {status}"));
+    }
+    if body.trim() != proof {
+        let shown = body.chars().take(MAX_BODY_SHOWN).collect::<String>();
+        return Err(format!("responded with {shown:?} instead of the proof"));
+    }
+    Ok(())
+}
+
+/// Requests `http://<domain>/.well-known/acme-challenge/<token>` like the CA will, through
+/// the DNS of the domain, and checks the proof is served
+pub async fn check_challenge(domain: &str, token: &str, proof: &str) -> anyhow::Result<()> {
+    let url = format!("http://{domain}/.well-known/acme-challenge/{token}");
+
+    let mut last_error = String::new();
+    for attempt in 0..ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+
+        let result = match CLIENT.get(&url).send().await {
+            Ok(response) => {
+                let status = response.status();
+                match response.text().await {
+                    Ok(body) => verify(status, &body, proof),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => last_error = err,
+        }
+    }
+
+    Err(anyhow!(
+        "the self-check of the HTTP-01 challenge failed, {url} {last_error}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        assert!(verify(StatusCode::OK, "token.thumbprint\n", "token.thumbprint").is_ok());
+        assert_eq!(
+            verify(StatusCode::NOT_FOUND, "", "token.thumbprint"),
+            Err("responded with 404 Not Found".to_string())
+        );
+        assert_eq!(
+            verify(StatusCode::OK, "<html>", "token.thumbprint"),
+            Err("responded with \"<html>\" instead of the proof".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_challenge() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]).to_string();
+                let response = if request.starts_with("GET /.well-known/acme-challenge/token ") {
+                    "HTTP/1.1 200 OK\r\ncontent-length: 16\r\n\r\ntoken.thumbprint"
+                } else {
+                    "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n"
+                };
+                stream.write_all(response.as_bytes()).await.ok();
+            }
+        });
+
+        assert!(check_challenge(&domain, "token", "token.thumbprint")
+            .await
+            .is_ok());
+        let err = check_challenge(&domain, "other", "other.thumbprint")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("responded with 404 Not Found"));
+    }
+}
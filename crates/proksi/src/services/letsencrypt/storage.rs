@@ -51,7 +51,7 @@ impl CertificatePersist {
                     .unwrap_or("redis://localhost:6379");
                 PersistType::Redis(RedisPersist::new(url))
             }
-            crate::config::StoreType::Memory => {
+            crate::config::StoreType::Memory | crate::config::StoreType::File => {
                 // Get directory based on whether we are running on staging/production
                 // LetsEncrypt configurations
                 let certificates_dir = self.get_lets_encrypt_directory();
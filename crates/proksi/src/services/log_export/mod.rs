@@ -0,0 +1,494 @@
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use once_cell::sync::OnceCell;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    config::{LogExport, LogExportSink},
+    metrics,
+};
+
+/// Time given to the sink to accept a batch
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry of a batch, doubled on each of the next ones
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Access logs waiting to be batched, set when an export is configured
+static QUEUE: OnceCell<mpsc::Sender<AccessLogEvent>> = OnceCell::new();
+
+/// Requests to send the access logs queued until then, answered once they are sent
+static FLUSHES: OnceCell<mpsc::UnboundedSender<oneshot::Sender<()>>> = OnceCell::new();
+
+/// Orders the batches spilled in the same millisecond
+static SPILLED: AtomicU64 = AtomicU64::new(0);
+
+/// An access log, as inserted in the ClickHouse table or posted to the HTTP endpoint
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccessLogEvent {
+    /// Unix timestamp, in milliseconds
+    pub timestamp: u128,
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    /// `0` when no response was sent (e.g. the client disconnected)
+    pub status: u16,
+    pub duration_ms: u128,
+    pub client_ip: String,
+    pub remote_addr: String,
+    pub user_agent: String,
+    pub referer: String,
+    pub http_version: String,
+    pub upstream: Option<String>,
+    pub cache: Option<String>,
+    pub request_id: Option<String>,
+    pub trace_id: Option<String>,
+}
+
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+/// Whether the access logs are exported, the events are only built then
+pub fn is_enabled() -> bool {
+    QUEUE.get().is_some()
+}
+
+/// Queues the access log, it's dropped when the queue is full (the sink is slower than
+/// the requests)
+pub fn export(event: AccessLogEvent) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if queue.try_send(event).is_err() {
+        count("dropped", 1);
+    }
+}
+
+/// Sends the access logs queued until now, e.g. before proksi exits
+pub async fn flush() {
+    let Some(flushes) = FLUSHES.get() else {
+        return;
+    };
+
+    let (done, flushed) = oneshot::channel();
+    if flushes.send(done).is_ok() {
+        let _ = flushed.await;
+    }
+}
+
+fn count(result: &str, events: usize) {
+    metrics::LOG_EXPORT_EVENTS
+        .with_label_values(&[result])
+        .inc_by(events as u64);
+}
+
+/// One JSON object per line, the format of both sinks
+fn encode(events: &[AccessLogEvent]) -> Vec<u8> {
+    let mut batch = Vec::with_capacity(events.len() * 256);
+    for event in events {
+        if serde_json::to_writer(&mut batch, event).is_ok() {
+            batch.push(b'\n');
+        }
+    }
+    batch
+}
+
+/// The URL the batches are sent to, the insert query of ClickHouse is in its query
+fn endpoint(config: &LogExport) -> anyhow::Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(&config.url)
+        .map_err(|err| anyhow!("logging.export.url {} is invalid: {err}", config.url))?;
+
+    if config.sink == LogExportSink::ClickHouse {
+        let table = config
+            .table
+            .as_deref()
+            .filter(|table| !table.is_empty())
+            .ok_or_else(|| anyhow!("logging.export.table is required with clickhouse"))?;
+        url.query_pairs_mut()
+            .append_pair("query", &format!("INSERT INTO {table} FORMAT JSONEachRow"));
+    }
+    Ok(url)
+}
+
+/// Checks the export settings, before proksi starts
+pub fn check_config(config: &LogExport) -> anyhow::Result<()> {
+    endpoint(config)?;
+    if config.batch_size == 0 {
+        return Err(anyhow!("logging.export.batch_size must be greater than 0"));
+    }
+    if config.flush_interval_secs == 0 {
+        return Err(anyhow!(
+            "logging.export.flush_interval_secs must be greater than 0"
+        ));
+    }
+    if config.queue_size == 0 {
+        return Err(anyhow!("logging.export.queue_size must be greater than 0"));
+    }
+    if config.spill_path.as_deref().is_some_and(Path::is_file) {
+        return Err(anyhow!("logging.export.spill_path must be a directory"));
+    }
+    Ok(())
+}
+
+/// Directory the batches are written to while the sink is down, one file per batch
+struct Spill {
+    path: PathBuf,
+    max_size: u64,
+}
+
+impl Spill {
+    /// The spilled batches, oldest first
+    async fn batches(&self) -> Vec<(PathBuf, u64)> {
+        let mut batches = vec![];
+        let Ok(mut entries) = tokio::fs::read_dir(&self.path).await else {
+            return batches;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "ndjson") {
+                let size = entry.metadata().await.map(|m| m.len()).unwrap_or_default();
+                batches.push((path, size));
+            }
+        }
+        // Named after the time they were spilled at
+        batches.sort();
+        batches
+    }
+
+    /// Whether the batch was written, the ones over `max_size` are not
+    async fn write(&self, batch: &[u8]) -> bool {
+        let size: u64 = self.batches().await.iter().map(|(_, size)| size).sum();
+        if size + batch.len() as u64 > self.max_size {
+            return false;
+        }
+
+        let name = format!(
+            "{:020}-{:020}.ndjson",
+            unix_millis(),
+            SPILLED.fetch_add(1, Ordering::Relaxed)
+        );
+        let written = async {
+            tokio::fs::create_dir_all(&self.path).await?;
+            // Written under a temporary name, so that a partial batch is never sent
+            let partial = self.path.join(format!("{name}.partial"));
+            tokio::fs::write(&partial, batch).await?;
+            tokio::fs::rename(&partial, self.path.join(&name)).await
+        };
+        written
+            .await
+            .inspect_err(|err| tracing::error!("failed to spill access logs: {err}"))
+            .is_ok()
+    }
+}
+
+fn lines(batch: &[u8]) -> usize {
+    batch.iter().filter(|b| **b == b'\n').count()
+}
+
+/// Ships the access logs to ClickHouse or to an HTTP bulk endpoint, in batches of
+/// `batch_size` logs or every `flush_interval_secs`. The batches the sink does not accept
+/// after `max_retries` are spilled to `spill_path`, and sent again once it's back.
+pub struct LogExportService {
+    config: LogExport,
+    endpoint: reqwest::Url,
+    client: reqwest::Client,
+    receiver: mpsc::Receiver<AccessLogEvent>,
+    spill: Option<Spill>,
+}
+
+impl LogExportService {
+    pub fn new(config: &LogExport) -> anyhow::Result<Self> {
+        let endpoint = endpoint(config)?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        let (queue, receiver) = mpsc::channel(config.queue_size.max(1));
+        QUEUE
+            .set(queue)
+            .map_err(|_| anyhow!("the access logs are already exported"))?;
+
+        Ok(Self {
+            endpoint,
+            client,
+            receiver,
+            spill: config.spill_path.as_ref().map(|path| Spill {
+                path: path.clone(),
+                max_size: config.max_spill_size,
+            }),
+            config: config.clone(),
+        })
+    }
+
+    /// Whether the sink accepted the batch, after `max_retries` retries at most
+    async fn send(&self, batch: &[u8]) -> bool {
+        let body = if self.config.compression {
+            let mut encoder =
+                GzEncoder::new(Vec::with_capacity(batch.len() / 4), Compression::fast());
+            match encoder.write_all(batch).and_then(|()| encoder.finish()) {
+                Ok(body) => body,
+                Err(err) => {
+                    tracing::error!("failed to compress access logs: {err}");
+                    return false;
+                }
+            }
+        } else {
+            batch.to_vec()
+        };
+
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 0..=self.config.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            }
+
+            let mut request = self
+                .client
+                .post(self.endpoint.clone())
+                .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+                .body(body.clone());
+            if self.config.compression {
+                request = request.header(http::header::CONTENT_ENCODING, "gzip");
+            }
+            for (name, value) in &self.config.headers {
+                request = request.header(name, value);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return true,
+                Ok(response) => tracing::warn!(
+                    attempt,
+                    "the log sink rejected a batch of access logs: {}",
+                    response.status()
+                ),
+                Err(err) => {
+                    tracing::warn!(attempt, "failed to send a batch of access logs: {err}");
+                }
+            }
+        }
+        false
+    }
+
+    async fn ship(&self, events: &mut Vec<AccessLogEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let batch = encode(events);
+        let count_events = events.len();
+        events.clear();
+
+        if self.send(&batch).await {
+            count("sent", count_events);
+            // The sink is back, the batches spilled while it was down follow
+            self.replay().await;
+            return;
+        }
+
+        match &self.spill {
+            Some(spill) if spill.write(&batch).await => count("spilled", count_events),
+            _ => {
+                tracing::error!("dropped {count_events} access logs, the log sink is down");
+                count("dropped", count_events);
+            }
+        }
+    }
+
+    /// Sends the spilled batches, oldest first, until the sink fails again
+    async fn replay(&self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+
+        for (path, _) in spill.batches().await {
+            let Ok(batch) = tokio::fs::read(&path).await else {
+                continue;
+            };
+            if !self.send(&batch).await {
+                return;
+            }
+            count("sent", lines(&batch));
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                tracing::error!("failed to remove the spilled access logs {path:?}: {err}");
+                return;
+            }
+        }
+    }
+
+    async fn has_spilled(&self) -> bool {
+        match &self.spill {
+            Some(spill) => !spill.batches().await.is_empty(),
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl Service for LogExportService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        _shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        tracing::info!("exporting the access logs to {}", self.config.url);
+
+        let (flushes, mut flush_requests) = mpsc::unbounded_channel();
+        let _ = FLUSHES.set(flushes);
+
+        let batch_size = self.config.batch_size.max(1);
+        let mut events = Vec::with_capacity(batch_size);
+        let mut ticks =
+            tokio::time::interval(Duration::from_secs(self.config.flush_interval_secs.max(1)));
+        loop {
+            tokio::select! {
+                event = self.receiver.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    events.push(event);
+                    if events.len() >= batch_size {
+                        self.ship(&mut events).await;
+                    }
+                }
+                Some(done) = flush_requests.recv() => {
+                    // The logs queued before the request are all in the channel already
+                    while let Ok(event) = self.receiver.try_recv() {
+                        events.push(event);
+                        if events.len() >= batch_size {
+                            self.ship(&mut events).await;
+                        }
+                    }
+                    self.ship(&mut events).await;
+                    let _ = done.send(());
+                }
+                _ = ticks.tick() => {
+                    if !events.is_empty() {
+                        self.ship(&mut events).await;
+                    } else if self.has_spilled().await {
+                        // No request since the sink went down
+                        self.replay().await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "log_export_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(value: serde_json::Value) -> LogExport {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let clickhouse = config(json!({
+            "sink": "clickhouse",
+            "url": "http://clickhouse:8123",
+            "table": "logs.access",
+        }));
+        assert_eq!(
+            endpoint(&clickhouse).unwrap().as_str(),
+            "http://clickhouse:8123/?query=INSERT+INTO+logs.access+FORMAT+JSONEachRow"
+        );
+        assert_eq!(clickhouse.batch_size, 1000);
+        assert!(clickhouse.compression);
+
+        let http = config(json!({ "sink": "http", "url": "https://logs.internal/bulk" }));
+        assert_eq!(
+            endpoint(&http).unwrap().as_str(),
+            "https://logs.internal/bulk"
+        );
+
+        assert!(
+            check_config(&config(json!({ "sink": "clickhouse", "url": "http://ch" }))).is_err()
+        );
+        assert!(check_config(&config(json!({ "sink": "http", "url": "logs" }))).is_err());
+        assert!(check_config(&config(
+            json!({ "sink": "http", "url": "http://logs", "batch_size": 0 })
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let events = [
+            AccessLogEvent {
+                host: "export.localhost".to_string(),
+                status: 200,
+                ..AccessLogEvent::default()
+            },
+            AccessLogEvent {
+                host: "export.localhost".to_string(),
+                status: 503,
+                request_id: Some("abc".to_string()),
+                ..AccessLogEvent::default()
+            },
+        ];
+        let batch = encode(&events);
+        assert_eq!(lines(&batch), 2);
+
+        let rows = batch
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows[0]["status"], 200);
+        assert_eq!(rows[1]["request_id"], "abc");
+    }
+
+    #[tokio::test]
+    async fn test_spill() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let spill = Spill {
+            path: path.clone(),
+            max_size: 11,
+        };
+
+        assert!(spill.batches().await.is_empty());
+        assert!(spill.write(b"{\"a\":1}\n").await);
+        assert!(spill.write(b"{}\n").await);
+        assert!(!spill.write(b"{}\n").await, "the spill is full");
+
+        let batches = spill.batches().await;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            tokio::fs::read(&batches[0].0).await.unwrap(),
+            b"{\"a\":1}\n",
+            "the oldest batch comes first"
+        );
+
+        tokio::fs::remove_dir_all(path).await.unwrap();
+    }
+}
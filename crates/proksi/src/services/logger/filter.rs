@@ -0,0 +1,160 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tracing_subscriber::EnvFilter;
+
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>;
+
+static LOG_FILTER: OnceCell<LogFilter> = OnceCell::new();
+
+/// Filter of the logs, which can be changed at runtime (e.g. through the admin API)
+struct LogFilter {
+    /// Filter of the configuration (`logging.level`), restored when a change expires
+    default: String,
+    reload: Reload,
+    state: Mutex<FilterState>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FilterState {
+    pub filter: String,
+    pub default: String,
+    /// Unix timestamp (in seconds) at which the default filter is restored
+    pub revert_at: Option<u64>,
+    /// Incremented on every change, so that an expired change doesn't revert a newer one
+    #[serde(skip)]
+    generation: u64,
+}
+
+/// Registers the function reloading the filter of the tracing subscriber
+pub fn init(
+    default: &str,
+    reload: impl Fn(EnvFilter) -> Result<(), String> + Send + Sync + 'static,
+) {
+    let state = FilterState {
+        filter: default.to_string(),
+        default: default.to_string(),
+        ..FilterState::default()
+    };
+
+    LOG_FILTER
+        .set(LogFilter {
+            default: default.to_string(),
+            reload: Box::new(reload),
+            state: Mutex::new(state),
+        })
+        .ok();
+}
+
+/// Active filter, `None` before the tracing subscriber is initialized
+pub fn current() -> Option<FilterState> {
+    let log_filter = LOG_FILTER.get()?;
+    log_filter.state.lock().ok().map(|state| state.clone())
+}
+
+/// Replaces the filter with the given directives (e.g. `proksi=debug,info`), the filter of
+/// the configuration is restored after `duration`
+pub fn set(directives: &str, duration: Duration) -> anyhow::Result<FilterState> {
+    let log_filter = LOG_FILTER
+        .get()
+        .ok_or_else(|| anyhow!("logging is not initialized"))?;
+    EnvFilter::try_new(directives).map_err(|err| anyhow!("invalid filter: {err}"))?;
+
+    let state = apply(log_filter, directives, Some(duration))?;
+
+    let generation = state.generation;
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        revert(generation);
+    });
+
+    Ok(state)
+}
+
+/// Restores the filter of the configuration
+pub fn reset() -> anyhow::Result<FilterState> {
+    let log_filter = LOG_FILTER
+        .get()
+        .ok_or_else(|| anyhow!("logging is not initialized"))?;
+
+    apply(log_filter, &log_filter.default, None)
+}
+
+fn revert(generation: u64) {
+    let Some(log_filter) = LOG_FILTER.get() else {
+        return;
+    };
+
+    let is_latest = log_filter
+        .state
+        .lock()
+        .is_ok_and(|state| state.generation == generation);
+
+    if is_latest {
+        tracing::info!("restoring the log filter {}", log_filter.default);
+        apply(log_filter, &log_filter.default, None).ok();
+    }
+}
+
+fn apply(
+    log_filter: &LogFilter,
+    directives: &str,
+    duration: Option<Duration>,
+) -> anyhow::Result<FilterState> {
+    let mut state = log_filter
+        .state
+        .lock()
+        .map_err(|_| anyhow!("log filter lock is poisoned"))?;
+
+    (log_filter.reload)(EnvFilter::try_new(directives)?).map_err(|err| anyhow!(err))?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    state.filter = directives.to_string();
+    state.revert_at = duration.map(|duration| (now + duration).as_secs());
+    state.generation += 1;
+
+    Ok(state.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_and_revert() {
+        let reloaded = Arc::new(Mutex::new(Vec::new()));
+        let reloaded_clone = reloaded.clone();
+        init("info", move |filter| {
+            reloaded_clone.lock().unwrap().push(filter.to_string());
+            Ok(())
+        });
+
+        assert!(set("not a filter[", Duration::from_secs(1)).is_err());
+
+        let state = set("proksi=debug", Duration::from_millis(50)).unwrap();
+        assert_eq!(state.filter, "proksi=debug");
+        assert!(state.revert_at.is_some());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let state = current().unwrap();
+        assert_eq!(state.filter, "info");
+        assert!(state.revert_at.is_none());
+        assert_eq!(*reloaded.lock().unwrap(), vec!["proksi=debug", "info"]);
+
+        // A newer change is not reverted by an older one expiring
+        set("debug", Duration::from_millis(50)).unwrap();
+        set("trace", Duration::from_secs(60)).unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(current().unwrap().filter, "trace");
+        assert_eq!(reset().unwrap().filter, "info");
+    }
+}
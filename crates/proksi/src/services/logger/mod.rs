@@ -6,10 +6,13 @@ use std::{
         Arc,
     },
     task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
 
+use bytes::BytesMut;
+use once_cell::sync::OnceCell;
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
@@ -18,25 +21,95 @@ use pingora::{
 use rotation::Rotation;
 use tokio::{
     io::AsyncWriteExt,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
 };
-use tracing_subscriber::fmt::MakeWriter;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
 
-use crate::config::Config;
+use crate::{
+    buffers::LOG_BUFFERS,
+    config::{Config, LogFormat},
+};
 
+pub mod filter;
 mod rotation;
 
-/// A `io::Write` implementation that sends logs to a background service
+/// Interval of the heartbeats of the logger service, while it's not blocked writing logs
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time of the last heartbeat of the logger service (UNIX seconds)
+static LAST_HEARTBEAT: AtomicI64 = AtomicI64::new(0);
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| i64::try_from(now.as_secs()).unwrap_or(i64::MAX))
+}
+
+/// Requests to write the logs received until then, answered once they are written
+static FLUSHES: OnceCell<UnboundedSender<oneshot::Sender<()>>> = OnceCell::new();
+
+/// Writes the logs sent until now by the other services, e.g. before proksi exits
+pub async fn flush() {
+    let Some(flushes) = FLUSHES.get() else {
+        return;
+    };
+
+    let (done, flushed) = oneshot::channel();
+    if flushes.send(done).is_ok() {
+        let _ = flushed.await;
+    }
+}
+
+/// Whether the logger service had a heartbeat in the last 5 seconds, i.e. it still writes
+/// the logs it receives
+pub fn is_alive() -> bool {
+    unix_now() - LAST_HEARTBEAT.load(Ordering::Relaxed) <= 5
+}
+
+/// Creates the tracing subscriber writing to the `appender`, its filter can be changed at
+/// runtime through the admin API
+pub fn init_subscriber(config: &Config, appender: ProxyLog) {
+    let level = LevelFilter::from(&config.logging.level).to_string();
+
+    if config.logging.format == LogFormat::Json {
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::new(&level))
+            .with_writer(appender)
+            .with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        filter::init(&level, move |f| handle.reload(f).map_err(|e| e.to_string()));
+        subscriber.init();
+    } else {
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new(&level))
+            .with_ansi(config.logging.path.is_none())
+            .with_writer(appender)
+            .with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        filter::init(&level, move |f| handle.reload(f).map_err(|e| e.to_string()));
+        subscriber.init();
+    }
+}
+
+/// A `io::Write` implementation that sends logs to a background service, in buffers of
+/// `LOG_BUFFERS` the service gives back once written
 #[derive(Debug, Clone)]
 pub struct StdoutWriter<'a> {
-    chan: &'a UnboundedSender<Vec<u8>>,
+    chan: &'a UnboundedSender<BytesMut>,
     skip_log: bool,
 }
 
 impl io::Write for StdoutWriter<'_> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if !self.skip_log {
-            self.chan.send(buf.to_vec()).ok();
+            let mut buffer = LOG_BUFFERS.get();
+            buffer.extend_from_slice(buf);
+            self.chan.send(buffer).ok();
         }
         Ok(buf.len())
     }
@@ -54,7 +127,7 @@ impl io::Write for StdoutWriter<'_> {
 #[derive(Debug)]
 pub struct ProxyLog {
     enabled: bool,
-    chan: UnboundedSender<Vec<u8>>,
+    chan: UnboundedSender<BytesMut>,
     access_logs: bool,
     error_logs: bool,
 }
@@ -62,7 +135,7 @@ pub struct ProxyLog {
 impl ProxyLog {
     #[allow(clippy::fn_params_excessive_bools)]
     pub fn new(
-        sender: UnboundedSender<Vec<u8>>,
+        sender: UnboundedSender<BytesMut>,
         log_enabled: bool,
         access_logs: bool,
         error_logs: bool,
@@ -142,7 +215,7 @@ impl tokio::io::AsyncWrite for LogWriter {
 
 /// A background service that receives logs from the main thread and writes them to stdout
 pub struct ProxyLoggerReceiver {
-    receiver: UnboundedReceiver<Vec<u8>>,
+    receiver: UnboundedReceiver<BytesMut>,
     config: Arc<Config>,
     bufwriter: tokio::io::BufWriter<LogWriter>,
     suffix: String,
@@ -156,7 +229,7 @@ pub struct Inner {
 }
 
 impl ProxyLoggerReceiver {
-    pub fn new(receiver: UnboundedReceiver<Vec<u8>>, config: &Arc<Config>) -> Self {
+    pub fn new(receiver: UnboundedReceiver<BytesMut>, config: &Arc<Config>) -> Self {
         ProxyLoggerReceiver {
             receiver,
             config: config.clone(),
@@ -270,10 +343,32 @@ impl Service for ProxyLoggerReceiver {
         tracing::info!("starting logger service");
         self.prepare_buf_writer().await;
 
-        while let Some(buf) = self.receiver.recv().await {
-            let _ = self.bufwriter.write(&buf).await.ok();
-
-            self.handle_log_rotation().await;
+        let (flushes, mut flush_requests) = mpsc::unbounded_channel();
+        let _ = FLUSHES.set(flushes);
+
+        let mut heartbeats = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                buf = self.receiver.recv() => {
+                    let Some(buf) = buf else {
+                        break;
+                    };
+                    let _ = self.bufwriter.write(&buf).await.ok();
+                    LOG_BUFFERS.put(buf);
+
+                    self.handle_log_rotation().await;
+                }
+                Some(done) = flush_requests.recv() => {
+                    // The logs sent before the request are all in the channel already
+                    while let Ok(buf) = self.receiver.try_recv() {
+                        let _ = self.bufwriter.write(&buf).await.ok();
+                        LOG_BUFFERS.put(buf);
+                    }
+                    let _ = self.bufwriter.flush().await;
+                    let _ = done.send(());
+                }
+                _ = heartbeats.tick() => LAST_HEARTBEAT.store(unix_now(), Ordering::Relaxed),
+            }
         }
     }
 
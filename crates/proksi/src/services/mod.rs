@@ -1,21 +1,42 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use alerts::AlertService;
 use async_trait::async_trait;
+use blue_green::BlueGreenService;
+use certificate_issuers::CertificateIssuerService;
 use config::FileWatcherService;
+use connection_metrics::ConnectionMetricsService;
+use crl::CrlReloadService;
 use discovery::RoutingService;
 use docker::LabelService;
+use drain::DrainService;
 use letsencrypt::http01::LetsencryptService;
 use pingora::server::{ListenFds, ShutdownWatch};
+use secrets::SecretsService;
+use session_tickets::SessionTicketService;
+use systemd::SystemdService;
 use tokio::sync::broadcast::Sender;
+use warm_restart::WarmRestartService;
 
-use crate::{config::Config, MsgProxy};
+use crate::{cache::disk::handlers, config::Config, MsgProxy};
 
+pub mod alerts;
+pub mod blue_green;
+pub mod certificate_issuers;
 pub mod config;
+pub mod connection_metrics;
+pub mod crl;
 pub mod discovery;
 pub mod docker;
+pub mod drain;
 pub mod health_check;
 pub mod letsencrypt;
+pub mod log_export;
 pub mod logger;
+pub mod secrets;
+pub mod session_tickets;
+pub mod systemd;
+pub mod warm_restart;
 
 /// Exploring: what if we grouped all the services into a single service using a single thread?
 pub struct BackgroundFunctionService {
@@ -37,20 +58,60 @@ impl pingora::services::Service for BackgroundFunctionService {
         shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
+        // The state of the previous run is restored first, before the other services use it
+        let mut warm_restart_service = WarmRestartService::new(self.config.clone());
+        warm_restart_service.restore().await;
+
         let mut routing_service = RoutingService::new(self.config.clone(), self.broadcast.clone());
 
         let mut health_service = health_check::HealthService::new();
         let mut docker_service = LabelService::new(self.config.clone(), self.broadcast.clone());
         let mut letsencrypt_service = LetsencryptService::new(self.config.clone());
+        let mut issuer_service = CertificateIssuerService::new(self.config.clone());
         let mut config_server = FileWatcherService::new(self.config.clone());
+        let mut secrets_service = SecretsService::new(self.config.clone());
+        let mut session_ticket_service = SessionTicketService::new(self.config.clone());
+        let mut crl_service = CrlReloadService;
+        let mut connection_metrics_service = ConnectionMetricsService;
+        let mut blue_green_service = BlueGreenService::new(&self.config);
+        let mut alert_service = AlertService::new(&self.config);
+        let mut systemd_service = SystemdService::new(&self.config);
+        let mut drain_service = DrainService::new(self.config.server.shutdown.drain_secs);
 
         let _ = tokio::join!(
+            warm_restart_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             routing_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             health_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             config_server.start_service(None, shutdown.clone(), _listeners_per_fd),
             docker_service.start_service(None, shutdown.clone(), _listeners_per_fd),
-            letsencrypt_service.start_service(None, shutdown, _listeners_per_fd),
+            letsencrypt_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            issuer_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            secrets_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            session_ticket_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            crl_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            connection_metrics_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            blue_green_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            alert_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            systemd_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            drain_service.start_service(None, shutdown, _listeners_per_fd),
+        );
+
+        // The services stop once proksi shuts down and its in-flight requests are drained,
+        // then the last logs and the cache files still being written are finished before
+        // the runtimes are shut down (at the end of the grace period)
+        let timeout = Duration::from_secs(self.config.server.shutdown.timeout_secs);
+        let flush = async { tokio::join!(logger::flush(), log_export::flush()) };
+        let (flushed, written) = tokio::join!(
+            tokio::time::timeout(timeout, flush),
+            drain::wait_for(timeout, handlers::writes_in_flight),
         );
+        if flushed.is_err() {
+            drain::record_timeout();
+            eprintln!("failed to write and export the last logs before exiting");
+        }
+        if !written {
+            eprintln!("failed to finish writing the cache files before exiting");
+        }
     }
 
     fn name(&self) -> &'static str {
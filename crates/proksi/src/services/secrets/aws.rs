@@ -0,0 +1,286 @@
+use std::fmt::Write as _;
+
+use anyhow::{anyhow, Context};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use crate::config::SecretsAws;
+
+/// Reads the secrets of AWS Secrets Manager and decrypts the ciphertexts of AWS KMS, with
+/// requests signed with the credentials of the environment (Signature Version 4)
+/// <https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv-create-signed-request.html>
+pub struct AwsSecrets {
+    region: String,
+    endpoint: Option<String>,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsSecrets {
+    pub fn new(config: &SecretsAws) -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name).with_context(|| format!("{name} is required by secrets.aws"))
+        };
+
+        Ok(AwsSecrets {
+            region: config.region.to_string(),
+            endpoint: config
+                .endpoint
+                .as_deref()
+                .map(|e| e.trim_end_matches('/').to_string()),
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// The secret (`SecretString`) of Secrets Manager, or a field of its JSON
+    pub async fn secret(
+        &self,
+        client: &reqwest::Client,
+        id: &str,
+        field: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let response = self
+            .call(
+                client,
+                "secretsmanager",
+                "secretsmanager.GetSecretValue",
+                &json!({ "SecretId": id }),
+            )
+            .await?;
+        let secret = response["SecretString"]
+            .as_str()
+            .ok_or_else(|| anyhow!("the secret {id} has no SecretString"))?;
+
+        let Some(field) = field else {
+            return Ok(secret.to_string());
+        };
+        let fields = serde_json::from_str::<Value>(secret)
+            .with_context(|| format!("the secret {id} is not JSON"))?;
+        match &fields[field] {
+            Value::String(value) => Ok(value.clone()),
+            Value::Null => Err(anyhow!("the secret {id} has no field {field}")),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    /// The plaintext of a ciphertext (base64) encrypted with a key of KMS
+    pub async fn decrypt(
+        &self,
+        client: &reqwest::Client,
+        ciphertext: &str,
+    ) -> anyhow::Result<String> {
+        let plaintext = self.decrypt_bytes(client, ciphertext, None).await?;
+        String::from_utf8(plaintext).context("the plaintext is not UTF-8")
+    }
+
+    /// The plaintext of a ciphertext (base64) encrypted with a key of KMS and the
+    /// encryption context, if any
+    pub async fn decrypt_bytes(
+        &self,
+        client: &reqwest::Client,
+        ciphertext: &str,
+        context: Option<&Value>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut body = json!({ "CiphertextBlob": ciphertext });
+        if let Some(context) = context {
+            body["EncryptionContext"] = context.clone();
+        }
+        let response = self
+            .call(client, "kms", "TrentService.Decrypt", &body)
+            .await?;
+        let plaintext = response["Plaintext"]
+            .as_str()
+            .ok_or_else(|| anyhow!("KMS sent no plaintext"))?;
+
+        Ok(openssl::base64::decode_block(plaintext)?)
+    }
+
+    /// Sends a signed request to the JSON API of the service
+    async fn call(
+        &self,
+        client: &reqwest::Client,
+        service: &str,
+        target: &str,
+        body: &Value,
+    ) -> anyhow::Result<Value> {
+        let url = match self.endpoint.as_deref() {
+            Some(endpoint) => format!("{endpoint}/"),
+            None => format!("https://{service}.{}.amazonaws.com/", self.region),
+        };
+        let parsed = reqwest::Url::parse(&url)?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(anyhow!("{url} has no host")),
+        };
+
+        let body = serde_json::to_vec(body)?;
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date(OffsetDateTime::now_utc())),
+            ("x-amz-target", target.to_string()),
+        ];
+        if let Some(token) = self.session_token.as_deref() {
+            headers.push(("x-amz-security-token", token.to_string()));
+            headers.sort_by_key(|(name, _)| *name);
+        }
+        let authorization = self.authorization(service, &headers, &body)?;
+
+        let mut request = client
+            .post(url)
+            .header("authorization", authorization)
+            .body(body);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let response = response.json::<Value>().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "{service} answered {status}: {}",
+                response["message"]
+                    .as_str()
+                    .or(response["Message"].as_str())
+                    .unwrap_or_default()
+            ));
+        }
+
+        Ok(response)
+    }
+
+    /// The `Authorization` header of a `POST /` request, signing its headers (sorted by
+    /// name) and its body
+    fn authorization(
+        &self,
+        service: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> anyhow::Result<String> {
+        let amz_date = headers
+            .iter()
+            .find(|(name, _)| *name == "x-amz-date")
+            .map(|(_, value)| value.as_str())
+            .ok_or_else(|| anyhow!("the request has no x-amz-date"))?;
+        let date = &amz_date[..8];
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers.iter().fold(String::new(), |mut canonical, (n, v)| {
+            let _ = writeln!(canonical, "{n}:{}", v.trim());
+            canonical
+        });
+        let canonical_request = format!(
+            "POST\n/\n\n{canonical_headers}\n{signed_headers}\n{}",
+            hex(&openssl::sha::sha256(body))
+        );
+
+        let scope = format!("{date}/{}/{service}/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&openssl::sha::sha256(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, service)?;
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes())?);
+
+        Ok(format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        ))
+    }
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'`
+fn amz_date(now: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> anyhow::Result<Vec<u8>> {
+    let key = hmac(format!("AWS4{secret}").as_bytes(), date.as_bytes())?;
+    let key = hmac(&key, region.as_bytes())?;
+    let key = hmac(&key, service.as_bytes())?;
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example of the documentation of AWS
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_authorization() {
+        let aws = AwsSecrets {
+            region: "eu-west-1".to_string(),
+            endpoint: None,
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", "kms.eu-west-1.amazonaws.com".to_string()),
+            ("x-amz-date", "20240102T030405Z".to_string()),
+            ("x-amz-target", "TrentService.Decrypt".to_string()),
+        ];
+
+        let authorization = aws.authorization("kms", &headers, b"{}").unwrap();
+        assert!(authorization.starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20240102/eu-west-1/kms/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date;x-amz-target, Signature="
+        ));
+        // Hex of a SHA-256 HMAC
+        assert_eq!(authorization.rsplit('=').next().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_amz_date() {
+        let date = OffsetDateTime::from_unix_timestamp(1_704_164_645).unwrap();
+        assert_eq!(amz_date(date), "20240102T030405Z");
+    }
+}
@@ -0,0 +1,255 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{
+    config::{Config, Route},
+    plugins,
+    stores::{
+        self,
+        secrets::{self as secret_values, SecretReference},
+    },
+};
+
+use super::discovery;
+
+pub mod aws;
+pub mod vault;
+
+/// Providers of the secret references, created from the configuration at startup
+static PROVIDERS: OnceCell<Providers> = OnceCell::new();
+
+struct Providers {
+    vault: Option<vault::VaultSecrets>,
+    aws: Option<aws::AwsSecrets>,
+    /// Root certificate trusted for the connections to Vault
+    ca_certificate: Option<reqwest::Certificate>,
+}
+
+impl Providers {
+    fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let vault = config.secrets.vault.as_ref();
+        let ca_certificate = match vault.and_then(|v| v.ca_certificate.as_deref()) {
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .with_context(|| format!("failed to read the CA certificate {path:?}"))?;
+                Some(reqwest::Certificate::from_pem(&pem)?)
+            }
+            None => None,
+        };
+
+        Ok(Providers {
+            vault: vault.map(vault::VaultSecrets::new).transpose()?,
+            aws: config
+                .secrets
+                .aws
+                .as_ref()
+                .map(aws::AwsSecrets::new)
+                .transpose()?,
+            ca_certificate,
+        })
+    }
+
+    /// A client for the requests of a refresh, the connections don't outlive the runtime
+    /// they were opened in (the secrets of the configuration are fetched before the
+    /// services start)
+    fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(10));
+        if let Some(certificate) = self.ca_certificate.clone() {
+            client = client.add_root_certificate(certificate);
+        }
+        Ok(client.build()?)
+    }
+
+    async fn fetch(&self, client: &reqwest::Client, reference: &str) -> anyhow::Result<String> {
+        let parsed = SecretReference::parse(reference)
+            .ok_or_else(|| anyhow!("{reference} is not a secret reference"))??;
+
+        match parsed {
+            SecretReference::Vault { path, field } => {
+                let vault = self
+                    .vault
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("secrets.vault is required by {reference}"))?;
+                vault.fetch(client, path, field).await
+            }
+            SecretReference::SecretsManager { id, field } => {
+                let aws = self
+                    .aws
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("secrets.aws is required by {reference}"))?;
+                aws.secret(client, id, field).await
+            }
+            SecretReference::Kms { ciphertext } => {
+                let aws = self
+                    .aws
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("secrets.aws is required by {reference}"))?;
+                aws.decrypt(client, ciphertext).await
+            }
+        }
+    }
+}
+
+/// Fetches the secret references of the configuration, before the services use them.
+/// Proksi doesn't start without them.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let providers = PROVIDERS.get_or_try_init(|| Providers::from_config(config))?;
+
+    let references = secret_values::references_in(&serde_json::to_value(config)?);
+    if references.is_empty() {
+        return Ok(());
+    }
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let client = providers.client()?;
+            for reference in &references {
+                let value = providers
+                    .fetch(&client, reference)
+                    .await
+                    .with_context(|| format!("failed to fetch the secret {reference}"))?;
+                secret_values::insert(reference, value);
+            }
+
+            tracing::info!("fetched {} secrets", references.len());
+            Ok::<(), anyhow::Error>(())
+        })
+}
+
+/// Fetches the secret references of a route that were not fetched yet (e.g. a route of
+/// the admin API)
+pub async fn load_route(route: &Route) -> anyhow::Result<()> {
+    let references = secret_values::references_in(&serde_json::to_value(route)?)
+        .into_iter()
+        .filter(|reference| secret_values::get(reference).is_none())
+        .collect::<Vec<_>>();
+    if references.is_empty() {
+        return Ok(());
+    }
+
+    let providers = PROVIDERS
+        .get()
+        .ok_or_else(|| anyhow!("the secrets providers are not configured"))?;
+    let client = providers.client()?;
+    for reference in references {
+        let value = providers
+            .fetch(&client, &reference)
+            .await
+            .with_context(|| format!("failed to fetch the secret {reference}"))?;
+        secret_values::insert(&reference, value);
+    }
+
+    Ok(())
+}
+
+/// A service fetching the secret references again, so that the secrets rotated in their
+/// provider are used without a restart
+pub struct SecretsService {
+    config: Arc<Config>,
+}
+
+impl SecretsService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Fetches every secret, returns the references whose value changed. A secret that
+    /// can't be fetched keeps its value.
+    async fn refresh(providers: &Providers) -> Vec<String> {
+        let client = match providers.client() {
+            Ok(client) => client,
+            Err(err) => {
+                tracing::error!("failed to create the client of the secrets: {err}");
+                return vec![];
+            }
+        };
+
+        let mut changed = vec![];
+        for reference in secret_values::references() {
+            match providers.fetch(&client, &reference).await {
+                Ok(value) => {
+                    if secret_values::insert(&reference, value) {
+                        changed.push(reference);
+                    }
+                }
+                Err(err) => tracing::error!("failed to refresh the secret {reference}: {err}"),
+            }
+        }
+
+        changed
+    }
+
+    /// Builds the routes using the secrets that changed again. The admin tokens and the
+    /// certificate issuers read their secrets on each use.
+    async fn apply_changes(changed: &[String]) {
+        for route in stores::get_route_definitions() {
+            let Ok(value) = serde_json::to_value(&route) else {
+                continue;
+            };
+            let references = secret_values::references_in(&value);
+            if !references.iter().any(|r| changed.contains(r)) {
+                continue;
+            }
+
+            match discovery::apply_route(&route).await {
+                Ok(()) => tracing::info!("route {} updated with its new secrets", route.host),
+                Err(err) => tracing::error!("failed to update route {}: {err}", route.host),
+            }
+        }
+
+        plugins::clear_settings_caches();
+    }
+}
+
+#[async_trait]
+impl Service for SecretsService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let Some(providers) = PROVIDERS.get() else {
+            return;
+        };
+        if providers.vault.is_none() && providers.aws.is_none() {
+            return;
+        }
+        tracing::info!("started the secrets service");
+
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            self.config.secrets.refresh_interval_secs.max(1),
+        ));
+        // The secrets were just fetched by `init`
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            let changed = Self::refresh(providers).await;
+            if !changed.is_empty() {
+                tracing::info!("{} secrets changed", changed.len());
+                Self::apply_changes(&changed).await;
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "secrets_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Context};
+use serde_json::Value;
+
+use crate::config::SecretsVault;
+
+/// Reads the secrets of Vault, from the KV engines (version 1 and 2) or any engine
+/// answering with fields <https://developer.hashicorp.com/vault/api-docs/secret/kv>
+pub struct VaultSecrets {
+    url: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl VaultSecrets {
+    pub fn new(config: &SecretsVault) -> anyhow::Result<Self> {
+        let token = match config.token.as_deref() {
+            Some(token) => token.to_string(),
+            None => std::env::var("VAULT_TOKEN")
+                .context("secrets.vault has no token and VAULT_TOKEN is not set")?,
+        };
+
+        Ok(VaultSecrets {
+            url: config.url.trim_end_matches('/').to_string(),
+            token,
+            namespace: config.namespace.as_deref().map(ToString::to_string),
+        })
+    }
+
+    /// The field of the secret at `path` (e.g. `secret/data/proksi`)
+    pub async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        path: &str,
+        field: &str,
+    ) -> anyhow::Result<String> {
+        let mut request = client
+            .get(format!("{}/v1/{}", self.url, path.trim_start_matches('/')))
+            .header("X-Vault-Token", &self.token);
+        if let Some(namespace) = self.namespace.as_deref() {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let errors = response.text().await.unwrap_or_default();
+            return Err(anyhow!("vault answered {status}: {errors}"));
+        }
+
+        secret_field(&response.json::<Value>().await?, field)
+            .ok_or_else(|| anyhow!("the secret {path} of vault has no field {field}"))
+    }
+}
+
+/// The fields are in `data.data` with the version 2 of the KV engine, in `data` otherwise
+fn secret_field(response: &Value, field: &str) -> Option<String> {
+    let data = &response["data"];
+    let fields = match data["data"].is_object() && data["metadata"].is_object() {
+        true => &data["data"],
+        false => data,
+    };
+
+    match &fields[field] {
+        Value::String(value) => Some(value.clone()),
+        Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_secret_field() {
+        let kv2 = json!({
+            "data": {
+                "data": { "token": "s3cr3t", "port": 5432 },
+                "metadata": { "version": 3 },
+            }
+        });
+        assert_eq!(secret_field(&kv2, "token").unwrap(), "s3cr3t");
+        assert_eq!(secret_field(&kv2, "port").unwrap(), "5432");
+        assert!(secret_field(&kv2, "missing").is_none());
+
+        let kv1 = json!({ "data": { "token": "s3cr3t" }, "lease_duration": 2_764_800 });
+        assert_eq!(secret_field(&kv1, "token").unwrap(), "s3cr3t");
+    }
+}
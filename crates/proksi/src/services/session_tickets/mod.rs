@@ -0,0 +1,154 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{
+    config::Config,
+    proxy_server::session_tickets::{
+        self, rotate, rotation_due, unix_now, SHARED_KEY_ACTIVATION_SECS,
+    },
+    stores,
+};
+
+/// Interval of the reads of the shared keys, shorter than the activation delay of a new key
+const SHARED_SYNC_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Longest time the rotation of the shared keys stays locked
+const ROTATION_LOCK_TTL: Duration = Duration::from_secs(30);
+
+const ROTATION_LOCK: &str = "ticket_keys";
+
+/// A service rotating the keys of the TLS session tickets (see `server.session_tickets`)
+/// and, with shared keys, reading the keys rotated by the other instances from the store
+pub struct SessionTicketService {
+    config: Arc<Config>,
+}
+
+impl SessionTicketService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Replaces the keys with a new one when the current key is old enough
+    fn rotate_local(interval: u64) -> anyhow::Result<()> {
+        let now = unix_now();
+        let keys = session_tickets::keys();
+        if rotation_due(&keys, now, interval) {
+            session_tickets::set_keys(rotate(&keys, now, interval, now)?);
+            tracing::debug!("rotated the session ticket keys");
+        }
+
+        Ok(())
+    }
+
+    /// Uses the keys of the store, and rotates them when their newest key is old enough.
+    /// A single instance rotates them, the new key activates once the others read it.
+    async fn sync_shared(interval: u64) -> anyhow::Result<()> {
+        let store = stores::global::get_store();
+        let keys = Self::read_shared().await?;
+        let now = unix_now();
+        if !rotation_due(&keys, now, interval) {
+            session_tickets::set_keys(keys);
+            return Ok(());
+        }
+
+        if !store
+            .try_lock(ROTATION_LOCK, ROTATION_LOCK_TTL)
+            .await
+            .map_err(|err| anyhow!("failed to lock the session ticket keys: {err}"))?
+        {
+            session_tickets::set_keys(keys);
+            return Ok(());
+        }
+
+        let result = async {
+            // The keys may have been rotated by another instance before the lock
+            let keys = Self::read_shared().await?;
+            if !rotation_due(&keys, now, interval) {
+                return Ok(keys);
+            }
+
+            // Without any key, the first one is used right away
+            let activates_at = match keys.is_empty() {
+                true => now,
+                false => now + SHARED_KEY_ACTIVATION_SECS,
+            };
+            let rotated = rotate(&keys, now, interval, activates_at)?;
+            store
+                .set_ticket_keys(session_tickets::serialize_keys(&rotated)?)
+                .await
+                .map_err(|err| anyhow!("failed to save the session ticket keys: {err}"))?;
+            tracing::info!("rotated the shared session ticket keys");
+            Ok::<_, anyhow::Error>(rotated)
+        }
+        .await;
+
+        if let Err(err) = store.unlock(ROTATION_LOCK).await {
+            tracing::warn!("failed to unlock the session ticket keys: {err}");
+        }
+        session_tickets::set_keys(result?);
+        Ok(())
+    }
+
+    async fn read_shared() -> anyhow::Result<Vec<session_tickets::TicketKey>> {
+        let keys = stores::global::get_store()
+            .get_ticket_keys()
+            .await
+            .map_err(|err| anyhow!("failed to read the session ticket keys: {err}"))?;
+
+        match keys {
+            Some(keys) => session_tickets::deserialize_keys(&keys),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for SessionTicketService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let config = &self.config.server.session_tickets;
+        if !config.enabled {
+            return;
+        }
+        tracing::info!("started the session tickets service");
+
+        let interval = config.rotation_interval_secs.max(1);
+        let mut ticks = tokio::time::interval(match config.shared {
+            true => SHARED_SYNC_INTERVAL,
+            // Checked more often than the rotation, to rotate on time
+            false => Duration::from_secs(interval.min(60)),
+        });
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            let result = match config.shared {
+                true => Self::sync_shared(interval).await,
+                false => Self::rotate_local(interval),
+            };
+            if let Err(err) = result {
+                tracing::error!("failed to rotate the session ticket keys: {err}");
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "session_tickets_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    ffi::OsStr,
+    os::unix::{
+        ffi::OsStrExt,
+        net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{config::Config, probes};
+
+use super::{discovery, logger};
+
+/// Interval of the checks of the listeners and routes, until proksi is ready
+const READY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sends a state to the service manager (`sd_notify`), when proksi was started by a unit
+/// of systemd with `Type=notify`. Does nothing otherwise.
+/// <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(err) = send(&path, state) {
+        tracing::warn!("failed to notify systemd: {err}");
+    }
+}
+
+/// Tells systemd the configuration is being reloaded, proksi notifies it again once ready
+pub fn notify_reloading() {
+    let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+        .map(|now| now.tv_sec() * 1_000_000 + now.tv_nsec() / 1_000)
+        .unwrap_or_default();
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={now}"));
+}
+
+fn send(path: &OsStr, state: &str) -> std::io::Result<()> {
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract sockets are only supported on Linux",
+            ))
+        }
+        None => SocketAddr::from_pathname(path)?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Interval of the keepalives expected by the watchdog of the unit (`WatchdogSec`)
+fn watchdog_timeout() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // The watchdog can be meant for another process of the unit
+    let for_proksi = std::env::var("WATCHDOG_PID")
+        .ok()
+        .is_none_or(|pid| pid.parse() == Ok(std::process::id()));
+
+    (usec > 0 && for_proksi).then(|| Duration::from_micros(usec))
+}
+
+/// Notifies systemd once proksi is ready and when it stops, and sends the keepalives of
+/// its watchdog while the core services are healthy: the HTTP and HTTPS listeners accept
+/// connections and the logger still writes the logs
+pub struct SystemdService {
+    listeners: Vec<String>,
+}
+
+impl SystemdService {
+    pub fn new(config: &Config) -> Self {
+        let listeners = [&config.server.https_address, &config.server.http_address]
+            .into_iter()
+            .flatten()
+            .map(ToString::to_string)
+            .collect();
+
+        Self { listeners }
+    }
+
+    async fn listening(&self) -> Result<(), String> {
+        for address in &self.listeners {
+            if !probes::is_listening(address).await {
+                return Err(format!("the listener {address} doesn't accept connections"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The reason proksi is unhealthy, if it is
+    async fn check_health(&self) -> Result<(), String> {
+        self.listening().await?;
+        if !logger::is_alive() {
+            return Err("the logger doesn't write the logs".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Service for SystemdService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        if std::env::var_os("NOTIFY_SOCKET").is_none() {
+            return;
+        }
+
+        // Ready once the listeners accept connections and the routes are added
+        loop {
+            if discovery::routes_loaded() && self.listening().await.is_ok() {
+                break;
+            }
+            tokio::select! {
+                () = tokio::time::sleep(READY_INTERVAL) => {}
+                _ = shutdown.changed() => {
+                    notify("STOPPING=1");
+                    return;
+                }
+            }
+        }
+        // The process started by a graceful upgrade becomes the main process of the unit
+        notify(&format!(
+            "READY=1\nMAINPID={}\nSTATUS=listening on {}",
+            std::process::id(),
+            self.listeners.join(", ")
+        ));
+        tracing::info!("notified systemd that proksi is ready");
+
+        if let Some(timeout) = watchdog_timeout() {
+            let mut keepalives = tokio::time::interval(timeout / 2);
+            let mut healthy = true;
+            loop {
+                tokio::select! {
+                    _ = keepalives.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                // Without keepalives, systemd restarts proksi once the timeout expires
+                match self.check_health().await {
+                    Ok(()) => {
+                        if !healthy {
+                            tracing::info!("proksi is healthy again");
+                        }
+                        notify(&format!(
+                            "WATCHDOG=1\nSTATUS=listening on {}",
+                            self.listeners.join(", ")
+                        ));
+                        healthy = true;
+                    }
+                    Err(reason) => {
+                        tracing::warn!("no keepalive sent to the watchdog of systemd: {reason}");
+                        notify(&format!("STATUS={reason}"));
+                        healthy = false;
+                    }
+                }
+            }
+        } else {
+            let _ = shutdown.changed().await;
+        }
+
+        notify("STOPPING=1");
+    }
+
+    fn name(&self) -> &'static str {
+        "systemd_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("proksi-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
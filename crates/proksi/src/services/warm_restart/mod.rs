@@ -0,0 +1,90 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{config::Config, stores::warm_state::WarmState};
+
+/// Restores the state saved by the previous run (see `store.warm_restart`) and saves it
+/// periodically, and on shutdown
+pub struct WarmRestartService {
+    config: Arc<Config>,
+    state: Option<WarmState>,
+}
+
+impl WarmRestartService {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            state: None,
+        }
+    }
+
+    /// Restores the state of the previous run, to be awaited before the services using
+    /// it are started
+    pub async fn restore(&mut self) {
+        let Some(config) = self.config.store.warm_restart.as_ref() else {
+            return;
+        };
+
+        let state = match WarmState::open(config) {
+            Ok(state) => state,
+            Err(err) => {
+                tracing::error!(
+                    "failed to open the warm restart state {:?}: {err}",
+                    config.path
+                );
+                return;
+            }
+        };
+        if let Err(err) = state.restore().await {
+            tracing::error!("failed to restore the state of the previous run: {err}");
+        }
+        self.state = Some(state);
+    }
+}
+
+#[async_trait]
+impl Service for WarmRestartService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let (Some(config), Some(state)) =
+            (self.config.store.warm_restart.as_ref(), self.state.as_ref())
+        else {
+            return;
+        };
+
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(config.save_interval_secs.max(1)));
+        interval.tick().await;
+
+        loop {
+            let stopping = tokio::select! {
+                _ = interval.tick() => false,
+                _ = shutdown.changed() => true,
+            };
+
+            if let Err(err) = state.save().await {
+                tracing::error!("failed to save the warm restart state: {err}");
+            }
+            if stopping {
+                break;
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "warm_restart_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use serde::Serialize;
+
+use crate::stores::health::{self, UpstreamHealth, UpstreamsHealth};
+
+const DAY_SECS: u64 = 86_400;
+/// History kept for each host, the uptime of older periods is not known
+const RETENTION_SECS: u64 = 90 * DAY_SECS;
+/// Days shown on the pages, the current one last
+pub const DAYS: u64 = 30;
+
+/// Changes of the availability of a host (Unix timestamp, in seconds), the oldest first
+type Changes = VecDeque<(u64, Availability)>;
+
+/// Changes of the availability of each host since it was first checked
+static HISTORY: Lazy<Mutex<HashMap<String, Changes>>> = Lazy::new(Mutex::default);
+
+/// Availability of a host, from the health of its upstreams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Availability {
+    /// Every upstream is healthy
+    Up,
+    /// Some upstreams are unhealthy, the others still serve the requests
+    Degraded,
+    /// No upstream is healthy
+    Down,
+    /// The upstreams of the host are not checked (yet)
+    Unknown,
+}
+
+impl Availability {
+    /// The availability of a host with these upstreams
+    pub fn of<'a>(upstreams: impl IntoIterator<Item = &'a UpstreamHealth>) -> Self {
+        let (healthy, total) = upstreams
+            .into_iter()
+            .fold((0, 0), |(healthy, total), upstream| {
+                (healthy + usize::from(upstream.healthy), total + 1)
+            });
+        match (healthy, total) {
+            (_, 0) => Self::Unknown,
+            (0, _) => Self::Down,
+            (healthy, total) if healthy == total => Self::Up,
+            _ => Self::Degraded,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Up => "up",
+            Self::Degraded => "degraded",
+            Self::Down => "down",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Uptime of a host on a day (UTC), `None` when its upstreams were not checked that day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Day {
+    pub date: String,
+    pub uptime: Option<f64>,
+}
+
+/// Availability of a host, now and over the last days
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub status: Availability,
+    /// Unix timestamp (in seconds) of the last change of the status
+    pub since: Option<u64>,
+    /// Share of the time (in %) at least one upstream was healthy, over the last day, the
+    /// last 7 and the last 30 days
+    pub uptime_24h: Option<f64>,
+    pub uptime_7d: Option<f64>,
+    pub uptime_30d: Option<f64>,
+    pub days: Vec<Day>,
+}
+
+pub fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Records the availability of the hosts, the hosts no longer checked become unknown
+fn record(state: &UpstreamsHealth, now: u64) {
+    let Ok(mut history) = HISTORY.lock() else {
+        return;
+    };
+
+    for (host, upstreams) in state {
+        let availability = Availability::of(upstreams.values());
+        let changes = history.entry(host.clone()).or_default();
+        if changes.back().map(|(_, last)| *last) != Some(availability) {
+            changes.push_back((now, availability));
+        }
+    }
+    for (host, changes) in history.iter_mut() {
+        let unknown = Some(Availability::Unknown);
+        if !state.contains_key(host) && changes.back().map(|(_, last)| *last) != unknown {
+            changes.push_back((now, Availability::Unknown));
+        }
+    }
+
+    // Only the change before the retention period is kept, from its start
+    let cutoff = now.saturating_sub(RETENTION_SECS);
+    history.retain(|_, changes| {
+        while changes.get(1).is_some_and(|(at, _)| *at <= cutoff) {
+            changes.pop_front();
+        }
+        if let Some((at, _)) = changes.front_mut() {
+            *at = (*at).max(cutoff);
+        }
+        changes.len() > 1
+            || changes
+                .front()
+                .is_some_and(|(_, a)| *a != Availability::Unknown)
+    });
+}
+
+/// Share of the checked time of the period (in %) the host was up or degraded
+#[allow(clippy::cast_precision_loss)]
+fn uptime(changes: &VecDeque<(u64, Availability)>, from: u64, to: u64) -> Option<f64> {
+    let (mut available, mut checked) = (0, 0);
+    for (index, (start, availability)) in changes.iter().enumerate() {
+        let end = changes.get(index + 1).map_or(to, |(end, _)| *end).min(to);
+        let start = (*start).max(from);
+        if end <= start {
+            continue;
+        }
+        match availability {
+            Availability::Up | Availability::Degraded => {
+                available += end - start;
+                checked += end - start;
+            }
+            Availability::Down => checked += end - start,
+            Availability::Unknown => {}
+        }
+    }
+
+    (checked > 0).then(|| available as f64 * 100.0 / checked as f64)
+}
+
+/// The availability of the host, now and over the last days
+pub fn report(host: &str, now: u64) -> Report {
+    let history = HISTORY.lock().ok();
+    let empty = VecDeque::new();
+    let changes = history
+        .as_ref()
+        .and_then(|history| history.get(host))
+        .unwrap_or(&empty);
+
+    let today = now - now % DAY_SECS;
+    let days = (0..DAYS)
+        .rev()
+        .map(|days_ago| {
+            let start = today - days_ago * DAY_SECS;
+            let date = i64::try_from(start)
+                .ok()
+                .and_then(|start| time::OffsetDateTime::from_unix_timestamp(start).ok())
+                .map(|start| start.date().to_string())
+                .unwrap_or_default();
+            Day {
+                date,
+                uptime: uptime(changes, start, (start + DAY_SECS).min(now)),
+            }
+        })
+        .collect();
+
+    Report {
+        status: changes
+            .back()
+            .map_or(Availability::Unknown, |(_, last)| *last),
+        since: changes.back().map(|(at, _)| *at),
+        uptime_24h: uptime(changes, now.saturating_sub(DAY_SECS), now),
+        uptime_7d: uptime(changes, now.saturating_sub(7 * DAY_SECS), now),
+        uptime_30d: uptime(changes, now.saturating_sub(DAYS * DAY_SECS), now),
+        days,
+    }
+}
+
+/// A service recording the availability of the hosts each time the health of their
+/// upstreams changes. The history starts when proksi starts.
+pub struct AvailabilityService;
+
+#[async_trait]
+impl Service for AvailabilityService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut changes = health::subscribe();
+        loop {
+            let state = changes.borrow_and_update().clone();
+            record(&state, unix_secs());
+
+            tokio::select! {
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "status_pages_availability"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::stores::health::HealthSource;
+
+    use super::*;
+
+    fn upstream(healthy: bool) -> UpstreamHealth {
+        UpstreamHealth {
+            healthy,
+            source: HealthSource::Active,
+            failures: 0,
+            changed_at: 0,
+            last_error: None,
+        }
+    }
+
+    fn state(host: &str, upstreams: &[bool]) -> UpstreamsHealth {
+        let upstreams = upstreams
+            .iter()
+            .enumerate()
+            .map(|(index, healthy)| (format!("127.0.0.1:{}", 3000 + index), upstream(*healthy)))
+            .collect();
+        BTreeMap::from([(host.to_string(), upstreams)])
+    }
+
+    #[test]
+    fn test_availability() {
+        assert_eq!(Availability::of([]), Availability::Unknown);
+        assert_eq!(
+            Availability::of(&[upstream(true), upstream(true)]),
+            Availability::Up
+        );
+        assert_eq!(
+            Availability::of(&[upstream(true), upstream(false)]),
+            Availability::Degraded
+        );
+        assert_eq!(Availability::of(&[upstream(false)]), Availability::Down);
+    }
+
+    #[test]
+    fn test_uptime() {
+        let changes = VecDeque::from([
+            (100, Availability::Up),
+            (200, Availability::Down),
+            (250, Availability::Degraded),
+            (300, Availability::Unknown),
+        ]);
+        assert_eq!(uptime(&changes, 0, 50), None);
+        assert_eq!(uptime(&changes, 0, 200), Some(100.0));
+        assert_eq!(uptime(&changes, 100, 300), Some(75.0));
+        // The time the host was not checked doesn't count
+        assert_eq!(uptime(&changes, 100, 1000), Some(75.0));
+        assert_eq!(uptime(&changes, 300, 1000), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let host = "status.localhost";
+        let now = 1_000 * DAY_SECS + 3_600;
+        record(&state(host, &[true, true]), now - 2 * DAY_SECS);
+        record(&state(host, &[false, false]), now - 1_800);
+        record(&state(host, &[true, false]), now - 900);
+
+        let report = report(host, now);
+        assert_eq!(report.status, Availability::Degraded);
+        assert_eq!(report.since, Some(now - 900));
+        // Down for 15 minutes of the last day
+        assert!(report
+            .uptime_24h
+            .is_some_and(|uptime| (uptime - 98.958).abs() < 0.001));
+        assert_eq!(report.uptime_30d, report.uptime_7d);
+
+        let today = report.days.len() - 1;
+        assert_eq!(report.days.len(), 30);
+        assert_eq!(report.days[0].uptime, None);
+        assert_eq!(report.days[today - 2].uptime, Some(100.0));
+        assert_eq!(report.days[today].uptime, Some(75.0));
+        assert_eq!(report.days[today].date, "1972-09-27");
+
+        // The routes removed are no longer checked
+        record(&BTreeMap::new(), now);
+        assert_eq!(super::report(host, now).status, Availability::Unknown);
+
+        // Only the retention period is kept
+        record(&BTreeMap::new(), now + RETENTION_SECS + 1);
+        assert!(!HISTORY.lock().unwrap().contains_key(host));
+    }
+}
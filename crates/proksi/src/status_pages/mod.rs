@@ -0,0 +1,309 @@
+use std::fmt::Write as _;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use http::{header, Response, StatusCode};
+use pingora::{
+    apps::http_app::{HttpServer, ServeHttp},
+    protocols::http::ServerSession,
+    services::listening::Service,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    admin::json_response,
+    config::{Config, StatusPage, StatusPages},
+    proxy_server::error_pages::escape,
+};
+
+use availability::{Availability, Report};
+
+pub mod availability;
+
+/// Seconds between the reloads of the HTML pages
+const REFRESH_SECS: u64 = 60;
+
+/// Serves the status pages of the groups of routes, without authentication: they only show
+/// the hosts and whether their upstreams are healthy, never their addresses or errors
+pub struct StatusPagesApp {
+    pages: Vec<StatusPage>,
+}
+
+impl StatusPagesApp {
+    pub fn new(config: &StatusPages) -> Self {
+        Self {
+            pages: config.pages.clone(),
+        }
+    }
+}
+
+/// Overall status of a page: up when every host is, down when every host is
+fn overall(reports: &[(&str, Report)]) -> Availability {
+    let mut known = reports
+        .iter()
+        .map(|(_, report)| report.status)
+        .filter(|status| *status != Availability::Unknown)
+        .peekable();
+    let Some(first) = known.peek().copied() else {
+        return Availability::Unknown;
+    };
+    match (first, known.all(|status| status == first)) {
+        (Availability::Up | Availability::Down, true) => first,
+        _ => Availability::Degraded,
+    }
+}
+
+fn title(page: &StatusPage) -> &str {
+    page.title.as_deref().unwrap_or(&page.name)
+}
+
+fn to_json(page: &StatusPage, reports: &[(&str, Report)], now: u64) -> Value {
+    let hosts = reports
+        .iter()
+        .map(|(host, report)| {
+            let mut value = json!(report);
+            value["host"] = json!(host);
+            value
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "name": page.name,
+        "title": title(page),
+        "status": overall(reports),
+        "updated_at": now,
+        "hosts": hosts,
+    })
+}
+
+fn percent(uptime: Option<f64>) -> String {
+    uptime.map_or_else(|| "-".to_string(), |uptime| format!("{uptime:.2}%"))
+}
+
+/// Color of the bar of a day, from its uptime
+fn day_color(uptime: Option<f64>) -> &'static str {
+    match uptime {
+        None => "#d0d4d9",
+        Some(uptime) if uptime >= 99.9 => "#2fbf71",
+        Some(uptime) if uptime >= 99.0 => "#f2c94c",
+        Some(_) => "#eb5757",
+    }
+}
+
+fn status_color(status: Availability) -> &'static str {
+    match status {
+        Availability::Up => "#2fbf71",
+        Availability::Degraded => "#f2c94c",
+        Availability::Down => "#eb5757",
+        Availability::Unknown => "#8a939e",
+    }
+}
+
+fn to_html(page: &StatusPage, reports: &[(&str, Report)]) -> String {
+    let title = escape(title(page));
+    let status = overall(reports);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <meta http-equiv=\"refresh\" content=\"{REFRESH_SECS}\">\n<title>{title} status</title>\n\
+         <style>\n\
+         body {{ font-family: system-ui, sans-serif; max-width: 760px; margin: 40px auto; padding: 0 16px; color: #1f2933; }}\n\
+         .banner {{ padding: 16px; border-radius: 6px; color: #fff; font-weight: 600; }}\n\
+         .host {{ margin-top: 28px; }}\n\
+         .host h2 {{ display: flex; justify-content: space-between; font-size: 1.05em; }}\n\
+         .days {{ display: flex; gap: 2px; }}\n\
+         .days span {{ flex: 1; height: 32px; border-radius: 2px; }}\n\
+         .uptime {{ color: #52606d; font-size: 0.9em; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n\
+         <div class=\"banner\" style=\"background: {}\">{}</div>\n",
+        status_color(status),
+        match status {
+            Availability::Up => "All systems operational",
+            Availability::Degraded => "Some systems are degraded",
+            Availability::Down => "Major outage",
+            Availability::Unknown => "Status unknown",
+        }
+    );
+
+    for (host, report) in reports {
+        let _ = write!(
+            html,
+            "<div class=\"host\">\n<h2><span>{}</span><span style=\"color: {}\">{}</span></h2>\n\
+             <div class=\"days\">",
+            escape(host),
+            status_color(report.status),
+            report.status.as_str(),
+        );
+        for day in &report.days {
+            let _ = write!(
+                html,
+                "<span style=\"background: {}\" title=\"{}: {}\"></span>",
+                day_color(day.uptime),
+                day.date,
+                percent(day.uptime),
+            );
+        }
+        let _ = write!(
+            html,
+            "</div>\n<p class=\"uptime\">Uptime: {} (24 hours), {} (7 days), {} (30 days)</p>\n</div>\n",
+            percent(report.uptime_24h),
+            percent(report.uptime_7d),
+            percent(report.uptime_30d),
+        );
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[async_trait]
+impl ServeHttp for StatusPagesApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let path = session.req_header().uri.path().trim_start_matches('/');
+        let (name, json) = match path.strip_suffix(".json") {
+            Some(name) => (name, true),
+            None => (path, false),
+        };
+        let Some(page) = self.pages.iter().find(|page| page.name == name) else {
+            return json_response(StatusCode::NOT_FOUND, &json!({ "error": "not found" }));
+        };
+
+        let now = availability::unix_secs();
+        let reports = page
+            .hosts
+            .iter()
+            .map(|host| (host.as_str(), availability::report(host, now)))
+            .collect::<Vec<_>>();
+        if json {
+            return json_response(StatusCode::OK, &to_json(page, &reports, now));
+        }
+
+        let body = to_html(page, &reports).into_bytes();
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap_or_default()
+    }
+}
+
+/// Validates the names and the hosts of the status pages
+pub fn check_config(config: &StatusPages) -> anyhow::Result<()> {
+    for (index, page) in config.pages.iter().enumerate() {
+        let valid_name = page
+            .name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if page.name.is_empty() || !valid_name {
+            return Err(anyhow!(
+                "server.status_pages.pages{index}.name must only contain letters, digits, '-' and '_'"
+            ));
+        }
+        if config.pages[..index]
+            .iter()
+            .any(|other| other.name == page.name)
+        {
+            return Err(anyhow!(
+                "server.status_pages.pages{index}.name {} is already used",
+                page.name
+            ));
+        }
+        if page.hosts.is_empty() {
+            return Err(anyhow!(
+                "server.status_pages.pages{index}.hosts cannot be empty"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates the service exposing the status pages, when an address is configured
+pub fn status_pages_service(config: &Config) -> Option<Service<HttpServer<StatusPagesApp>>> {
+    let status_pages = &config.server.status_pages;
+    let address = status_pages.address.as_deref()?;
+
+    let mut service = Service::new(
+        "status_pages".to_string(),
+        HttpServer::new_app(StatusPagesApp::new(status_pages)),
+    );
+    service.add_tcp(address);
+    Some(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(status: Availability) -> Report {
+        Report {
+            status,
+            since: Some(1_000),
+            uptime_24h: Some(99.5),
+            uptime_7d: Some(99.95),
+            uptime_30d: None,
+            days: vec![availability::Day {
+                date: "2026-10-15".to_string(),
+                uptime: Some(99.5),
+            }],
+        }
+    }
+
+    fn page() -> StatusPage {
+        StatusPage {
+            name: "acme".to_string(),
+            title: Some("Acme <Inc>".to_string()),
+            hosts: vec!["api.acme.localhost".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_overall() {
+        let up = ("a", report(Availability::Up));
+        let down = ("b", report(Availability::Down));
+        let unknown = ("c", report(Availability::Unknown));
+
+        assert_eq!(overall(&[up.clone(), unknown.clone()]), Availability::Up);
+        assert_eq!(overall(&[down.clone()]), Availability::Down);
+        assert_eq!(overall(&[up, down]), Availability::Degraded);
+        assert_eq!(overall(&[unknown]), Availability::Unknown);
+        assert_eq!(overall(&[]), Availability::Unknown);
+    }
+
+    #[test]
+    fn test_pages() {
+        let reports = [("api.acme.localhost", report(Availability::Degraded))];
+
+        let value = to_json(&page(), &reports, 2_000);
+        assert_eq!(value["status"], "degraded");
+        assert_eq!(value["hosts"][0]["host"], "api.acme.localhost");
+        assert_eq!(value["hosts"][0]["uptime_30d"], Value::Null);
+        assert_eq!(value["hosts"][0]["days"][0]["date"], "2026-10-15");
+
+        let html = to_html(&page(), &reports);
+        assert!(html.contains("<h1>Acme &lt;Inc&gt;</h1>"));
+        assert!(html.contains("Some systems are degraded"));
+        assert!(html.contains("title=\"2026-10-15: 99.50%\""));
+        assert!(html.contains("Uptime: 99.50% (24 hours), 99.95% (7 days), - (30 days)"));
+    }
+
+    #[test]
+    fn test_check_config() {
+        let mut config = StatusPages {
+            address: None,
+            pages: vec![page()],
+        };
+        assert!(check_config(&config).is_ok());
+
+        config.pages.push(page());
+        assert!(check_config(&config).is_err(), "same name");
+
+        config.pages[1].name = "acme/eu".to_string();
+        assert!(check_config(&config).is_err());
+
+        config.pages[1].name = "acme-eu".to_string();
+        config.pages[1].hosts.clear();
+        assert!(check_config(&config).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+/// An API key registered in the store.
+/// Keys are always identified by the (hex encoded) SHA-256 hash of the key,
+/// the key itself is never stored.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApiKey {
+    /// Name of the key owner, used in logs and metrics
+    pub name: String,
+
+    /// Maximum number of requests per rate limit window (overrides the route default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u64>,
+}
+
+/// Returns the hash used to identify a given API key in the store
+pub fn hash_api_key(key: &str) -> String {
+    openssl::sha::sha256(key.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hash, b| {
+            let _ = write!(hash, "{b:02x}");
+            hash
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_api_key() {
+        assert_eq!(
+            hash_api_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
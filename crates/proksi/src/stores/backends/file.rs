@@ -0,0 +1,291 @@
+use std::{
+    error::Error,
+    fs,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use redb::{Database, ReadOnlyTable, ReadableTable, Table, TableDefinition};
+use tokio::sync::broadcast;
+
+use super::{Backend, Change, CHANGES_CAPACITY};
+
+/// The entries of the store: their value, and the Unix timestamp (in seconds) they
+/// expire at
+const ENTRIES: TableDefinition<&str, (Option<u64>, &str)> = TableDefinition::new("entries");
+
+type Entries<'t> = Table<'t, &'static str, (Option<u64>, &'static str)>;
+type ReadOnlyEntries = ReadOnlyTable<&'static str, (Option<u64>, &'static str)>;
+
+/// Persists the data in a database file on disk (redb), in which only the changed entries
+/// are written. The file holds the private keys of the certificates, it's only readable
+/// by its owner.
+///
+/// The file is owned by a single instance, another process can't open it meanwhile.
+pub struct FileBackend {
+    db: Arc<Database>,
+    changes: broadcast::Sender<Change>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|at| at <= now)
+}
+
+impl FileBackend {
+    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Created with the permissions of its owner only, before redb initializes it
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        let db = Database::builder()
+            .create_file(file)
+            .map_err(|err| format!("invalid store file {path:?}: {err}"))?;
+
+        // The table exists for the first reads
+        let tx = db.begin_write()?;
+        tx.open_table(ENTRIES)?;
+        tx.commit()?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        })
+    }
+
+    /// Reads the entries on the blocking threads
+    async fn read<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&ReadOnlyEntries, u64) -> Result<T, redb::Error> + Send + 'static,
+    ) -> Result<T, Box<dyn Error>> {
+        let db = self.db.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let table = db.begin_read()?.open_table(ENTRIES)?;
+            read(&table, unix_now())
+        })
+        .await?;
+
+        Ok(result?)
+    }
+
+    /// Changes the entries in a transaction, committed on the blocking threads
+    async fn write<T: Send + 'static>(
+        &self,
+        change: impl for<'t> FnOnce(&mut Entries<'t>, u64) -> Result<T, redb::Error> + Send + 'static,
+    ) -> Result<T, Box<dyn Error>> {
+        let db = self.db.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let tx = db.begin_write()?;
+            let result = {
+                let mut table = tx.open_table(ENTRIES)?;
+                change(&mut table, unix_now())?
+            };
+            tx.commit()?;
+            Ok::<_, redb::Error>(result)
+        })
+        .await?;
+
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl Backend for FileBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let key = key.to_string();
+        self.read(move |entries, now| {
+            Ok(entries.get(key.as_str())?.and_then(|entry| {
+                let (expires_at, value) = entry.value();
+                (!is_expired(expires_at, now)).then(|| value.to_string())
+            }))
+        })
+        .await
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = key.to_string();
+        self.write(move |entries, now| {
+            let expires_at = ttl.map(|ttl| now + ttl.as_secs());
+            entries.insert(key.as_str(), (expires_at, value.as_str()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let key = key.to_string();
+        self.write(move |entries, now| {
+            // An expired entry is absent
+            let present = entries
+                .get(key.as_str())?
+                .is_some_and(|entry| !is_expired(entry.value().0, now));
+            if present {
+                return Ok(false);
+            }
+
+            entries.insert(key.as_str(), (Some(now + ttl.as_secs()), value.as_str()))?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let key = key.to_string();
+        self.write(move |entries, _| {
+            entries.remove(key.as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.write(move |entries, _| {
+            let equal = entries
+                .get(key.as_str())?
+                .is_some_and(|entry| entry.value().1 == value);
+            if equal {
+                entries.remove(key.as_str())?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        let prefix = prefix.to_string();
+        self.read(move |entries, now| {
+            let mut found = vec![];
+            for entry in entries.range(prefix.as_str()..)? {
+                let (key, entry) = entry?;
+                if !key.value().starts_with(prefix.as_str()) {
+                    break;
+                }
+
+                let (expires_at, value) = entry.value();
+                if !is_expired(expires_at, now) {
+                    found.push((key.value().to_string(), value.to_string()));
+                }
+            }
+            Ok(found)
+        })
+        .await
+    }
+
+    async fn purge_expired(&self) -> Result<usize, Box<dyn Error>> {
+        self.write(|entries, now| {
+            let mut purged = 0;
+            entries.retain(|_, (expires_at, _)| {
+                let expired = is_expired(expires_at, now);
+                purged += usize::from(expired);
+                !expired
+            })?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_backend() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let path = dir.join("store").join("proksi.redb");
+
+        let backend = FileBackend::new(&path).unwrap();
+        backend
+            .set("proksi:route:a", "1".into(), None)
+            .await
+            .unwrap();
+        backend
+            .set("proksi:route:b", "2".into(), None)
+            .await
+            .unwrap();
+        backend.set("proksi:token", "3".into(), None).await.unwrap();
+        backend
+            .set("proksi:challenge:a", "4".into(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        backend.delete("proksi:route:b").await.unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The file belongs to a single instance at a time
+        assert!(FileBackend::new(&path).is_err());
+        drop(backend);
+
+        // The data is restored from the file
+        let backend = FileBackend::new(&path).unwrap();
+        assert_eq!(
+            backend.scan("proksi:route:").await.unwrap(),
+            vec![("proksi:route:a".to_string(), "1".to_string())]
+        );
+        assert_eq!(
+            backend.get("proksi:token").await.unwrap().as_deref(),
+            Some("3")
+        );
+        assert!(backend.get("proksi:challenge:a").await.unwrap().is_none());
+
+        let ttl = Duration::from_secs(60);
+        assert!(backend
+            .set_if_absent("lock", "a".into(), ttl)
+            .await
+            .unwrap());
+        assert!(!backend
+            .set_if_absent("lock", "b".into(), ttl)
+            .await
+            .unwrap());
+        backend.delete_if_equal("lock", "a").await.unwrap();
+        assert!(backend.get("lock").await.unwrap().is_none());
+
+        backend
+            .set("proksi:challenge:b", "5".into(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+        // Both expired challenges, the writes don't remove the other entries
+        assert_eq!(backend.purge_expired().await.unwrap(), 2);
+        assert_eq!(backend.purge_expired().await.unwrap(), 0);
+
+        drop(backend);
+        fs::write(&path, "not a database").unwrap();
+        assert!(FileBackend::new(&path).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use papaya::{Compute, Operation};
+use tokio::sync::broadcast;
+
+use super::{Backend, Change, CHANGES_CAPACITY};
+
+/// Keeps the data in the memory of the instance, it's lost on restart
+pub struct MemoryBackend {
+    /// Values, with the instant they expire at
+    entries: papaya::HashMap<String, (String, Option<Instant>)>,
+    /// No other instance can change the data, nothing is ever sent
+    changes: broadcast::Sender<Change>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            entries: papaya::HashMap::new(),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        }
+    }
+}
+
+fn is_expired(expires_at: Option<&Instant>) -> bool {
+    expires_at.is_some_and(|at| *at <= Instant::now())
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let entries = self.entries.pin();
+        match entries.get(key) {
+            Some((_, expires_at)) if is_expired(expires_at.as_ref()) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            Some((value, _)) => Ok(Some(value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        self.entries
+            .pin()
+            .insert(key.to_string(), (value, expires_at));
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let entry = (value, Some(Instant::now() + ttl));
+        let entries = self.entries.pin();
+        let result = entries.compute(key.to_string(), |current| match current {
+            Some((_, (_, expires_at))) if !is_expired(expires_at.as_ref()) => Operation::Abort(()),
+            _ => Operation::Insert(entry.clone()),
+        });
+
+        Ok(!matches!(result, Compute::Aborted(())))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.entries.pin().remove(key);
+        Ok(())
+    }
+
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        self.entries
+            .pin()
+            .remove_if(key, |_, (current, _)| current == value)
+            .ok();
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        Ok(self
+            .entries
+            .pin()
+            .iter()
+            .filter(|(key, (_, expires_at))| {
+                key.starts_with(prefix) && !is_expired(expires_at.as_ref())
+            })
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn purge_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let mut entries = self.entries.pin();
+        let before = entries.len();
+        entries.retain(|_, (_, expires_at)| !is_expired(expires_at.as_ref()));
+        Ok(before.saturating_sub(entries.len()))
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        backend.set("proksi:a", "1".into(), None).await.unwrap();
+        backend
+            .set("proksi:b", "2".into(), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        backend.set("other:c", "3".into(), None).await.unwrap();
+
+        assert_eq!(backend.get("proksi:b").await.unwrap().as_deref(), Some("2"));
+        assert_eq!(backend.scan("proksi:").await.unwrap().len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(backend.get("proksi:b").await.unwrap().is_none());
+        assert_eq!(
+            backend.scan("proksi:").await.unwrap(),
+            vec![("proksi:a".to_string(), "1".to_string())]
+        );
+
+        backend.delete("proksi:a").await.unwrap();
+        assert!(backend.get("proksi:a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_purge_expired() {
+        let backend = MemoryBackend::new();
+        backend.set("a", "1".into(), None).await.unwrap();
+        backend
+            .set("b", "2".into(), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        backend
+            .set("c", "3".into(), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(backend.purge_expired().await.unwrap(), 2);
+        assert_eq!(backend.entries.pin().len(), 1);
+        assert_eq!(backend.purge_expired().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_lock() {
+        let backend = MemoryBackend::new();
+        let ttl = Duration::from_millis(10);
+
+        assert!(backend
+            .set_if_absent("lock", "a".into(), ttl)
+            .await
+            .unwrap());
+        assert!(!backend
+            .set_if_absent("lock", "b".into(), ttl)
+            .await
+            .unwrap());
+
+        // Only the owner releases the lock
+        backend.delete_if_equal("lock", "b").await.unwrap();
+        assert_eq!(backend.get("lock").await.unwrap().as_deref(), Some("a"));
+        backend.delete_if_equal("lock", "a").await.unwrap();
+        assert!(backend
+            .set_if_absent("lock", "b".into(), ttl)
+            .await
+            .unwrap());
+
+        // An expired lock is taken over
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(backend
+            .set_if_absent("lock", "c".into(), ttl)
+            .await
+            .unwrap());
+    }
+}
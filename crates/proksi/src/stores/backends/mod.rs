@@ -0,0 +1,62 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+mod file;
+mod memory;
+mod redis;
+
+pub use file::FileBackend;
+pub use memory::MemoryBackend;
+pub use redis::RedisBackend;
+
+/// Capacity of the channels of changes, slower receivers miss the oldest changes
+const CHANGES_CAPACITY: usize = 256;
+
+/// Key-value storage of the store (see `KvStore`): the data stays in memory
+/// (`memory`), is shared by the instances (`redis`) or persisted on disk (`file`)
+#[async_trait]
+pub trait Backend: Send + Sync + 'static {
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Sets the value of the key, which expires after `ttl` when given
+    async fn set(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Sets the value of the key only if it has none, returns whether it was set.
+    /// Atomic across the instances sharing the backend.
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Deletes the key only if it has the given value
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Keys starting with `prefix`, with their values
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Box<dyn Error>>;
+
+    /// Removes the expired keys, returns how many were removed. Expired keys are never
+    /// returned already, this only frees the ones that are not read again.
+    async fn purge_expired(&self) -> Result<usize, Box<dyn Error>>;
+
+    /// Changes made by the other instances sharing the backend (the changes of this
+    /// instance are not sent)
+    fn watch(&self) -> broadcast::Receiver<Change>;
+}
+
+/// A key changed by another instance, `value` is `None` when the key was deleted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub value: Option<String>,
+}
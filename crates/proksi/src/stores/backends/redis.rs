@@ -0,0 +1,254 @@
+use std::{error::Error, time::Duration};
+
+use async_trait::async_trait;
+use redis::{Client, Commands, Connection, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::{Backend, Change, CHANGES_CAPACITY};
+
+/// Channel the instances publish their changes to
+const CHANGES_CHANNEL: &str = "proksi:changes";
+
+/// Compares and deletes the key at once, the value may have changed in between otherwise
+const DELETE_IF_EQUAL: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Delay before subscribing again to the changes, when the connection to Redis is lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Keys asked for by each `SCAN` (and read by each `MGET`) of a scan
+const SCAN_COUNT: usize = 500;
+
+/// Shares the data with the other instances using the same Redis
+pub struct RedisBackend {
+    pool: r2d2::Pool<Client>,
+    /// Identifies the changes of this instance, which are not sent to its watchers
+    instance_id: String,
+    changes: broadcast::Sender<Change>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChangeMessage {
+    origin: String,
+    key: String,
+    value: Option<String>,
+}
+
+impl RedisBackend {
+    pub fn new(redis_url: &str) -> Result<Self, Box<dyn Error>> {
+        let client = Client::open(redis_url)?;
+        let pool = r2d2::Pool::builder().build(client.clone())?;
+
+        let instance_id = uuid::Uuid::new_v4().to_string();
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+
+        let (sender, origin) = (changes.clone(), instance_id.clone());
+        std::thread::Builder::new()
+            .name("proksi-store-changes".to_string())
+            .spawn(move || subscribe(&client, &origin, &sender))?;
+
+        Ok(Self {
+            pool,
+            instance_id,
+            changes,
+        })
+    }
+
+    /// Runs the commands with a connection of the pool, on the blocking threads: the
+    /// connections of the pool are synchronous
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        commands: impl FnOnce(&mut Connection) -> RedisResult<T> + Send + 'static,
+    ) -> Result<T, Box<dyn Error>> {
+        let pool = self.pool.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|err| err.to_string())?;
+            commands(&mut conn).map_err(|err| err.to_string())
+        })
+        .await?;
+
+        Ok(result?)
+    }
+
+    /// Changes the data and publishes the change to the other instances, with the same
+    /// connection
+    async fn change(
+        &self,
+        key: &str,
+        value: Option<String>,
+        commands: impl FnOnce(&mut Connection) -> RedisResult<()> + Send + 'static,
+    ) -> Result<(), Box<dyn Error>> {
+        let message = serde_json::to_string(&ChangeMessage {
+            origin: self.instance_id.clone(),
+            key: key.to_string(),
+            value,
+        })?;
+
+        self.with_connection(move |conn| {
+            commands(conn)?;
+            conn.publish(CHANGES_CHANNEL, message)
+        })
+        .await
+    }
+}
+
+/// Forwards the changes of the other instances to the watchers, for as long as the
+/// process runs
+fn subscribe(client: &Client, instance_id: &str, sender: &broadcast::Sender<Change>) {
+    loop {
+        let result: redis::RedisResult<()> = client.get_connection().and_then(|mut conn| {
+            let mut pubsub = conn.as_pubsub();
+            pubsub.subscribe(CHANGES_CHANNEL)?;
+
+            loop {
+                let payload = pubsub.get_message()?.get_payload::<String>()?;
+                let Ok(message) = serde_json::from_str::<ChangeMessage>(&payload) else {
+                    continue;
+                };
+
+                if message.origin != instance_id {
+                    sender
+                        .send(Change {
+                            key: message.key,
+                            value: message.value,
+                        })
+                        .ok();
+                }
+            }
+        });
+
+        if let Err(err) = result {
+            tracing::warn!("lost the subscription to the changes of the store: {err}");
+        }
+        std::thread::sleep(RESUBSCRIBE_DELAY);
+    }
+}
+
+#[async_trait]
+impl Backend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let key = key.to_string();
+        self.with_connection(move |conn| conn.get(key)).await
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (key_, value_) = (key.to_string(), value.clone());
+        self.change(key, Some(value), move |conn| match ttl {
+            Some(ttl) => conn.set_ex(key_, value_, ttl.as_secs().max(1)),
+            None => conn.set(key_, value_),
+        })
+        .await
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let key = key.to_string();
+        let reply: Option<String> = self
+            .with_connection(move |conn| {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1))
+                    .query(conn)
+            })
+            .await?;
+
+        Ok(reply.is_some())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let key_ = key.to_string();
+        self.change(key, None, move |conn| conn.del(key_)).await
+    }
+
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.with_connection(move |conn| {
+            redis::Script::new(DELETE_IF_EQUAL)
+                .key(key)
+                .arg(value)
+                .invoke(conn)
+        })
+        .await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        let pattern = format!("{}*", escape_pattern(prefix));
+        self.with_connection(move |conn| {
+            // SCAN doesn't block Redis like KEYS, a key may be returned more than once
+            let mut keys = redis::cmd("SCAN")
+                .cursor_arg(0)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .clone()
+                .iter::<String>(conn)?
+                .collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys.dedup();
+
+            // Keys can expire between the commands
+            let mut entries = Vec::with_capacity(keys.len());
+            for keys in keys.chunks(SCAN_COUNT) {
+                let values: Vec<Option<String>> = conn.mget(keys)?;
+                entries.extend(
+                    keys.iter()
+                        .cloned()
+                        .zip(values)
+                        .filter_map(|(key, value)| Some((key, value?))),
+                );
+            }
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn purge_expired(&self) -> Result<usize, Box<dyn Error>> {
+        // Redis removes the expired keys itself
+        Ok(0)
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+}
+
+/// Escapes the characters of the glob patterns of `SCAN MATCH`
+fn escape_pattern(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(escape_pattern("proksi:route:"), "proksi:route:");
+        assert_eq!(escape_pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+    }
+}
@@ -1,9 +1,9 @@
 use openssl::{
+    base64,
     pkey::{PKey, Private},
     x509::X509,
-    base64,
 };
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 
 #[derive(Debug, Clone)]
@@ -13,6 +13,31 @@ pub struct Certificate {
     pub chain: Option<X509>,
 }
 
+/// Last attempt of Let's Encrypt to issue or renew the certificate of a host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RenewalStatus {
+    pub state: RenewalState,
+    /// Unix timestamps (in seconds) of the last order and of the last issued certificate
+    pub last_attempt_at: Option<u64>,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenewalState {
+    /// Not ordered yet
+    #[default]
+    Pending,
+    /// An order (and its HTTP-01 challenge) is in progress
+    Ordering,
+    Issued,
+    /// The order failed, the next one happens at the next renewal check
+    Failed,
+    /// The order failed and a self-signed certificate is served instead
+    SelfSigned,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct SerializableCertificate {
     key: String,
@@ -25,14 +50,17 @@ impl Certificate {
         Ok(SerializableCertificate {
             key: base64::encode_block(&self.key.private_key_to_pem_pkcs8()?),
             leaf: base64::encode_block(&self.leaf.to_pem()?),
-            chain: self.chain.as_ref().map(|c| base64::encode_block(&c.to_pem().unwrap_or_default())),
+            chain: self
+                .chain
+                .as_ref()
+                .map(|c| base64::encode_block(&c.to_pem().unwrap_or_default())),
         })
     }
 
     pub fn from_serializable(cert: SerializableCertificate) -> Result<Self, Box<dyn Error>> {
         let key_data = base64::decode_block(&cert.key)?;
         let leaf_data = base64::decode_block(&cert.leaf)?;
-        
+
         let key = PKey::private_key_from_pem(&key_data)?;
         let leaf = X509::from_pem(&leaf_data)?;
         let chain = if let Some(chain_b64) = cert.chain {
@@ -44,6 +72,4 @@ impl Certificate {
 
         Ok(Certificate { key, leaf, chain })
     }
-
-
 }
@@ -0,0 +1,56 @@
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+/// Events a subscriber can fall behind by, it receives `Lagged` after that
+const EVENTS_CAPACITY: usize = 256;
+
+/// A change of the stores, made by this instance or by another one sharing the store
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreEvent {
+    /// A new version of the route table, with the hosts added, changed or removed by it
+    Routes { version: u64, hosts: Vec<String> },
+    /// A certificate was saved in the store, by this instance or another one
+    Certificate { domain: String },
+    /// A route of the admin API changed by another instance, as JSON (`None` once deleted)
+    SharedRoute { host: String, route: Option<String> },
+}
+
+static EVENTS: Lazy<broadcast::Sender<StoreEvent>> =
+    Lazy::new(|| broadcast::channel(EVENTS_CAPACITY).0);
+
+/// Receives the events published from now on. Subscribers that lag behind should load
+/// the state they depend on again, some events were missed.
+pub fn subscribe() -> broadcast::Receiver<StoreEvent> {
+    EVENTS.subscribe()
+}
+
+/// Sends the event to every subscriber of this instance, the other instances get the
+/// changes of the store through its backend (e.g. Redis pub/sub)
+pub fn publish(event: StoreEvent) {
+    tracing::debug!("store event: {event:?}");
+    // No subscriber yet (e.g. during startup)
+    EVENTS.send(event).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_to_subscribers() {
+        let (mut first, mut second) = (subscribe(), subscribe());
+        let event = StoreEvent::Certificate {
+            domain: "events.localhost".to_string(),
+        };
+        publish(event.clone());
+
+        // Events of the other tests are published to the same bus
+        for receiver in [&mut first, &mut second] {
+            loop {
+                if receiver.recv().await.unwrap() == event {
+                    break;
+                }
+            }
+        }
+    }
+}
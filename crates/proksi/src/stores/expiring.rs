@@ -0,0 +1,156 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use papaya::{Compute, Operation};
+
+/// Interval between two removals of all the expired entries of a map
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An in-memory map whose entries expire after their own TTL. Expired entries are never
+/// returned, removed when they are read, and every expired entry is removed at most every
+/// minute when entries are inserted, so the map doesn't keep the keys that are not read again.
+pub struct ExpiringMap<V> {
+    entries: papaya::HashMap<String, (V, Instant)>,
+    created_at: Instant,
+    /// Seconds after `created_at` of the last removal of the expired entries
+    swept_at: AtomicU64,
+}
+
+impl<V: Clone> Default for ExpiringMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Clone> ExpiringMap<V> {
+    pub fn new() -> Self {
+        Self {
+            entries: papaya::HashMap::new(),
+            created_at: Instant::now(),
+            swept_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let entries = self.entries.pin();
+        match entries.get(key) {
+            Some((_, expires_at)) if *expires_at <= Instant::now() => {
+                entries
+                    .remove_if(key, |_, (_, at)| *at <= Instant::now())
+                    .ok();
+                None
+            }
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: String, value: V, ttl: Duration) {
+        self.entries
+            .pin()
+            .insert(key, (value, Instant::now() + ttl));
+        self.sweep();
+    }
+
+    /// Replaces the value of the key with the value `update` returns for it (`None` when
+    /// it's absent or expired), atomically. The entry keeps its expiry, a new entry expires
+    /// after `ttl`. Returns the new value, or `None` when `update` left the entry unchanged.
+    #[cfg_attr(not(feature = "lua"), allow(dead_code))]
+    pub fn update(
+        &self,
+        key: String,
+        ttl: Duration,
+        mut update: impl FnMut(Option<&V>) -> Option<V>,
+    ) -> Option<V> {
+        let now = Instant::now();
+        let entries = self.entries.pin();
+        let computed = entries.compute(key, |entry| {
+            let current = entry.map(|(_, entry)| entry).filter(|(_, at)| *at > now);
+            let expires_at = current.map_or(now + ttl, |(_, at)| *at);
+            match update(current.map(|(value, _)| value)) {
+                Some(value) => Operation::Insert((value, expires_at)),
+                None => Operation::Abort(()),
+            }
+        });
+        let updated = match computed {
+            Compute::Inserted(_, (value, _))
+            | Compute::Updated {
+                new: (_, (value, _)),
+                ..
+            } => Some(value.clone()),
+            Compute::Removed(..) | Compute::Aborted(()) => None,
+        };
+        self.sweep();
+        updated
+    }
+
+    #[cfg_attr(not(feature = "lua"), allow(dead_code))]
+    pub fn remove(&self, key: &str) {
+        self.entries.pin().remove(key);
+    }
+
+    /// Removes the expired entries when they were not removed for a minute
+    fn sweep(&self) {
+        let now = self.created_at.elapsed().as_secs();
+        let swept_at = self.swept_at.load(Ordering::Relaxed);
+        if now.saturating_sub(swept_at) >= SWEEP_INTERVAL.as_secs()
+            && self
+                .swept_at
+                .compare_exchange(swept_at, now, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.purge_expired();
+        }
+    }
+
+    /// Removes the expired entries, returns how many were removed
+    pub fn purge_expired(&self) -> usize {
+        let mut entries = self.entries.pin();
+        let before = entries.len();
+        let now = Instant::now();
+        entries.retain(|_, (_, expires_at)| *expires_at > now);
+        before.saturating_sub(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiring_entries() {
+        let map = ExpiringMap::new();
+        map.insert("a".to_string(), 1, Duration::from_secs(60));
+        map.insert("b".to_string(), 2, Duration::ZERO);
+        map.insert("c".to_string(), 3, Duration::ZERO);
+
+        assert_eq!(map.get("a"), Some(1));
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.entries.pin().len(), 2);
+
+        assert_eq!(map.purge_expired(), 1);
+        assert_eq!(map.entries.pin().len(), 1);
+        assert_eq!(map.get("a"), Some(1));
+    }
+
+    #[test]
+    fn test_update() {
+        let map = ExpiringMap::new();
+        let ttl = Duration::from_secs(60);
+        let incr = |value: Option<&i64>| Some(value.copied().unwrap_or(0) + 1);
+        assert_eq!(map.update("a".to_string(), ttl, incr), Some(1));
+        assert_eq!(map.update("a".to_string(), ttl, incr), Some(2));
+        // Left unchanged
+        assert_eq!(map.update("a".to_string(), ttl, |_| None), None);
+        assert_eq!(map.get("a"), Some(2));
+
+        // Expired entries are absent
+        map.insert("b".to_string(), 10, Duration::ZERO);
+        assert_eq!(map.update("b".to_string(), ttl, incr), Some(1));
+
+        map.remove("a");
+        assert_eq!(map.get("a"), None);
+    }
+}
@@ -0,0 +1,13 @@
+use std::{error::Error, path::Path};
+
+use super::backends::FileBackend;
+use super::kv_store::KvStore;
+
+/// Persists the certificates, challenges and routes in a file, they are restored on startup
+pub type FileStore = KvStore<FileBackend>;
+
+impl FileStore {
+    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(KvStore::with_backend(FileBackend::new(path)?))
+    }
+}
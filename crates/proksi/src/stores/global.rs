@@ -1,7 +1,11 @@
+use anyhow::anyhow;
 use once_cell::sync::OnceCell;
 use std::sync::Arc;
 
+use crate::config::{StoreConfig, StoreType};
+
 use super::store_trait::Store;
+use super::{FileStore, MemoryStore, RedisStore};
 
 static GLOBAL_STORE: OnceCell<Arc<dyn Store>> = OnceCell::new();
 
@@ -14,3 +18,33 @@ pub fn init_store<S: Store>(store: S) {
 pub fn get_store() -> &'static Arc<dyn Store> {
     GLOBAL_STORE.get().expect("Global store not initialized")
 }
+
+/// Initializes the store selected in the configuration (`store.store_type`)
+pub fn init_store_from_config(config: &StoreConfig) -> anyhow::Result<()> {
+    match config.store_type {
+        StoreType::Memory => {
+            tracing::info!("using Memory store for certificates");
+            init_store(MemoryStore::new());
+        }
+        StoreType::Redis => {
+            let redis_url = config.redis_url.as_deref().ok_or_else(|| {
+                anyhow!("Failed to get redis_url from configuration when store type is 'redis'")
+            })?;
+            let store = RedisStore::new(redis_url)
+                .map_err(|err| anyhow!("Failed to initialize Redis store: {err}"))?;
+            tracing::info!("using Redis store for certificates");
+            init_store(store);
+        }
+        StoreType::File => {
+            let path = config.path.as_deref().ok_or_else(|| {
+                anyhow!("Failed to get path from configuration when store type is 'file'")
+            })?;
+            let store = FileStore::new(path)
+                .map_err(|err| anyhow!("Failed to initialize file store: {err}"))?;
+            tracing::info!("using file store {path:?} for certificates");
+            init_store(store);
+        }
+    }
+
+    Ok(())
+}
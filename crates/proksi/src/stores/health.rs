@@ -0,0 +1,220 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Consecutive failures of the requests to an upstream before it's considered unhealthy,
+/// until the next active check succeeds
+pub const PASSIVE_FAILURE_THRESHOLD: u32 = 3;
+
+/// Health of the upstreams of each host (by address), as observed by the active checks of
+/// the health check service and by the requests that failed to connect (passive checks)
+pub type UpstreamsHealth = BTreeMap<String, BTreeMap<String, UpstreamHealth>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamHealth {
+    pub healthy: bool,
+    /// The check that changed the state last
+    pub source: HealthSource,
+    /// Requests that failed to connect since the last success
+    pub failures: u32,
+    /// Unix timestamp (in seconds) the state changed at
+    pub changed_at: u64,
+    /// Error of the last request that failed to connect
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthSource {
+    Active,
+    Passive,
+}
+
+impl HealthSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Passive => "passive",
+        }
+    }
+}
+
+static HEALTH: Lazy<watch::Sender<Arc<UpstreamsHealth>>> =
+    Lazy::new(|| watch::Sender::new(Arc::default()));
+
+/// Receives the health of every upstream, each time it changes
+pub fn subscribe() -> watch::Receiver<Arc<UpstreamsHealth>> {
+    HEALTH.subscribe()
+}
+
+/// The current health of every upstream
+pub fn snapshot() -> Arc<UpstreamsHealth> {
+    HEALTH.borrow().clone()
+}
+
+/// Whether the upstream of the host can receive requests, `None` before it was checked
+pub fn is_healthy(host: &str, address: &str) -> Option<bool> {
+    HEALTH
+        .borrow()
+        .get(host)
+        .and_then(|upstreams| upstreams.get(address))
+        .map(|health| health.healthy)
+}
+
+/// Records the result of an active check. A successful check also resets the passive
+/// failures of the upstream.
+pub fn report_active(host: &str, address: &str, healthy: bool) {
+    update(host, address, |health, now| {
+        if health.healthy != healthy {
+            health.healthy = healthy;
+            health.source = HealthSource::Active;
+            health.changed_at = now;
+        }
+        if healthy {
+            health.failures = 0;
+        }
+    });
+}
+
+/// Records a request that failed to connect to the upstream
+pub fn report_failure(host: &str, address: &str, error: String) {
+    update(host, address, |health, now| {
+        health.failures += 1;
+        health.last_error = Some(error);
+        if health.healthy && health.failures >= PASSIVE_FAILURE_THRESHOLD {
+            health.healthy = false;
+            health.source = HealthSource::Passive;
+            health.changed_at = now;
+        }
+    });
+}
+
+/// Records a request connected to the upstream
+pub fn report_success(host: &str, address: &str) {
+    // Most requests don't change anything, the state is only locked when they do
+    let failing = HEALTH
+        .borrow()
+        .get(host)
+        .and_then(|upstreams| upstreams.get(address))
+        .is_some_and(|health| health.failures > 0 || !health.healthy);
+    if !failing {
+        return;
+    }
+
+    update(host, address, |health, now| {
+        health.failures = 0;
+        if !health.healthy {
+            health.healthy = true;
+            health.source = HealthSource::Passive;
+            health.changed_at = now;
+        }
+    });
+}
+
+/// Replaces the health of every upstream (e.g. with the one saved before a restart), the
+/// upstreams that were checked already are kept as they are
+pub fn restore(saved: UpstreamsHealth) {
+    HEALTH.send_modify(|state| {
+        let mut next = saved;
+        for (host, upstreams) in state.iter() {
+            next.entry(host.clone())
+                .or_default()
+                .extend(upstreams.iter().map(|(a, h)| (a.clone(), h.clone())));
+        }
+        *state = Arc::new(next);
+    });
+}
+
+/// Forgets the upstreams that are no longer in the router
+pub fn retain(keep: impl Fn(&str, &str) -> bool) {
+    HEALTH.send_if_modified(|state| {
+        let mut next = (**state).clone();
+        for (host, upstreams) in &mut next {
+            upstreams.retain(|address, _| keep(host, address));
+        }
+        next.retain(|_, upstreams| !upstreams.is_empty());
+
+        if next == **state {
+            return false;
+        }
+        *state = Arc::new(next);
+        true
+    });
+}
+
+fn update(host: &str, address: &str, change: impl FnOnce(&mut UpstreamHealth, u64)) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    HEALTH.send_if_modified(|state| {
+        let current = state.get(host).and_then(|upstreams| upstreams.get(address));
+        let mut health = current.cloned().unwrap_or(UpstreamHealth {
+            healthy: true,
+            source: HealthSource::Active,
+            failures: 0,
+            changed_at: now,
+            last_error: None,
+        });
+        change(&mut health, now);
+        if current == Some(&health) {
+            return false;
+        }
+
+        // Receivers keep the previous state, which is copied on change
+        Arc::make_mut(state)
+            .entry(host.to_string())
+            .or_default()
+            .insert(address.to_string(), health);
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passive_and_active_checks() {
+        let (host, address) = ("health.localhost", "127.0.0.1:3000");
+        let mut receiver = subscribe();
+        assert_eq!(is_healthy(host, address), None);
+
+        report_active(host, address, true);
+        assert_eq!(is_healthy(host, address), Some(true));
+        assert!(receiver.has_changed().unwrap());
+        receiver.mark_unchanged();
+
+        // Same state, the receivers are not notified
+        report_active(host, address, true);
+        report_success(host, address);
+        assert!(!receiver.has_changed().unwrap());
+
+        for _ in 1..PASSIVE_FAILURE_THRESHOLD {
+            report_failure(host, address, "connection refused".to_string());
+        }
+        assert_eq!(is_healthy(host, address), Some(true));
+        report_failure(host, address, "connection refused".to_string());
+
+        let state = snapshot()[host][address].clone();
+        assert!(!state.healthy);
+        assert_eq!(state.source, HealthSource::Passive);
+        assert_eq!(state.failures, PASSIVE_FAILURE_THRESHOLD);
+        assert_eq!(state.last_error.as_deref(), Some("connection refused"));
+
+        // The next successful active check takes the upstream back
+        report_active(host, address, true);
+        let state = snapshot()[host][address].clone();
+        assert!(state.healthy);
+        assert_eq!(state.failures, 0);
+
+        report_active(host, address, false);
+        assert_eq!(is_healthy(host, address), Some(false));
+
+        retain(|h, _| h != host);
+        assert_eq!(is_healthy(host, address), None);
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    error::Error,
+    hash::RandomState,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use papaya::HashMapRef;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::api_keys::ApiKey;
+use super::backends::{Backend, Change};
+use super::certificates::{Certificate, SerializableCertificate};
+use super::events::{self, StoreEvent};
+use super::store_trait::{KeyUsage, Store};
+
+const CERTIFICATE_PREFIX: &str = "proksi:cert:";
+const CHALLENGE_PREFIX: &str = "proksi:challenge:";
+const ROUTE_PREFIX: &str = "proksi:route:";
+const API_KEY_PREFIX: &str = "proksi:api_key:";
+const LOCK_PREFIX: &str = "proksi:lock:";
+const SESSION_KEY_KEY: &str = "proksi:session_key";
+const TICKET_KEYS_KEY: &str = "proksi:tls:ticket_keys";
+
+/// Challenges are only needed while Let's Encrypt validates the order
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+/// The key of the sticky sessions is replaced after a year, the sessions then start over
+const SESSION_KEY_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// Interval between two removals of the expired keys of the backend
+const EXPIRED_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The store, on top of a key-value backend (memory, Redis or a file).
+/// Certificates are parsed once and kept in memory, until another instance changes them.
+pub struct KvStore<B: Backend> {
+    backend: Arc<B>,
+    certificates: Arc<papaya::HashMap<String, Certificate>>,
+    /// Value of the locks held by this instance
+    lock_owner: String,
+    /// The changes of the backend are watched, and its expired keys removed, from the first
+    /// use of the store in a runtime
+    watching: OnceLock<()>,
+}
+
+impl<B: Backend> KvStore<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            certificates: Arc::new(papaya::HashMap::new()),
+            lock_owner: uuid::Uuid::new_v4().to_string(),
+            watching: OnceLock::new(),
+        }
+    }
+
+    fn watch_backend(&self) {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+
+        self.watching.get_or_init(|| {
+            let mut backend_changes = self.backend.watch();
+            let certificates = self.certificates.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    match backend_changes.recv().await {
+                        Ok(change) => {
+                            if let Some(event) = to_event(change) {
+                                if let StoreEvent::Certificate { domain } = &event {
+                                    certificates.pin().remove(domain);
+                                }
+                                events::publish(event);
+                            }
+                        }
+                        // Some certificates may have changed, they are loaded again
+                        Err(RecvError::Lagged(_)) => certificates.pin().clear(),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+
+            // Expired keys are not returned by the backends, but the ones that are not
+            // read again (e.g. sessions of clients that left) would be kept otherwise
+            let backend = Arc::downgrade(&self.backend);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(EXPIRED_CLEANUP_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let Some(backend) = backend.upgrade() else {
+                        break;
+                    };
+                    match backend.purge_expired().await {
+                        Ok(0) => {}
+                        Ok(purged) => {
+                            tracing::debug!("removed {purged} expired keys from the store")
+                        }
+                        Err(err) => {
+                            tracing::warn!("failed to remove the expired keys of the store: {err}")
+                        }
+                    }
+                }
+            });
+        });
+    }
+
+    async fn load_certificate(&self, key: &str) -> Option<Certificate> {
+        let data = self.backend.get(key).await.ok()??;
+        parse_certificate(&data)
+    }
+}
+
+fn parse_certificate(data: &str) -> Option<Certificate> {
+    let certificate = serde_json::from_str::<SerializableCertificate>(data).ok()?;
+    Certificate::from_serializable(certificate).ok()
+}
+
+fn to_event(change: Change) -> Option<StoreEvent> {
+    if let Some(domain) = change.key.strip_prefix(CERTIFICATE_PREFIX) {
+        return Some(StoreEvent::Certificate {
+            domain: domain.to_string(),
+        });
+    }
+
+    let host = change.key.strip_prefix(ROUTE_PREFIX)?;
+    Some(StoreEvent::SharedRoute {
+        host: host.to_string(),
+        route: change.value,
+    })
+}
+
+#[async_trait]
+impl<B: Backend> Store for KvStore<B> {
+    async fn get_routes(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        self.watch_backend();
+
+        let routes = self.backend.scan(ROUTE_PREFIX).await?;
+        Ok(routes.into_iter().map(|(_, route)| route).collect())
+    }
+
+    async fn set_route(&self, host: &str, route: String) -> Result<(), Box<dyn Error>> {
+        self.backend
+            .set(&format!("{ROUTE_PREFIX}{host}"), route, None)
+            .await
+    }
+
+    async fn remove_route(&self, host: &str) -> Result<(), Box<dyn Error>> {
+        self.backend.delete(&format!("{ROUTE_PREFIX}{host}")).await
+    }
+
+    async fn get_certificate(&self, domain: &str) -> Option<Certificate> {
+        self.watch_backend();
+
+        if let Some(certificate) = self.certificates.pin().get(domain) {
+            return Some(certificate.clone());
+        }
+
+        let certificate = self
+            .load_certificate(&format!("{CERTIFICATE_PREFIX}{domain}"))
+            .await?;
+        self.certificates
+            .pin()
+            .insert(domain.to_string(), certificate.clone());
+        Some(certificate)
+    }
+
+    async fn set_certificate(&self, domain: &str, cert: Certificate) -> Result<(), Box<dyn Error>> {
+        let data = serde_json::to_string(&cert.to_serializable()?)?;
+        self.backend
+            .set(&format!("{CERTIFICATE_PREFIX}{domain}"), data, None)
+            .await?;
+
+        self.certificates.pin().insert(domain.to_string(), cert);
+        events::publish(StoreEvent::Certificate {
+            domain: domain.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn get_certificates(
+        &self,
+    ) -> HashMapRef<'_, String, Certificate, RandomState, seize::LocalGuard<'_>> {
+        self.watch_backend();
+
+        // Certificates added by the other instances are loaded in memory too
+        if let Ok(stored) = self.backend.scan(CERTIFICATE_PREFIX).await {
+            let certificates = self.certificates.pin();
+            for (key, data) in stored {
+                let domain = key.trim_start_matches(CERTIFICATE_PREFIX);
+                if certificates.contains_key(domain) {
+                    continue;
+                }
+                if let Some(certificate) = parse_certificate(&data) {
+                    certificates.insert(domain.to_string(), certificate);
+                }
+            }
+        }
+
+        self.certificates.pin()
+    }
+
+    async fn get_challenge(&self, domain: &str) -> Option<(String, String)> {
+        let data = self
+            .backend
+            .get(&format!("{CHALLENGE_PREFIX}{domain}"))
+            .await
+            .ok()??;
+        serde_json::from_str(&data).ok()
+    }
+
+    async fn set_challenge(
+        &self,
+        domain: &str,
+        token: String,
+        proof: String,
+    ) -> Result<(), Box<dyn Error>> {
+        // Each token has its own key, so that the orders of several instances don't
+        // overwrite each other; the latest challenge of the domain is kept too
+        self.backend
+            .set(
+                &format!("{CHALLENGE_PREFIX}{domain}:{token}"),
+                proof.clone(),
+                Some(CHALLENGE_TTL),
+            )
+            .await?;
+
+        let data = serde_json::to_string(&(token, proof))?;
+        self.backend
+            .set(
+                &format!("{CHALLENGE_PREFIX}{domain}"),
+                data,
+                Some(CHALLENGE_TTL),
+            )
+            .await
+    }
+
+    async fn get_challenge_proof(&self, domain: &str, token: &str) -> Option<String> {
+        if let Ok(Some(proof)) = self
+            .backend
+            .get(&format!("{CHALLENGE_PREFIX}{domain}:{token}"))
+            .await
+        {
+            return Some(proof);
+        }
+
+        // Challenges set by instances that only keep the latest one of the domain
+        let (latest_token, proof) = self.get_challenge(domain).await?;
+        (latest_token == token).then_some(proof)
+    }
+
+    async fn get_challenge_tokens(&self) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        let challenges = self.backend.scan(CHALLENGE_PREFIX).await?;
+
+        // The latest challenge of each domain has a key of its own too
+        Ok(challenges
+            .into_iter()
+            .filter_map(|(key, _)| {
+                let (domain, token) = key.strip_prefix(CHALLENGE_PREFIX)?.split_once(':')?;
+                Some((domain.to_string(), token.to_string()))
+            })
+            .collect())
+    }
+
+    async fn get_api_key(&self, key_hash: &str) -> Option<ApiKey> {
+        // API keys are not cached so that revoked keys stop working right away
+        let data = self
+            .backend
+            .get(&format!("{API_KEY_PREFIX}{key_hash}"))
+            .await
+            .ok()??;
+        serde_json::from_str(&data).ok()
+    }
+
+    async fn get_or_set_session_key(&self, key: String) -> Result<String, Box<dyn Error>> {
+        if let Some(stored) = self.backend.get(SESSION_KEY_KEY).await? {
+            return Ok(stored);
+        }
+        if self
+            .backend
+            .set_if_absent(SESSION_KEY_KEY, key.clone(), SESSION_KEY_TTL)
+            .await?
+        {
+            return Ok(key);
+        }
+
+        // Stored by another instance in the meantime
+        Ok(self
+            .backend
+            .get(SESSION_KEY_KEY)
+            .await?
+            .ok_or("the key of the sticky sessions expired")?)
+    }
+
+    async fn try_lock(&self, name: &str, ttl: Duration) -> Result<bool, Box<dyn Error>> {
+        self.backend
+            .set_if_absent(
+                &format!("{LOCK_PREFIX}{name}"),
+                self.lock_owner.clone(),
+                ttl,
+            )
+            .await
+    }
+
+    async fn unlock(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.backend
+            .delete_if_equal(&format!("{LOCK_PREFIX}{name}"), &self.lock_owner)
+            .await
+    }
+
+    async fn get_ticket_keys(&self) -> Result<Option<String>, Box<dyn Error>> {
+        self.backend.get(TICKET_KEYS_KEY).await
+    }
+
+    async fn set_ticket_keys(&self, keys: String) -> Result<(), Box<dyn Error>> {
+        self.backend.set(TICKET_KEYS_KEY, keys, None).await
+    }
+
+    fn forward_changes(&self) {
+        self.watch_backend();
+    }
+
+    async fn usage(&self) -> Result<Vec<(&'static str, KeyUsage)>, Box<dyn Error>> {
+        let mut usage = Vec::new();
+        for (name, prefix) in [
+            ("certificates", CERTIFICATE_PREFIX),
+            ("challenges", CHALLENGE_PREFIX),
+            ("routes", ROUTE_PREFIX),
+            ("api_keys", API_KEY_PREFIX),
+            ("session_key", SESSION_KEY_KEY),
+            ("locks", LOCK_PREFIX),
+            ("ticket_keys", TICKET_KEYS_KEY),
+        ] {
+            let entries = self.backend.scan(prefix).await?;
+            usage.push((
+                name,
+                KeyUsage {
+                    keys: entries.len(),
+                    bytes: entries.iter().map(|(k, v)| k.len() + v.len()).sum(),
+                },
+            ));
+        }
+
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::{self, Sender};
+
+    use super::*;
+
+    /// A backend whose changes are sent by the test, as if they came from another instance
+    struct SharedBackend {
+        inner: Arc<super::super::backends::MemoryBackend>,
+        changes: Sender<Change>,
+    }
+
+    #[async_trait]
+    impl Backend for SharedBackend {
+        async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+            self.inner.get(key).await
+        }
+
+        async fn set(
+            &self,
+            key: &str,
+            value: String,
+            ttl: Option<Duration>,
+        ) -> Result<(), Box<dyn Error>> {
+            self.inner.set(key, value, ttl).await
+        }
+
+        async fn set_if_absent(
+            &self,
+            key: &str,
+            value: String,
+            ttl: Duration,
+        ) -> Result<bool, Box<dyn Error>> {
+            self.inner.set_if_absent(key, value, ttl).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.delete(key).await
+        }
+
+        async fn delete_if_equal(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.delete_if_equal(key, value).await
+        }
+
+        async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+            self.inner.scan(prefix).await
+        }
+
+        async fn purge_expired(&self) -> Result<usize, Box<dyn Error>> {
+            self.inner.purge_expired().await
+        }
+
+        fn watch(&self) -> broadcast::Receiver<Change> {
+            self.changes.subscribe()
+        }
+    }
+
+    /// The next event of the host or domain, the other tests publish to the same bus
+    async fn next_event(receiver: &mut broadcast::Receiver<StoreEvent>, name: &str) -> StoreEvent {
+        loop {
+            let event = receiver.recv().await.unwrap();
+            match &event {
+                StoreEvent::Certificate { domain } if domain == name => return event,
+                StoreEvent::SharedRoute { host, .. } if host == name => return event,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_of_other_instances() {
+        let (sender, _) = broadcast::channel(8);
+        let store = KvStore::with_backend(SharedBackend {
+            inner: Arc::new(super::super::backends::MemoryBackend::new()),
+            changes: sender.clone(),
+        });
+        let mut receiver = events::subscribe();
+        store.forward_changes();
+
+        store
+            .set_route("a.kv.localhost", r#"{"host":"a.kv.localhost"}"#.to_string())
+            .await
+            .unwrap();
+        assert_eq!(store.get_routes().await.unwrap().len(), 1);
+
+        sender
+            .send(Change {
+                key: format!("{ROUTE_PREFIX}b.kv.localhost"),
+                value: None,
+            })
+            .unwrap();
+        assert_eq!(
+            next_event(&mut receiver, "b.kv.localhost").await,
+            StoreEvent::SharedRoute {
+                host: "b.kv.localhost".to_string(),
+                route: None
+            }
+        );
+
+        // Keys of the store that are not routes or certificates are not forwarded
+        sender
+            .send(Change {
+                key: format!("{CHALLENGE_PREFIX}a.kv.localhost"),
+                value: None,
+            })
+            .unwrap();
+        sender
+            .send(Change {
+                key: format!("{CERTIFICATE_PREFIX}a.kv.localhost"),
+                value: None,
+            })
+            .unwrap();
+        assert_eq!(
+            next_event(&mut receiver, "a.kv.localhost").await,
+            StoreEvent::Certificate {
+                domain: "a.kv.localhost".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locks_of_instances() {
+        let inner = Arc::new(super::super::backends::MemoryBackend::new());
+        let (sender, _) = broadcast::channel(8);
+        let instance = |inner: &Arc<_>| {
+            KvStore::with_backend(SharedBackend {
+                inner: Arc::clone(inner),
+                changes: sender.clone(),
+            })
+        };
+        let (first, second) = (instance(&inner), instance(&inner));
+        let ttl = Duration::from_secs(60);
+
+        assert!(first.try_lock("order:a.localhost", ttl).await.unwrap());
+        assert!(!second.try_lock("order:a.localhost", ttl).await.unwrap());
+        assert!(second.try_lock("order:b.localhost", ttl).await.unwrap());
+
+        // Only the instance holding the lock releases it
+        second.unlock("order:a.localhost").await.unwrap();
+        assert!(!second.try_lock("order:a.localhost", ttl).await.unwrap());
+        first.unlock("order:a.localhost").await.unwrap();
+        assert!(second.try_lock("order:a.localhost", ttl).await.unwrap());
+    }
+}
@@ -1,57 +1,12 @@
-use async_trait::async_trait;
-use papaya::HashMapRef;
-use std::{error::Error, hash::RandomState};
-
-use super::certificates::Certificate;
-use super::store_trait::Store;
-
-pub struct MemoryStore {
-    /// Map of domain names to certificates (including leaf & chain)
-    inner_certs: papaya::HashMap<String, Certificate>,
-    /// Map of domain names to challenge tokens and proofs (token, proof)
-    inner_challenges: papaya::HashMap<String, (String, String)>,
-}
+use super::backends::MemoryBackend;
+use super::kv_store::KvStore;
+
+/// Keeps the certificates, challenges and routes in memory, they are lost on restart
+pub type MemoryStore = KvStore<MemoryBackend>;
 
 impl MemoryStore {
     pub fn new() -> Self {
-        MemoryStore {
-            inner_certs: papaya::HashMap::new(),
-            inner_challenges: papaya::HashMap::new(),
-        }
-    }
-}
-
-#[async_trait]
-impl Store for MemoryStore {
-    async fn get_certificate(&self, host: &str) -> Option<Certificate> {
-        self.inner_certs.pin().get(host).cloned()
-    }
-
-    async fn set_certificate(&self, host: &str, cert: Certificate) -> Result<(), Box<dyn Error>> {
-        self.inner_certs.pin().insert(host.to_string(), cert);
-        Ok(())
-    }
-
-    async fn get_certificates(
-        &self,
-    ) -> HashMapRef<'_, String, Certificate, RandomState, seize::LocalGuard<'_>> {
-        self.inner_certs.pin()
-    }
-
-    async fn get_challenge(&self, domain: &str) -> Option<(String, String)> {
-        self.inner_challenges.pin().get(domain).cloned()
-    }
-
-    async fn set_challenge(
-        &self,
-        domain: &str,
-        token: String,
-        proof: String,
-    ) -> Result<(), Box<dyn Error>> {
-        self.inner_challenges
-            .pin()
-            .insert(domain.to_string(), (token, proof));
-        Ok(())
+        KvStore::with_backend(MemoryBackend::new())
     }
 }
 
@@ -198,6 +153,51 @@ mod tests {
         assert_eq!(challenge.1, "proof2");
     }
 
+    #[tokio::test]
+    async fn test_concurrent_challenges() {
+        let store = MemoryStore::new();
+        let domain = "example.com";
+
+        // Two instances ordering a certificate for the same domain
+        store
+            .set_challenge(domain, "token1".to_string(), "proof1".to_string())
+            .await
+            .unwrap();
+        store
+            .set_challenge(domain, "token2".to_string(), "proof2".to_string())
+            .await
+            .unwrap();
+
+        let proof = store.get_challenge_proof(domain, "token1").await;
+        assert_eq!(proof.as_deref(), Some("proof1"));
+        let proof = store.get_challenge_proof(domain, "token2").await;
+        assert_eq!(proof.as_deref(), Some("proof2"));
+        assert!(store.get_challenge_proof(domain, "token3").await.is_none());
+        assert!(store
+            .get_challenge_proof("other.com", "token1")
+            .await
+            .is_none());
+
+        let mut tokens = store.get_challenge_tokens().await.unwrap();
+        tokens.sort();
+        assert_eq!(
+            tokens,
+            [
+                (domain.to_string(), "token1".to_string()),
+                (domain.to_string(), "token2".to_string())
+            ]
+        );
+
+        // Both tokens, and the latest challenge of the domain
+        let usage = store.usage().await.unwrap();
+        let (_, challenges) = usage
+            .iter()
+            .find(|(name, _)| *name == "challenges")
+            .unwrap();
+        assert_eq!(challenges.keys, 3);
+        assert!(challenges.bytes > 0);
+    }
+
     #[tokio::test]
     async fn test_nonexistent_data() {
         let store = MemoryStore::new();
@@ -1,18 +1,33 @@
-use std::hash::RandomState;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, PoisonError},
+};
 
+use arc_swap::ArcSwap;
 use once_cell::sync::Lazy;
-use papaya::HashMapRef;
-use routes::{RouteStore, RouteStoreContainer};
+use routes::{RouteStoreContainer, RouteTable, RouteTableChange};
 
+use crate::config::Route;
+
+pub mod api_keys;
+pub mod backends;
 pub mod cache;
 pub mod certificates;
+pub mod events;
+pub mod expiring;
+pub mod file_store;
 pub mod global;
+pub mod health;
+pub mod kv_store;
 pub mod memory_store;
 pub mod redis_store;
 pub mod routes;
+pub mod secrets;
 pub mod store_trait;
+pub mod warm_state;
 
 // Re-export stores
+pub use file_store::FileStore;
 pub use memory_store::MemoryStore;
 pub use redis_store::RedisStore;
 
@@ -28,20 +43,72 @@ pub use redis_store::RedisStore;
 //     CHALLENGE_STORE.pin().insert(key, value);
 // }
 
-// ROUTE store
-static ROUTE_STORE: Lazy<RouteStore> = Lazy::new(papaya::HashMap::new);
+// ROUTE store: the table of the router, swapped as a whole on each change
+static ROUTE_TABLE: Lazy<ArcSwap<RouteTable>> = Lazy::new(ArcSwap::default);
+/// Changes of the table are made one at a time, so that none is lost
+static ROUTE_TABLE_CHANGES: Mutex<()> = Mutex::new(());
 
 pub fn get_route_by_key(key: &str) -> Option<RouteStoreContainer> {
-    ROUTE_STORE.pin().get(key).cloned()
+    ROUTE_TABLE.load().get(key).cloned()
+}
+
+/// Snapshot of the routes, it doesn't change when the router does
+pub fn get_routes() -> Arc<RouteTable> {
+    ROUTE_TABLE.load_full()
 }
 
-pub fn get_routes(
-) -> HashMapRef<'static, String, RouteStoreContainer, RandomState, seize::OwnedGuard<'static>> {
-    ROUTE_STORE.pin_owned()
+/// Applies the changes to the routes at once, as a new version of the table
+pub fn update_routes(change: impl FnOnce(&mut RouteTableChange)) {
+    let _guard = ROUTE_TABLE_CHANGES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    let updated_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let next = ROUTE_TABLE.load().next(change, updated_at);
+    let event = events::StoreEvent::Routes {
+        version: next.version,
+        hosts: next.changed_hosts.clone(),
+    };
+    ROUTE_TABLE.store(Arc::new(next));
+    events::publish(event);
 }
 
 pub fn insert_route(key: String, value: RouteStoreContainer) {
-    ROUTE_STORE.pin().insert(key, value);
+    update_routes(|routes| routes.insert(key, value));
+}
+
+pub fn remove_route(key: &str) {
+    update_routes(|routes| routes.remove(key));
+}
+
+// ROUTE DEFINITION store: the routes of the configuration and the admin API, as they were
+// defined, so they can be changed through the admin API
+static ROUTE_DEFINITION_STORE: Lazy<papaya::HashMap<String, Route>> =
+    Lazy::new(papaya::HashMap::new);
+
+pub fn get_route_definition(host: &str) -> Option<Route> {
+    ROUTE_DEFINITION_STORE.pin().get(host).cloned()
+}
+
+/// Every route definition, sorted by host
+pub fn get_route_definitions() -> Vec<Route> {
+    let definitions = ROUTE_DEFINITION_STORE.pin();
+    let mut routes = definitions.values().cloned().collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.host.cmp(&b.host));
+    routes
+}
+
+pub fn insert_route_definition(route: Route) {
+    ROUTE_DEFINITION_STORE
+        .pin()
+        .insert(route.host.to_string(), route);
+}
+
+pub fn remove_route_definition(host: &str) {
+    ROUTE_DEFINITION_STORE.pin().remove(host);
 }
 
 // CERTIFICATE store
@@ -60,6 +127,30 @@ pub fn insert_route(key: String, value: RouteStoreContainer) {
 //     CERTIFICATE_STORE.pin().insert(key, value);
 // }
 
+// RENEWAL STATUS store: the orders of the Let's Encrypt service of this instance
+static RENEWAL_STATUS_STORE: Lazy<papaya::HashMap<String, certificates::RenewalStatus>> =
+    Lazy::new(papaya::HashMap::new);
+
+pub fn get_renewal_status(host: &str) -> Option<certificates::RenewalStatus> {
+    RENEWAL_STATUS_STORE.pin().get(host).cloned()
+}
+
+/// Renewal status of every host, sorted by host
+pub fn get_renewal_statuses() -> BTreeMap<String, certificates::RenewalStatus> {
+    RENEWAL_STATUS_STORE
+        .pin()
+        .iter()
+        .map(|(host, status)| (host.clone(), status.clone()))
+        .collect()
+}
+
+/// Changes the renewal status of the host, starting from the default one
+pub fn update_renewal_status(host: &str, update: impl FnOnce(&mut certificates::RenewalStatus)) {
+    let mut status = get_renewal_status(host).unwrap_or_default();
+    update(&mut status);
+    RENEWAL_STATUS_STORE.pin().insert(host.to_string(), status);
+}
+
 // Cache Routing store
 static CACHE_ROUTING_STORE: Lazy<cache::PathCacheStorage> = Lazy::new(papaya::HashMap::new);
 
@@ -68,6 +159,23 @@ pub fn get_cache_routing_by_key(key: &str) -> Option<String> {
     CACHE_ROUTING_STORE.pin().get(key).cloned()
 }
 
+/// Cache directory of every host, sorted by host
+pub fn get_cache_routings() -> BTreeMap<String, String> {
+    CACHE_ROUTING_STORE
+        .pin()
+        .iter()
+        .map(|(host, path)| (host.clone(), path.clone()))
+        .collect()
+}
+
+/// Forgets the cache routing of the hosts, e.g. after their route changed
+pub fn remove_cache_routing(hosts: &[String]) {
+    let routing = CACHE_ROUTING_STORE.pin();
+    for host in hosts {
+        routing.remove(host);
+    }
+}
+
 /// Insert given cache routing into the store if it does not exist
 pub fn insert_cache_routing(key: &str, new_value: String, should_override: bool) {
     if CACHE_ROUTING_STORE.pin().get(key).is_some() {
@@ -1,10 +1,27 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use http::{HeaderName, HeaderValue};
 use path_tree::PathTree;
 use pingora::lb::{selection::RoundRobin, LoadBalancer};
 
-use crate::config::{RouteCache, RoutePlugin, RouteUpstream};
+use crate::{
+    config::{
+        IpFilter, RouteCache, RouteCompression, RouteGrpc, RouteLimits, RoutePlugin,
+        RouteStaticFiles, RouteStickySessions, RouteStreaming, RouteUpstream, RouteWebSocket,
+    },
+    proxy_server::{
+        body_logging::BodyLogging, canary::Canary, capture::Capture,
+        client_certificates::ClientAuth, error_pages::ErrorPages, header_rules::HeaderRules,
+        methods::AllowedMethods, mime_types::MimeTypes, mirror::Mirror,
+    },
+};
 
 #[derive(Debug, Default, Clone)]
 pub struct RouteStorePathMatcher {
@@ -33,8 +50,14 @@ impl RouteStorePathMatcher {
     }
 }
 
+/// Id of the next route container
+static NEXT_ROUTE_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Clone)]
 pub struct RouteStoreContainer {
+    /// Unique to this version of the route: a changed route is a new container, with a
+    /// new id
+    pub id: u64,
     pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
     pub path_matcher: RouteStorePathMatcher,
     pub host_header_remove: Vec<String>,
@@ -42,15 +65,47 @@ pub struct RouteStoreContainer {
 
     pub upstreams: Vec<RouteUpstream>,
     pub self_signed_certificate: bool,
+    /// Name of the issuer of the certificate, Let's Encrypt without one
+    pub certificate_issuer: Option<String>,
+    /// Verification of the client certificates (mutual TLS)
+    pub client_auth: Option<Arc<ClientAuth>>,
 
     pub plugins: HashMap<String, RoutePlugin>,
 
     pub cache: Option<RouteCache>,
+    pub compression: Option<RouteCompression>,
+    pub limits: Option<RouteLimits>,
+    pub websocket: Option<RouteWebSocket>,
+    pub grpc: Option<RouteGrpc>,
+    pub streaming: Option<RouteStreaming>,
+    pub sticky_sessions: Option<RouteStickySessions>,
+    pub static_files: Option<RouteStaticFiles>,
+    /// Content types of the extensions, of the static files and of the upstream responses
+    pub mime_types: Option<Arc<MimeTypes>>,
+    /// Templates of the responses generated by the proxy, and maintenance mode
+    pub error_pages: Option<Arc<ErrorPages>>,
+
+    pub ip_filter: Option<IpFilter>,
+    /// Methods of the requests accepted by the route (all of them when not set)
+    pub methods: Option<Arc<AllowedMethods>>,
+    /// Pool of upstreams receiving a share of the requests
+    pub canary: Option<Arc<Canary>>,
+    /// Shadow upstream receiving a copy of a share of the requests
+    pub mirror: Option<Arc<Mirror>>,
+    /// Sample of the requests written to a file
+    pub capture: Option<Arc<Capture>>,
+    /// Sample of the request and response bodies written to the logs
+    pub body_logging: Option<Arc<BodyLogging>>,
+
+    /// Header rules applied to the upstream request and response
+    pub request_headers: Option<Arc<HeaderRules>>,
+    pub response_headers: Option<Arc<HeaderRules>>,
 }
 
 impl Default for RouteStoreContainer {
     fn default() -> Self {
         RouteStoreContainer {
+            id: NEXT_ROUTE_ID.fetch_add(1, Ordering::Relaxed),
             load_balancer: Arc::new(
                 LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]).unwrap(),
             ),
@@ -58,9 +113,28 @@ impl Default for RouteStoreContainer {
             host_header_remove: Vec::with_capacity(0),
             host_header_add: Vec::with_capacity(0),
             self_signed_certificate: false,
+            certificate_issuer: None,
+            client_auth: None,
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(0),
             cache: None,
+            compression: None,
+            limits: None,
+            websocket: None,
+            grpc: None,
+            streaming: None,
+            sticky_sessions: None,
+            static_files: None,
+            mime_types: None,
+            error_pages: None,
+            ip_filter: None,
+            methods: None,
+            canary: None,
+            mirror: None,
+            capture: None,
+            body_logging: None,
+            request_headers: None,
+            response_headers: None,
         }
     }
 }
@@ -68,24 +142,100 @@ impl Default for RouteStoreContainer {
 impl RouteStoreContainer {
     pub fn new(load_balancer: LoadBalancer<RoundRobin>) -> Self {
         RouteStoreContainer {
+            id: NEXT_ROUTE_ID.fetch_add(1, Ordering::Relaxed),
             load_balancer: Arc::new(load_balancer),
             path_matcher: RouteStorePathMatcher::new(),
             host_header_remove: Vec::with_capacity(5),
             host_header_add: Vec::with_capacity(5),
             self_signed_certificate: false,
+            certificate_issuer: None,
+            client_auth: None,
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(5),
             cache: None,
+            compression: None,
+            limits: None,
+            websocket: None,
+            grpc: None,
+            streaming: None,
+            sticky_sessions: None,
+            static_files: None,
+            mime_types: None,
+            error_pages: None,
+            ip_filter: None,
+            methods: None,
+            canary: None,
+            mirror: None,
+            capture: None,
+            body_logging: None,
+            request_headers: None,
+            response_headers: None,
         }
     }
 }
 
-// LoadBalancer<RoundRobin>
-/// A store for routes that is updated in a background thread
-pub type RouteStore = papaya::HashMap<String, RouteStoreContainer>;
+/// The routes of the router at a given version. The table is never changed in place,
+/// each change swaps a new table: a request sees the routes of a single version.
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    /// Incremented by every change of the routes, starting from 0 (no route)
+    pub version: u64,
+    /// Unix timestamp (in seconds) of the change
+    pub updated_at: u64,
+    /// Hosts added, replaced or removed by the change, sorted
+    pub changed_hosts: Vec<String>,
+    routes: HashMap<String, Arc<RouteStoreContainer>>,
+}
 
-#[cfg(test)]
+impl RouteTable {
+    pub fn get(&self, host: &str) -> Option<&RouteStoreContainer> {
+        self.routes.get(host).map(AsRef::as_ref)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &RouteStoreContainer)> {
+        self.routes
+            .iter()
+            .map(|(host, route)| (host, route.as_ref()))
+    }
+
+    /// The next version of the table, after the routes were changed with `change`.
+    /// The routes that didn't change are shared with this table.
+    pub fn next(&self, change: impl FnOnce(&mut RouteTableChange), updated_at: u64) -> Self {
+        let mut next = RouteTableChange {
+            routes: self.routes.clone(),
+            hosts: BTreeSet::new(),
+        };
+        change(&mut next);
+
+        Self {
+            version: self.version + 1,
+            updated_at,
+            changed_hosts: next.hosts.into_iter().collect(),
+            routes: next.routes,
+        }
+    }
+}
 
+/// Changes to the routes, applied together to the next version of the table
+pub struct RouteTableChange {
+    routes: HashMap<String, Arc<RouteStoreContainer>>,
+    hosts: BTreeSet<String>,
+}
+
+impl RouteTableChange {
+    pub fn insert(&mut self, host: String, route: RouteStoreContainer) {
+        self.hosts.insert(host.clone());
+        self.routes.insert(host, Arc::new(route));
+    }
+
+    pub fn remove(&mut self, host: &str) {
+        if self.routes.remove(host).is_some() {
+            self.hosts.insert(host.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
 
     use super::*;
@@ -117,4 +267,36 @@ mod tests {
 
         assert!(pattern.find("/invalid").is_none());
     }
+
+    #[test]
+    fn test_route_table_versions() {
+        let table = RouteTable::default();
+        let next = table.next(
+            |routes| {
+                routes.insert("a.localhost".to_string(), RouteStoreContainer::default());
+                routes.insert("b.localhost".to_string(), RouteStoreContainer::default());
+            },
+            10,
+        );
+        let last = next.next(|routes| routes.remove("a.localhost"), 20);
+
+        // Previous versions are unchanged
+        assert_eq!((table.version, table.iter().count()), (0, 0));
+        assert_eq!(
+            (next.version, next.iter().count(), next.updated_at),
+            (1, 2, 10)
+        );
+        assert_eq!(
+            (last.version, last.iter().count(), last.updated_at),
+            (2, 1, 20)
+        );
+        assert_eq!(next.changed_hosts, ["a.localhost", "b.localhost"]);
+        assert_eq!(last.changed_hosts, ["a.localhost"]);
+        assert!(last.get("a.localhost").is_none());
+        assert!(last.get("b.localhost").is_some());
+        assert!(Arc::ptr_eq(
+            &next.routes["b.localhost"],
+            &last.routes["b.localhost"]
+        ));
+    }
 }
@@ -0,0 +1,229 @@
+use std::borrow::Cow;
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::config::Route;
+
+/// Values of the secret references, by reference (e.g. `vault://secret/data/proksi#token`)
+static SECRETS: Lazy<papaya::HashMap<String, String>> = Lazy::new(papaya::HashMap::new);
+
+/// A value of the configuration fetched from a secrets provider
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecretReference<'a> {
+    /// `vault://<path>#<field>`, a field of the secret read at `/v1/<path>`
+    Vault { path: &'a str, field: &'a str },
+    /// `aws-sm://<secret id>[#<field>]`, a secret of AWS Secrets Manager, or a field of
+    /// its JSON
+    SecretsManager { id: &'a str, field: Option<&'a str> },
+    /// `aws-kms://<ciphertext>`, a ciphertext (base64) decrypted by AWS KMS
+    Kms { ciphertext: &'a str },
+}
+
+impl<'a> SecretReference<'a> {
+    /// `None` when the value isn't a secret reference
+    pub fn parse(value: &'a str) -> Option<anyhow::Result<Self>> {
+        if let Some(reference) = value.strip_prefix("vault://") {
+            let reference = match reference.split_once('#') {
+                Some((path, field)) if !path.is_empty() && !field.is_empty() => {
+                    Ok(SecretReference::Vault { path, field })
+                }
+                _ => Err(anyhow!("{value} must be vault://<path>#<field>")),
+            };
+            return Some(reference);
+        }
+
+        if let Some(reference) = value.strip_prefix("aws-sm://") {
+            let (id, field) = match reference.split_once('#') {
+                Some((id, field)) => (id, Some(field).filter(|f| !f.is_empty())),
+                None => (reference, None),
+            };
+            return Some(match id.is_empty() {
+                true => Err(anyhow!("{value} must be aws-sm://<secret id>[#<field>]")),
+                false => Ok(SecretReference::SecretsManager { id, field }),
+            });
+        }
+
+        value
+            .strip_prefix("aws-kms://")
+            .map(|ciphertext| match ciphertext.is_empty() {
+                true => Err(anyhow!("{value} must be aws-kms://<ciphertext>")),
+                false => Ok(SecretReference::Kms { ciphertext }),
+            })
+    }
+}
+
+/// The value of a secret reference, once it was fetched
+pub fn get(reference: &str) -> Option<String> {
+    SECRETS.pin().get(reference).cloned()
+}
+
+/// Sets the value of a secret reference, returns whether it changed
+pub fn insert(reference: &str, value: String) -> bool {
+    let secrets = SECRETS.pin();
+    if secrets.get(reference) == Some(&value) {
+        return false;
+    }
+
+    secrets.insert(reference.to_string(), value);
+    true
+}
+
+/// Every secret reference that was fetched
+pub fn references() -> Vec<String> {
+    SECRETS.pin().keys().cloned().collect()
+}
+
+/// The value itself, or the value of its secret reference (`None` until it's fetched)
+pub fn resolve(value: &str) -> Option<Cow<'_, str>> {
+    match SecretReference::parse(value) {
+        None => Some(Cow::Borrowed(value)),
+        Some(_) => get(value).map(Cow::Owned),
+    }
+}
+
+/// The secret references of the strings of the value, wherever they are
+pub fn references_in(value: &Value) -> Vec<String> {
+    let mut references = vec![];
+    collect_references(value, &mut references);
+    references
+}
+
+fn collect_references(value: &Value, references: &mut Vec<String>) {
+    match value {
+        Value::String(string) => {
+            if SecretReference::parse(string).is_some() && !references.contains(string) {
+                references.push(string.clone());
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_references(value, references);
+            }
+        }
+        Value::Object(object) => {
+            for value in object.values() {
+                collect_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces the secret references of the strings of the value with their values
+pub fn resolve_value(value: &mut Value) -> anyhow::Result<()> {
+    match value {
+        Value::String(string) => {
+            if SecretReference::parse(string).is_some() {
+                *string =
+                    get(string).ok_or_else(|| anyhow!("the secret {string} is not loaded"))?;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                resolve_value(value)?;
+            }
+        }
+        Value::Object(object) => {
+            for value in object.values_mut() {
+                resolve_value(value)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// The route with the values of its secret references (e.g. the credentials of its plugins)
+pub fn resolve_route(route: &Route) -> anyhow::Result<Cow<'_, Route>> {
+    let mut value = serde_json::to_value(route)?;
+    if references_in(&value).is_empty() {
+        return Ok(Cow::Borrowed(route));
+    }
+
+    resolve_value(&mut value)?;
+    Ok(Cow::Owned(serde_json::from_value(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_references() {
+        assert_eq!(
+            SecretReference::parse("vault://secret/data/proksi#token")
+                .unwrap()
+                .unwrap(),
+            SecretReference::Vault {
+                path: "secret/data/proksi",
+                field: "token"
+            }
+        );
+        assert!(SecretReference::parse("vault://secret/data/proksi")
+            .unwrap()
+            .is_err());
+
+        assert_eq!(
+            SecretReference::parse("aws-sm://prod/proksi#pass")
+                .unwrap()
+                .unwrap(),
+            SecretReference::SecretsManager {
+                id: "prod/proksi",
+                field: Some("pass")
+            }
+        );
+        assert_eq!(
+            SecretReference::parse("aws-sm://prod/proksi")
+                .unwrap()
+                .unwrap(),
+            SecretReference::SecretsManager {
+                id: "prod/proksi",
+                field: None
+            }
+        );
+
+        assert_eq!(
+            SecretReference::parse("aws-kms://AQID").unwrap().unwrap(),
+            SecretReference::Kms { ciphertext: "AQID" }
+        );
+        assert!(SecretReference::parse("aws-kms://").unwrap().is_err());
+
+        assert!(SecretReference::parse("https://vault.internal").is_none());
+    }
+
+    #[test]
+    fn test_resolve_value() {
+        insert("vault://secret/data/test#user", "admin".to_string());
+
+        let mut value = json!({
+            "plugins": [{ "config": { "user": "vault://secret/data/test#user", "realm": "app" } }]
+        });
+        assert_eq!(
+            references_in(&value),
+            vec!["vault://secret/data/test#user".to_string()]
+        );
+
+        resolve_value(&mut value).unwrap();
+        assert_eq!(value["plugins"][0]["config"]["user"], "admin");
+        assert_eq!(value["plugins"][0]["config"]["realm"], "app");
+
+        let mut value = json!({ "pass": "vault://secret/data/test#missing" });
+        assert!(resolve_value(&mut value).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert!(insert("aws-sm://test/resolve", "value".to_string()));
+        assert!(!insert("aws-sm://test/resolve", "value".to_string()));
+
+        assert_eq!(resolve("plain").unwrap(), "plain");
+        assert_eq!(resolve("aws-sm://test/resolve").unwrap(), "value");
+        // A reference is never used as the value itself
+        assert!(resolve("aws-sm://test/unknown").is_none());
+    }
+}
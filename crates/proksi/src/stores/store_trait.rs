@@ -1,14 +1,17 @@
 use async_trait::async_trait;
 use papaya::HashMapRef;
-use std::{error::Error, hash::RandomState};
+use serde::Serialize;
+use std::{error::Error, hash::RandomState, time::Duration};
 
-use super::certificates::Certificate;
+use super::{api_keys::ApiKey, certificates::Certificate};
 
 #[async_trait]
 pub trait Store: Send + Sync + 'static {
-    // async fn get_route(&self, host: &str) -> Result<Option<String>, Box<dyn Error>>;
-    // async fn remove_route(&self, host: &str) -> Result<(), Box<dyn Error>>;
-    // async fn set_route(&self, route: &str) -> Result<(), Box<dyn Error>>;
+    // Routes created or changed with the admin API, serialized as JSON
+    async fn get_routes(&self) -> Result<Vec<String>, Box<dyn Error>>;
+    async fn set_route(&self, host: &str, route: String) -> Result<(), Box<dyn Error>>;
+    async fn remove_route(&self, host: &str) -> Result<(), Box<dyn Error>>;
+
     async fn get_certificate(&self, domain: &str) -> Option<Certificate>;
     async fn set_certificate(&self, domain: &str, cert: Certificate) -> Result<(), Box<dyn Error>>;
     async fn get_certificates(
@@ -23,4 +26,39 @@ pub trait Store: Send + Sync + 'static {
         token: String,
         proof: String,
     ) -> Result<(), Box<dyn Error>>;
+    // Proof of any challenge of the domain that wasn't validated yet, even one set by
+    // another instance for a concurrent order
+    async fn get_challenge_proof(&self, domain: &str, token: &str) -> Option<String>;
+    // Domains and tokens of the challenges that didn't expire yet, of every instance
+    async fn get_challenge_tokens(&self) -> Result<Vec<(String, String)>, Box<dyn Error>>;
+
+    // API keys, identified by their SHA-256 hash (managed outside of proksi)
+    async fn get_api_key(&self, key_hash: &str) -> Option<ApiKey>;
+
+    // Key signing the cookies of the sticky sessions, shared by the instances: the stored
+    // key, or `key` once stored when there was none
+    async fn get_or_set_session_key(&self, key: String) -> Result<String, Box<dyn Error>>;
+
+    // Locks held by a single instance at a time (e.g. to order a certificate), released
+    // after `ttl` if the instance stops before unlocking them. Returns whether it was acquired.
+    async fn try_lock(&self, name: &str, ttl: Duration) -> Result<bool, Box<dyn Error>>;
+    async fn unlock(&self, name: &str) -> Result<(), Box<dyn Error>>;
+
+    // Keys of the TLS session tickets (serialized as JSON), shared by the instances
+    async fn get_ticket_keys(&self) -> Result<Option<String>, Box<dyn Error>>;
+    async fn set_ticket_keys(&self, keys: String) -> Result<(), Box<dyn Error>>;
+
+    // Publishes the changes made by the other instances sharing the store (e.g. with Redis)
+    // as events, from the current runtime
+    fn forward_changes(&self);
+
+    // Number and size of the keys of each kind of data, to inspect the store
+    async fn usage(&self) -> Result<Vec<(&'static str, KeyUsage)>, Box<dyn Error>>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct KeyUsage {
+    pub keys: usize,
+    /// Size of the keys and their values
+    pub bytes: usize,
 }
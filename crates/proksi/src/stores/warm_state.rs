@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, error::Error, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{config::WarmRestart, plugins};
+
+use super::{
+    backends::{Backend, FileBackend},
+    certificates::RenewalStatus,
+    health,
+};
+
+const HEALTH_KEY: &str = "proksi:warm:health";
+const RENEWALS_KEY: &str = "proksi:warm:renewals";
+const RATE_LIMITS_KEY: &str = "proksi:warm:rate_limits";
+
+/// The state of this instance that is lost on restart otherwise, kept in a file (see
+/// `store.warm_restart`). Each part expires after `max_age_secs`, a state that old is
+/// not restored.
+pub struct WarmState {
+    backend: FileBackend,
+    max_age: Duration,
+}
+
+impl WarmState {
+    pub fn open(config: &WarmRestart) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            backend: FileBackend::new(&config.path)?,
+            max_age: Duration::from_secs(config.max_age_secs),
+        })
+    }
+
+    /// Restores the saved state, before the services start to use it
+    pub async fn restore(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(saved) = self.load::<health::UpstreamsHealth>(HEALTH_KEY).await? {
+            tracing::info!(
+                "restored the health of the upstreams of {} hosts",
+                saved.len()
+            );
+            health::restore(saved);
+        }
+
+        if let Some(saved) = self
+            .load::<BTreeMap<String, RenewalStatus>>(RENEWALS_KEY)
+            .await?
+        {
+            for (host, saved) in saved {
+                if super::get_renewal_status(&host).is_none() {
+                    super::update_renewal_status(&host, |status| *status = saved);
+                }
+            }
+        }
+
+        if let Some(saved) = self.load(RATE_LIMITS_KEY).await? {
+            plugins::restore_rate_limit_windows(saved);
+        }
+
+        Ok(())
+    }
+
+    /// Saves the current state
+    pub async fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.store(HEALTH_KEY, &*health::snapshot()).await?;
+        self.store(RENEWALS_KEY, &super::get_renewal_statuses())
+            .await?;
+        self.store(RATE_LIMITS_KEY, &plugins::rate_limit_windows())
+            .await
+    }
+
+    async fn load<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Box<dyn Error>> {
+        match self.backend.get(key).await? {
+            Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store<T: Serialize>(&self, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
+        let data = serde_json::to_string(value)?;
+        self.backend.set(key, data, Some(self.max_age)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_restore() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let config = WarmRestart {
+            path: dir.join("state.redb"),
+            save_interval_secs: 30,
+            max_age_secs: 600,
+        };
+        let (host, address) = ("warm.localhost", "127.0.0.1:3000");
+
+        health::report_active(host, address, false);
+        super::super::update_renewal_status(host, |status| {
+            status.last_attempt_at = Some(1000);
+        });
+        WarmState::open(&config).unwrap().save().await.unwrap();
+
+        // A new process starts from the saved state
+        health::retain(|h, _| h != host);
+        WarmState::open(&config).unwrap().restore().await.unwrap();
+        assert_eq!(health::is_healthy(host, address), Some(false));
+        assert_eq!(
+            super::super::get_renewal_status(host)
+                .unwrap()
+                .last_attempt_at,
+            Some(1000)
+        );
+
+        // A state older than `max_age_secs` is not restored
+        let expired = WarmRestart {
+            max_age_secs: 0,
+            ..config.clone()
+        };
+        WarmState::open(&expired).unwrap().save().await.unwrap();
+        health::retain(|h, _| h != host);
+        WarmState::open(&config).unwrap().restore().await.unwrap();
+        assert_eq!(health::is_healthy(host, address), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+pub mod replay;
+
 use tracing::info;
 
 pub fn _access_log(_attrs: Option<u32>) {
@@ -0,0 +1,180 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use openssl::base64;
+
+use crate::{
+    config::ReplayArgs,
+    proxy_server::capture::{CapturedRequest, REDACTED},
+};
+
+/// Headers of the connection of the captured request, the replayed ones have their own
+const SKIPPED_HEADERS: [HeaderName; 4] = [
+    http::header::CONNECTION,
+    http::header::CONTENT_LENGTH,
+    http::header::TRANSFER_ENCODING,
+    http::header::UPGRADE,
+];
+
+/// `proksi replay`: sends the captured requests of a file to an upstream, one after the
+/// other, and prints their status next to the one they were captured with
+pub fn run(args: &ReplayArgs) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(replay(args))
+}
+
+async fn replay(args: &ReplayArgs) -> anyhow::Result<()> {
+    let upstream = reqwest::Url::parse(&args.upstream)
+        .map_err(|err| anyhow!("upstream {} is invalid: {err}", args.upstream))?;
+    let extra_headers = parse_headers(&args.headers)?;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    let file = File::open(&args.file)
+        .map_err(|err| anyhow!("failed to open {:?}: {err}", args.file))?;
+    let (mut replayed, mut failed) = (0, 0);
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let captured = serde_json::from_str::<CapturedRequest>(&line)
+            .map_err(|err| anyhow!("line {}: {err}", number + 1))?;
+        if args.host.as_ref().is_some_and(|host| *host != captured.host) {
+            continue;
+        }
+
+        let request = build_request(&client, &upstream, &captured, &extra_headers)
+            .map_err(|err| anyhow!("line {}: {err}", number + 1))?;
+        match request.send().await {
+            Ok(response) => println!(
+                "{} {}{} -> {} (captured: {})",
+                captured.method,
+                captured.host,
+                captured.uri,
+                response.status().as_u16(),
+                captured.status
+            ),
+            Err(err) => {
+                failed += 1;
+                println!(
+                    "{} {}{} -> {err}",
+                    captured.method, captured.host, captured.uri
+                );
+            }
+        }
+        replayed += 1;
+
+        if args.delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(args.delay_ms)).await;
+        }
+    }
+
+    println!("{replayed} requests replayed, {failed} failed");
+    Ok(())
+}
+
+/// The captured request, sent to the upstream with its original host. The redacted headers
+/// are left out, unless they are given with `--header`.
+fn build_request(
+    client: &reqwest::Client,
+    upstream: &reqwest::Url,
+    captured: &CapturedRequest,
+    extra_headers: &HeaderMap,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let method = Method::from_bytes(captured.method.as_bytes())?;
+    let url = upstream.join(&captured.uri)?;
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &captured.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        if value == REDACTED || SKIPPED_HEADERS.contains(&name) {
+            continue;
+        }
+        headers.append(name, HeaderValue::from_str(value)?);
+    }
+    for (name, value) in extra_headers {
+        headers.insert(name, value.clone());
+    }
+
+    let body = if captured.body.is_empty() {
+        vec![]
+    } else {
+        base64::decode_block(&captured.body)?
+    };
+    Ok(client.request(method, url).headers(headers).body(body))
+}
+
+/// Headers of `--header`, as `name: value`
+fn parse_headers(headers: &[String]) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("header {header} must be `name: value`"))?;
+        map.append(
+            HeaderName::from_bytes(name.trim().as_bytes())?,
+            HeaderValue::from_str(value.trim())?,
+        );
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let captured = CapturedRequest {
+            at: 0,
+            host: "example.com".to_string(),
+            method: "POST".to_string(),
+            uri: "/api/users?page=2".to_string(),
+            headers: vec![
+                ("host".to_string(), "example.com".to_string()),
+                ("authorization".to_string(), REDACTED.to_string()),
+                ("cookie".to_string(), REDACTED.to_string()),
+                ("content-length".to_string(), "2".to_string()),
+                ("content-type".to_string(), "application/json".to_string()),
+            ],
+            body: base64::encode_block(b"{}"),
+            body_truncated: false,
+            status: 201,
+        };
+        let upstream = reqwest::Url::parse("http://127.0.0.1:3000").unwrap();
+        let extra = parse_headers(&["Authorization: Bearer token".to_string()]).unwrap();
+
+        let request = build_request(&reqwest::Client::new(), &upstream, &captured, &extra)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(
+            request.url().as_str(),
+            "http://127.0.0.1:3000/api/users?page=2"
+        );
+        let headers = request.headers();
+        assert_eq!(headers["host"], "example.com");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(headers["content-type"], "application/json");
+        assert!(!headers.contains_key("cookie"));
+        assert!(!headers.contains_key("content-length"));
+        assert_eq!(request.body().unwrap().as_bytes(), Some(&b"{}"[..]));
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert!(parse_headers(&["x-token".to_string()]).is_err());
+        let headers = parse_headers(&["x-token: a:b".to_string()]).unwrap();
+        assert_eq!(headers["x-token"], "a:b");
+    }
+}
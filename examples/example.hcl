@@ -16,6 +16,24 @@ service_name = "proksi"
 # (and other background services) is single threaded.
 worker_threads = 4
 
+# The threads and CPUs of each service (https, http, listeners, logger,
+# background and admin). See docs/configuration/services.md.
+# services {
+#   https {
+#     threads = 6
+#     cpus = [2, 3, 4, 5, 6, 7]
+#   }
+#   logger {
+#     cpus = [1]
+#   }
+# }
+
+# Size of the objects the disk cache keeps in memory, in bytes (256 MiB by default).
+# See docs/use-cases/cache.md.
+# cache {
+#   memory_size = 536870912
+# }
+
 
 # The server block specifies the settings for the HTTP/HTTPS server.
 server {
@@ -28,8 +46,89 @@ server {
   # This can be a TCP address or a Unix socket.
   # The default value is "0.0.0.0:80".
   http_address = "0.0.0.0:80"
+
+  # The address used to expose Prometheus metrics (under `/metrics`).
+  # Metrics are not exposed if no address is provided.
+  # metrics_address = "127.0.0.1:9090"
+
+  # Proxies/load balancers (IPs or CIDR) in front of Proksi.
+  # For requests coming from them, the client IP is read from `X-Forwarded-For`.
+  # trusted_proxies = ["10.0.0.0/8"]
+
+  # `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Real-IP` sent to the upstreams.
+  # "append" keeps the headers of trusted proxies, "overwrite" always replaces them.
+  # forwarded_headers {
+  #   enabled = true
+  #   mode = "append"
+  # }
+
+  # Timeouts (seconds) and minimum transfer rate (bytes per second) of the request bodies.
+  # slow_clients {
+  #   read_timeout = 30
+  #   body_timeout = 300
+  #   min_body_rate = 1024
+  # }
+
+  # Connections of each client IP: open at once, and new ones per `rate_window_secs`.
+  # The rejected connections get a 429 response, after `tarpit_secs`.
+  # connection_limits {
+  #   max_connections = 100
+  #   max_new_connections = 20
+  #   rate_window_secs = 1
+  #   tarpit_secs = 5
+  #   exempt = ["10.0.0.0/8"]
+  # }
+
+  # Checks of the requests of each listener against request smuggling.
+  # duplicate_host: "normalize" merges identical `Host` headers, "reject" or "allow".
+  # strict_parsing {
+  #   https {
+  #     reject_conflicting_length = true
+  #     reject_obs_fold = true
+  #     duplicate_host = "normalize"
+  #     max_headers = 100
+  #   }
+  #   http {
+  #     enabled = false
+  #   }
+  # }
+
+  # Keys of the TLS session tickets, rotated every `rotation_interval_secs`. With `shared`,
+  # the keys are kept in the store so that every instance resumes the sessions.
+  # session_tickets {
+  #   enabled = true
+  #   rotation_interval_secs = 3600
+  #   shared = false
+  # }
+
+  # Graceful shutdowns (SIGTERM) and upgrades (SIGQUIT): the in-flight requests get up to
+  # `drain_secs` to finish, then the last logs and cache writes up to `timeout_secs`.
+  # shutdown {
+  #   drain_secs = 300
+  #   timeout_secs = 5
+  # }
+
+  # Idle connections to the upstreams kept by each thread of the HTTPS service (for all
+  # the upstreams), and how long they are kept, in seconds.
+  # upstream_pool {
+  #   size = 128
+  #   idle_timeout = 360
+  # }
+
+  # Liveness (`/healthz`) and readiness (`/readyz`) probes, e.g. for Kubernetes.
+  # probes {
+  #   address = "0.0.0.0:9092"
+  #   min_healthy_upstreams = 1
+  # }
 }
 
+# Global IP allow/deny lists (IPs or CIDR), applied to every route.
+# Routes can also define their own `ip_filter`.
+# ip_filter {
+#   allow = ["192.168.0.0/16"]
+#   deny = ["192.168.1.10"]
+# }
+
 
 # The store block specifies the settings for the store
 # You can store all proksi configuration such as certificates, routes, pathing etc.
@@ -40,6 +139,18 @@ store {
   # Change to get data from redis
   # store_type = "redis"
   # redis_url = "redis://localhost:6479/"
+
+  # Or keep the data in a file on disk, restored on startup (single instance)
+  # store_type = "file"
+  # path = "/var/lib/proksi/store.redb"
+
+  # Keep the health of the upstreams, the renewal status of the certificates and the
+  # rate limits of this instance across restarts (whatever the store type)
+  # warm_restart {
+  #   path = "/var/lib/proksi/state.redb"
+  #   save_interval_secs = 30
+  #   max_age_secs = 600
+  # }
 }
 
 docker {
@@ -86,6 +197,20 @@ lets_encrypt {
   staging = true
 }
 
+# Private CAs (step-ca, Vault PKI) issuing the certificates of the routes that select them
+# with `ssl_certificate.issuer`, e.g. for internal hostnames.
+# certificate_issuers = [
+#   { name = "step", type = "acme", url = "https://ca.internal:9000/acme/acme/directory" },
+#   { name = "vault", type = "vault", url = "https://vault.internal:8200", role = "proksi" },
+# ]
+
+# Providers of the secret references (`vault://<path>#<field>`, `aws-sm://<secret id>#<field>`
+# and `aws-kms://<ciphertext>`), fetched at startup and refreshed every `refresh_interval_secs`.
+# secrets {
+#   vault = { url = "https://vault.internal:8200" }
+#   aws = { region = "eu-west-1" }
+# }
+
 logging  {
   # Whether to log anything at all (default: true)
   enabled = true
@@ -122,6 +247,37 @@ paths {
   lets_encrypt = "/etc/proksi/letsencrypt"
 }
 
+# Admin API exposing the routes, upstreams, certificates and cache statistics as JSON.
+# Every request needs the `Authorization: Bearer <token>` header.
+# admin {
+#   address = "127.0.0.1:9091"
+#   token = env("PROKSI_ADMIN_TOKEN")
+#   tokens = [
+#     { name = "grafana", token = env("PROKSI_GRAFANA_TOKEN"), scope = "read" },
+#   ]
+#   tls {
+#     key = "/etc/proksi/admin/admin.key"
+#     pem = "/etc/proksi/admin/admin.pem"
+#     client_ca = "/etc/proksi/admin/clients-ca.pem"
+#     clients = [
+#       { name = "deploy", organization = "ops", scope = "write" },
+#     ]
+#   }
+#   persist_routes = false
+# }
+
+# Listeners proxying TCP connections (e.g. databases, SMTP) or UDP datagrams (e.g. DNS)
+# to their upstreams, as they are.
+# listeners = [
+#   {
+#     name = "postgres"
+#     address = "0.0.0.0:5432"
+#     upstreams = [{ ip = "10.1.2.30", port = 5432 }]
+#     health_check_interval = 15
+#     connect_timeout = 5
+#   }
+# ]
+
 routes = [
   {
     # The host attribute specifies the hostname that the route will match.
@@ -172,6 +328,78 @@ routes = [
       }
     ]
 
+    # Sticky sessions: the clients are sent to the same upstream, assigned in the store,
+    # until they are idle for `idle_timeout` seconds or the upstream is unhealthy.
+    # sticky_sessions = {
+    #   cookie = "proksi_session"
+    #   idle_timeout = 3600
+    # }
+
+    # Content types of file extensions, for the static files and the upstream responses.
+    # mime_types = {
+    #   wasm = "application/wasm"
+    #   glb = "model/gltf-binary"
+    # }
+
+    # Files served from a directory instead of the upstreams. With `fallback`, the paths
+    # without a file (except assets like `/app.js`) get the entrypoint of a single-page app.
+    # static_files = {
+    #   root = "/var/www/app"
+    #   fallback = "index.html"
+    #   precompressed = ["br", "gzip"]
+    # }
+
+    # Pages of the errors generated by the proxy (e.g. a 502 when the upstreams are down),
+    # rendered from the templates of a directory: 502.html, 5xx.html, error.html and
+    # maintenance.html. Clients preferring JSON get a JSON body.
+    # error_pages = {
+    #   directory = "/etc/proksi/error-pages"
+    #   maintenance = false
+    #   retry_after = 300
+    # }
+
+    # Methods allowed by the route, the others get a 405 (HEAD is allowed with GET).
+    # methods = ["GET", "POST"]
+
+    # Blue and green pools of upstreams, used instead of `upstreams`. The active pool is
+    # switched with the admin API (PUT /routes/{host}/active), and switched back when more
+    # than `max_error_rate` of its requests fail within `window_secs`.
+    # blue_green = {
+    #   blue = [{ ip = "10.0.1.1", port = 3000 }]
+    #   green = [{ ip = "10.0.2.1", port = 3000 }]
+    #   active = "blue"
+    #   rollback = {
+    #     max_error_rate = 0.05
+    #     min_requests = 20
+    #     window_secs = 300
+    #   }
+    # }
+
+    # A share of the requests (`weight`, in percent) sent to a canary pool of upstreams,
+    # changed with the admin API (PUT /routes/{host}/canary). With `sticky`, clients keep
+    # their pool: by a cookie set by proksi, a header (`name`) or their IP (`client_ip`).
+    # canary = {
+    #   upstreams = [{ ip = "10.0.3.1", port = 3000 }]
+    #   weight = 10
+    #   sticky = { by = "cookie" }
+    # }
+
+    # A copy of a share of the requests (`percentage`) sent to a shadow upstream, whose
+    # responses are discarded. The bodies are copied with `include_body`.
+    # mirror = {
+    #   url = "http://10.0.4.1:3000"
+    #   percentage = 10
+    #   include_body = false
+    # }
+
+    # A sample of the requests (`percentage`) written to a file, to be sent again with
+    # `proksi replay <file> --upstream <url>`. Credentials are replaced by `<redacted>`.
+    # capture = {
+    #   path = "/var/lib/proksi/captures/my-host.jsonl"
+    #   percentage = 1
+    #   redact_headers = ["x-api-key"]
+    # }
+
 
     # The headers attribute specifies the headers that will
     # be added or removed at the end of the response to DOWNSTREAM (client)
@@ -193,11 +421,33 @@ routes = [
       }
 
       self_signed_fallback = true
+
+      # Mutual TLS: the clients send a certificate issued by one of the `ca` certificates.
+      # With `forward`, the verified certificate is sent to the upstreams
+      # (`X-Forwarded-Client-Cert` with the "xfcc" format, a header per detail with "headers").
+      # client_auth = {
+      #   ca = "/etc/proksi/certs/clients-ca.pem"
+      #   required = true
+      #   forward = {
+      #     format = "xfcc"
+      #     include_pem = false
+      #   }
+      #   # Revoked certificates, from CRL files (reloaded when they change) or OCSP.
+      #   # A certificate whose status is unknown is rejected with "fail_closed".
+      #   revocation = {
+      #     crl = ["/etc/proksi/certs/clients-ca.crl"]
+      #     ocsp = false
+      #     ocsp_timeout_secs = 5
+      #     ocsp_cache_secs = 3600
+      #     policy = "fail_closed"
+      #   }
+      # }
     }
 
     // DEPRECATED
     # ssl_certificate = {
     #   self_signed_on_failure = true
+    #   issuer = "step"
     # }
 
     # Match a given request path with the route.